@@ -0,0 +1,315 @@
+use std::{
+    fmt::Display,
+    io,
+    sync::{atomic::Ordering, Arc, Mutex},
+    thread,
+    time::{Duration, Instant},
+};
+
+use clap::{Arg, ArgMatches, Command};
+
+use crate::{
+    embedded::Angler,
+    msgproc::retry::RetryPolicy,
+    net::{http::{HttpHandler, HttpRequest, HttpResponse, HttpServer, HttpUrl}, pool::ConnectionPool},
+    utils::{
+        json::JsonValue,
+        random::FastRng,
+        time::{DurationDeserializer, DurationSequenceDeserializer},
+    },
+};
+
+/// How long the publishing of a benchmark message waits for the response of the RESTful API
+const PUBLISH_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Create the `bench` subcommand with all its arguments
+pub fn bench_command() -> Command {
+    Command::new("bench")
+        .about("Run an embedded Angler instance against mock HTTP destinations and report throughput and latency")
+        .arg(Arg::new("rate").long("rate").default_value("1000").value_parser(clap::value_parser!(u64).range(1..))
+            .help("How many messages per second will be published"))
+        .arg(Arg::new("duration").long("duration").default_value("10s")
+            .help("For how long messages will be published. Uses the Angler time syntax"))
+        .arg(Arg::new("workers").long("workers").default_value("64").value_parser(clap::value_parser!(usize))
+            .help("How many workers the message processor will have"))
+        .arg(Arg::new("destinations").long("destinations").default_value("10").value_parser(clap::value_parser!(usize))
+            .help("How many mock destinations will receive the messages"))
+        .arg(Arg::new("latency").long("latency").default_value("20").value_parser(clap::value_parser!(u64))
+            .help("The mean response time (in milliseconds) of the mock destinations"))
+        .arg(Arg::new("jitter").long("jitter").default_value("5").value_parser(clap::value_parser!(u64))
+            .help("The maximum random variation (in milliseconds) added or removed from the latency"))
+        .arg(Arg::new("error-rate").long("error-rate").default_value("0").value_parser(clap::value_parser!(f64))
+            .help("The probability (between 0 and 1) that a mock destination fails an attempt"))
+        .arg(Arg::new("retry-interval").long("retry-interval").default_value("1s")
+            .help("The retry interval of the published messages. Uses the Angler DurationSequence syntax"))
+        .arg(Arg::new("max-attempts").long("max-attempts").default_value("3").value_parser(clap::value_parser!(u16))
+            .help("The maximum amount of retries of the published messages"))
+        .arg(Arg::new("payload-size").long("payload-size").default_value("256").value_parser(clap::value_parser!(usize))
+            .help("The size (in bytes) of the published payloads"))
+        .arg(Arg::new("drain-timeout").long("drain-timeout").default_value("30s")
+            .help("How long to wait for the outstanding messages after publishing finishes"))
+}
+
+/// Store the parameters of a benchmark run
+#[derive(Debug, Clone)]
+pub struct BenchOptions {
+    pub rate: u64,
+    pub duration: Duration,
+    pub workers: usize,
+    pub destinations: usize,
+    pub latency: Duration,
+    pub jitter: Duration,
+    pub error_rate: f64,
+    pub retry_policy: RetryPolicy,
+    pub payload_size: usize,
+    pub drain_timeout: Duration,
+}
+
+impl BenchOptions {
+    /// Create the options from the arguments of the `bench` subcommand
+    pub fn from_args(args: &ArgMatches) -> Result<BenchOptions, String> {
+        let duration_arg = |name: &str| -> Result<Duration, String> {
+            let value = args.get_one::<String>(name).unwrap();
+            value.as_str().to_duration()
+                .map(|d| d.unsigned_abs())
+                .map_err(|err| format!("--{} {}: {}", name, value, err))
+        };
+        let retry_interval = args.get_one::<String>("retry-interval").unwrap();
+
+        Ok(BenchOptions {
+            rate: *args.get_one("rate").unwrap(),
+            duration: duration_arg("duration")?,
+            workers: *args.get_one("workers").unwrap(),
+            destinations: (*args.get_one::<usize>("destinations").unwrap()).max(1),
+            latency: Duration::from_millis(*args.get_one("latency").unwrap()),
+            jitter: Duration::from_millis(*args.get_one("jitter").unwrap()),
+            error_rate: args.get_one::<f64>("error-rate").unwrap().clamp(0.0, 1.0),
+            retry_policy: RetryPolicy {
+                interval: Some(retry_interval.as_str().to_duration_sequence()
+                    .map_err(|err| format!("--retry-interval {}: {}", retry_interval, err))?),
+                max_attempts: *args.get_one("max-attempts").unwrap(),
+            },
+            payload_size: *args.get_one("payload-size").unwrap(),
+            drain_timeout: duration_arg("drain-timeout")?,
+        })
+    }
+}
+
+/// The mock destinations, HTTP servers that answer after a random latency and fail with a given
+/// probability. Each payload carries when it was published so the servers measure the latency
+struct MockDestinations {
+    latency: Duration,
+    jitter: Duration,
+    error_rate: f64,
+    rng: Mutex<FastRng>,
+    started_at: Instant,
+    /// The time between the publishing and the delivery of each delivered message
+    latencies: Mutex<Vec<Duration>>,
+}
+
+impl MockDestinations {
+    fn handle(&self, request: &HttpRequest) -> HttpResponse {
+        // the response time is uniformly distributed in [latency - jitter, latency + jitter]
+        let (spread, failed) = {
+            let mut rng = self.rng.lock().unwrap();
+            (self.jitter.mul_f64(rng.next_f64() * 2.0), rng.next_f64() < self.error_rate)
+        };
+        thread::sleep((self.latency + spread).saturating_sub(self.jitter));
+
+        if failed {
+            return HttpResponse::new(503);
+        }
+
+        let published_at = JsonValue::parse_bytes(&request.body).ok()
+            .and_then(|payload| payload.get(PUBLISHED_AT_FIELD).and_then(JsonValue::as_u64));
+        if let Some(published_at) = published_at {
+            let latency = self.started_at.elapsed().saturating_sub(Duration::from_micros(published_at));
+            self.latencies.lock().unwrap().push(latency);
+        }
+        HttpResponse::new(200)
+    }
+}
+
+/// The payload field with the microseconds between the start of the benchmark and the publishing
+const PUBLISHED_AT_FIELD: &str = "publishedAt";
+
+/// The result of a benchmark run
+#[derive(Debug, Clone)]
+pub struct BenchReport {
+    pub published: u64,
+    pub delivered: u64,
+    pub dead: u64,
+    pub attempts: u64,
+    /// Messages that did not reach a final status before the drain timeout
+    pub outstanding: u64,
+    pub elapsed: Duration,
+    /// The latencies between publishing and delivery, sorted
+    pub latencies: Vec<Duration>,
+}
+
+impl BenchReport {
+    /// Return how many messages were delivered per second
+    pub fn throughput(&self) -> f64 {
+        self.delivered as f64 / self.elapsed.as_secs_f64().max(f64::EPSILON)
+    }
+
+    /// Return the latency at the given percentile (between 0 and 100)
+    pub fn latency_percentile(&self, percentile: f64) -> Duration {
+        percentile_of_sorted(&self.latencies, percentile)
+    }
+}
+
+impl Display for BenchReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let ms = |d: Duration| d.as_secs_f64() * 1000.0;
+        writeln!(f, "Angler benchmark report")?;
+        writeln!(f, "  elapsed:      {:.2}s", self.elapsed.as_secs_f64())?;
+        writeln!(f, "  published:    {}", self.published)?;
+        writeln!(f, "  delivered:    {}", self.delivered)?;
+        writeln!(f, "  dead:         {}", self.dead)?;
+        writeln!(f, "  outstanding:  {}", self.outstanding)?;
+        writeln!(f, "  attempts:     {}", self.attempts)?;
+        writeln!(f, "  throughput:   {:.1} msg/s", self.throughput())?;
+        write!(
+            f,
+            "  latency (ms): p50={:.1} p90={:.1} p99={:.1} max={:.1}",
+            ms(self.latency_percentile(50.0)),
+            ms(self.latency_percentile(90.0)),
+            ms(self.latency_percentile(99.0)),
+            ms(self.latency_percentile(100.0)),
+        )
+    }
+}
+
+/// Return the value at the given percentile (between 0 and 100) using the nearest-rank method
+fn percentile_of_sorted(sorted: &[Duration], percentile: f64) -> Duration {
+    if sorted.is_empty() {
+        return Duration::ZERO;
+    }
+    let rank = ((percentile.clamp(0.0, 100.0) / 100.0) * sorted.len() as f64).ceil() as usize;
+    sorted[rank.saturating_sub(1).min(sorted.len() - 1)]
+}
+
+/// Run a benchmark with the given options. The messages are published through the RESTful API of
+/// a embedded instance with a memory store, and delivered by HTTP to the mock destinations
+pub fn run_bench(options: &BenchOptions) -> io::Result<BenchReport> {
+    let started_at = Instant::now();
+    let destinations = Arc::new(MockDestinations {
+        latency: options.latency,
+        jitter: options.jitter,
+        error_rate: options.error_rate,
+        rng: Mutex::new(FastRng::new()),
+        started_at,
+        latencies: Mutex::new(Vec::new()),
+    });
+    let angler = Angler::builder().workers(options.workers).build()?;
+    let mut servers = Vec::with_capacity(options.destinations);
+    for index in 0..options.destinations {
+        let handler_destinations = destinations.clone();
+        let handler: Arc<HttpHandler> = Arc::new(move |request: &HttpRequest| handler_destinations.handle(request));
+        let server = HttpServer::bind("127.0.0.1:0", handler)?;
+        angler.register_destination(&format!("destination-{}", index), &format!("http://{}/hooks", server.local_addr()));
+        servers.push(server);
+    }
+
+    // publish messages at a constant rate through connections kept alive
+    let client_url = HttpUrl::parse(&format!("{}/messages", angler.client_url())).map_err(|err| io::Error::other(err.to_string()))?;
+    let pool = ConnectionPool::default();
+    let total = (options.rate as f64 * options.duration.as_secs_f64()).round() as u64;
+    let padding = "a".repeat(options.payload_size);
+    let retry_policy = JsonValue::object()
+        .with("interval", options.retry_policy.interval.as_ref().map(ToString::to_string))
+        .with("maxAttempts", options.retry_policy.max_attempts);
+    for index in 0..total {
+        let due = started_at + Duration::from_secs_f64(index as f64 / options.rate as f64);
+        if let Some(wait) = due.checked_duration_since(Instant::now()) {
+            thread::sleep(wait);
+        }
+
+        let send_message = JsonValue::object()
+            .with("recipientId", format!("destination-{}", index as usize % options.destinations))
+            .with("serviceId", "bench")
+            .with("eventId", "bench")
+            .with("retryPolicy", retry_policy.clone());
+        let data = JsonValue::object()
+            .with(PUBLISHED_AT_FIELD, started_at.elapsed().as_micros() as u64)
+            .with("padding", padding.as_str());
+        let mut request = HttpRequest::new("POST", "/messages");
+        request.headers.set("Content-Type", "application/json");
+        request.body = JsonValue::object().with("sendMessage", send_message).with("data", data).to_string().into_bytes();
+        match pool.send("client", angler.client_addr(), &client_url, request, PUBLISH_TIMEOUT) {
+            Ok(response) if response.is_success() => {}
+            Ok(response) => eprintln!("Failed to publish a benchmark message: {} {}", response.status, String::from_utf8_lossy(&response.body)),
+            Err(err) => eprintln!("Failed to publish a benchmark message: {}", err),
+        }
+    }
+
+    // wait for the outstanding messages
+    let drain_started_at = Instant::now();
+    while angler.stats().outstanding() > 0 && drain_started_at.elapsed() < options.drain_timeout {
+        thread::sleep(Duration::from_millis(10));
+    }
+    let elapsed = started_at.elapsed();
+
+    let stats = angler.stats();
+    let report = BenchReport {
+        published: stats.published.load(Ordering::SeqCst),
+        delivered: stats.delivered.load(Ordering::SeqCst),
+        dead: stats.dead.load(Ordering::SeqCst),
+        attempts: stats.attempts.load(Ordering::SeqCst),
+        outstanding: stats.outstanding(),
+        elapsed,
+        latencies: Vec::new(),
+    };
+    let _ = angler.shutdown();
+    drop(servers);
+
+    let mut latencies = std::mem::take(&mut *destinations.latencies.lock().unwrap());
+    latencies.sort();
+    Ok(BenchReport { latencies, ..report })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_percentile_of_sorted_uses_nearest_rank() {
+        let values: Vec<Duration> = (1..=100).map(Duration::from_millis).collect();
+        assert_eq!(percentile_of_sorted(&values, 50.0), Duration::from_millis(50));
+        assert_eq!(percentile_of_sorted(&values, 99.0), Duration::from_millis(99));
+        assert_eq!(percentile_of_sorted(&values, 100.0), Duration::from_millis(100));
+        assert_eq!(percentile_of_sorted(&[], 50.0), Duration::ZERO);
+    }
+
+    #[test]
+    fn test_if_bench_options_are_parsed_from_args() {
+        let args = bench_command().get_matches_from(["bench", "--rate", "50", "--duration", "2s", "--error-rate", "2"]);
+        let options = BenchOptions::from_args(&args).unwrap();
+        assert_eq!(options.rate, 50);
+        assert_eq!(options.duration, Duration::from_secs(2));
+        assert_eq!(options.error_rate, 1.0);
+        assert_eq!(options.retry_policy.max_attempts, 3);
+    }
+
+    #[test]
+    fn test_if_bench_delivers_all_published_messages() {
+        let options = BenchOptions {
+            rate: 500,
+            duration: Duration::from_millis(100),
+            workers: 8,
+            destinations: 3,
+            latency: Duration::from_millis(1),
+            jitter: Duration::ZERO,
+            error_rate: 0.0,
+            retry_policy: RetryPolicy::default(),
+            payload_size: 16,
+            drain_timeout: Duration::from_secs(5),
+        };
+        let report = run_bench(&options).unwrap();
+        assert_eq!(report.published, 50);
+        assert_eq!(report.delivered, 50);
+        assert_eq!(report.latencies.len(), 50);
+        assert_eq!(report.outstanding, 0);
+    }
+}
@@ -0,0 +1,327 @@
+//! Fault injection used to validate the retry and failover behavior of Angler before trusting it in
+//! production. Only available with the `chaos` feature and controlled through `/admin/chaos`.
+
+use std::{
+    collections::HashSet,
+    sync::{Arc, Mutex, RwLock},
+    time::Duration as StdDuration,
+};
+
+use time::{Duration, OffsetDateTime};
+
+use crate::{
+    db::{MessageQuery, MessageStore, PurgeScope, StoreError, StoreWrite},
+    msgproc::{delivery::Deliverer, message::{AttemptOutcome, AttemptRecord, DeliveryError, DeliveryErrorClass, Message, MessageStatus}, pipeline::StageTimings, retry::{RetryBudget, RetryOn}},
+    syscom::usage::UsageRecord,
+    utils::{clock::{Clock, ClockListener}, json::JsonValue, random::FastRng},
+};
+
+/// Which faults are currently injected
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct FaultSettings {
+    /// The probability (between 0 and 1) that a delivery attempt fails before reaching the destination
+    pub delivery_failure_rate: f64,
+    /// The probability (between 0 and 1) that a store write fails
+    pub store_write_failure_rate: f64,
+    /// The IDs of the cluster nodes that this node can not talk to
+    pub partitioned_nodes: HashSet<String>,
+    /// How much the clock of this node is shifted from the real time
+    pub clock_skew: Duration,
+}
+
+impl FaultSettings {
+    /// Serialize the settings into the JSON used by `/admin/chaos`
+    pub fn to_json(&self) -> JsonValue {
+        let mut partitioned_nodes: Vec<&str> = self.partitioned_nodes.iter().map(String::as_str).collect();
+        partitioned_nodes.sort();
+        JsonValue::object()
+            .with("deliveryFailureRate", self.delivery_failure_rate)
+            .with("storeWriteFailureRate", self.store_write_failure_rate)
+            .with("partitionedNodes", partitioned_nodes)
+            .with("clockSkewMs", self.clock_skew.whole_milliseconds() as i64)
+    }
+
+    /// Read the settings from the JSON used by `/admin/chaos`. Missing fields keep the current value
+    pub fn merge_json(&self, json: &JsonValue) -> Result<FaultSettings, String> {
+        let mut settings = self.clone();
+        let rate = |field: &str| -> Result<Option<f64>, String> {
+            match json.get(field) {
+                None => Ok(None),
+                Some(value) => value.as_f64()
+                    .filter(|rate| (0.0..=1.0).contains(rate))
+                    .map(Some)
+                    .ok_or_else(|| format!("{} should be a number between 0 and 1", field)),
+            }
+        };
+
+        if let Some(rate) = rate("deliveryFailureRate")? {
+            settings.delivery_failure_rate = rate;
+        }
+        if let Some(rate) = rate("storeWriteFailureRate")? {
+            settings.store_write_failure_rate = rate;
+        }
+        if let Some(nodes) = json.get("partitionedNodes") {
+            settings.partitioned_nodes = nodes.as_array()
+                .and_then(|nodes| nodes.iter().map(|node| node.as_str().map(String::from)).collect())
+                .ok_or("partitionedNodes should be an array of strings")?;
+        }
+        if let Some(skew) = json.get("clockSkewMs") {
+            let skew = skew.as_f64().filter(|skew| skew.fract() == 0.0).ok_or("clockSkewMs should be an integer")?;
+            settings.clock_skew = Duration::milliseconds(skew as i64);
+        }
+
+        Ok(settings)
+    }
+}
+
+/// Hold the fault settings and decide, randomly, when each fault happens
+#[derive(Debug, Default)]
+pub struct FaultInjector {
+    settings: RwLock<FaultSettings>,
+    rng: Mutex<FastRng>,
+}
+
+impl FaultInjector {
+    pub fn new() -> FaultInjector {
+        FaultInjector::default()
+    }
+
+    /// Return a copy of the current settings
+    pub fn settings(&self) -> FaultSettings {
+        self.settings.read().unwrap().clone()
+    }
+
+    /// Replace the current settings
+    pub fn update(&self, settings: FaultSettings) {
+        *self.settings.write().unwrap() = settings;
+    }
+
+    /// Stop injecting all the faults
+    pub fn reset(&self) {
+        self.update(FaultSettings::default());
+    }
+
+    fn roll(&self, rate: f64) -> bool {
+        rate > 0.0 && self.rng.lock().unwrap().next_f64() < rate
+    }
+
+    /// Return if the next delivery attempt should fail
+    pub fn should_fail_delivery(&self) -> bool {
+        self.roll(self.settings.read().unwrap().delivery_failure_rate)
+    }
+
+    /// Return if the next store write should fail
+    pub fn should_fail_store_write(&self) -> bool {
+        self.roll(self.settings.read().unwrap().store_write_failure_rate)
+    }
+
+    /// Return if this node is partitioned from the given node
+    pub fn is_partitioned(&self, node_id: &str) -> bool {
+        self.settings.read().unwrap().partitioned_nodes.contains(node_id)
+    }
+
+    /// Return how much the clock of this node should be shifted
+    pub fn clock_skew(&self) -> Duration {
+        self.settings.read().unwrap().clock_skew
+    }
+}
+
+/// A Deliverer that fails attempts according to the FaultInjector before calling the inner Deliverer
+pub struct ChaosDeliverer {
+    inner: Arc<dyn Deliverer>,
+    faults: Arc<FaultInjector>,
+}
+
+impl ChaosDeliverer {
+    pub fn new(inner: Arc<dyn Deliverer>, faults: Arc<FaultInjector>) -> ChaosDeliverer {
+        ChaosDeliverer { inner, faults }
+    }
+}
+
+impl Deliverer for ChaosDeliverer {
+    fn deliver(&self, message: &Message) -> AttemptOutcome {
+        if self.faults.should_fail_delivery() {
+            return AttemptOutcome::Failed(DeliveryError::new(DeliveryErrorClass::Other, "injected delivery failure"));
+        }
+        self.inner.deliver(message)
+    }
+
+    fn deliver_timed(&self, message: &Message, timings: &mut StageTimings) -> AttemptOutcome {
+        if self.faults.should_fail_delivery() {
+            return AttemptOutcome::Failed(DeliveryError::new(DeliveryErrorClass::Other, "injected delivery failure"));
+        }
+        self.inner.deliver_timed(message, timings)
+    }
+
+    fn retry_on(&self, message: &Message) -> Option<RetryOn> {
+        self.inner.retry_on(message)
+    }
+
+    fn retry_budget(&self, message: &Message) -> Option<RetryBudget> {
+        self.inner.retry_budget(message)
+    }
+
+    fn max_concurrency(&self, message: &Message) -> Option<usize> {
+        self.inner.max_concurrency(message)
+    }
+
+    fn is_pulled(&self, message: &Message) -> bool {
+        self.inner.is_pulled(message)
+    }
+
+    fn parked_until(&self, message: &Message) -> Option<OffsetDateTime> {
+        self.inner.parked_until(message)
+    }
+
+    fn held_until(&self, message: &Message, now: OffsetDateTime) -> Option<OffsetDateTime> {
+        self.inner.held_until(message, now)
+    }
+}
+
+/// A MessageStore whose writes fail according to the FaultInjector. Reads are never affected
+pub struct ChaosStore {
+    inner: Arc<dyn MessageStore>,
+    faults: Arc<FaultInjector>,
+}
+
+impl ChaosStore {
+    pub fn new(inner: Arc<dyn MessageStore>, faults: Arc<FaultInjector>) -> ChaosStore {
+        ChaosStore { inner, faults }
+    }
+}
+
+impl MessageStore for ChaosStore {
+    fn write_batch(&self, writes: &[StoreWrite]) -> Result<(), StoreError> {
+        if self.faults.should_fail_store_write() {
+            return Err(StoreError::Backend(String::from("injected store write failure")));
+        }
+        self.inner.write_batch(writes)
+    }
+
+    fn get_message(&self, message_id: &str) -> Result<Option<Message>, StoreError> {
+        self.inner.get_message(message_id)
+    }
+
+    fn get_attempts(&self, message_id: &str) -> Result<Vec<AttemptRecord>, StoreError> {
+        self.inner.get_attempts(message_id)
+    }
+
+    fn find_messages(&self, query: &MessageQuery) -> Result<Vec<Message>, StoreError> {
+        self.inner.find_messages(query)
+    }
+
+    fn find_by_producer_message_id(&self, namespace: &str, topic: &str, producer_message_id: &str) -> Result<Option<Message>, StoreError> {
+        self.inner.find_by_producer_message_id(namespace, topic, producer_message_id)
+    }
+
+    fn purge_finished(&self, status: MessageStatus, finished_before: OffsetDateTime, scope: &PurgeScope) -> Result<usize, StoreError> {
+        if self.faults.should_fail_store_write() {
+            return Err(StoreError::Backend(String::from("injected store write failure")));
+        }
+        self.inner.purge_finished(status, finished_before, scope)
+    }
+
+    fn record_usage(&self, records: &[UsageRecord]) -> Result<(), StoreError> {
+        if self.faults.should_fail_store_write() {
+            return Err(StoreError::Backend(String::from("injected store write failure")));
+        }
+        self.inner.record_usage(records)
+    }
+
+    fn find_usage(&self, from: OffsetDateTime, to: OffsetDateTime) -> Result<Vec<UsageRecord>, StoreError> {
+        self.inner.find_usage(from, to)
+    }
+}
+
+/// A Clock whose wall clock is shifted from the inner Clock by the clock skew of the FaultInjector.
+/// The monotonic time is not affected
+pub struct ChaosClock {
+    inner: Arc<dyn Clock>,
+    faults: Arc<FaultInjector>,
+}
+
+impl ChaosClock {
+    pub fn new(inner: Arc<dyn Clock>, faults: Arc<FaultInjector>) -> ChaosClock {
+        ChaosClock { inner, faults }
+    }
+}
+
+impl Clock for ChaosClock {
+    fn now(&self) -> OffsetDateTime {
+        self.inner.now() + self.faults.clock_skew()
+    }
+
+    fn monotonic(&self) -> StdDuration {
+        self.inner.monotonic()
+    }
+
+    fn real_wait(&self, duration: StdDuration) -> Option<StdDuration> {
+        self.inner.real_wait(duration)
+    }
+
+    fn on_advance(&self, listener: Arc<ClockListener>) {
+        self.inner.on_advance(listener)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{db::memory::MemoryStore, utils::clock::VirtualClock};
+
+    use super::*;
+
+    struct AlwaysDelivers;
+
+    impl Deliverer for AlwaysDelivers {
+        fn deliver(&self, _: &Message) -> AttemptOutcome {
+            AttemptOutcome::Delivered
+        }
+    }
+
+    fn message() -> Message {
+        Message::new("a".to_string(), "r".to_string(), "s".to_string(), "e".to_string(), vec![])
+    }
+
+    #[test]
+    fn test_if_faults_follow_the_settings() {
+        let faults = Arc::new(FaultInjector::new());
+        let deliverer = ChaosDeliverer::new(Arc::new(AlwaysDelivers), faults.clone());
+        let store = ChaosStore::new(Arc::new(MemoryStore::new()), faults.clone());
+
+        assert_eq!(deliverer.deliver(&message()), AttemptOutcome::Delivered);
+        assert!(store.write(StoreWrite::InsertMessage(Box::new(message()))).is_ok());
+
+        faults.update(FaultSettings { delivery_failure_rate: 1.0, store_write_failure_rate: 1.0, ..FaultSettings::default() });
+        assert!(matches!(deliverer.deliver(&message()), AttemptOutcome::Failed(_)));
+        assert!(store.write(StoreWrite::InsertMessage(Box::new(message()))).is_err());
+        assert!(store.get_message("a").unwrap().is_some());
+
+        faults.reset();
+        assert_eq!(deliverer.deliver(&message()), AttemptOutcome::Delivered);
+    }
+
+    #[test]
+    fn test_if_clock_is_skewed() {
+        let faults = Arc::new(FaultInjector::new());
+        let start_time = OffsetDateTime::from_unix_timestamp(1_704_067_200).unwrap();
+        let clock = ChaosClock::new(Arc::new(VirtualClock::new(start_time)), faults.clone());
+        assert_eq!(clock.now(), start_time);
+
+        faults.update(FaultSettings { clock_skew: Duration::minutes(-5), ..FaultSettings::default() });
+        assert_eq!(clock.now(), start_time - Duration::minutes(5));
+    }
+
+    #[test]
+    fn test_if_settings_are_merged_from_json() {
+        let json = JsonValue::parse(r#"{"deliveryFailureRate": 0.25, "partitionedNodes": ["b1"], "clockSkewMs": -1500}"#).unwrap();
+        let settings = FaultSettings::default().merge_json(&json).unwrap();
+        assert_eq!(settings.delivery_failure_rate, 0.25);
+        assert_eq!(settings.store_write_failure_rate, 0.0);
+        assert!(settings.partitioned_nodes.contains("b1"));
+        assert_eq!(settings.clock_skew, Duration::milliseconds(-1500));
+        assert_eq!(FaultSettings::default().merge_json(&settings.to_json()).unwrap(), settings);
+
+        let invalid = JsonValue::parse(r#"{"storeWriteFailureRate": 2}"#).unwrap();
+        assert!(FaultSettings::default().merge_json(&invalid).is_err());
+    }
+}
@@ -0,0 +1,312 @@
+use std::{
+    collections::{BTreeSet, HashMap},
+    sync::{mpsc::{self, RecvTimeoutError, Sender}, Arc},
+    thread::{self, JoinHandle},
+    time::Duration,
+};
+
+use time::OffsetDateTime;
+
+use crate::{
+    db::{MessageStore, StoreError, StoreWrite},
+    log,
+    msgproc::message::Message,
+    utils::log::Level,
+};
+
+use super::{readiness::ReadySignal, ring::stable_hash};
+
+/// How many leaves the Merkle trees compared by the anti-entropy repair have
+pub const MERKLE_LEAVES: usize = 256;
+
+/// How often a storage node compares its store with its replicas when
+/// `cluster.antiEntropy.interval` is not set
+pub const DEFAULT_ANTI_ENTROPY_INTERVAL: Duration = Duration::from_secs(5 * 60);
+
+/// The digest of the state of a stored message
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MessageDigest {
+    pub message_id: String,
+    pub digest: u64,
+}
+
+impl MessageDigest {
+    /// Digest the fields that change while the message is delivered, and how many annotations it
+    /// has. The other fields are set when the message is published and never change
+    pub fn of(message: &Message) -> MessageDigest {
+        let next_attempt_at = message.next_attempt_at.map(|at| at.unix_timestamp_nanos().to_string()).unwrap_or_default();
+        let sequence = message.sequence.map(|sequence| sequence.to_string()).unwrap_or_default();
+        let mut state = format!("{}|{}|{}|{}|{}", message.id, message.status.as_str(), message.attempts, next_attempt_at, sequence);
+        // appended only when annotated, so the digests of the other messages match the ones of
+        // the nodes that do not annotate messages
+        if !message.annotations.is_empty() {
+            state.push_str(&format!("|{}", message.annotations.len()));
+        }
+        MessageDigest { message_id: message.id.clone(), digest: stable_hash(&state) }
+    }
+
+    /// Return the leaf of a tree with `leaves` leaves that has the message
+    pub fn leaf(message_id: &str, leaves: usize) -> usize {
+        (stable_hash(message_id) % leaves.max(1) as u64) as usize
+    }
+}
+
+/// Return the hash of each leaf of a tree with `leaves` leaves: the wrapping sum of the digests of
+/// its messages, so it does not depend on the order they are read from the store
+pub fn leaf_hashes(digests: &[MessageDigest], leaves: usize) -> Vec<u64> {
+    let mut hashes = vec![0u64; leaves.max(1)];
+    for digest in digests {
+        let leaf = MessageDigest::leaf(&digest.message_id, leaves);
+        hashes[leaf] = hashes[leaf].wrapping_add(digest.digest);
+    }
+    hashes
+}
+
+/// A Merkle tree over the leaf hashes of a store. Two stores with the same messages in the same
+/// state have the same root, and the leaves that differ are found by walking down the nodes that
+/// differ
+#[derive(Debug, Clone, PartialEq)]
+pub struct MerkleTree {
+    leaves: usize,
+    /// The nodes of the tree, starting at the root at 1. The children of `n` are `2n` and `2n + 1`
+    /// and the leaves are the last `leaves` nodes
+    nodes: Vec<u64>,
+}
+
+impl MerkleTree {
+    /// Build the tree over the leaf hashes. The amount of leaves is padded up to a power of two
+    pub fn from_leaves(hashes: &[u64]) -> MerkleTree {
+        let leaves = hashes.len().max(1).next_power_of_two();
+        let mut nodes = vec![0u64; 2 * leaves];
+        nodes[leaves..leaves + hashes.len()].copy_from_slice(hashes);
+        for node in (1..leaves).rev() {
+            nodes[node] = stable_hash(&format!("{:016x}{:016x}", nodes[2 * node], nodes[2 * node + 1]));
+        }
+        MerkleTree { leaves, nodes }
+    }
+
+    pub fn root(&self) -> u64 {
+        self.nodes[1]
+    }
+
+    /// Return the leaves whose hashes differ from the other tree, ordered by index. Trees with a
+    /// different amount of leaves differ in all of them
+    pub fn differing_leaves(&self, other: &MerkleTree) -> Vec<usize> {
+        if self.leaves != other.leaves {
+            return (0..self.leaves.max(other.leaves)).collect();
+        }
+        let mut differing = Vec::new();
+        let mut pending = vec![1];
+        while let Some(node) = pending.pop() {
+            if self.nodes[node] == other.nodes[node] {
+                continue;
+            }
+            if node >= self.leaves {
+                differing.push(node - self.leaves);
+            } else {
+                pending.extend([2 * node, 2 * node + 1]);
+            }
+        }
+        differing.sort_unstable();
+        differing
+    }
+}
+
+/// What a repair found and fixed in the replica
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct RepairReport {
+    /// How many leaves of the trees differed
+    pub differing_leaves: usize,
+    /// How many messages were missing or divergent in the replica and were copied from the primary
+    pub repaired: usize,
+    /// How many messages of the replica are not in the primary. They are kept, as they may be
+    /// messages that the primary already purged
+    pub extra: usize,
+}
+
+/// Compare the store of a primary with a replica and copy into the replica the messages and the
+/// attempts that it is missing, so the replica converges after crashes and network partitions
+pub struct AntiEntropy {
+    primary: Arc<dyn MessageStore>,
+    replica: Arc<dyn MessageStore>,
+}
+
+enum AntiEntropySignal {
+    Stop,
+}
+
+impl AntiEntropy {
+    pub fn new(primary: Arc<dyn MessageStore>, replica: Arc<dyn MessageStore>) -> AntiEntropy {
+        AntiEntropy { primary, replica }
+    }
+
+    /// Compare the Merkle trees of both stores and repair the messages of the leaves that differ.
+    /// Only the leaf hashes are read when the stores agree. The replica is marked as synced at the
+    /// start of a repair that succeeds, as it has all the messages the primary had then
+    pub fn repair(&self) -> Result<RepairReport, StoreError> {
+        let started_at = OffsetDateTime::now_utc();
+        let report = self.compare_and_copy()?;
+        self.replica.mark_synced(started_at)?;
+        Ok(report)
+    }
+
+    fn compare_and_copy(&self) -> Result<RepairReport, StoreError> {
+        let primary_tree = MerkleTree::from_leaves(&self.primary.merkle_leaves(MERKLE_LEAVES)?);
+        let replica_tree = MerkleTree::from_leaves(&self.replica.merkle_leaves(MERKLE_LEAVES)?);
+        let differing = primary_tree.differing_leaves(&replica_tree);
+        let mut report = RepairReport { differing_leaves: differing.len(), ..RepairReport::default() };
+        if differing.is_empty() {
+            return Ok(report);
+        }
+
+        let digests = |store: &dyn MessageStore| -> Result<HashMap<String, u64>, StoreError> {
+            Ok(store.message_digests(MERKLE_LEAVES, Some(&differing))?.into_iter().map(|digest| (digest.message_id, digest.digest)).collect())
+        };
+        let primary_digests = digests(self.primary.as_ref())?;
+        let replica_digests = digests(self.replica.as_ref())?;
+        report.extra = replica_digests.keys().filter(|message_id| !primary_digests.contains_key(*message_id)).count();
+
+        for (message_id, digest) in &primary_digests {
+            if replica_digests.get(message_id) == Some(digest) {
+                continue;
+            }
+            // the message may have been purged since the digests were read
+            let Some(message) = self.primary.get_message(message_id)? else { continue };
+            let replicated: BTreeSet<u16> = self.replica.get_attempts(message_id)?.iter().map(|attempt| attempt.attempt).collect();
+            let mut writes = vec![StoreWrite::InsertMessage(Box::new(message))];
+            writes.extend(self.primary.get_attempts(message_id)?.into_iter()
+                .filter(|attempt| !replicated.contains(&attempt.attempt))
+                .map(StoreWrite::RecordAttempt));
+            self.replica.write_batch(&writes)?;
+            report.repaired += 1;
+        }
+        Ok(report)
+    }
+
+    /// Repair the replica on a background thread when it starts and then every `interval`
+    pub fn start(self, name: &str, interval: Duration) -> AntiEntropyHandle {
+        let (sender, receiver) = mpsc::channel();
+        let name = name.to_string();
+        let synced = ReadySignal::new();
+        let signal = synced.clone();
+        let thread = thread::Builder::new()
+            .name(String::from("angler-anti-entropy"))
+            .spawn(move || loop {
+                match self.repair() {
+                    Ok(report) => {
+                        signal.raise();
+                        if report.repaired > 0 || report.extra > 0 {
+                            log!(
+                                Level::Warn,
+                                "Repaired {} messages of the replica {} ({} differing leaves, {} messages only in the replica)",
+                                report.repaired, name, report.differing_leaves, report.extra
+                            );
+                        }
+                    }
+                    Err(err) => log!(Level::Error, "Failed to compare the store with the replica {}: {}", name, err),
+                }
+                match receiver.recv_timeout(interval) {
+                    Ok(AntiEntropySignal::Stop) | Err(RecvTimeoutError::Disconnected) => return,
+                    Err(RecvTimeoutError::Timeout) => {}
+                }
+            })
+            .expect("failed to spawn the anti-entropy repair");
+
+        AntiEntropyHandle { sender, thread: Some(thread), synced }
+    }
+}
+
+/// Stop the background repairs when dropped
+pub struct AntiEntropyHandle {
+    sender: Sender<AntiEntropySignal>,
+    thread: Option<JoinHandle<()>>,
+    synced: ReadySignal,
+}
+
+impl AntiEntropyHandle {
+    /// Return the signal raised by the first repair that succeeds
+    pub fn synced(&self) -> ReadySignal {
+        self.synced.clone()
+    }
+
+    /// Stop the background repairs, waiting for the repair in progress
+    pub fn stop(&mut self) {
+        let _ = self.sender.send(AntiEntropySignal::Stop);
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+impl Drop for AntiEntropyHandle {
+    fn drop(&mut self) {
+        self.stop();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use time::OffsetDateTime;
+
+    use crate::{
+        db::memory::MemoryStore,
+        msgproc::message::{AttemptOutcome, AttemptRecord, MessageStatus},
+        net::storage::{RemoteStore, StoreServer},
+    };
+
+    use super::*;
+
+    fn insert(store: &dyn MessageStore, id: &str, status: MessageStatus, attempts: u16) {
+        let mut message = Message::new_at(id.to_string(), "recipient".to_string(), "service".to_string(), "event".to_string(), vec![], OffsetDateTime::UNIX_EPOCH);
+        message.status = status;
+        let mut writes = vec![StoreWrite::InsertMessage(Box::new(message.clone()))];
+        writes.extend((1..=attempts).map(|attempt| StoreWrite::RecordAttempt(AttemptRecord {
+            message_id: id.to_string(),
+            attempt,
+            finished_at: message.created_at,
+            outcome: AttemptOutcome::Delivered,
+        })));
+        store.write_batch(&writes).unwrap();
+    }
+
+    #[test]
+    fn test_if_only_the_differing_leaves_are_found() {
+        let mut hashes = vec![7u64; MERKLE_LEAVES];
+        let tree = MerkleTree::from_leaves(&hashes);
+        assert!(tree.differing_leaves(&tree.clone()).is_empty());
+        hashes[3] = 8;
+        hashes[200] = 9;
+        let other = MerkleTree::from_leaves(&hashes);
+        assert_ne!(tree.root(), other.root());
+        assert_eq!(tree.differing_leaves(&other), vec![3, 200]);
+    }
+
+    #[test]
+    fn test_if_missing_and_divergent_messages_of_a_remote_replica_are_repaired() {
+        let primary = Arc::new(MemoryStore::new());
+        let replica_store = Arc::new(MemoryStore::new());
+        let server = StoreServer::listen(Arc::new(StoreServer::new(replica_store.clone())), "127.0.0.1:0").unwrap();
+        let replica = Arc::new(RemoteStore::new(&format!("http://{}", server.local_addr())));
+
+        insert(primary.as_ref(), "a", MessageStatus::Delivered, 1);
+        insert(primary.as_ref(), "b", MessageStatus::Dead, 3);
+        insert(primary.as_ref(), "c", MessageStatus::Pending, 0);
+        insert(replica_store.as_ref(), "a", MessageStatus::Delivered, 1);
+        // the replica missed the last attempts of b
+        insert(replica_store.as_ref(), "b", MessageStatus::Pending, 1);
+        insert(replica_store.as_ref(), "d", MessageStatus::Delivered, 1);
+
+        let anti_entropy = AntiEntropy::new(primary.clone(), replica.clone());
+        assert_eq!(replica.synced_at().unwrap(), None);
+        let report = anti_entropy.repair().unwrap();
+        assert_eq!((report.repaired, report.extra), (2, 1));
+        assert!(replica.synced_at().unwrap().is_some());
+        assert_eq!(replica_store.get_message("b").unwrap(), primary.get_message("b").unwrap());
+        assert_eq!(replica_store.get_attempts("b").unwrap().len(), 3);
+        assert!(replica_store.get_message("c").unwrap().is_some());
+
+        let report = anti_entropy.repair().unwrap();
+        assert_eq!(report.repaired, 0);
+        assert_eq!(report.extra, 1);
+    }
+}
@@ -0,0 +1,95 @@
+use std::thread;
+
+use crate::utils::json::JsonValue;
+
+use super::ring::HashRing;
+
+/// Below this much free disk a node gets the smallest weight, so it receives as few keys as
+/// possible until space is freed
+pub const LOW_DISK_FREE_BYTES: u64 = 1024 * 1024 * 1024;
+
+/// What a node reports about its resources, so the nodes with more of them receive a larger
+/// share of the keys instead of an equal one
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct NodeCapacity {
+    /// How many CPUs the node can use
+    pub cpus: usize,
+    /// How many workers the node runs, the `msgproc.workers`
+    pub workers: usize,
+    /// How many bytes are free on the disk of the store, when it is known
+    pub disk_free_bytes: Option<u64>,
+}
+
+impl NodeCapacity {
+    /// Return the capacity of this node running `workers` workers. The free disk is not known, as
+    /// the standard library can not read it
+    pub fn local(workers: usize) -> NodeCapacity {
+        let cpus = thread::available_parallelism().map(|n| n.get()).unwrap_or(1);
+        NodeCapacity { cpus, workers, disk_free_bytes: None }
+    }
+
+    pub fn with_disk_free(mut self, bytes: u64) -> NodeCapacity {
+        self.disk_free_bytes = Some(bytes);
+        self
+    }
+
+    /// Return the weight of the node in a HashRing: its CPUs, capped by its workers as a node can
+    /// not deliver more messages at once than it has workers. Nodes low on disk have weight 1
+    pub fn weight(&self) -> usize {
+        if self.disk_free_bytes.is_some_and(|free| free < LOW_DISK_FREE_BYTES) {
+            return 1;
+        }
+        self.cpus.min(self.workers).max(1)
+    }
+
+    /// Serialize the capacity into the JSON reported by the nodes
+    pub fn to_json(&self) -> JsonValue {
+        JsonValue::object()
+            .with("cpus", self.cpus)
+            .with("workers", self.workers)
+            // a number would lose precision above 2^53 bytes
+            .with("diskFreeBytes", self.disk_free_bytes.map(|bytes| bytes.to_string()))
+    }
+
+    /// Read the capacity from the JSON reported by the nodes
+    pub fn from_json(json: &JsonValue) -> Result<NodeCapacity, String> {
+        let count = |field: &str| json.get(field).and_then(JsonValue::as_u64).filter(|count| *count >= 1).map(|count| count as usize)
+            .ok_or_else(|| format!("{} should be a integer >= 1", field));
+        let disk_free_bytes = match json.get("diskFreeBytes") {
+            None | Some(JsonValue::Null) => None,
+            Some(bytes) => Some(bytes.as_str().and_then(|bytes| bytes.parse().ok()).ok_or("diskFreeBytes should be a string with a integer")?),
+        };
+        Ok(NodeCapacity { cpus: count("cpus")?, workers: count("workers")?, disk_free_bytes })
+    }
+}
+
+/// Create a ring with the default amount of points per unit of weight where each node is
+/// weighted by its capacity
+pub fn weighted_ring<'a>(nodes: impl IntoIterator<Item = (&'a str, &'a NodeCapacity)>) -> HashRing {
+    let mut ring = HashRing::default();
+    for (node, capacity) in nodes {
+        ring.add_weighted(node, capacity.weight());
+    }
+    ring
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_if_larger_nodes_receive_more_keys() {
+        let small = NodeCapacity { cpus: 4, workers: 500, disk_free_bytes: None };
+        let large = NodeCapacity { cpus: 64, workers: 500, disk_free_bytes: Some(500 * LOW_DISK_FREE_BYTES) };
+        assert_eq!((small.weight(), large.weight()), (4, 64));
+        assert_eq!(NodeCapacity { cpus: 64, workers: 8, disk_free_bytes: None }.weight(), 8);
+        assert_eq!(large.with_disk_free(1024).weight(), 1);
+
+        let ring = weighted_ring([("small", &small), ("large", &large)]);
+        let owned = (0..10_000).filter(|index| ring.owner(&format!("recipient-{}", index)) == Some("large")).count();
+        assert!(owned > 9_000, "large owns {} keys", owned);
+
+        assert_eq!(NodeCapacity::from_json(&JsonValue::parse(&large.to_json().to_string()).unwrap()), Ok(large));
+        assert!(NodeCapacity::from_json(&small.to_json().with("cpus", 0)).is_err());
+    }
+}
@@ -0,0 +1,169 @@
+use std::{
+    collections::HashMap,
+    sync::{Mutex, OnceLock},
+    time::{Duration, Instant},
+};
+
+use crate::utils::{json::JsonValue, random::uuid_v4};
+
+/// The version of the cluster protocol spoken by this node. It is sent in the header of every
+/// cluster call, and a call without it comes from a node of version 1
+pub const PROTOCOL_VERSION: u32 = 6;
+
+/// The header with the protocol version of the node that sent a cluster call or its response
+pub const PROTOCOL_VERSION_HEADER: &str = "X-Angler-Protocol-Version";
+
+/// The header with the ID of the node that sent a cluster call
+pub const NODE_HEADER: &str = "X-Angler-Node";
+
+/// How long a node that stopped calling is still a member. The nodes of an older version that
+/// were upgraded stop holding the features back after it
+pub const MEMBER_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// The member that stands for the nodes that do not send their ID, the ones of version 1
+const UNKNOWN_MEMBER: &str = "unknown";
+
+/// A feature of the cluster protocol that is only used when every member supports it, so the
+/// nodes can be upgraded one at a time
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ClusterFeature {
+    /// The `merkleLeaves` and `messageDigests` calls of the anti-entropy repair
+    MerkleRepair,
+    /// The `markSynced` and `syncedAt` calls of the read replicas
+    ReplicaSync,
+    /// The LZ4 compression of the bodies of the calls
+    Lz4Compression,
+    /// The `recordUsage` and `findUsage` calls of the usage records
+    UsageRecords,
+    /// The `after` of the queries of `findMessages`, where a page of messages starts
+    CursorPagination,
+}
+
+impl ClusterFeature {
+    pub const ALL: [ClusterFeature; 5] = [ClusterFeature::MerkleRepair, ClusterFeature::ReplicaSync, ClusterFeature::Lz4Compression, ClusterFeature::UsageRecords, ClusterFeature::CursorPagination];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ClusterFeature::MerkleRepair => "merkleRepair",
+            ClusterFeature::ReplicaSync => "replicaSync",
+            ClusterFeature::Lz4Compression => "lz4Compression",
+            ClusterFeature::UsageRecords => "usageRecords",
+            ClusterFeature::CursorPagination => "cursorPagination",
+        }
+    }
+
+    /// Return the first protocol version with the feature
+    pub fn required_version(&self) -> u32 {
+        match self {
+            ClusterFeature::MerkleRepair => 2,
+            ClusterFeature::ReplicaSync => 3,
+            ClusterFeature::Lz4Compression => 4,
+            ClusterFeature::UsageRecords => 5,
+            ClusterFeature::CursorPagination => 6,
+        }
+    }
+}
+
+/// Return the ID this process sends in the cluster calls. It is created when the process starts
+pub fn local_node_id() -> &'static str {
+    static NODE_ID: OnceLock<String> = OnceLock::new();
+    NODE_ID.get_or_init(uuid_v4)
+}
+
+/// Parse the protocol version of a cluster call or response, 1 when the header is missing
+pub fn parse_protocol_version(header: Option<&str>) -> u32 {
+    header.and_then(|version| version.trim().parse().ok()).filter(|version| *version >= 1).unwrap_or(1)
+}
+
+/// Track the protocol versions of the nodes that call this one and enable the features that all
+/// of them, and this node, support
+#[derive(Debug)]
+pub struct FeatureGate {
+    member_timeout: Duration,
+    /// The members with their versions and when they last called
+    members: Mutex<HashMap<String, (u32, Instant)>>,
+}
+
+impl Default for FeatureGate {
+    fn default() -> Self {
+        FeatureGate::new(MEMBER_TIMEOUT)
+    }
+}
+
+impl FeatureGate {
+    pub fn new(member_timeout: Duration) -> FeatureGate {
+        FeatureGate { member_timeout, members: Mutex::new(HashMap::new()) }
+    }
+
+    /// Record the version of a node that made a call. Nodes without ID are tracked together
+    pub fn observe(&self, node_id: Option<&str>, version: u32) {
+        let node_id = node_id.map(str::trim).filter(|node_id| !node_id.is_empty()).unwrap_or(UNKNOWN_MEMBER);
+        self.members.lock().unwrap().insert(node_id.to_string(), (version, Instant::now()));
+    }
+
+    /// Return the members that called in the last `member_timeout` with their versions, ordered by ID
+    pub fn members(&self) -> Vec<(String, u32)> {
+        let mut members = self.members.lock().unwrap();
+        members.retain(|_, (_, last_seen)| last_seen.elapsed() <= self.member_timeout);
+        let mut members: Vec<(String, u32)> = members.iter().map(|(node_id, (version, _))| (node_id.clone(), *version)).collect();
+        members.sort();
+        members
+    }
+
+    /// Return the version supported by this node and every member
+    pub fn negotiated_version(&self) -> u32 {
+        self.members().iter().map(|(_, version)| *version).fold(PROTOCOL_VERSION, u32::min)
+    }
+
+    pub fn is_enabled(&self, feature: ClusterFeature) -> bool {
+        self.negotiated_version() >= feature.required_version()
+    }
+
+    /// Serialize the members and the negotiated features into the JSON of `GET /cluster/features`
+    pub fn to_json(&self) -> JsonValue {
+        let members = self.members();
+        let negotiated = members.iter().map(|(_, version)| *version).fold(PROTOCOL_VERSION, u32::min);
+        JsonValue::object()
+            .with("protocolVersion", PROTOCOL_VERSION)
+            .with("negotiatedVersion", negotiated)
+            .with("members", members.into_iter()
+                .map(|(node_id, version)| JsonValue::object().with("nodeId", node_id).with("protocolVersion", version))
+                .collect::<Vec<_>>())
+            .with("features", ClusterFeature::ALL.iter()
+                .map(|feature| JsonValue::object()
+                    .with("name", feature.as_str())
+                    .with("requiredVersion", feature.required_version())
+                    .with("enabled", negotiated >= feature.required_version()))
+                .collect::<Vec<_>>())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_if_features_wait_for_every_member_to_support_them() {
+        let gate = FeatureGate::default();
+        assert!(gate.is_enabled(ClusterFeature::Lz4Compression));
+
+        gate.observe(Some("b1"), PROTOCOL_VERSION);
+        gate.observe(Some("b2"), 3);
+        assert_eq!(gate.negotiated_version(), 3);
+        assert!(gate.is_enabled(ClusterFeature::ReplicaSync));
+        assert!(!gate.is_enabled(ClusterFeature::Lz4Compression));
+
+        // b2 was upgraded
+        gate.observe(Some("b2"), PROTOCOL_VERSION);
+        assert!(gate.is_enabled(ClusterFeature::Lz4Compression));
+        gate.observe(None, parse_protocol_version(None));
+        assert_eq!(gate.negotiated_version(), 1);
+        assert_eq!(gate.members()[2], (String::from("unknown"), 1));
+
+        let expired = FeatureGate::new(Duration::ZERO);
+        expired.observe(Some("b1"), 1);
+        std::thread::sleep(Duration::from_millis(2));
+        assert!(expired.members().is_empty());
+        assert_eq!(expired.to_json().get("negotiatedVersion").and_then(JsonValue::as_u64), Some(u64::from(PROTOCOL_VERSION)));
+    }
+}
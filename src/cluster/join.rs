@@ -0,0 +1,170 @@
+use std::{collections::HashMap, sync::Mutex};
+
+use clap::{Arg, ArgMatches, Command};
+use thiserror::Error;
+use time::{Duration, OffsetDateTime};
+
+use crate::{
+    net::admin::constant_time_eq,
+    utils::{random::uuid_v4, sha256::{hmac_sha256, to_hex}, time::DurationDeserializer},
+};
+
+/// The prefix of the join tokens, `angler-join.<id>.<expiry>.<signature>`
+const JOIN_TOKEN_PREFIX: &str = "angler-join";
+
+/// The prefix of the node credentials, `angler-node.<node ID>.<token ID>.<signature>`
+const NODE_CREDENTIAL_PREFIX: &str = "angler-node";
+
+#[derive(Debug, Error, PartialEq)]
+pub enum JoinError {
+    #[error("the join token is malformed")]
+    Malformed,
+    #[error("the join token was not signed with the cluster.authKey")]
+    InvalidSignature,
+    #[error("the join token expired at {0}")]
+    Expired(OffsetDateTime),
+    #[error("the join token was already used")]
+    AlreadyUsed,
+}
+
+/// A single-use token that lets a new node join the cluster without the `cluster.authKey`. It is
+/// signed with HMAC-SHA-256 by the key, so the storage nodes verify it without storing it
+#[derive(Debug, Clone, PartialEq)]
+pub struct JoinToken {
+    pub id: String,
+    pub expires_at: OffsetDateTime,
+}
+
+impl JoinToken {
+    /// Create a token that expires `ttl` after `now`, signed with the key
+    pub fn create(auth_key: &str, ttl: Duration, now: OffsetDateTime) -> String {
+        let id = uuid_v4();
+        let expires_at = (now + ttl).unix_timestamp();
+        let payload = format!("{}.{}.{}", JOIN_TOKEN_PREFIX, id, expires_at);
+        let signature = to_hex(&hmac_sha256(auth_key.as_bytes(), payload.as_bytes()));
+        format!("{}.{}", payload, signature)
+    }
+
+    /// Check the signature and the expiry of the token
+    pub fn verify(auth_key: &str, token: &str, now: OffsetDateTime) -> Result<JoinToken, JoinError> {
+        let (payload, signature) = token.trim().rsplit_once('.').ok_or(JoinError::Malformed)?;
+        let [prefix, id, expires_at] = payload.split('.').collect::<Vec<_>>()[..] else {
+            return Err(JoinError::Malformed);
+        };
+        if prefix != JOIN_TOKEN_PREFIX {
+            return Err(JoinError::Malformed);
+        }
+        let expected = to_hex(&hmac_sha256(auth_key.as_bytes(), payload.as_bytes()));
+        if !constant_time_eq(signature.as_bytes(), expected.as_bytes()) {
+            return Err(JoinError::InvalidSignature);
+        }
+        let expires_at = expires_at.parse().ok().and_then(|at| OffsetDateTime::from_unix_timestamp(at).ok()).ok_or(JoinError::Malformed)?;
+        if expires_at <= now {
+            return Err(JoinError::Expired(expires_at));
+        }
+        Ok(JoinToken { id: id.to_string(), expires_at })
+    }
+}
+
+/// The node and the join token of a node credential
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct NodeCredential {
+    pub node_id: String,
+    pub token_id: String,
+}
+
+/// Return the credential a node that joined with the token of `token_id` uses instead of the
+/// `cluster.authKey`. It only lets that node in, so a leaked credential does not expose the key, and
+/// it is revoked with its token by `cluster.revokedJoinTokens`
+pub fn node_credential(auth_key: &str, node_id: &str, token_id: &str) -> String {
+    let payload = format!("{}.{}.{}", NODE_CREDENTIAL_PREFIX, node_id, token_id);
+    format!("{}.{}", payload, to_hex(&hmac_sha256(auth_key.as_bytes(), payload.as_bytes())))
+}
+
+/// Return the node and the token of the credential, None when it was not signed with the key
+pub fn verify_node_credential(auth_key: &str, credential: &str) -> Option<NodeCredential> {
+    let (node_id, token_id) = credential.strip_prefix(NODE_CREDENTIAL_PREFIX)?.strip_prefix('.')?.rsplit_once('.')?.0.split_once('.')?;
+    constant_time_eq(credential.as_bytes(), node_credential(auth_key, node_id, token_id).as_bytes())
+        .then(|| NodeCredential { node_id: node_id.to_string(), token_id: token_id.to_string() })
+}
+
+/// Return if the node ID can be part of a credential
+pub fn is_valid_node_id(node_id: &str) -> bool {
+    !node_id.is_empty() && node_id.len() <= 128 && node_id.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+}
+
+/// Remember the tokens used until they expire, so each one is used once. The tokens are
+/// remembered in memory by each storage node, so the other storage nodes and a storage node that
+/// restarted accept them again until they expire
+#[derive(Debug, Default)]
+pub struct JoinRegistry {
+    used: Mutex<HashMap<String, OffsetDateTime>>,
+}
+
+impl JoinRegistry {
+    pub fn new() -> JoinRegistry {
+        JoinRegistry::default()
+    }
+
+    /// Verify the token and mark it as used
+    pub fn redeem(&self, auth_key: &str, token: &str, now: OffsetDateTime) -> Result<JoinToken, JoinError> {
+        let token = JoinToken::verify(auth_key, token, now)?;
+        let mut used = self.used.lock().unwrap();
+        used.retain(|_, expires_at| *expires_at > now);
+        if used.insert(token.id.clone(), token.expires_at).is_some() {
+            return Err(JoinError::AlreadyUsed);
+        }
+        Ok(token)
+    }
+}
+
+/// The `cluster` subcommand, with the operations run by the operators of the cluster
+pub fn cluster_command() -> Command {
+    Command::new("cluster")
+        .about("Operate the Angler cluster")
+        .subcommand_required(true)
+        .subcommand(Command::new("create-join-token")
+            .about("Print a single-use token that lets a new node join the cluster without the cluster.authKey")
+            .after_help("The used tokens are remembered in memory by each storage node, so until it expires a token is \
+                accepted again by the other storage nodes, like the replicas, and by a storage node that restarted. \
+                Keep the --ttl short, and revoke a leaked token by its ID with cluster.revokedJoinTokens")
+            .arg(Arg::new("ttl").long("ttl").default_value("1h")
+                .help("How long the token can be used. Uses the Angler time syntax")))
+}
+
+/// Create the join token of `create-join-token` with the key of the configuration
+pub fn create_join_token(args: &ArgMatches, auth_key: Option<&str>) -> Result<String, String> {
+    let auth_key = auth_key.ok_or("cluster.authKey is required to sign join tokens")?;
+    let ttl = args.get_one::<String>("ttl").unwrap();
+    let ttl = ttl.as_str().to_duration().map_err(|err| format!("--ttl {}: {}", ttl, err))?;
+    if ttl <= Duration::ZERO {
+        return Err(String::from("--ttl should be greater than zero"));
+    }
+    Ok(JoinToken::create(auth_key, ttl, OffsetDateTime::now_utc()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_if_join_tokens_are_used_once_before_they_expire() {
+        let now = OffsetDateTime::UNIX_EPOCH + Duration::days(20_000);
+        let token = JoinToken::create("k3y", Duration::hours(1), now);
+        assert_eq!(JoinToken::verify("other", &token, now), Err(JoinError::InvalidSignature));
+        assert!(matches!(JoinToken::verify("k3y", &token, now + Duration::hours(1)), Err(JoinError::Expired(_))));
+        let tampered = token.replacen(&(now + Duration::hours(1)).unix_timestamp().to_string(), &(now + Duration::days(365)).unix_timestamp().to_string(), 1);
+        assert_eq!(JoinToken::verify("k3y", &tampered, now), Err(JoinError::InvalidSignature));
+        assert_eq!(JoinToken::verify("k3y", "k3y", now), Err(JoinError::Malformed));
+
+        let registry = JoinRegistry::new();
+        assert!(registry.redeem("k3y", &token, now).is_ok());
+        assert_eq!(registry.redeem("k3y", &token, now + Duration::minutes(1)), Err(JoinError::AlreadyUsed));
+
+        let credential = node_credential("k3y", "b1", "t-1");
+        assert_eq!(verify_node_credential("k3y", &credential), Some(NodeCredential { node_id: String::from("b1"), token_id: String::from("t-1") }));
+        assert_eq!(verify_node_credential("other", &credential), None);
+        assert_eq!(verify_node_credential("k3y", &credential.replace("b1", "b2")), None);
+        assert_eq!(verify_node_credential("k3y", &credential.replace("t-1", "t-2")), None);
+    }
+}
@@ -0,0 +1,10 @@
+//! The pieces used to split the work between the nodes of a cluster. The membership of the
+//! cluster is not tracked yet, so they are used by the callers that know the nodes
+
+pub mod antientropy;
+pub mod capacity;
+pub mod features;
+pub mod join;
+pub mod readiness;
+pub mod ring;
+pub mod skew;
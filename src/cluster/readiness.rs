@@ -0,0 +1,192 @@
+use std::sync::{atomic::{AtomicBool, Ordering}, Arc};
+
+use crate::{
+    ctx::appenv::ApplicationRoles,
+    db::MessageStore,
+    msgproc::processor::MessageProcessor,
+    utils::json::JsonValue,
+};
+
+/// The ID read by the readiness check of the store. No message has it, so checking the store
+/// only reads an index
+const READINESS_PROBE_ID: &str = "angler-readiness-probe";
+
+/// Raised once by a background task when it finished its first run, like the first repair of a
+/// replica. The clones share the signal
+#[derive(Debug, Clone, Default)]
+pub struct ReadySignal(Arc<AtomicBool>);
+
+impl ReadySignal {
+    pub fn new() -> ReadySignal {
+        ReadySignal::default()
+    }
+
+    pub fn raise(&self) {
+        self.0.store(true, Ordering::SeqCst);
+    }
+
+    pub fn is_raised(&self) -> bool {
+        self.0.load(Ordering::SeqCst)
+    }
+}
+
+/// A condition of a role that the node must meet to receive traffic
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ReadinessCheck {
+    pub name: &'static str,
+    pub role: ApplicationRoles,
+    /// Why the node does not meet the condition, None when it does
+    pub failure: Option<String>,
+}
+
+impl ReadinessCheck {
+    fn new(name: &'static str, role: ApplicationRoles, result: Result<(), String>) -> ReadinessCheck {
+        ReadinessCheck { name, role, failure: result.err() }
+    }
+
+    pub fn to_json(&self) -> JsonValue {
+        JsonValue::object()
+            .with("name", self.name)
+            .with("role", self.role.as_str())
+            .with("ready", self.failure.is_none())
+            .with("reason", self.failure.clone())
+    }
+}
+
+/// The checks of the roles of a node, as answered by `GET /readyz`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Readiness {
+    pub roles: Vec<ApplicationRoles>,
+    pub checks: Vec<ReadinessCheck>,
+}
+
+impl Readiness {
+    pub fn is_ready(&self) -> bool {
+        self.checks.iter().all(|check| check.failure.is_none())
+    }
+
+    /// Return the status of `GET /readyz`: `200` when the node is ready, else `503`
+    pub fn status(&self) -> u16 {
+        if self.is_ready() { 200 } else { 503 }
+    }
+
+    pub fn to_json(&self) -> JsonValue {
+        JsonValue::object()
+            .with("ready", self.is_ready())
+            .with("roles", self.roles.iter().map(|role| JsonValue::from(role.as_str())).collect::<Vec<_>>())
+            .with("checks", self.checks.iter().map(ReadinessCheck::to_json).collect::<Vec<_>>())
+    }
+}
+
+/// Tell if a node is ready by the checks of its roles, so a load balancer only sends it traffic
+/// once it can serve it. A storage node is ready when its store answers and each replica of
+/// `cluster.storage.replicas` was repaired once, and a message-processor node when its store (the
+/// storage node, without the storage role) answers, it recovered the messages of the store and it
+/// warmed up the connections of the destinations with `warmConnections`
+pub struct ReadinessProbe {
+    roles: Vec<ApplicationRoles>,
+    store: Arc<dyn MessageStore>,
+    processor: Option<Arc<MessageProcessor>>,
+    /// The first repair of each replica, by its URL
+    replicas: Vec<(String, ReadySignal)>,
+    warmup: Option<ReadySignal>,
+}
+
+impl ReadinessProbe {
+    pub fn new(roles: &[ApplicationRoles], store: Arc<dyn MessageStore>) -> ReadinessProbe {
+        let mut roles = roles.to_vec();
+        roles.sort_by_key(|role| role.as_str());
+        ReadinessProbe { roles, store, processor: None, replicas: Vec::new(), warmup: None }
+    }
+
+    pub fn with_processor(mut self, processor: Arc<MessageProcessor>) -> ReadinessProbe {
+        self.processor = Some(processor);
+        self
+    }
+
+    /// Wait for the first repair of the replica before the storage node is ready
+    pub fn with_replica(mut self, url: &str, synced: ReadySignal) -> ReadinessProbe {
+        self.replicas.push((url.to_string(), synced));
+        self
+    }
+
+    /// Wait for the first warmup of the connections before the message-processor node is ready
+    pub fn with_warmup(mut self, warmed: ReadySignal) -> ReadinessProbe {
+        self.warmup = Some(warmed);
+        self
+    }
+
+    pub fn check(&self) -> Readiness {
+        let mut checks = Vec::new();
+        let storage_role = self.roles.contains(&ApplicationRoles::Storage);
+        let store_role = if storage_role { ApplicationRoles::Storage } else { ApplicationRoles::MessageProcessor };
+        let store = self.store.get_message(READINESS_PROBE_ID).map(|_| ()).map_err(|err| format!("the store failed: {}", err));
+        checks.push(ReadinessCheck::new("store", store_role, store));
+        if storage_role && !self.replicas.is_empty() {
+            let unsynced: Vec<&str> = self.replicas.iter().filter(|(_, synced)| !synced.is_raised()).map(|(url, _)| url.as_str()).collect();
+            let replicated = match unsynced.is_empty() {
+                true => Ok(()),
+                false => Err(format!("the replicas {} were not repaired yet", unsynced.join(", "))),
+            };
+            checks.push(ReadinessCheck::new("replicas", ApplicationRoles::Storage, replicated));
+        }
+        if self.roles.contains(&ApplicationRoles::MessageProcessor) {
+            let recovered = match self.processor.as_ref().is_none_or(|processor| processor.recovery().is_some()) {
+                true => Ok(()),
+                false => Err(String::from("the messages of the store were not recovered yet")),
+            };
+            checks.push(ReadinessCheck::new("recovery", ApplicationRoles::MessageProcessor, recovered));
+            let warmed = match self.warmup.as_ref().is_none_or(ReadySignal::is_raised) {
+                true => Ok(()),
+                false => Err(String::from("the connections of the destinations were not warmed up yet")),
+            };
+            checks.push(ReadinessCheck::new("connections", ApplicationRoles::MessageProcessor, warmed));
+        }
+        Readiness { roles: self.roles.clone(), checks }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        db::{batch::BatchConfiguration, memory::MemoryStore},
+        msgproc::{delivery::Deliverer, message::{AttemptOutcome, Message}},
+    };
+
+    use super::*;
+
+    struct AlwaysDelivers;
+
+    impl Deliverer for AlwaysDelivers {
+        fn deliver(&self, _: &Message) -> AttemptOutcome {
+            AttemptOutcome::Delivered
+        }
+    }
+
+    #[test]
+    fn test_if_each_role_waits_for_its_checks() {
+        let store: Arc<dyn MessageStore> = Arc::new(MemoryStore::new());
+        let synced = ReadySignal::new();
+        let storage = ReadinessProbe::new(&[ApplicationRoles::Storage], store.clone()).with_replica("http://replica-1:2462", synced.clone());
+        let readiness = storage.check();
+        assert_eq!((readiness.is_ready(), readiness.status()), (false, 503));
+        assert_eq!(readiness.checks.iter().map(|check| (check.name, check.failure.is_none())).collect::<Vec<_>>(), vec![("store", true), ("replicas", false)]);
+        assert_eq!(readiness.checks[1].failure.as_deref(), Some("the replicas http://replica-1:2462 were not repaired yet"));
+        synced.raise();
+        assert!(storage.check().is_ready());
+
+        let processor = Arc::new(MessageProcessor::start(1, store.clone(), BatchConfiguration::default(), Arc::new(AlwaysDelivers)));
+        let warmed = ReadySignal::new();
+        let node = ReadinessProbe::new(&[ApplicationRoles::MessageProcessor], store).with_processor(processor.clone()).with_warmup(warmed.clone());
+        assert_eq!(node.check().checks.iter().map(|check| (check.name, check.role)).collect::<Vec<_>>(), vec![
+            ("store", ApplicationRoles::MessageProcessor),
+            ("recovery", ApplicationRoles::MessageProcessor),
+            ("connections", ApplicationRoles::MessageProcessor),
+        ]);
+        assert!(!node.check().is_ready());
+        processor.recover().unwrap();
+        warmed.raise();
+        let readiness = node.check();
+        assert_eq!(readiness.to_json().to_string(), r#"{"checks":[{"name":"store","ready":true,"reason":null,"role":"messageProcessor"},{"name":"recovery","ready":true,"reason":null,"role":"messageProcessor"},{"name":"connections","ready":true,"reason":null,"role":"messageProcessor"}],"ready":true,"roles":["messageProcessor"]}"#);
+    }
+}
@@ -0,0 +1,206 @@
+use std::collections::BTreeMap;
+
+/// How many points each node has in a ring created with `HashRing::default()`. More points spread
+/// the keys more evenly between the nodes
+pub const DEFAULT_VIRTUAL_NODES: usize = 128;
+
+/// Hash the key with 64-bit FNV-1a followed by the finalizer of MurmurHash3. The hash does not
+/// change between versions or platforms, so clients can compute the owners of the keys themselves
+pub fn stable_hash(key: &str) -> u64 {
+    let mut hash: u64 = 0xcbf29ce484222325;
+    for byte in key.as_bytes() {
+        hash ^= u64::from(*byte);
+        hash = hash.wrapping_mul(0x100000001b3);
+    }
+    hash ^= hash >> 33;
+    hash = hash.wrapping_mul(0xff51afd7ed558ccd);
+    hash ^= hash >> 33;
+    hash = hash.wrapping_mul(0xc4ceb9fe1a85ec53);
+    hash ^ (hash >> 33)
+}
+
+/// A consistent hash ring that maps keys, like the recipient IDs of the destinations, to the
+/// nodes that own them. Each node has `virtual_nodes` points on the ring per unit of its weight,
+/// placed at the hashes of `<node>#<index>`, and a key is owned by the node of the first point at
+/// or after its hash. When a node joins or leaves only the keys of its points move, about 1/N of
+/// them, and each node owns a share of the keys proportional to its weight
+#[derive(Debug, Clone, PartialEq)]
+pub struct HashRing {
+    virtual_nodes: usize,
+    points: BTreeMap<u64, String>,
+    /// The nodes and their weights
+    nodes: BTreeMap<String, usize>,
+}
+
+impl Default for HashRing {
+    fn default() -> Self {
+        HashRing::new(DEFAULT_VIRTUAL_NODES)
+    }
+}
+
+impl HashRing {
+    /// Create a empty ring where each node has `virtual_nodes` points. It has at least one
+    pub fn new(virtual_nodes: usize) -> HashRing {
+        HashRing { virtual_nodes: virtual_nodes.max(1), points: BTreeMap::new(), nodes: BTreeMap::new() }
+    }
+
+    /// Create a ring with the default amount of points per node and the given nodes
+    pub fn with_nodes<'a>(nodes: impl IntoIterator<Item = &'a str>) -> HashRing {
+        let mut ring = HashRing::default();
+        for node in nodes {
+            ring.add(node);
+        }
+        ring
+    }
+
+    /// Add the node into the ring with weight 1. Return false when it was already there
+    pub fn add(&mut self, node: &str) -> bool {
+        self.add_weighted(node, 1)
+    }
+
+    /// Add the node into the ring with `weight` times the points of a node with weight 1, like the
+    /// weight of its NodeCapacity. The weight is at least 1. Return false when it was already there
+    pub fn add_weighted(&mut self, node: &str, weight: usize) -> bool {
+        if self.nodes.contains_key(node) {
+            return false;
+        }
+        let weight = weight.max(1);
+        self.nodes.insert(node.to_string(), weight);
+        for index in 0..self.virtual_nodes * weight {
+            // on the unlikely collision of two points the smaller node keeps it, so every ring
+            // with the same nodes has the same owners
+            let point = self.points.entry(stable_hash(&format!("{}#{}", node, index))).or_insert_with(|| node.to_string());
+            if node < point.as_str() {
+                *point = node.to_string();
+            }
+        }
+        true
+    }
+
+    /// Return the weight of the node, None when it is not in the ring
+    pub fn weight(&self, node: &str) -> Option<usize> {
+        self.nodes.get(node).copied()
+    }
+
+    /// Remove the node from the ring. Return false when it was not there
+    pub fn remove(&mut self, node: &str) -> bool {
+        if self.nodes.remove(node).is_none() {
+            return false;
+        }
+        self.points.retain(|_, owner| owner != node);
+        // give back the points that the removed node won on a collision
+        let nodes: Vec<(String, usize)> = self.nodes.iter().map(|(other, weight)| (other.clone(), *weight)).collect();
+        for (other, weight) in nodes {
+            for index in 0..self.virtual_nodes * weight {
+                self.points.entry(stable_hash(&format!("{}#{}", other, index))).or_insert_with(|| other.clone());
+            }
+        }
+        true
+    }
+
+    /// Return the nodes of the ring, ordered by name
+    pub fn nodes(&self) -> impl Iterator<Item = &str> {
+        self.nodes.keys().map(String::as_str)
+    }
+
+    pub fn len(&self) -> usize {
+        self.nodes.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.nodes.is_empty()
+    }
+
+    /// Return the node that owns the key, None when the ring is empty
+    pub fn owner(&self, key: &str) -> Option<&str> {
+        let hash = stable_hash(key);
+        self.points.range(hash..).chain(self.points.range(..hash)).next().map(|(_, node)| node.as_str())
+    }
+
+    /// Return up to `count` distinct nodes for the key, in the order they follow its hash on the
+    /// ring. The first one is the owner and the others are the nodes that take the key over when
+    /// the ones before them leave
+    pub fn owners(&self, key: &str, count: usize) -> Vec<&str> {
+        let hash = stable_hash(key);
+        let mut owners: Vec<&str> = Vec::with_capacity(count.min(self.nodes.len()));
+        for (_, node) in self.points.range(hash..).chain(self.points.range(..hash)) {
+            if owners.len() == count.min(self.nodes.len()) {
+                break;
+            }
+            if !owners.contains(&node.as_str()) {
+                owners.push(node);
+            }
+        }
+        owners
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::{BTreeSet, HashMap};
+
+    use super::*;
+
+    fn assignment(ring: &HashRing, keys: &[String]) -> HashMap<String, String> {
+        keys.iter().map(|key| (key.clone(), ring.owner(key).unwrap().to_string())).collect()
+    }
+
+    #[test]
+    fn test_if_only_the_keys_of_the_changed_node_move() {
+        let keys: Vec<String> = (0..10_000).map(|index| format!("recipient-{}", index)).collect();
+        let nodes: Vec<String> = (0..10).map(|index| format!("b{}", index)).collect();
+        let mut ring = HashRing::with_nodes(nodes.iter().map(String::as_str));
+        let before = assignment(&ring, &keys);
+
+        // every node owns a fair share of the keys
+        for node in &nodes {
+            let owned = before.values().filter(|owner| *owner == node).count();
+            assert!((500..=1500).contains(&owned), "{} owns {} keys", node, owned);
+        }
+
+        assert!(ring.add("b10"));
+        let after = assignment(&ring, &keys);
+        let moved: Vec<&String> = keys.iter().filter(|key| before[*key] != after[*key]).collect();
+        assert!(moved.iter().all(|key| after[*key] == "b10"));
+        assert!(moved.len() < 2 * keys.len() / 11, "{} keys moved", moved.len());
+
+        assert!(ring.remove("b10"));
+        assert_eq!(assignment(&ring, &keys), before);
+        assert!(!ring.remove("b10"));
+    }
+
+    #[test]
+    fn test_if_nodes_own_keys_in_proportion_to_their_weights() {
+        let keys: Vec<String> = (0..20_000).map(|index| format!("recipient-{}", index)).collect();
+        let mut ring = HashRing::default();
+        ring.add_weighted("small", 1);
+        ring.add_weighted("large", 4);
+        assert_eq!(ring.weight("large"), Some(4));
+
+        let owned = assignment(&ring, &keys);
+        let large = owned.values().filter(|owner| *owner == "large").count();
+        // four fifths of the keys, give or take the unevenness of the points
+        assert!((14_000..=18_000).contains(&large), "large owns {} keys", large);
+
+        // the points of weight 1 are the ones of a node added without weight
+        let mut unweighted = HashRing::with_nodes(["small"]);
+        unweighted.add_weighted("large", 4);
+        assert_eq!(unweighted, ring);
+        assert!(ring.remove("large"));
+        assert_eq!(ring, HashRing::with_nodes(["small"]));
+    }
+
+    #[test]
+    fn test_if_owners_are_distinct_nodes_starting_at_the_owner() {
+        let ring = HashRing::with_nodes(["b1", "b2", "b3"]);
+        let owners = ring.owners("recipient", 5);
+        assert_eq!(owners.len(), 3);
+        assert_eq!(Some(owners[0]), ring.owner("recipient"));
+        assert_eq!(owners.iter().collect::<BTreeSet<_>>().len(), 3);
+        assert_eq!(HashRing::default().owner("recipient"), None);
+
+        // the hash is part of the routing contract with the clients, so it can not change
+        assert_eq!(stable_hash("recipient"), 0x310fbe882e32a5d9);
+        assert_eq!(stable_hash(""), 0xefd01f60ba992926);
+    }
+}
@@ -0,0 +1,128 @@
+use std::{
+    collections::HashMap,
+    sync::Mutex,
+    time::Instant,
+};
+
+use time::{Duration, OffsetDateTime};
+
+use crate::{log, utils::log::Level};
+
+use super::features::MEMBER_TIMEOUT;
+
+/// The header with the wall clock of the node that sent a cluster call, in milliseconds since the
+/// unix epoch
+pub const CLOCK_HEADER: &str = "X-Angler-Clock";
+
+/// How far the clock of a node can be from the one of the storage node when
+/// `cluster.clockSkew.threshold` is not set
+pub const DEFAULT_CLOCK_SKEW_THRESHOLD: Duration = Duration::seconds(2);
+
+/// Return the value of the CLOCK_HEADER for the time
+pub fn clock_header(now: OffsetDateTime) -> String {
+    (now.unix_timestamp_nanos() / 1_000_000).to_string()
+}
+
+#[derive(Debug, Clone, Copy)]
+struct NodeClock {
+    skew: Duration,
+    seen_at: Instant,
+}
+
+/// Measure how far the clocks of the nodes that call this one are from its clock, from the time
+/// they send in each call. The measure also counts the time the call took to arrive, that is
+/// small next to the threshold in a local network. The nodes whose skew is over the threshold
+/// are logged, as their retries would be scheduled at the wrong time, and are fenced, their store
+/// calls refused until their clock is fixed, when `fence` is on
+#[derive(Debug)]
+pub struct ClockSkewMonitor {
+    threshold: Duration,
+    fence: bool,
+    nodes: Mutex<HashMap<String, NodeClock>>,
+}
+
+impl Default for ClockSkewMonitor {
+    fn default() -> Self {
+        ClockSkewMonitor::new(DEFAULT_CLOCK_SKEW_THRESHOLD, false)
+    }
+}
+
+impl ClockSkewMonitor {
+    pub fn new(threshold: Duration, fence: bool) -> ClockSkewMonitor {
+        ClockSkewMonitor { threshold: threshold.abs(), fence, nodes: Mutex::new(HashMap::new()) }
+    }
+
+    pub fn threshold(&self) -> Duration {
+        self.threshold
+    }
+
+    /// Return if the nodes over the threshold are fenced
+    pub fn fences(&self) -> bool {
+        self.fence
+    }
+
+    /// Record the skew of the node from the CLOCK_HEADER of its call received at `now`, returning
+    /// it. Calls without the header, from older nodes, are not measured
+    pub fn observe(&self, node_id: Option<&str>, header: Option<&str>, now: OffsetDateTime) -> Option<Duration> {
+        let node_id = node_id.map(str::trim).filter(|node_id| !node_id.is_empty())?;
+        let sent_at = header.and_then(|header| header.trim().parse::<i64>().ok())
+            .and_then(|millis| OffsetDateTime::from_unix_timestamp_nanos(i128::from(millis) * 1_000_000).ok())?;
+        let skew = sent_at - now;
+
+        let mut nodes = self.nodes.lock().unwrap();
+        nodes.retain(|_, node| node.seen_at.elapsed() <= MEMBER_TIMEOUT);
+        let was_over = nodes.get(node_id).is_some_and(|node| node.skew.abs() > self.threshold);
+        let is_over = skew.abs() > self.threshold;
+        if is_over && !was_over {
+            let action = if self.fence { ", its store calls are refused until it is fixed" } else { "" };
+            log!(Level::Warn, "The clock of the node {} is {}ms from the one of this node, more than the cluster.clockSkew.threshold of {}ms{}",
+                node_id, skew.whole_milliseconds(), self.threshold.whole_milliseconds(), action);
+        } else if was_over && !is_over {
+            log!(Level::Info, "The clock of the node {} is {}ms from the one of this node again", node_id, skew.whole_milliseconds());
+        }
+        nodes.insert(node_id.to_string(), NodeClock { skew, seen_at: Instant::now() });
+        Some(skew)
+    }
+
+    /// Return the last skew measured for the node, positive when its clock is ahead
+    pub fn skew(&self, node_id: &str) -> Option<Duration> {
+        self.nodes.lock().unwrap().get(node_id).map(|node| node.skew)
+    }
+
+    /// Return if the calls of the node are refused, because fencing is on and its last skew is
+    /// over the threshold
+    pub fn is_fenced(&self, node_id: Option<&str>) -> bool {
+        self.fence && node_id.and_then(|node_id| self.skew(node_id.trim())).is_some_and(|skew| skew.abs() > self.threshold)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_if_skewed_clocks_are_measured_and_fenced() {
+        let now = OffsetDateTime::from_unix_timestamp(1_760_000_000).unwrap();
+        let monitor = ClockSkewMonitor::new(Duration::seconds(2), true);
+
+        let header = clock_header(now + Duration::milliseconds(1500));
+        assert_eq!(monitor.observe(Some("b1"), Some(&header), now), Some(Duration::milliseconds(1500)));
+        assert!(!monitor.is_fenced(Some("b1")));
+
+        let behind = clock_header(now - Duration::seconds(5));
+        assert_eq!(monitor.observe(Some("b1"), Some(&behind), now), Some(Duration::seconds(-5)));
+        assert!(monitor.is_fenced(Some("b1")));
+        assert_eq!(monitor.skew("b1"), Some(Duration::seconds(-5)));
+
+        // the clock was fixed
+        monitor.observe(Some("b1"), Some(&clock_header(now)), now);
+        assert!(!monitor.is_fenced(Some("b1")));
+
+        assert_eq!(monitor.observe(Some("b2"), None, now), None);
+        assert_eq!(monitor.observe(None, Some(&header), now), None);
+        assert!(!monitor.is_fenced(Some("b2")) && !monitor.is_fenced(None));
+        let not_fencing = ClockSkewMonitor::default();
+        not_fencing.observe(Some("b1"), Some(&behind), now);
+        assert!(!not_fencing.is_fenced(Some("b1")));
+    }
+}
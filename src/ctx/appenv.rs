@@ -1,35 +1,79 @@
-use std::{collections::HashSet, env, fmt::format, fs, path::{self, Path}, sync::OnceLock};
+use std::{collections::{HashMap, HashSet}, env, path::Path, process, sync::OnceLock};
 
 use clap::{Arg, ArgMatches, Command};
 
-use crate::ctx::config::properties_separate_by_semicolon_to_map;
+use crate::{
+    bench::bench_command,
+    cluster::join::cluster_command,
+    ctx::{completions::{completions_command, man_command}, config::{configuration_file_to_map, properties_separate_by_semicolon_to_map, ConfigurationFormat, ConfigurationLayers, ConfigurationValidationError}},
+    net::{admin::search::messages_command, client::routing::{export_command, import_command}},
+};
 
 use super::config::Configuration;
 
+/// Create the Command with all the arguments, flags and subcommands of the application
+pub fn app_command() -> Command {
+    Command::new("angler")
+        .version("0.1")
+        .about("Deliver the messages published by services to their recipients, retrying until they are delivered")
+        .arg(
+            Arg::new("dev")
+                .long("dev")
+                .help("Indicates that the application is running on Development context")
+                .action(clap::ArgAction::SetTrue)
+        )
+        .arg(
+            Arg::new("controller")
+                .long("controller")
+                .short('c')
+                .help("Indicates that this instance of node is the Controller instance")
+                .action(clap::ArgAction::SetTrue)
+        )
+        .arg(
+            Arg::new("config")
+                .long("config")
+                .value_name("PATH")
+                .help("Read the configuration from the file instead of the config file of the context, in the format of its extension")
+                .global(true)
+        )
+        .arg(
+            Arg::new("set")
+                .long("set")
+                .value_name("KEY=VALUE")
+                .help("Set the configuration, over the configuration file and the environment variables. Can be repeated")
+                .value_parser(parse_key_value)
+                .action(clap::ArgAction::Append)
+                .global(true)
+        )
+        .subcommand(bench_command())
+        .subcommand(cluster_command())
+        .subcommand(export_command())
+        .subcommand(import_command())
+        .subcommand(messages_command())
+        .subcommand(completions_command())
+        .subcommand(man_command())
+}
+
+/// Split a `--set` value into its key and value, at the first `=`
+fn parse_key_value(value: &str) -> Result<(String, String), String> {
+    match value.split_once('=') {
+        Some((key, value)) if !key.trim().is_empty() => Ok((key.trim().to_string(), value.trim().to_string())),
+        _ => Err(format!("{} should be a configuration like key=value", value)),
+    }
+}
+
+/// Return the configurations of the `--set` arguments, the last one of each key winning
+pub fn set_arguments_to_map(app_args: &ArgMatches) -> HashMap<String, String> {
+    app_args.get_many::<(String, String)>("set").into_iter().flatten().cloned().collect()
+}
+
 /**
  * Create a thread-safe instance of Command that contains all
  * arguments, flags and parameter of the application
  */
 pub fn app_args() -> &'static ArgMatches {
     static COMMAND: OnceLock<ArgMatches> = OnceLock::new();
-    COMMAND.get_or_init(|| {
-        Command::new("angler")
-            .version("0.1")
-            .arg(
-                Arg::new("dev")
-                    .long("dev")
-                    .help("Indicates that the application is running on Development context")
-                    .action(clap::ArgAction::SetTrue)
-            )
-            .arg(
-                Arg::new("controller")
-                    .long("controller")
-                    .short('c')
-                    .help("Indicates that this instance of node is the Controller instance")
-                    .action(clap::ArgAction::SetTrue)
-            )
-            .get_matches()
-    })
+    COMMAND.get_or_init(|| app_command().get_matches())
 }
 
 /// Return a thread-safe static reference of the AppEnvironment instance
@@ -42,25 +86,63 @@ pub fn app_env() -> &'static AppEnvironment {
             false => AppContexts::Production
         };
         
-        // loading configuration from configuration file
-        println!("{:?}", std::env::current_dir().unwrap());
-        let path_to_conf_file = context.path_to_conf_file();
-        println!("Loading configuration file from: {:?}", path_to_conf_file);
-        let mut configuration = Configuration::from_properties_file(path_to_conf_file).unwrap();
-        
+        // loading configuration from configuration file, the one of --config when set. The
+        // diagnostics go to the standard error so the output of the subcommands can be captured
+        let path_to_conf_file = app_args.get_one::<String>("config").cloned().unwrap_or_else(|| context.path_to_conf_file());
+        eprintln!("Loading configuration file from: {:?}", path_to_conf_file);
+        // each source overrides the keys of the ones before it, and they are all read at once
+        let mut layers = ConfigurationLayers::new();
+        match configuration_file_to_map(&path_to_conf_file) {
+            Ok(map) => layers.insert_map(map),
+            Err(err) => exit_with_invalid_configurations(&err.to_string(), &[]),
+        }
+
         // merging with conf from environment variable
         if let Ok(env_var_value) = env::var("ANGLER_CFG") {
-            println!("ANGLER_CFG environment variable found with value: {}", env_var_value);
-            configuration.merge(&Configuration::from_map(&properties_separate_by_semicolon_to_map(&env_var_value)));
+            // only the keys are printed, as the values may be secrets
+            let map = properties_separate_by_semicolon_to_map(&env_var_value);
+            let mut keys: Vec<&str> = map.keys().map(String::as_str).collect();
+            keys.sort();
+            eprintln!("ANGLER_CFG environment variable found with the configurations: {}", keys.join(", "));
+            layers.insert_map(map);
         }
         else {
-            println!("ANGLER_CFG environment variable not found");
+            eprintln!("ANGLER_CFG environment variable not found");
         }
 
-        AppEnvironment { context, configuration }
+        // the ANGLER__ environment variables override the file and ANGLER_CFG key by key
+        let names = layers.insert_environment(env::vars());
+        if !names.is_empty() {
+            eprintln!("Configurations set by the environment variables: {}", names.join(", "));
+        }
+
+        // and the --set arguments override them all
+        let set_arguments = set_arguments_to_map(app_args);
+        if !set_arguments.is_empty() {
+            let mut keys: Vec<&String> = set_arguments.keys().collect();
+            keys.sort();
+            eprintln!("Configurations set by the --set arguments: {}", keys.iter().map(|key| key.as_str()).collect::<Vec<_>>().join(", "));
+            layers.insert_map(set_arguments);
+        }
+        let configuration = match layers.to_configuration() {
+            Ok(configuration) => configuration,
+            Err(invalid) => exit_with_invalid_configurations("The configurations are invalid", &invalid),
+        };
+
+        let roles = configuration.cluster.node_roles();
+        AppEnvironment { context, configuration, path_to_conf_file, roles }
     })
 }
 
+/// Print why the configurations could not be loaded, with each invalid one, and exit
+fn exit_with_invalid_configurations(reason: &str, invalid: &[ConfigurationValidationError]) -> ! {
+    eprintln!("{}", reason);
+    for invalid in invalid {
+        eprintln!("  {}", invalid);
+    }
+    process::exit(2);
+}
+
 /// Store the context where the application is running
 #[derive(Debug)]
 pub enum AppContexts {
@@ -72,21 +154,53 @@ pub enum AppContexts {
 
 /// Default implementations for AppContexts
 impl AppContexts {
+    /// Return the path of the configuration file of the context: the first `config.properties`,
+    /// `config.toml`, `config.yaml` or `config.yml` of its directory that exists, read in the format
+    /// of its extension. The `config.properties` when none exists
     pub fn path_to_conf_file(&self) -> String {
+        let directory = self.conf_directory();
+        ConfigurationFormat::EXTENSIONS.iter()
+            .map(|(extension, _)| format!("{}/config.{}", directory, extension))
+            .find(|path| Path::new(path).is_file())
+            .unwrap_or_else(|| format!("{}/config.properties", directory))
+    }
+
+    fn conf_directory(&self) -> String {
         match self {
-            AppContexts::Development => format!("{}/src/dev/tests/resources/config.properties",  std::env::current_dir().unwrap().display()),
-            AppContexts::Production => String::from("./conf/config.properties"),
+            AppContexts::Development => format!("{}/src/dev/tests/resources", std::env::current_dir().unwrap().display()),
+            AppContexts::Production => String::from("./conf"),
         }
     }
 }
 
 /// The application roles defines witch functionalities will be made by the node
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
 pub enum ApplicationRoles {
+    /// Receive the messages of the clients and deliver them to the destinations
     MessageProcessor,
+    /// Keep the messages and serve them to the message-processor nodes over the cluster protocol
     Storage,
 }
 
+impl ApplicationRoles {
+    /// Return the name of the role used in `cluster.roles`
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ApplicationRoles::MessageProcessor => "messageProcessor",
+            ApplicationRoles::Storage => "storage",
+        }
+    }
+
+    /// Parse the name of the role used in `cluster.roles`
+    pub fn from_name(name: &str) -> Option<ApplicationRoles> {
+        match name {
+            "messageProcessor" => Some(ApplicationRoles::MessageProcessor),
+            "storage" => Some(ApplicationRoles::Storage),
+            _ => None,
+        }
+    }
+}
+
 /// Identify witch role this application will have in the Cluster
 pub enum NodeType {
     /// This instance runs as a broker so it never expose an direct API to connection
@@ -102,6 +216,8 @@ pub struct AppEnvironment {
     configuration: Configuration,
     /// Store the context where the app is currently executing
     context: AppContexts,
+    /// Store the path of the configuration file that was read
+    path_to_conf_file: String,
     /// Store all the roles that this application will have
     roles: HashSet<ApplicationRoles>,
 }
@@ -112,6 +228,50 @@ impl AppEnvironment {
         app_env()
     }
 
-    
+    /// Return the configuration used in the application
+    pub fn configuration(&self) -> &Configuration {
+        &self.configuration
+    }
+
+    /// Return the context where the app is currently executing
+    pub fn context(&self) -> &AppContexts {
+        &self.context
+    }
+
+    /// Return the path of the configuration file that was read, the one of `--config` when set
+    pub fn path_to_conf_file(&self) -> &str {
+        &self.path_to_conf_file
+    }
+
+    /// Return all the roles that this application will have
+    pub fn roles(&self) -> &HashSet<ApplicationRoles> {
+        &self.roles
+    }
 }
 
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_if_set_arguments_are_read_before_and_after_the_subcommands() {
+        let args = app_command().get_matches_from(["angler", "--set", "msgproc.workers=4", "--config", "/etc/angler/config.toml", "--set", "cluster.authKey = k=1", "--set", "msgproc.workers=8"]);
+        let set = set_arguments_to_map(&args);
+        assert_eq!((set.len(), set.get("msgproc.workers").map(String::as_str), set.get("cluster.authKey").map(String::as_str)), (2, Some("8"), Some("k=1")));
+        assert_eq!(args.get_one::<String>("config").map(String::as_str), Some("/etc/angler/config.toml"));
+        assert_eq!(Configuration::from_map(&set).unwrap().messages_processor.workers_count, Some(8));
+
+        // a --set key overrides only that key of the file
+        let mut layers = ConfigurationLayers::new();
+        layers.insert_map(properties_separate_by_semicolon_to_map("net.admin.port=2461; net.admin.tls=admin-cert; net.admin.maxConnections=64; net.admin.maxConnectionsPerIp=8"));
+        layers.insert_map(set_arguments_to_map(&app_command().get_matches_from(["angler", "--set", "net.admin.port=2470"])));
+        let admin = layers.to_configuration().unwrap().networking.admin.unwrap();
+        assert_eq!((admin.port, admin.tls.as_deref()), (2470, Some("admin-cert")));
+        assert_eq!((admin.limits.max_connections, admin.limits.max_connections_per_ip), (Some(64), Some(8)));
+
+        let args = app_command().get_matches_from(["angler", "messages", "search", "--set", "net.admin.port=2470"]);
+        assert_eq!(set_arguments_to_map(&args).get("net.admin.port").map(String::as_str), Some("2470"));
+        assert!(app_command().try_get_matches_from(["angler", "--set", "msgproc.workers"]).is_err());
+    }
+}
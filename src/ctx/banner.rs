@@ -0,0 +1,134 @@
+use std::net::SocketAddr;
+
+use crate::{cluster::features::local_node_id, log, utils::log::Level};
+
+use super::{appenv::ApplicationRoles, config::Configuration};
+
+/// What a node runs with, logged when it starts so the effective configuration can be checked in
+/// its logs, with the warnings about the configurations that are unsafe in production
+#[derive(Debug, Clone, PartialEq)]
+pub struct StartupSummary {
+    pub version: &'static str,
+    pub node_id: String,
+    pub roles: Vec<ApplicationRoles>,
+    /// The addresses opened by the node, by listener name
+    pub listeners: Vec<(&'static str, SocketAddr)>,
+    /// Where the messages are kept: `memory` or the URL of the storage node
+    pub storage: String,
+    /// How many workers send the messages, None on the nodes that only store them
+    pub workers: Option<usize>,
+    pub configuration_file: Option<String>,
+    admin_auth: bool,
+    cluster_auth: bool,
+}
+
+impl StartupSummary {
+    pub fn new(configuration: &Configuration, roles: &[ApplicationRoles]) -> StartupSummary {
+        let mut roles = roles.to_vec();
+        roles.sort_by_key(|role| role.as_str());
+        let storage = match &configuration.cluster.storage_url {
+            Some(url) if !roles.contains(&ApplicationRoles::Storage) => url.clone(),
+            _ => String::from("memory"),
+        };
+        StartupSummary {
+            version: env!("CARGO_PKG_VERSION"),
+            node_id: local_node_id().to_string(),
+            roles,
+            listeners: Vec::new(),
+            storage,
+            workers: None,
+            configuration_file: None,
+            admin_auth: configuration.networking.admin_auth_token.is_some(),
+            cluster_auth: configuration.cluster.auth_key.is_some(),
+        }
+    }
+
+    pub fn with_listener(mut self, name: &'static str, address: SocketAddr) -> StartupSummary {
+        self.listeners.push((name, address));
+        self
+    }
+
+    pub fn with_workers(mut self, workers: usize) -> StartupSummary {
+        self.workers = Some(workers);
+        self
+    }
+
+    pub fn with_configuration_file(mut self, path: &str) -> StartupSummary {
+        self.configuration_file = Some(path.to_string());
+        self
+    }
+
+    /// Return the summary as `key=value` pairs separated by spaces
+    pub fn line(&self) -> String {
+        let roles: Vec<&str> = self.roles.iter().map(ApplicationRoles::as_str).collect();
+        let mut fields = vec![
+            format!("version={}", self.version),
+            format!("nodeId={}", self.node_id),
+            format!("roles={}", roles.join(",")),
+        ];
+        fields.extend(self.listeners.iter().map(|(name, address)| format!("{}Listener={}", name, address)));
+        fields.push(format!("storage={}", self.storage));
+        fields.extend(self.workers.map(|workers| format!("workers={}", workers)));
+        fields.extend(self.configuration_file.as_ref().map(|path| format!("configuration={}", path)));
+        fields.join(" ")
+    }
+
+    /// Return the warnings about the configurations that are unsafe in production
+    pub fn warnings(&self) -> Vec<String> {
+        let mut warnings = Vec::new();
+        for (name, address) in self.listeners.iter().filter(|(_, address)| !address.ip().is_loopback()) {
+            warnings.push(format!("The {} listener {} is reachable from other hosts and serves plain HTTP, without TLS", name, address));
+        }
+        let is_public = |listener: &str| self.listeners.iter().any(|(name, address)| *name == listener && !address.ip().is_loopback());
+        if is_public("admin") && !self.admin_auth {
+            warnings.push(String::from("The admin API is reachable from other hosts without a token, set net.admin.authToken"));
+        }
+        if (is_public("storage") || self.storage != "memory") && !self.cluster_auth {
+            warnings.push(String::from("The calls between the nodes are not authenticated, set cluster.authKey"));
+        }
+        if self.storage == "memory" {
+            warnings.push(String::from("The messages are kept in memory and are lost when the node stops"));
+        }
+        warnings
+    }
+
+    /// Log the summary, then each warning
+    pub fn log(&self) {
+        log!(Level::Info, "Angler started: {}", self.line());
+        for warning in self.warnings() {
+            log!(Level::Warn, "{}", warning);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_if_the_summary_lists_the_node_and_warns_about_unsafe_configurations() {
+        let mut configuration = Configuration::new();
+        let summary = StartupSummary::new(&configuration, &[ApplicationRoles::Storage, ApplicationRoles::MessageProcessor])
+            .with_listener("client", "0.0.0.0:2460".parse().unwrap())
+            .with_listener("admin", "127.0.0.1:2461".parse().unwrap())
+            .with_workers(8)
+            .with_configuration_file("./conf/config.properties");
+        assert_eq!(summary.line(), format!("version={} nodeId={} roles=messageProcessor,storage clientListener=0.0.0.0:2460 adminListener=127.0.0.1:2461 storage=memory workers=8 configuration=./conf/config.properties", summary.version, local_node_id()));
+        assert_eq!(summary.warnings(), vec![
+            String::from("The client listener 0.0.0.0:2460 is reachable from other hosts and serves plain HTTP, without TLS"),
+            String::from("The messages are kept in memory and are lost when the node stops"),
+        ]);
+
+        configuration.cluster.storage_url = Some(String::from("http://storage-1:2462"));
+        let processor = StartupSummary::new(&configuration, &[ApplicationRoles::MessageProcessor])
+            .with_listener("admin", "[::]:2461".parse().unwrap());
+        assert_eq!(processor.storage, "http://storage-1:2462");
+        assert_eq!(processor.warnings().len(), 3);
+        assert!(processor.warnings()[1].contains("net.admin.authToken") && processor.warnings()[2].contains("cluster.authKey"));
+
+        configuration.cluster.auth_key = Some(String::from("abcd1234"));
+        configuration.networking.admin_auth_token = Some(String::from("s3cr3t"));
+        let secured = StartupSummary::new(&configuration, &[ApplicationRoles::MessageProcessor]).with_listener("client", "127.0.0.1:2460".parse().unwrap());
+        assert!(secured.warnings().is_empty());
+    }
+}
@@ -0,0 +1,272 @@
+use clap::{Arg, ArgMatches, Command};
+
+/// The shells `completions` writes scripts for
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Shell {
+    Bash,
+    Zsh,
+    Fish,
+    PowerShell,
+}
+
+impl Shell {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Shell::Bash => "bash",
+            Shell::Zsh => "zsh",
+            Shell::Fish => "fish",
+            Shell::PowerShell => "powershell",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Shell> {
+        match name {
+            "bash" => Some(Shell::Bash),
+            "zsh" => Some(Shell::Zsh),
+            "fish" => Some(Shell::Fish),
+            "powershell" => Some(Shell::PowerShell),
+            _ => None,
+        }
+    }
+}
+
+/// The `completions` subcommand, that prints the completion script of a shell
+pub fn completions_command() -> Command {
+    Command::new("completions")
+        .about("Print the script that completes the subcommands and the options of angler in a shell")
+        .arg(Arg::new("shell").required(true).value_parser(["bash", "zsh", "fish", "powershell"])
+            .help("The shell of the script"))
+}
+
+/// The `man` subcommand, that prints the manual page
+pub fn man_command() -> Command {
+    Command::new("man")
+        .about("Print the manual page of angler, in roff. Example: angler man > /usr/local/share/man/man1/angler.1")
+}
+
+/// A command of the tree, with the words that are completed after it
+struct CompletionNode {
+    /// The names of the command and its parents joined by `__`, like `angler__export__routing`
+    id: String,
+    /// The subcommands, options and possible values, with their help
+    words: Vec<(String, String)>,
+    /// The name and the id of each subcommand
+    children: Vec<(String, String)>,
+}
+
+/// Return the text of the help of the argument, on one line
+fn help_of(arg: &Arg) -> String {
+    arg.get_help().map(|help| help.to_string().replace('\n', " ")).unwrap_or_default()
+}
+
+/// Return the commands of the tree, the root first
+fn completion_nodes(command: &Command) -> Vec<CompletionNode> {
+    fn visit(command: &Command, id: String, nodes: &mut Vec<CompletionNode>) {
+        let mut words = Vec::new();
+        let visible: Vec<&Command> = command.get_subcommands().filter(|subcommand| !subcommand.is_hide_set()).collect();
+        // the help subcommand has the same tree as the command, so only its name is completed
+        let nested: Vec<&Command> = visible.iter().copied().filter(|subcommand| subcommand.get_name() != "help").collect();
+        let children = nested.iter().map(|subcommand| (subcommand.get_name().to_string(), format!("{}__{}", id, subcommand.get_name()))).collect();
+        for subcommand in &visible {
+            words.push((subcommand.get_name().to_string(), subcommand.get_about().map(|about| about.to_string()).unwrap_or_default()));
+        }
+        for arg in command.get_arguments().filter(|arg| !arg.is_hide_set()) {
+            if arg.is_positional() {
+                words.extend(arg.get_possible_values().iter().filter(|value| !value.is_hide_set())
+                    .map(|value| (value.get_name().to_string(), value.get_help().map(|help| help.to_string()).unwrap_or_else(|| help_of(arg)))));
+            } else {
+                words.extend(arg.get_long().map(|long| (format!("--{}", long), help_of(arg))));
+                words.extend(arg.get_short().map(|short| (format!("-{}", short), help_of(arg))));
+            }
+        }
+        nodes.push(CompletionNode { id: id.clone(), words, children });
+        for subcommand in nested {
+            visit(subcommand, format!("{}__{}", id, subcommand.get_name()), nodes);
+        }
+    }
+
+    let mut command = command.clone();
+    // adds the --help, --version and help that clap generates
+    command.build();
+    let mut nodes = Vec::new();
+    visit(&command, command.get_name().to_string(), &mut nodes);
+    nodes
+}
+
+/// Return the completion script of the command for the shell
+pub fn completion_script(command: &Command, shell: Shell) -> String {
+    let nodes = completion_nodes(command);
+    let name = command.get_name();
+    let transitions = || nodes.iter().flat_map(|node| node.children.iter().map(move |(child, id)| (node, child, id)));
+    let mut script = String::new();
+    match shell {
+        Shell::Bash => {
+            script.push_str(&format!("_{}() {{\n    local cur=\"${{COMP_WORDS[COMP_CWORD]}}\" path_id={} word\n", name, name));
+            script.push_str("    for word in \"${COMP_WORDS[@]:1:COMP_CWORD-1}\"; do\n        case \"${path_id} ${word}\" in\n");
+            for (node, child, id) in transitions() {
+                script.push_str(&format!("            \"{} {}\") path_id={} ;;\n", node.id, child, id));
+            }
+            script.push_str("        esac\n    done\n    case \"${path_id}\" in\n");
+            for node in &nodes {
+                let words: Vec<&str> = node.words.iter().map(|(word, _)| word.as_str()).collect();
+                script.push_str(&format!("        {}) COMPREPLY=($(compgen -W \"{}\" -- \"${{cur}}\")) ;;\n", node.id, words.join(" ")));
+            }
+            script.push_str(&format!("    esac\n}}\ncomplete -o default -F _{} {}\n", name, name));
+        }
+        Shell::Zsh => {
+            let quote = |text: &str| format!("'{}'", text.replace('\'', "'\\''"));
+            script.push_str(&format!("#compdef {}\n\n_{}() {{\n    local path_id={} word\n    local -a candidates\n", name, name, name));
+            script.push_str("    for word in \"${(@)words[2,CURRENT-1]}\"; do\n        case \"${path_id} ${word}\" in\n");
+            for (node, child, id) in transitions() {
+                script.push_str(&format!("            (\"{} {}\") path_id={} ;;\n", node.id, child, id));
+            }
+            script.push_str("        esac\n    done\n    case \"${path_id}\" in\n");
+            for node in &nodes {
+                let words: Vec<String> = node.words.iter().map(|(word, help)| quote(&format!("{}:{}", word, help))).collect();
+                script.push_str(&format!("        ({}) candidates=({}) ;;\n", node.id, words.join(" ")));
+            }
+            script.push_str(&format!("    esac\n    _describe -t commands '{}' candidates || _files\n}}\n\n", name));
+            script.push_str(&format!("if [ \"$funcstack[1]\" = \"_{}\" ]; then\n    _{} \"$@\"\nelse\n    compdef _{} {}\nfi\n", name, name, name, name));
+        }
+        Shell::Fish => {
+            let quote = |text: &str| format!("'{}'", text.replace('\\', "\\\\").replace('\'', "\\'"));
+            script.push_str(&format!("function __fish_{}_path\n    set -l path_id {}\n    for word in (commandline -opc)[2..-1]\n        switch \"$path_id $word\"\n", name, name));
+            for (node, child, id) in transitions() {
+                script.push_str(&format!("            case '{} {}'\n                set path_id {}\n", node.id, child, id));
+            }
+            script.push_str("        end\n    end\n    echo $path_id\nend\n\n");
+            script.push_str(&format!("complete -c {} -f\n", name));
+            for node in &nodes {
+                let condition = format!("'test (__fish_{}_path) = {}'", name, node.id);
+                for (word, help) in &node.words {
+                    let (flag, value) = match (word.strip_prefix("--"), word.strip_prefix('-')) {
+                        (Some(long), _) => ("-l", long),
+                        (None, Some(short)) => ("-s", short),
+                        (None, None) => ("-a", word.as_str()),
+                    };
+                    script.push_str(&format!("complete -c {} -n {} {} {} -d {}\n", name, condition, flag, quote(value), quote(help)));
+                }
+            }
+        }
+        Shell::PowerShell => {
+            let quote = |text: &str| format!("'{}'", text.replace('\'', "''"));
+            script.push_str(&format!("Register-ArgumentCompleter -Native -CommandName '{}', '{}.exe' -ScriptBlock {{\n", name, name));
+            script.push_str("    param($wordToComplete, $commandAst, $cursorPosition)\n");
+            script.push_str(&format!("    $pathId = '{}'\n", name));
+            script.push_str("    foreach ($element in $commandAst.CommandElements | Select-Object -Skip 1) {\n");
+            script.push_str("        if ($element.Extent.EndOffset -ge $cursorPosition) { break }\n");
+            script.push_str("        switch (\"$pathId $($element.ToString())\") {\n");
+            for (node, child, id) in transitions() {
+                script.push_str(&format!("            '{} {}' {{ $pathId = '{}' }}\n", node.id, child, id));
+            }
+            script.push_str("        }\n    }\n    $candidates = @(switch ($pathId) {\n");
+            for node in &nodes {
+                script.push_str(&format!("        '{}' {{\n", node.id));
+                for (word, help) in &node.words {
+                    let help = if help.is_empty() { word } else { help };
+                    script.push_str(&format!("            [pscustomobject]@{{ Name = {}; Help = {} }}\n", quote(word), quote(help)));
+                }
+                script.push_str("        }\n");
+            }
+            script.push_str("    })\n    $candidates | Where-Object { $_.Name -like \"$wordToComplete*\" } | ForEach-Object {\n");
+            script.push_str("        [System.Management.Automation.CompletionResult]::new($_.Name, $_.Name, 'ParameterValue', $_.Help)\n    }\n}\n");
+        }
+    }
+    script
+}
+
+/// Escape the text for roff, so its dashes, backslashes and leading dots are printed as they are
+fn roff(text: &str) -> String {
+    let escaped = text.replace('\\', "\\\\").replace('-', "\\-");
+    if escaped.starts_with(['.', '\'']) { format!("\\&{}", escaped) } else { escaped }
+}
+
+/// Return the manual page of the command, in roff, with a section for each of its subcommands
+pub fn man_page(command: &Command) -> String {
+    fn options(command: &Command, page: &mut String) {
+        for arg in command.get_arguments().filter(|arg| !arg.is_hide_set()) {
+            let value = arg.get_value_names().and_then(|names| names.first()).map(|name| name.to_string()).unwrap_or_else(|| arg.get_id().to_string().to_uppercase());
+            let takes_value = arg.get_action().takes_values();
+            let name = match (arg.is_positional(), arg.get_short(), arg.get_long()) {
+                (true, _, _) => format!("\\fI<{}>\\fR", roff(&value)),
+                (false, short, long) => {
+                    let names: Vec<String> = short.map(|short| format!("\\fB\\-{}\\fR", short)).into_iter()
+                        .chain(long.map(|long| format!("\\fB\\-\\-{}\\fR", roff(long))))
+                        .collect();
+                    let value = if takes_value { format!(" \\fI<{}>\\fR", roff(&value)) } else { String::new() };
+                    format!("{}{}", names.join(", "), value)
+                }
+            };
+            page.push_str(&format!(".TP\n{}\n{}\n", name, roff(&help_of(arg))));
+        }
+    }
+    fn subcommands(command: &Command, path: &str, page: &mut String) {
+        for subcommand in command.get_subcommands().filter(|subcommand| !subcommand.is_hide_set() && subcommand.get_name() != "help") {
+            let path = format!("{} {}", path, subcommand.get_name());
+            page.push_str(&format!(".SS \"{}\"\n", roff(&path)));
+            if let Some(about) = subcommand.get_about() {
+                page.push_str(&format!("{}\n", roff(&about.to_string())));
+            }
+            options(subcommand, page);
+            subcommands(subcommand, &path, page);
+        }
+    }
+
+    let mut command = command.clone();
+    command.build();
+    let name = command.get_name().to_string();
+    let version = command.get_version().unwrap_or_default();
+    let mut page = format!(".TH {} 1 \"\" \"{} {}\" \"User Commands\"\n", name.to_uppercase(), roff(&name), roff(version));
+    page.push_str(&format!(".SH NAME\n{} \\- {}\n", roff(&name), roff(&command.get_about().map(|about| about.to_string()).unwrap_or_default())));
+    page.push_str(&format!(".SH SYNOPSIS\n\\fB{}\\fR [\\fIOPTIONS\\fR] [\\fICOMMAND\\fR]\n", roff(&name)));
+    page.push_str(".SH OPTIONS\n");
+    options(&command, &mut page);
+    page.push_str(".SH COMMANDS\n");
+    subcommands(&command, &name, &mut page);
+    page
+}
+
+/// Run `completions`, returning the script of the shell for the command
+pub fn run_completions(args: &ArgMatches, command: &Command) -> Result<String, String> {
+    let shell = args.get_one::<String>("shell").unwrap();
+    let shell = Shell::from_name(shell).ok_or_else(|| format!("{} is not a supported shell", shell))?;
+    Ok(completion_script(command, shell))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn command() -> Command {
+        Command::new("angler")
+            .version("0.1")
+            .about("Deliver messages")
+            .arg(Arg::new("dev").long("dev").help("Run in the 'dev' context").action(clap::ArgAction::SetTrue))
+            .subcommand(Command::new("export").about("Print the configuration")
+                .subcommand(Command::new("routing").about("Print the destinations").arg(Arg::new("url").long("url").help("The URL"))))
+            .subcommand(completions_command())
+    }
+
+    #[test]
+    fn test_if_completions_and_the_man_page_list_every_subcommand() {
+        let nodes = completion_nodes(&command());
+        assert_eq!(nodes.iter().map(|node| node.id.as_str()).collect::<Vec<_>>(), vec!["angler", "angler__export", "angler__export__routing", "angler__completions"]);
+        assert_eq!(nodes[1].words.iter().map(|(word, _)| word.as_str()).collect::<Vec<_>>(), vec!["routing", "help", "--help", "-h"]);
+        assert_eq!(nodes[3].words.iter().map(|(word, _)| word.as_str()).collect::<Vec<_>>(), vec!["bash", "zsh", "fish", "powershell", "--help", "-h"]);
+
+        let bash = completion_script(&command(), Shell::Bash);
+        assert!(bash.contains("            \"angler__export routing\") path_id=angler__export__routing ;;\n"));
+        assert!(bash.contains("        angler__export__routing) COMPREPLY=($(compgen -W \"--url --help -h\" -- \"${cur}\")) ;;\n"));
+        assert!(bash.ends_with("complete -o default -F _angler angler\n"));
+        assert!(completion_script(&command(), Shell::Zsh).contains("(angler) candidates=('export:Print the configuration' 'completions:"));
+        assert!(completion_script(&command(), Shell::Zsh).contains("'--dev:Run in the '\\''dev'\\'' context'"));
+        assert!(completion_script(&command(), Shell::Fish).contains("complete -c angler -n 'test (__fish_angler_path) = angler' -l 'dev' -d 'Run in the \\'dev\\' context'\n"));
+        assert!(completion_script(&command(), Shell::PowerShell).contains("[pscustomobject]@{ Name = '--dev'; Help = 'Run in the ''dev'' context' }"));
+
+        let page = man_page(&command());
+        assert!(page.starts_with(".TH ANGLER 1 \"\" \"angler 0.1\" \"User Commands\"\n.SH NAME\nangler \\- Deliver messages\n"));
+        assert!(page.contains(".SS \"angler export routing\"\nPrint the destinations\n.TP\n\\fB\\-\\-url\\fR \\fI<URL>\\fR\nThe URL\n"));
+        assert!(page.contains(".TP\n\\fI<SHELL>\\fR\nThe shell of the script\n"));
+        assert!(!page.contains("angler help"));
+    }
+}
@@ -1,12 +1,12 @@
-use std::{collections::{HashMap, HashSet}, error, fmt::Display, fs, path::Path};
+use std::{collections::{HashMap, HashSet}, fmt::Display, fs, net::{IpAddr, SocketAddr}, path::Path};
 
 use thiserror::Error;
 use time::Duration;
 
-use crate::utils::time::{DurationDeserializer, DurationSequence, DurationSequenceDeserializer};
+use crate::{ctx::appenv::ApplicationRoles, msgproc::{id::{IdGeneratorKind, MAX_SNOWFLAKE_NODE_ID}, retry::RetryOn, topic::OrderingMode}, net::{auth::AuthProviderKind, http::{default_listener_address, ConnectionLimits}, storage::ClusterCompression}, utils::{json::JsonValue, time::{DurationDeserializer, DurationSequence, DurationSequenceDeserializer}, toml::parse_toml, yaml::parse_yaml}};
 
 /// Store cluster configurations nominated by `cluster.` prefix
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct ClusterConfiguration {
     /// The authentication token used to authenticate brokers into the cluster
     pub auth_key: Option<String>,
@@ -16,6 +16,56 @@ pub struct ClusterConfiguration {
 
     /// The duration that a request should have to be sent until it is considered a timeout
     pub request_timeout: Option<Duration>,
+
+    /// The roles of this node, set by `cluster.roles=messageProcessor,storage`. A node has all the
+    /// roles when it is not set
+    pub roles: Option<HashSet<ApplicationRoles>>,
+
+    /// The listener where a storage node serves its store to the message-processor nodes, set by
+    /// `cluster.storage.address` or `cluster.storage.port`
+    pub storage: Option<ListenerConfig>,
+
+    /// The base URL of the storage node used by the message-processor nodes without the storage
+    /// role, like `http://storage-1:2462`
+    pub storage_url: Option<String>,
+
+    /// The base URLs of the replicas of a storage node, set by `cluster.storage.replicas`. The node
+    /// repairs them with the messages they are missing
+    pub storage_replicas: Option<Vec<String>>,
+
+    /// The base URL of a replica of the storage node, set by `cluster.storage.readUrl`. The status
+    /// queries of the RESTful API are served by it instead of the storage node
+    pub storage_read_url: Option<String>,
+
+    /// How often a storage node compares its store with its replicas
+    pub anti_entropy_interval: Option<Duration>,
+
+    /// How the bodies of the cluster calls are compressed, set by `cluster.compression=none|lz4`
+    pub compression: Option<ClusterCompression>,
+
+    /// A single-use token created by `angler cluster create-join-token`, set by `cluster.joinToken`.
+    /// A node without the storage role nor the `cluster.authKey` trades it for its own credential
+    pub join_token: Option<String>,
+
+    /// The IDs of the join tokens whose node credentials are refused by the storage nodes, set by
+    /// `cluster.revokedJoinTokens`
+    pub revoked_join_tokens: Option<HashSet<String>>,
+
+    /// How often the connections to the storage node are pinged, set by `cluster.keepalive.interval`.
+    /// The connections are only kept open between the calls when it is set
+    pub keepalive_interval: Option<Duration>,
+
+    /// How many pings in a row the storage node can miss before it is considered unreachable, set
+    /// by `cluster.keepalive.threshold`
+    pub keepalive_threshold: Option<u32>,
+
+    /// How far the clock of a node can be from the one of the storage node before it is warned
+    /// about, set by `cluster.clockSkew.threshold`
+    pub clock_skew_threshold: Option<Duration>,
+
+    /// If the store calls of the nodes over the `cluster.clockSkew.threshold` are refused, set by
+    /// `cluster.clockSkew.fence`
+    pub clock_skew_fence: Option<bool>,
 }
 
 impl ClusterConfiguration {
@@ -23,69 +73,275 @@ impl ClusterConfiguration {
         ClusterConfiguration {
             auth_key: None,
             controller_host: None,
-            request_timeout: None
+            request_timeout: None,
+            roles: None,
+            storage: None,
+            storage_url: None,
+            storage_replicas: None,
+            storage_read_url: None,
+            anti_entropy_interval: None,
+            compression: None,
+            join_token: None,
+            revoked_join_tokens: None,
+            keepalive_interval: None,
+            keepalive_threshold: None,
+            clock_skew_threshold: None,
+            clock_skew_fence: None,
         }
     }
+
+    /// Return the roles of this node, all of them when `cluster.roles` is not set
+    pub fn node_roles(&self) -> HashSet<ApplicationRoles> {
+        self.roles.clone().unwrap_or_else(|| HashSet::from([ApplicationRoles::MessageProcessor, ApplicationRoles::Storage]))
+    }
 }
 
 /// Store database configurations nominated by `db.` prefix
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct DatabaseConfigurations {
     /// The amount of time where dead messages will be stored until it will be deleted
-    dead_messages_retention: Option<Duration>,
+    pub dead_messages_retention: Option<Duration>,
 
     /// The amount of time where delivered messages will be stored until it will be deleted
-    delivered_messages_retention: Option<Duration>,
+    pub delivered_messages_retention: Option<Duration>,
+
+    /// The maximum amount of writes (status updates and attempt records) grouped into a single store write
+    pub write_batch_size: Option<usize>,
+
+    /// The maximum amount of time that a write can wait in the batch before it is flushed into the store
+    pub write_flush_interval: Option<Duration>,
+
+    /// The payload JSON fields indexed for search by topic. Set by `db.payloadIndex.<eventId>=field,other.field`
+    pub payload_index: Option<HashMap<String, Vec<String>>>,
+
+    /// How many messages read by ID are cached in front of the store, set by `db.cache.capacity`
+    pub cache_capacity: Option<usize>,
 }
 
 impl DatabaseConfigurations {
     fn new() -> DatabaseConfigurations {
         DatabaseConfigurations {
             dead_messages_retention: None,
-            delivered_messages_retention: None
+            delivered_messages_retention: None,
+            write_batch_size: None,
+            write_flush_interval: None,
+            payload_index: None,
+            cache_capacity: None,
         }
     }
 }
 
 /// Store configurations about the message processor nominated by `msgproc.` prefix
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct MessagesProcessorConfigurations {
     /// The duration that a message should wait in delivery process until it is considered a timeout
-    message_delivery_timeout: Option<Duration>,
+    pub message_delivery_timeout: Option<Duration>,
 
     /// The amount of workers that the broker should make available to send messages
-    workers_count: Option<usize>,
+    pub workers_count: Option<usize>,
+
+    /// How long a producer message ID is remembered to drop duplicated publishes of the same topic
+    pub dedup_window: Option<Duration>,
+
+    /// The maximum size, in bytes, of a published payload. Larger payloads are rejected
+    pub max_payload_size: Option<usize>,
+
+    /// The path of the JSON Schema file that the payloads of each event ID should conform to
+    pub topic_schemas: Option<HashMap<String, String>>,
+
+    /// How long the addresses resolved for the destinations are cached. Zero disables the cache
+    pub dns_ttl: Option<Duration>,
+
+    /// How long a failed resolution is cached before the host is resolved again
+    pub dns_negative_ttl: Option<Duration>,
+
+    /// How long a deleted destination can be restored. Zero removes the destinations at once
+    pub delete_grace_period: Option<Duration>,
+
+    /// The URL of the client API the receivers reach, where they confirm the messages accepted with `202`
+    pub ack_callback_url: Option<String>,
+
+    /// How often the usage of each namespace is recorded in the store. None does not record it
+    pub usage_interval: Option<Duration>,
+
+    /// How the IDs of the published messages are created, set by `msgproc.ids.generator=uuidv7|snowflake`
+    pub id_generator: Option<IdGeneratorKind>,
+
+    /// The node ID of the Snowflake IDs of this node, from 0 to 1023, set by `msgproc.ids.nodeId`
+    pub snowflake_node_id: Option<u16>,
+
+    /// How long the oldest due message of a destination can wait before it is lagging, set by
+    /// `msgproc.lagging.backlogAge`
+    pub lagging_backlog_age: Option<Duration>,
+
+    /// How long the responses of a destination can take on average before it is lagging, set by
+    /// `msgproc.lagging.latency`
+    pub lagging_latency: Option<Duration>,
+
+    /// If the lagging destinations take fewer turns of the workers, set by `msgproc.lagging.demote`
+    pub lagging_demote: Option<bool>,
+
+    /// The template of the `User-Agent` of the deliveries, with the `{version}` and `{nodeId}`
+    /// variables, set by `msgproc.delivery.userAgent`
+    pub delivery_user_agent: Option<String>,
+
+    /// The headers sent by every delivery to identify this Angler, by name, set by the
+    /// `msgproc.delivery.headers.<name>` configurations
+    pub delivery_headers: Option<HashMap<String, String>>,
+
+    /// The recipient of the notifications of the messages that die or expire, set by
+    /// `msgproc.deathNotifications.recipientId`
+    pub death_notifications_recipient_id: Option<String>,
+
+    /// The `eventId` of the death notifications, set by `msgproc.deathNotifications.eventId`
+    pub death_notifications_event_id: Option<String>,
+
+    /// The recipient of the death notifications of each namespace, by its serviceId, set by the
+    /// `msgproc.deathNotifications.namespace.<serviceId>.recipientId` configurations
+    pub death_notifications_namespaces: Option<HashMap<String, String>>,
 }
 
 impl MessagesProcessorConfigurations {
     fn new() -> MessagesProcessorConfigurations {
         MessagesProcessorConfigurations {
             message_delivery_timeout: None,
-            workers_count: None
+            workers_count: None,
+            dedup_window: None,
+            max_payload_size: None,
+            topic_schemas: None,
+            dns_ttl: None,
+            dns_negative_ttl: None,
+            delete_grace_period: None,
+            ack_callback_url: None,
+            usage_interval: None,
+            id_generator: None,
+            snowflake_node_id: None,
+            lagging_backlog_age: None,
+            lagging_latency: None,
+            lagging_demote: None,
+            delivery_user_agent: None,
+            delivery_headers: None,
+            death_notifications_recipient_id: None,
+            death_notifications_event_id: None,
+            death_notifications_namespaces: None,
         }
     }
 }
 
 /// Store networking configuration nominated by `net.` prefix
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct NetworkingConfiguration {
     /// The protocols that will be opened to the client API. Supported values are: `restful`
-    client_protocols: Option<HashSet<String>>,
+    pub client_protocols: Option<HashSet<String>>,
 
-    /// The port that will be used to expose the RESTFul API when set in `net.client.protocols` config.
-    restful_port: Option<u32>,
+    /// The listener of the RESTFul API when set in `net.client.protocols` config. It is set by
+    /// `net.client.restful.address` or `net.client.restful.port`
+    pub restful: Option<ListenerConfig>,
+
+    /// The listener of the admin API, set by `net.admin.address` or `net.admin.port`. The admin API
+    /// is only opened when it is set
+    pub admin: Option<ListenerConfig>,
+
+    /// The token required by the admin API. When it is set every admin request must send it as a
+    /// `Bearer` token or as the password of a `Basic` authorization
+    pub admin_auth_token: Option<String>,
+
+    /// How the requests of the client API are authenticated, set by
+    /// `net.client.auth.provider=apiKeys|jwt`. The client API is not authenticated when it is not set
+    pub client_auth_provider: Option<AuthProviderKind>,
+
+    /// The `(subject, key)` pairs accepted by the `apiKeys` provider, set by
+    /// `net.client.auth.apiKeys=billing:k-1, shop:k-2`
+    pub client_api_keys: Option<Vec<(String, String)>>,
+
+    /// The secret of the HS256 tokens accepted by the `jwt` provider
+    pub client_jwt_secret: Option<String>,
 }
 
 impl NetworkingConfiguration {
     fn new() -> NetworkingConfiguration {
         NetworkingConfiguration {
             client_protocols: None,
-            restful_port: None,
+            restful: None,
+            admin: None,
+            admin_auth_token: None,
+            client_auth_provider: None,
+            client_api_keys: None,
+            client_jwt_secret: None,
+        }
+    }
+}
+
+/// The address a listener binds to, set by the `address`, `port` and `tls` keys of the listener,
+/// and the limits of its connections, set by `maxConnections`, `acceptBacklog` and `maxConnectionsPerIp`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ListenerConfig {
+    pub ip: IpAddr,
+    pub port: u16,
+    /// The name of the TLS certificate of the listener. The listeners only serve plain HTTP for
+    /// now, so Angler refuses to start a listener that has it
+    pub tls: Option<String>,
+    pub limits: ConnectionLimits,
+}
+
+impl ListenerConfig {
+    /// Create a listener on all interfaces: `[::]`, that accepts IPv6 and IPv4 connections, or
+    /// `0.0.0.0` when the host does not support IPv6
+    pub fn unspecified(port: u16) -> ListenerConfig {
+        ListenerConfig::from(default_listener_address(port))
+    }
+
+    pub fn socket_addr(&self) -> SocketAddr {
+        SocketAddr::new(self.ip, self.port)
+    }
+
+    /// Read the listener from the `address`, `port` and `tls` keys under the prefix. The address
+    /// has precedence over the port
+    fn from_map(reader: &mut ConfigurationReader, prefix: &str) -> Option<ListenerConfig> {
+        let address = reader.parse(&format!("{}.address", prefix), "a address like [::]:2460 or 0.0.0.0:2460", |v| v.trim().parse::<SocketAddr>().ok());
+        let port = reader.parse(&format!("{}.port", prefix), "a integer between 1 and 65535", |v| v.trim().parse::<u16>().ok().filter(|port| *port >= 1));
+        let tls = reader.get(&format!("{}.tls", prefix)).map(str::to_string);
+        let mut count = |key: &str, min: usize| reader.parse(&format!("{}.{}", prefix, key), &format!("a integer >= {}", min), |v|
+            v.trim().parse::<usize>().ok().filter(|count| *count >= min)
+        );
+        let limits = ConnectionLimits {
+            max_connections: count("maxConnections", 1),
+            accept_backlog: count("acceptBacklog", 0),
+            max_connections_per_ip: count("maxConnectionsPerIp", 1),
+        };
+
+        // the keys that need a listener are invalid without its address or port
+        let is_listening = reader.get(&format!("{}.address", prefix)).is_some() || reader.get(&format!("{}.port", prefix)).is_some();
+        if !is_listening {
+            for key in ["tls", "maxConnections", "acceptBacklog", "maxConnectionsPerIp"].map(|key| format!("{}.{}", prefix, key)) {
+                if let Some(v) = reader.get(&key) {
+                    reader.invalid(&key, v, &format!("set with {}.address or {}.port", prefix, prefix));
+                }
+            }
         }
+
+        let mut listener = address.map(ListenerConfig::from).or_else(|| port.map(ListenerConfig::unspecified));
+        if let Some(listener) = &mut listener {
+            listener.tls = tls;
+            listener.limits = limits;
+        }
+        listener
+    }
+}
+
+impl From<SocketAddr> for ListenerConfig {
+    fn from(address: SocketAddr) -> Self {
+        ListenerConfig { ip: address.ip(), port: address.port(), tls: None, limits: ConnectionLimits::default() }
+    }
+}
+
+impl Display for ListenerConfig {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.socket_addr())
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct RetryPolicyConfiguration { 
     /// The default interval duration that will be applied when a message sent by a client
     /// does not have a config defined.
@@ -99,8 +355,18 @@ pub struct RetryPolicyConfiguration {
     /// that could be applied for a message sent by the client
     pub max_interval_limit: Option<Duration>,
 
+    /// The limit of a min interval. Intervals shorter than it that are sent by the client are raised to it
+    pub min_interval_limit: Option<Duration>,
+
     /// The maximum amount of attempts that a client could define in a sent message
     pub max_attempts_limit: Option<u16>,
+
+    /// Which failed attempts are retried. Destinations can override it with their own `retryOn`
+    pub retry_on: Option<RetryOn>,
+
+    /// The defaults and the limits of the messages of each namespace, by service ID, set by the
+    /// `retryPolicy.namespace.<serviceId>.` configurations. See `for_namespace`
+    pub namespaces: Option<HashMap<String, RetryPolicyConfiguration>>,
 }
 
 impl RetryPolicyConfiguration {
@@ -110,6 +376,93 @@ impl RetryPolicyConfiguration {
             default_max_attempts: None,
             max_attempts_limit: None,
             max_interval_limit: None,
+            min_interval_limit: None,
+            retry_on: None,
+            namespaces: None,
+        }
+    }
+
+    /// Read the `defaults.` and `limit.` configurations under the prefix, like `retryPolicy.`
+    fn from_map(reader: &mut ConfigurationReader, prefix: &str) -> RetryPolicyConfiguration {
+        let key = |key: &str| format!("{}{}", prefix, key);
+        let mut configuration = RetryPolicyConfiguration::new();
+        // defaults.
+        configuration.default_interval = reader.duration_sequence(&key("defaults.interval"));
+        configuration.default_max_attempts = reader.parse(&key("defaults.maxAttempts"), "a integer >= 0", |v| v.trim().parse().ok());
+        // limit.
+        configuration.max_interval_limit = reader.parse(&key("limit.maxInterval"), "a Duration, like 30m", |v| v.to_duration().ok());
+        configuration.min_interval_limit = reader.parse(&key("limit.minInterval"), "a Duration, like 1m", |v| v.to_duration().ok());
+        configuration.max_attempts_limit = reader.parse(&key("limit.maxAttempts"), "a integer >= 1", |v| v.trim().parse().ok().filter(|limit| *limit >= 1));
+        configuration
+    }
+
+    /// Return the configuration of the messages of the namespace, when it has its own
+    /// `retryPolicy.namespace.<serviceId>.` configurations. Its defaults replace the global ones and
+    /// its limits are layered under the global limits, so a namespace can only tighten them
+    pub fn for_namespace(&self, service_id: &str) -> Option<RetryPolicyConfiguration> {
+        fn tighter<T: Copy>(global: Option<T>, namespace: Option<T>, pick: fn(T, T) -> T) -> Option<T> {
+            match (global, namespace) {
+                (Some(global), Some(namespace)) => Some(pick(global, namespace)),
+                (global, namespace) => global.or(namespace),
+            }
+        }
+        let namespace = self.namespaces.as_ref()?.get(service_id)?;
+        Some(RetryPolicyConfiguration {
+            default_interval: namespace.default_interval.clone().or_else(|| self.default_interval.clone()),
+            default_max_attempts: namespace.default_max_attempts.or(self.default_max_attempts),
+            max_interval_limit: tighter(self.max_interval_limit, namespace.max_interval_limit, std::cmp::min),
+            min_interval_limit: tighter(self.min_interval_limit, namespace.min_interval_limit, std::cmp::max),
+            max_attempts_limit: tighter(self.max_attempts_limit, namespace.max_attempts_limit, std::cmp::min),
+            retry_on: self.retry_on.clone(),
+            namespaces: None,
+        })
+    }
+}
+
+/// The configurations of a retry policy, read under `retryPolicy.` and under the prefix of each
+/// namespace
+const RETRY_POLICY_KEYS: [&str; 5] = ["defaults.interval", "defaults.maxAttempts", "limit.maxInterval", "limit.minInterval", "limit.maxAttempts"];
+
+/// Store the configurations of the topics nominated by `topics.` prefix
+#[derive(Debug, Clone)]
+pub struct TopicsConfiguration {
+    /// If the topics that were not created through `/topics` are created on their first publish,
+    /// with the `topics.defaults.` settings. When false their messages are rejected
+    pub auto_create: Option<bool>,
+
+    /// The ordering of the topics created on their first publish, `unordered` or `ordered`
+    pub default_ordering: Option<OrderingMode>,
+
+    /// The max payload size, in bytes, of the topics created on their first publish
+    pub default_max_payload_size: Option<usize>,
+
+    /// The path of the JSON Schema file of the topics created on their first publish
+    pub default_schema_ref: Option<String>,
+
+    /// How long the delivered messages of the topics created on their first publish are kept
+    pub default_delivered_messages_retention: Option<Duration>,
+
+    /// How long the dead messages of the topics created on their first publish are kept
+    pub default_dead_messages_retention: Option<Duration>,
+
+    /// The retry interval of the messages of the topics created on their first publish
+    pub default_retry_interval: Option<DurationSequence>,
+
+    /// The max attempts of the messages of the topics created on their first publish
+    pub default_retry_max_attempts: Option<u16>,
+}
+
+impl TopicsConfiguration {
+    fn new() -> TopicsConfiguration {
+        TopicsConfiguration {
+            auto_create: None,
+            default_ordering: None,
+            default_max_payload_size: None,
+            default_schema_ref: None,
+            default_delivered_messages_retention: None,
+            default_dead_messages_retention: None,
+            default_retry_interval: None,
+            default_retry_max_attempts: None,
         }
     }
 }
@@ -117,22 +470,131 @@ impl RetryPolicyConfiguration {
 #[derive(Debug, Error)]
 enum ConfigurationErrorCauses {
     #[error("An error occur while trying to read the configuration file")]
-    FailedToReadConfigurationFile
+    FailedToReadConfigurationFile,
+    #[error("The configuration file has invalid configurations")]
+    InvalidConfigurations,
+    #[error("The configuration file is not in the format of its extension")]
+    MalformedConfigurationFile,
 }
 
 #[derive(Debug, Error)]
 pub struct ConfigurationError {
     cause: ConfigurationErrorCauses,
     reason: String,
+    invalid: Vec<ConfigurationValidationError>,
+}
+
+impl ConfigurationError {
+    fn malformed(reason: String) -> ConfigurationError {
+        ConfigurationError { cause: ConfigurationErrorCauses::MalformedConfigurationFile, reason, invalid: Vec::new() }
+    }
+
+    fn invalid(invalid: Vec<ConfigurationValidationError>) -> ConfigurationError {
+        let reason = invalid.iter().map(ConfigurationValidationError::to_string).collect::<Vec<_>>().join("; ");
+        ConfigurationError { cause: ConfigurationErrorCauses::InvalidConfigurations, reason, invalid }
+    }
+
+    /// Return each invalid configuration of the file, empty when the file could not be read
+    pub fn invalid_configurations(&self) -> &[ConfigurationValidationError] {
+        &self.invalid
+    }
 }
 
 impl Display for ConfigurationError {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, "An {} error occur. Error message: {}. Details: {}", self.cause, self.cause.to_string(), self.reason)
+        write!(f, "An {} error occur. Error message: {}. Details: {}", self.cause, self.cause, self.reason)
     }
 }
 
-#[derive(Debug)]
+/// The configurations whose values are secrets, never written in the errors and the logs
+pub const SECRET_KEYS: [&str; 5] = ["cluster.authKey", "cluster.joinToken", "net.admin.authToken", "net.client.auth.apiKeys", "net.client.auth.jwtSecret"];
+
+/// Written instead of the value of a secret configuration
+pub const REDACTED_VALUE: &str = "<redacted>";
+
+/// A configuration whose value is not in the format it should have
+#[derive(Debug, Clone, PartialEq, Eq, Error)]
+#[error("{key} should be {expected}, but is {value}")]
+pub struct ConfigurationValidationError {
+    pub key: String,
+    /// The value as it was written, or `<redacted>` for the SECRET_KEYS
+    pub value: String,
+    /// The format the value should have, like `a integer >= 1`
+    pub expected: String,
+}
+
+/// Read the configurations of a map, recording each invalid one instead of stopping at the first.
+/// The invalid configurations are left unset
+struct ConfigurationReader<'a> {
+    map: &'a HashMap<String, String>,
+    errors: Vec<ConfigurationValidationError>,
+}
+
+impl<'a> ConfigurationReader<'a> {
+    fn new(map: &'a HashMap<String, String>) -> ConfigurationReader<'a> {
+        ConfigurationReader { map, errors: Vec::new() }
+    }
+
+    fn get(&self, key: &str) -> Option<&'a str> {
+        self.map.get(key).map(String::as_str)
+    }
+
+    /// Record that the value of the key is not in the expected format. The values of the secrets
+    /// are redacted, as the errors are printed
+    fn invalid(&mut self, key: &str, value: &str, expected: &str) {
+        let value = if SECRET_KEYS.contains(&key) { REDACTED_VALUE } else { value };
+        self.errors.push(ConfigurationValidationError { key: key.to_string(), value: value.to_string(), expected: expected.to_string() });
+    }
+
+    /// Read the key with the parser, recording it as invalid when the parser returns None
+    fn parse<T>(&mut self, key: &str, expected: &str, parse: impl FnOnce(&str) -> Option<T>) -> Option<T> {
+        let value = self.get(key)?;
+        let parsed = parse(value);
+        if parsed.is_none() {
+            self.invalid(key, value, expected);
+        }
+        parsed
+    }
+
+    fn duration(&mut self, key: &str) -> Option<Duration> {
+        self.parse(key, "a Duration, like 30m", |v| v.to_duration().ok())
+    }
+
+    /// Read a DurationSequence, also written without its brackets, like the lists of the TOML and
+    /// YAML files: `1m, 5m, 1d`
+    fn duration_sequence(&mut self, key: &str) -> Option<DurationSequence> {
+        self.parse(key, "a DurationSequence, like [1m, 5m, 1d]", |v| {
+            v.to_duration_sequence().ok().or_else(|| format!("[{}]", v.trim()).as_str().to_duration_sequence().ok())
+        })
+    }
+
+    fn boolean(&mut self, key: &str) -> Option<bool> {
+        self.parse(key, "true or false", |v| v.trim().parse().ok())
+    }
+
+    /// Record the DurationSequence of the key as invalid when it is beyond the `limit.` configurations
+    /// it would be clamped by, with each element beyond them
+    fn within_retry_limits(&mut self, key: &str, interval: Option<&DurationSequence>, limits: &RetryPolicyConfiguration) {
+        let (Some(value), Some(interval)) = (self.get(key), interval) else {
+            return;
+        };
+        if let Err(violations) = interval.validate_against(limits.max_interval_limit, limits.max_attempts_limit.map(usize::from)) {
+            let violations: Vec<String> = violations.iter().map(ToString::to_string).collect();
+            self.invalid(key, value, &format!("within the retryPolicy.limit configurations ({})", violations.join(", ")));
+        }
+    }
+
+    /// Return the configuration, or each invalid configuration sorted by key
+    fn finish<T>(mut self, configuration: T) -> Result<T, Vec<ConfigurationValidationError>> {
+        if self.errors.is_empty() {
+            return Ok(configuration);
+        }
+        self.errors.sort_by(|a, b| a.key.cmp(&b.key));
+        Err(self.errors)
+    }
+}
+
+#[derive(Debug, Clone)]
 pub struct Configuration {
     /// Configurations for angler cluster defined by `cluster.` prefix
     pub cluster: ClusterConfiguration,
@@ -144,6 +606,8 @@ pub struct Configuration {
     pub networking: NetworkingConfiguration,
     /// Configuration for retry policy defined by `retryPolicy.` prefix
     pub retry_policy: RetryPolicyConfiguration,
+    /// Configuration for the topics defined by `topics.` prefix
+    pub topics: TopicsConfiguration,
 }
 
 impl Configuration {
@@ -154,77 +618,204 @@ impl Configuration {
             messages_processor: MessagesProcessorConfigurations::new(),
             networking: NetworkingConfiguration::new(),
             retry_policy: RetryPolicyConfiguration::new(),
+            topics: TopicsConfiguration::new(),
         }
     }
 
-    pub fn from_map(map: &HashMap<String, String>) -> Configuration {
+    /// Read the configurations of the map. Each invalid configuration is returned, instead of only
+    /// the first, so they can all be fixed at once
+    pub fn from_map(map: &HashMap<String, String>) -> Result<Configuration, Vec<ConfigurationValidationError>> {
+        let mut reader = ConfigurationReader::new(map);
         let mut configuration = Configuration::new();
         
         // cluster.
-        configuration.cluster.auth_key = map.get("cluster.authKey").and_then(|v| Some(v.clone()));
-        configuration.cluster.controller_host = map.get("cluster.controller.host").and_then(|v| Some(v.clone()));
-        configuration.cluster.request_timeout = map.get("cluster.requestTimeout").and_then(|v| 
-            Some(Duration::milliseconds(v.parse().expect("cluster.requestTimeout should be a time in milliseconds >= 0")))
+        configuration.cluster.auth_key = map.get("cluster.authKey").cloned();
+        configuration.cluster.controller_host = map.get("cluster.controller.host").cloned();
+        configuration.cluster.request_timeout = reader.parse("cluster.requestTimeout", "a time in milliseconds >= 0", |v|
+            v.parse().ok().filter(|millis| *millis >= 0).map(Duration::milliseconds)
+        );
+        configuration.cluster.roles = reader.parse("cluster.roles", "a list of roles, messageProcessor or storage", |v|
+            v.split(',').map(str::trim).filter(|role| !role.is_empty()).map(ApplicationRoles::from_name).collect()
+        );
+        configuration.cluster.storage = ListenerConfig::from_map(&mut reader, "cluster.storage");
+        configuration.cluster.storage_url = map.get("cluster.storage.url").map(|v| v.trim().trim_end_matches('/').to_string());
+        configuration.cluster.storage_replicas = map.get("cluster.storage.replicas").map(|v|
+            v.split(',').map(|url| url.trim().trim_end_matches('/').to_string()).filter(|url| !url.is_empty()).collect()
+        );
+        configuration.cluster.storage_read_url = map.get("cluster.storage.readUrl").map(|v| v.trim().trim_end_matches('/').to_string());
+        configuration.cluster.anti_entropy_interval = reader.duration("cluster.antiEntropy.interval");
+        configuration.cluster.join_token = map.get("cluster.joinToken").map(|v| v.trim().to_string());
+        configuration.cluster.revoked_join_tokens = map.get("cluster.revokedJoinTokens").map(|v|
+            v.split(',').map(str::trim).filter(|id| !id.is_empty()).map(String::from).collect()
         );
+        configuration.cluster.keepalive_interval = reader.duration("cluster.keepalive.interval");
+        configuration.cluster.keepalive_threshold = reader.parse("cluster.keepalive.threshold", "a number of pings >= 1", |v|
+            v.trim().parse().ok().filter(|threshold| *threshold >= 1)
+        );
+        configuration.cluster.clock_skew_threshold = reader.duration("cluster.clockSkew.threshold");
+        configuration.cluster.clock_skew_fence = reader.boolean("cluster.clockSkew.fence");
+        configuration.cluster.compression = reader.parse("cluster.compression", "none or lz4", |v| ClusterCompression::from_name(v.trim()));
         
         // db.
-        configuration.database.dead_messages_retention = map.get("db.deadMessages.retention").and_then(|v| 
-            Some(v.as_str().to_duration().expect("db.deadMessages.retention has a invalid syntax for Duration"))
+        configuration.database.dead_messages_retention = reader.duration("db.deadMessages.retention");
+        configuration.database.delivered_messages_retention = reader.duration("db.deliveredMessages.retention");
+        configuration.database.write_batch_size = reader.parse("db.writes.batchSize", "a integer >= 1", |v|
+            v.trim().parse().ok().filter(|size| *size >= 1)
+        );
+        configuration.database.cache_capacity = reader.parse("db.cache.capacity", "a integer >= 0", |v| v.trim().parse().ok());
+        configuration.database.write_flush_interval = reader.parse("db.writes.flushInterval", "a time in milliseconds >= 1", |v|
+            v.trim().parse().ok().filter(|millis| *millis >= 1).map(Duration::milliseconds)
         );
-        configuration.database.delivered_messages_retention = map.get("db.deliveredMessages.retention").and_then(|v| 
-            Some(v.as_str().to_duration().expect("db.deliveredMessages.retention has a invalid syntax for Duration")))
-        ;
+        let payload_index: HashMap<String, Vec<String>> = map.iter()
+            .filter_map(|(key, v)| key.strip_prefix("db.payloadIndex.").map(|topic| (topic.to_string(),
+                v.split(',').map(|field| String::from(field.trim())).filter(|field| !field.is_empty()).collect()
+            )))
+            .collect();
+        configuration.database.payload_index = Some(payload_index).filter(|payload_index| !payload_index.is_empty());
 
         // msgproc.
-        configuration.messages_processor.message_delivery_timeout = map.get("msgproc.message_delivery_timeout").and_then(|v| 
-            Some(Duration::milliseconds(v.parse().expect("msgproc.message_delivery_timeout should be in milliseconds")))
+        configuration.messages_processor.message_delivery_timeout = reader.parse("msgproc.message_delivery_timeout", "a time in milliseconds", |v|
+            v.trim().parse().ok().map(Duration::milliseconds)
         );
-        configuration.messages_processor.workers_count = map.get("msgproc.workers").and_then(|v| 
-            Some(v.parse().expect("msgproc.workers should be integer >= 1"))
+        configuration.messages_processor.workers_count = reader.parse("msgproc.workers", "a integer >= 1", |v|
+            v.trim().parse().ok().filter(|workers| *workers >= 1)
         );
-
-        // net.
-        configuration.networking.client_protocols = map.get("net.client.protocols").and_then(|v| 
-            Some(v.split(',').map(|v| String::from(v.trim())).collect()) //split("a, b") and transform it into Set["a", "b"]
+        configuration.messages_processor.dedup_window = reader.duration("msgproc.dedup.window");
+        configuration.messages_processor.dns_ttl = reader.duration("msgproc.dns.ttl");
+        configuration.messages_processor.dns_negative_ttl = reader.duration("msgproc.dns.negativeTtl");
+        configuration.messages_processor.delete_grace_period = reader.duration("msgproc.destinations.deleteGracePeriod");
+        configuration.messages_processor.ack_callback_url = map.get("msgproc.asyncAck.callbackUrl").map(|v| v.trim().trim_end_matches('/').to_string());
+        configuration.messages_processor.usage_interval = reader.duration("msgproc.usage.interval");
+        configuration.messages_processor.id_generator = reader.parse("msgproc.ids.generator", "uuidv7 or snowflake", |v| IdGeneratorKind::from_name(v.trim()));
+        configuration.messages_processor.snowflake_node_id = reader.parse("msgproc.ids.nodeId", "a integer from 0 to 1023", |v|
+            v.trim().parse().ok().filter(|node_id| *node_id <= MAX_SNOWFLAKE_NODE_ID)
         );
-        configuration.networking.restful_port = map.get("net.client.restful.port").and_then(|v| 
-            Some(v.parse().expect("net.client.restful.port should be a integer >= 1"))
+        configuration.messages_processor.lagging_backlog_age = reader.duration("msgproc.lagging.backlogAge");
+        configuration.messages_processor.lagging_latency = reader.duration("msgproc.lagging.latency");
+        configuration.messages_processor.lagging_demote = reader.boolean("msgproc.lagging.demote");
+        configuration.messages_processor.delivery_user_agent = map.get("msgproc.delivery.userAgent").map(|v| v.trim().to_string());
+        let mut delivery_headers = HashMap::new();
+        for (key, v) in map.iter() {
+            let Some(name) = key.strip_prefix("msgproc.delivery.headers.") else {
+                continue;
+            };
+            match is_identification_header(name) {
+                true => { delivery_headers.insert(name.to_string(), v.trim().to_string()); }
+                false => reader.invalid(key, v, "a header not set by the delivery, with a valid name, like msgproc.delivery.headers.X-Sender"),
+            }
+        }
+        configuration.messages_processor.delivery_headers = Some(delivery_headers).filter(|headers| !headers.is_empty());
+        configuration.messages_processor.death_notifications_recipient_id = map.get("msgproc.deathNotifications.recipientId").map(|v| v.trim().to_string());
+        configuration.messages_processor.death_notifications_event_id = map.get("msgproc.deathNotifications.eventId").map(|v| v.trim().to_string());
+        let death_notifications_namespaces: HashMap<String, String> = map.iter()
+            .filter_map(|(key, v)| key.strip_prefix("msgproc.deathNotifications.namespace.")?.strip_suffix(".recipientId").map(|service_id| (service_id.to_string(), v.trim().to_string())))
+            .collect();
+        configuration.messages_processor.death_notifications_namespaces = Some(death_notifications_namespaces).filter(|namespaces| !namespaces.is_empty());
+        configuration.messages_processor.max_payload_size = reader.parse("msgproc.interceptors.maxPayloadSize", "a integer >= 1", |v|
+            v.trim().parse().ok().filter(|size| *size >= 1)
         );
+        let topic_schemas: HashMap<String, String> = map.iter()
+            .filter_map(|(key, v)| key.strip_prefix("msgproc.interceptors.schema.").map(|topic| (topic.to_string(), v.trim().to_string())))
+            .collect();
+        configuration.messages_processor.topic_schemas = Some(topic_schemas).filter(|topic_schemas| !topic_schemas.is_empty());
 
-        // retryPolicy.defaults.
-        configuration.retry_policy.default_interval = map.get("retryPolicy.defaults.interval").and_then(|v|
-            Some(v.as_str().to_duration_sequence().expect("retryPolicy.defaults.interval should have a valid DurationSequence syntax. Example: [1m, 5m, 1d]"))
-        );
-        configuration.retry_policy.default_max_attempts = map.get("retryPolicy.defaults.maxAttempts").and_then(|v| 
-            Some(v.parse().expect("retryPolicy.defaults.maxAttempts should be a valid integer >= 0"))
+        // net.
+        configuration.networking.client_protocols = map.get("net.client.protocols").map(|v|
+            v.split(',').map(|v| String::from(v.trim())).collect() //split("a, b") and transform it into Set["a", "b"]
         );
-        // retryPolicy.limit.
-        configuration.retry_policy.max_interval_limit = map.get("retryPolicy.limit.maxInterval").and_then(|v|
-            Some(v.as_str().to_duration().expect("retryPolicy.limit.maxInterval should have a valid Duration syntax. Example: 30m"))
+        configuration.networking.restful = ListenerConfig::from_map(&mut reader, "net.client.restful");
+        configuration.networking.admin = ListenerConfig::from_map(&mut reader, "net.admin");
+        configuration.networking.admin_auth_token = map.get("net.admin.authToken").cloned();
+        configuration.networking.client_auth_provider = reader.parse("net.client.auth.provider", "apiKeys or jwt", |v| AuthProviderKind::from_name(v.trim()));
+        configuration.networking.client_api_keys = reader.parse("net.client.auth.apiKeys", "a list of subject:key, like billing:k-1, shop:k-2", |v|
+            v.split(',').map(|pair| match pair.trim().split_once(':') {
+                Some((subject, key)) if !subject.trim().is_empty() && !key.trim().is_empty() => Some((subject.trim().to_string(), key.trim().to_string())),
+                _ => None,
+            }).collect()
         );
-        configuration.retry_policy.max_attempts_limit = map.get("retryPolicy.limit.maxAttempts").and_then(|v|
-            Some(v.parse().expect("retryPolicy.limit.maxAttempts should be a integer >= 1"))
+        configuration.networking.client_jwt_secret = map.get("net.client.auth.jwtSecret").map(|v| v.trim().to_string());
+        // the provider is checked against the keys it reads, so a missing one is not found only when Angler starts
+        let networking = &configuration.networking;
+        match (networking.client_auth_provider, reader.get("net.client.auth.provider")) {
+            (Some(AuthProviderKind::ApiKeys), Some(v)) if networking.client_api_keys.is_none() && reader.get("net.client.auth.apiKeys").is_none() =>
+                reader.invalid("net.client.auth.provider", v, "apiKeys with the net.client.auth.apiKeys set"),
+            (Some(AuthProviderKind::Jwt), Some(v)) if networking.client_jwt_secret.is_none() =>
+                reader.invalid("net.client.auth.provider", v, "jwt with the net.client.auth.jwtSecret set"),
+            _ => {}
+        }
+
+        configuration.retry_policy = RetryPolicyConfiguration::from_map(&mut reader, "retryPolicy.");
+        let service_ids: HashSet<&str> = map.keys()
+            .filter_map(|key| key.strip_prefix("retryPolicy.namespace."))
+            .filter_map(|key| RETRY_POLICY_KEYS.iter().find_map(|suffix| key.strip_suffix(suffix)?.strip_suffix('.')))
+            .collect();
+        let namespaces: HashMap<String, RetryPolicyConfiguration> = service_ids.into_iter()
+            .map(|service_id| (service_id.to_string(), RetryPolicyConfiguration::from_map(&mut reader, &format!("retryPolicy.namespace.{}.", service_id))))
+            .collect();
+        configuration.retry_policy.namespaces = Some(namespaces).filter(|namespaces| !namespaces.is_empty());
+        if let Some(v) = reader.get("retryPolicy.retryOn") {
+            match RetryOn::parse(v) {
+                Ok(retry_on) => configuration.retry_policy.retry_on = Some(retry_on),
+                Err(err) => reader.invalid("retryPolicy.retryOn", v, &format!("a list of error classes and HTTP statuses ({})", err)),
+            }
+        }
+
+        // topics.
+        configuration.topics.auto_create = reader.boolean("topics.autoCreate");
+        configuration.topics.default_ordering = reader.parse("topics.defaults.ordering", "unordered or ordered", |v| OrderingMode::from_name(v.trim()));
+        configuration.topics.default_max_payload_size = reader.parse("topics.defaults.maxPayloadSize", "a integer >= 1", |v|
+            v.trim().parse().ok().filter(|size| *size >= 1)
         );
+        configuration.topics.default_schema_ref = map.get("topics.defaults.schemaRef").map(|v| v.trim().to_string());
+        configuration.topics.default_delivered_messages_retention = reader.duration("topics.defaults.deliveredMessages.retention");
+        configuration.topics.default_dead_messages_retention = reader.duration("topics.defaults.deadMessages.retention");
+        configuration.topics.default_retry_interval = reader.duration_sequence("topics.defaults.retryPolicy.interval");
+        configuration.topics.default_retry_max_attempts = reader.parse("topics.defaults.retryPolicy.maxAttempts", "a integer >= 0", |v| v.trim().parse().ok());
+
+        // the default intervals are checked against the limits they are clamped by
+        let retry_policy = &configuration.retry_policy;
+        reader.within_retry_limits("retryPolicy.defaults.interval", retry_policy.default_interval.as_ref(), retry_policy);
+        reader.within_retry_limits("topics.defaults.retryPolicy.interval", configuration.topics.default_retry_interval.as_ref(), retry_policy);
+        for (service_id, namespace) in retry_policy.namespaces.iter().flatten() {
+            let limits = retry_policy.for_namespace(service_id).expect("the namespace has its own configurations");
+            reader.within_retry_limits(&format!("retryPolicy.namespace.{}.defaults.interval", service_id), namespace.default_interval.as_ref(), &limits);
+        }
 
-        configuration
+        reader.finish(configuration)
     }
 
     /// Create a instance of Configuration based on the content of the file plus merging with the value
     /// of the environment variable `ANGLER_CFG` that should be in a format like 
     pub fn from_properties_file<P: AsRef<Path>>(file_path: P) -> Result<Configuration, ConfigurationError> {
+        let file_content = read_configuration_file(file_path)?;
+        Configuration::from_map(&properties_file_content_to_map(file_content.as_str())).map_err(ConfigurationError::invalid)
+    }
 
-        // read the file content and return error if it fails
-        let file_content = match fs::read_to_string(file_path) {
-            Ok(file_content) => file_content,
-            Err(err) => return Err(ConfigurationError { 
-                cause: ConfigurationErrorCauses::FailedToReadConfigurationFile,
-                reason: err.to_string()
-            })
-        };
+    /// Create a instance of Configuration from a TOML file, whose tables are the sections of the
+    /// dotted keys: `[cluster.storage]` with `port = 2462` is `cluster.storage.port=2462`
+    pub fn from_toml_file<P: AsRef<Path>>(file_path: P) -> Result<Configuration, ConfigurationError> {
+        let document = parse_toml(&read_configuration_file(file_path)?).map_err(|err| ConfigurationError::malformed(err.to_string()))?;
+        Configuration::from_map(&document_to_map(&document)?).map_err(ConfigurationError::invalid)
+    }
 
-        let configuration = Configuration::from_map(&properties_file_content_to_map(file_content.as_str()));
+    /// Create a instance of Configuration from a YAML file, whose mappings are the sections of the
+    /// dotted keys
+    pub fn from_yaml_file<P: AsRef<Path>>(file_path: P) -> Result<Configuration, ConfigurationError> {
+        let document = parse_yaml(&read_configuration_file(file_path)?).map_err(|err| ConfigurationError::malformed(err.to_string()))?;
+        Configuration::from_map(&document_to_map(&document)?).map_err(ConfigurationError::invalid)
+    }
 
-        Ok(configuration)
+    /// Create a instance of Configuration from the file, in the format of its extension
+    pub fn from_file<P: AsRef<Path>>(file_path: P) -> Result<Configuration, ConfigurationError> {
+        Configuration::from_map(&configuration_file_to_map(file_path)?).map_err(ConfigurationError::invalid)
+    }
+
+    /// Read the configurations of the `ANGLER__` environment variables, like `ANGLER__CLUSTER__AUTH_KEY`
+    /// for `cluster.authKey`. The invalid configurations are returned by the name of their variable
+    pub fn from_environment(vars: impl IntoIterator<Item = (String, String)>) -> Result<Configuration, Vec<ConfigurationValidationError>> {
+        let mut layers = ConfigurationLayers::new();
+        layers.insert_environment(vars);
+        layers.to_configuration()
     }
 
     pub fn merge(&mut self, other: &Configuration) {
@@ -238,6 +829,45 @@ impl Configuration {
         if self.cluster.request_timeout.is_none() {
             self.cluster.request_timeout = other.cluster.request_timeout;
         }
+        if self.cluster.roles.is_none() {
+            self.cluster.roles = other.cluster.roles.clone();
+        }
+        if self.cluster.storage.is_none() {
+            self.cluster.storage = other.cluster.storage.clone();
+        }
+        if self.cluster.storage_url.is_none() {
+            self.cluster.storage_url = other.cluster.storage_url.clone();
+        }
+        if self.cluster.storage_replicas.is_none() {
+            self.cluster.storage_replicas = other.cluster.storage_replicas.clone();
+        }
+        if self.cluster.storage_read_url.is_none() {
+            self.cluster.storage_read_url = other.cluster.storage_read_url.clone();
+        }
+        if self.cluster.anti_entropy_interval.is_none() {
+            self.cluster.anti_entropy_interval = other.cluster.anti_entropy_interval;
+        }
+        if self.cluster.compression.is_none() {
+            self.cluster.compression = other.cluster.compression;
+        }
+        if self.cluster.join_token.is_none() {
+            self.cluster.join_token = other.cluster.join_token.clone();
+        }
+        if self.cluster.revoked_join_tokens.is_none() {
+            self.cluster.revoked_join_tokens = other.cluster.revoked_join_tokens.clone();
+        }
+        if self.cluster.keepalive_interval.is_none() {
+            self.cluster.keepalive_interval = other.cluster.keepalive_interval;
+        }
+        if self.cluster.keepalive_threshold.is_none() {
+            self.cluster.keepalive_threshold = other.cluster.keepalive_threshold;
+        }
+        if self.cluster.clock_skew_threshold.is_none() {
+            self.cluster.clock_skew_threshold = other.cluster.clock_skew_threshold;
+        }
+        if self.cluster.clock_skew_fence.is_none() {
+            self.cluster.clock_skew_fence = other.cluster.clock_skew_fence;
+        }
 
         // Merge DatabaseConfigurations
         if self.database.dead_messages_retention.is_none() {
@@ -246,6 +876,18 @@ impl Configuration {
         if self.database.delivered_messages_retention.is_none() {
             self.database.delivered_messages_retention = other.database.delivered_messages_retention;
         }
+        if self.database.write_batch_size.is_none() {
+            self.database.write_batch_size = other.database.write_batch_size;
+        }
+        if self.database.cache_capacity.is_none() {
+            self.database.cache_capacity = other.database.cache_capacity;
+        }
+        if self.database.write_flush_interval.is_none() {
+            self.database.write_flush_interval = other.database.write_flush_interval;
+        }
+        if self.database.payload_index.is_none() {
+            self.database.payload_index = other.database.payload_index.clone();
+        }
 
         // Merge MessagesProcessorConfigurations
         if self.messages_processor.message_delivery_timeout.is_none() {
@@ -254,13 +896,82 @@ impl Configuration {
         if self.messages_processor.workers_count.is_none() {
             self.messages_processor.workers_count = other.messages_processor.workers_count;
         }
+        if self.messages_processor.dedup_window.is_none() {
+            self.messages_processor.dedup_window = other.messages_processor.dedup_window;
+        }
+        if self.messages_processor.dns_ttl.is_none() {
+            self.messages_processor.dns_ttl = other.messages_processor.dns_ttl;
+        }
+        if self.messages_processor.delete_grace_period.is_none() {
+            self.messages_processor.delete_grace_period = other.messages_processor.delete_grace_period;
+        }
+        if self.messages_processor.delivery_user_agent.is_none() {
+            self.messages_processor.delivery_user_agent = other.messages_processor.delivery_user_agent.clone();
+        }
+        if self.messages_processor.delivery_headers.is_none() {
+            self.messages_processor.delivery_headers = other.messages_processor.delivery_headers.clone();
+        }
+        if self.messages_processor.death_notifications_recipient_id.is_none() {
+            self.messages_processor.death_notifications_recipient_id = other.messages_processor.death_notifications_recipient_id.clone();
+        }
+        if self.messages_processor.death_notifications_event_id.is_none() {
+            self.messages_processor.death_notifications_event_id = other.messages_processor.death_notifications_event_id.clone();
+        }
+        if self.messages_processor.death_notifications_namespaces.is_none() {
+            self.messages_processor.death_notifications_namespaces = other.messages_processor.death_notifications_namespaces.clone();
+        }
+        if self.messages_processor.ack_callback_url.is_none() {
+            self.messages_processor.ack_callback_url = other.messages_processor.ack_callback_url.clone();
+        }
+        if self.messages_processor.usage_interval.is_none() {
+            self.messages_processor.usage_interval = other.messages_processor.usage_interval;
+        }
+        if self.messages_processor.id_generator.is_none() {
+            self.messages_processor.id_generator = other.messages_processor.id_generator;
+        }
+        if self.messages_processor.snowflake_node_id.is_none() {
+            self.messages_processor.snowflake_node_id = other.messages_processor.snowflake_node_id;
+        }
+        if self.messages_processor.lagging_backlog_age.is_none() {
+            self.messages_processor.lagging_backlog_age = other.messages_processor.lagging_backlog_age;
+        }
+        if self.messages_processor.lagging_latency.is_none() {
+            self.messages_processor.lagging_latency = other.messages_processor.lagging_latency;
+        }
+        if self.messages_processor.lagging_demote.is_none() {
+            self.messages_processor.lagging_demote = other.messages_processor.lagging_demote;
+        }
+        if self.messages_processor.dns_negative_ttl.is_none() {
+            self.messages_processor.dns_negative_ttl = other.messages_processor.dns_negative_ttl;
+        }
+        if self.messages_processor.max_payload_size.is_none() {
+            self.messages_processor.max_payload_size = other.messages_processor.max_payload_size;
+        }
+        if self.messages_processor.topic_schemas.is_none() {
+            self.messages_processor.topic_schemas = other.messages_processor.topic_schemas.clone();
+        }
 
         // Merge NetworkingConfiguration
         if self.networking.client_protocols.is_none() {
             self.networking.client_protocols = other.networking.client_protocols.clone();
         }
-        if self.networking.restful_port.is_none() {
-            self.networking.restful_port = other.networking.restful_port;
+        if self.networking.restful.is_none() {
+            self.networking.restful = other.networking.restful.clone();
+        }
+        if self.networking.admin.is_none() {
+            self.networking.admin = other.networking.admin.clone();
+        }
+        if self.networking.admin_auth_token.is_none() {
+            self.networking.admin_auth_token = other.networking.admin_auth_token.clone();
+        }
+        if self.networking.client_auth_provider.is_none() {
+            self.networking.client_auth_provider = other.networking.client_auth_provider;
+        }
+        if self.networking.client_api_keys.is_none() {
+            self.networking.client_api_keys = other.networking.client_api_keys.clone();
+        }
+        if self.networking.client_jwt_secret.is_none() {
+            self.networking.client_jwt_secret = other.networking.client_jwt_secret.clone();
         }
 
         // Merge RetryPolicyConfiguration
@@ -273,10 +984,198 @@ impl Configuration {
         if self.retry_policy.max_interval_limit.is_none() {
             self.retry_policy.max_interval_limit = other.retry_policy.max_interval_limit;
         }
+        if self.retry_policy.min_interval_limit.is_none() {
+            self.retry_policy.min_interval_limit = other.retry_policy.min_interval_limit;
+        }
         if self.retry_policy.max_attempts_limit.is_none() {
             self.retry_policy.max_attempts_limit = other.retry_policy.max_attempts_limit;
         }
+        if self.retry_policy.retry_on.is_none() {
+            self.retry_policy.retry_on = other.retry_policy.retry_on.clone();
+        }
+        if self.retry_policy.namespaces.is_none() {
+            self.retry_policy.namespaces = other.retry_policy.namespaces.clone();
+        }
+
+        // Merge TopicsConfiguration
+        if self.topics.auto_create.is_none() {
+            self.topics.auto_create = other.topics.auto_create;
+        }
+        if self.topics.default_ordering.is_none() {
+            self.topics.default_ordering = other.topics.default_ordering;
+        }
+        if self.topics.default_max_payload_size.is_none() {
+            self.topics.default_max_payload_size = other.topics.default_max_payload_size;
+        }
+        if self.topics.default_schema_ref.is_none() {
+            self.topics.default_schema_ref = other.topics.default_schema_ref.clone();
+        }
+        if self.topics.default_delivered_messages_retention.is_none() {
+            self.topics.default_delivered_messages_retention = other.topics.default_delivered_messages_retention;
+        }
+        if self.topics.default_dead_messages_retention.is_none() {
+            self.topics.default_dead_messages_retention = other.topics.default_dead_messages_retention;
+        }
+        if self.topics.default_retry_interval.is_none() {
+            self.topics.default_retry_interval = other.topics.default_retry_interval.clone();
+        }
+        if self.topics.default_retry_max_attempts.is_none() {
+            self.topics.default_retry_max_attempts = other.topics.default_retry_max_attempts;
+        }
+    }
+}
+
+impl Default for Configuration {
+    fn default() -> Self {
+        Configuration::new()
+    }
+}
+
+/// The configurations of each source by their dotted keys, like the configuration file and the
+/// environment variables, read into a single Configuration. The keys of a source overwrite the
+/// ones of the sources inserted before it, so a source can override a single key of a section,
+/// like `net.admin.port`, and keep the others of the file
+#[derive(Debug, Clone, Default)]
+pub struct ConfigurationLayers {
+    map: HashMap<String, String>,
+    /// The name of the environment variable of each key set by one, so it is reported by it
+    variables: HashMap<String, String>,
+}
+
+impl ConfigurationLayers {
+    pub fn new() -> ConfigurationLayers {
+        ConfigurationLayers::default()
+    }
+
+    /// Set the configurations of the map, over the ones set before
+    pub fn insert_map(&mut self, map: HashMap<String, String>) {
+        for (key, value) in map {
+            self.variables.remove(&key);
+            self.map.insert(key, value);
+        }
+    }
+
+    /// Set the configurations of the `ANGLER__` environment variables, over the ones set before.
+    /// Return the names of the variables that were read
+    pub fn insert_environment(&mut self, vars: impl IntoIterator<Item = (String, String)>) -> Vec<String> {
+        let mut names = Vec::new();
+        for (name, value) in vars {
+            if let Some(key) = environment_variable_to_key(&name) {
+                self.map.insert(key.clone(), value);
+                self.variables.insert(key, name.clone());
+                names.push(name);
+            }
+        }
+        names.sort();
+        names
+    }
+
+    /// Read the configurations of every source at once, so the ones that depend on each other,
+    /// like the retry defaults and their limits, are checked together. The invalid configurations
+    /// set by environment variables are returned by the name of their variable
+    pub fn to_configuration(&self) -> Result<Configuration, Vec<ConfigurationValidationError>> {
+        Configuration::from_map(&self.map).map_err(|mut invalid| {
+            for invalid in invalid.iter_mut() {
+                if let Some(name) = self.variables.get(&invalid.key) {
+                    invalid.key = name.clone();
+                }
+            }
+            invalid
+        })
+    }
+}
+
+/// The formats of the configuration files, told apart by their extension
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConfigurationFormat {
+    Properties,
+    Toml,
+    Yaml,
+}
+
+impl ConfigurationFormat {
+    /// The extensions of each format, in the order the configuration files are looked up
+    pub const EXTENSIONS: [(&'static str, ConfigurationFormat); 4] = [
+        ("properties", ConfigurationFormat::Properties),
+        ("toml", ConfigurationFormat::Toml),
+        ("yaml", ConfigurationFormat::Yaml),
+        ("yml", ConfigurationFormat::Yaml),
+    ];
+
+    /// Return the format of the file by its extension, properties when it has another one
+    pub fn from_path<P: AsRef<Path>>(file_path: P) -> ConfigurationFormat {
+        let extension = file_path.as_ref().extension().and_then(|extension| extension.to_str()).unwrap_or_default();
+        ConfigurationFormat::EXTENSIONS.iter()
+            .find(|(name, _)| name.eq_ignore_ascii_case(extension))
+            .map_or(ConfigurationFormat::Properties, |(_, format)| *format)
+    }
+}
+
+/// Read the configurations of the file by their dotted keys, in the format of its extension
+pub fn configuration_file_to_map<P: AsRef<Path>>(file_path: P) -> Result<HashMap<String, String>, ConfigurationError> {
+    let content = read_configuration_file(&file_path)?;
+    match ConfigurationFormat::from_path(&file_path) {
+        ConfigurationFormat::Properties => Ok(properties_file_content_to_map(&content)),
+        ConfigurationFormat::Toml => document_to_map(&parse_toml(&content).map_err(|err| ConfigurationError::malformed(err.to_string()))?),
+        ConfigurationFormat::Yaml => document_to_map(&parse_yaml(&content).map_err(|err| ConfigurationError::malformed(err.to_string()))?),
+    }
+}
+
+/// Read the configurations of a TOML or YAML document, by their dotted keys
+fn document_to_map(document: &JsonValue) -> Result<HashMap<String, String>, ConfigurationError> {
+    let mut map = HashMap::new();
+    match document {
+        JsonValue::Object(_) => flatten_document("", document, &mut map).map_err(ConfigurationError::malformed)?,
+        JsonValue::Null => {}
+        _ => return Err(ConfigurationError::malformed(String::from("the file should have the sections of the configurations"))),
+    }
+    Ok(map)
+}
+
+fn read_configuration_file<P: AsRef<Path>>(file_path: P) -> Result<String, ConfigurationError> {
+    fs::read_to_string(file_path).map_err(|err| ConfigurationError {
+        cause: ConfigurationErrorCauses::FailedToReadConfigurationFile,
+        reason: err.to_string(),
+        invalid: Vec::new(),
+    })
+}
+
+/// Insert the values of the document under their dotted keys. The lists are written separated by
+/// comma, like `cluster.roles=messageProcessor, storage`
+fn flatten_document(prefix: &str, value: &JsonValue, map: &mut HashMap<String, String>) -> Result<(), String> {
+    let scalar = |value: &JsonValue| match value {
+        JsonValue::String(value) => Some(value.clone()),
+        JsonValue::Bool(_) | JsonValue::Number(_) => Some(value.to_string()),
+        _ => None,
+    };
+    match value {
+        JsonValue::Object(sections) => {
+            for (key, value) in sections {
+                let key = if prefix.is_empty() { key.clone() } else { format!("{}.{}", prefix, key) };
+                flatten_document(&key, value, map)?;
+            }
+        }
+        JsonValue::Null => {}
+        JsonValue::Array(values) => {
+            let values: Option<Vec<String>> = values.iter().map(scalar).collect();
+            let values = values.ok_or_else(|| format!("{} should be a list of values", prefix))?;
+            map.insert(prefix.to_string(), values.join(", "));
+        }
+        value => {
+            map.insert(prefix.to_string(), scalar(value).expect("the value is a scalar"));
+        }
     }
+    Ok(())
+}
+
+/// Return if a header can be set by `msgproc.delivery.headers.`. The headers of the HTTP framing,
+/// the `User-Agent` and the `X-Angler-` headers are set by the delivery itself
+fn is_identification_header(name: &str) -> bool {
+    let framing = ["Host", "Content-Length", "Content-Type", "Connection", "Transfer-Encoding", "User-Agent"];
+    !name.is_empty()
+        && name.bytes().all(|byte| byte.is_ascii_alphanumeric() || byte == b'-' || byte == b'_')
+        && !framing.iter().any(|header| header.eq_ignore_ascii_case(name))
+        && !name.to_ascii_lowercase().starts_with("x-angler-")
 }
 
 /// Parse a properties file content into a HashMap<String, String>. Here is a example of properties file:
@@ -304,12 +1203,38 @@ pub fn properties_file_content_to_map(file_content: &str) -> HashMap<String, Str
         }
 
         // insert the [0] as the key and all the other values will be joined into a single value
-        map.insert(key_and_value[0].to_string(), key_and_value[1..].join("=").trim().to_string());
+        map.insert(key_and_value[0].trim().to_string(), key_and_value[1..].join("=").trim().to_string());
     }
 
     map
 }
 
+/// The prefix of the environment variables that set each configuration
+pub const ENVIRONMENT_PREFIX: &str = "ANGLER__";
+
+/// Return the dotted key of the `ANGLER__` environment variable, whose parts are separated by `__`.
+/// The parts in upper case are written in camel case, like `RETRY_POLICY` for `retryPolicy`, and
+/// the others are kept as they are, for the keys like `message_delivery_timeout` and the names of
+/// the topics and headers: `ANGLER__MSGPROC__DELIVERY__HEADERS__X-Sender`
+pub fn environment_variable_to_key(name: &str) -> Option<String> {
+    let parts: Vec<&str> = name.strip_prefix(ENVIRONMENT_PREFIX)?.split("__").collect();
+    if parts.iter().any(|part| part.is_empty()) {
+        return None;
+    }
+    let parts: Vec<String> = parts.into_iter().map(|part| match part.chars().any(|c| c.is_ascii_lowercase()) {
+        true => part.to_string(),
+        false => part.split('_').filter(|word| !word.is_empty()).enumerate().map(|(index, word)| {
+            let word = word.to_ascii_lowercase();
+            let mut chars = word.chars();
+            match (index, chars.next()) {
+                (0, _) | (_, None) => word,
+                (_, Some(first)) => first.to_ascii_uppercase().to_string() + chars.as_str(),
+            }
+        }).collect(),
+    }).collect();
+    Some(parts.join("."))
+}
+
 /// Parse a properties separate by semicolon into a HashMap<String, String>. Here is a example of properties file:
 /// `akey=avalue; bkey=bvalue; ckey=cvalue`
 pub fn properties_separate_by_semicolon_to_map(content: &str) -> HashMap<String, String> {
@@ -331,7 +1256,7 @@ pub fn properties_separate_by_semicolon_to_map(content: &str) -> HashMap<String,
         }
 
         // insert the [0] as the key and all the other values will be joined into a single value
-        map.insert(key_and_value[0].to_string(), key_and_value[1..].join("=").trim().to_string());
+        map.insert(key_and_value[0].trim().to_string(), key_and_value[1..].join("=").trim().to_string());
     }
 
     map
@@ -339,28 +1264,80 @@ pub fn properties_separate_by_semicolon_to_map(content: &str) -> HashMap<String,
 
 #[cfg(test)]
 mod tests {
-    use std::{fs, path::Path};
+    use crate::{msgproc::{id::IdGeneratorKind, topic::OrderingMode}, net::{auth::AuthProviderKind, http::ConnectionLimits, storage::ClusterCompression}};
+
+    use crate::{ctx::appenv::ApplicationRoles, utils::{json::JsonValue, yaml::to_yaml}};
+
+    use std::collections::{HashMap, HashSet};
+
+    use crate::utils::random::FastRng;
 
-    use super::{properties_file_content_to_map, properties_separate_by_semicolon_to_map, Configuration};
+    use super::{environment_variable_to_key, properties_file_content_to_map, properties_separate_by_semicolon_to_map, Configuration, ConfigurationFormat, ConfigurationLayers, ConfigurationValidationError, REDACTED_VALUE};
 
-    const TEST_CONF_PROPERTIES_FILE: &'static str  =r#"
+    const TEST_CONF_PROPERTIES_FILE: &str  =r#"
 
 # Cluster configurations
 cluster.authKey=abcd1234
 cluster.controller.host=webhooks.my-web.services
 cluster.requestTimeout=10000
+cluster.roles=messageProcessor, storage
+cluster.storage.port=2462
+cluster.storage.url=http://storage-1:2462/
+cluster.storage.replicas=http://storage-2:2462, http://storage-3:2462
+cluster.storage.readUrl=http://storage-2:2462/
+cluster.antiEntropy.interval=5m
+cluster.compression=lz4
+cluster.joinToken=angler-join.0.0.0
+cluster.revokedJoinTokens=t-1, t-2
+cluster.keepalive.interval=10s
+cluster.keepalive.threshold=3
+cluster.clockSkew.threshold=5s
+cluster.clockSkew.fence=true
 
 # Database properties
 db.deadMessages.retention=30d
 db.deliveredMessages.retention=30d
+db.writes.batchSize=250
+db.cache.capacity=10000
+db.writes.flushInterval=50
+db.payloadIndex.order.created=order.id, customer.email
 
 # Message Processor configurations
 msgproc.message_delivery_timeout=10000
 msgproc.workers=500
+msgproc.dedup.window=1h
+msgproc.dns.ttl=30s
+msgproc.dns.negativeTtl=5s
+msgproc.destinations.deleteGracePeriod=12h
+msgproc.asyncAck.callbackUrl=http://angler.internal:8080
+msgproc.usage.interval=1h
+msgproc.ids.generator=snowflake
+msgproc.ids.nodeId=12
+msgproc.lagging.backlogAge=5m
+msgproc.lagging.latency=2s
+msgproc.lagging.demote=true
+msgproc.delivery.userAgent=Angler/{version} ({nodeId})
+msgproc.delivery.headers.X-Sender=angler-prod
+msgproc.deathNotifications.recipientId=producers
+msgproc.deathNotifications.eventId=dlq-events
+msgproc.deathNotifications.namespace.billing.recipientId=billing-compensation
+msgproc.interceptors.maxPayloadSize=1048576
+msgproc.interceptors.schema.order.created=/etc/angler/schemas/order.created.json
 
 # Configuration about the client net communication interface
 net.client.protocols=restful
 net.client.restful.port=80
+net.client.restful.address=[::]:80
+net.client.auth.provider=apiKeys
+net.client.auth.apiKeys=billing:k-1, shop:k-2
+net.client.auth.jwtSecret=sso-secret
+net.admin.port=2461
+net.admin.address=[::1]:2461
+net.admin.tls=admin-cert
+net.admin.maxConnections=64
+net.admin.acceptBacklog=16
+net.admin.maxConnectionsPerIp=8
+net.admin.authToken=s3cr3t-admin
 
 # The default values set on retryPolicy if not set by the client
 retryPolicy.defaults.interval=1d
@@ -368,79 +1345,224 @@ retryPolicy.defaults.maxAttempts=7
 
 # The limit (max or min) of interval and resend attempts
 retryPolicy.limit.maxInterval=30d
+retryPolicy.limit.minInterval=1m
 retryPolicy.limit.maxAttempts=20
+retryPolicy.retryOn=5xx,timeout,connect,404
+# The retryPolicy of the messages of a namespace, by its serviceId
+retryPolicy.namespace.billing.defaults.maxAttempts=3
+retryPolicy.namespace.billing.limit.maxAttempts=5
+
+# The settings of the topics created on their first publish
+topics.autoCreate=false
+topics.defaults.ordering=ordered
+topics.defaults.maxPayloadSize=65536
+topics.defaults.schemaRef=/etc/angler/schemas/default.json
+topics.defaults.deliveredMessages.retention=7d
+topics.defaults.deadMessages.retention=90d
+topics.defaults.retryPolicy.interval=[1m, 5m]
+topics.defaults.retryPolicy.maxAttempts=5
     
-    "#;
+    
+"#;
 
-    const TEST_CONF_PROPERTIES_FILE_SEMICOLON: &'static str = "
+    const TEST_CONF_PROPERTIES_FILE_SEMICOLON: &str = "
 cluster.authKey=abcd1234;
 cluster.controller.host=webhooks.my-web.services;
 cluster.requestTimeout=10000;
+cluster.roles=messageProcessor, storage;
+cluster.storage.port=2462;
+cluster.storage.url=http://storage-1:2462/;
+cluster.storage.replicas=http://storage-2:2462, http://storage-3:2462;
+cluster.storage.readUrl=http://storage-2:2462/;
+cluster.antiEntropy.interval=5m;
+cluster.compression=lz4;
+cluster.joinToken=angler-join.0.0.0;
+cluster.revokedJoinTokens=t-1, t-2;
+cluster.keepalive.interval=10s;
+cluster.keepalive.threshold=3;
+cluster.clockSkew.threshold=5s;
+cluster.clockSkew.fence=true;
 db.deadMessages.retention=30d;
 db.deliveredMessages.retention=30d;
+db.writes.batchSize=250;
+db.cache.capacity=10000;
+db.writes.flushInterval=50;
+db.payloadIndex.order.created=order.id, customer.email;
 msgproc.message_delivery_timeout=10000;
 msgproc.workers=500;
+msgproc.dedup.window=1h;
+msgproc.dns.ttl=30s;
+msgproc.dns.negativeTtl=5s;
+msgproc.destinations.deleteGracePeriod=12h;
+msgproc.asyncAck.callbackUrl=http://angler.internal:8080;
+msgproc.usage.interval=1h;
+msgproc.ids.generator=snowflake;
+msgproc.ids.nodeId=12;
+msgproc.lagging.backlogAge=5m;
+msgproc.lagging.latency=2s;
+msgproc.lagging.demote=true;
+msgproc.delivery.userAgent=Angler/{version} ({nodeId});
+msgproc.delivery.headers.X-Sender=angler-prod;
+msgproc.deathNotifications.recipientId=producers;
+msgproc.deathNotifications.eventId=dlq-events;
+msgproc.deathNotifications.namespace.billing.recipientId=billing-compensation;
+msgproc.interceptors.maxPayloadSize=1048576;
+msgproc.interceptors.schema.order.created=/etc/angler/schemas/order.created.json;
 net.client.protocols=restful;
 net.client.restful.port=80;
+net.client.restful.address=[::]:80;
+net.client.auth.provider=apiKeys;
+net.client.auth.apiKeys=billing:k-1, shop:k-2;
+net.client.auth.jwtSecret=sso-secret;
+net.admin.port=2461;
+net.admin.address=[::1]:2461;
+net.admin.tls=admin-cert;
+net.admin.maxConnections=64;
+net.admin.acceptBacklog=16;
+net.admin.maxConnectionsPerIp=8;
+net.admin.authToken=s3cr3t-admin;
 retryPolicy.defaults.interval=1d;
 retryPolicy.defaults.maxAttempts=7;
 retryPolicy.limit.maxInterval=30d;
+retryPolicy.limit.minInterval=1m;
 retryPolicy.limit.maxAttempts=20;
+retryPolicy.retryOn=5xx,timeout,connect,404;
+retryPolicy.namespace.billing.defaults.maxAttempts=3;
+retryPolicy.namespace.billing.limit.maxAttempts=5;
+topics.autoCreate=false;
+topics.defaults.ordering=ordered;
+topics.defaults.maxPayloadSize=65536;
+topics.defaults.schemaRef=/etc/angler/schemas/default.json;
+topics.defaults.deliveredMessages.retention=7d;
+topics.defaults.deadMessages.retention=90d;
+topics.defaults.retryPolicy.interval=[1m, 5m];
+topics.defaults.retryPolicy.maxAttempts=5;
 ";
 
     fn assert_configuration_has_all_props(conf: &Configuration) {
         assert_eq!(conf.cluster.auth_key.as_ref().unwrap(), "abcd1234");
         assert_eq!(conf.cluster.controller_host.as_ref().unwrap(), "webhooks.my-web.services");
         assert_eq!(conf.cluster.request_timeout.unwrap().whole_milliseconds(), 10000);
+        assert_eq!(conf.cluster.roles.as_ref().unwrap().len(), 2);
+        assert_eq!(conf.cluster.storage.as_ref().unwrap().port, 2462);
+        assert_eq!(conf.cluster.storage_url.as_deref(), Some("http://storage-1:2462"));
+        assert_eq!(conf.cluster.storage_replicas.as_ref().unwrap(), &vec!["http://storage-2:2462", "http://storage-3:2462"]);
+        assert_eq!(conf.cluster.storage_read_url.as_deref(), Some("http://storage-2:2462"));
+        assert_eq!(conf.cluster.anti_entropy_interval.unwrap().whole_minutes(), 5);
+        assert_eq!(conf.cluster.compression, Some(ClusterCompression::Lz4));
+        assert_eq!(conf.cluster.join_token.as_deref(), Some("angler-join.0.0.0"));
+        assert_eq!(conf.cluster.revoked_join_tokens, Some(HashSet::from([String::from("t-1"), String::from("t-2")])));
+        assert_eq!(conf.cluster.keepalive_interval.unwrap().whole_seconds(), 10);
+        assert_eq!(conf.cluster.keepalive_threshold, Some(3));
+        assert_eq!(conf.cluster.clock_skew_threshold.unwrap().whole_seconds(), 5);
+        assert_eq!(conf.cluster.clock_skew_fence, Some(true));
 
         assert_eq!(conf.database.dead_messages_retention.unwrap().whole_days(), 30);
         assert_eq!(conf.database.delivered_messages_retention.unwrap().whole_days(), 30);
+        assert_eq!(conf.database.write_batch_size.unwrap(), 250);
+        assert_eq!(conf.database.cache_capacity, Some(10000));
+        assert_eq!(conf.database.write_flush_interval.unwrap().whole_milliseconds(), 50);
+        assert_eq!(conf.database.payload_index.as_ref().unwrap().get("order.created").unwrap(), &vec!["order.id", "customer.email"]);
 
         assert_eq!(conf.messages_processor.message_delivery_timeout.unwrap().whole_milliseconds(), 10000);
         assert_eq!(conf.messages_processor.workers_count.unwrap(), 500);
+        assert_eq!(conf.messages_processor.dedup_window.unwrap().whole_hours(), 1);
+        assert_eq!(conf.messages_processor.dns_ttl.unwrap().whole_seconds(), 30);
+        assert_eq!(conf.messages_processor.dns_negative_ttl.unwrap().whole_seconds(), 5);
+        assert_eq!(conf.messages_processor.delete_grace_period.unwrap().whole_hours(), 12);
+        assert_eq!(conf.messages_processor.ack_callback_url.as_deref(), Some("http://angler.internal:8080"));
+        assert_eq!(conf.messages_processor.delivery_user_agent.as_deref(), Some("Angler/{version} ({nodeId})"));
+        assert_eq!(conf.messages_processor.delivery_headers.as_ref().unwrap().get("X-Sender").unwrap(), "angler-prod");
+        assert_eq!(conf.messages_processor.death_notifications_recipient_id.as_deref(), Some("producers"));
+        assert_eq!(conf.messages_processor.death_notifications_event_id.as_deref(), Some("dlq-events"));
+        assert_eq!(conf.messages_processor.death_notifications_namespaces.as_ref().unwrap().get("billing").unwrap(), "billing-compensation");
+        assert_eq!(conf.messages_processor.usage_interval.unwrap().whole_hours(), 1);
+        assert_eq!(conf.messages_processor.id_generator, Some(IdGeneratorKind::Snowflake));
+        assert_eq!(conf.messages_processor.snowflake_node_id, Some(12));
+        assert_eq!(conf.messages_processor.lagging_backlog_age.unwrap().whole_minutes(), 5);
+        assert_eq!(conf.messages_processor.lagging_latency.unwrap().whole_seconds(), 2);
+        assert_eq!(conf.messages_processor.lagging_demote, Some(true));
+        assert_eq!(conf.messages_processor.max_payload_size.unwrap(), 1048576);
+        assert_eq!(conf.messages_processor.topic_schemas.as_ref().unwrap().get("order.created").unwrap(), "/etc/angler/schemas/order.created.json");
 
         assert!(conf.networking.client_protocols.as_ref().unwrap().contains("restful"));
-        assert_eq!(conf.networking.restful_port.unwrap(), 80);
+        assert_eq!(conf.networking.restful.as_ref().unwrap().to_string(), "[::]:80");
+        assert_eq!(conf.networking.admin.as_ref().unwrap().to_string(), "[::1]:2461");
+        assert_eq!(conf.networking.admin.as_ref().unwrap().tls.as_deref(), Some("admin-cert"));
+        assert_eq!(conf.networking.admin.as_ref().unwrap().limits, ConnectionLimits { max_connections: Some(64), accept_backlog: Some(16), max_connections_per_ip: Some(8) });
+        assert_eq!(conf.networking.admin_auth_token.as_deref(), Some("s3cr3t-admin"));
+        assert_eq!(conf.networking.client_auth_provider, Some(AuthProviderKind::ApiKeys));
+        assert_eq!(conf.networking.client_api_keys.as_ref().unwrap()[1], (String::from("shop"), String::from("k-2")));
+        assert_eq!(conf.networking.client_jwt_secret.as_deref(), Some("sso-secret"));
 
         assert_eq!(conf.retry_policy.default_interval.as_ref().unwrap().total_duration().whole_days(), 1);
         assert_eq!(conf.retry_policy.default_max_attempts.unwrap(), 7);
 
         assert_eq!(conf.retry_policy.max_interval_limit.unwrap().whole_days(), 30);
+        assert_eq!(conf.retry_policy.min_interval_limit.unwrap().whole_minutes(), 1);
         assert_eq!(conf.retry_policy.max_attempts_limit.unwrap(), 20);
+        assert_eq!(conf.retry_policy.retry_on.as_ref().unwrap().to_string(), "connectTimeout,connection,responseTimeout,http5xx,404");
+        let billing = conf.retry_policy.for_namespace("billing").unwrap();
+        assert_eq!((billing.default_max_attempts, billing.max_attempts_limit), (Some(3), Some(5)));
+        assert_eq!(billing.max_interval_limit.unwrap().whole_days(), 30);
+        assert!(conf.retry_policy.for_namespace("shop").is_none());
+
+        assert_eq!(conf.topics.auto_create, Some(false));
+        assert_eq!(conf.topics.default_ordering, Some(OrderingMode::Ordered));
+        assert_eq!(conf.topics.default_max_payload_size, Some(65536));
+        assert_eq!(conf.topics.default_schema_ref.as_deref(), Some("/etc/angler/schemas/default.json"));
+        assert_eq!(conf.topics.default_delivered_messages_retention.unwrap().whole_days(), 7);
+        assert_eq!(conf.topics.default_dead_messages_retention.unwrap().whole_days(), 90);
+        assert_eq!(conf.topics.default_retry_interval.as_ref().unwrap().total_duration().whole_minutes(), 6);
+        assert_eq!(conf.topics.default_retry_max_attempts, Some(5));
     }
 
     #[test]
     fn test_properties_file_content_to_map_from_a_config_file() {
-        let map = properties_file_content_to_map(&TEST_CONF_PROPERTIES_FILE);
+        let map = properties_file_content_to_map(TEST_CONF_PROPERTIES_FILE);
         assert_eq!(map.get("cluster.authKey").unwrap(), "abcd1234");
         assert_eq!(map.get("cluster.controller.host").unwrap(), "webhooks.my-web.services");
         assert_eq!(map.get("cluster.requestTimeout").unwrap(), "10000");
+        assert_eq!(map.get("cluster.roles").unwrap(), "messageProcessor, storage");
 
         assert_eq!(map.get("db.deadMessages.retention").unwrap(), "30d");
         assert_eq!(map.get("db.deliveredMessages.retention").unwrap(), "30d");
 
         assert_eq!(map.get("msgproc.message_delivery_timeout").unwrap(), "10000");
         assert_eq!(map.get("msgproc.workers").unwrap(), "500");
+        assert_eq!(map.get("msgproc.dedup.window").unwrap(), "1h");
+        assert_eq!(map.get("msgproc.dns.ttl").unwrap(), "30s");
+        assert_eq!(map.get("msgproc.dns.negativeTtl").unwrap(), "5s");
+        assert_eq!(map.get("msgproc.destinations.deleteGracePeriod").unwrap(), "12h");
+        assert_eq!(map.get("msgproc.asyncAck.callbackUrl").unwrap(), "http://angler.internal:8080");
+        assert_eq!(map.get("msgproc.usage.interval").unwrap(), "1h");
+        assert_eq!(map.get("msgproc.interceptors.maxPayloadSize").unwrap(), "1048576");
 
         assert_eq!(map.get("net.client.protocols").unwrap(), "restful");
         assert_eq!(map.get("net.client.restful.port").unwrap(), "80");
+        assert_eq!(map.get("net.client.restful.address").unwrap(), "[::]:80");
+        assert_eq!(map.get("net.admin.port").unwrap(), "2461");
+        assert_eq!(map.get("net.admin.address").unwrap(), "[::1]:2461");
+        assert_eq!(map.get("net.admin.authToken").unwrap(), "s3cr3t-admin");
 
         assert_eq!(map.get("retryPolicy.defaults.interval").unwrap(), "1d");
         assert_eq!(map.get("retryPolicy.defaults.maxAttempts").unwrap(), "7");
 
         assert_eq!(map.get("retryPolicy.limit.maxInterval").unwrap(), "30d");
+        assert_eq!(map.get("retryPolicy.limit.minInterval").unwrap(), "1m");
         assert_eq!(map.get("retryPolicy.limit.maxAttempts").unwrap(), "20");
+        assert_eq!(map.get("retryPolicy.retryOn").unwrap(), "5xx,timeout,connect,404");
     }
 
     #[test]
     fn test_if_all_configurations_are_set_in_configuration_struct_from_hash_map() {
-        let conf = Configuration::from_map(&properties_file_content_to_map(&TEST_CONF_PROPERTIES_FILE));
+        let conf = Configuration::from_map(&properties_file_content_to_map(TEST_CONF_PROPERTIES_FILE)).unwrap();
         assert_configuration_has_all_props(&conf);
     }
 
     #[test]
     fn test_if_all_configurations_are_set_in_semicolon_conf_string_from_hash_map() {
-        let conf = Configuration::from_map(&properties_separate_by_semicolon_to_map(&TEST_CONF_PROPERTIES_FILE_SEMICOLON));
+        let conf = Configuration::from_map(&properties_separate_by_semicolon_to_map(TEST_CONF_PROPERTIES_FILE_SEMICOLON)).unwrap();
         assert_configuration_has_all_props(&conf);
     }
 
@@ -450,6 +1572,88 @@ retryPolicy.limit.maxAttempts=20;
         assert_configuration_has_all_props(&conf);
     }
 
+    /// Write the content into a temporary file with the extension, returning its path
+    fn temporary_file(name: &str, content: &str) -> std::path::PathBuf {
+        let path = std::env::temp_dir().join(format!("angler-{}-{}", std::process::id(), name));
+        std::fs::write(&path, content).unwrap();
+        path
+    }
+
+    #[test]
+    fn test_if_every_configuration_is_read_from_the_sections_of_a_yaml_file() {
+        // each dotted key is nested in the sections of its parts
+        let mut document = JsonValue::object();
+        for (key, value) in properties_file_content_to_map(TEST_CONF_PROPERTIES_FILE) {
+            let (sections, name) = key.rsplit_once('.').unwrap();
+            let mut section = &mut document;
+            for part in sections.split('.') {
+                let JsonValue::Object(map) = section else { unreachable!() };
+                section = map.entry(part.to_string()).or_insert_with(JsonValue::object);
+            }
+            let JsonValue::Object(map) = section else { unreachable!() };
+            map.insert(name.to_string(), JsonValue::from(value));
+        }
+        let path = temporary_file("all.yaml", &to_yaml(&document));
+        assert_configuration_has_all_props(&Configuration::from_file(&path).unwrap());
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_if_toml_and_yaml_files_are_read_by_their_sections() {
+        let toml = temporary_file("config.toml", r#"
+[cluster]
+roles = ["storage"]
+requestTimeout = 2500
+storage = { port = 2462 }
+
+[msgproc]
+workers = 4
+lagging.demote = true
+interceptors.schema."order.created" = "/etc/angler/order.json"
+
+[retryPolicy.defaults]
+interval = ["1m", "5m"]
+"#);
+        let yaml = temporary_file("config.yml", "
+cluster:
+  roles: [storage]
+  requestTimeout: 2500
+  storage:
+    port: 2462
+msgproc:
+  workers: 4
+  lagging: {demote: true}
+  interceptors:
+    schema:
+      order.created: /etc/angler/order.json
+retryPolicy:
+  defaults:
+    interval: [1m, 5m]
+");
+        for path in [&toml, &yaml] {
+            let conf = Configuration::from_file(path).unwrap();
+            assert_eq!(conf.cluster.roles.as_ref().unwrap().iter().collect::<Vec<_>>(), vec![&ApplicationRoles::Storage]);
+            assert_eq!(conf.cluster.request_timeout.unwrap().whole_milliseconds(), 2500);
+            assert_eq!(conf.cluster.storage.as_ref().unwrap().port, 2462);
+            assert_eq!((conf.messages_processor.workers_count, conf.messages_processor.lagging_demote), (Some(4), Some(true)));
+            assert_eq!(conf.messages_processor.topic_schemas.as_ref().unwrap().get("order.created").unwrap(), "/etc/angler/order.json");
+            assert_eq!(conf.retry_policy.default_interval.unwrap().to_string(), "[1m, 5m]");
+        }
+        assert_eq!(ConfigurationFormat::from_path(&toml), ConfigurationFormat::Toml);
+        assert_eq!(ConfigurationFormat::from_path("conf/config.properties"), ConfigurationFormat::Properties);
+
+        // the invalid configurations are told apart from the malformed files
+        std::fs::write(&toml, "[msgproc]\nworkers = 0\n").unwrap();
+        assert_eq!(Configuration::from_toml_file(&toml).unwrap_err().invalid_configurations()[0].key, "msgproc.workers");
+        std::fs::write(&toml, "[msgproc\n").unwrap();
+        let malformed = Configuration::from_toml_file(&toml).unwrap_err();
+        assert!(malformed.invalid_configurations().is_empty() && malformed.to_string().contains("Invalid TOML at line 1"), "{}", malformed);
+        std::fs::write(&yaml, "- a\n").unwrap();
+        assert!(Configuration::from_yaml_file(&yaml).is_err());
+        std::fs::remove_file(toml).unwrap();
+        std::fs::remove_file(yaml).unwrap();
+    }
+
     #[test]
     fn test_configuration_merge() {
         let mut will_be_merged_conf = Configuration::new();
@@ -460,23 +1664,233 @@ retryPolicy.limit.maxAttempts=20;
         assert_ne!(will_be_merged_conf.cluster.auth_key, None);
         assert_ne!(will_be_merged_conf.cluster.controller_host, None);
         assert_ne!(will_be_merged_conf.cluster.request_timeout, None);
+        assert_ne!(will_be_merged_conf.cluster.roles, None);
+        assert_ne!(will_be_merged_conf.cluster.storage, None);
+        assert_ne!(will_be_merged_conf.cluster.storage_url, None);
+        assert_ne!(will_be_merged_conf.cluster.storage_replicas, None);
+        assert_ne!(will_be_merged_conf.cluster.storage_read_url, None);
+        assert_ne!(will_be_merged_conf.cluster.anti_entropy_interval, None);
+        assert_ne!(will_be_merged_conf.cluster.compression, None);
+        assert_ne!(will_be_merged_conf.cluster.join_token, None);
+        assert_ne!(will_be_merged_conf.cluster.revoked_join_tokens, None);
+        assert_ne!(will_be_merged_conf.cluster.keepalive_interval, None);
+        assert_ne!(will_be_merged_conf.cluster.keepalive_threshold, None);
+        assert_ne!(will_be_merged_conf.cluster.clock_skew_threshold, None);
+        assert_ne!(will_be_merged_conf.cluster.clock_skew_fence, None);
 
         // DatabaseConfigurations assertions
         assert_ne!(will_be_merged_conf.database.dead_messages_retention, None);
         assert_ne!(will_be_merged_conf.database.delivered_messages_retention, None);
+        assert_ne!(will_be_merged_conf.database.write_batch_size, None);
+        assert_ne!(will_be_merged_conf.database.cache_capacity, None);
+        assert_ne!(will_be_merged_conf.database.write_flush_interval, None);
+        assert_ne!(will_be_merged_conf.database.payload_index, None);
 
         // MessagesProcessorConfigurations assertions
         assert_ne!(will_be_merged_conf.messages_processor.message_delivery_timeout, None);
         assert_ne!(will_be_merged_conf.messages_processor.workers_count, None);
+        assert_ne!(will_be_merged_conf.messages_processor.dedup_window, None);
+        assert_ne!(will_be_merged_conf.messages_processor.dns_ttl, None);
+        assert_ne!(will_be_merged_conf.messages_processor.dns_negative_ttl, None);
+        assert_ne!(will_be_merged_conf.messages_processor.delete_grace_period, None);
+        assert_ne!(will_be_merged_conf.messages_processor.ack_callback_url, None);
+        assert_ne!(will_be_merged_conf.messages_processor.delivery_user_agent, None);
+        assert_ne!(will_be_merged_conf.messages_processor.delivery_headers, None);
+        assert_ne!(will_be_merged_conf.messages_processor.death_notifications_recipient_id, None);
+        assert_ne!(will_be_merged_conf.messages_processor.death_notifications_event_id, None);
+        assert_ne!(will_be_merged_conf.messages_processor.death_notifications_namespaces, None);
+        assert_ne!(will_be_merged_conf.messages_processor.usage_interval, None);
+        assert_ne!(will_be_merged_conf.messages_processor.id_generator, None);
+        assert_ne!(will_be_merged_conf.messages_processor.snowflake_node_id, None);
+        assert_ne!(will_be_merged_conf.messages_processor.lagging_backlog_age, None);
+        assert_ne!(will_be_merged_conf.messages_processor.lagging_latency, None);
+        assert_ne!(will_be_merged_conf.messages_processor.lagging_demote, None);
+        assert_ne!(will_be_merged_conf.messages_processor.max_payload_size, None);
+        assert_ne!(will_be_merged_conf.messages_processor.topic_schemas, None);
 
         // NetworkingConfiguration assertions
         assert_ne!(will_be_merged_conf.networking.client_protocols, None);
-        assert_ne!(will_be_merged_conf.networking.restful_port, None);
+        assert_ne!(will_be_merged_conf.networking.restful, None);
+        assert_ne!(will_be_merged_conf.networking.admin, None);
+        assert_ne!(will_be_merged_conf.networking.admin_auth_token, None);
+        assert_ne!(will_be_merged_conf.networking.client_auth_provider, None);
+        assert_ne!(will_be_merged_conf.networking.client_api_keys, None);
+        assert_ne!(will_be_merged_conf.networking.client_jwt_secret, None);
 
         // RetryPolicyConfiguration assertions
         assert_ne!(will_be_merged_conf.retry_policy.default_interval, None);
         assert_ne!(will_be_merged_conf.retry_policy.default_max_attempts, None);
         assert_ne!(will_be_merged_conf.retry_policy.max_interval_limit, None);
+        assert_ne!(will_be_merged_conf.retry_policy.min_interval_limit, None);
         assert_ne!(will_be_merged_conf.retry_policy.max_attempts_limit, None);
+        assert_ne!(will_be_merged_conf.retry_policy.retry_on, None);
+
+        // TopicsConfiguration assertions
+        assert_ne!(will_be_merged_conf.topics.auto_create, None);
+        assert_ne!(will_be_merged_conf.topics.default_ordering, None);
+        assert_ne!(will_be_merged_conf.topics.default_max_payload_size, None);
+        assert_ne!(will_be_merged_conf.topics.default_schema_ref, None);
+        assert_ne!(will_be_merged_conf.topics.default_delivered_messages_retention, None);
+        assert_ne!(will_be_merged_conf.topics.default_dead_messages_retention, None);
+        assert_ne!(will_be_merged_conf.topics.default_retry_interval, None);
+        assert_ne!(will_be_merged_conf.topics.default_retry_max_attempts, None);
+        assert!(will_be_merged_conf.retry_policy.namespaces.is_some());
+    }
+
+    #[test]
+    fn test_if_listener_is_read_from_the_port_when_the_address_is_not_set() {
+        let listener = Configuration::from_map(&properties_separate_by_semicolon_to_map("net.admin.port=2461;")).unwrap().networking.admin.unwrap();
+        assert_eq!(listener.port, 2461);
+        assert!(listener.ip.is_unspecified());
+        assert_eq!(listener.tls, None);
     }
-}
\ No newline at end of file
+
+    /// Return the `key: expected` of each invalid configuration of the semicolon separated content
+    fn invalid_configurations(content: &str) -> Vec<String> {
+        let invalid = Configuration::from_map(&properties_separate_by_semicolon_to_map(content)).unwrap_err();
+        invalid.iter().map(|invalid| format!("{}: {}", invalid.key, invalid.expected)).collect()
+    }
+
+    #[test]
+    fn test_if_listener_port_out_of_range_is_rejected() {
+        assert_eq!(invalid_configurations("net.client.restful.port=70000;"), vec!["net.client.restful.port: a integer between 1 and 65535"]);
+    }
+
+    #[test]
+    fn test_if_auth_providers_without_their_keys_are_rejected() {
+        assert_eq!(invalid_configurations("net.client.auth.provider=jwt;"), vec!["net.client.auth.provider: jwt with the net.client.auth.jwtSecret set"]);
+        assert_eq!(invalid_configurations("net.client.auth.provider=apiKeys;net.client.auth.jwtSecret=s-1;"), vec!["net.client.auth.provider: apiKeys with the net.client.auth.apiKeys set"]);
+        // the malformed keys are reported by themselves
+        assert_eq!(invalid_configurations("net.client.auth.provider=apiKeys;net.client.auth.apiKeys=billing;"), vec!["net.client.auth.apiKeys: a list of subject:key, like billing:k-1, shop:k-2"]);
+        assert!(Configuration::from_map(&properties_separate_by_semicolon_to_map("net.client.auth.provider=jwt;net.client.auth.jwtSecret=s-1;")).is_ok());
+    }
+
+    #[test]
+    fn test_if_unknown_role_is_rejected() {
+        assert_eq!(invalid_configurations("cluster.roles=storage,broker;"), vec!["cluster.roles: a list of roles, messageProcessor or storage"]);
+    }
+
+    #[test]
+    fn test_if_malformed_listener_address_is_rejected() {
+        assert_eq!(invalid_configurations("net.admin.address=::1:2461;"), vec!["net.admin.address: a address like [::]:2460 or 0.0.0.0:2460"]);
+        assert_eq!(invalid_configurations("net.admin.tls=cert.pem;"), vec!["net.admin.tls: set with net.admin.address or net.admin.port"]);
+    }
+
+    #[test]
+    fn test_if_delivery_headers_set_by_the_delivery_are_rejected() {
+        let invalid = invalid_configurations("msgproc.delivery.headers.X-Angler-Attempt=1;");
+        assert_eq!(invalid.len(), 1);
+        assert!(invalid[0].starts_with("msgproc.delivery.headers.X-Angler-Attempt: a header not set by the delivery"));
+    }
+
+    #[test]
+    fn test_if_every_invalid_configuration_is_returned_at_once() {
+        let content = "msgproc.workers=0;db.deadMessages.retention=7 days;cluster.clockSkew.fence=yes;retryPolicy.namespace.billing.defaults.maxAttempts=-1;retryPolicy.retryOn=http6xx;msgproc.ids.generator=uuidv7;";
+        let invalid = Configuration::from_map(&properties_separate_by_semicolon_to_map(content)).unwrap_err();
+        assert_eq!(invalid.iter().map(|invalid| invalid.key.as_str()).collect::<Vec<_>>(), vec![
+            "cluster.clockSkew.fence",
+            "db.deadMessages.retention",
+            "msgproc.workers",
+            "retryPolicy.namespace.billing.defaults.maxAttempts",
+            "retryPolicy.retryOn",
+        ]);
+        assert_eq!(invalid[1].to_string(), "db.deadMessages.retention should be a Duration, like 30m, but is 7 days");
+        assert_eq!(invalid[2], ConfigurationValidationError { key: String::from("msgproc.workers"), value: String::from("0"), expected: String::from("a integer >= 1") });
+        assert!(invalid[4].expected.starts_with("a list of error classes and HTTP statuses (http6xx is not an error class"), "{}", invalid[4].expected);
+    }
+
+    #[test]
+    fn test_if_the_values_of_invalid_secrets_are_redacted() {
+        let content = "net.client.auth.apiKeys=k-1 k-2;net.client.auth.provider=apiKey";
+        let invalid = Configuration::from_map(&properties_separate_by_semicolon_to_map(content)).unwrap_err();
+        let messages: Vec<String> = invalid.iter().map(ToString::to_string).collect();
+        assert_eq!(messages, vec!["net.client.auth.apiKeys should be a list of subject:key, like billing:k-1, shop:k-2, but is <redacted>", "net.client.auth.provider should be apiKeys or jwt, but is apiKey"]);
+
+        let invalid = Configuration::from_environment([(String::from("ANGLER__NET__CLIENT__AUTH__API_KEYS"), String::from("k-1"))]).unwrap_err();
+        assert_eq!(invalid[0].value, REDACTED_VALUE);
+    }
+
+    #[test]
+    fn test_if_environment_variables_are_read_by_their_dotted_keys() {
+        assert_eq!(environment_variable_to_key("ANGLER__CLUSTER__AUTH_KEY").as_deref(), Some("cluster.authKey"));
+        assert_eq!(environment_variable_to_key("ANGLER__RETRY_POLICY__NAMESPACE__billing__LIMIT__MAX_ATTEMPTS").as_deref(), Some("retryPolicy.namespace.billing.limit.maxAttempts"));
+        assert_eq!(environment_variable_to_key("ANGLER__MSGPROC__message_delivery_timeout").as_deref(), Some("msgproc.message_delivery_timeout"));
+        assert_eq!(environment_variable_to_key("ANGLER__NET__ADMIN__MAX_CONNECTIONS_PER_IP").as_deref(), Some("net.admin.maxConnectionsPerIp"));
+        assert_eq!(environment_variable_to_key("ANGLER_CFG"), None);
+        assert_eq!(environment_variable_to_key("ANGLER__CLUSTER____AUTH_KEY"), None);
+
+        let vars = [("ANGLER__CLUSTER__AUTH_KEY", "k-1"), ("ANGLER__MSGPROC__delivery__headers__X-Sender", "angler-eu"), ("PATH", "/bin")];
+        let conf = Configuration::from_environment(vars.map(|(name, value)| (name.to_string(), value.to_string()))).unwrap();
+        assert_eq!(conf.cluster.auth_key.as_deref(), Some("k-1"));
+        assert_eq!(conf.messages_processor.delivery_headers.unwrap().get("X-Sender").map(String::as_str), Some("angler-eu"));
+
+        let invalid = Configuration::from_environment([(String::from("ANGLER__MSGPROC__WORKERS"), String::from("0"))]).unwrap_err();
+        assert_eq!(invalid[0].to_string(), "ANGLER__MSGPROC__WORKERS should be a integer >= 1, but is 0");
+    }
+
+    #[test]
+    fn test_if_environment_variables_override_single_keys_of_the_file() {
+        let mut layers = ConfigurationLayers::new();
+        layers.insert_map(properties_separate_by_semicolon_to_map("net.admin.port=2461; net.admin.tls=admin-cert; net.admin.maxConnectionsPerIp=8; retryPolicy.limit.maxInterval=30m"));
+        let vars = [("ANGLER__NET__ADMIN__PORT", "2470"), ("ANGLER__NET__ADMIN__MAX_CONNECTIONS", "64"), ("ANGLER__RETRY_POLICY__DEFAULTS__INTERVAL", "[1m, 1h]")];
+        let names = layers.insert_environment(vars.map(|(name, value)| (name.to_string(), value.to_string())));
+        assert_eq!(names, vec!["ANGLER__NET__ADMIN__MAX_CONNECTIONS", "ANGLER__NET__ADMIN__PORT", "ANGLER__RETRY_POLICY__DEFAULTS__INTERVAL"]);
+
+        // the defaults of the environment are checked against the limits of the file
+        let invalid = layers.to_configuration().unwrap_err();
+        assert_eq!(invalid.len(), 1);
+        assert!(invalid[0].to_string().starts_with("ANGLER__RETRY_POLICY__DEFAULTS__INTERVAL should be within the retryPolicy.limit configurations"), "{}", invalid[0]);
+
+        layers.insert_map(HashMap::from([(String::from("retryPolicy.defaults.interval"), String::from("[1m, 5m]"))]));
+        let admin = layers.to_configuration().unwrap().networking.admin.unwrap();
+        assert_eq!((admin.port, admin.tls.as_deref()), (2470, Some("admin-cert")));
+        assert_eq!((admin.limits.max_connections, admin.limits.max_connections_per_ip), (Some(64), Some(8)));
+    }
+
+    #[test]
+    fn test_if_default_intervals_beyond_the_limits_are_rejected_with_their_elements() {
+        let content = "retryPolicy.limit.maxInterval=30m;retryPolicy.defaults.interval=[1m, 5m, 10m, 2d];retryPolicy.namespace.billing.limit.maxAttempts=1;retryPolicy.namespace.billing.defaults.interval=[1m, 5m];topics.defaults.retryPolicy.interval=1m then every 1h";
+        let invalid = Configuration::from_map(&properties_separate_by_semicolon_to_map(content)).unwrap_err();
+        assert_eq!(invalid.iter().map(ToString::to_string).collect::<Vec<_>>(), vec![
+            "retryPolicy.defaults.interval should be within the retryPolicy.limit configurations (element 4 (2d) exceeds maxInterval 30m), but is [1m, 5m, 10m, 2d]",
+            "retryPolicy.namespace.billing.defaults.interval should be within the retryPolicy.limit configurations (the sequence has 2 elements, more than the limit of 1), but is [1m, 5m]",
+            "topics.defaults.retryPolicy.interval should be within the retryPolicy.limit configurations (the tail (every 1h) exceeds maxInterval 30m), but is 1m then every 1h",
+        ]);
+        assert!(Configuration::from_map(&properties_separate_by_semicolon_to_map("retryPolicy.limit.maxInterval=30m;retryPolicy.defaults.interval=[1m, 30m]")).is_ok());
+    }
+
+    #[test]
+    fn test_if_generated_properties_are_read_back_and_garbage_values_are_refused_without_panicking() {
+        let mut rng = FastRng::with_seed(1254);
+        let text = |rng: &mut FastRng, alphabet: &[char], len: usize| -> String {
+            (0..1 + rng.next_u64() as usize % len).map(|_| alphabet[rng.next_u64() as usize % alphabet.len()]).collect()
+        };
+        let key_alphabet: Vec<char> = "abcXYZ019.-_".chars().collect();
+        let value_alphabet: Vec<char> = "abc019 =#[],.:/é".chars().collect();
+        for _ in 0..300 {
+            let mut expected = std::collections::HashMap::new();
+            let (mut file, mut semicolon) = (String::new(), String::new());
+            for _ in 0..rng.next_u64() % 8 {
+                let key = text(&mut rng, &key_alphabet, 12);
+                let value = text(&mut rng, &value_alphabet, 16).trim().to_string();
+                let spacing = [" ", "", "\t"][rng.next_u64() as usize % 3];
+                file.push_str(&format!("{}{}{}={}{}\r\n# {}\n\n", spacing, key, spacing, spacing, value, text(&mut rng, &value_alphabet, 8)));
+                semicolon.push_str(&format!("{}{} = {};", spacing, key, value));
+                expected.insert(key, value);
+            }
+            assert_eq!(properties_file_content_to_map(&file), expected, "{:?}", file);
+            assert_eq!(properties_separate_by_semicolon_to_map(&semicolon), expected, "{:?}", semicolon);
+        }
+
+        // the values of every configuration from a untrusted source, like the environment
+        let keys: Vec<String> = properties_file_content_to_map(TEST_CONF_PROPERTIES_FILE).into_keys().collect();
+        let garbage: Vec<char> = "0123456789smhdw-.,;:[]{}=_ xyzé".chars().collect();
+        for _ in 0..300 {
+            let map = keys.iter().map(|key| (key.clone(), text(&mut rng, &garbage, 24))).collect();
+            let _ = Configuration::from_map(&map);
+            let name: String = format!("ANGLER__{}", text(&mut rng, &"AB_é-".chars().collect::<Vec<_>>(), 12));
+            let _ = environment_variable_to_key(&name);
+        }
+        assert_eq!(environment_variable_to_key("ANGLER__A_éB").as_deref(), Some("aéb"));
+    }
+}
@@ -1,2 +1,4 @@
 pub mod appenv;
+pub mod banner;
+pub mod completions;
 pub mod config;
\ No newline at end of file
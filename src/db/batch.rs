@@ -0,0 +1,354 @@
+use std::{
+    sync::{mpsc::{self, Receiver, RecvTimeoutError, Sender}, Arc},
+    thread::{self, JoinHandle},
+    time::{Duration, Instant},
+};
+
+use crate::{
+    ctx::config::DatabaseConfigurations,
+    log,
+    msgproc::pipeline::{PipelineMetrics, PipelineStage, STORE_WRITER},
+    utils::log::Level,
+};
+
+use super::{MessageStore, StoreError, StoreWrite};
+
+/// The default value of `db.writes.batchSize`
+const DEFAULT_BATCH_SIZE: usize = 500;
+
+/// The default value of `db.writes.flushInterval`
+const DEFAULT_FLUSH_INTERVAL: Duration = Duration::from_millis(50);
+
+/// Store how the BatchedStoreWriter should group the writes
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct BatchConfiguration {
+    /// When the batch reaches this size it is flushed immediately
+    pub max_batch_size: usize,
+    /// The maximum amount of time that the first write of a batch waits until the batch is flushed
+    pub flush_interval: Duration,
+}
+
+impl BatchConfiguration {
+    /// Create a BatchConfiguration from the `db.writes.` configurations, using default values
+    /// for the ones that are not set
+    pub fn from_configuration(conf: &DatabaseConfigurations) -> BatchConfiguration {
+        BatchConfiguration {
+            max_batch_size: conf.write_batch_size.unwrap_or(DEFAULT_BATCH_SIZE).max(1),
+            flush_interval: conf.write_flush_interval
+                .map(|d| d.unsigned_abs())
+                .filter(|d| !d.is_zero())
+                .unwrap_or(DEFAULT_FLUSH_INTERVAL),
+        }
+    }
+}
+
+impl Default for BatchConfiguration {
+    fn default() -> Self {
+        BatchConfiguration { max_batch_size: DEFAULT_BATCH_SIZE, flush_interval: DEFAULT_FLUSH_INTERVAL }
+    }
+}
+
+enum WriterCommand {
+    Write(Box<StoreWrite>),
+    Flush(Sender<Result<(), StoreError>>),
+}
+
+/// Group the writes sent to a MessageStore into batches that are flushed when the batch reaches
+/// `max_batch_size` or when its first write waited `flush_interval`, so the store persists many
+/// attempt outcomes in a single write instead of one write per attempt.
+pub struct BatchedStoreWriter {
+    sender: Option<Sender<WriterCommand>>,
+    flusher: Option<JoinHandle<()>>,
+    metrics: Arc<PipelineMetrics>,
+}
+
+impl BatchedStoreWriter {
+    /// Create a writer and start its flusher thread
+    pub fn new(store: Arc<dyn MessageStore>, config: BatchConfiguration) -> BatchedStoreWriter {
+        BatchedStoreWriter::with_metrics(store, config, Arc::default())
+    }
+
+    /// Create a writer that counts its pending writes and times its batches in the metrics
+    pub fn with_metrics(store: Arc<dyn MessageStore>, config: BatchConfiguration, metrics: Arc<PipelineMetrics>) -> BatchedStoreWriter {
+        let (sender, receiver) = mpsc::channel();
+        let flusher_metrics = metrics.clone();
+        let flusher = thread::Builder::new()
+            .name(String::from("angler-store-writer"))
+            .spawn(move || run_flusher(store, config, receiver, &flusher_metrics))
+            .expect("failed to spawn the store writer thread");
+
+        BatchedStoreWriter { sender: Some(sender), flusher: Some(flusher), metrics }
+    }
+
+    /// Queue a write to be applied in the next batch
+    pub fn submit(&self, write: StoreWrite) -> Result<(), StoreError> {
+        // counted before it is sent, so the flusher never persists a write that was not counted
+        self.metrics.write_submitted();
+        self.send(WriterCommand::Write(Box::new(write))).inspect_err(|_| self.metrics.writes_finished(1))
+    }
+
+    /// Flush all the queued writes into the store and wait until they are persisted. If a previous
+    /// batch failed to be written its error is returned here
+    pub fn flush(&self) -> Result<(), StoreError> {
+        let (reply_sender, reply_receiver) = mpsc::channel();
+        self.send(WriterCommand::Flush(reply_sender))?;
+        reply_receiver.recv().map_err(|_| StoreError::WriterClosed)?
+    }
+
+    fn send(&self, command: WriterCommand) -> Result<(), StoreError> {
+        self.sender.as_ref()
+            .ok_or(StoreError::WriterClosed)?
+            .send(command)
+            .map_err(|_| StoreError::WriterClosed)
+    }
+}
+
+/// Dropping the writer flushes all the pending writes
+impl Drop for BatchedStoreWriter {
+    fn drop(&mut self) {
+        // closing the channel makes the flusher write the last batch and finish
+        self.sender.take();
+        if let Some(flusher) = self.flusher.take() {
+            let _ = flusher.join();
+        }
+    }
+}
+
+fn run_flusher(store: Arc<dyn MessageStore>, config: BatchConfiguration, receiver: Receiver<WriterCommand>, metrics: &PipelineMetrics) {
+    let mut pending: Vec<StoreWrite> = Vec::with_capacity(config.max_batch_size);
+    let mut deadline = Instant::now();
+    let mut last_error: Option<StoreError> = None;
+
+    loop {
+        // when there is nothing to write the flusher sleeps until a new command arrives
+        let command = if pending.is_empty() {
+            receiver.recv().map_err(|_| RecvTimeoutError::Disconnected)
+        } else {
+            receiver.recv_timeout(deadline.saturating_duration_since(Instant::now()))
+        };
+
+        match command {
+            Ok(WriterCommand::Write(write)) => {
+                if pending.is_empty() {
+                    deadline = Instant::now() + config.flush_interval;
+                }
+                pending.push(*write);
+                if pending.len() >= config.max_batch_size {
+                    flush_pending(store.as_ref(), &mut pending, &mut last_error, &mut deadline, &config, metrics);
+                }
+            }
+            Ok(WriterCommand::Flush(reply)) => {
+                flush_pending(store.as_ref(), &mut pending, &mut last_error, &mut deadline, &config, metrics);
+                let _ = reply.send(last_error.take().map_or(Ok(()), Err));
+            }
+            Err(RecvTimeoutError::Timeout) => {
+                flush_pending(store.as_ref(), &mut pending, &mut last_error, &mut deadline, &config, metrics);
+            }
+            Err(RecvTimeoutError::Disconnected) => {
+                flush_pending(store.as_ref(), &mut pending, &mut last_error, &mut deadline, &config, metrics);
+                if let Some(err) = last_error {
+                    log!(Level::Error, "Failed to write {} pending writes into the store: {}", pending.len(), err);
+                }
+                return;
+            }
+        }
+    }
+}
+
+/// Write the pending batch into the store. A batch that failed with a transient error is kept so
+/// it is retried on the next flush. One that failed for good is written write by write instead, so
+/// the writes that can never be applied, like the updates of a purged message, are dropped instead
+/// of failing every later batch
+fn flush_pending(
+    store: &dyn MessageStore,
+    pending: &mut Vec<StoreWrite>,
+    last_error: &mut Option<StoreError>,
+    deadline: &mut Instant,
+    config: &BatchConfiguration,
+    metrics: &PipelineMetrics,
+) {
+    if pending.is_empty() {
+        return;
+    }
+
+    let started = Instant::now();
+    let result = store.write_batch(pending);
+    metrics.record(STORE_WRITER, PipelineStage::Persist, started.elapsed());
+    match result {
+        Ok(()) => {
+            metrics.writes_finished(pending.len());
+            pending.clear();
+        }
+        Err(err) if err.is_permanent() => {
+            let mut writes = std::mem::take(pending).into_iter();
+            while let Some(write) = writes.next() {
+                match store.write_batch(std::slice::from_ref(&write)) {
+                    Ok(()) => metrics.writes_finished(1),
+                    Err(err) if err.is_permanent() => {
+                        log!(Level::Error, "Dropped a write that the store can not apply: {}", err);
+                        metrics.writes_finished(1);
+                        *last_error = Some(err);
+                    }
+                    Err(err) => {
+                        // the rest is kept in order, to be retried with the next flush
+                        pending.push(write);
+                        pending.extend(writes.by_ref());
+                        *last_error = Some(err);
+                        *deadline = Instant::now() + config.flush_interval;
+                    }
+                }
+            }
+        }
+        Err(err) => {
+            *last_error = Some(err);
+            *deadline = Instant::now() + config.flush_interval;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{atomic::{AtomicUsize, Ordering}, Mutex};
+
+    use crate::{db::{memory::MemoryStore, MessageQuery, PurgeScope}, msgproc::message::{AttemptOutcome, AttemptRecord, DeliveryError, DeliveryErrorClass, Message, MessageStatus}};
+
+    use super::*;
+
+    /// A MessageStore that counts how many batches were written into the inner MemoryStore, failing
+    /// the first `failures` of them as a backend that is down
+    struct CountingStore {
+        inner: MemoryStore,
+        batches: AtomicUsize,
+        batch_sizes: Mutex<Vec<usize>>,
+        failures: AtomicUsize,
+    }
+
+    impl CountingStore {
+        fn new() -> CountingStore {
+            CountingStore { inner: MemoryStore::new(), batches: AtomicUsize::new(0), batch_sizes: Mutex::new(vec![]), failures: AtomicUsize::new(0) }
+        }
+    }
+
+    impl MessageStore for CountingStore {
+        fn write_batch(&self, writes: &[StoreWrite]) -> Result<(), StoreError> {
+            if self.failures.fetch_update(Ordering::SeqCst, Ordering::SeqCst, |failures| failures.checked_sub(1)).is_ok() {
+                return Err(StoreError::Backend(String::from("the database is down")));
+            }
+            self.batches.fetch_add(1, Ordering::SeqCst);
+            self.batch_sizes.lock().unwrap().push(writes.len());
+            self.inner.write_batch(writes)
+        }
+
+        fn get_message(&self, message_id: &str) -> Result<Option<Message>, StoreError> {
+            self.inner.get_message(message_id)
+        }
+
+        fn get_attempts(&self, message_id: &str) -> Result<Vec<AttemptRecord>, StoreError> {
+            self.inner.get_attempts(message_id)
+        }
+
+        fn find_messages(&self, query: &MessageQuery) -> Result<Vec<Message>, StoreError> {
+            self.inner.find_messages(query)
+        }
+
+        fn find_by_producer_message_id(&self, namespace: &str, topic: &str, producer_message_id: &str) -> Result<Option<Message>, StoreError> {
+            self.inner.find_by_producer_message_id(namespace, topic, producer_message_id)
+        }
+
+        fn purge_finished(&self, status: MessageStatus, finished_before: time::OffsetDateTime, scope: &PurgeScope) -> Result<usize, StoreError> {
+            self.inner.purge_finished(status, finished_before, scope)
+        }
+    }
+
+    fn message(id: &str) -> Message {
+        Message::new(id.to_string(), "recipient".to_string(), "service".to_string(), "event".to_string(), vec![])
+    }
+
+    fn attempt(message_id: &str, attempt: u16) -> StoreWrite {
+        StoreWrite::RecordAttempt(AttemptRecord {
+            message_id: message_id.to_string(),
+            attempt,
+            finished_at: time::OffsetDateTime::now_utc(),
+            outcome: AttemptOutcome::Failed(DeliveryError::new(DeliveryErrorClass::Connection, "connection refused")),
+        })
+    }
+
+    #[test]
+    fn test_if_writes_are_grouped_by_batch_size() {
+        let store = Arc::new(CountingStore::new());
+        let config = BatchConfiguration { max_batch_size: 10, flush_interval: Duration::from_secs(60) };
+        let writer = BatchedStoreWriter::new(store.clone(), config);
+
+        writer.submit(StoreWrite::InsertMessage(Box::new(message("a")))).unwrap();
+        for i in 1..=19 {
+            writer.submit(attempt("a", i)).unwrap();
+        }
+        writer.flush().unwrap();
+
+        assert_eq!(store.batches.load(Ordering::SeqCst), 2);
+        assert_eq!(*store.batch_sizes.lock().unwrap(), vec![10, 10]);
+        assert_eq!(store.get_attempts("a").unwrap().len(), 19);
+        assert_eq!(store.get_message("a").unwrap().unwrap().attempts, 19);
+    }
+
+    #[test]
+    fn test_if_writes_are_flushed_after_flush_interval() {
+        let store = Arc::new(CountingStore::new());
+        let config = BatchConfiguration { max_batch_size: 1000, flush_interval: Duration::from_millis(10) };
+        let writer = BatchedStoreWriter::new(store.clone(), config);
+
+        writer.submit(StoreWrite::InsertMessage(Box::new(message("a")))).unwrap();
+        writer.submit(StoreWrite::UpdateStatus { message_id: "a".to_string(), status: MessageStatus::Delivered, next_attempt_at: None }).unwrap();
+        thread::sleep(Duration::from_millis(200));
+
+        assert_eq!(store.batches.load(Ordering::SeqCst), 1);
+        assert_eq!(store.get_message("a").unwrap().unwrap().status, MessageStatus::Delivered);
+    }
+
+    #[test]
+    fn test_if_failed_batch_is_reported_and_retried() {
+        let store = Arc::new(CountingStore::new());
+        let writer = BatchedStoreWriter::new(store.clone(), BatchConfiguration::default());
+        store.inner.write(StoreWrite::InsertMessage(Box::new(message("a")))).unwrap();
+
+        // a backend that is down fails the whole batch
+        store.failures.store(1, Ordering::SeqCst);
+        writer.submit(StoreWrite::UpdateStatus { message_id: "a".to_string(), status: MessageStatus::Dead, next_attempt_at: None }).unwrap();
+        assert!(matches!(writer.flush(), Err(StoreError::Backend(_))));
+
+        // the failed batch is kept, so the next flush writes it once the backend is back
+        writer.flush().unwrap();
+        assert_eq!(store.get_message("a").unwrap().unwrap().status, MessageStatus::Dead);
+    }
+
+    #[test]
+    fn test_if_writes_of_purged_messages_do_not_block_the_next_ones() {
+        let store = Arc::new(CountingStore::new());
+        let config = BatchConfiguration { max_batch_size: 2, flush_interval: Duration::from_secs(60) };
+        let writer = BatchedStoreWriter::new(store.clone(), config);
+
+        // the update of a message purged meanwhile can never be applied
+        writer.submit(StoreWrite::UpdateStatus { message_id: "purged".to_string(), status: MessageStatus::Dead, next_attempt_at: None }).unwrap();
+        writer.submit(StoreWrite::InsertMessage(Box::new(message("a")))).unwrap();
+        writer.submit(StoreWrite::InsertMessage(Box::new(message("b")))).unwrap();
+        writer.submit(StoreWrite::UpdateStatus { message_id: "b".to_string(), status: MessageStatus::Delivered, next_attempt_at: None }).unwrap();
+        assert!(matches!(writer.flush(), Err(StoreError::MessageNotFound(message_id)) if message_id == "purged"));
+
+        assert!(store.get_message("a").unwrap().is_some());
+        assert_eq!(store.get_message("b").unwrap().unwrap().status, MessageStatus::Delivered);
+        writer.submit(StoreWrite::InsertMessage(Box::new(message("c")))).unwrap();
+        writer.flush().unwrap();
+        assert!(store.get_message("c").unwrap().is_some());
+    }
+
+    #[test]
+    fn test_if_pending_writes_are_flushed_on_drop() {
+        let store = Arc::new(CountingStore::new());
+        let config = BatchConfiguration { max_batch_size: 1000, flush_interval: Duration::from_secs(60) };
+        let writer = BatchedStoreWriter::new(store.clone(), config);
+        writer.submit(StoreWrite::InsertMessage(Box::new(message("a")))).unwrap();
+        drop(writer);
+
+        assert!(store.get_message("a").unwrap().is_some());
+    }
+}
@@ -0,0 +1,226 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    sync::{Arc, Mutex},
+};
+
+use time::OffsetDateTime;
+
+use crate::{
+    cluster::antientropy::MessageDigest,
+    msgproc::message::{AttemptRecord, Message, MessageStatus},
+    syscom::usage::UsageRecord,
+};
+
+use super::{MessageQuery, MessageStore, PurgeScope, StoreError, StoreWrite};
+
+/// How the cache of a CachedStore was used since it was created
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct CacheStats {
+    pub hits: u64,
+    pub misses: u64,
+    /// How many messages are cached
+    pub entries: usize,
+}
+
+#[derive(Default)]
+struct CacheData {
+    /// The cached messages and when they were last read
+    messages: HashMap<String, (Message, u64)>,
+    /// The IDs of the cached messages by when they were last read, the least recently used first
+    recency: BTreeMap<u64, String>,
+    /// Incremented on every read and invalidation
+    clock: u64,
+    /// The `clock` of the last invalidation, so a read that started before it is not cached
+    invalidated_at: u64,
+    hits: u64,
+    misses: u64,
+}
+
+impl CacheData {
+    fn remove(&mut self, message_id: &str) {
+        if let Some((_, used)) = self.messages.remove(message_id) {
+            self.recency.remove(&used);
+        }
+    }
+
+    fn invalidate(&mut self, message_id: &str) {
+        self.remove(message_id);
+        self.clock += 1;
+        self.invalidated_at = self.clock;
+    }
+}
+
+/// A MessageStore that keeps the messages last read by ID in memory, so the producers that poll
+/// the status of their messages are answered without reading the inner store. The writes go
+/// through to the inner store and remove the messages they change from the cache, so it is only
+/// coherent when every write of the inner store goes through it, like in the node that keeps the
+/// messages. At most `capacity` messages are kept, evicting the least recently read
+pub struct CachedStore {
+    inner: Arc<dyn MessageStore>,
+    capacity: usize,
+    data: Mutex<CacheData>,
+}
+
+impl CachedStore {
+    pub fn new(inner: Arc<dyn MessageStore>, capacity: usize) -> CachedStore {
+        CachedStore { inner, capacity: capacity.max(1), data: Mutex::default() }
+    }
+
+    /// Cache the store when the `db.cache.capacity` is set and greater than zero
+    pub fn from_capacity(inner: Arc<dyn MessageStore>, capacity: Option<usize>) -> Arc<dyn MessageStore> {
+        match capacity.filter(|capacity| *capacity > 0) {
+            Some(capacity) => Arc::new(CachedStore::new(inner, capacity)),
+            None => inner,
+        }
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        let data = self.data.lock().unwrap();
+        CacheStats { hits: data.hits, misses: data.misses, entries: data.messages.len() }
+    }
+
+    fn invalidate(&self, writes: &[StoreWrite]) {
+        let mut data = self.data.lock().unwrap();
+        for write in writes {
+            match write {
+                StoreWrite::InsertMessage(message) => data.invalidate(&message.id),
+                StoreWrite::UpdateStatus { message_id, .. } | StoreWrite::AnnotateMessage { message_id, .. } => data.invalidate(message_id),
+                StoreWrite::RecordAttempt(attempt) => data.invalidate(&attempt.message_id),
+            }
+        }
+    }
+}
+
+impl MessageStore for CachedStore {
+    fn write_batch(&self, writes: &[StoreWrite]) -> Result<(), StoreError> {
+        let result = self.inner.write_batch(writes);
+        // invalidated even when the write failed, as it may have been partially applied
+        self.invalidate(writes);
+        result
+    }
+
+    fn get_message(&self, message_id: &str) -> Result<Option<Message>, StoreError> {
+        let read_at = {
+            let mut data = self.data.lock().unwrap();
+            data.clock += 1;
+            let used = data.clock;
+            if let Some((message, last_used)) = data.messages.get_mut(message_id) {
+                let (message, last_used) = (message.clone(), std::mem::replace(last_used, used));
+                data.recency.remove(&last_used);
+                data.recency.insert(used, message_id.to_string());
+                data.hits += 1;
+                return Ok(Some(message));
+            }
+            data.misses += 1;
+            used
+        };
+
+        // read without the lock, so the misses do not wait for each other
+        let message = self.inner.get_message(message_id)?;
+        if let Some(message) = &message {
+            let mut data = self.data.lock().unwrap();
+            if data.invalidated_at < read_at && !data.messages.contains_key(message_id) {
+                while data.messages.len() >= self.capacity {
+                    let Some((_, evicted)) = data.recency.pop_first() else { break };
+                    data.messages.remove(&evicted);
+                }
+                data.messages.insert(message_id.to_string(), (message.clone(), read_at));
+                data.recency.insert(read_at, message_id.to_string());
+            }
+        }
+        Ok(message)
+    }
+
+    fn get_attempts(&self, message_id: &str) -> Result<Vec<AttemptRecord>, StoreError> {
+        self.inner.get_attempts(message_id)
+    }
+
+    fn find_messages(&self, query: &MessageQuery) -> Result<Vec<Message>, StoreError> {
+        self.inner.find_messages(query)
+    }
+
+    fn find_by_producer_message_id(&self, namespace: &str, topic: &str, producer_message_id: &str) -> Result<Option<Message>, StoreError> {
+        self.inner.find_by_producer_message_id(namespace, topic, producer_message_id)
+    }
+
+    fn purge_finished(&self, status: MessageStatus, finished_before: OffsetDateTime, scope: &PurgeScope) -> Result<usize, StoreError> {
+        let result = self.inner.purge_finished(status, finished_before, scope);
+        let mut data = self.data.lock().unwrap();
+        let purged: Vec<String> = data.messages.iter()
+            .filter(|(_, (message, _))| message.status == status && scope.includes(message.topic()))
+            .map(|(id, _)| id.clone())
+            .collect();
+        purged.iter().for_each(|id| data.invalidate(id));
+        result
+    }
+
+    fn message_digests(&self, leaves: usize, selected: Option<&[usize]>) -> Result<Vec<MessageDigest>, StoreError> {
+        self.inner.message_digests(leaves, selected)
+    }
+
+    fn merkle_leaves(&self, leaves: usize) -> Result<Vec<u64>, StoreError> {
+        self.inner.merkle_leaves(leaves)
+    }
+
+    fn record_usage(&self, records: &[UsageRecord]) -> Result<(), StoreError> {
+        self.inner.record_usage(records)
+    }
+
+    fn find_usage(&self, from: OffsetDateTime, to: OffsetDateTime) -> Result<Vec<UsageRecord>, StoreError> {
+        self.inner.find_usage(from, to)
+    }
+
+    fn mark_synced(&self, at: OffsetDateTime) -> Result<(), StoreError> {
+        self.inner.mark_synced(at)
+    }
+
+    fn synced_at(&self) -> Result<Option<OffsetDateTime>, StoreError> {
+        self.inner.synced_at()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::db::memory::MemoryStore;
+
+    use super::*;
+
+    fn message(id: &str) -> Message {
+        Message::new(id.to_string(), String::from("r1"), String::from("orders"), String::from("order.created"), vec![1])
+    }
+
+    #[test]
+    fn test_if_messages_are_read_from_the_cache_until_they_are_written() {
+        let inner = Arc::new(MemoryStore::new());
+        let store = CachedStore::new(inner.clone(), 2);
+        for id in ["a", "b", "c"] {
+            store.write(StoreWrite::InsertMessage(Box::new(message(id)))).unwrap();
+        }
+
+        assert_eq!(store.get_message("a").unwrap().unwrap().status, MessageStatus::Pending);
+        assert_eq!(store.get_message("a").unwrap().unwrap().status, MessageStatus::Pending);
+        assert_eq!(store.stats(), CacheStats { hits: 1, misses: 1, entries: 1 });
+
+        let next_attempt_at = Some(OffsetDateTime::now_utc());
+        store.write(StoreWrite::UpdateStatus { message_id: String::from("a"), status: MessageStatus::InFlight, next_attempt_at }).unwrap();
+        assert_eq!(store.stats().entries, 0);
+        let updated = store.get_message("a").unwrap().unwrap();
+        assert_eq!((updated.status, updated.next_attempt_at), (MessageStatus::InFlight, next_attempt_at));
+
+        // b evicts nothing, c evicts a, that was read before b
+        store.get_message("b").unwrap();
+        store.get_message("c").unwrap();
+        assert_eq!(store.stats(), CacheStats { hits: 1, misses: 4, entries: 2 });
+        store.get_message("b").unwrap();
+        assert_eq!(store.stats().hits, 2);
+        store.get_message("a").unwrap();
+        assert_eq!(store.stats().misses, 5);
+
+        // a message written to the inner store is stale in the cache until it is purged
+        inner.write(StoreWrite::UpdateStatus { message_id: String::from("a"), status: MessageStatus::Dead, next_attempt_at: None }).unwrap();
+        assert_eq!(store.get_message("a").unwrap().unwrap().status, MessageStatus::InFlight);
+        store.purge_finished(MessageStatus::InFlight, OffsetDateTime::now_utc(), &PurgeScope::AllTopics).unwrap();
+        assert_eq!(store.get_message("a").unwrap().unwrap().status, MessageStatus::Dead);
+        assert_eq!(store.get_message("missing").unwrap(), None);
+    }
+}
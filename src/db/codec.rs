@@ -0,0 +1,39 @@
+use thiserror::Error;
+
+use crate::{
+    msgproc::message::Message,
+    net::storage::{message_from_json, message_to_json},
+    utils::json::JsonValue,
+};
+
+#[derive(Debug, Error)]
+pub enum CodecError {
+    #[error("The message could not be encoded: {0}")]
+    Encode(String),
+    #[error("The stored record could not be decoded: {0}")]
+    Decode(String),
+}
+
+/// The format of the records kept by a store for its messages. Embedders can wrap the
+/// JsonMessageCodec to transform each record, like encrypting it again or putting it in a
+/// compliance envelope, without changing the store
+pub trait StoredMessageCodec: Send + Sync {
+    fn encode(&self, message: &Message) -> Result<Vec<u8>, CodecError>;
+
+    fn decode(&self, record: &[u8]) -> Result<Message, CodecError>;
+}
+
+/// The default StoredMessageCodec, keeping the message in the JSON used by the cluster store calls
+#[derive(Debug, Clone, Copy, Default)]
+pub struct JsonMessageCodec;
+
+impl StoredMessageCodec for JsonMessageCodec {
+    fn encode(&self, message: &Message) -> Result<Vec<u8>, CodecError> {
+        Ok(message_to_json(message).to_string().into_bytes())
+    }
+
+    fn decode(&self, record: &[u8]) -> Result<Message, CodecError> {
+        let json = JsonValue::parse_bytes(record).map_err(|err| CodecError::Decode(err.to_string()))?;
+        message_from_json(&json).map_err(CodecError::Decode)
+    }
+}
@@ -0,0 +1,437 @@
+use std::{collections::{HashMap, HashSet}, sync::{Arc, Mutex}};
+
+use time::OffsetDateTime;
+
+use crate::{
+    msgproc::message::{AttemptRecord, Message, MessageStatus},
+    syscom::usage::UsageRecord,
+    utils::sha256::sha256,
+};
+
+use super::{codec::StoredMessageCodec, MessageQuery, MessageStore, PurgeScope, StoreError, StoreWrite};
+
+/// A stored message, without its payload, that is kept once by its hash in `payloads`. With a
+/// codec, `message` only has the fields the messages are found by and the status, `record` keeps
+/// the other fields encoded by the codec, and `payloads` keeps the encoded payloads
+#[derive(Debug)]
+struct StoredMessage {
+    message: Message,
+    record: Option<Vec<u8>>,
+    payload_hash: [u8; 32],
+}
+
+/// The records of the codec computed for a write before the batch is applied
+enum EncodedWrite {
+    None,
+    Insert { record: Vec<u8>, payload: Vec<u8> },
+    Annotate(Vec<u8>),
+}
+
+/// Return the fields of the message that are kept without the codec: the ones the messages are
+/// found by, and the status fields the processor updates
+fn searched_fields(message: &Message) -> Message {
+    Message {
+        status: message.status,
+        attempts: message.attempts,
+        next_attempt_at: message.next_attempt_at,
+        indexed_fields: message.indexed_fields.clone(),
+        ..Message::new_at(message.id.clone(), message.recipient_id.clone(), message.service_id.clone(), message.event_id.clone(), Vec::new(), message.created_at)
+    }
+}
+
+/// Return the message the codec encodes for a payload. Only the payload is set, so the equal
+/// payloads of different messages are encoded into the same record by a deterministic codec
+fn payload_message(payload: &[u8]) -> Message {
+    Message::new_at(String::new(), String::new(), String::new(), String::new(), payload.to_vec(), OffsetDateTime::UNIX_EPOCH)
+}
+
+/// A payload shared by the messages with the same content, removed with the last of them
+#[derive(Debug)]
+struct PayloadBlob {
+    payload: Vec<u8>,
+    references: usize,
+}
+
+/// How much memory the payloads of a MemoryStore use
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct PayloadUsage {
+    /// The distinct payloads kept
+    pub blobs: usize,
+    /// The size of the distinct payloads
+    pub stored_bytes: u64,
+    /// The size of the payloads of all the messages, as if each one kept its own
+    pub referenced_bytes: u64,
+}
+
+#[derive(Debug, Default)]
+struct MemoryStoreData {
+    messages: HashMap<String, StoredMessage>,
+    /// The payloads of the messages by their SHA-256, so an event published to many recipients
+    /// keeps its payload once
+    payloads: HashMap<[u8; 32], PayloadBlob>,
+    attempts: HashMap<String, Vec<AttemptRecord>>,
+    /// The ID of the last message published with each (namespace, topic, producer message ID), by
+    /// the hash of the producer message ID, so it is not kept outside the record of a codec
+    producer_message_ids: HashMap<(String, String, [u8; 32]), String>,
+    usage: Vec<UsageRecord>,
+}
+
+/// A MessageStore that keeps all the data in memory. Used on tests and embedded instances
+#[derive(Default)]
+pub struct MemoryStore {
+    data: Mutex<MemoryStoreData>,
+    codec: Option<Arc<dyn StoredMessageCodec>>,
+}
+
+impl MemoryStore {
+    pub fn new() -> MemoryStore {
+        MemoryStore::default()
+    }
+
+    /// Keep each message as the records encoded by the codec. Only the fields the messages are
+    /// found by (ID, recipient, namespace, topic, creation time and indexed payload fields) and the
+    /// status, attempts and next attempt time are kept as they are. The payload is encoded in its
+    /// own record, kept once by the hash of the record, so a deterministic codec still keeps the
+    /// payloads of a fan-out once. The messages searched by attributes are decoded to be matched
+    pub fn with_codec(mut self, codec: Arc<dyn StoredMessageCodec>) -> MemoryStore {
+        self.codec = Some(codec);
+        self
+    }
+
+    /// Return how much memory the payloads of the stored messages use
+    pub fn payload_usage(&self) -> PayloadUsage {
+        let data = self.data.lock().unwrap();
+        PayloadUsage {
+            blobs: data.payloads.len(),
+            stored_bytes: data.payloads.values().map(|blob| blob.payload.len() as u64).sum(),
+            referenced_bytes: data.payloads.values().map(|blob| (blob.payload.len() * blob.references) as u64).sum(),
+        }
+    }
+}
+
+impl MemoryStoreData {
+    /// Keep the payload once, returning its hash
+    fn intern_payload(&mut self, payload: &[u8]) -> [u8; 32] {
+        let hash = sha256(payload);
+        self.payloads.entry(hash).or_insert_with(|| PayloadBlob { payload: payload.to_vec(), references: 0 }).references += 1;
+        hash
+    }
+
+    /// Drop a reference to the payload, removing it with the last one
+    fn release_payload(&mut self, hash: &[u8; 32]) {
+        if let Some(blob) = self.payloads.get_mut(hash) {
+            blob.references -= 1;
+            if blob.references == 0 {
+                self.payloads.remove(hash);
+            }
+        }
+    }
+
+    /// Return the stored message with its payload, decoding its records with the codec
+    fn load(&self, stored: &StoredMessage, codec: Option<&dyn StoredMessageCodec>) -> Result<Message, StoreError> {
+        let blob = self.payloads.get(&stored.payload_hash).map(|blob| blob.payload.as_slice()).unwrap_or_default();
+        let (Some(codec), Some(record)) = (codec, &stored.record) else {
+            return Ok(Message { payload: blob.to_vec(), ..stored.message.clone() });
+        };
+        let decoded = codec.decode(record).map_err(|err| StoreError::Backend(err.to_string()))?;
+        let payload = codec.decode(blob).map_err(|err| StoreError::Backend(err.to_string()))?.payload;
+        Ok(Message {
+            payload,
+            status: stored.message.status,
+            next_attempt_at: stored.message.next_attempt_at,
+            attempts: stored.message.attempts,
+            ..decoded
+        })
+    }
+
+    /// Encode the records of the inserts and annotations of the batch, in order, before it is
+    /// applied, so a record the codec fails to encode leaves the batch out
+    fn encode(&self, writes: &[StoreWrite], codec: &dyn StoredMessageCodec) -> Result<Vec<EncodedWrite>, StoreError> {
+        let backend = |err: super::codec::CodecError| StoreError::Backend(err.to_string());
+        // the records of the messages written earlier in the batch, by their IDs
+        let mut records: HashMap<&str, Vec<u8>> = HashMap::new();
+        let mut encoded = Vec::with_capacity(writes.len());
+        for write in writes {
+            encoded.push(match write {
+                StoreWrite::InsertMessage(message) => {
+                    let record = codec.encode(&Message { payload: Vec::new(), ..message.as_ref().clone() }).map_err(backend)?;
+                    let payload = codec.encode(&payload_message(&message.payload)).map_err(backend)?;
+                    records.insert(&message.id, record.clone());
+                    EncodedWrite::Insert { record, payload }
+                }
+                StoreWrite::AnnotateMessage { message_id, annotation } => {
+                    let record = match records.get(message_id.as_str()) {
+                        Some(record) => record.as_slice(),
+                        None => self.messages.get(message_id).and_then(|stored| stored.record.as_deref()).unwrap_or_default(),
+                    };
+                    let mut message = codec.decode(record).map_err(backend)?;
+                    message.annotations.push(annotation.clone());
+                    let record = codec.encode(&message).map_err(backend)?;
+                    records.insert(message_id, record.clone());
+                    EncodedWrite::Annotate(record)
+                }
+                _ => EncodedWrite::None,
+            });
+        }
+        Ok(encoded)
+    }
+}
+
+impl MessageStore for MemoryStore {
+    fn write_batch(&self, writes: &[StoreWrite]) -> Result<(), StoreError> {
+        let mut data = self.data.lock().map_err(|err| StoreError::Backend(err.to_string()))?;
+
+        // validate the whole batch before applying it so a batch is never partially applied
+        let inserted_in_batch: HashSet<&str> = writes.iter()
+            .filter_map(|write| match write {
+                StoreWrite::InsertMessage(message) => Some(message.id.as_str()),
+                _ => None,
+            })
+            .collect();
+        for write in writes {
+            let message_id = match write {
+                StoreWrite::UpdateStatus { message_id, .. } | StoreWrite::AnnotateMessage { message_id, .. } => message_id,
+                StoreWrite::RecordAttempt(attempt) => &attempt.message_id,
+                StoreWrite::InsertMessage(_) => continue,
+            };
+            if !inserted_in_batch.contains(message_id.as_str()) && !data.messages.contains_key(message_id) {
+                return Err(StoreError::MessageNotFound(message_id.clone()));
+            }
+        }
+        let encoded = match &self.codec {
+            Some(codec) => data.encode(writes, codec.as_ref())?,
+            None => Vec::new(),
+        };
+        let mut encoded = encoded.into_iter();
+
+        for write in writes {
+            match (write, encoded.next().unwrap_or(EncodedWrite::None)) {
+                (StoreWrite::InsertMessage(message), encoded) => {
+                    if let Some(producer_message_id) = &message.producer_message_id {
+                        let key = (message.namespace().to_string(), message.topic().to_string(), sha256(producer_message_id.as_bytes()));
+                        data.producer_message_ids.insert(key, message.id.clone());
+                    }
+                    let stored = match encoded {
+                        EncodedWrite::Insert { record, payload } => {
+                            StoredMessage { message: searched_fields(message), record: Some(record), payload_hash: data.intern_payload(&payload) }
+                        }
+                        _ => {
+                            let payload_hash = data.intern_payload(&message.payload);
+                            StoredMessage { message: Message { payload: Vec::new(), ..message.as_ref().clone() }, record: None, payload_hash }
+                        }
+                    };
+                    if let Some(replaced) = data.messages.insert(message.id.clone(), stored) {
+                        data.release_payload(&replaced.payload_hash);
+                    }
+                }
+                (StoreWrite::UpdateStatus { message_id, status, next_attempt_at }, _) => {
+                    if let Some(StoredMessage { message, .. }) = data.messages.get_mut(message_id) {
+                        message.status = *status;
+                        message.next_attempt_at = *next_attempt_at;
+                    }
+                }
+                (StoreWrite::RecordAttempt(attempt), _) => {
+                    if let Some(StoredMessage { message, .. }) = data.messages.get_mut(&attempt.message_id) {
+                        message.attempts = message.attempts.max(attempt.attempt);
+                    }
+                    data.attempts.entry(attempt.message_id.clone()).or_default().push(attempt.clone());
+                }
+                (StoreWrite::AnnotateMessage { message_id, annotation }, encoded) => {
+                    if let Some(stored) = data.messages.get_mut(message_id) {
+                        match encoded {
+                            EncodedWrite::Annotate(record) => stored.record = Some(record),
+                            _ => stored.message.annotations.push(annotation.clone()),
+                        }
+                    }
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    fn get_message(&self, message_id: &str) -> Result<Option<Message>, StoreError> {
+        let data = self.data.lock().map_err(|err| StoreError::Backend(err.to_string()))?;
+        data.messages.get(message_id).map(|stored| data.load(stored, self.codec.as_deref())).transpose()
+    }
+
+    fn get_attempts(&self, message_id: &str) -> Result<Vec<AttemptRecord>, StoreError> {
+        let data = self.data.lock().map_err(|err| StoreError::Backend(err.to_string()))?;
+        Ok(data.attempts.get(message_id).cloned().unwrap_or_default())
+    }
+
+    fn find_messages(&self, query: &MessageQuery) -> Result<Vec<Message>, StoreError> {
+        let data = self.data.lock().map_err(|err| StoreError::Backend(err.to_string()))?;
+        // the attributes are in the records of the codec, so they are matched after decoding
+        let decoded_attributes = self.codec.is_some() && !query.attributes.is_empty();
+        let searched = match decoded_attributes {
+            true => &MessageQuery { attributes: Vec::new(), limit: None, ..query.clone() },
+            false => query,
+        };
+        let mut matched: Vec<&StoredMessage> = data.messages.values().filter(|stored| searched.matches(&stored.message)).collect();
+        matched.sort_by(|a, b| a.message.created_at.cmp(&b.message.created_at).then_with(|| a.message.id.cmp(&b.message.id)));
+        if !decoded_attributes {
+            matched.truncate(query.limit.unwrap_or(usize::MAX));
+            return matched.into_iter().map(|stored| data.load(stored, self.codec.as_deref())).collect();
+        }
+        let mut found = Vec::new();
+        for stored in matched {
+            if found.len() >= query.limit.unwrap_or(usize::MAX) {
+                break;
+            }
+            let message = data.load(stored, self.codec.as_deref())?;
+            if query.matches(&message) {
+                found.push(message);
+            }
+        }
+        Ok(found)
+    }
+
+    fn find_by_producer_message_id(&self, namespace: &str, topic: &str, producer_message_id: &str) -> Result<Option<Message>, StoreError> {
+        let data = self.data.lock().map_err(|err| StoreError::Backend(err.to_string()))?;
+        let key = (namespace.to_string(), topic.to_string(), sha256(producer_message_id.as_bytes()));
+        data.producer_message_ids.get(&key).and_then(|message_id| data.messages.get(message_id))
+            .map(|stored| data.load(stored, self.codec.as_deref())).transpose()
+    }
+
+    fn purge_finished(&self, status: MessageStatus, finished_before: OffsetDateTime, scope: &PurgeScope) -> Result<usize, StoreError> {
+        let mut data = self.data.lock().map_err(|err| StoreError::Backend(err.to_string()))?;
+
+        let expired: Vec<String> = data.messages.values()
+            .map(|stored| &stored.message)
+            .filter(|message| message.status == status && scope.includes(message.topic()))
+            .filter(|message| {
+                let finished_at = data.attempts.get(&message.id).and_then(|attempts| attempts.iter().map(|a| a.finished_at).max());
+                finished_at.is_some_and(|finished_at| finished_at < finished_before)
+            })
+            .map(|message| message.id.clone())
+            .collect();
+
+        for message_id in &expired {
+            if let Some(stored) = data.messages.remove(message_id) {
+                data.release_payload(&stored.payload_hash);
+            }
+            data.attempts.remove(message_id);
+        }
+        let MemoryStoreData { messages, producer_message_ids, .. } = &mut *data;
+        producer_message_ids.retain(|_, message_id| messages.contains_key(message_id));
+        Ok(expired.len())
+    }
+
+    fn record_usage(&self, records: &[UsageRecord]) -> Result<(), StoreError> {
+        let mut data = self.data.lock().map_err(|err| StoreError::Backend(err.to_string()))?;
+        data.usage.extend_from_slice(records);
+        Ok(())
+    }
+
+    fn find_usage(&self, from: OffsetDateTime, to: OffsetDateTime) -> Result<Vec<UsageRecord>, StoreError> {
+        let data = self.data.lock().map_err(|err| StoreError::Backend(err.to_string()))?;
+        let mut records: Vec<UsageRecord> = data.usage.iter().filter(|record| from <= record.period_start && record.period_start < to).cloned().collect();
+        records.sort_by(|a, b| a.period_start.cmp(&b.period_start).then_with(|| a.namespace.cmp(&b.namespace)));
+        Ok(records)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{db::codec::{CodecError, JsonMessageCodec}, msgproc::message::Annotation};
+
+    use super::*;
+
+    fn message(id: &str, payload: &[u8]) -> StoreWrite {
+        StoreWrite::InsertMessage(Box::new(Message::new(id.to_string(), format!("recipient-{}", id), "service".to_string(), "event".to_string(), payload.to_vec())))
+    }
+
+    #[test]
+    fn test_if_equal_payloads_are_kept_once_until_their_last_message_is_removed() {
+        let store = MemoryStore::new();
+        let fan_out: Vec<StoreWrite> = (0..50).map(|index| message(&index.to_string(), b"{\"order\":1}")).collect();
+        store.write_batch(&fan_out).unwrap();
+        store.write(message("other", b"{}")).unwrap();
+        assert_eq!(store.payload_usage(), PayloadUsage { blobs: 2, stored_bytes: 13, referenced_bytes: 50 * 11 + 2 });
+        assert_eq!(store.get_message("7").unwrap().unwrap().payload, b"{\"order\":1}");
+        assert_eq!(store.find_messages(&MessageQuery::default()).unwrap()[0].payload.len(), 11);
+
+        // replacing a message drops its reference to the previous payload
+        store.write(message("other", b"{\"order\":1}")).unwrap();
+        assert_eq!(store.payload_usage().blobs, 1);
+
+        let finished_at = OffsetDateTime::now_utc();
+        let writes: Vec<StoreWrite> = (0..50).map(|index| index.to_string()).chain([String::from("other")])
+            .flat_map(|id| [
+                StoreWrite::RecordAttempt(AttemptRecord { message_id: id.clone(), attempt: 1, finished_at, outcome: crate::msgproc::message::AttemptOutcome::Delivered }),
+                StoreWrite::UpdateStatus { message_id: id, status: MessageStatus::Delivered, next_attempt_at: None },
+            ])
+            .collect();
+        store.write_batch(&writes).unwrap();
+        assert_eq!(store.purge_finished(MessageStatus::Delivered, finished_at + time::Duration::seconds(1), &PurgeScope::AllTopics).unwrap(), 51);
+        assert_eq!(store.payload_usage(), PayloadUsage::default());
+    }
+
+    #[test]
+    fn test_if_batches_with_writes_of_unknown_messages_are_not_applied() {
+        let store = MemoryStore::new();
+        let attempt = |id: &str| StoreWrite::RecordAttempt(AttemptRecord { message_id: id.to_string(), attempt: 1, finished_at: OffsetDateTime::now_utc(), outcome: crate::msgproc::message::AttemptOutcome::Delivered });
+
+        // the writes can follow the insert of their message in the same batch
+        store.write_batch(&[attempt("a"), message("a", b"{}")]).unwrap();
+        assert_eq!(store.get_attempts("a").unwrap().len(), 1);
+
+        let err = store.write_batch(&[message("b", b"{}"), attempt("a"), attempt("missing")]).unwrap_err();
+        assert!(matches!(err, StoreError::MessageNotFound(message_id) if message_id == "missing"));
+        assert_eq!(store.get_message("b").unwrap(), None);
+        assert_eq!(store.get_attempts("a").unwrap().len(), 1);
+        assert!(store.get_attempts("missing").unwrap().is_empty());
+    }
+
+    /// Flip the bits of the JSON records, like an embedder encrypting them again
+    struct Envelope;
+
+    impl StoredMessageCodec for Envelope {
+        fn encode(&self, message: &Message) -> Result<Vec<u8>, CodecError> {
+            Ok(JsonMessageCodec.encode(message)?.into_iter().map(|byte| !byte).collect())
+        }
+
+        fn decode(&self, record: &[u8]) -> Result<Message, CodecError> {
+            JsonMessageCodec.decode(&record.iter().map(|byte| !byte).collect::<Vec<u8>>())
+        }
+    }
+
+    #[test]
+    fn test_if_messages_are_kept_as_the_records_of_the_codec() {
+        let store = MemoryStore::new().with_codec(Arc::new(Envelope));
+        let StoreWrite::InsertMessage(mut secret) = message("1", b"{\"card\":\"4111\"}") else { unreachable!() };
+        secret.producer_message_id = Some(String::from("order-4111"));
+        secret.attributes.insert(String::from("customer"), String::from("alice"));
+        store.write_batch(&[StoreWrite::InsertMessage(secret), message("2", b"{\"card\":\"4111\"}")]).unwrap();
+        store.write(StoreWrite::UpdateStatus { message_id: String::from("1"), status: MessageStatus::Dead, next_attempt_at: None }).unwrap();
+        let annotation = Annotation { note: String::from("refunded to alice"), author: None, created_at: OffsetDateTime::UNIX_EPOCH };
+        store.write(StoreWrite::AnnotateMessage { message_id: String::from("1"), annotation: annotation.clone() }).unwrap();
+
+        {
+            // the payload of both messages is kept once, and nothing but the searched fields is kept in plaintext
+            let data = store.data.lock().unwrap();
+            assert_eq!(data.payloads.len(), 1);
+            let kept = format!("{:?} {:?}", data.messages.values().collect::<Vec<_>>(), data.producer_message_ids);
+            for secret in ["4111", "alice", "refunded"] {
+                assert!(!kept.contains(secret), "{} is kept in plaintext", secret);
+                assert!(!data.payloads.values().any(|blob| String::from_utf8_lossy(&blob.payload).contains(secret)));
+            }
+        }
+
+        let stored = store.get_message("1").unwrap().unwrap();
+        assert_eq!((stored.payload.as_slice(), stored.status, stored.next_attempt_at), (&b"{\"card\":\"4111\"}"[..], MessageStatus::Dead, None));
+        assert_eq!((stored.annotations, stored.attributes.get("customer").map(String::as_str)), (vec![annotation], Some("alice")));
+        assert_eq!(store.find_by_producer_message_id("service", "event", "order-4111").unwrap().map(|message| message.id), Some(String::from("1")));
+        let query = MessageQuery { recipient_id: Some(String::from("recipient-1")), ..MessageQuery::default() };
+        assert_eq!(store.find_messages(&query).unwrap()[0].id, "1");
+        let query = MessageQuery { attributes: vec![(String::from("customer"), String::from("alice"))], limit: Some(1), ..MessageQuery::default() };
+        assert_eq!(store.find_messages(&query).unwrap().iter().map(|message| message.id.as_str()).collect::<Vec<_>>(), ["1"]);
+
+        let other = MemoryStore::new().with_codec(Arc::new(JsonMessageCodec));
+        other.write(message("2", b"{}")).unwrap();
+        assert_eq!(other.get_message("2").unwrap().unwrap().payload, b"{}");
+        other.data.lock().unwrap().payloads.values_mut().for_each(|blob| blob.payload = b"{".to_vec());
+        assert!(matches!(other.get_message("2"), Err(StoreError::Backend(_))));
+    }
+}
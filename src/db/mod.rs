@@ -0,0 +1,178 @@
+use thiserror::Error;
+use time::OffsetDateTime;
+
+use crate::{
+    cluster::antientropy::{leaf_hashes, MessageDigest},
+    msgproc::{lifecycle::TransitionError, message::{Annotation, AttemptRecord, Message, MessageStatus}},
+    syscom::usage::UsageRecord,
+};
+
+pub mod batch;
+pub mod cache;
+pub mod codec;
+pub mod memory;
+
+#[derive(Debug, Error)]
+pub enum StoreError {
+    #[error("Message {0} was not found in the store")]
+    MessageNotFound(String),
+    #[error("The store writer is closed and does not accept writes anymore")]
+    WriterClosed,
+    #[error("The store backend failed: {0}")]
+    Backend(String),
+    #[error(transparent)]
+    IllegalTransition(#[from] TransitionError),
+}
+
+impl StoreError {
+    /// Return if writing again can not succeed, like the writes of a message that is not in the
+    /// store anymore, instead of a backend that may recover
+    pub fn is_permanent(&self) -> bool {
+        matches!(self, StoreError::MessageNotFound(_) | StoreError::IllegalTransition(_))
+    }
+}
+
+/// A single write operation that can be applied into a MessageStore
+#[derive(Debug, Clone, PartialEq)]
+pub enum StoreWrite {
+    /// Insert (or replace) a message into the store
+    InsertMessage(Box<Message>),
+    /// Update the status and the next attempt of a stored message
+    UpdateStatus {
+        message_id: String,
+        status: MessageStatus,
+        next_attempt_at: Option<OffsetDateTime>,
+    },
+    /// Record the outcome of an attempt to send a message
+    RecordAttempt(AttemptRecord),
+    /// Attach an annotation to a stored message
+    AnnotateMessage {
+        message_id: String,
+        annotation: Annotation,
+    },
+}
+
+/// The filters used to find messages in a MessageStore. Unset filters match all the messages
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct MessageQuery {
+    pub status: Option<MessageStatus>,
+    pub recipient_id: Option<String>,
+    pub namespace: Option<String>,
+    pub topic: Option<String>,
+    /// Only match messages created at or after this time
+    pub created_after: Option<OffsetDateTime>,
+    /// Only match messages created before this time
+    pub created_before: Option<OffsetDateTime>,
+    /// Only match messages whose indexed payload fields have all these values
+    pub indexed_fields: Vec<(String, String)>,
+    /// Only match messages whose attributes have all these values
+    pub attributes: Vec<(String, String)>,
+    /// Only match messages after this creation time and ID in the order of `find_messages`, the
+    /// last message of the previous page
+    pub after: Option<(OffsetDateTime, String)>,
+    /// The maximum amount of messages returned
+    pub limit: Option<usize>,
+}
+
+impl MessageQuery {
+    /// Return if the message matches all the filters of this query
+    pub fn matches(&self, message: &Message) -> bool {
+        self.status.is_none_or(|status| message.status == status)
+            && self.recipient_id.as_ref().is_none_or(|recipient_id| &message.recipient_id == recipient_id)
+            && self.namespace.as_deref().is_none_or(|namespace| message.namespace() == namespace)
+            && self.topic.as_deref().is_none_or(|topic| message.topic() == topic)
+            && self.created_after.is_none_or(|after| message.created_at >= after)
+            && self.created_before.is_none_or(|before| message.created_at < before)
+            && self.indexed_fields.iter().all(|(field, value)| message.indexed_fields.get(field) == Some(value))
+            && self.attributes.iter().all(|(key, value)| message.attributes.get(key) == Some(value))
+            && self.after.as_ref().is_none_or(|(created_at, id)| (message.created_at, &message.id) > (*created_at, id))
+    }
+}
+
+/// The topics of the messages removed by `purge_finished`, so the topics with their own retention
+/// are purged apart from the others
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub enum PurgeScope {
+    #[default]
+    AllTopics,
+    Topic(String),
+    /// Every topic but these
+    ExceptTopics(Vec<String>),
+}
+
+impl PurgeScope {
+    /// Return if the messages of the topic are in the scope
+    pub fn includes(&self, topic: &str) -> bool {
+        match self {
+            PurgeScope::AllTopics => true,
+            PurgeScope::Topic(scope) => scope == topic,
+            PurgeScope::ExceptTopics(excluded) => !excluded.iter().any(|excluded| excluded == topic),
+        }
+    }
+}
+
+/// The persistence layer of the messages handled by Angler
+pub trait MessageStore: Send + Sync {
+    /// Apply all writes into the store. Backends should persist the whole batch at once (a single
+    /// transaction or fsync) as this is the function used by the BatchedStoreWriter to amortize writes
+    fn write_batch(&self, writes: &[StoreWrite]) -> Result<(), StoreError>;
+
+    /// Apply a single write into the store
+    fn write(&self, write: StoreWrite) -> Result<(), StoreError> {
+        self.write_batch(&[write])
+    }
+
+    /// Return the message with the given ID wrapped on a Option
+    fn get_message(&self, message_id: &str) -> Result<Option<Message>, StoreError>;
+
+    /// Return all the attempts made to send the message with the given ID
+    fn get_attempts(&self, message_id: &str) -> Result<Vec<AttemptRecord>, StoreError>;
+
+    /// Return the messages that match the query ordered by their creation time
+    fn find_messages(&self, query: &MessageQuery) -> Result<Vec<Message>, StoreError>;
+
+    /// Return the last message published in the namespace and topic with the given producer message ID
+    fn find_by_producer_message_id(&self, namespace: &str, topic: &str, producer_message_id: &str) -> Result<Option<Message>, StoreError>;
+
+    /// Remove the messages of the topics in the scope with the given status, and their attempts,
+    /// when their last attempt finished before `finished_before`. Return how many messages were removed
+    fn purge_finished(&self, status: MessageStatus, finished_before: OffsetDateTime, scope: &PurgeScope) -> Result<usize, StoreError>;
+
+    /// Return the digests of the messages in the `selected` leaves of a Merkle tree with `leaves`
+    /// leaves, or of all the messages when it is None. Used by the anti-entropy repair
+    fn message_digests(&self, leaves: usize, selected: Option<&[usize]>) -> Result<Vec<MessageDigest>, StoreError> {
+        Ok(self.find_messages(&MessageQuery::default())?.iter()
+            .filter(|message| selected.is_none_or(|selected| selected.contains(&MessageDigest::leaf(&message.id, leaves))))
+            .map(MessageDigest::of)
+            .collect())
+    }
+
+    /// Return the hash of each leaf of a Merkle tree with `leaves` leaves over the digests of the
+    /// messages. Remote stores compute it where the messages are, so only the hashes are sent
+    fn merkle_leaves(&self, leaves: usize) -> Result<Vec<u64>, StoreError> {
+        Ok(leaf_hashes(&self.message_digests(leaves, None)?, leaves))
+    }
+
+    /// Keep the usage records of the namespaces, exported for billing
+    fn record_usage(&self, _records: &[UsageRecord]) -> Result<(), StoreError> {
+        Err(StoreError::Backend(String::from("the store does not keep usage records")))
+    }
+
+    /// Return the usage records whose period started at or after `from` and before `to`, ordered
+    /// by the start of their period and their namespace
+    fn find_usage(&self, _from: OffsetDateTime, _to: OffsetDateTime) -> Result<Vec<UsageRecord>, StoreError> {
+        Ok(Vec::new())
+    }
+
+    /// Record that the store, a replica, was repaired from its primary at the given time. Stores
+    /// that are not replicas ignore it
+    fn mark_synced(&self, _at: OffsetDateTime) -> Result<(), StoreError> {
+        Ok(())
+    }
+
+    /// Return when the store, a replica, was last repaired from its primary. None when it never
+    /// was or when the store is not a replica
+    fn synced_at(&self) -> Result<Option<OffsetDateTime>, StoreError> {
+        Ok(None)
+    }
+}
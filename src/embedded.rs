@@ -0,0 +1,617 @@
+use std::{io, net::SocketAddr, sync::Arc, thread, time::{Duration, Instant}};
+
+use thiserror::Error;
+
+#[cfg(feature = "chaos")]
+use crate::chaos::{ChaosClock, ChaosDeliverer, ChaosStore, FaultInjector};
+use crate::{
+    cluster::{
+        antientropy::{AntiEntropy, AntiEntropyHandle, DEFAULT_ANTI_ENTROPY_INTERVAL},
+        readiness::ReadinessProbe,
+        skew::DEFAULT_CLOCK_SKEW_THRESHOLD,
+    },
+    ctx::{appenv::ApplicationRoles, config::{Configuration, ListenerConfig, RetryPolicyConfiguration}},
+    db::{cache::CachedStore, memory::MemoryStore, MessageStore, StoreError},
+    msgproc::{
+        capture::DebugCaptures,
+        delivery::{Deliverer, HttpDeliverer, WarmerHandle, DEFAULT_WARMUP_INTERVAL},
+        destination::{Destination, DestinationRegistry, DEFAULT_DELETE_GRACE_PERIOD},
+        interceptor::{Interceptor, Rejection},
+        message::{AttemptRecord, Message, MessageStatus},
+        notify::DeathNotifications,
+        processor::{MessageProcessor, ProcessorStats, PublishOutcome, RecoveryReport},
+        retry::RetryPolicy,
+        sse::SseHub,
+        topic::TopicRegistry,
+    },
+    net::{admin::AdminApi, auth::{auth_provider_from_configuration, AuthProvider}, client::restful::RestfulApi, http::HttpServer, pool::ConnectionPool, storage::{join_cluster, ClusterCompression, KeepaliveHandle, RemoteStore, StoreServer, DEFAULT_KEEPALIVE_THRESHOLD, DEFAULT_STORAGE_TIMEOUT}},
+    syscom::{
+        jobs::JobRegistry,
+        retention::{RetentionPolicy, RetentionSweeper, SweeperHandle, DEFAULT_SWEEP_INTERVAL},
+        usage::{UsageMeter, UsageMeterHandle},
+    },
+    utils::clock::{Clock, SystemClock},
+};
+
+/// The errors returned when a message is published into an Angler instance
+#[derive(Debug, Error)]
+pub enum PublishError {
+    #[error(transparent)]
+    Store(#[from] StoreError),
+    #[error("The message was rejected: {0}")]
+    Rejected(Rejection),
+}
+
+/// Configure and start an in-process Angler instance. By default the instance uses a memory store
+/// and listens on an ephemeral port of the loopback interface
+pub struct AnglerBuilder {
+    configuration: Configuration,
+    store: Option<Arc<dyn MessageStore>>,
+    deliverer: Option<Arc<dyn Deliverer>>,
+    clock: Option<Arc<dyn Clock>>,
+    client_address: String,
+    admin_address: Option<String>,
+    storage_address: Option<String>,
+    workers: Option<usize>,
+    interceptors: Vec<Arc<dyn Interceptor>>,
+    auth_provider: Option<Arc<dyn AuthProvider>>,
+}
+
+impl AnglerBuilder {
+    fn new() -> AnglerBuilder {
+        AnglerBuilder {
+            configuration: Configuration::new(),
+            store: None,
+            deliverer: None,
+            clock: None,
+            client_address: String::from("127.0.0.1:0"),
+            admin_address: None,
+            storage_address: None,
+            workers: None,
+            interceptors: vec![],
+            auth_provider: None,
+        }
+    }
+
+    /// Use the given configuration instead of an empty one
+    pub fn configuration(mut self, configuration: Configuration) -> AnglerBuilder {
+        self.configuration = configuration;
+        self
+    }
+
+    /// Use the given store instead of a MemoryStore
+    pub fn store(mut self, store: Arc<dyn MessageStore>) -> AnglerBuilder {
+        self.store = Some(store);
+        self
+    }
+
+    /// Use the given Deliverer instead of sending the messages over HTTP to the registered destinations
+    pub fn deliverer(mut self, deliverer: Arc<dyn Deliverer>) -> AnglerBuilder {
+        self.deliverer = Some(deliverer);
+        self
+    }
+
+    /// Use the given Clock instead of the system clock. A VirtualClock allows tests to fast-forward
+    /// the retry schedules and the retention of the messages
+    pub fn clock(mut self, clock: Arc<dyn Clock>) -> AnglerBuilder {
+        self.clock = Some(clock);
+        self
+    }
+
+    /// Set the address of the client RESTful API. Use port 0 for an ephemeral port
+    pub fn client_address(mut self, address: &str) -> AnglerBuilder {
+        self.client_address = address.to_string();
+        self
+    }
+
+    /// Open the admin API on the address. Use port 0 for an ephemeral port
+    pub fn admin_address(mut self, address: &str) -> AnglerBuilder {
+        self.admin_address = Some(address.to_string());
+        self
+    }
+
+    /// Serve the store to the message-processor nodes of the cluster on the address. Use port 0 for
+    /// an ephemeral port. Overrides `cluster.storage.address` and requires the storage role
+    pub fn storage_address(mut self, address: &str) -> AnglerBuilder {
+        self.storage_address = Some(address.to_string());
+        self
+    }
+
+    /// Set how many workers will send messages. Overrides `msgproc.workers`
+    pub fn workers(mut self, workers: usize) -> AnglerBuilder {
+        self.workers = Some(workers);
+        self
+    }
+
+    /// Apply the interceptor to the published messages. Custom interceptors run after the
+    /// built-in ones, in the order they were added
+    pub fn interceptor(mut self, interceptor: Arc<dyn Interceptor>) -> AnglerBuilder {
+        self.interceptors.push(interceptor);
+        self
+    }
+
+    /// Authenticate the requests of the client API with the provider instead of the one of
+    /// `net.client.auth.provider`
+    pub fn auth_provider(mut self, provider: Arc<dyn AuthProvider>) -> AnglerBuilder {
+        self.auth_provider = Some(provider);
+        self
+    }
+
+    /// Start the instance. The instance needs the message-processor role, the nodes with only the
+    /// storage role are started with StorageNode. Without the storage role the messages are kept
+    /// by the storage node of `cluster.storage.url`, unless a store is given
+    pub fn build(mut self) -> io::Result<Angler> {
+        if let Some(workers) = self.workers {
+            self.configuration.messages_processor.workers_count = Some(workers);
+        }
+
+        let roles = self.configuration.cluster.node_roles();
+        if !roles.contains(&ApplicationRoles::MessageProcessor) {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "a node without the messageProcessor role is started as a StorageNode"));
+        }
+        let storage_role = roles.contains(&ApplicationRoles::Storage);
+        let storage_address = self.storage_address.clone().or_else(|| self.configuration.cluster.storage.as_ref().map(ToString::to_string));
+        if storage_address.is_some() && !storage_role {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "the store is only served by the nodes with the storage role"));
+        }
+        if let (Some(join_token), None, false) = (&self.configuration.cluster.join_token, &self.configuration.cluster.auth_key, storage_role) {
+            let storage_url = self.configuration.cluster.storage_url.as_deref().ok_or_else(|| {
+                io::Error::new(io::ErrorKind::InvalidInput, "cluster.storage.url is required to join the cluster with cluster.joinToken")
+            })?;
+            let timeout = self.configuration.cluster.request_timeout.and_then(|timeout| Duration::try_from(timeout).ok()).unwrap_or(DEFAULT_STORAGE_TIMEOUT);
+            let credential = join_cluster(storage_url, join_token, timeout)
+                .map_err(|err| io::Error::new(io::ErrorKind::PermissionDenied, format!("failed to join the cluster with cluster.joinToken: {}", err)))?;
+            // the credential is sent in the place of the key by every RemoteStore of this node
+            self.configuration.cluster.auth_key = Some(credential);
+        }
+        let mut keepalives = Vec::new();
+        let faults = ClusterFaults::default();
+        let cache_capacity = self.configuration.database.cache_capacity;
+        let store: Arc<dyn MessageStore> = match self.store {
+            // the messages are only cached by the node that keeps them, as every write goes through it
+            Some(store) if storage_role => CachedStore::from_capacity(store, cache_capacity),
+            Some(store) => store,
+            None if storage_role => CachedStore::from_capacity(Arc::new(MemoryStore::new()), cache_capacity),
+            None => {
+                let storage_url = self.configuration.cluster.storage_url.as_deref().ok_or_else(|| {
+                    io::Error::new(io::ErrorKind::InvalidInput, "cluster.storage.url is required by the nodes without the storage role")
+                })?;
+                remote_store(storage_url, &self.configuration, &faults, &mut keepalives)
+            }
+        };
+        let delete_grace_period = self.configuration.messages_processor.delete_grace_period.unwrap_or(DEFAULT_DELETE_GRACE_PERIOD);
+        let destinations = Arc::new(DestinationRegistry::new().with_delete_grace_period(delete_grace_period));
+        let captures = Arc::new(DebugCaptures::new());
+        let sse = Arc::new(SseHub::new());
+        let pool = Arc::new(ConnectionPool::default());
+        let (deliverer, warmer): (Arc<dyn Deliverer>, _) = match self.deliverer {
+            Some(deliverer) => (deliverer, None),
+            None => {
+                let deliverer = HttpDeliverer::from_configuration(&self.configuration.messages_processor, destinations.clone())
+                    .with_captures(captures.clone())
+                    .with_sse_hub(sse.clone())
+                    .with_connection_pool(pool.clone());
+                let warmer = deliverer.connection_warmer().start(DEFAULT_WARMUP_INTERVAL);
+                (Arc::new(deliverer), Some(warmer))
+            }
+        };
+
+        let clock = self.clock.unwrap_or_else(|| Arc::new(SystemClock));
+
+        #[cfg(feature = "chaos")]
+        let (store, deliverer, clock): (Arc<dyn MessageStore>, Arc<dyn Deliverer>, Arc<dyn Clock>) = (
+            Arc::new(ChaosStore::new(store, faults.clone())),
+            Arc::new(ChaosDeliverer::new(deliverer, faults.clone())),
+            Arc::new(ChaosClock::new(clock, faults.clone())),
+        );
+
+        let topics = Arc::new(TopicRegistry::from_configuration(&self.configuration.topics).map_err(io::Error::other)?);
+        let processor = MessageProcessor::from_configuration(&self.configuration, store.clone(), deliverer, clock.clone())
+            .map_err(|err| io::Error::new(io::ErrorKind::InvalidInput, err))?;
+        let processor = self.interceptors.into_iter().fold(processor, MessageProcessor::with_interceptor);
+        let processor = Arc::new(processor.with_topics(topics.clone()));
+        if let Some(notifications) = DeathNotifications::from_configuration(&self.configuration) {
+            processor.notify_deaths(notifications);
+        }
+        processor.recover().map_err(io::Error::other)?;
+        let jobs = Arc::new(JobRegistry::new(clock.clone()));
+        // the retention of the messages is applied by the node that keeps them
+        let sweeper = storage_role.then(|| {
+            let retention = RetentionPolicy::from_configuration(&self.configuration.database);
+            RetentionSweeper::new(store.clone(), clock.clone(), retention).with_jobs(jobs.clone()).with_topics(topics.clone()).start(DEFAULT_SWEEP_INTERVAL)
+        });
+        let usage_meter = self.configuration.messages_processor.usage_interval
+            .and_then(|interval| Duration::try_from(interval).ok())
+            .filter(|interval| !interval.is_zero())
+            .map(|interval| UsageMeter::new(processor.clone(), store.clone(), clock.clone()).start(interval));
+
+        let anti_entropy = if storage_role { start_anti_entropy(&store, &self.configuration, &faults) } else { Vec::new() };
+        let mut readiness = readiness_probe(&roles.iter().copied().collect::<Vec<_>>(), &store, &self.configuration, &anti_entropy).with_processor(processor.clone());
+        if let Some(warmer) = &warmer {
+            readiness = readiness.with_warmup(warmer.warmed());
+        }
+        let readiness = Arc::new(readiness);
+
+        let mut api = RestfulApi::new(processor.clone(), store.clone(), destinations.clone(), self.configuration.retry_policy.clone())
+            .with_sse_hub(sse)
+            .with_jobs(jobs.clone())
+            .with_readiness(readiness.clone())
+            .with_topics(topics.clone());
+        if let Some(read_url) = &self.configuration.cluster.storage_read_url {
+            api = api.with_read_replica(remote_store(read_url, &self.configuration, &faults, &mut keepalives));
+        }
+        let auth_provider = match self.auth_provider {
+            Some(auth) => Some(auth),
+            None => auth_provider_from_configuration(&self.configuration.networking, clock.clone())
+                .map_err(|err| io::Error::new(io::ErrorKind::InvalidInput, err))?,
+        };
+        if let Some(auth) = auth_provider {
+            api = api.with_auth_provider(auth);
+        }
+        let api = Arc::new(api);
+        let limits = |listener: &Option<ListenerConfig>| listener.as_ref().map(|listener| listener.limits).unwrap_or_default();
+        let client_server = RestfulApi::listen_with_limits(api, self.client_address.as_str(), limits(&self.configuration.networking.restful))?;
+
+        let admin_server = match &self.admin_address {
+            Some(address) => {
+                let mut admin = AdminApi::new(processor.clone(), store.clone()).with_captures(captures).with_connection_pool(pool).with_jobs(jobs.clone());
+                if let Some(token) = &self.configuration.networking.admin_auth_token {
+                    admin = admin.with_auth_token(token.clone());
+                }
+                #[cfg(feature = "chaos")]
+                let admin = admin.with_faults(faults.clone());
+                Some(AdminApi::listen_with_limits(Arc::new(admin), address.as_str(), limits(&self.configuration.networking.admin))?)
+            }
+            None => None,
+        };
+        let storage_server = match storage_address {
+            Some(address) => {
+                let server = Arc::new(store_server(store.clone(), &self.configuration, &faults).with_readiness(readiness));
+                Some(StoreServer::listen_with_limits(server, address.as_str(), limits(&self.configuration.cluster.storage))?)
+            }
+            None => None,
+        };
+
+        Ok(Angler {
+            store,
+            destinations,
+            processor,
+            retry_configuration: self.configuration.retry_policy.clone(),
+            topics,
+            jobs,
+            sweeper,
+            warmer,
+            usage_meter,
+            keepalives,
+            clock,
+            client_server,
+            admin_server,
+            storage_server,
+            anti_entropy,
+            #[cfg(feature = "chaos")]
+            faults,
+        })
+    }
+}
+
+/// A running Angler instance. It is used by the `angler` binary and by applications that want to
+/// run hermetic integration tests against a real broker. Dropping it stops the instance
+pub struct Angler {
+    store: Arc<dyn MessageStore>,
+    destinations: Arc<DestinationRegistry>,
+    processor: Arc<MessageProcessor>,
+    /// The defaults and the limits of the published messages, by namespace
+    retry_configuration: RetryPolicyConfiguration,
+    /// The topics managed through `/topics`
+    topics: Arc<TopicRegistry>,
+    /// The background jobs followed by `GET /jobs`
+    jobs: Arc<JobRegistry>,
+    sweeper: Option<SweeperHandle>,
+    /// Keeps the connections of the destinations with `warmConnections` open, unless a Deliverer was given
+    warmer: Option<WarmerHandle>,
+    /// Records the usage of the namespaces every `msgproc.usage.interval`, when it is set
+    usage_meter: Option<UsageMeterHandle>,
+    /// Ping the storage nodes every `cluster.keepalive.interval`, when it is set
+    keepalives: Vec<KeepaliveHandle>,
+    clock: Arc<dyn Clock>,
+    client_server: HttpServer,
+    admin_server: Option<HttpServer>,
+    storage_server: Option<HttpServer>,
+    anti_entropy: Vec<AntiEntropyHandle>,
+    #[cfg(feature = "chaos")]
+    faults: Arc<FaultInjector>,
+}
+
+impl Angler {
+    /// Return a builder to configure a new instance
+    pub fn builder() -> AnglerBuilder {
+        AnglerBuilder::new()
+    }
+
+    /// Return the address of the client RESTful API
+    pub fn client_addr(&self) -> SocketAddr {
+        self.client_server.local_addr()
+    }
+
+    /// Return the base URL of the client RESTful API
+    pub fn client_url(&self) -> String {
+        format!("http://{}", self.client_addr())
+    }
+
+    /// Return the address of the admin API, if it was opened
+    pub fn admin_addr(&self) -> Option<SocketAddr> {
+        self.admin_server.as_ref().map(HttpServer::local_addr)
+    }
+
+    /// Return the address where the store is served to the other nodes, if it was opened
+    pub fn storage_addr(&self) -> Option<SocketAddr> {
+        self.storage_server.as_ref().map(HttpServer::local_addr)
+    }
+
+    /// Return the FaultInjector used by the store and the deliverer of this instance
+    #[cfg(feature = "chaos")]
+    pub fn faults(&self) -> &Arc<FaultInjector> {
+        &self.faults
+    }
+
+    /// Return the topics of this instance, managed through `/topics`
+    pub fn topics(&self) -> &Arc<TopicRegistry> {
+        &self.topics
+    }
+
+    /// Register the destination that will receive the messages of the recipient
+    pub fn register_destination(&self, recipient_id: &str, url: &str) {
+        self.destinations.register(Destination::new(recipient_id, url));
+        self.processor.unpark(recipient_id);
+    }
+
+    /// Publish a message with the default retry policy of its namespace and topic returning its ID
+    pub fn publish(&self, recipient_id: &str, service_id: &str, event_id: &str, payload: &[u8]) -> Result<String, PublishError> {
+        let mut message = Message::new_at(
+            self.processor.next_message_id(),
+            recipient_id.to_string(),
+            service_id.to_string(),
+            event_id.to_string(),
+            payload.to_vec(),
+            self.clock.now(),
+        );
+        let defaults = RetryPolicy::for_namespace(&self.retry_configuration, service_id);
+        message.retry_policy = match self.topics.retry_profile(event_id) {
+            Some(profile) => {
+                let limits = self.retry_configuration.for_namespace(service_id).unwrap_or_else(|| self.retry_configuration.clone());
+                profile.over(&defaults).clamp(&limits)
+            }
+            None => defaults,
+        };
+        self.publish_message(message)
+    }
+
+    /// Publish a message as it is, returning its ID. When the message is a duplicate inside the
+    /// `msgproc.dedup.window` the ID of the original message is returned
+    pub fn publish_message(&self, message: Message) -> Result<String, PublishError> {
+        let id = message.id.clone();
+        match self.processor.publish(message)? {
+            PublishOutcome::Accepted => Ok(id),
+            PublishOutcome::Duplicate(original) => Ok(original.id),
+            PublishOutcome::Rejected(rejection) => Err(PublishError::Rejected(rejection)),
+        }
+    }
+
+    /// Return the message with the given ID after writing all the pending writes
+    pub fn message(&self, message_id: &str) -> Result<Option<Message>, StoreError> {
+        self.processor.flush()?;
+        self.store.get_message(message_id)
+    }
+
+    /// Return all the attempts of the message after writing all the pending writes
+    pub fn attempts(&self, message_id: &str) -> Result<Vec<AttemptRecord>, StoreError> {
+        self.processor.flush()?;
+        self.store.get_attempts(message_id)
+    }
+
+    /// Wait until the message reaches the status. Return the message, or None on timeout
+    pub fn wait_for_status(&self, message_id: &str, status: MessageStatus, timeout: Duration) -> Result<Option<Message>, StoreError> {
+        let deadline = Instant::now() + timeout;
+        loop {
+            if let Some(message) = self.message(message_id)?.filter(|message| message.status == status) {
+                return Ok(Some(message));
+            }
+            if Instant::now() >= deadline {
+                return Ok(None);
+            }
+            thread::sleep(Duration::from_millis(5));
+        }
+    }
+
+    /// Return how many workers send the messages
+    pub fn workers_count(&self) -> usize {
+        self.processor.workers_count()
+    }
+
+    /// Return the counters of the message processor
+    pub fn stats(&self) -> &ProcessorStats {
+        self.processor.stats()
+    }
+
+    /// Return what was recovered from the store when the instance started
+    pub fn recovery(&self) -> Option<RecoveryReport> {
+        self.processor.recovery()
+    }
+
+    /// Return the Clock used by this instance
+    pub fn clock(&self) -> &Arc<dyn Clock> {
+        &self.clock
+    }
+
+    /// Return the store used by this instance
+    pub fn store(&self) -> &Arc<dyn MessageStore> {
+        &self.store
+    }
+
+    /// Return the background jobs of this instance, like the replays and the retention sweeps
+    pub fn jobs(&self) -> &Arc<JobRegistry> {
+        &self.jobs
+    }
+
+    /// Stop the listeners and the workers, flushing all the pending writes
+    pub fn shutdown(mut self) -> Result<(), StoreError> {
+        self.client_server.shutdown();
+        for server in [self.admin_server.as_mut(), self.storage_server.as_mut()].into_iter().flatten() {
+            server.shutdown();
+        }
+        if let Some(sweeper) = self.sweeper.as_mut() {
+            sweeper.stop();
+        }
+        if let Some(warmer) = self.warmer.as_mut() {
+            warmer.stop();
+        }
+        if let Some(usage_meter) = self.usage_meter.as_mut() {
+            usage_meter.stop();
+        }
+        self.keepalives.iter_mut().for_each(KeepaliveHandle::stop);
+        self.anti_entropy.iter_mut().for_each(AntiEntropyHandle::stop);
+        self.processor.shutdown()
+    }
+}
+
+impl Drop for Angler {
+    fn drop(&mut self) {
+        self.client_server.shutdown();
+        for server in [self.admin_server.as_mut(), self.storage_server.as_mut()].into_iter().flatten() {
+            server.shutdown();
+        }
+        if let Some(sweeper) = self.sweeper.as_mut() {
+            sweeper.stop();
+        }
+        if let Some(warmer) = self.warmer.as_mut() {
+            warmer.stop();
+        }
+        if let Some(usage_meter) = self.usage_meter.as_mut() {
+            usage_meter.stop();
+        }
+        self.keepalives.iter_mut().for_each(KeepaliveHandle::stop);
+        self.anti_entropy.iter_mut().for_each(AntiEntropyHandle::stop);
+        let _ = self.processor.shutdown();
+    }
+}
+
+/// The faults injected into the cluster calls of the node, with the `chaos` feature
+#[cfg(feature = "chaos")]
+type ClusterFaults = Arc<FaultInjector>;
+
+/// Without the `chaos` feature no fault is injected into the cluster calls
+#[cfg(not(feature = "chaos"))]
+#[derive(Default)]
+struct ClusterFaults;
+
+#[cfg(feature = "chaos")]
+fn with_cluster_faults(store: RemoteStore, faults: &ClusterFaults) -> RemoteStore {
+    store.with_faults(faults.clone())
+}
+
+#[cfg(not(feature = "chaos"))]
+fn with_cluster_faults(store: RemoteStore, _: &ClusterFaults) -> RemoteStore {
+    store
+}
+
+#[cfg(feature = "chaos")]
+fn with_server_faults(server: StoreServer, faults: &ClusterFaults) -> StoreServer {
+    server.with_faults(faults.clone())
+}
+
+#[cfg(not(feature = "chaos"))]
+fn with_server_faults(server: StoreServer, _: &ClusterFaults) -> StoreServer {
+    server
+}
+
+/// Serve the store with the `cluster.authKey`, watching the clocks of the nodes with the
+/// `cluster.clockSkew.threshold`
+fn store_server(store: Arc<dyn MessageStore>, configuration: &Configuration, faults: &ClusterFaults) -> StoreServer {
+    let cluster = &configuration.cluster;
+    let server = with_server_faults(StoreServer::new(store), faults)
+        .with_compression(cluster.compression.unwrap_or(ClusterCompression::None))
+        .with_clock_skew(cluster.clock_skew_threshold.unwrap_or(DEFAULT_CLOCK_SKEW_THRESHOLD), cluster.clock_skew_fence.unwrap_or(false));
+    let server = server.with_revoked_join_tokens(cluster.revoked_join_tokens.clone().unwrap_or_default());
+    match &configuration.cluster.auth_key {
+        Some(auth_key) => server.with_auth_key(auth_key.clone()),
+        None => server,
+    }
+}
+
+/// Use the storage node on the base URL, keeping its connections open and pinging them every
+/// `cluster.keepalive.interval` when it is set
+fn remote_store(base_url: &str, configuration: &Configuration, faults: &ClusterFaults, keepalives: &mut Vec<KeepaliveHandle>) -> Arc<RemoteStore> {
+    let cluster = &configuration.cluster;
+    let interval = cluster.keepalive_interval.and_then(|interval| Duration::try_from(interval).ok()).filter(|interval| !interval.is_zero());
+    let Some(interval) = interval else {
+        return Arc::new(with_cluster_faults(RemoteStore::for_node(base_url, cluster), faults));
+    };
+    let store = Arc::new(with_cluster_faults(RemoteStore::for_node(base_url, cluster), faults).with_keepalive(cluster.keepalive_threshold.unwrap_or(DEFAULT_KEEPALIVE_THRESHOLD)));
+    keepalives.push(RemoteStore::start_keepalive(store.clone(), interval));
+    store
+}
+
+/// Repair each replica of `cluster.storage.replicas` in the background
+fn start_anti_entropy(store: &Arc<dyn MessageStore>, configuration: &Configuration, faults: &ClusterFaults) -> Vec<AntiEntropyHandle> {
+    let cluster = &configuration.cluster;
+    let interval = cluster.anti_entropy_interval.and_then(|interval| Duration::try_from(interval).ok()).unwrap_or(DEFAULT_ANTI_ENTROPY_INTERVAL);
+    cluster.storage_replicas.iter().flatten()
+        .map(|replica| AntiEntropy::new(store.clone(), Arc::new(with_cluster_faults(RemoteStore::for_node(replica, cluster), faults))).start(replica, interval))
+        .collect()
+}
+
+/// Return the checks of `GET /readyz` for the roles, waiting for the first repair of each replica
+/// started by `start_anti_entropy`
+fn readiness_probe(roles: &[ApplicationRoles], store: &Arc<dyn MessageStore>, configuration: &Configuration, anti_entropy: &[AntiEntropyHandle]) -> ReadinessProbe {
+    configuration.cluster.storage_replicas.iter().flatten().zip(anti_entropy)
+        .fold(ReadinessProbe::new(roles, store.clone()), |probe, (replica, handle)| probe.with_replica(replica, handle.synced()))
+}
+
+/// A running node with only the storage role. It keeps the messages, applies their retention and
+/// serves them to the message-processor nodes, without receiving or delivering messages itself
+pub struct StorageNode {
+    store: Arc<dyn MessageStore>,
+    sweeper: SweeperHandle,
+    server: HttpServer,
+    anti_entropy: Vec<AntiEntropyHandle>,
+}
+
+impl StorageNode {
+    /// Serve the store on the address, requiring the `cluster.authKey` when it is set. Use port 0
+    /// for an ephemeral port
+    pub fn start(configuration: &Configuration, store: Arc<dyn MessageStore>, address: &str) -> io::Result<StorageNode> {
+        let store = CachedStore::from_capacity(store, configuration.database.cache_capacity);
+        let retention = RetentionPolicy::from_configuration(&configuration.database);
+        let sweeper = RetentionSweeper::new(store.clone(), Arc::new(SystemClock), retention).start(DEFAULT_SWEEP_INTERVAL);
+        let limits = configuration.cluster.storage.as_ref().map(|listener| listener.limits).unwrap_or_default();
+        // a storage-only node has no admin API to inject faults with
+        let faults = ClusterFaults::default();
+        let anti_entropy = start_anti_entropy(&store, configuration, &faults);
+        let readiness = Arc::new(readiness_probe(&[ApplicationRoles::Storage], &store, configuration, &anti_entropy));
+        let server = StoreServer::listen_with_limits(Arc::new(store_server(store.clone(), configuration, &faults).with_readiness(readiness)), address, limits)?;
+        Ok(StorageNode { store, sweeper, server, anti_entropy })
+    }
+
+    /// Return the address where the store is served
+    pub fn local_addr(&self) -> SocketAddr {
+        self.server.local_addr()
+    }
+
+    /// Return the base URL used in the `cluster.storage.url` of the message-processor nodes
+    pub fn url(&self) -> String {
+        format!("http://{}", self.local_addr())
+    }
+
+    /// Return the store served by this node
+    pub fn store(&self) -> &Arc<dyn MessageStore> {
+        &self.store
+    }
+}
+
+impl Drop for StorageNode {
+    fn drop(&mut self) {
+        self.server.shutdown();
+        self.sweeper.stop();
+        self.anti_entropy.iter_mut().for_each(AntiEntropyHandle::stop);
+    }
+}
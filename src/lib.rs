@@ -0,0 +1,15 @@
+pub mod bench;
+#[cfg(feature = "chaos")]
+pub mod chaos;
+pub mod cluster;
+pub mod ctx;
+pub mod db;
+pub mod embedded;
+pub mod msgproc;
+pub mod net;
+pub mod syscom;
+#[cfg(feature = "test-util")]
+pub mod testutil;
+pub mod utils;
+
+pub use embedded::{Angler, AnglerBuilder};
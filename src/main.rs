@@ -1,8 +1,160 @@
-use ctx::appenv::AppEnvironment;
+use std::{process, sync::Arc, thread};
 
-mod ctx;
-mod utils;
+use angler::{
+    bench::{run_bench, BenchOptions},
+    cluster::join::create_join_token,
+    ctx::{
+        appenv::{app_args, app_command, AppEnvironment, ApplicationRoles},
+        banner::StartupSummary,
+        completions::{man_page, run_completions},
+        config::ListenerConfig,
+    },
+    db::memory::MemoryStore,
+    embedded::StorageNode,
+    net::{
+        admin::search::run_messages_search,
+        client::{restful::DEFAULT_RESTFUL_PORT, routing::{run_export, run_import}},
+        storage::DEFAULT_STORAGE_PORT,
+    },
+    Angler,
+};
 
 fn main() {
+    if let Some(("bench", bench_args)) = app_args().subcommand() {
+        match BenchOptions::from_args(bench_args) {
+            Ok(options) => match run_bench(&options) {
+                Ok(report) => println!("{}", report),
+                Err(err) => {
+                    eprintln!("Failed to start the benchmark: {}", err);
+                    process::exit(1);
+                }
+            },
+            Err(err) => {
+                eprintln!("Invalid bench arguments: {}", err);
+                process::exit(2);
+            }
+        }
+        return;
+    }
+    if let Some(("completions", completions_args)) = app_args().subcommand() {
+        match run_completions(completions_args, &app_command()) {
+            Ok(script) => print!("{}", script),
+            Err(err) => {
+                eprintln!("Failed to print the completions: {}", err);
+                process::exit(2);
+            }
+        }
+        return;
+    }
+    if let Some(("man", _)) = app_args().subcommand() {
+        print!("{}", man_page(&app_command()));
+        return;
+    }
+    if let Some(("cluster", cluster_args)) = app_args().subcommand() {
+        if let Some(("create-join-token", token_args)) = cluster_args.subcommand() {
+            match create_join_token(token_args, AppEnvironment::get().configuration().cluster.auth_key.as_deref()) {
+                Ok(token) => println!("{}", token),
+                Err(err) => {
+                    eprintln!("Failed to create the join token: {}", err);
+                    process::exit(2);
+                }
+            }
+        }
+        return;
+    }
+    if let Some(("export", export_args)) = app_args().subcommand() {
+        if let Some(("routing", routing_args)) = export_args.subcommand() {
+            match run_export(routing_args) {
+                Ok(document) => print!("{}", document),
+                Err(err) => {
+                    eprintln!("Failed to export the routing: {}", err);
+                    process::exit(1);
+                }
+            }
+        }
+        return;
+    }
+    if let Some(("import", import_args)) = app_args().subcommand() {
+        if let Some(("routing", routing_args)) = import_args.subcommand() {
+            match run_import(routing_args) {
+                Ok(output) => println!("{}", output),
+                Err(err) => {
+                    eprintln!("Failed to import the routing: {}", err);
+                    process::exit(1);
+                }
+            }
+        }
+        return;
+    }
+    if let Some(("messages", messages_args)) = app_args().subcommand() {
+        if let Some(("search", search_args)) = messages_args.subcommand() {
+            match run_messages_search(search_args, &AppEnvironment::get().configuration().networking) {
+                Ok(messages) => println!("{}", messages),
+                Err(err) => {
+                    eprintln!("Failed to search the messages: {}", err);
+                    process::exit(1);
+                }
+            }
+        }
+        return;
+    }
+
     let app_env: &AppEnvironment = AppEnvironment::get();
+    let configuration = app_env.configuration();
+    let roles: Vec<ApplicationRoles> = app_env.roles().iter().copied().collect();
+    let summary = StartupSummary::new(configuration, &roles).with_configuration_file(app_env.path_to_conf_file());
+    let client_listener = configuration.networking.restful.clone().unwrap_or_else(|| ListenerConfig::unspecified(DEFAULT_RESTFUL_PORT));
+    let admin_listener = configuration.networking.admin.clone();
+    let storage_listener = configuration.cluster.storage.clone();
+    for listener in [Some(&client_listener), admin_listener.as_ref(), storage_listener.as_ref()].into_iter().flatten() {
+        if let Some(tls) = &listener.tls {
+            eprintln!("Failed to start Angler: the listener {} has the TLS certificate {} but TLS is not supported yet", listener, tls);
+            process::exit(1);
+        }
+    }
+
+    if !app_env.roles().contains(&ApplicationRoles::MessageProcessor) {
+        let storage_listener = storage_listener.unwrap_or_else(|| ListenerConfig::unspecified(DEFAULT_STORAGE_PORT));
+        let node = match StorageNode::start(configuration, Arc::new(MemoryStore::new()), &storage_listener.to_string()) {
+            Ok(node) => node,
+            Err(err) => {
+                eprintln!("Failed to start Angler: {}", err);
+                process::exit(1);
+            }
+        };
+        summary.with_listener("storage", node.local_addr()).log();
+        loop {
+            thread::park();
+        }
+    }
+
+    let mut builder = Angler::builder()
+        .configuration(configuration.clone())
+        .client_address(&client_listener.to_string());
+    if let Some(admin_listener) = admin_listener {
+        builder = builder.admin_address(&admin_listener.to_string());
+    }
+
+    let angler = match builder.build() {
+        Ok(angler) => angler,
+        Err(err) => {
+            eprintln!("Failed to start Angler: {}", err);
+            process::exit(1);
+        }
+    };
+    if let Some(recovery) = angler.recovery() {
+        println!("{}", recovery);
+    }
+    let mut summary = summary.with_listener("client", angler.client_addr()).with_workers(angler.workers_count());
+    if let Some(admin_addr) = angler.admin_addr() {
+        summary = summary.with_listener("admin", admin_addr);
+    }
+    if let Some(storage_addr) = angler.storage_addr() {
+        summary = summary.with_listener("storage", storage_addr);
+    }
+    summary.log();
+
+    loop {
+        thread::park();
+    }
 }
@@ -0,0 +1,105 @@
+use std::sync::OnceLock;
+
+use thiserror::Error;
+use time::OffsetDateTime;
+
+use crate::{
+    net::admin::constant_time_eq,
+    utils::{random::uuid_v4, sha256::{hmac_sha256, to_hex}},
+};
+
+/// The prefix of the ack tokens, `angler-ack.<message ID>.<attempt>.<deadline in ms>.<signature>`
+const ACK_TOKEN_PREFIX: &str = "angler-ack";
+
+/// The header with the token the receiver confirms an accepted message with
+pub const ACK_TOKEN_HEADER: &str = "X-Angler-Ack-Token";
+
+/// The header with the URL the receiver confirms an accepted message at, sent when the
+/// `msgproc.asyncAck.callbackUrl` is configured
+pub const ACK_URL_HEADER: &str = "X-Angler-Ack-Url";
+
+#[derive(Debug, Error, PartialEq)]
+pub enum AckError {
+    #[error("the ack token is malformed")]
+    Malformed,
+    #[error("the ack token was not issued by this node")]
+    InvalidSignature,
+    #[error("the ack token expired at {0}")]
+    Expired(OffsetDateTime),
+}
+
+/// The token sent with the attempts of the destinations with async ack, that the receiver uses to
+/// confirm the messages it answered with `202`. It is signed with HMAC-SHA-256 by a key created
+/// when the process starts, so only the node that sent the message accepts it, until it restarts
+#[derive(Debug, Clone, PartialEq)]
+pub struct AckToken {
+    pub message_id: String,
+    pub attempt: u16,
+    /// When the attempt fails if the receiver did not confirm it
+    pub confirm_before: OffsetDateTime,
+}
+
+impl AckToken {
+    pub fn new(message_id: &str, attempt: u16, confirm_before: OffsetDateTime) -> AckToken {
+        AckToken { message_id: message_id.to_string(), attempt, confirm_before }
+    }
+
+    /// Return the signed token
+    pub fn sign(&self) -> String {
+        let payload = format!("{}.{}.{}.{}", ACK_TOKEN_PREFIX, self.message_id, self.attempt, self.confirm_before.unix_timestamp_nanos() / 1_000_000);
+        let signature = to_hex(&hmac_sha256(signing_key().as_bytes(), payload.as_bytes()));
+        format!("{}.{}", payload, signature)
+    }
+
+    /// Check the signature and the deadline of the token
+    pub fn verify(token: &str, now: OffsetDateTime) -> Result<AckToken, AckError> {
+        let (payload, signature) = token.trim().rsplit_once('.').ok_or(AckError::Malformed)?;
+        let expected = to_hex(&hmac_sha256(signing_key().as_bytes(), payload.as_bytes()));
+        if !constant_time_eq(signature.as_bytes(), expected.as_bytes()) {
+            return Err(AckError::InvalidSignature);
+        }
+        // the message IDs may have dots, so the fields are read from the end
+        let [confirm_before, attempt, message_id] = payload.rsplitn(3, '.').collect::<Vec<_>>()[..] else {
+            return Err(AckError::Malformed);
+        };
+        let message_id = message_id.strip_prefix(ACK_TOKEN_PREFIX).and_then(|id| id.strip_prefix('.')).ok_or(AckError::Malformed)?;
+        let attempt = attempt.parse().map_err(|_| AckError::Malformed)?;
+        let confirm_before = confirm_before.parse::<i128>().ok()
+            .and_then(|ms| OffsetDateTime::from_unix_timestamp_nanos(ms * 1_000_000).ok())
+            .ok_or(AckError::Malformed)?;
+        if confirm_before <= now {
+            return Err(AckError::Expired(confirm_before));
+        }
+        Ok(AckToken::new(message_id, attempt, confirm_before))
+    }
+}
+
+/// Return the key this process signs the ack tokens with
+fn signing_key() -> &'static str {
+    static KEY: OnceLock<String> = OnceLock::new();
+    KEY.get_or_init(uuid_v4)
+}
+
+/// Return the URL where the message of the token is confirmed, under the URL of the client API
+pub fn ack_url(callback_url: &str, token: &str) -> String {
+    format!("{}/acks/{}", callback_url.trim_end_matches('/'), token)
+}
+
+#[cfg(test)]
+mod tests {
+    use time::Duration;
+
+    use super::*;
+
+    #[test]
+    fn test_if_ack_tokens_are_verified_until_their_deadline() {
+        let now = OffsetDateTime::UNIX_EPOCH + Duration::days(20_000);
+        let token = AckToken::new("order.1", 2, now + Duration::minutes(30));
+        let signed = token.sign();
+        assert_eq!(AckToken::verify(&signed, now), Ok(token.clone()));
+        assert_eq!(AckToken::verify(&signed, now + Duration::minutes(30)), Err(AckError::Expired(token.confirm_before)));
+        assert_eq!(AckToken::verify(&signed.replace("order.1", "order.2"), now), Err(AckError::InvalidSignature));
+        assert_eq!(AckToken::verify("angler-ack", now), Err(AckError::Malformed));
+        assert_eq!(ack_url("http://angler.internal:8080/", "t"), "http://angler.internal:8080/acks/t");
+    }
+}
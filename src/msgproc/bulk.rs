@@ -0,0 +1,154 @@
+use std::{collections::HashSet, sync::Arc};
+
+use crate::{
+    db::{MessageQuery, MessageStore, StoreError},
+    syscom::jobs::{Job, JobContext, JobRegistry},
+};
+
+use super::{
+    message::{Annotation, MessageStatus},
+    processor::MessageProcessor,
+};
+
+/// How many messages are cancelled at once, so the workers are not blocked for long waiting for
+/// the queue of the processor
+const CANCEL_CHUNK_SIZE: usize = 500;
+
+/// What a bulk job does
+#[derive(Debug, Clone, PartialEq)]
+pub enum BulkOperation {
+    /// Cancel the pending messages that match the query
+    CancelMessages(MessageQuery),
+    /// Pause the destinations with the IDs
+    PauseDestinations(Vec<String>),
+    /// Resume the paused destinations with the IDs
+    ResumeDestinations(Vec<String>),
+}
+
+impl BulkOperation {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            BulkOperation::CancelMessages(_) => "cancelMessages",
+            BulkOperation::PauseDestinations(_) => "pauseDestinations",
+            BulkOperation::ResumeDestinations(_) => "resumeDestinations",
+        }
+    }
+}
+
+/// Run the operation, that acts on many messages or destinations at once like the ones needed
+/// during an incident, as a background job. The job stops early when it is cancelled
+pub fn start_bulk_job(jobs: &Arc<JobRegistry>, processor: Arc<MessageProcessor>, store: Arc<dyn MessageStore>, operation: BulkOperation, actor: Option<String>) -> Job {
+    let annotation_author = actor.clone();
+    jobs.spawn(operation.as_str(), actor, move |job| match operation {
+        BulkOperation::CancelMessages(query) => cancel_messages(&processor, store.as_ref(), &query, annotation_author, job).map_err(|err| err.to_string()),
+        BulkOperation::PauseDestinations(recipient_ids) => {
+            change_each(&recipient_ids, job, |recipient_id| processor.pause(recipient_id));
+            Ok(())
+        }
+        BulkOperation::ResumeDestinations(recipient_ids) => {
+            change_each(&recipient_ids, job, |recipient_id| processor.resume(recipient_id).is_some());
+            Ok(())
+        }
+    })
+}
+
+/// Run the change on each destination, counting the ones it changed
+fn change_each(recipient_ids: &[String], job: &JobContext, change: impl Fn(&str) -> bool) {
+    job.set_total(recipient_ids.len());
+    for recipient_id in recipient_ids {
+        if job.is_cancelled() {
+            return;
+        }
+        job.advance(1, usize::from(change(recipient_id)));
+    }
+}
+
+/// Cancel the pending messages that match the query, in chunks, annotating who cancelled them
+fn cancel_messages(processor: &MessageProcessor, store: &dyn MessageStore, query: &MessageQuery, actor: Option<String>, job: &JobContext) -> Result<(), StoreError> {
+    let query = MessageQuery { status: Some(MessageStatus::Pending), ..query.clone() };
+    let messages = store.find_messages(&query)?;
+    job.set_total(messages.len());
+
+    let annotation = Annotation { note: format!("Cancelled by the bulk job {}", job.id()), author: actor, created_at: processor.clock().now() };
+    for chunk in messages.chunks(CANCEL_CHUNK_SIZE) {
+        if job.is_cancelled() {
+            break;
+        }
+        let message_ids: HashSet<String> = chunk.iter().map(|message| message.id.clone()).collect();
+        let cancelled = processor.cancel(&message_ids, &annotation)?;
+        job.advance(chunk.len(), cancelled.len());
+    }
+    // finished once the cancellations can be read from the store
+    processor.flush()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{thread, time::{Duration, Instant}};
+
+    use crate::{
+        db::{batch::BatchConfiguration, memory::MemoryStore},
+        msgproc::{delivery::Deliverer, message::{AttemptOutcome, Message}},
+        syscom::jobs::JobState,
+        utils::json::JsonValue,
+    };
+
+    use super::*;
+
+    struct AlwaysDelivers;
+
+    impl Deliverer for AlwaysDelivers {
+        fn deliver(&self, _: &Message) -> AttemptOutcome {
+            AttemptOutcome::Delivered
+        }
+    }
+
+    fn wait_for_job(jobs: &JobRegistry, id: &str) -> Job {
+        let deadline = Instant::now() + Duration::from_secs(5);
+        loop {
+            let job = jobs.get(id).unwrap();
+            if job.state != JobState::Running || Instant::now() > deadline {
+                return job;
+            }
+            thread::sleep(Duration::from_millis(5));
+        }
+    }
+
+    #[test]
+    fn test_if_bulk_jobs_pause_destinations_and_cancel_their_messages() {
+        let store = Arc::new(MemoryStore::new());
+        let processor = Arc::new(MessageProcessor::start(2, store.clone(), BatchConfiguration::default(), Arc::new(AlwaysDelivers)));
+        let jobs = Arc::new(JobRegistry::new(processor.clock().clone()));
+        let start = |operation: BulkOperation, actor: Option<&str>| start_bulk_job(&jobs, processor.clone(), store.clone(), operation, actor.map(String::from));
+
+        let paused = start(BulkOperation::PauseDestinations(vec![String::from("r1"), String::from("r2")]), None);
+        assert_eq!((paused.kind, paused.state), ("pauseDestinations", JobState::Running));
+        let paused = wait_for_job(&jobs, &paused.id);
+        assert_eq!((paused.state, paused.total, paused.processed, paused.changed), (JobState::Succeeded, 2, 2, 2));
+
+        for (id, recipient_id) in [("a", "r1"), ("b", "r1"), ("c", "r2"), ("d", "r3")] {
+            processor.publish(Message::new(id.to_string(), recipient_id.to_string(), String::from("shop"), String::from("order.created"), vec![])).unwrap();
+        }
+        while store.get_message("d").unwrap().is_none_or(|message| message.status != MessageStatus::Delivered) {
+            processor.flush().unwrap();
+        }
+        assert_eq!(processor.paused().get("r1"), Some(&2));
+
+        let query = MessageQuery { recipient_id: Some(String::from("r1")), ..MessageQuery::default() };
+        let cancelled = wait_for_job(&jobs, &start(BulkOperation::CancelMessages(query), Some("alice")).id);
+        assert_eq!((cancelled.state, cancelled.total, cancelled.changed), (JobState::Succeeded, 2, 2));
+        let message = store.get_message("a").unwrap().unwrap();
+        assert_eq!(message.status, MessageStatus::Dead);
+        assert_eq!(message.annotations[0].author.as_deref(), Some("alice"));
+        assert_eq!(processor.stats().outstanding(), 1);
+
+        let resumed = wait_for_job(&jobs, &start(BulkOperation::ResumeDestinations(vec![String::from("r2"), String::from("r3")]), None).id);
+        assert_eq!(resumed.changed, 1);
+        while store.get_message("c").unwrap().is_none_or(|message| message.status != MessageStatus::Delivered) {
+            processor.flush().unwrap();
+        }
+        assert_eq!(jobs.list().len(), 3);
+        assert_eq!(jobs.list()[1].to_json().get("actor").and_then(JsonValue::as_str), Some("alice"));
+        processor.shutdown().unwrap();
+    }
+}
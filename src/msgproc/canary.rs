@@ -0,0 +1,155 @@
+use std::{collections::HashMap, sync::Mutex};
+
+use crate::{cluster::ring::stable_hash, utils::json::JsonValue};
+
+use super::message::Message;
+
+/// The branches of a destination with a canary
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum Branch {
+    /// The `url` of the destination
+    Primary,
+    /// The `url` of its CanaryRoute
+    Canary,
+}
+
+impl Branch {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Branch::Primary => "primary",
+            Branch::Canary => "canary",
+        }
+    }
+}
+
+/// Send a share of the messages of a destination to another URL, like the new endpoint of a
+/// receiver being migrated, so the migration is done a few percent at a time
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CanaryRoute {
+    /// The `http://` URL that receives the share of the messages
+    pub url: String,
+    /// The percentage of the messages sent to the url, from 1 to 99
+    pub percent: u8,
+    /// The attribute with the ordering key of the messages, like the ID of a customer. The
+    /// messages with the same key go to the same branch, so the events of a customer do not
+    /// interleave between the old and the new receiver
+    pub key_attribute: Option<String>,
+}
+
+impl CanaryRoute {
+    pub fn new(url: &str, percent: u8) -> CanaryRoute {
+        CanaryRoute { url: url.to_string(), percent, key_attribute: None }
+    }
+
+    /// Choose the branch of the messages by the value of the attribute instead of their ID
+    pub fn with_key_attribute(mut self, attribute: &str) -> CanaryRoute {
+        self.key_attribute = Some(attribute.to_string());
+        self
+    }
+
+    /// Return the branch of the message. It is chosen by the hash of its key attribute or, when
+    /// it has none, of its ID, so the retries of a message go to the same branch on every node.
+    /// Raising the percentage keeps the keys that were in the canary there
+    pub fn branch_of(&self, message: &Message) -> Branch {
+        let key = self.key_attribute.as_ref().and_then(|attribute| message.attributes.get(attribute)).unwrap_or(&message.id);
+        if stable_hash(key) % 100 < u64::from(self.percent) { Branch::Canary } else { Branch::Primary }
+    }
+}
+
+/// The outcomes of the attempts sent to a branch
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct BranchStats {
+    pub delivered: u64,
+    pub failed: u64,
+}
+
+impl BranchStats {
+    /// Return the share of the attempts that were delivered, None before the first attempt
+    pub fn success_rate(&self) -> Option<f64> {
+        let attempts = self.delivered + self.failed;
+        (attempts > 0).then(|| self.delivered as f64 / attempts as f64)
+    }
+
+    pub fn to_json(&self, branch: Branch, url: &str) -> JsonValue {
+        JsonValue::object()
+            .with("branch", branch.as_str())
+            .with("url", url)
+            .with("delivered", self.delivered)
+            .with("failed", self.failed)
+            .with("successRate", self.success_rate())
+    }
+}
+
+/// Count the outcomes of the attempts of each branch of the destinations with a canary, so a
+/// migration is moved forward by comparing the branches
+#[derive(Debug, Default)]
+pub struct CanaryStats {
+    branches: Mutex<HashMap<(String, Branch), BranchStats>>,
+}
+
+impl CanaryStats {
+    pub fn new() -> CanaryStats {
+        CanaryStats::default()
+    }
+
+    /// Record an attempt of the destination sent to the branch
+    pub fn record(&self, recipient_id: &str, branch: Branch, delivered: bool) {
+        let mut branches = self.branches.lock().unwrap();
+        let stats = branches.entry((recipient_id.to_string(), branch)).or_default();
+        if delivered {
+            stats.delivered += 1;
+        } else {
+            stats.failed += 1;
+        }
+    }
+
+    /// Return the outcomes of the attempts of the branch of the destination
+    pub fn get(&self, recipient_id: &str, branch: Branch) -> BranchStats {
+        self.branches.lock().unwrap().get(&(recipient_id.to_string(), branch)).copied().unwrap_or_default()
+    }
+
+    /// Forget the outcomes of the destination, as its canary changed
+    pub fn reset(&self, recipient_id: &str) {
+        self.branches.lock().unwrap().retain(|(id, _), _| id != recipient_id);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_if_the_canary_gets_its_share_of_the_messages_and_keeps_the_keys() {
+        let canary = CanaryRoute::new("http://orders-v2.local/", 10);
+        let message = |id: usize| Message::new(format!("m-{}", id), String::from("r"), String::from("s"), String::from("e"), vec![]);
+        let routed = (0..10_000).filter(|id| canary.branch_of(&message(*id)) == Branch::Canary).count();
+        assert!((800..1200).contains(&routed), "{} messages were routed to the canary", routed);
+        // a message always goes to the same branch
+        assert_eq!(canary.branch_of(&message(7)), canary.branch_of(&message(7)));
+
+        // the messages of a customer stay in one branch, and the ones without the key are routed by their ID
+        let sticky = CanaryRoute::new("http://orders-v2.local/", 50).with_key_attribute("customerId");
+        let of_customer = |id: usize, customer: &str| {
+            let mut message = message(id);
+            message.attributes.insert(String::from("customerId"), customer.to_string());
+            sticky.branch_of(&message)
+        };
+        for customer in ["c-1", "c-2", "c-3", "c-4"] {
+            assert!((0..20).all(|id| of_customer(id, customer) == of_customer(0, customer)));
+        }
+        let unkeyed: Vec<Branch> = (0..20).map(|id| sticky.branch_of(&message(id))).collect();
+        assert!(unkeyed.contains(&Branch::Canary) && unkeyed.contains(&Branch::Primary));
+        // raising the percentage does not move the keys already in the canary
+        let wider = CanaryRoute { percent: 90, ..sticky.clone() };
+        assert!((0..100).map(message).filter(|message| sticky.branch_of(message) == Branch::Canary).all(|message| wider.branch_of(&message) == Branch::Canary));
+
+        let stats = CanaryStats::new();
+        stats.record("r", Branch::Canary, true);
+        stats.record("r", Branch::Canary, false);
+        stats.record("r", Branch::Primary, true);
+        assert_eq!(stats.get("r", Branch::Canary), BranchStats { delivered: 1, failed: 1 });
+        assert_eq!(stats.get("r", Branch::Canary).success_rate(), Some(0.5));
+        stats.reset("r");
+        assert_eq!(stats.get("r", Branch::Primary).success_rate(), None);
+    }
+}
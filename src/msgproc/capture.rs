@@ -0,0 +1,146 @@
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    sync::{Mutex, RwLock},
+};
+
+use time::OffsetDateTime;
+
+use crate::net::http::HttpHeaders;
+
+/// How many exchanges are kept for each destination. Older ones are dropped first
+pub const DEBUG_CAPTURE_LIMIT: usize = 50;
+
+/// How many bytes of each request and response body are kept
+pub const DEBUG_CAPTURE_BODY_LIMIT: usize = 16 * 1024;
+
+/// A request or response body, cut at DEBUG_CAPTURE_BODY_LIMIT
+#[derive(Debug, Clone, PartialEq)]
+pub struct CapturedBody {
+    pub bytes: Vec<u8>,
+    /// Whether the body was larger than the limit
+    pub truncated: bool,
+}
+
+impl CapturedBody {
+    pub fn new(body: &[u8]) -> CapturedBody {
+        let truncated = body.len() > DEBUG_CAPTURE_BODY_LIMIT;
+        CapturedBody { bytes: body[..body.len().min(DEBUG_CAPTURE_BODY_LIMIT)].to_vec(), truncated }
+    }
+}
+
+/// The response received by an attempt
+#[derive(Debug, Clone, PartialEq)]
+pub struct CapturedResponse {
+    pub status: u16,
+    pub headers: HttpHeaders,
+    pub body: CapturedBody,
+}
+
+/// A request sent to a destination while its debug capture was enabled, with the response
+/// received or the error that prevented it
+#[derive(Debug, Clone, PartialEq)]
+pub struct CapturedExchange {
+    pub message_id: String,
+    pub attempt: u16,
+    pub captured_at: OffsetDateTime,
+    pub method: String,
+    pub url: String,
+    pub request_headers: HttpHeaders,
+    pub request_body: CapturedBody,
+    pub response: Option<CapturedResponse>,
+    pub error: Option<String>,
+}
+
+/// The destinations whose deliveries are being captured and the exchanges captured from them.
+/// Captures are only kept in memory, so they are lost when the instance stops
+#[derive(Debug, Default)]
+pub struct DebugCaptures {
+    enabled: RwLock<HashSet<String>>,
+    exchanges: Mutex<HashMap<String, VecDeque<CapturedExchange>>>,
+}
+
+impl DebugCaptures {
+    pub fn new() -> DebugCaptures {
+        DebugCaptures::default()
+    }
+
+    /// Start capturing the deliveries to the destination
+    pub fn enable(&self, destination_id: &str) {
+        self.enabled.write().unwrap().insert(destination_id.to_string());
+    }
+
+    /// Stop capturing the deliveries to the destination, dropping what was captured. Return if
+    /// the capture was enabled
+    pub fn disable(&self, destination_id: &str) -> bool {
+        self.exchanges.lock().unwrap().remove(destination_id);
+        self.enabled.write().unwrap().remove(destination_id)
+    }
+
+    pub fn is_enabled(&self, destination_id: &str) -> bool {
+        self.enabled.read().unwrap().contains(destination_id)
+    }
+
+    /// Keep the exchange if the capture of the destination is enabled
+    pub fn record(&self, destination_id: &str, exchange: CapturedExchange) {
+        if !self.is_enabled(destination_id) {
+            return;
+        }
+        let mut exchanges = self.exchanges.lock().unwrap();
+        let exchanges = exchanges.entry(destination_id.to_string()).or_default();
+        if exchanges.len() == DEBUG_CAPTURE_LIMIT {
+            exchanges.pop_front();
+        }
+        exchanges.push_back(exchange);
+    }
+
+    /// Return the exchanges captured from the destination, the oldest first
+    pub fn exchanges(&self, destination_id: &str) -> Vec<CapturedExchange> {
+        self.exchanges.lock().unwrap().get(destination_id).map(|exchanges| exchanges.iter().cloned().collect()).unwrap_or_default()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn exchange(attempt: u16) -> CapturedExchange {
+        CapturedExchange {
+            message_id: String::from("a"),
+            attempt,
+            captured_at: OffsetDateTime::UNIX_EPOCH,
+            method: String::from("POST"),
+            url: String::from("http://localhost/hooks"),
+            request_headers: HttpHeaders::new(),
+            request_body: CapturedBody::new(b"{}"),
+            response: None,
+            error: Some(String::from("refused")),
+        }
+    }
+
+    #[test]
+    fn test_if_only_enabled_destinations_are_captured_up_to_the_limit() {
+        let captures = DebugCaptures::new();
+        captures.record("r", exchange(1));
+        assert!(captures.exchanges("r").is_empty());
+
+        captures.enable("r");
+        for attempt in 1..=DEBUG_CAPTURE_LIMIT as u16 + 2 {
+            captures.record("r", exchange(attempt));
+        }
+        let exchanges = captures.exchanges("r");
+        assert_eq!(exchanges.len(), DEBUG_CAPTURE_LIMIT);
+        assert_eq!(exchanges[0].attempt, 3);
+
+        assert!(captures.disable("r"));
+        assert!(captures.exchanges("r").is_empty());
+        assert!(!captures.disable("r"));
+    }
+
+    #[test]
+    fn test_if_bodies_are_cut_at_the_limit() {
+        let body = CapturedBody::new(&vec![b'a'; DEBUG_CAPTURE_BODY_LIMIT + 1]);
+        assert!(body.truncated);
+        assert_eq!(body.bytes.len(), DEBUG_CAPTURE_BODY_LIMIT);
+        assert!(!CapturedBody::new(b"ok").truncated);
+    }
+}
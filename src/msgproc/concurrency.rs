@@ -0,0 +1,177 @@
+use std::{collections::{BTreeMap, HashMap}, sync::Mutex, time::Duration};
+
+use crate::{log, utils::log::Level};
+
+use super::message::DeliveryErrorClass;
+
+/// The most `maxConcurrency` of a destination
+pub const MAX_CONCURRENCY: u16 = 256;
+
+/// How much the last response weighs in the latency of a destination, the others decaying
+/// exponentially, so a single slow response does not back it off
+const LATENCY_WEIGHT: f64 = 0.2;
+
+/// How much of the gap to the latency the baseline closes at each response, so a receiver that got
+/// slower for good gets a new baseline instead of staying backed off
+const BASELINE_DRIFT: f64 = 0.01;
+
+/// How many times its baseline the latency of a destination can be before it is overloaded
+const LATENCY_TOLERANCE: f64 = 2.0;
+
+/// How much above its baseline the latency of a destination can be regardless of the tolerance,
+/// so the jitter of the fast receivers does not back them off
+const LATENCY_SLACK: Duration = Duration::from_millis(20);
+
+/// How much the limit is multiplied by when the destination is overloaded
+const DECREASE_FACTOR: f64 = 0.5;
+
+/// The failures that tell that the receiver is overloaded, instead of refusing the message
+const OVERLOAD_CLASSES: [DeliveryErrorClass; 4] = [
+    DeliveryErrorClass::ConnectTimeout,
+    DeliveryErrorClass::Connection,
+    DeliveryErrorClass::ResponseTimeout,
+    DeliveryErrorClass::Http5xx,
+];
+
+/// Return if the failure tells that the receiver is overloaded
+pub fn is_overload(class: DeliveryErrorClass) -> bool {
+    OVERLOAD_CLASSES.contains(&class)
+}
+
+/// How many attempts of a destination are sent at once, as adapted to its responses
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ConcurrencyLimit {
+    /// The `maxConcurrency` of the destination
+    pub max: usize,
+    /// How many attempts can be sent at once, from 1 to max. Fractional so it grows by one after
+    /// as many good responses as the limit
+    limit: f64,
+    /// The average time its responses take
+    pub latency: Duration,
+    /// The latency of the destination without load
+    pub baseline: Duration,
+    /// The responses since the limit was decreased, so it is decreased once for the attempts that
+    /// were sent together
+    since_decrease: usize,
+}
+
+impl ConcurrencyLimit {
+    fn new(max: usize, latency: Duration) -> ConcurrencyLimit {
+        ConcurrencyLimit { max, limit: max as f64, latency, baseline: latency, since_decrease: max }
+    }
+
+    /// Return how many attempts of the destination can be sent at once
+    pub fn limit(&self) -> usize {
+        self.limit as usize
+    }
+
+    fn is_slow(&self) -> bool {
+        self.latency > self.baseline.mul_f64(LATENCY_TOLERANCE) && self.latency > self.baseline + LATENCY_SLACK
+    }
+}
+
+/// Adapt the attempts of each destination with `maxConcurrency` sent at once to its responses, by
+/// additive increase and multiplicative decrease: each good response raises the limit by
+/// `1 / limit`, up to the `maxConcurrency`, and a failure that tells that the receiver is overloaded
+/// or a latency beyond twice its baseline halves it. So a degraded receiver gets fewer attempts at
+/// once instead of more retries piling on it
+#[derive(Debug, Default)]
+pub struct AdaptiveConcurrency {
+    destinations: Mutex<HashMap<String, ConcurrencyLimit>>,
+}
+
+impl AdaptiveConcurrency {
+    pub fn new() -> AdaptiveConcurrency {
+        AdaptiveConcurrency::default()
+    }
+
+    /// Return if another attempt of the destination can be sent while `in_flight` are being sent.
+    /// The destinations without responses yet can send up to their max
+    pub fn allows(&self, recipient_id: &str, max: usize, in_flight: usize) -> bool {
+        let limit = self.destinations.lock().unwrap().get(recipient_id).map_or(max, |limit| limit.limit().min(max));
+        in_flight < limit.max(1)
+    }
+
+    /// Record a response of the destination that took `latency`, `overloaded` when it failed with
+    /// a failure that tells that the receiver is overloaded. Return the limit of the destination
+    pub fn record(&self, recipient_id: &str, max: usize, latency: Duration, overloaded: bool) -> usize {
+        let max = max.max(1);
+        let mut destinations = self.destinations.lock().unwrap();
+        let limit = destinations.entry(recipient_id.to_string()).or_insert_with(|| ConcurrencyLimit::new(max, latency));
+        limit.max = max;
+        limit.limit = limit.limit.min(max as f64);
+        limit.latency = limit.latency.mul_f64(1.0 - LATENCY_WEIGHT) + latency.mul_f64(LATENCY_WEIGHT);
+        limit.baseline = match limit.latency < limit.baseline {
+            true => limit.latency,
+            false => limit.baseline + (limit.latency - limit.baseline).mul_f64(BASELINE_DRIFT),
+        };
+        limit.since_decrease += 1;
+
+        if overloaded || limit.is_slow() {
+            if limit.since_decrease >= limit.limit() {
+                limit.limit = (limit.limit * DECREASE_FACTOR).max(1.0);
+                limit.since_decrease = 0;
+                log!(Level::Debug, "The concurrency of the destination {} was decreased to {}: its responses take {}ms", recipient_id, limit.limit(), limit.latency.as_millis());
+            }
+        } else {
+            limit.limit = (limit.limit + 1.0 / limit.limit).min(max as f64);
+        }
+        limit.limit()
+    }
+
+    /// Return the limit of the destination, None when it had no response yet
+    pub fn get(&self, recipient_id: &str) -> Option<ConcurrencyLimit> {
+        self.destinations.lock().unwrap().get(recipient_id).copied()
+    }
+
+    /// Return the limit of each destination that had a response, by destination
+    pub fn list(&self) -> BTreeMap<String, ConcurrencyLimit> {
+        self.destinations.lock().unwrap().iter().map(|(recipient_id, limit)| (recipient_id.clone(), *limit)).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_if_the_limit_backs_off_when_the_receiver_degrades_and_recovers_after() {
+        let concurrency = AdaptiveConcurrency::new();
+        let ms = Duration::from_millis;
+        assert!(concurrency.allows("orders", 8, 7));
+        assert!(!concurrency.allows("orders", 8, 8));
+        for _ in 0..20 {
+            assert_eq!(concurrency.record("orders", 8, ms(40), false), 8);
+        }
+
+        // the attempts sent together are decreased once
+        assert_eq!(concurrency.record("orders", 8, ms(40), true), 4);
+        assert_eq!(concurrency.record("orders", 8, ms(40), true), 4);
+        for _ in 0..3 {
+            concurrency.record("orders", 8, ms(40), true);
+        }
+        assert_eq!(concurrency.get("orders").unwrap().limit(), 2);
+        assert!(!concurrency.allows("orders", 8, 2));
+
+        // it grows by one after as many good responses as the limit
+        assert_eq!(concurrency.record("orders", 8, ms(40), false), 2);
+        assert_eq!(concurrency.record("orders", 8, ms(40), false), 2);
+        assert_eq!(concurrency.record("orders", 8, ms(40), false), 3);
+        // a latency beyond twice the baseline backs it off without failures
+        let mut limit = 3;
+        for _ in 0..10 {
+            limit = concurrency.record("orders", 8, Duration::from_secs(2), false);
+        }
+        assert_eq!(limit, 1);
+        assert!(concurrency.allows("orders", 8, 0) && !concurrency.allows("orders", 8, 1));
+
+        // the jitter of a fast receiver is not a overload, and the limit follows a lower max
+        for _ in 0..20 {
+            concurrency.record("fast", 4, ms(1), false);
+        }
+        assert_eq!(concurrency.record("fast", 4, ms(15), false), 4);
+        assert_eq!(concurrency.record("fast", 2, ms(1), false), 2);
+        assert!(is_overload(DeliveryErrorClass::Http5xx) && !is_overload(DeliveryErrorClass::Http4xx));
+        assert_eq!(concurrency.list().len(), 2);
+    }
+}
@@ -0,0 +1,689 @@
+use std::{
+    collections::HashMap,
+    io,
+    net::SocketAddr,
+    sync::{mpsc::{self, RecvTimeoutError, Sender}, Arc, Mutex},
+    thread::{self, JoinHandle},
+    time::{Duration, Instant},
+};
+
+use time::OffsetDateTime;
+
+use crate::{
+    cluster::{features::local_node_id, readiness::ReadySignal},
+    ctx::config::MessagesProcessorConfigurations,
+    log,
+    net::{
+        dns::{DnsCache, DEFAULT_DNS_NEGATIVE_TTL, DEFAULT_DNS_TTL},
+        http::{send_request_to, HttpError, HttpRequest, HttpResponse, HttpUrl},
+        pool::ConnectionPool,
+    },
+    utils::{gzip, log::Level, time::format_rfc3339},
+};
+
+use super::{
+    ack::{ack_url, AckToken, ACK_TOKEN_HEADER, ACK_URL_HEADER},
+    canary::Branch,
+    capture::{CapturedBody, CapturedExchange, CapturedResponse, DebugCaptures},
+    destination::{DeliveryMode, Destination, DestinationRegistry, RedirectPolicy},
+    message::{AttemptOutcome, DeliveryError, DeliveryErrorClass, Message},
+    pipeline::StageTimings,
+    retry::{RetryBudget, RetryOn},
+    sse::SseHub,
+};
+
+/// The default value of `msgproc.message_delivery_timeout`
+const DEFAULT_DELIVERY_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How often the ConnectionWarmer opens the connections of the destinations with `warmConnections`
+/// that were closed
+pub const DEFAULT_WARMUP_INTERVAL: Duration = Duration::from_secs(5);
+
+/// The prefix of the headers that carry the message attributes, like `X-Angler-Attr-region`
+pub const ATTRIBUTE_HEADER_PREFIX: &str = "X-Angler-Attr-";
+
+/// The header that carries the sequence of the message in its topic, so recipients can detect
+/// gaps and reordering
+pub const SEQUENCE_HEADER: &str = "X-Angler-Sequence";
+
+/// The header with the ID of the message, so recipients can drop the messages they already handled
+pub const MESSAGE_ID_HEADER: &str = "X-Angler-Message-Id";
+
+/// The header with the number of the attempt, starting at 1
+pub const ATTEMPT_HEADER: &str = "X-Angler-Attempt";
+
+/// The header with how many attempts the message can have: the first one and its retries
+pub const MAX_ATTEMPTS_HEADER: &str = "X-Angler-Max-Attempts";
+
+/// The header with when the message is retried if the attempt fails with a retried error. It is
+/// not sent on the last attempt, so recipients know that a failure kills the message
+pub const NEXT_RETRY_AT_HEADER: &str = "X-Angler-Next-Retry-At";
+
+/// The header of the copies of the deliveries sent to the `shadowUrl` of a destination
+pub const SHADOW_HEADER: &str = "X-Angler-Shadow";
+
+/// The headers that tell the recipient about the attempt
+pub const ATTEMPT_HEADERS: [&str; 4] = [MESSAGE_ID_HEADER, ATTEMPT_HEADER, MAX_ATTEMPTS_HEADER, NEXT_RETRY_AT_HEADER];
+
+/// The template of the `User-Agent` of the deliveries when `msgproc.delivery.userAgent` is not set
+pub const DEFAULT_USER_AGENT: &str = "Angler/{version}";
+
+/// How the deliveries identify this Angler to the receivers, as some of them firewall the requests
+/// by user agent: the `User-Agent` and the static headers of `msgproc.delivery.headers.`. The
+/// headers set by a destination are kept, so it can identify its own requests differently
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DeliveryIdentification {
+    user_agent: String,
+    headers: Vec<(String, String)>,
+}
+
+impl Default for DeliveryIdentification {
+    fn default() -> Self {
+        DeliveryIdentification::new(DEFAULT_USER_AGENT)
+    }
+}
+
+impl DeliveryIdentification {
+    /// Create the identification with the `User-Agent` template, replacing its `{version}` with the
+    /// version of Angler and its `{nodeId}` with the ID of this node
+    pub fn new(user_agent: &str) -> DeliveryIdentification {
+        let user_agent = user_agent.replace("{version}", env!("CARGO_PKG_VERSION")).replace("{nodeId}", local_node_id());
+        DeliveryIdentification { user_agent, headers: Vec::new() }
+    }
+
+    /// Send the header with every delivery
+    pub fn with_header(mut self, name: &str, value: &str) -> DeliveryIdentification {
+        self.headers.push((name.to_string(), value.to_string()));
+        self
+    }
+
+    /// Create the identification using the `msgproc.delivery.` configurations
+    pub fn from_configuration(conf: &MessagesProcessorConfigurations) -> DeliveryIdentification {
+        let identification = DeliveryIdentification::new(conf.delivery_user_agent.as_deref().unwrap_or(DEFAULT_USER_AGENT));
+        let mut headers: Vec<(&String, &String)> = conf.delivery_headers.iter().flatten().collect();
+        headers.sort();
+        headers.into_iter().fold(identification, |identification, (name, value)| identification.with_header(name, value))
+    }
+
+    pub fn user_agent(&self) -> &str {
+        &self.user_agent
+    }
+
+    /// Set the headers of the identification that the request does not have yet
+    pub fn apply(&self, request: &mut HttpRequest) {
+        let user_agent = [(String::from("User-Agent"), self.user_agent.clone())];
+        for (name, value) in user_agent.iter().chain(&self.headers) {
+            if request.headers.get(name).is_none() {
+                request.headers.set(name, value);
+            }
+        }
+    }
+}
+
+/// Send messages to their recipients. Implementations are called concurrently by the
+/// workers of the MessageProcessor and may block until the attempt finishes
+pub trait Deliverer: Send + Sync {
+    /// Make a single attempt to send the message to its recipient
+    fn deliver(&self, message: &Message) -> AttemptOutcome;
+
+    /// Make the attempt like `deliver`, recording how long the message took to be transformed
+    /// into the request of its recipient, so it is not counted as time in the network
+    fn deliver_timed(&self, message: &Message, _timings: &mut StageTimings) -> AttemptOutcome {
+        self.deliver(message)
+    }
+
+    /// Return which failures of the message are retried, when it is not the `retryPolicy.retryOn`
+    fn retry_on(&self, _message: &Message) -> Option<RetryOn> {
+        None
+    }
+
+    /// Return the RetryBudget of the destination of the message, when its retries are limited
+    fn retry_budget(&self, _message: &Message) -> Option<RetryBudget> {
+        None
+    }
+
+    /// Return how many attempts of the destination of the message can be sent at once, when they
+    /// are limited. The processor adapts them to the responses of the destination up to this max
+    fn max_concurrency(&self, _message: &Message) -> Option<usize> {
+        None
+    }
+
+    /// Return if the message is pulled by its recipient instead of being sent by `deliver`
+    fn is_pulled(&self, _message: &Message) -> bool {
+        false
+    }
+
+    /// Return until when the message is parked instead of attempted, as its recipient was
+    /// deleted but can still be restored
+    fn parked_until(&self, _message: &Message) -> Option<OffsetDateTime> {
+        None
+    }
+
+    /// Return when the message can be sent, when it is due at `now` outside of the delivery window
+    /// of its recipient
+    fn held_until(&self, _message: &Message, _now: OffsetDateTime) -> Option<OffsetDateTime> {
+        None
+    }
+}
+
+/// Return the error of a request that did not get a response
+fn request_error(err: HttpError) -> DeliveryError {
+    let is_timeout = |err: &io::Error| matches!(err.kind(), io::ErrorKind::TimedOut | io::ErrorKind::WouldBlock);
+    let class = match &err {
+        HttpError::Connect(err) if is_timeout(err) => DeliveryErrorClass::ConnectTimeout,
+        HttpError::Io(err) if is_timeout(err) => DeliveryErrorClass::ResponseTimeout,
+        HttpError::Connect(_) | HttpError::Io(_) => DeliveryErrorClass::Connection,
+        HttpError::Malformed(_) | HttpError::TooLarge => DeliveryErrorClass::InvalidResponse,
+        HttpError::InvalidUrl(_) => DeliveryErrorClass::NoDestination,
+    };
+    DeliveryError::new(class, err.to_string())
+}
+
+/// A Deliverer that POSTs the message payload to the URL of the recipient destination. Any 2xx
+/// response means that the message was delivered, except a `202` of a destination with async ack,
+/// which means that the receiver will confirm it later
+pub struct HttpDeliverer {
+    destinations: Arc<DestinationRegistry>,
+    timeout: Duration,
+    captures: Arc<DebugCaptures>,
+    resolver: Arc<DnsCache>,
+    sse: Arc<SseHub>,
+    /// The URL of the client API sent to the receivers of destinations with async ack
+    ack_callback_url: Option<String>,
+    /// The connections kept open to the destinations with `warmConnections`
+    pool: Arc<ConnectionPool>,
+    identification: DeliveryIdentification,
+    /// If the receiver of each destination with `compression` accepts gzip bodies, by the URL
+    receiver_encodings: Mutex<HashMap<String, bool>>,
+}
+
+impl HttpDeliverer {
+    pub fn new(destinations: Arc<DestinationRegistry>, timeout: Duration) -> HttpDeliverer {
+        HttpDeliverer {
+            destinations,
+            timeout,
+            captures: Arc::new(DebugCaptures::new()),
+            resolver: Arc::new(DnsCache::new(DEFAULT_DNS_TTL, DEFAULT_DNS_NEGATIVE_TTL)),
+            sse: Arc::new(SseHub::new()),
+            ack_callback_url: None,
+            pool: Arc::new(ConnectionPool::default()),
+            identification: DeliveryIdentification::default(),
+            receiver_encodings: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Send the messages of the `sse` destinations to the consumers connected to the SseHub
+    pub fn with_sse_hub(mut self, sse: Arc<SseHub>) -> HttpDeliverer {
+        self.sse = sse;
+        self
+    }
+
+    /// Record the requests and responses of the destinations with the debug capture enabled
+    pub fn with_captures(mut self, captures: Arc<DebugCaptures>) -> HttpDeliverer {
+        self.captures = captures;
+        self
+    }
+
+    /// Resolve the hosts of the destinations with the given DnsCache
+    pub fn with_resolver(mut self, resolver: Arc<DnsCache>) -> HttpDeliverer {
+        self.resolver = resolver;
+        self
+    }
+
+    /// Send the messages of the destinations with `warmConnections` through the given ConnectionPool
+    pub fn with_connection_pool(mut self, pool: Arc<ConnectionPool>) -> HttpDeliverer {
+        self.pool = pool;
+        self
+    }
+
+    /// Return the ConnectionWarmer that keeps the connections of the destinations with
+    /// `warmConnections` open in the ConnectionPool of this HttpDeliverer
+    pub fn connection_warmer(&self) -> ConnectionWarmer {
+        ConnectionWarmer { destinations: self.destinations.clone(), resolver: self.resolver.clone(), pool: self.pool.clone(), timeout: self.timeout }
+    }
+
+    /// Identify the deliveries with the given `User-Agent` and headers
+    pub fn with_identification(mut self, identification: DeliveryIdentification) -> HttpDeliverer {
+        self.identification = identification;
+        self
+    }
+
+    /// Send the URL where the accepted messages are confirmed, under the given URL of the client
+    /// API, to the receivers of destinations with async ack
+    pub fn with_ack_callback_url(mut self, callback_url: &str) -> HttpDeliverer {
+        self.ack_callback_url = Some(callback_url.to_string());
+        self
+    }
+
+    /// Create a HttpDeliverer using the `msgproc.message_delivery_timeout`, `msgproc.dns.`,
+    /// `msgproc.asyncAck.` and `msgproc.delivery.` configurations
+    pub fn from_configuration(conf: &MessagesProcessorConfigurations, destinations: Arc<DestinationRegistry>) -> HttpDeliverer {
+        let timeout = conf.message_delivery_timeout
+            .map(|d| d.unsigned_abs())
+            .filter(|d| !d.is_zero())
+            .unwrap_or(DEFAULT_DELIVERY_TIMEOUT);
+        let deliverer = HttpDeliverer::new(destinations, timeout)
+            .with_resolver(Arc::new(DnsCache::from_configuration(conf)))
+            .with_identification(DeliveryIdentification::from_configuration(conf));
+        match &conf.ack_callback_url {
+            Some(callback_url) => deliverer.with_ack_callback_url(callback_url),
+            None => deliverer,
+        }
+    }
+}
+
+/// Send the request, hedging it when the destination has `hedge_after`. The first successful
+/// response wins and the other request is abandoned: its response is ignored when it arrives.
+/// When both requests fail the last failure is returned
+fn send_hedged(destination: &Destination, resolver: &Arc<DnsCache>, pool: &Arc<ConnectionPool>, url: HttpUrl, request: HttpRequest, timeout: Duration) -> Result<HttpResponse, DeliveryError> {
+    let Some(hedge_after) = destination.hedge_after else {
+        return send(destination, resolver, pool, url, request, timeout);
+    };
+
+    let (sender, receiver) = mpsc::channel();
+    let spawn = |sender: mpsc::Sender<Result<HttpResponse, DeliveryError>>| {
+        let (destination, resolver, pool, url, request) = (destination.clone(), resolver.clone(), pool.clone(), url.clone(), request.clone());
+        thread::Builder::new()
+            .name(String::from("angler-hedge"))
+            .spawn(move || {
+                let _ = sender.send(send(&destination, &resolver, &pool, url, request, timeout));
+            })
+            .map_err(|err| DeliveryError::new(DeliveryErrorClass::Other, err.to_string()))
+    };
+
+    spawn(sender.clone())?;
+    let first = match receiver.recv_timeout(hedge_after) {
+        Ok(result) => return result,
+        Err(_) => {
+            spawn(sender)?;
+            receiver.recv().map_err(|err| DeliveryError::new(DeliveryErrorClass::Other, err.to_string()))?
+        }
+    };
+    match first {
+        Ok(response) if response.is_success() => Ok(response),
+        first => receiver.recv().unwrap_or(first),
+    }
+}
+
+/// Send the request following the redirects allowed by the RedirectPolicy of the destination.
+/// A redirect that is not followed is returned as the response. 303 redirects are followed with
+/// a GET without body, the others repeat the request. The requests to the URL of a destination
+/// with `warmConnections` use its connections of the ConnectionPool
+fn send(destination: &Destination, resolver: &DnsCache, pool: &ConnectionPool, mut url: HttpUrl, mut request: HttpRequest, timeout: Duration) -> Result<HttpResponse, DeliveryError> {
+    let mut redirects = 0;
+    loop {
+        let address = destination_address(destination, resolver, &url)?;
+        let response = match destination.warm_connections {
+            Some(_) if redirects == 0 => pool.send(&destination.id, address, &url, request.clone(), timeout),
+            _ => send_request_to(address, &url, request.clone(), timeout),
+        };
+        let response = response.map_err(request_error)?;
+        let Some(location) = redirect_location(&response) else {
+            return Ok(response);
+        };
+        if redirects == destination.redirect_policy.max_redirects() {
+            return Ok(response);
+        }
+        let Ok(next) = url.join(location) else {
+            return Ok(response);
+        };
+        if matches!(destination.redirect_policy, RedirectPolicy::SameHost(_)) && (next.host != url.host || next.port != url.port) {
+            return Ok(response);
+        }
+
+        redirects += 1;
+        if response.status == 303 {
+            request.method = String::from("GET");
+            request.headers.remove("Content-Type");
+            request.headers.remove("Content-Encoding");
+            request.body.clear();
+        }
+        url = next;
+    }
+}
+
+/// Return if the `Accept-Encoding` header of the response accepts gzip bodies, None when the
+/// response does not have it. The encodings with `q=0` are refused
+fn accepts_gzip(response: &HttpResponse) -> Option<bool> {
+    let accepted = response.headers.get("Accept-Encoding")?.split(',').any(|encoding| {
+        let mut params = encoding.split(';').map(str::trim);
+        let name = params.next().unwrap_or_default();
+        let refused = params.any(|param| param.strip_prefix("q=").and_then(|q| q.parse::<f64>().ok()) == Some(0.0));
+        (name.eq_ignore_ascii_case("gzip") || name == "*") && !refused
+    });
+    Some(accepted)
+}
+
+/// Return the `Location` of a redirect response
+fn redirect_location(response: &HttpResponse) -> Option<&str> {
+    match response.status {
+        301 | 302 | 303 | 307 | 308 => response.headers.get("Location"),
+        _ => None,
+    }
+}
+
+/// Return the address the URL of the destination, or of one of its redirects, connects to
+fn destination_address(destination: &Destination, resolver: &DnsCache, url: &HttpUrl) -> Result<SocketAddr, DeliveryError> {
+    let pinned_host = HttpUrl::parse(&destination.url).map(|url| url.host).ok();
+    match destination.pinned_address {
+        Some(ip) if pinned_host.as_ref() == Some(&url.host) => Ok(SocketAddr::new(ip, url.port)),
+        _ => Ok(resolver.resolve(&url.host, url.port)
+            .map_err(|err| DeliveryError::new(DeliveryErrorClass::Dns, format!("failed to resolve {}: {}", url.host, err)))?[0]),
+    }
+}
+
+/// Keep open the connections of the destinations with `warmConnections`, opening the ones closed
+/// by the receivers or by the ConnectionPool after they were idle for too long
+pub struct ConnectionWarmer {
+    destinations: Arc<DestinationRegistry>,
+    resolver: Arc<DnsCache>,
+    pool: Arc<ConnectionPool>,
+    timeout: Duration,
+}
+
+impl ConnectionWarmer {
+    /// Open the missing connections of the destinations and close the ones of the destinations
+    /// that no longer have `warmConnections`
+    pub fn warm_up(&self) {
+        let mut warmed = Vec::new();
+        for destination in self.destinations.list() {
+            let Some(count) = destination.warm_connections.filter(|_| destination.mode == DeliveryMode::Push) else {
+                continue;
+            };
+            let address = HttpUrl::parse(&destination.url).map_err(|err| err.to_string())
+                .and_then(|url| destination_address(&destination, &self.resolver, &url).map_err(|err| err.to_string()));
+            match address {
+                Ok(address) => {
+                    let open = self.pool.warm(&destination.id, address, usize::from(count), self.timeout);
+                    if open < usize::from(count) {
+                        log!(Level::Debug, "Only {} of the {} warm connections of the destination {} are open", open, count, destination.id);
+                    }
+                }
+                Err(err) => log!(Level::Debug, "Failed to warm up the connections of the destination {}: {}", destination.id, err),
+            }
+            warmed.push(destination.id);
+        }
+        self.pool.retain(&warmed.iter().map(String::as_str).collect::<Vec<_>>());
+    }
+
+    /// Warm up the connections every `interval` in the background
+    pub fn start(self, interval: Duration) -> WarmerHandle {
+        let (sender, receiver) = mpsc::channel();
+        let warmed = ReadySignal::new();
+        let signal = warmed.clone();
+        let thread = thread::Builder::new()
+            .name(String::from("angler-connection-warmer"))
+            .spawn(move || loop {
+                self.warm_up();
+                signal.raise();
+                match receiver.recv_timeout(interval) {
+                    // stopped by the WarmerHandle
+                    Ok(()) | Err(RecvTimeoutError::Disconnected) => return,
+                    Err(RecvTimeoutError::Timeout) => {}
+                }
+            })
+            .expect("failed to spawn the connection warmer");
+        WarmerHandle { sender, thread: Some(thread), warmed }
+    }
+}
+
+/// The background warmups of a ConnectionWarmer, stopped when it is dropped
+pub struct WarmerHandle {
+    sender: Sender<()>,
+    thread: Option<JoinHandle<()>>,
+    warmed: ReadySignal,
+}
+
+impl WarmerHandle {
+    /// Return the signal raised when the first warmup finished
+    pub fn warmed(&self) -> ReadySignal {
+        self.warmed.clone()
+    }
+
+    /// Stop the background warmups, waiting for the warmup in progress
+    pub fn stop(&mut self) {
+        let _ = self.sender.send(());
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+impl Drop for WarmerHandle {
+    fn drop(&mut self) {
+        self.stop();
+    }
+}
+
+impl HttpDeliverer {
+    /// Send the copy of a delivery to the shadow URL of the destination in the background. Its
+    /// response is ignored and its failures are only logged
+    fn send_shadow(&self, destination_id: &str, shadow_url: &str, mut request: HttpRequest) {
+        let url = match HttpUrl::parse(shadow_url) {
+            Ok(url) => url,
+            Err(err) => {
+                log!(Level::Debug, "The shadow of the destination {} has a invalid URL: {}", destination_id, err);
+                return;
+            }
+        };
+        request.headers.set(SHADOW_HEADER, "true");
+        let (destination_id, resolver, timeout) = (destination_id.to_string(), self.resolver.clone(), self.timeout);
+        let spawned = thread::Builder::new()
+            .name(String::from("angler-shadow"))
+            .spawn(move || {
+                let result = resolver.resolve(&url.host, url.port)
+                    .map_err(|err| format!("failed to resolve {}: {}", url.host, err))
+                    .and_then(|addresses| send_request_to(addresses[0], &url, request, timeout).map_err(|err| err.to_string()));
+                match result {
+                    Ok(response) if !response.is_success() => log!(Level::Debug, "The shadow of the destination {} answered HTTP {}", destination_id, response.status),
+                    Ok(_) => {}
+                    Err(err) => log!(Level::Debug, "Failed to send the copy to the shadow of the destination {}: {}", destination_id, err),
+                }
+            });
+        if let Err(err) = spawned {
+            log!(Level::Debug, "Failed to spawn the copy to the shadow: {}", err);
+        }
+    }
+}
+
+/// Return the request that sends the message to the target of the destination, in an attempt made at `now`
+pub fn delivery_request(destination: &Destination, message: &Message, target: &str, now: OffsetDateTime) -> HttpRequest {
+    let mut request = HttpRequest::new(destination.method.as_str(), target);
+    for (name, value) in &destination.headers {
+        request.headers.set(name, value);
+    }
+    request.headers.set("Content-Type", &destination.content_type);
+    for (key, value) in &message.attributes {
+        request.headers.set(&format!("{}{}", ATTRIBUTE_HEADER_PREFIX, key), value);
+    }
+    if let Some(sequence) = message.sequence {
+        request.headers.set(SEQUENCE_HEADER, &sequence.to_string());
+    }
+    let attempt = message.attempts + 1;
+    let max_attempts = match message.retry_policy.interval {
+        Some(_) => u32::from(message.retry_policy.max_attempts) + 1,
+        None => 1,
+    };
+    request.headers.set(MESSAGE_ID_HEADER, &message.id);
+    request.headers.set(ATTEMPT_HEADER, &attempt.to_string());
+    request.headers.set(MAX_ATTEMPTS_HEADER, &max_attempts.to_string());
+    if let Some(delay) = message.retry_policy.next_retry_delay(attempt) {
+        request.headers.set(NEXT_RETRY_AT_HEADER, &format_rfc3339(now + delay));
+    }
+    request.body = message.payload.clone();
+    request
+}
+
+impl Deliverer for HttpDeliverer {
+    fn deliver(&self, message: &Message) -> AttemptOutcome {
+        self.deliver_timed(message, &mut StageTimings::default())
+    }
+
+    fn deliver_timed(&self, message: &Message, timings: &mut StageTimings) -> AttemptOutcome {
+        let Some(destination) = self.destinations.get(&message.recipient_id) else {
+            let reason = format!("recipient {} has no destination registered", message.recipient_id);
+            return AttemptOutcome::Failed(DeliveryError::new(DeliveryErrorClass::NoDestination, reason));
+        };
+        if !destination.accepts(message) {
+            return AttemptOutcome::Filtered;
+        }
+        if destination.mode == DeliveryMode::Sse {
+            let data = String::from_utf8_lossy(&message.payload).into_owned();
+            return match self.sse.publish(&message.recipient_id, message.topic(), data) {
+                Some(_) => AttemptOutcome::Delivered,
+                None => {
+                    let reason = format!("no consumer is connected to the events of recipient {}", message.recipient_id);
+                    AttemptOutcome::Failed(DeliveryError::new(DeliveryErrorClass::Connection, reason))
+                }
+            };
+        }
+        let (branch, destination) = match destination.canary.clone() {
+            Some(canary) => match canary.branch_of(message) {
+                // the pinned address and the warm connections are the ones of the url of the destination
+                Branch::Canary => (Some(Branch::Canary), Destination { url: canary.url, pinned_address: None, warm_connections: None, ..destination }),
+                Branch::Primary => (Some(Branch::Primary), destination),
+            },
+            None => (None, destination),
+        };
+        let url = match HttpUrl::parse(&destination.url) {
+            Ok(url) => url,
+            Err(err) => return AttemptOutcome::Failed(DeliveryError::new(DeliveryErrorClass::NoDestination, err.to_string())),
+        };
+
+        let transform_started = Instant::now();
+        let now = OffsetDateTime::now_utc();
+        let mut request = delivery_request(&destination, message, &url.target, now);
+        self.identification.apply(&mut request);
+        if let Some(shadow_url) = &destination.shadow_url {
+            // the copy is sent without the ack token, so the shadow can not confirm the message
+            self.send_shadow(&destination.id, shadow_url, request.clone());
+        }
+        let confirm_before = destination.async_ack_timeout.map(|timeout| now + timeout);
+        if let Some(confirm_before) = confirm_before {
+            let token = AckToken::new(&message.id, message.attempts + 1, confirm_before).sign();
+            if let Some(callback_url) = &self.ack_callback_url {
+                request.headers.set(ACK_URL_HEADER, &ack_url(callback_url, &token));
+            }
+            request.headers.set(ACK_TOKEN_HEADER, &token);
+        }
+        // the bodies that already have a encoding, set by the headers of the destination, are sent as they are
+        let compression = destination.compression
+            .filter(|compression| request.body.len() >= compression.min_bytes && request.headers.get("Content-Encoding").is_none())
+            .filter(|_| self.receiver_encodings.lock().unwrap().get(&destination.url).copied().unwrap_or(false));
+        if let Some(compression) = compression {
+            request.body = gzip::compress(&request.body);
+            request.headers.set("Content-Encoding", compression.encoding());
+        }
+        timings.transform = Some(transform_started.elapsed());
+        let capture = self.captures.is_enabled(&destination.id).then(|| CapturedExchange {
+            message_id: message.id.clone(),
+            attempt: message.attempts + 1,
+            captured_at: OffsetDateTime::now_utc(),
+            method: request.method.clone(),
+            url: destination.url.clone(),
+            request_headers: request.headers.clone(),
+            request_body: CapturedBody::new(&request.body),
+            response: None,
+            error: None,
+        });
+
+        let result = send_hedged(&destination, &self.resolver, &self.pool, url, request, self.timeout);
+        if let Some(mut capture) = capture {
+            match &result {
+                Ok(response) => capture.response = Some(CapturedResponse {
+                    status: response.status,
+                    headers: response.headers.clone(),
+                    body: CapturedBody::new(&response.body),
+                }),
+                Err(err) => capture.error = Some(err.to_string()),
+            }
+            self.captures.record(&destination.id, capture);
+        }
+        if let (Some(_), Ok(response)) = (destination.compression, &result) {
+            // a receiver that refuses a compressed body no longer accepts gzip, and the retry is sent as published
+            let accepted = match response.status {
+                415 if compression.is_some() => Some(false),
+                _ => accepts_gzip(response),
+            };
+            if let Some(accepted) = accepted {
+                self.receiver_encodings.lock().unwrap().insert(destination.url.clone(), accepted);
+            }
+        }
+
+        let outcome = match result {
+            Ok(response) if response.is_success() => match confirm_before.filter(|_| response.status == 202) {
+                Some(confirm_before) => AttemptOutcome::Accepted { confirm_before },
+                None => AttemptOutcome::Delivered,
+            },
+            Ok(response) => {
+                let reason = match redirect_location(&response) {
+                    Some(location) => format!("HTTP {} redirect to {} was not followed", response.status, location),
+                    None => format!("HTTP {}", response.status),
+                };
+                AttemptOutcome::Failed(DeliveryError::from_response(response.status, reason))
+            }
+            Err(err) => AttemptOutcome::Failed(err),
+        };
+        if let Some(branch) = branch {
+            self.destinations.canary_stats().record(&destination.id, branch, !matches!(outcome, AttemptOutcome::Failed(_)));
+        }
+        outcome
+    }
+
+    fn retry_on(&self, message: &Message) -> Option<RetryOn> {
+        self.destinations.get(&message.recipient_id).and_then(|destination| destination.retry_on)
+    }
+
+    fn retry_budget(&self, message: &Message) -> Option<RetryBudget> {
+        self.destinations.get(&message.recipient_id).and_then(|destination| destination.retry_budget)
+    }
+
+    fn max_concurrency(&self, message: &Message) -> Option<usize> {
+        self.destinations.get(&message.recipient_id).and_then(|destination| destination.max_concurrency).map(usize::from)
+    }
+
+    fn is_pulled(&self, message: &Message) -> bool {
+        self.destinations.get(&message.recipient_id).is_some_and(|destination| destination.mode == DeliveryMode::Pull && destination.accepts(message))
+    }
+
+    fn parked_until(&self, message: &Message) -> Option<OffsetDateTime> {
+        self.destinations.purged_at(&message.recipient_id)
+    }
+
+    fn held_until(&self, message: &Message, now: OffsetDateTime) -> Option<OffsetDateTime> {
+        let window = self.destinations.get(&message.recipient_id)?.delivery_window?;
+        Some(window.next_opening(now)).filter(|opening| *opening > now)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_if_request_errors_are_classified() {
+        let timed_out = || io::Error::from(io::ErrorKind::TimedOut);
+        assert_eq!(request_error(HttpError::Connect(timed_out())).class, DeliveryErrorClass::ConnectTimeout);
+        assert_eq!(request_error(HttpError::Io(timed_out())).class, DeliveryErrorClass::ResponseTimeout);
+        assert_eq!(request_error(HttpError::Io(io::Error::from(io::ErrorKind::WouldBlock))).class, DeliveryErrorClass::ResponseTimeout);
+        assert_eq!(request_error(HttpError::Connect(io::Error::from(io::ErrorKind::ConnectionRefused))).class, DeliveryErrorClass::Connection);
+        assert_eq!(request_error(HttpError::TooLarge).class, DeliveryErrorClass::InvalidResponse);
+        assert_eq!(DeliveryErrorClass::from_status(413), DeliveryErrorClass::PayloadTooLarge);
+        assert_eq!(DeliveryErrorClass::from_status(404), DeliveryErrorClass::Http4xx);
+        assert_eq!(DeliveryErrorClass::from_status(503), DeliveryErrorClass::Http5xx);
+        assert_eq!(DeliveryErrorClass::from_status(308), DeliveryErrorClass::Redirect);
+    }
+
+    #[test]
+    fn test_if_deliveries_are_identified_without_replacing_the_headers_of_the_destination() {
+        let identification = DeliveryIdentification::new("acme-angler/{version} node={nodeId}").with_header("X-Sender", "angler-prod");
+        assert_eq!(identification.user_agent(), format!("acme-angler/{} node={}", env!("CARGO_PKG_VERSION"), local_node_id()));
+
+        let mut request = HttpRequest::new("POST", "/orders");
+        request.headers.set("X-Sender", "orders-destination");
+        identification.apply(&mut request);
+        assert_eq!(request.headers.get("User-Agent"), Some(identification.user_agent()));
+        assert_eq!(request.headers.get("X-Sender"), Some("orders-destination"));
+        assert_eq!(DeliveryIdentification::default().user_agent(), format!("Angler/{}", env!("CARGO_PKG_VERSION")));
+    }
+}
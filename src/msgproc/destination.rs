@@ -0,0 +1,695 @@
+use std::{collections::{BTreeMap, HashMap}, net::IpAddr, sync::RwLock, time::Duration};
+
+use thiserror::Error;
+use time::OffsetDateTime;
+
+use super::{canary::{CanaryRoute, CanaryStats}, message::Message, retry::{RetryBudget, RetryOn}, window::DeliveryWindow};
+
+/// The default value of the `Content-Type` sent to destinations
+pub const DEFAULT_CONTENT_TYPE: &str = "application/json";
+
+/// How long a deleted destination can be restored when `msgproc.destinations.deleteGracePeriod`
+/// is not set
+pub const DEFAULT_DELETE_GRACE_PERIOD: time::Duration = time::Duration::days(1);
+
+/// How many changes of each destination are kept in its history
+pub const MAX_HISTORY_CHANGES: usize = 100;
+
+/// The HTTP method used to send the messages to a destination
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum DeliveryMethod {
+    #[default]
+    Post,
+    Put,
+    Patch,
+}
+
+impl DeliveryMethod {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            DeliveryMethod::Post => "POST",
+            DeliveryMethod::Put => "PUT",
+            DeliveryMethod::Patch => "PATCH",
+        }
+    }
+
+    /// Parse the name of the method used in the APIs
+    pub fn from_name(name: &str) -> Option<DeliveryMethod> {
+        match name {
+            "POST" => Some(DeliveryMethod::Post),
+            "PUT" => Some(DeliveryMethod::Put),
+            "PATCH" => Some(DeliveryMethod::Patch),
+            _ => None,
+        }
+    }
+}
+
+/// How the messages reach a destination
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum DeliveryMode {
+    /// The messages are sent to the URL of the destination
+    #[default]
+    Push,
+    /// The recipient pulls the messages from the pull API, for receivers without a public endpoint
+    Pull,
+    /// The messages are sent to the consumers connected to the server-sent events stream of the destination
+    Sse,
+}
+
+impl DeliveryMode {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            DeliveryMode::Push => "push",
+            DeliveryMode::Pull => "pull",
+            DeliveryMode::Sse => "sse",
+        }
+    }
+
+    /// Parse the name of the mode used in the APIs
+    pub fn from_name(name: &str) -> Option<DeliveryMode> {
+        match name {
+            "push" => Some(DeliveryMode::Push),
+            "pull" => Some(DeliveryMode::Pull),
+            "sse" => Some(DeliveryMode::Sse),
+            _ => None,
+        }
+    }
+}
+
+/// How many redirects a RedirectPolicy follows when the destination does not set it
+pub const DEFAULT_MAX_REDIRECTS: u8 = 3;
+
+/// The maximum number of redirects a RedirectPolicy can follow
+pub const MAX_REDIRECTS_LIMIT: u8 = 10;
+
+/// Which redirect responses (301, 302, 303, 307 and 308) are followed when delivering to a
+/// destination. Redirects are not followed by default, and the response is a failed attempt, so a
+/// receiver can not send messages to a host the destination owner did not register
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum RedirectPolicy {
+    #[default]
+    None,
+    /// Follow up to the given amount of redirects to the same host and port
+    SameHost(u8),
+    /// Follow up to the given amount of redirects to any `http://` URL
+    Limited(u8),
+}
+
+impl RedirectPolicy {
+    /// Return the name of the policy used in the APIs
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            RedirectPolicy::None => "none",
+            RedirectPolicy::SameHost(_) => "sameHost",
+            RedirectPolicy::Limited(_) => "limited",
+        }
+    }
+
+    /// Return how many redirects are followed
+    pub fn max_redirects(&self) -> u8 {
+        match self {
+            RedirectPolicy::None => 0,
+            RedirectPolicy::SameHost(max) | RedirectPolicy::Limited(max) => *max,
+        }
+    }
+
+    /// Parse the name of the policy and its maximum number of redirects
+    pub fn from_name(name: &str, max_redirects: u8) -> Option<RedirectPolicy> {
+        match name {
+            "none" => Some(RedirectPolicy::None),
+            "sameHost" => Some(RedirectPolicy::SameHost(max_redirects)),
+            "limited" => Some(RedirectPolicy::Limited(max_redirects)),
+            _ => None,
+        }
+    }
+}
+
+/// The `compression.minBytes` of a destination when it is not set
+pub const DEFAULT_COMPRESSION_MIN_BYTES: usize = 1024;
+
+/// Send the bodies of a destination compressed with gzip, with `Content-Encoding: gzip`, when
+/// the payload has at least `min_bytes` and the receiver advertised that it accepts gzip by a
+/// `Accept-Encoding` header in its responses. Until it does the bodies are sent as published
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BodyCompression {
+    pub min_bytes: usize,
+}
+
+impl BodyCompression {
+    pub fn new(min_bytes: usize) -> BodyCompression {
+        BodyCompression { min_bytes }
+    }
+
+    /// Return the `Content-Encoding` of the compressed bodies
+    pub fn encoding(&self) -> &'static str {
+        "gzip"
+    }
+}
+
+impl Default for BodyCompression {
+    fn default() -> Self {
+        BodyCompression::new(DEFAULT_COMPRESSION_MIN_BYTES)
+    }
+}
+
+/// The endpoint where the messages of a recipient are sent to
+#[derive(Debug, Clone, PartialEq)]
+pub struct Destination {
+    /// The ID of the recipient that owns this destination. It is the `recipientId` of the messages
+    pub id: String,
+    /// The `http://` URL that will receive the messages. Empty for pull destinations without URL
+    pub url: String,
+    pub mode: DeliveryMode,
+    /// Only send the messages whose attributes have all these values. Empty sends every message
+    pub attribute_filter: BTreeMap<String, String>,
+    pub method: DeliveryMethod,
+    /// The `Content-Type` of the requests. The payload is sent as it was published, so the messages
+    /// of receivers that expect form-encoded bodies should be published already encoded
+    pub content_type: String,
+    /// Headers sent in every request, like the credentials expected by the receiver
+    pub headers: BTreeMap<String, String>,
+    pub redirect_policy: RedirectPolicy,
+    /// Send a second request when the first one did not get a response after this delay, using
+    /// the response that arrives first. It trades more requests for a lower tail latency, so it
+    /// should only be used by receivers that handle duplicated messages
+    pub hedge_after: Option<Duration>,
+    /// Connect to this address instead of resolving the host of the URL. Redirects to other hosts
+    /// are still resolved
+    pub pinned_address: Option<IpAddr>,
+    /// Which failures are retried instead of the `retryPolicy.retryOn`
+    pub retry_on: Option<RetryOn>,
+    /// Limit the retries of the destination, so a failing receiver is not flooded by them
+    pub retry_budget: Option<RetryBudget>,
+    /// Only send the messages inside the window, holding the other ones as pending until it opens
+    pub delivery_window: Option<DeliveryWindow>,
+    /// Treat a `202` as accepted instead of delivered, and wait up to this timeout for the receiver
+    /// to confirm the message with the token of the `X-Angler-Ack-Token` header
+    pub async_ack_timeout: Option<Duration>,
+    /// Keep this many connections open to the destination, reused by its attempts, so they do not
+    /// wait for a new connection. See `ConnectionPool`
+    pub warm_connections: Option<u16>,
+    /// The most attempts sent at once to the destination. The processor sends fewer when its
+    /// responses slow down or fail with overload, see `AdaptiveConcurrency`
+    pub max_concurrency: Option<u16>,
+    /// Compress the large bodies for the receiver when it accepts it
+    pub compression: Option<BodyCompression>,
+    /// The `http://` URL that receives a copy of each delivery, like a receiver being migrated or
+    /// a staging environment. The copies are not retried and their failures do not affect the attempt
+    pub shadow_url: Option<String>,
+    /// Send a share of the messages to another URL instead of the `url`
+    pub canary: Option<CanaryRoute>,
+    /// Set by the DestinationRegistry each time the destination is registered, 0 before that
+    pub version: u64,
+}
+
+impl Destination {
+    pub fn new(id: &str, url: &str) -> Destination {
+        Destination {
+            id: id.to_string(),
+            url: url.to_string(),
+            mode: DeliveryMode::default(),
+            attribute_filter: BTreeMap::new(),
+            method: DeliveryMethod::default(),
+            content_type: DEFAULT_CONTENT_TYPE.to_string(),
+            headers: BTreeMap::new(),
+            redirect_policy: RedirectPolicy::default(),
+            hedge_after: None,
+            pinned_address: None,
+            retry_on: None,
+            retry_budget: None,
+            delivery_window: None,
+            async_ack_timeout: None,
+            warm_connections: None,
+            max_concurrency: None,
+            compression: None,
+            shadow_url: None,
+            canary: None,
+            version: 0,
+        }
+    }
+
+    pub fn with_delivery_window(mut self, window: DeliveryWindow) -> Destination {
+        self.delivery_window = Some(window);
+        self
+    }
+
+    /// Wait up to the timeout for the receiver to confirm the messages it answers with `202`
+    pub fn with_async_ack(mut self, timeout: Duration) -> Destination {
+        self.async_ack_timeout = Some(timeout);
+        self
+    }
+
+    /// Keep the connections open to the destination and send its messages through them
+    pub fn with_warm_connections(mut self, count: u16) -> Destination {
+        self.warm_connections = Some(count);
+        self
+    }
+
+    /// Send at most `max` attempts at once to the destination
+    pub fn with_max_concurrency(mut self, max: u16) -> Destination {
+        self.max_concurrency = Some(max);
+        self
+    }
+
+    /// Compress the bodies of the destination that have at least `compression.min_bytes`
+    pub fn with_compression(mut self, compression: BodyCompression) -> Destination {
+        self.compression = Some(compression);
+        self
+    }
+
+    /// Send a copy of each delivery to the shadow URL
+    pub fn with_shadow(mut self, shadow_url: &str) -> Destination {
+        self.shadow_url = Some(shadow_url.to_string());
+        self
+    }
+
+    /// Send the share of the messages of the canary to its URL
+    pub fn with_canary(mut self, canary: CanaryRoute) -> Destination {
+        self.canary = Some(canary);
+        self
+    }
+
+    /// Only retry the failures that match instead of the `retryPolicy.retryOn`
+    pub fn with_retry_on(mut self, retry_on: RetryOn) -> Destination {
+        self.retry_on = Some(retry_on);
+        self
+    }
+
+    pub fn with_retry_budget(mut self, retry_budget: RetryBudget) -> Destination {
+        self.retry_budget = Some(retry_budget);
+        self
+    }
+
+    /// Create a destination whose messages are pulled by the recipient
+    pub fn pull(id: &str) -> Destination {
+        Destination::new(id, "").with_mode(DeliveryMode::Pull)
+    }
+
+    /// Deliver the messages with the given mode instead of pushing them
+    pub fn with_mode(mut self, mode: DeliveryMode) -> Destination {
+        self.mode = mode;
+        self
+    }
+
+    /// Connect to the address instead of resolving the host of the URL
+    pub fn with_pinned_address(mut self, address: IpAddr) -> Destination {
+        self.pinned_address = Some(address);
+        self
+    }
+
+    /// Hedge the requests that did not get a response after the delay
+    pub fn with_hedging(mut self, hedge_after: Duration) -> Destination {
+        self.hedge_after = Some(hedge_after);
+        self
+    }
+
+    /// Follow the redirects allowed by the policy instead of failing the attempt
+    pub fn with_redirect_policy(mut self, redirect_policy: RedirectPolicy) -> Destination {
+        self.redirect_policy = redirect_policy;
+        self
+    }
+
+    /// Send the messages with the given HTTP method instead of POST
+    pub fn with_method(mut self, method: DeliveryMethod) -> Destination {
+        self.method = method;
+        self
+    }
+
+    /// Send the messages with the given `Content-Type` instead of `application/json`
+    pub fn with_content_type(mut self, content_type: &str) -> Destination {
+        self.content_type = content_type.to_string();
+        self
+    }
+
+    /// Send the headers in every request
+    pub fn with_headers(mut self, headers: BTreeMap<String, String>) -> Destination {
+        self.headers = headers;
+        self
+    }
+
+    /// Only send the messages whose attributes have all the values of the filter
+    pub fn with_attribute_filter(mut self, attribute_filter: BTreeMap<String, String>) -> Destination {
+        self.attribute_filter = attribute_filter;
+        self
+    }
+
+    /// Return if the message attributes match the attribute filter of this destination
+    pub fn accepts(&self, message: &Message) -> bool {
+        self.attribute_filter.iter().all(|(key, value)| message.attributes.get(key) == Some(value))
+    }
+}
+
+/// The version a destination should have for a change to be applied, so the changes made at the
+/// same time by two operators do not overwrite each other
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ExpectedVersion {
+    /// Apply the change whatever the version is
+    #[default]
+    Any,
+    /// Only apply the change when the destination does not exist
+    Absent,
+    /// Only apply the change when the destination exists
+    Present,
+    /// Only apply the change when the destination has the version
+    Exactly(u64),
+}
+
+impl ExpectedVersion {
+    /// Return if the version of the destination, None when it does not exist, is the expected one
+    pub fn matches(&self, current: Option<u64>) -> bool {
+        match self {
+            ExpectedVersion::Any => true,
+            ExpectedVersion::Absent => current.is_none(),
+            ExpectedVersion::Present => current.is_some(),
+            ExpectedVersion::Exactly(version) => current == Some(*version),
+        }
+    }
+}
+
+/// A change of a destination refused because its version was not the expected one
+#[derive(Debug, Error, PartialEq)]
+#[error("the destination {}", .current.map_or_else(|| String::from("does not exist"), |version| format!("is at version {}", version)))]
+pub struct VersionConflict {
+    /// The version of the destination, None when it does not exist
+    pub current: Option<u64>,
+}
+
+/// What a change did to a destination
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ChangeKind {
+    Registered,
+    Removed,
+    Restored,
+    RolledBack,
+}
+
+impl ChangeKind {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ChangeKind::Registered => "registered",
+            ChangeKind::Removed => "removed",
+            ChangeKind::Restored => "restored",
+            ChangeKind::RolledBack => "rolledBack",
+        }
+    }
+}
+
+/// When and by whom a destination was changed
+#[derive(Debug, Clone, PartialEq)]
+pub struct ChangeOrigin {
+    pub at: OffsetDateTime,
+    /// Who made the change, when it is known
+    pub by: Option<String>,
+}
+
+impl ChangeOrigin {
+    /// A change made at the given time by someone unknown
+    pub fn at(at: OffsetDateTime) -> ChangeOrigin {
+        ChangeOrigin { at, by: None }
+    }
+
+    pub fn with_author(mut self, by: Option<String>) -> ChangeOrigin {
+        self.by = by;
+        self
+    }
+}
+
+/// A change in the history of a destination
+#[derive(Debug, Clone, PartialEq)]
+pub struct DestinationChange {
+    pub kind: ChangeKind,
+    pub origin: ChangeOrigin,
+    /// The destination after the change, None when it was removed
+    pub destination: Option<Destination>,
+}
+
+/// A rollback refused because the version is unknown or the destination changed
+#[derive(Debug, Error, PartialEq)]
+pub enum RollbackError {
+    #[error("the destination has no version {0} in its history")]
+    UnknownVersion(u64),
+    #[error(transparent)]
+    Conflict(#[from] VersionConflict),
+}
+
+#[derive(Debug, Default)]
+struct Destinations {
+    registered: HashMap<String, Destination>,
+    /// The removed destinations that can still be restored, with when they are purged
+    deleted: HashMap<String, (Destination, OffsetDateTime)>,
+    /// The last version given to a destination. Versions are never reused, so a version read
+    /// before a destination was removed does not match the one registered after it
+    last_version: u64,
+    /// The last MAX_HISTORY_CHANGES changes of each destination, oldest first. The history is kept
+    /// after the destination is removed
+    history: HashMap<String, Vec<DestinationChange>>,
+}
+
+impl Destinations {
+    /// Register the destination with a new version, recording the change in its history
+    fn insert(&mut self, mut destination: Destination, kind: ChangeKind, origin: &ChangeOrigin) -> Destination {
+        // a destination registered again replaces the deleted one
+        self.deleted.remove(&destination.id);
+        self.last_version += 1;
+        destination.version = self.last_version;
+        self.registered.insert(destination.id.clone(), destination.clone());
+        self.record(&destination.id, kind, origin, Some(destination.clone()));
+        destination
+    }
+
+    fn record(&mut self, id: &str, kind: ChangeKind, origin: &ChangeOrigin, destination: Option<Destination>) {
+        let history = self.history.entry(id.to_string()).or_default();
+        history.push(DestinationChange { kind, origin: origin.clone(), destination });
+        if history.len() > MAX_HISTORY_CHANGES {
+            history.remove(0);
+        }
+    }
+}
+
+/// A thread-safe registry of the destinations known by this instance. Removed destinations are
+/// kept for a grace period, when they can be restored and the messages sent to them are parked
+#[derive(Debug)]
+pub struct DestinationRegistry {
+    destinations: RwLock<Destinations>,
+    delete_grace_period: time::Duration,
+    /// The outcomes of the branches of the destinations with a canary, reset when it changes
+    canary_stats: CanaryStats,
+}
+
+impl Default for DestinationRegistry {
+    fn default() -> Self {
+        DestinationRegistry { destinations: RwLock::default(), delete_grace_period: DEFAULT_DELETE_GRACE_PERIOD, canary_stats: CanaryStats::new() }
+    }
+}
+
+impl DestinationRegistry {
+    pub fn new() -> DestinationRegistry {
+        DestinationRegistry::default()
+    }
+
+    /// Keep the removed destinations for the grace period instead of the default one. With zero
+    /// they are removed at once
+    pub fn with_delete_grace_period(mut self, grace_period: time::Duration) -> DestinationRegistry {
+        self.delete_grace_period = grace_period;
+        self
+    }
+
+    /// Add or replace the destination with the same ID, returning it with its new version
+    pub fn register(&self, destination: Destination) -> Destination {
+        self.register_if(destination, ExpectedVersion::Any, &ChangeOrigin::at(OffsetDateTime::now_utc())).expect("any version is expected")
+    }
+
+    /// Add or replace the destination with the same ID when the current one has the expected
+    /// version, returning it with its new version
+    pub fn register_if(&self, destination: Destination, expected: ExpectedVersion, origin: &ChangeOrigin) -> Result<Destination, VersionConflict> {
+        let destinations = &mut *self.destinations.write().unwrap();
+        let current = destinations.registered.get(&destination.id).map(|current| current.version);
+        if !expected.matches(current) {
+            return Err(VersionConflict { current });
+        }
+        self.reset_changed_canary(destinations, &destination);
+        Ok(destinations.insert(destination, ChangeKind::Registered, origin))
+    }
+
+    /// Reset the CanaryStats of the destination when it is registered with another canary, so the
+    /// branches are compared with the outcomes of the new one
+    fn reset_changed_canary(&self, destinations: &Destinations, destination: &Destination) {
+        if destinations.registered.get(&destination.id).map(|current| &current.canary) != Some(&destination.canary) {
+            self.canary_stats.reset(&destination.id);
+        }
+    }
+
+    /// Return the outcomes of the branches of the destinations with a canary
+    pub fn canary_stats(&self) -> &CanaryStats {
+        &self.canary_stats
+    }
+
+    /// Remove the destination returning it if it existed. It can be restored until the grace
+    /// period after `now` ends
+    pub fn remove(&self, id: &str, now: OffsetDateTime) -> Option<Destination> {
+        self.remove_if(id, ExpectedVersion::Any, &ChangeOrigin::at(now)).expect("any version is expected")
+    }
+
+    /// Remove the destination like `remove` when it has the expected version
+    pub fn remove_if(&self, id: &str, expected: ExpectedVersion, origin: &ChangeOrigin) -> Result<Option<Destination>, VersionConflict> {
+        let destinations = &mut *self.destinations.write().unwrap();
+        let current = destinations.registered.get(id).map(|current| current.version);
+        if !expected.matches(current) {
+            return Err(VersionConflict { current });
+        }
+        let removed = destinations.registered.remove(id);
+        if let Some(removed) = &removed {
+            if self.delete_grace_period.is_positive() {
+                destinations.deleted.insert(id.to_string(), (removed.clone(), origin.at + self.delete_grace_period));
+            }
+            destinations.record(id, ChangeKind::Removed, origin, None);
+        }
+        Ok(removed)
+    }
+
+    /// Register again a removed destination whose grace period did not end, with a new version
+    pub fn restore(&self, id: &str, origin: &ChangeOrigin) -> Option<Destination> {
+        let destinations = &mut *self.destinations.write().unwrap();
+        let (destination, purge_at) = destinations.deleted.remove(id)?;
+        if purge_at <= origin.at {
+            return None;
+        }
+        Some(destinations.insert(destination, ChangeKind::Restored, origin))
+    }
+
+    /// Register again the destination as it was at a version of its history, with a new version,
+    /// when the current one has the expected version. A removed destination is registered again
+    pub fn rollback(&self, id: &str, version: u64, expected: ExpectedVersion, origin: &ChangeOrigin) -> Result<Destination, RollbackError> {
+        let destinations = &mut *self.destinations.write().unwrap();
+        let current = destinations.registered.get(id).map(|current| current.version);
+        if !expected.matches(current) {
+            return Err(VersionConflict { current }.into());
+        }
+        let destination = destinations.history.get(id).into_iter().flatten()
+            .filter_map(|change| change.destination.as_ref())
+            .find(|destination| destination.version == version)
+            .cloned()
+            .ok_or(RollbackError::UnknownVersion(version))?;
+        self.reset_changed_canary(destinations, &destination);
+        Ok(destinations.insert(destination, ChangeKind::RolledBack, origin))
+    }
+
+    /// Return the last changes of the destination, oldest first
+    pub fn history(&self, id: &str) -> Vec<DestinationChange> {
+        self.destinations.read().unwrap().history.get(id).cloned().unwrap_or_default()
+    }
+
+    /// Return the removed destinations that can be restored, with when they are purged. The
+    /// ones whose grace period ended are purged
+    pub fn deleted(&self, now: OffsetDateTime) -> Vec<(Destination, OffsetDateTime)> {
+        let destinations = &mut *self.destinations.write().unwrap();
+        destinations.deleted.retain(|_, (_, purge_at)| *purge_at > now);
+        destinations.deleted.values().cloned().collect()
+    }
+
+    /// Return when the removed destination with the given ID is purged, while it can be restored.
+    /// The messages of its recipient are parked until then
+    pub fn purged_at(&self, id: &str) -> Option<OffsetDateTime> {
+        self.destinations.read().unwrap().deleted.get(id).map(|(_, purge_at)| *purge_at)
+    }
+
+    /// Return a copy of the destination with the given ID
+    pub fn get(&self, id: &str) -> Option<Destination> {
+        self.destinations.read().unwrap().registered.get(id).cloned()
+    }
+
+    /// Return a copy of all registered destinations
+    pub fn list(&self) -> Vec<Destination> {
+        self.destinations.read().unwrap().registered.values().cloned().collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_if_destination_only_accepts_messages_matching_its_attribute_filter() {
+        let mut message = Message::new("a".to_string(), "r".to_string(), "s".to_string(), "e".to_string(), vec![]);
+        message.attributes.insert(String::from("region"), String::from("eu"));
+        message.attributes.insert(String::from("plan"), String::from("pro"));
+
+        assert!(Destination::new("r", "http://localhost/").accepts(&message));
+        let eu = Destination::new("r", "http://localhost/").with_attribute_filter(BTreeMap::from([(String::from("region"), String::from("eu"))]));
+        assert!(eu.accepts(&message));
+        let us = Destination::new("r", "http://localhost/").with_attribute_filter(BTreeMap::from([(String::from("region"), String::from("us"))]));
+        assert!(!us.accepts(&message));
+    }
+
+    #[test]
+    fn test_if_destinations_are_only_changed_at_the_expected_version() {
+        let registry = DestinationRegistry::new();
+        let first = registry.register_if(Destination::new("r", "http://a/"), ExpectedVersion::Absent, &ChangeOrigin::at(OffsetDateTime::UNIX_EPOCH)).unwrap();
+        assert_eq!(first.version, 1);
+        assert_eq!(registry.register_if(Destination::new("r", "http://b/"), ExpectedVersion::Absent, &ChangeOrigin::at(OffsetDateTime::UNIX_EPOCH)), Err(VersionConflict { current: Some(1) }));
+
+        // the second operator read the same version, so its change is refused
+        let second = registry.register_if(Destination::new("r", "http://b/"), ExpectedVersion::Exactly(1), &ChangeOrigin::at(OffsetDateTime::UNIX_EPOCH)).unwrap();
+        assert_eq!(second.version, 2);
+        assert!(registry.register_if(Destination::new("r", "http://c/"), ExpectedVersion::Exactly(1), &ChangeOrigin::at(OffsetDateTime::UNIX_EPOCH)).is_err());
+        assert_eq!(registry.get("r").unwrap().url, "http://b/");
+        assert_eq!(registry.remove_if("r", ExpectedVersion::Exactly(1), &ChangeOrigin::at(OffsetDateTime::UNIX_EPOCH)), Err(VersionConflict { current: Some(2) }));
+
+        // the versions are not reused after a removal
+        assert!(registry.remove_if("r", ExpectedVersion::Exactly(2), &ChangeOrigin::at(OffsetDateTime::UNIX_EPOCH)).unwrap().is_some());
+        assert_eq!(registry.register(Destination::new("r", "http://d/")).version, 3);
+        assert_eq!(VersionConflict { current: None }.to_string(), "the destination does not exist");
+    }
+
+    #[test]
+    fn test_if_removed_destinations_are_restored_during_their_grace_period() {
+        let now = OffsetDateTime::UNIX_EPOCH + time::Duration::days(20_000);
+        let registry = DestinationRegistry::new().with_delete_grace_period(time::Duration::hours(1));
+        registry.register(Destination::new("a", "http://a/"));
+        registry.register(Destination::new("b", "http://b/"));
+        registry.remove("a", now);
+        registry.remove("b", now);
+        assert!(registry.get("a").is_none());
+        assert_eq!(registry.purged_at("a"), Some(now + time::Duration::hours(1)));
+        assert_eq!(registry.deleted(now).len(), 2);
+
+        let restored = registry.restore("a", &ChangeOrigin::at(now + time::Duration::minutes(59))).unwrap();
+        assert_eq!((restored.url.as_str(), restored.version), ("http://a/", 3));
+        assert_eq!(registry.get("a"), Some(restored));
+        assert!(registry.restore("b", &ChangeOrigin::at(now + time::Duration::hours(1))).is_none());
+        assert!(registry.deleted(now).is_empty());
+        assert_eq!(registry.purged_at("a"), None);
+
+        let without_grace = DestinationRegistry::new().with_delete_grace_period(time::Duration::ZERO);
+        without_grace.register(Destination::new("a", "http://a/"));
+        without_grace.remove("a", now);
+        assert!(without_grace.restore("a", &ChangeOrigin::at(now)).is_none());
+    }
+
+    #[test]
+    fn test_if_destinations_are_rolled_back_to_a_version_of_their_history() {
+        let now = OffsetDateTime::UNIX_EPOCH + time::Duration::days(20_000);
+        let registry = DestinationRegistry::new();
+        let by = |author: &str, minutes: i64| ChangeOrigin::at(now + time::Duration::minutes(minutes)).with_author(Some(author.to_string()));
+        registry.register_if(Destination::new("r", "http://a/"), ExpectedVersion::Any, &by("alice", 0)).unwrap();
+        registry.register_if(Destination::new("r", "http://broken/"), ExpectedVersion::Any, &by("bob", 5)).unwrap();
+        registry.remove_if("r", ExpectedVersion::Any, &by("bob", 10)).unwrap();
+
+        let history = registry.history("r");
+        assert_eq!(history.iter().map(|change| change.kind).collect::<Vec<_>>(), vec![ChangeKind::Registered, ChangeKind::Registered, ChangeKind::Removed]);
+        assert_eq!(history[1].origin, by("bob", 5));
+        assert_eq!(history[2].destination, None);
+
+        let rolled_back = registry.rollback("r", 1, ExpectedVersion::Absent, &by("alice", 15)).unwrap();
+        assert_eq!((rolled_back.url.as_str(), rolled_back.version), ("http://a/", 3));
+        assert_eq!(registry.history("r").last().unwrap().kind, ChangeKind::RolledBack);
+        assert_eq!(registry.rollback("r", 2, ExpectedVersion::Exactly(2), &by("bob", 20)), Err(RollbackError::Conflict(VersionConflict { current: Some(3) })));
+        assert_eq!(registry.rollback("r", 7, ExpectedVersion::Any, &by("bob", 20)), Err(RollbackError::UnknownVersion(7)));
+
+        for index in 0..MAX_HISTORY_CHANGES {
+            registry.register(Destination::new("r", &format!("http://{}/", index)));
+        }
+        assert_eq!(registry.history("r").len(), MAX_HISTORY_CHANGES);
+        assert_eq!(registry.rollback("r", 1, ExpectedVersion::Any, &by("alice", 30)), Err(RollbackError::UnknownVersion(1)));
+    }
+}
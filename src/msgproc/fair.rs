@@ -0,0 +1,240 @@
+use std::collections::{HashMap, VecDeque};
+
+/// A demoted key takes one of this many of its turns, giving the others to the next keys
+pub const DEMOTED_TURN_SHARE: usize = 4;
+
+/// A queue that serves its keys in turns, one item of each key per turn, keeping the FIFO order of
+/// the items of each key. It is a deficit round-robin where every item costs the same, so a key
+/// with a large backlog can not starve the keys with small ones
+#[derive(Debug)]
+pub struct FairQueue<T> {
+    queues: HashMap<String, VecDeque<T>>,
+    /// The keys with items, in the order of their next turn
+    turns: VecDeque<String>,
+    /// The demoted keys, with how many turns they gave away since their last one
+    demoted: HashMap<String, usize>,
+    len: usize,
+}
+
+impl<T> Default for FairQueue<T> {
+    fn default() -> Self {
+        FairQueue { queues: HashMap::new(), turns: VecDeque::new(), demoted: HashMap::new(), len: 0 }
+    }
+}
+
+impl<T> FairQueue<T> {
+    pub fn new() -> FairQueue<T> {
+        FairQueue::default()
+    }
+
+    /// Add the item after the other items of the key. A key without items takes the last turn
+    pub fn push(&mut self, key: &str, item: T) {
+        let queue = self.queues.entry(key.to_string()).or_default();
+        if queue.is_empty() {
+            self.turns.push_back(key.to_string());
+        }
+        queue.push_back(item);
+        self.len += 1;
+    }
+
+    /// Remove the oldest item of the key whose turn it is. The key goes to the last turn. A demoted
+    /// key gives its turn to the next key, unless all the keys with items are demoted, until it
+    /// gave DEMOTED_TURN_SHARE - 1 of them
+    pub fn pop(&mut self) -> Option<T> {
+        self.pop_where(|_, _| true)
+    }
+
+    /// Remove the oldest item of the key whose turn it is, like `pop`, skipping the keys whose
+    /// oldest item is not allowed. The skipped keys keep their turns
+    pub fn pop_where(&mut self, mut allowed: impl FnMut(&str, &T) -> bool) -> Option<T> {
+        self.pop_at(|key, queue| queue.front().filter(|oldest| allowed(key, oldest)).map(|_| 0))
+    }
+
+    /// Remove the oldest allowed item of the key whose turn it is, like `pop_where`, skipping the
+    /// items that are not allowed, that keep their place. The key is allowed by its oldest item
+    pub fn pop_first_where(&mut self, mut allowed_key: impl FnMut(&str, &T) -> bool, mut allowed_item: impl FnMut(&T) -> bool) -> Option<T> {
+        self.pop_at(|key, queue| match queue.front() {
+            Some(oldest) if allowed_key(key, oldest) => queue.iter().position(&mut allowed_item),
+            _ => None,
+        })
+    }
+
+    /// Remove the item at the position returned for the key whose turn it is, skipping the keys
+    /// without a position
+    fn pop_at(&mut self, mut position: impl FnMut(&str, &VecDeque<T>) -> Option<usize>) -> Option<T> {
+        let has_promoted = self.turns.iter().any(|turn| !self.demoted.contains_key(turn));
+        let mut skipped = Vec::new();
+        let (key, index) = loop {
+            let Some(key) = self.turns.pop_front() else {
+                self.turns.extend(skipped);
+                return None;
+            };
+            let queue = self.queues.get(&key).expect("the keys with turns have items");
+            let Some(index) = position(&key, queue) else {
+                skipped.push(key);
+                continue;
+            };
+            match self.demoted.get_mut(&key) {
+                Some(given) if has_promoted && *given + 1 < DEMOTED_TURN_SHARE => {
+                    *given += 1;
+                    self.turns.push_back(key);
+                }
+                Some(given) => {
+                    *given = 0;
+                    break (key, index);
+                }
+                None => break (key, index),
+            }
+        };
+        for skipped in skipped.into_iter().rev() {
+            self.turns.push_front(skipped);
+        }
+        let queue = self.queues.get_mut(&key).expect("the keys with turns have a queue");
+        let item = queue.remove(index);
+        if queue.is_empty() {
+            self.queues.remove(&key);
+        } else {
+            self.turns.push_back(key);
+        }
+        self.len -= 1;
+        item
+    }
+
+    /// Remove all the items of the key, in their order
+    pub fn take(&mut self, key: &str) -> Vec<T> {
+        let Some(queue) = self.queues.remove(key) else {
+            return Vec::new();
+        };
+        self.turns.retain(|turn| turn != key);
+        self.len -= queue.len();
+        queue.into()
+    }
+
+    /// Remove the items that match, keeping the order and the turns of the others
+    pub fn remove_matching(&mut self, mut matches: impl FnMut(&T) -> bool) -> Vec<T> {
+        let mut removed = Vec::new();
+        for queue in self.queues.values_mut() {
+            let (matched, kept): (VecDeque<T>, VecDeque<T>) = std::mem::take(queue).into_iter().partition(|item| matches(item));
+            *queue = kept;
+            removed.extend(matched);
+        }
+        self.queues.retain(|_, queue| !queue.is_empty());
+        let queues = &self.queues;
+        self.turns.retain(|turn| queues.contains_key(turn));
+        self.len -= removed.len();
+        removed
+    }
+
+    /// Make the key take fewer turns, or all of them again when `demoted` is false
+    pub fn set_demoted(&mut self, key: &str, demoted: bool) {
+        if !demoted {
+            self.demoted.remove(key);
+        } else if !self.demoted.contains_key(key) {
+            self.demoted.insert(key.to_string(), 0);
+        }
+    }
+
+    /// Return the oldest item of the key
+    pub fn peek(&self, key: &str) -> Option<&T> {
+        self.queues.get(key).and_then(VecDeque::front)
+    }
+
+    /// Return how many items are queued
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_if_keys_are_served_in_turns_keeping_their_order() {
+        let mut queue = FairQueue::new();
+        for item in 0..1000 {
+            queue.push("backlog", format!("backlog-{}", item));
+        }
+        queue.push("small", String::from("small-0"));
+        queue.push("small", String::from("small-1"));
+        queue.push("other", String::from("other-0"));
+
+        let served: Vec<String> = (0..6).filter_map(|_| queue.pop()).collect();
+        assert_eq!(served, vec!["backlog-0", "small-0", "other-0", "backlog-1", "small-1", "backlog-2"]);
+        assert_eq!(queue.len(), 997);
+
+        queue.push("small", String::from("small-2"));
+        assert_eq!(queue.pop().as_deref(), Some("backlog-3"));
+        assert_eq!(queue.pop().as_deref(), Some("small-2"));
+        queue.push("small", String::from("small-3"));
+        queue.push("small", String::from("small-4"));
+        queue.push("other", String::from("other-1"));
+        let mut removed = queue.remove_matching(|item| item == "small-3" || item.starts_with("other"));
+        removed.sort();
+        assert_eq!(removed, vec!["other-1", "small-3"]);
+        assert_eq!(queue.take("small"), vec!["small-4"]);
+        assert!(queue.take("other").is_empty());
+        assert_eq!((0..1000).filter_map(|_| queue.pop()).count(), 996);
+        assert!(queue.is_empty());
+    }
+
+    #[test]
+    fn test_if_demoted_keys_take_a_share_of_their_turns() {
+        let mut queue = FairQueue::new();
+        for item in 0..10 {
+            queue.push("slow", format!("slow-{}", item));
+            queue.push("fast", format!("fast-{}", item));
+        }
+        queue.set_demoted("slow", true);
+        let served: Vec<String> = (0..6).filter_map(|_| queue.pop()).collect();
+        assert_eq!(served, vec!["fast-0", "fast-1", "fast-2", "slow-0", "fast-3", "fast-4"]);
+        assert_eq!(queue.peek("slow").map(String::as_str), Some("slow-1"));
+
+        // it takes all of its turns when it is the only key with items
+        assert_eq!(queue.take("fast").len(), 5);
+        assert_eq!(queue.pop().as_deref(), Some("slow-1"));
+        assert_eq!(queue.pop().as_deref(), Some("slow-2"));
+
+        queue.set_demoted("slow", false);
+        queue.push("fast", String::from("fast-10"));
+        assert_eq!(queue.pop().as_deref(), Some("slow-3"));
+        assert_eq!(queue.pop().as_deref(), Some("fast-10"));
+    }
+
+    #[test]
+    fn test_if_keys_that_are_not_allowed_keep_their_turns() {
+        let mut queue = FairQueue::new();
+        for item in 0..3 {
+            queue.push("busy", format!("busy-{}", item));
+            queue.push("idle", format!("idle-{}", item));
+        }
+        assert_eq!(queue.pop_where(|key, _| key != "busy").as_deref(), Some("idle-0"));
+        assert_eq!(queue.pop_where(|key, _| key != "busy").as_deref(), Some("idle-1"));
+        // the busy key is served first once it is allowed again
+        assert_eq!(queue.pop().as_deref(), Some("busy-0"));
+        assert_eq!(queue.pop_where(|_, item| item.ends_with('2')).as_deref(), Some("idle-2"));
+        assert_eq!(queue.pop_where(|_, _| false), None);
+        assert_eq!(queue.len(), 2);
+        assert_eq!(queue.pop().as_deref(), Some("busy-1"));
+    }
+
+    #[test]
+    fn test_if_items_that_are_not_allowed_keep_their_place() {
+        let mut queue = FairQueue::new();
+        for item in ["a-0", "b-0", "a-1", "c-0"] {
+            queue.push("orders", item.to_string());
+        }
+        queue.push("billing", String::from("b-1"));
+        assert_eq!(queue.pop_first_where(|_, _| true, |item| !item.starts_with("a-")).as_deref(), Some("b-0"));
+        // the key is skipped when it is not allowed, whatever its items
+        assert_eq!(queue.pop_first_where(|key, _| key == "orders", |item| item.starts_with("b-")), None);
+        assert_eq!(queue.pop_first_where(|_, _| true, |item| item.starts_with('c')).as_deref(), Some("c-0"));
+        assert_eq!(queue.pop().as_deref(), Some("b-1"));
+        assert_eq!((queue.pop().as_deref(), queue.pop().as_deref()), (Some("a-0"), Some("a-1")));
+        assert!(queue.is_empty());
+    }
+}
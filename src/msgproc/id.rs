@@ -0,0 +1,162 @@
+use std::sync::Mutex;
+
+use time::OffsetDateTime;
+
+use crate::utils::random::{format_uuid, FastRng};
+
+/// The epoch of the Snowflake IDs, 2024-01-01T00:00:00Z in milliseconds
+const SNOWFLAKE_EPOCH_MS: u64 = 1_704_067_200_000;
+
+/// The node IDs of the Snowflake IDs have 10 bits
+pub const MAX_SNOWFLAKE_NODE_ID: u16 = 1023;
+
+/// Create the IDs of the published messages. The IDs are sorted by the time they were created, so
+/// the messages of a period are a range of IDs and sorting them by ID sorts them chronologically
+pub trait MessageIdGenerator: Send + Sync {
+    /// Return a new unique ID created at `now`
+    fn generate(&self, now: OffsetDateTime) -> String;
+}
+
+/// The generators of `msgproc.ids.generator`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IdGeneratorKind {
+    UuidV7,
+    Snowflake,
+}
+
+impl IdGeneratorKind {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            IdGeneratorKind::UuidV7 => "uuidv7",
+            IdGeneratorKind::Snowflake => "snowflake",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<IdGeneratorKind> {
+        match name {
+            "uuidv7" => Some(IdGeneratorKind::UuidV7),
+            "snowflake" => Some(IdGeneratorKind::Snowflake),
+            _ => None,
+        }
+    }
+}
+
+fn unix_millis(now: OffsetDateTime) -> u64 {
+    (now.unix_timestamp_nanos() / 1_000_000).max(0) as u64
+}
+
+/// Generate UUIDs version 7: the 48 bits of the milliseconds since the unix epoch, followed by a
+/// 12 bits counter, that keeps the IDs of the same millisecond in order, and random bits. The IDs
+/// never go back when the clock does
+pub struct UuidV7Generator {
+    /// The milliseconds and counter of the last ID, and the source of the random bits
+    state: Mutex<(u64, u16, FastRng)>,
+}
+
+impl UuidV7Generator {
+    pub fn new() -> UuidV7Generator {
+        UuidV7Generator { state: Mutex::new((0, 0, FastRng::new())) }
+    }
+}
+
+impl Default for UuidV7Generator {
+    fn default() -> Self {
+        UuidV7Generator::new()
+    }
+}
+
+impl MessageIdGenerator for UuidV7Generator {
+    fn generate(&self, now: OffsetDateTime) -> String {
+        let mut state = self.state.lock().unwrap();
+        let (last_millis, counter, rng) = &mut *state;
+        let millis = unix_millis(now);
+        if millis > *last_millis {
+            // started in the lower half, so the counter rarely overflows
+            *last_millis = millis;
+            *counter = (rng.next_u64() & 0x7ff) as u16;
+        } else if *counter < 0xfff {
+            *counter += 1;
+        } else {
+            // borrowed from the next millisecond to keep the order
+            *last_millis += 1;
+            *counter = 0;
+        }
+
+        let mut bytes = [0u8; 16];
+        bytes[..6].copy_from_slice(&last_millis.to_be_bytes()[2..]);
+        bytes[6..8].copy_from_slice(&(0x7000 | *counter).to_be_bytes());
+        bytes[8..].copy_from_slice(&rng.next_u64().to_be_bytes());
+        bytes[8] = (bytes[8] & 0x3f) | 0x80; // RFC 9562 variant
+        format_uuid(&bytes)
+    }
+}
+
+/// Generate Snowflake IDs: the 41 bits of the milliseconds since 2024-01-01, the 10 bits of the
+/// node ID and a 12 bits sequence of the millisecond. The IDs are written as 19 decimal digits, so
+/// they sort as strings like they sort as numbers. Each node of a cluster needs its own node ID
+pub struct SnowflakeGenerator {
+    node_id: u16,
+    /// The milliseconds and sequence of the last ID
+    state: Mutex<(u64, u16)>,
+}
+
+impl SnowflakeGenerator {
+    /// Generate the IDs of the node, whose ID is truncated to its 10 bits
+    pub fn new(node_id: u16) -> SnowflakeGenerator {
+        SnowflakeGenerator { node_id: node_id & MAX_SNOWFLAKE_NODE_ID, state: Mutex::new((0, 0)) }
+    }
+
+    pub fn node_id(&self) -> u16 {
+        self.node_id
+    }
+}
+
+impl MessageIdGenerator for SnowflakeGenerator {
+    fn generate(&self, now: OffsetDateTime) -> String {
+        let mut state = self.state.lock().unwrap();
+        let (last_millis, sequence) = &mut *state;
+        let millis = unix_millis(now).saturating_sub(SNOWFLAKE_EPOCH_MS);
+        if millis > *last_millis {
+            *last_millis = millis;
+            *sequence = 0;
+        } else if *sequence < 0xfff {
+            *sequence += 1;
+        } else {
+            *last_millis += 1;
+            *sequence = 0;
+        }
+        let id = ((*last_millis & 0x1ff_ffff_ffff) << 22) | (u64::from(self.node_id) << 12) | u64::from(*sequence);
+        format!("{:019}", id)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use time::Duration;
+
+    use super::*;
+
+    #[test]
+    fn test_if_ids_are_sorted_by_their_creation_time() {
+        let now = OffsetDateTime::from_unix_timestamp(1_704_067_200).unwrap() + Duration::days(30);
+        let uuids = UuidV7Generator::new();
+        let snowflakes = SnowflakeGenerator::new(7);
+        for generator in [&uuids as &dyn MessageIdGenerator, &snowflakes] {
+            let mut ids: Vec<String> = (0..5000).map(|_| generator.generate(now)).collect();
+            ids.push(generator.generate(now - Duration::seconds(1)));
+            ids.push(generator.generate(now + Duration::seconds(1)));
+            assert!(ids.windows(2).all(|pair| pair[0] < pair[1]), "{:?}", &ids[..3]);
+        }
+
+        let uuid = uuids.generate(now + Duration::seconds(2));
+        assert_eq!(uuid.len(), 36);
+        assert_eq!(&uuid[14..15], "7");
+        assert!(["8", "9", "a", "b"].contains(&&uuid[19..20]));
+        // the first 48 bits are the milliseconds
+        assert_eq!(u64::from_str_radix(&uuid[..13].replace('-', ""), 16).unwrap(), unix_millis(now + Duration::seconds(2)));
+
+        let snowflake: u64 = SnowflakeGenerator::new(1030).generate(now).parse().unwrap();
+        assert_eq!((snowflake >> 22, (snowflake >> 12) & 0x3ff, snowflake & 0xfff), (30 * 86_400_000, 6, 0));
+        assert_eq!(IdGeneratorKind::from_name("snowflake"), Some(IdGeneratorKind::Snowflake));
+    }
+}
@@ -0,0 +1,77 @@
+use std::collections::{BTreeMap, HashMap};
+
+use crate::{ctx::config::DatabaseConfigurations, utils::json::JsonValue};
+
+use super::{interceptor::{Interceptor, Rejection}, message::Message};
+
+/// Extract the values of the payload fields configured by `db.payloadIndex.<eventId>`, so messages
+/// can be searched by them, like finding "the webhook for order 12345"
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct PayloadIndexer {
+    fields_by_topic: HashMap<String, Vec<String>>,
+}
+
+impl PayloadIndexer {
+    /// Index the given fields of the topics. Nested fields are separated by dots, like `order.id`
+    pub fn new(fields_by_topic: HashMap<String, Vec<String>>) -> PayloadIndexer {
+        PayloadIndexer { fields_by_topic }
+    }
+
+    /// Create the indexer from `db.payloadIndex.`. Return None when no topic is indexed
+    pub fn from_configuration(conf: &DatabaseConfigurations) -> Option<PayloadIndexer> {
+        conf.payload_index.clone().map(PayloadIndexer::new)
+    }
+
+    /// Return the values of the indexed fields found in the message payload. Fields that are
+    /// missing or that are not a string, a number or a boolean are not indexed
+    pub fn index(&self, message: &Message) -> BTreeMap<String, String> {
+        let Some(fields) = self.fields_by_topic.get(message.topic()) else {
+            return BTreeMap::new();
+        };
+        let Ok(payload) = JsonValue::parse_bytes(&message.payload) else {
+            return BTreeMap::new();
+        };
+
+        fields.iter()
+            .filter_map(|field| {
+                let value = match payload.pointer(field)? {
+                    JsonValue::String(value) => value.clone(),
+                    value @ (JsonValue::Number(_) | JsonValue::Bool(_)) => value.to_string(),
+                    _ => return None,
+                };
+                Some((field.clone(), value))
+            })
+            .collect()
+    }
+}
+
+/// Indexing is one of the built-in interceptors. It never rejects a message
+impl Interceptor for PayloadIndexer {
+    fn intercept(&self, message: &mut Message) -> Result<(), Rejection> {
+        message.indexed_fields.extend(self.index(message));
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn message(event_id: &str, payload: &str) -> Message {
+        Message::new("a".to_string(), "r".to_string(), "s".to_string(), event_id.to_string(), payload.as_bytes().to_vec())
+    }
+
+    #[test]
+    fn test_if_configured_fields_are_indexed_by_topic() {
+        let fields = HashMap::from([(String::from("order.created"), vec![String::from("order.id"), String::from("customer.email"), String::from("items")])]);
+        let indexer = PayloadIndexer::new(fields);
+
+        let indexed = indexer.index(&message("order.created", r#"{"order": {"id": 12345}, "customer": {"email": "a@b.c"}, "items": [1]}"#));
+        assert_eq!(indexed.get("order.id").map(String::as_str), Some("12345"));
+        assert_eq!(indexed.get("customer.email").map(String::as_str), Some("a@b.c"));
+        assert!(!indexed.contains_key("items"));
+
+        assert!(indexer.index(&message("order.deleted", r#"{"order": {"id": 1}}"#)).is_empty());
+        assert!(indexer.index(&message("order.created", "not json")).is_empty());
+    }
+}
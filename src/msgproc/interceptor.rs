@@ -0,0 +1,140 @@
+use std::sync::Arc;
+
+use thiserror::Error;
+
+use crate::ctx::config::Configuration;
+
+use super::{index::PayloadIndexer, message::Message, schema::{SchemaLoadError, TopicSchemas}};
+
+/// Why an Interceptor rejected a published message. The message is not stored and the producer
+/// receives the reason
+#[derive(Debug, Clone, PartialEq, Error)]
+#[error("{reason}")]
+pub struct Rejection {
+    pub reason: String,
+    /// Each problem found in the message, like the JSON Schema violations of its payload
+    pub violations: Vec<String>,
+}
+
+impl Rejection {
+    pub fn new(reason: impl Into<String>) -> Rejection {
+        Rejection { reason: reason.into(), violations: vec![] }
+    }
+
+    /// Create a rejection listing the problems found in the message
+    pub fn with_violations(reason: impl Into<String>, violations: Vec<String>) -> Rejection {
+        Rejection { reason: reason.into(), violations }
+    }
+}
+
+/// Run on every published message before it is stored. An interceptor can validate the message,
+/// rejecting it, or change it, like enriching it or tagging it with its tenant
+pub trait Interceptor: Send + Sync {
+    /// Validate or change the message. Returning a Rejection stops the chain and drops the message
+    fn intercept(&self, message: &mut Message) -> Result<(), Rejection>;
+}
+
+/// The ordered list of interceptors applied on the publish path
+#[derive(Clone, Default)]
+pub struct InterceptorChain {
+    interceptors: Vec<Arc<dyn Interceptor>>,
+}
+
+impl InterceptorChain {
+    /// Create a chain without interceptors
+    pub fn new() -> InterceptorChain {
+        InterceptorChain::default()
+    }
+
+    /// Create a chain with the built-in interceptors enabled by the configuration, in this order:
+    /// `msgproc.interceptors.maxPayloadSize`, `msgproc.interceptors.schema.` and `db.payloadIndex.`
+    pub fn from_configuration(conf: &Configuration) -> Result<InterceptorChain, SchemaLoadError> {
+        let mut chain = InterceptorChain::new();
+        if let Some(max_size) = conf.messages_processor.max_payload_size {
+            chain.push(Arc::new(MaxPayloadSize(max_size)));
+        }
+        if let Some(schemas) = TopicSchemas::from_configuration(&conf.messages_processor)? {
+            chain.push(Arc::new(schemas));
+        }
+        if let Some(indexer) = PayloadIndexer::from_configuration(&conf.database) {
+            chain.push(Arc::new(indexer));
+        }
+        Ok(chain)
+    }
+
+    /// Add an interceptor at the end of the chain
+    pub fn push(&mut self, interceptor: Arc<dyn Interceptor>) {
+        self.interceptors.push(interceptor);
+    }
+
+    /// Run the interceptors in order, stopping at the first rejection
+    pub fn apply(&self, message: &mut Message) -> Result<(), Rejection> {
+        self.interceptors.iter().try_for_each(|interceptor| interceptor.intercept(message))
+    }
+}
+
+/// Reject the messages whose payload is larger than the given amount of bytes
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct MaxPayloadSize(pub usize);
+
+impl Interceptor for MaxPayloadSize {
+    fn intercept(&self, message: &mut Message) -> Result<(), Rejection> {
+        if message.payload.len() > self.0 {
+            return Err(Rejection::new(format!("the payload has {} bytes, more than the limit of {} bytes", message.payload.len(), self.0)));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::*;
+
+    struct TagTenant;
+
+    impl Interceptor for TagTenant {
+        fn intercept(&self, message: &mut Message) -> Result<(), Rejection> {
+            message.indexed_fields.insert(String::from("tenant"), message.service_id.clone());
+            Ok(())
+        }
+    }
+
+    struct RejectAll;
+
+    impl Interceptor for RejectAll {
+        fn intercept(&self, _: &mut Message) -> Result<(), Rejection> {
+            Err(Rejection::new("rejected"))
+        }
+    }
+
+    fn message(payload: &[u8]) -> Message {
+        Message::new("a".to_string(), "r".to_string(), "shop".to_string(), "e".to_string(), payload.to_vec())
+    }
+
+    #[test]
+    fn test_if_interceptors_run_in_order_until_a_rejection() {
+        let mut chain = InterceptorChain::new();
+        chain.push(Arc::new(MaxPayloadSize(4)));
+        chain.push(Arc::new(TagTenant));
+
+        let mut accepted = message(b"1234");
+        assert_eq!(chain.apply(&mut accepted), Ok(()));
+        assert_eq!(accepted.indexed_fields.get("tenant").map(String::as_str), Some("shop"));
+
+        let mut too_large = message(b"12345");
+        assert!(chain.apply(&mut too_large).is_err());
+        assert!(too_large.indexed_fields.is_empty());
+
+        chain.push(Arc::new(RejectAll));
+        assert_eq!(chain.apply(&mut message(b"")), Err(Rejection::new("rejected")));
+    }
+
+    #[test]
+    fn test_if_unreadable_schema_files_are_returned_as_errors() {
+        let mut conf = Configuration::default();
+        conf.messages_processor.topic_schemas = Some(HashMap::from([(String::from("orders"), String::from("/nonexistent/orders.schema.json"))]));
+        assert!(matches!(InterceptorChain::from_configuration(&conf), Err(SchemaLoadError::Io(path, _)) if path == "/nonexistent/orders.schema.json"));
+    }
+}
@@ -0,0 +1,152 @@
+use std::{collections::{BTreeMap, HashMap}, sync::Mutex, time::Duration};
+
+use time::OffsetDateTime;
+
+use crate::{log, utils::{json::JsonValue, log::Level, time::format_rfc3339}};
+
+/// How much the last attempt weighs in the delivery latency of a destination, the others decaying
+/// exponentially, so a few slow responses do not mark it as lagging
+const LATENCY_WEIGHT: f64 = 0.2;
+
+/// When a destination is lagging, set by the `msgproc.lagging.` configurations. A threshold that
+/// is not set is not checked
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct LagThresholds {
+    /// How long the oldest due message of the destination can wait for its attempt
+    pub backlog_age: Option<Duration>,
+    /// How long the responses of the destination can take, on average
+    pub latency: Option<Duration>,
+    /// If the lagging destinations take fewer turns in the processor queue, so they hold fewer
+    /// workers
+    pub demote: bool,
+}
+
+impl LagThresholds {
+    fn is_lagging(&self, backlog_age: Duration, latency: Duration) -> bool {
+        self.backlog_age.is_some_and(|threshold| backlog_age > threshold) || self.latency.is_some_and(|threshold| latency > threshold)
+    }
+}
+
+/// How far behind a destination is, as of its last attempt
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ConsumerLag {
+    /// How long its last attempt waited since the message was due. The messages of a destination
+    /// are sent in order, so it is the age of its backlog
+    pub backlog_age: Duration,
+    /// The average time its responses take
+    pub latency: Duration,
+    pub lagging: bool,
+    /// Since when it is lagging
+    pub lagging_since: Option<OffsetDateTime>,
+    pub updated_at: OffsetDateTime,
+}
+
+impl ConsumerLag {
+    pub fn to_json(&self, recipient_id: &str) -> JsonValue {
+        JsonValue::object()
+            .with("recipientId", recipient_id)
+            .with("lagging", self.lagging)
+            .with("backlogAgeMs", self.backlog_age.as_millis() as u64)
+            .with("latencyMs", self.latency.as_millis() as u64)
+            .with("laggingSince", self.lagging_since.map(format_rfc3339))
+            .with("updatedAt", format_rfc3339(self.updated_at))
+    }
+}
+
+/// A change of the lagging state of a destination, so the processor demotes or promotes it
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LagChange {
+    StartedLagging,
+    CaughtUp,
+}
+
+/// Track the backlog age and the delivery latency of each destination, marking the ones beyond the
+/// thresholds as lagging, so one slow receiver can be found before it degrades the others
+#[derive(Debug, Default)]
+pub struct LagMonitor {
+    thresholds: LagThresholds,
+    destinations: Mutex<HashMap<String, ConsumerLag>>,
+}
+
+impl LagMonitor {
+    pub fn new(thresholds: LagThresholds) -> LagMonitor {
+        LagMonitor { thresholds, destinations: Mutex::new(HashMap::new()) }
+    }
+
+    pub fn thresholds(&self) -> LagThresholds {
+        self.thresholds
+    }
+
+    /// Record an attempt of the destination made at `now`, that waited `backlog_age` since it was
+    /// due and whose response took `latency`. Return the change of its lagging state, if any
+    pub fn record(&self, recipient_id: &str, backlog_age: Duration, latency: Duration, now: OffsetDateTime) -> Option<LagChange> {
+        let mut destinations = self.destinations.lock().unwrap();
+        let lag = destinations.entry(recipient_id.to_string()).or_insert(ConsumerLag {
+            backlog_age,
+            latency,
+            lagging: false,
+            lagging_since: None,
+            updated_at: now,
+        });
+        lag.backlog_age = backlog_age;
+        lag.latency = lag.latency.mul_f64(1.0 - LATENCY_WEIGHT) + latency.mul_f64(LATENCY_WEIGHT);
+        lag.updated_at = now;
+
+        let lagging = self.thresholds.is_lagging(lag.backlog_age, lag.latency);
+        if lagging == lag.lagging {
+            return None;
+        }
+        lag.lagging = lagging;
+        if lagging {
+            lag.lagging_since = Some(now);
+            log!(Level::Warn, "The destination {} is lagging: its backlog is {}ms old and its responses take {}ms", recipient_id, lag.backlog_age.as_millis(), lag.latency.as_millis());
+            Some(LagChange::StartedLagging)
+        } else {
+            lag.lagging_since = None;
+            log!(Level::Info, "The destination {} caught up", recipient_id);
+            Some(LagChange::CaughtUp)
+        }
+    }
+
+    /// Return the lag of the destination, None when it had no attempt yet
+    pub fn get(&self, recipient_id: &str) -> Option<ConsumerLag> {
+        self.destinations.lock().unwrap().get(recipient_id).copied()
+    }
+
+    /// Return the lag of each destination that had an attempt, by destination
+    pub fn list(&self) -> BTreeMap<String, ConsumerLag> {
+        self.destinations.lock().unwrap().iter().map(|(recipient_id, lag)| (recipient_id.clone(), *lag)).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_if_destinations_beyond_the_thresholds_are_lagging_until_they_catch_up() {
+        let now = OffsetDateTime::from_unix_timestamp(1_760_000_000).unwrap();
+        let thresholds = LagThresholds { backlog_age: Some(Duration::from_secs(60)), latency: Some(Duration::from_secs(2)), demote: false };
+        let monitor = LagMonitor::new(thresholds);
+        let ms = Duration::from_millis;
+
+        assert_eq!(monitor.record("slow", ms(10), ms(100), now), None);
+        assert_eq!(monitor.record("slow", Duration::from_secs(90), ms(100), now), Some(LagChange::StartedLagging));
+        assert_eq!(monitor.get("slow").unwrap().lagging_since, Some(now));
+        assert_eq!(monitor.record("slow", ms(10), ms(100), now), Some(LagChange::CaughtUp));
+
+        // a single slow response is averaged with the previous ones
+        assert_eq!(monitor.record("slow", ms(10), Duration::from_secs(5), now), None);
+        let mut change = None;
+        for _ in 0..10 {
+            change = change.or(monitor.record("slow", ms(10), Duration::from_secs(5), now));
+        }
+        assert_eq!(change, Some(LagChange::StartedLagging));
+        assert!(monitor.get("slow").unwrap().latency > Duration::from_secs(2));
+
+        let unchecked = LagMonitor::default();
+        assert_eq!(unchecked.record("slow", Duration::from_secs(3600), Duration::from_secs(30), now), None);
+        assert_eq!(monitor.list().len(), 1);
+        assert_eq!(monitor.get("other"), None);
+    }
+}
@@ -0,0 +1,165 @@
+use std::fmt::Display;
+
+use thiserror::Error;
+
+use crate::db::StoreError;
+
+use super::message::{Message, MessageStatus};
+
+/// The states of a message along its delivery. The store keeps only its MessageStatus, so the
+/// scheduled and the retrying messages are pending, and the cancelled and the expired ones are dead
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum MessageState {
+    /// The message was published and is not stored yet
+    Accepted,
+    /// The message is waiting for its first attempt
+    Scheduled,
+    /// The message is being sent to the recipient
+    InFlight,
+    /// The message was sent to the recipient
+    Delivered,
+    /// An attempt failed and the message is waiting for the next one
+    Retrying,
+    /// The message exhausted its attempts or failed with an error that is not retried
+    Dead,
+    /// The message was cancelled before it was delivered
+    Cancelled,
+    /// The message was not delivered before it expired
+    Expired,
+}
+
+/// A change of the state of a message that is not in the lifecycle, like sending a delivered
+/// message again
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Error)]
+#[error("A message can not go from {from} to {to}")]
+pub struct TransitionError {
+    pub from: MessageState,
+    pub to: MessageState,
+}
+
+/// Why a message could not be forced into a state by an operator
+#[derive(Debug, Error)]
+pub enum ForceTransitionError {
+    #[error("Message {0} was not found in the store")]
+    MessageNotFound(String),
+    #[error("A message can not be forced to {0}, only its attempts make it")]
+    NotForceable(MessageState),
+    #[error("An attempt of message {0} is being sent, wait for it to finish")]
+    AttemptRunning(String),
+    #[error(transparent)]
+    Store(#[from] StoreError),
+}
+
+impl MessageState {
+    /// Return the name of the state used in the APIs
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            MessageState::Accepted => "accepted",
+            MessageState::Scheduled => "scheduled",
+            MessageState::InFlight => "inFlight",
+            MessageState::Delivered => "delivered",
+            MessageState::Retrying => "retrying",
+            MessageState::Dead => "dead",
+            MessageState::Cancelled => "cancelled",
+            MessageState::Expired => "expired",
+        }
+    }
+
+    /// Parse the name of the state used in the APIs
+    pub fn from_name(name: &str) -> Option<MessageState> {
+        match name {
+            "accepted" => Some(MessageState::Accepted),
+            "scheduled" => Some(MessageState::Scheduled),
+            "inFlight" => Some(MessageState::InFlight),
+            "delivered" => Some(MessageState::Delivered),
+            "retrying" => Some(MessageState::Retrying),
+            "dead" => Some(MessageState::Dead),
+            "cancelled" => Some(MessageState::Cancelled),
+            "expired" => Some(MessageState::Expired),
+            _ => None,
+        }
+    }
+
+    /// Return the state of a stored message. A dead message is told apart from a cancelled or an
+    /// expired one only by its annotations, so it is always dead
+    pub fn of(message: &Message) -> MessageState {
+        match message.status {
+            MessageStatus::Pending => MessageState::waiting(message.attempts),
+            MessageStatus::InFlight => MessageState::InFlight,
+            MessageStatus::Delivered => MessageState::Delivered,
+            MessageStatus::Dead => MessageState::Dead,
+        }
+    }
+
+    /// Return the state of a message waiting for its next attempt after `attempts` attempts
+    pub fn waiting(attempts: u16) -> MessageState {
+        if attempts == 0 { MessageState::Scheduled } else { MessageState::Retrying }
+    }
+
+    /// Return the status kept by the store for the state
+    pub fn status(&self) -> MessageStatus {
+        match self {
+            MessageState::Accepted | MessageState::Scheduled | MessageState::Retrying => MessageStatus::Pending,
+            MessageState::InFlight => MessageStatus::InFlight,
+            MessageState::Delivered => MessageStatus::Delivered,
+            MessageState::Dead | MessageState::Cancelled | MessageState::Expired => MessageStatus::Dead,
+        }
+    }
+
+    /// Return if an operator can force a message into the state. Only an attempt makes a message
+    /// in flight, and only a publish accepts it
+    pub fn is_forceable(&self) -> bool {
+        !matches!(self, MessageState::Accepted | MessageState::InFlight)
+    }
+
+    /// Return if the message does not leave the state anymore
+    pub fn is_final(&self) -> bool {
+        matches!(self, MessageState::Delivered | MessageState::Dead | MessageState::Cancelled | MessageState::Expired)
+    }
+
+    /// Return if the lifecycle goes from this state to the other. Waiting messages can be
+    /// postponed, staying in their state, and an interrupted attempt goes back to waiting
+    pub fn can_transition_to(&self, to: MessageState) -> bool {
+        use MessageState::*;
+        matches!(
+            (self, to),
+            (Accepted, Scheduled | Cancelled | Expired)
+                | (Scheduled, Scheduled | InFlight | Cancelled | Expired)
+                | (Retrying, Retrying | InFlight | Cancelled | Expired)
+                | (InFlight, Delivered | Retrying | Dead | Scheduled)
+        )
+    }
+
+    /// Return the state the message goes to, or the error when the lifecycle does not allow it
+    pub fn transition(self, to: MessageState) -> Result<MessageState, TransitionError> {
+        if self.can_transition_to(to) { Ok(to) } else { Err(TransitionError { from: self, to }) }
+    }
+}
+
+impl Display for MessageState {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_if_only_the_transitions_of_the_lifecycle_are_allowed() {
+        use MessageState::*;
+        for (from, to) in [(Accepted, Scheduled), (Scheduled, InFlight), (InFlight, Retrying), (Retrying, Retrying), (Retrying, InFlight), (InFlight, Delivered), (Retrying, Cancelled), (InFlight, Scheduled)] {
+            assert_eq!(from.transition(to), Ok(to));
+        }
+        assert_eq!(Delivered.transition(InFlight), Err(TransitionError { from: Delivered, to: InFlight }));
+        assert!(!Scheduled.can_transition_to(Delivered));
+        assert!(!InFlight.can_transition_to(Cancelled));
+        assert!([Delivered, Dead, Cancelled, Expired].iter().all(|state| state.is_final() && !state.can_transition_to(Scheduled)));
+        assert_eq!(Dead.transition(Retrying).unwrap_err().to_string(), "A message can not go from dead to retrying");
+
+        assert_eq!((MessageState::waiting(0), MessageState::waiting(3)), (Scheduled, Retrying));
+        assert_eq!((Cancelled.status(), Retrying.status()), (MessageStatus::Dead, MessageStatus::Pending));
+        assert!(["accepted", "inFlight", "expired"].iter().all(|name| MessageState::from_name(name).unwrap().as_str() == *name));
+    }
+}
@@ -0,0 +1,285 @@
+use std::{collections::BTreeMap, fmt::Display};
+
+use time::OffsetDateTime;
+
+use crate::utils::clock::{Clock, SystemClock};
+
+use super::retry::RetryPolicy;
+
+/// Store the status of a message in the delivery pipeline
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum MessageStatus {
+    /// The message is waiting to be sent to the recipient
+    Pending,
+    /// The message is being sent to the recipient by a worker
+    InFlight,
+    /// The message was successfully sent to the recipient
+    Delivered,
+    /// The message exhausted all its attempts and will not be sent anymore
+    Dead,
+}
+
+impl MessageStatus {
+    /// Return the name of the status used in the APIs
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            MessageStatus::Pending => "pending",
+            MessageStatus::InFlight => "inFlight",
+            MessageStatus::Delivered => "delivered",
+            MessageStatus::Dead => "dead",
+        }
+    }
+
+    /// Parse the name of the status used in the APIs
+    pub fn from_name(name: &str) -> Option<MessageStatus> {
+        match name {
+            "pending" => Some(MessageStatus::Pending),
+            "inFlight" => Some(MessageStatus::InFlight),
+            "delivered" => Some(MessageStatus::Delivered),
+            "dead" => Some(MessageStatus::Dead),
+            _ => None,
+        }
+    }
+}
+
+/// A message sent by a client that should be delivered to a recipient. Messages are grouped in
+/// topics by their event ID inside the namespace of the service that produced them
+#[derive(Debug, Clone, PartialEq)]
+pub struct Message {
+    /// The unique identifier of the message
+    pub id: String,
+    /// The ID of the recipient that will receive the message
+    pub recipient_id: String,
+    /// The ID of the service that generated the message
+    pub service_id: String,
+    /// The ID of the event that the message represents
+    pub event_id: String,
+    /// The content that will be sent to the recipient
+    pub payload: Vec<u8>,
+    /// The ID given to the message by its producer. Used to drop duplicated publishes of the same
+    /// topic inside the `msgproc.dedup.window`
+    pub producer_message_id: Option<String>,
+    /// The ID of the dead message that this message is a replay of
+    pub replayed_from: Option<String>,
+    /// The `X-Request-ID` of the client API call that published the message
+    pub request_id: Option<String>,
+    /// The position of the message in its topic, starting at 1 and increasing by one for each
+    /// message published in the topic. It is set when the message is published
+    pub sequence: Option<u64>,
+    /// The values of the payload fields indexed for search. See `db.payloadIndex.<eventId>`
+    pub indexed_fields: BTreeMap<String, String>,
+    /// Key-value metadata set by the producer, separate from the payload. They are sent to the
+    /// recipient as `X-Angler-Attr-<key>` headers and can be used to filter and search messages
+    pub attributes: BTreeMap<String, String>,
+    /// Define when the message should be sent again if an attempt fails. It is the effective
+    /// policy, after the requested one was clamped by the `retryPolicy.limit.` configurations
+    pub retry_policy: RetryPolicy,
+    /// The retry policy sent by the producer, before it was clamped. None when the producer used
+    /// the defaults
+    pub requested_retry_policy: Option<RetryPolicy>,
+    /// The current status of the message
+    pub status: MessageStatus,
+    /// How many attempts were made to send the message
+    pub attempts: u16,
+    /// When the message was received by Angler
+    pub created_at: OffsetDateTime,
+    /// When the next attempt to send the message should happen
+    pub next_attempt_at: Option<OffsetDateTime>,
+    /// The notes attached to the message by operators, the oldest first
+    pub annotations: Vec<Annotation>,
+}
+
+impl Message {
+    /// Create a new pending message received now, according to the SystemClock, that should be sent
+    /// immediately and is never retried
+    pub fn new(id: String, recipient_id: String, service_id: String, event_id: String, payload: Vec<u8>) -> Message {
+        Message::new_at(id, recipient_id, service_id, event_id, payload, SystemClock.now())
+    }
+
+    /// Create a new pending message received at `now` that should be sent immediately and is never retried
+    pub fn new_at(id: String, recipient_id: String, service_id: String, event_id: String, payload: Vec<u8>, now: OffsetDateTime) -> Message {
+        Message {
+            id,
+            recipient_id,
+            service_id,
+            event_id,
+            payload,
+            producer_message_id: None,
+            replayed_from: None,
+            request_id: None,
+            sequence: None,
+            indexed_fields: BTreeMap::new(),
+            attributes: BTreeMap::new(),
+            retry_policy: RetryPolicy::default(),
+            requested_retry_policy: None,
+            status: MessageStatus::Pending,
+            attempts: 0,
+            created_at: now,
+            next_attempt_at: Some(now),
+            annotations: Vec::new(),
+        }
+    }
+
+    /// Return the namespace of the message, the ID of the service that produced it
+    pub fn namespace(&self) -> &str {
+        &self.service_id
+    }
+
+    /// Return the topic of the message, its event ID
+    pub fn topic(&self) -> &str {
+        &self.event_id
+    }
+}
+
+/// A note attached to a message by an operator through the admin API, like "customer notified"
+/// or "receiver bug #4521", to coordinate the follow-up of an incident
+#[derive(Debug, Clone, PartialEq)]
+pub struct Annotation {
+    pub note: String,
+    /// Who wrote the note, when the operator told it
+    pub author: Option<String>,
+    pub created_at: OffsetDateTime,
+}
+
+/// The result of a single attempt to send a message
+#[derive(Debug, Clone, PartialEq)]
+pub enum AttemptOutcome {
+    /// The recipient accepted the message
+    Delivered,
+    /// The attempt failed with the given error
+    Failed(DeliveryError),
+    /// The message was not sent because its attributes do not match the attribute filter of the
+    /// destination. It finishes as delivered, as there is nothing left to send
+    Filtered,
+    /// The receiver answered `202` to a destination with async ack, so the attempt finishes when
+    /// it confirms the message, or fails if it does not confirm it before the deadline
+    Accepted { confirm_before: OffsetDateTime },
+}
+
+/// The class of a failed attempt, used to filter the dead messages and to group the failures
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum DeliveryErrorClass {
+    /// The recipient has no destination registered or its URL is invalid
+    NoDestination,
+    /// The host of the destination could not be resolved
+    Dns,
+    /// The connection was not established before the timeout
+    ConnectTimeout,
+    /// The connection was refused, reset or closed
+    Connection,
+    /// The TLS handshake failed. Destinations only use plain HTTP for now, so it is not produced yet
+    Tls,
+    /// The response was not received before the timeout
+    ResponseTimeout,
+    /// The response is not a valid HTTP response
+    InvalidResponse,
+    /// The destination answered with a redirect that was not followed
+    Redirect,
+    /// The destination answered `413`
+    PayloadTooLarge,
+    /// The destination answered with any other 4xx status
+    Http4xx,
+    /// The destination answered with a 5xx status
+    Http5xx,
+    /// The attempt was not made because the circuit of the destination is open. There is no
+    /// circuit breaker yet, so it is not produced yet
+    CircuitOpen,
+    /// The consumer of a pull destination nacked the message, or the receiver of a destination
+    /// with async ack confirmed it as failed
+    Nacked,
+    Other,
+}
+
+impl DeliveryErrorClass {
+    pub const ALL: [DeliveryErrorClass; 14] = [
+        DeliveryErrorClass::NoDestination,
+        DeliveryErrorClass::Dns,
+        DeliveryErrorClass::ConnectTimeout,
+        DeliveryErrorClass::Connection,
+        DeliveryErrorClass::Tls,
+        DeliveryErrorClass::ResponseTimeout,
+        DeliveryErrorClass::InvalidResponse,
+        DeliveryErrorClass::Redirect,
+        DeliveryErrorClass::PayloadTooLarge,
+        DeliveryErrorClass::Http4xx,
+        DeliveryErrorClass::Http5xx,
+        DeliveryErrorClass::CircuitOpen,
+        DeliveryErrorClass::Nacked,
+        DeliveryErrorClass::Other,
+    ];
+
+    /// Return the name of the class used in the APIs
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            DeliveryErrorClass::NoDestination => "noDestination",
+            DeliveryErrorClass::Dns => "dns",
+            DeliveryErrorClass::ConnectTimeout => "connectTimeout",
+            DeliveryErrorClass::Connection => "connection",
+            DeliveryErrorClass::Tls => "tls",
+            DeliveryErrorClass::ResponseTimeout => "responseTimeout",
+            DeliveryErrorClass::InvalidResponse => "invalidResponse",
+            DeliveryErrorClass::Redirect => "redirect",
+            DeliveryErrorClass::PayloadTooLarge => "payloadTooLarge",
+            DeliveryErrorClass::Http4xx => "http4xx",
+            DeliveryErrorClass::Http5xx => "http5xx",
+            DeliveryErrorClass::CircuitOpen => "circuitOpen",
+            DeliveryErrorClass::Nacked => "nacked",
+            DeliveryErrorClass::Other => "other",
+        }
+    }
+
+    /// Parse the name of the class used in the APIs
+    pub fn from_name(name: &str) -> Option<DeliveryErrorClass> {
+        DeliveryErrorClass::ALL.into_iter().find(|class| class.as_str() == name)
+    }
+
+    /// Return the class of a response status that is not a success
+    pub fn from_status(status: u16) -> DeliveryErrorClass {
+        match status {
+            300..=399 => DeliveryErrorClass::Redirect,
+            413 => DeliveryErrorClass::PayloadTooLarge,
+            400..=499 => DeliveryErrorClass::Http4xx,
+            500..=599 => DeliveryErrorClass::Http5xx,
+            _ => DeliveryErrorClass::Other,
+        }
+    }
+}
+
+/// Why an attempt failed: its class and a message for the operators
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DeliveryError {
+    pub class: DeliveryErrorClass,
+    pub message: String,
+    /// The status of the response, when the destination answered
+    pub status: Option<u16>,
+}
+
+impl DeliveryError {
+    pub fn new(class: DeliveryErrorClass, message: impl Into<String>) -> DeliveryError {
+        DeliveryError { class, message: message.into(), status: None }
+    }
+
+    /// Create the error of a response that is not a success
+    pub fn from_response(status: u16, message: impl Into<String>) -> DeliveryError {
+        DeliveryError { class: DeliveryErrorClass::from_status(status), message: message.into(), status: Some(status) }
+    }
+}
+
+impl Display for DeliveryError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.message)
+    }
+}
+
+/// Record of a single attempt to send a message to its recipient
+#[derive(Debug, Clone, PartialEq)]
+pub struct AttemptRecord {
+    /// The ID of the message that was sent
+    pub message_id: String,
+    /// The number of the attempt, starting at 1
+    pub attempt: u16,
+    /// When the attempt finished
+    pub finished_at: OffsetDateTime,
+    /// The outcome of the attempt
+    pub outcome: AttemptOutcome,
+}
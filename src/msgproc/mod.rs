@@ -0,0 +1,24 @@
+pub mod ack;
+pub mod bulk;
+pub mod canary;
+pub mod capture;
+pub mod concurrency;
+pub mod delivery;
+pub mod destination;
+pub mod fair;
+pub mod id;
+pub mod index;
+pub mod interceptor;
+pub mod lag;
+pub mod lifecycle;
+pub mod message;
+pub mod notify;
+pub mod pipeline;
+pub mod processor;
+pub mod pull;
+pub mod replay;
+pub mod retry;
+pub mod sse;
+pub mod schema;
+pub mod topic;
+pub mod window;
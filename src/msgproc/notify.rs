@@ -0,0 +1,160 @@
+use std::collections::{BTreeMap, HashMap};
+
+use time::OffsetDateTime;
+
+use crate::{
+    ctx::config::{Configuration, RetryPolicyConfiguration},
+    utils::{json::JsonValue, time::format_rfc3339},
+};
+
+use super::{lifecycle::MessageState, message::Message, retry::RetryPolicy};
+
+/// The `eventId` of the death notifications when `msgproc.deathNotifications.eventId` is not set
+pub const DEFAULT_DEATH_NOTIFICATIONS_EVENT_ID: &str = "dlq-events";
+
+/// The attribute of a death notification with the state the message died in, so a destination
+/// can filter them by `attributeFilter`
+pub const DEATH_STATE_ATTRIBUTE: &str = "state";
+
+/// A message that died, without another attempt
+#[derive(Debug, Clone, PartialEq)]
+pub struct DeadMessage {
+    pub message: Message,
+    /// `Dead` or `Expired`
+    pub state: MessageState,
+    /// Why it died: the failure of its last attempt or the reason of the operator that forced it
+    pub reason: Option<String>,
+    pub died_at: OffsetDateTime,
+}
+
+/// Notify the producers of the messages that die or expire, so they can compensate them. Each
+/// notification is a message of the same `serviceId`, with the `eventId` of the notifications,
+/// sent to the destination of the producer of the namespace like any other message: retried,
+/// kept in the store and pulled or streamed when the destination is not `push`. The messages have
+/// no TTL, so nothing expires them on its own: the `Expired` deaths are only the ones forced by
+/// `POST /admin/messages/{id}/transition`
+#[derive(Debug, Clone)]
+pub struct DeathNotifications {
+    event_id: String,
+    /// The recipient of the notifications of the namespaces without their own
+    recipient_id: Option<String>,
+    /// The recipient of the notifications of each namespace, by its serviceId
+    namespaces: HashMap<String, String>,
+    retry_configuration: RetryPolicyConfiguration,
+}
+
+impl DeathNotifications {
+    /// Notify the deaths of every namespace to the recipient
+    pub fn new(recipient_id: &str) -> DeathNotifications {
+        DeathNotifications {
+            event_id: DEFAULT_DEATH_NOTIFICATIONS_EVENT_ID.to_string(),
+            recipient_id: Some(recipient_id.to_string()),
+            namespaces: HashMap::new(),
+            retry_configuration: Configuration::new().retry_policy,
+        }
+    }
+
+    /// Create the notifications of the `msgproc.deathNotifications.` configurations, None when no
+    /// recipient is set
+    pub fn from_configuration(conf: &Configuration) -> Option<DeathNotifications> {
+        let processor = &conf.messages_processor;
+        let namespaces = processor.death_notifications_namespaces.clone().unwrap_or_default();
+        if processor.death_notifications_recipient_id.is_none() && namespaces.is_empty() {
+            return None;
+        }
+        Some(DeathNotifications {
+            event_id: processor.death_notifications_event_id.clone().unwrap_or_else(|| DEFAULT_DEATH_NOTIFICATIONS_EVENT_ID.to_string()),
+            recipient_id: processor.death_notifications_recipient_id.clone(),
+            namespaces,
+            retry_configuration: conf.retry_policy.clone(),
+        })
+    }
+
+    /// Notify the deaths of the namespace to its own recipient
+    pub fn with_namespace(mut self, service_id: &str, recipient_id: &str) -> DeathNotifications {
+        self.namespaces.insert(service_id.to_string(), recipient_id.to_string());
+        self
+    }
+
+    /// Publish the notifications with the `eventId` instead of `dlq-events`
+    pub fn with_event_id(mut self, event_id: &str) -> DeathNotifications {
+        self.event_id = event_id.to_string();
+        self
+    }
+
+    /// Retry the notifications by the `retryPolicy.` configurations of their namespace
+    pub fn with_retry_configuration(mut self, retry_configuration: RetryPolicyConfiguration) -> DeathNotifications {
+        self.retry_configuration = retry_configuration;
+        self
+    }
+
+    pub fn event_id(&self) -> &str {
+        &self.event_id
+    }
+
+    /// Return the recipient of the notifications of the namespace, None when they are not notified
+    pub fn recipient_of(&self, service_id: &str) -> Option<&str> {
+        self.namespaces.get(service_id).or(self.recipient_id.as_ref()).map(String::as_str)
+    }
+
+    /// Return the notification of the dead message with the ID, None when its namespace is not
+    /// notified or it is a notification itself, so a notification that dies is not notified again
+    pub fn notification(&self, id: String, dead: &DeadMessage) -> Option<Message> {
+        let message = &dead.message;
+        if message.event_id == self.event_id {
+            return None;
+        }
+        let recipient_id = self.recipient_of(&message.service_id)?;
+        let payload = death_notification_payload(dead).to_string().into_bytes();
+        let mut notification = Message::new_at(id, recipient_id.to_string(), message.service_id.clone(), self.event_id.clone(), payload, dead.died_at);
+        notification.attributes = BTreeMap::from([(DEATH_STATE_ATTRIBUTE.to_string(), dead.state.as_str().to_string())]);
+        notification.retry_policy = match self.retry_configuration.for_namespace(&message.service_id) {
+            Some(limits) => RetryPolicy::from_configuration(&limits).clamp(&limits),
+            None => RetryPolicy::from_configuration(&self.retry_configuration).clamp(&self.retry_configuration),
+        };
+        Some(notification)
+    }
+}
+
+/// Return the payload of the notification of the dead message
+pub fn death_notification_payload(dead: &DeadMessage) -> JsonValue {
+    let message = &dead.message;
+    JsonValue::object()
+        .with("messageId", message.id.as_str())
+        .with("recipientId", message.recipient_id.as_str())
+        .with("serviceId", message.service_id.as_str())
+        .with("eventId", message.event_id.as_str())
+        .with("producerMessageId", message.producer_message_id.as_deref())
+        .with("state", dead.state.as_str())
+        .with("reason", dead.reason.as_deref())
+        .with("attempts", message.attempts)
+        .with("createdAt", format_rfc3339(message.created_at))
+        .with("diedAt", format_rfc3339(dead.died_at))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::utils::time::parse_rfc3339;
+
+    use super::*;
+
+    #[test]
+    fn test_if_the_notification_is_sent_to_the_recipient_of_the_namespace() {
+        let died_at = parse_rfc3339("2024-05-30T12:00:00Z").unwrap();
+        let mut message = Message::new_at(String::from("m-1"), String::from("orders"), String::from("billing"), String::from("invoice.paid"), b"{}".to_vec(), died_at);
+        message.attempts = 3;
+        let dead = DeadMessage { message, state: MessageState::Dead, reason: Some(String::from("HTTP 500")), died_at };
+        let notifications = DeathNotifications::new("producers").with_namespace("billing", "billing-compensation");
+
+        let notification = notifications.notification(String::from("n-1"), &dead).unwrap();
+        assert_eq!((notification.recipient_id.as_str(), notification.service_id.as_str(), notification.event_id.as_str()), ("billing-compensation", "billing", "dlq-events"));
+        assert_eq!(notification.attributes.get("state").map(String::as_str), Some("dead"));
+        assert_eq!(String::from_utf8(notification.payload).unwrap(), r#"{"attempts":3,"createdAt":"2024-05-30T12:00:00.000Z","diedAt":"2024-05-30T12:00:00.000Z","eventId":"invoice.paid","messageId":"m-1","producerMessageId":null,"reason":"HTTP 500","recipientId":"orders","serviceId":"billing","state":"dead"}"#);
+        assert_eq!(notifications.recipient_of("shop"), Some("producers"));
+
+        // the notifications that die are not notified
+        let dead_notification = DeadMessage { message: notifications.notification(String::from("n-2"), &dead).unwrap(), ..dead.clone() };
+        assert!(notifications.notification(String::from("n-3"), &dead_notification).is_none());
+        assert!(DeathNotifications::from_configuration(&Configuration::new()).is_none());
+    }
+}
@@ -0,0 +1,131 @@
+use std::{
+    collections::BTreeMap,
+    sync::{atomic::{AtomicU64, Ordering}, Mutex},
+    time::Duration,
+};
+
+/// The upper bounds, in seconds, of the buckets of the stage latency histograms
+pub const STAGE_LATENCY_BUCKETS: [f64; 12] = [0.0005, 0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 5.0];
+
+/// The worker label of the batches written by the BatchedStoreWriter, that persists the outcomes
+/// submitted by all the workers
+pub const STORE_WRITER: &str = "storeWriter";
+
+/// A step of an attempt in the MessageProcessor
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum PipelineStage {
+    /// Taking the due message and marking it in flight
+    Claim,
+    /// Turning the message into the request of its destination
+    Transform,
+    /// Sending the request and waiting for the response
+    Deliver,
+    /// Recording the outcome and scheduling the retry
+    Persist,
+}
+
+impl PipelineStage {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            PipelineStage::Claim => "claim",
+            PipelineStage::Transform => "transform",
+            PipelineStage::Deliver => "deliver",
+            PipelineStage::Persist => "persist",
+        }
+    }
+}
+
+/// How much each of the stages of an attempt took inside the Deliverer, when it can tell them apart
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct StageTimings {
+    pub transform: Option<Duration>,
+}
+
+/// The latencies of a stage, counted in the STAGE_LATENCY_BUCKETS
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct LatencyHistogram {
+    /// How many latencies fell in each bucket, the last one for the ones above every bound
+    buckets: [u64; STAGE_LATENCY_BUCKETS.len() + 1],
+    pub count: u64,
+    pub sum: Duration,
+}
+
+impl LatencyHistogram {
+    pub fn record(&mut self, elapsed: Duration) {
+        let seconds = elapsed.as_secs_f64();
+        let bucket = STAGE_LATENCY_BUCKETS.iter().position(|bound| seconds <= *bound).unwrap_or(STAGE_LATENCY_BUCKETS.len());
+        self.buckets[bucket] += 1;
+        self.count += 1;
+        self.sum += elapsed;
+    }
+
+    /// Return how many latencies were up to each bound, like the `le` buckets of Prometheus
+    pub fn cumulative(&self) -> Vec<(f64, u64)> {
+        let mut total = 0;
+        STAGE_LATENCY_BUCKETS.iter().zip(self.buckets).map(|(bound, count)| {
+            total += count;
+            (*bound, total)
+        }).collect()
+    }
+}
+
+/// The latencies of the stages of the attempts made by each worker of a MessageProcessor and the
+/// count of the outcomes waiting to be persisted, so a stall can be told to be in the store, in
+/// the transformation or in the network
+#[derive(Debug, Default)]
+pub struct PipelineMetrics {
+    /// The latencies of each stage, by worker and stage
+    stages: Mutex<BTreeMap<(String, PipelineStage), LatencyHistogram>>,
+    /// How many writes were submitted to the BatchedStoreWriter and not persisted yet
+    pending_writes: AtomicU64,
+}
+
+impl PipelineMetrics {
+    pub fn record(&self, worker: &str, stage: PipelineStage, elapsed: Duration) {
+        self.stages.lock().unwrap().entry((worker.to_string(), stage)).or_default().record(elapsed);
+    }
+
+    /// Return the latencies of each stage, by worker and stage. Stages that were never timed by a
+    /// worker are not listed
+    pub fn histograms(&self) -> BTreeMap<(String, PipelineStage), LatencyHistogram> {
+        self.stages.lock().unwrap().clone()
+    }
+
+    pub fn pending_writes(&self) -> u64 {
+        self.pending_writes.load(Ordering::Relaxed)
+    }
+
+    pub(crate) fn write_submitted(&self) {
+        self.pending_writes.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn writes_finished(&self, count: usize) {
+        self.pending_writes.fetch_sub(count as u64, Ordering::Relaxed);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_if_latencies_are_counted_in_cumulative_buckets() {
+        let metrics = PipelineMetrics::default();
+        metrics.record("0", PipelineStage::Deliver, Duration::from_millis(3));
+        metrics.record("0", PipelineStage::Deliver, Duration::from_millis(40));
+        metrics.record("0", PipelineStage::Deliver, Duration::from_secs(30));
+        metrics.record("1", PipelineStage::Claim, Duration::from_micros(10));
+
+        let histograms = metrics.histograms();
+        let deliver = &histograms[&(String::from("0"), PipelineStage::Deliver)];
+        assert_eq!((deliver.count, deliver.sum), (3, Duration::from_millis(30_043)));
+        let cumulative = deliver.cumulative();
+        assert_eq!(cumulative[2], (0.0025, 0));
+        assert_eq!(cumulative[3], (0.005, 1));
+        assert_eq!(cumulative[6], (0.05, 2));
+        // the latency above every bound is only in the count
+        assert_eq!(cumulative.last(), Some(&(5.0, 2)));
+        assert_eq!(histograms[&(String::from("1"), PipelineStage::Claim)].cumulative()[0], (0.0005, 1));
+        assert_eq!(histograms.len(), 2);
+    }
+}
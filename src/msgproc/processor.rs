@@ -0,0 +1,1994 @@
+use std::{
+    cmp::{Ordering as CmpOrdering, Reverse},
+    collections::{BTreeMap, BTreeSet, BinaryHeap, HashMap, HashSet},
+    sync::{atomic::{AtomicBool, AtomicU64, Ordering}, mpsc::{self, Sender}, Arc, Condvar, Mutex, RwLock, Weak},
+    thread::{self, JoinHandle},
+    time::{Duration as StdDuration, Instant},
+};
+
+use time::{Duration, OffsetDateTime};
+
+use crate::{
+    cluster::features::local_node_id,
+    ctx::config::Configuration,
+    db::{batch::{BatchConfiguration, BatchedStoreWriter}, MessageQuery, MessageStore, StoreError, StoreWrite},
+    log,
+    utils::{clock::{monotonic_deadline, Clock, SystemClock}, log::Level},
+};
+
+use super::{
+    ack::AckToken,
+    concurrency::{is_overload, AdaptiveConcurrency, ConcurrencyLimit},
+    delivery::Deliverer,
+    fair::FairQueue,
+    id::{IdGeneratorKind, MessageIdGenerator, SnowflakeGenerator, UuidV7Generator, MAX_SNOWFLAKE_NODE_ID},
+    interceptor::{Interceptor, InterceptorChain, Rejection},
+    lag::{ConsumerLag, LagChange, LagMonitor, LagThresholds},
+    lifecycle::{ForceTransitionError, MessageState},
+    message::{Annotation, AttemptOutcome, AttemptRecord, DeliveryError, DeliveryErrorClass, Message, MessageStatus},
+    notify::{DeadMessage, DeathNotifications},
+    pipeline::{PipelineMetrics, PipelineStage, StageTimings},
+    pull::{PullQueue, PulledMessage},
+    retry::{RetryBudgets, RetryOn},
+    schema::SchemaLoadError,
+    topic::{OrderingMode, TopicRegistry},
+};
+
+/// How long a message whose processing failed, like when its writes were refused, waits before it
+/// is processed again
+const PROCESSING_RETRY_DELAY: Duration = Duration::seconds(5);
+
+/// A message waiting in the processor queue until its next attempt is due
+struct ScheduledMessage {
+    /// The monotonic time of the processor Clock when the message is due, so wall clock jumps do
+    /// not make retries happen too early or too late
+    due_at: StdDuration,
+    /// Used to keep FIFO order between messages due at the same time
+    sequence: u64,
+    message: Message,
+}
+
+impl PartialEq for ScheduledMessage {
+    fn eq(&self, other: &Self) -> bool {
+        self.due_at == other.due_at && self.sequence == other.sequence
+    }
+}
+
+impl Eq for ScheduledMessage {}
+
+impl PartialOrd for ScheduledMessage {
+    fn partial_cmp(&self, other: &Self) -> Option<CmpOrdering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for ScheduledMessage {
+    fn cmp(&self, other: &Self) -> CmpOrdering {
+        self.due_at.cmp(&other.due_at).then(self.sequence.cmp(&other.sequence))
+    }
+}
+
+/// The messages of the processor: the ones waiting for their next attempt to be due, by due time,
+/// and the due ones, served in turns by destination so a large backlog does not starve the others
+#[derive(Default)]
+struct Schedule {
+    waiting: BinaryHeap<Reverse<ScheduledMessage>>,
+    due: FairQueue<Message>,
+    /// How many attempts are in progress, by destination
+    in_flight: HashMap<String, usize>,
+    /// The destinations released to another processor, with the messages scheduled for them since
+    /// they were released, kept to be handed off to their new owner
+    released: HashMap<String, Vec<Message>>,
+    /// The messages of deleted destinations that can still be restored, by destination, with the
+    /// monotonic time when the destination is purged and they are due again
+    parked: HashMap<String, (StdDuration, Vec<Message>)>,
+    /// The destinations paused by an operator, with their messages that became due since they were
+    /// paused, kept until they are resumed
+    paused: HashMap<String, Vec<Message>>,
+    /// The messages accepted with `202` by the receivers of destinations with async ack, by message
+    /// ID, with the monotonic time when their attempt fails if it was not confirmed
+    awaiting_ack: HashMap<String, (StdDuration, Message)>,
+    /// The attempts being sent, by message ID, with the confirmation of the receivers that confirm
+    /// a message before answering `202`
+    confirmed_early: HashMap<String, (u16, Option<Result<(), String>>)>,
+    /// The sequences of the unfinished messages of the ordered topics, by destination, namespace
+    /// and topic. Only the lowest one of each is sent
+    ordered: HashMap<OrderingKey, BTreeSet<u64>>,
+}
+
+/// The destination, namespace and topic of a message of an ordered topic
+type OrderingKey = (String, String, String);
+
+fn ordering_key(message: &Message) -> OrderingKey {
+    (message.recipient_id.clone(), message.namespace().to_string(), message.topic().to_string())
+}
+
+/// Return if the message can be sent: it is the oldest unfinished message of its ordered topic or
+/// its topic is not ordered
+fn is_next_in_order(ordered: &HashMap<OrderingKey, BTreeSet<u64>>, message: &Message) -> bool {
+    if ordered.is_empty() {
+        return true;
+    }
+    match (message.sequence, ordered.get(&ordering_key(message))) {
+        (Some(sequence), Some(sequences)) => sequences.first() == Some(&sequence) || !sequences.contains(&sequence),
+        _ => true,
+    }
+}
+
+/// What a worker of the processor does next
+#[derive(Clone)]
+enum Work {
+    /// Make an attempt to send the message
+    Attempt(Message),
+    /// Fail the attempt of the message, as its receiver did not confirm it in time
+    AckTimeout(Message),
+}
+
+/// The unfinished messages of a destination whose ownership moved to another processor. It is
+/// returned by `MessageProcessor::release` on the previous owner and given to `adopt` on the new one
+#[derive(Debug, Clone, PartialEq)]
+pub struct Handoff {
+    pub recipient_id: String,
+    /// The messages with the time of their next attempt, the earliest first
+    pub messages: Vec<Message>,
+}
+
+/// The result of publishing a message into a MessageProcessor
+#[derive(Debug, Clone, PartialEq)]
+pub enum PublishOutcome {
+    /// The message was stored and scheduled
+    Accepted,
+    /// A message with the same producer message ID was published in the topic inside the dedup
+    /// window. The new message was dropped and the original one is returned
+    Duplicate(Box<Message>),
+    /// An interceptor rejected the message, that was not stored
+    Rejected(Rejection),
+}
+
+/// What a MessageProcessor recovered from the store when it started, so operators know what a
+/// crash cost
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct RecoveryReport {
+    /// How many stored messages had each status before the recovery
+    pub statuses: BTreeMap<MessageStatus, usize>,
+    /// How many in-flight messages, whose attempt was interrupted, were reset to pending
+    pub reset_in_flight: usize,
+    /// How many pending messages were scheduled again
+    pub rescheduled: usize,
+}
+
+impl std::fmt::Display for RecoveryReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Recovered {} pending messages and reset {} in-flight messages to pending", self.rescheduled, self.reset_in_flight)?;
+        let statuses: Vec<String> = self.statuses.iter().map(|(status, count)| format!("{}: {}", status.as_str(), count)).collect();
+        if !statuses.is_empty() {
+            write!(f, " (stored {})", statuses.join(", "))?;
+        }
+        Ok(())
+    }
+}
+
+/// Counters about the messages of a topic
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct TopicCounters {
+    /// How many messages were published
+    pub messages_in: u64,
+    /// The sum of the payload sizes of the published messages
+    pub bytes_in: u64,
+    /// How many messages were delivered
+    pub delivered: u64,
+    /// How many messages died after exhausting their retry policy
+    pub dead: u64,
+}
+
+/// Counters about the messages handled by a MessageProcessor
+#[derive(Debug, Default)]
+pub struct ProcessorStats {
+    /// How many messages were published into the processor
+    pub published: AtomicU64,
+    /// How many unfinished messages were scheduled again from the store when the processor started,
+    /// and finished ones forced out of their final state
+    pub recovered: AtomicU64,
+    /// How many unfinished messages were handed off to the new owner of their destination
+    pub handed_off: AtomicU64,
+    /// How many unfinished messages were adopted from the previous owner of their destination
+    pub adopted: AtomicU64,
+    /// How many attempts were made to send messages
+    pub attempts: AtomicU64,
+    /// How many messages were delivered
+    pub delivered: AtomicU64,
+    /// How many messages died after exhausting their retry policy
+    pub dead: AtomicU64,
+    /// How many messages were not sent because they did not match the attribute filter of their destination
+    pub filtered: AtomicU64,
+    /// How many pending messages were cancelled by an operator
+    pub cancelled: AtomicU64,
+    /// How many attempts failed with each class
+    failures: Mutex<BTreeMap<DeliveryErrorClass, u64>>,
+    /// The counters of each topic, by namespace and topic
+    topics: Mutex<BTreeMap<(String, String), TopicCounters>>,
+    /// How many retries of each destination were postponed by its RetryBudget
+    retry_budget_exhausted: Mutex<BTreeMap<String, u64>>,
+    /// The latencies of the stages of the attempts, by worker
+    pub pipeline: Arc<PipelineMetrics>,
+}
+
+impl ProcessorStats {
+    /// Return how many published messages did not reach a final status yet
+    pub fn outstanding(&self) -> u64 {
+        let finished = self.delivered.load(Ordering::SeqCst) + self.dead.load(Ordering::SeqCst) + self.filtered.load(Ordering::SeqCst)
+            + self.cancelled.load(Ordering::SeqCst) + self.handed_off.load(Ordering::SeqCst);
+        let started = self.published.load(Ordering::SeqCst) + self.recovered.load(Ordering::SeqCst) + self.adopted.load(Ordering::SeqCst);
+        started.saturating_sub(finished)
+    }
+
+    /// Return how many attempts failed with each class. Classes without failures are not listed
+    pub fn failures(&self) -> BTreeMap<DeliveryErrorClass, u64> {
+        self.failures.lock().unwrap().clone()
+    }
+
+    fn record_failure(&self, class: DeliveryErrorClass) {
+        *self.failures.lock().unwrap().entry(class).or_default() += 1;
+    }
+
+    /// Return the counters of each topic that had published messages, by namespace and topic
+    pub fn topics(&self) -> BTreeMap<(String, String), TopicCounters> {
+        self.topics.lock().unwrap().clone()
+    }
+
+    fn record_topic(&self, message: &Message, update: impl FnOnce(&mut TopicCounters)) {
+        let mut topics = self.topics.lock().unwrap();
+        update(topics.entry((message.namespace().to_string(), message.topic().to_string())).or_default());
+    }
+
+    /// Count the message that reached the final state. The expired messages are counted as dead,
+    /// as they die without another attempt
+    fn record_finished(&self, message: &Message, state: MessageState) {
+        match state {
+            MessageState::Delivered => {
+                self.record_topic(message, |topic| topic.delivered += 1);
+                self.delivered.fetch_add(1, Ordering::SeqCst);
+            }
+            MessageState::Dead | MessageState::Expired => {
+                self.record_topic(message, |topic| topic.dead += 1);
+                self.dead.fetch_add(1, Ordering::SeqCst);
+            }
+            MessageState::Cancelled => { self.cancelled.fetch_add(1, Ordering::SeqCst); }
+            _ => {}
+        }
+    }
+
+    /// Return how many retries of each destination were postponed by its RetryBudget. Destinations
+    /// that never exhausted their budget are not listed
+    pub fn retry_budget_exhausted(&self) -> BTreeMap<String, u64> {
+        self.retry_budget_exhausted.lock().unwrap().clone()
+    }
+
+    fn record_retry_budget_exhausted(&self, recipient_id: &str) {
+        *self.retry_budget_exhausted.lock().unwrap().entry(recipient_id.to_string()).or_default() += 1;
+    }
+}
+
+struct ProcessorShared {
+    queue: Mutex<Schedule>,
+    queue_changed: Condvar,
+    /// Notified when the attempts of a destination finish, for the `release` waiting for them
+    attempts_finished: Condvar,
+    next_sequence: AtomicU64,
+    running: AtomicBool,
+    store: Arc<dyn MessageStore>,
+    writer: BatchedStoreWriter,
+    deliverer: Arc<dyn Deliverer>,
+    clock: Arc<dyn Clock>,
+    stats: ProcessorStats,
+    /// Which failures are retried when the Deliverer does not override it for the message
+    retry_on: RwLock<RetryOn>,
+    /// The attempts of the last minute of the destinations with a RetryBudget
+    retry_budgets: RetryBudgets,
+    /// The backlog age and the delivery latency of each destination
+    lag: RwLock<LagMonitor>,
+    /// The attempts sent at once to each destination with a max concurrency
+    concurrency: AdaptiveConcurrency,
+    /// The due messages of the pull destinations
+    pull: PullQueue,
+    /// The report of the last recovery of the store
+    recovery: Mutex<Option<RecoveryReport>>,
+    /// Where the messages that die or expire are sent to be notified, when their producers are
+    deaths: Mutex<Option<Sender<DeadMessage>>>,
+    /// The topics whose settings the messages follow, like their ordering
+    topics: RwLock<Arc<TopicRegistry>>,
+}
+
+/// Remove the messages waiting in the schedule that match, returning them. The messages being sent,
+/// awaiting their confirmation or leased by a pull are not in the schedule
+fn take_scheduled(queue: &mut Schedule, matches: impl Fn(&Message) -> bool) -> Vec<Message> {
+    let (matched, waiting): (Vec<_>, Vec<_>) = std::mem::take(&mut queue.waiting).into_vec().into_iter()
+        .partition(|Reverse(scheduled)| matches(&scheduled.message));
+    queue.waiting = waiting.into();
+    let mut taken: Vec<Message> = matched.into_iter().map(|Reverse(scheduled)| scheduled.message).collect();
+    taken.extend(queue.due.remove_matching(&matches));
+    let Schedule { released, parked, paused, .. } = queue;
+    let kept = released.values_mut().chain(parked.values_mut().map(|(_, messages)| messages)).chain(paused.values_mut());
+    for messages in kept {
+        let (matched, others): (Vec<Message>, Vec<Message>) = std::mem::take(messages).into_iter().partition(&matches);
+        *messages = others;
+        taken.extend(matched);
+    }
+    taken
+}
+
+/// Return the outcome of an attempt confirmed by its receiver
+fn confirmation_outcome(confirmation: Result<(), String>) -> AttemptOutcome {
+    match confirmation {
+        Ok(()) => AttemptOutcome::Delivered,
+        Err(reason) => AttemptOutcome::Failed(DeliveryError::new(DeliveryErrorClass::Nacked, reason)),
+    }
+}
+
+impl Work {
+    fn message(&self) -> &Message {
+        match self {
+            Work::Attempt(message) | Work::AckTimeout(message) => message,
+        }
+    }
+}
+
+impl ProcessorShared {
+    /// Move the message to the state, submitting its new status. Every status update of the
+    /// processor goes through here so a transition out of the lifecycle is refused before it is
+    /// written
+    fn transition(&self, message: &mut Message, to: MessageState, next_attempt_at: Option<OffsetDateTime>) -> Result<(), StoreError> {
+        MessageState::of(message).transition(to)?;
+        self.writer.submit(StoreWrite::UpdateStatus { message_id: message.id.clone(), status: to.status(), next_attempt_at })?;
+        message.status = to.status();
+        Ok(())
+    }
+
+    fn schedule(&self, mut message: Message, due_at: OffsetDateTime) {
+        let mut queue = self.queue.lock().unwrap();
+        if let Some(released) = queue.released.get_mut(&message.recipient_id) {
+            message.next_attempt_at = Some(due_at);
+            released.push(message);
+            return;
+        }
+        if let Some(sequence) = message.sequence.filter(|_| self.topics.read().unwrap().ordering(message.topic()) == OrderingMode::Ordered) {
+            queue.ordered.entry(ordering_key(&message)).or_default().insert(sequence);
+        }
+        let due_at = monotonic_deadline(self.clock.as_ref(), due_at);
+        let sequence = self.next_sequence.fetch_add(1, Ordering::Relaxed);
+        queue.waiting.push(Reverse(ScheduledMessage { due_at, sequence, message }));
+        self.queue_changed.notify_one();
+    }
+
+    /// Put back the work whose processing failed, to be processed again after the
+    /// PROCESSING_RETRY_DELAY instead of being lost until the processor restarts. A message whose
+    /// transition was refused stays scheduled too, so an operator can still force it out
+    fn retry_later(&self, work: Work) {
+        let due_at = self.clock.now() + PROCESSING_RETRY_DELAY;
+        match work {
+            Work::Attempt(mut message) => {
+                message.next_attempt_at = Some(due_at);
+                self.schedule(message, due_at);
+            }
+            Work::AckTimeout(message) => {
+                let deadline = monotonic_deadline(self.clock.as_ref(), due_at);
+                self.queue.lock().unwrap().awaiting_ack.insert(message.id.clone(), (deadline, message));
+                self.queue_changed.notify_one();
+            }
+        }
+    }
+
+    /// Let the next message of the ordered topic of the message be sent, once it is finished
+    fn finish_in_order(&self, message: &Message) {
+        let Some(sequence) = message.sequence else {
+            return;
+        };
+        let mut queue = self.queue.lock().unwrap();
+        let key = ordering_key(message);
+        let Some(sequences) = queue.ordered.get_mut(&key) else {
+            return;
+        };
+        if sequences.remove(&sequence) {
+            if sequences.is_empty() {
+                queue.ordered.remove(&key);
+            }
+            self.queue_changed.notify_all();
+        }
+    }
+
+    /// Count an attempt of the destination as finished, once its retry was scheduled
+    fn finish_processing(&self, recipient_id: &str) {
+        let mut queue = self.queue.lock().unwrap();
+        if let Some(count) = queue.in_flight.get_mut(recipient_id) {
+            *count -= 1;
+            if *count == 0 {
+                queue.in_flight.remove(recipient_id);
+            }
+        }
+        self.attempts_finished.notify_all();
+    }
+
+    /// Block until a message is due to be sent or its confirmation timed out. Return None when the
+    /// processor is stopped
+    fn next_work(&self) -> Option<Work> {
+        let mut queue = self.queue.lock().unwrap();
+        loop {
+            if !self.running.load(Ordering::SeqCst) {
+                return None;
+            }
+
+            let now = self.clock.monotonic();
+            let expired: Vec<String> = queue.parked.iter().filter(|(_, (until, _))| *until <= now).map(|(id, _)| id.clone()).collect();
+            for recipient_id in expired {
+                let (_, messages) = queue.parked.remove(&recipient_id).expect("the expired destination is parked");
+                for message in messages {
+                    queue.due.push(&recipient_id, message);
+                }
+            }
+            while queue.waiting.peek().is_some_and(|Reverse(scheduled)| scheduled.due_at <= now) {
+                let Reverse(scheduled) = queue.waiting.pop().expect("the peeked message is in the queue");
+                let recipient_id = scheduled.message.recipient_id.clone();
+                queue.due.push(&recipient_id, scheduled.message);
+            }
+            let timed_out = queue.awaiting_ack.iter().find(|(_, (deadline, _))| *deadline <= now).map(|(id, _)| id.clone());
+            let work = match timed_out {
+                Some(message_id) => queue.awaiting_ack.remove(&message_id).map(|(_, message)| Work::AckTimeout(message)),
+                None => {
+                    // the destinations sending as many attempts as their concurrency allows keep their
+                    // turns, and the messages of the ordered topics wait for the previous ones
+                    let Schedule { due, in_flight, ordered, .. } = &mut *queue;
+                    due.pop_first_where(
+                        |recipient_id, message| self.deliverer.max_concurrency(message)
+                            .is_none_or(|max| self.concurrency.allows(recipient_id, max, in_flight.get(recipient_id).copied().unwrap_or(0))),
+                        |message| is_next_in_order(ordered, message),
+                    ).map(Work::Attempt)
+                }
+            };
+            if let Some(work) = work {
+                *queue.in_flight.entry(work.message().recipient_id.clone()).or_default() += 1;
+                return Some(work);
+            }
+
+            let next_due_at = queue.waiting.peek().map(|Reverse(scheduled)| scheduled.due_at);
+            let deadlines = queue.awaiting_ack.values().map(|(deadline, _)| *deadline);
+            let wait = match queue.parked.values().map(|(until, _)| *until).chain(deadlines).chain(next_due_at).min() {
+                Some(due_at) => self.clock.real_wait(due_at - now),
+                None => None,
+            };
+
+            queue = match wait {
+                Some(wait) => self.queue_changed.wait_timeout(queue, wait).unwrap().0,
+                None => self.queue_changed.wait(queue).unwrap(),
+            };
+        }
+    }
+
+    /// Return if the failure matches the `retryOn` of the message destination, or the one of the processor
+    fn is_retriable(&self, message: &Message, error: &DeliveryError) -> bool {
+        match self.deliverer.retry_on(message) {
+            Some(retry_on) => retry_on.matches(error),
+            None => self.retry_on.read().unwrap().matches(error),
+        }
+    }
+
+    /// Make an attempt to send the message and handle its outcome. The messages of pull
+    /// destinations wait in the PullQueue instead, and their attempt finishes when they are acked.
+    /// The messages of paused destinations wait for them to be resumed, the ones of deleted
+    /// destinations are parked, still pending, until they are restored or
+    /// purged, the ones due outside the delivery window of their destination wait for it to open and
+    /// the retries above the RetryBudget of their destination wait for it to free. The attempts
+    /// accepted with `202` finish when their receiver confirms them. Each stage of the attempt is
+    /// timed for the worker
+    fn process(&self, worker: &str, mut message: Message) -> Result<(), StoreError> {
+        let claim_started = Instant::now();
+        if let Some(paused) = self.queue.lock().unwrap().paused.get_mut(&message.recipient_id) {
+            paused.push(message);
+            return Ok(());
+        }
+        if let Some(until) = self.deliverer.parked_until(&message) {
+            let until = monotonic_deadline(self.clock.as_ref(), until);
+            if until > self.clock.monotonic() {
+                let mut queue = self.queue.lock().unwrap();
+                let parked = queue.parked.entry(message.recipient_id.clone()).or_insert_with(|| (until, Vec::new()));
+                parked.0 = until;
+                parked.1.push(message);
+                // the worker waiting for the next message may wait for the purge
+                self.queue_changed.notify_one();
+                return Ok(());
+            }
+        }
+        if let Some(opens_at) = self.deliverer.held_until(&message, self.clock.now()) {
+            let held = MessageState::of(&message);
+            self.transition(&mut message, held, Some(opens_at))?;
+            log!(Level::Debug, "Message {} is held until the delivery window of {} opens at {}", message.id, message.recipient_id, opens_at);
+            message.next_attempt_at = Some(opens_at);
+            self.schedule(message, opens_at);
+            return Ok(());
+        }
+        if self.deliverer.is_pulled(&message) {
+            self.pull.push(message);
+            return Ok(());
+        }
+        if let Some(budget) = self.deliverer.retry_budget(&message) {
+            let now = self.clock.monotonic();
+            if message.attempts == 0 {
+                self.retry_budgets.record_first_attempt(&message.recipient_id, now);
+            } else if let Err(wait) = self.retry_budgets.try_retry(&message.recipient_id, &budget, now) {
+                let retry_at = self.clock.now() + wait;
+                self.transition(&mut message, MessageState::Retrying, Some(retry_at))?;
+                self.stats.record_retry_budget_exhausted(&message.recipient_id);
+                log!(Level::Debug, "Retry of message {} is postponed to {} by the retry budget of {}", message.id, retry_at, message.recipient_id);
+                message.next_attempt_at = Some(retry_at);
+                self.schedule(message, retry_at);
+                return Ok(());
+            }
+        }
+        self.transition(&mut message, MessageState::InFlight, None)?;
+
+        self.queue.lock().unwrap().confirmed_early.insert(message.id.clone(), (message.attempts + 1, None));
+        let pipeline = &self.stats.pipeline;
+        pipeline.record(worker, PipelineStage::Claim, claim_started.elapsed());
+
+        let mut timings = StageTimings::default();
+        let deliver_started = Instant::now();
+        let outcome = self.deliverer.deliver_timed(&message, &mut timings);
+        let delivered_in = deliver_started.elapsed();
+        if let Some(transform) = timings.transform {
+            pipeline.record(worker, PipelineStage::Transform, transform);
+        }
+        let latency = delivered_in.saturating_sub(timings.transform.unwrap_or_default());
+        pipeline.record(worker, PipelineStage::Deliver, latency);
+        if let Some(max) = self.deliverer.max_concurrency(&message) {
+            let overloaded = matches!(&outcome, AttemptOutcome::Failed(error) if is_overload(error.class));
+            self.concurrency.record(&message.recipient_id, max, latency, overloaded);
+        }
+
+        let now = self.clock.now();
+        // the messages of a destination are sent in order, so the one just sent was its oldest due one
+        let backlog_age = message.next_attempt_at.and_then(|due_at| (now - due_at).try_into().ok()).unwrap_or_default();
+        let lag = self.lag.read().unwrap();
+        let change = lag.record(&message.recipient_id, backlog_age, latency, now);
+        let mut queue = self.queue.lock().unwrap();
+        if lag.thresholds().demote {
+            match change {
+                Some(LagChange::StartedLagging) => queue.due.set_demoted(&message.recipient_id, true),
+                Some(LagChange::CaughtUp) => queue.due.set_demoted(&message.recipient_id, false),
+                None => {}
+            }
+        }
+        drop(lag);
+        let confirmed_early = queue.confirmed_early.remove(&message.id).and_then(|(_, confirmation)| confirmation);
+        let AttemptOutcome::Accepted { confirm_before } = outcome else {
+            drop(queue);
+            return self.persist(worker, message, outcome);
+        };
+        if let Some(confirmation) = confirmed_early {
+            drop(queue);
+            return self.persist(worker, message, confirmation_outcome(confirmation));
+        }
+        log!(Level::Debug, "Attempt {} of message {} was accepted, waiting for its confirmation until {}", message.attempts + 1, message.id, confirm_before);
+        let deadline = monotonic_deadline(self.clock.as_ref(), confirm_before);
+        queue.awaiting_ack.insert(message.id.clone(), (deadline, message));
+        // the worker waiting for the next message may wait for the deadline
+        self.queue_changed.notify_one();
+        Ok(())
+    }
+
+    /// Fail the attempt of a message accepted with `202` whose receiver did not confirm it in time
+    fn time_out_ack(&self, worker: &str, message: Message) -> Result<(), StoreError> {
+        let error = DeliveryError::new(DeliveryErrorClass::ResponseTimeout, "the receiver did not confirm the accepted message in time");
+        self.persist(worker, message, AttemptOutcome::Failed(error))
+    }
+
+    /// Finish the attempt made by the worker, timing it as the persist stage
+    fn persist(&self, worker: &str, message: Message, outcome: AttemptOutcome) -> Result<(), StoreError> {
+        let started = Instant::now();
+        let result = self.finish_attempt(message, outcome);
+        self.stats.pipeline.record(worker, PipelineStage::Persist, started.elapsed());
+        result
+    }
+
+    /// Send the message that died to be notified, when the deaths are notified
+    fn notify_death(&self, message: &Message, state: MessageState, reason: Option<String>, died_at: OffsetDateTime) {
+        if let Some(deaths) = self.deaths.lock().unwrap().as_ref() {
+            let _ = deaths.send(DeadMessage { message: message.clone(), state, reason, died_at });
+        }
+    }
+
+    /// Record the outcome of an attempt, scheduling the next one when the message should be retried
+    fn finish_attempt(&self, mut message: Message, outcome: AttemptOutcome) -> Result<(), StoreError> {
+        let now = self.clock.now();
+        message.attempts += 1;
+        if let AttemptOutcome::Failed(error) = &outcome {
+            self.stats.record_failure(error.class);
+        }
+
+        let (state, next_attempt_at) = match &outcome {
+            AttemptOutcome::Delivered | AttemptOutcome::Filtered => (MessageState::Delivered, None),
+            AttemptOutcome::Failed(error) if !self.is_retriable(&message, error) => (MessageState::Dead, None),
+            AttemptOutcome::Failed(_) => match message.retry_policy.next_retry_delay(message.attempts) {
+                Some(delay) => (MessageState::Retrying, Some(now + delay)),
+                None => (MessageState::Dead, None),
+            },
+            AttemptOutcome::Accepted { .. } => unreachable!("the accepted attempts finish when they are confirmed"),
+        };
+
+        match &outcome {
+            AttemptOutcome::Failed(error) => log!(Level::Debug, "Attempt {} of message {} failed with {}: {}", message.attempts, message.id, error.class.as_str(), error),
+            _ => log!(Level::Debug, "Attempt {} of message {} finished as {}", message.attempts, message.id, state.as_str()),
+        }
+        // checked before the attempt is recorded, so a refused transition writes nothing
+        MessageState::of(&message).transition(state)?;
+        let outcome_filtered = outcome == AttemptOutcome::Filtered;
+        let failure = match (&outcome, state) {
+            (AttemptOutcome::Failed(error), MessageState::Dead) => Some(format!("{}: {}", error.class.as_str(), error)),
+            _ => None,
+        };
+        self.writer.submit(StoreWrite::RecordAttempt(AttemptRecord {
+            message_id: message.id.clone(),
+            attempt: message.attempts,
+            finished_at: now,
+            outcome,
+        }))?;
+        self.transition(&mut message, state, next_attempt_at)?;
+        // counted once its writes were submitted, so a flush after it was counted also writes them
+        self.stats.attempts.fetch_add(1, Ordering::SeqCst);
+
+        match state {
+            MessageState::Delivered if outcome_filtered => { self.stats.filtered.fetch_add(1, Ordering::SeqCst); }
+            MessageState::Delivered => self.stats.record_finished(&message, state),
+            MessageState::Dead => {
+                self.stats.record_finished(&message, state);
+                self.notify_death(&message, state, failure, now);
+            }
+            _ => {}
+        }
+
+        if state.is_final() {
+            self.finish_in_order(&message);
+        }
+        if let Some(next_attempt_at) = next_attempt_at {
+            message.next_attempt_at = Some(next_attempt_at);
+            self.schedule(message, next_attempt_at);
+        }
+
+        Ok(())
+    }
+}
+
+/// The pool of workers that send the messages to their recipients, scheduling the retries of the
+/// failed attempts according to the message retry policy. Published messages are written into the
+/// store before `publish` returns, while status changes and attempt outcomes are written through a
+/// BatchedStoreWriter, so they may take up to a flush interval to be visible in the store
+pub struct MessageProcessor {
+    shared: Arc<ProcessorShared>,
+    workers: Mutex<Vec<JoinHandle<()>>>,
+    dedup_window: Option<Duration>,
+    interceptors: InterceptorChain,
+    /// The last sequence number given to each topic, by namespace and topic
+    sequences: Mutex<HashMap<(String, String), u64>>,
+    /// Held while a message with a producer message ID is checked and stored, so concurrent
+    /// duplicates are not both accepted
+    dedup_lock: Mutex<()>,
+    id_generator: Arc<dyn MessageIdGenerator>,
+}
+
+impl MessageProcessor {
+    /// Start a processor with `workers_count` workers
+    pub fn start(
+        workers_count: usize,
+        store: Arc<dyn MessageStore>,
+        batch: BatchConfiguration,
+        deliverer: Arc<dyn Deliverer>,
+    ) -> MessageProcessor {
+        MessageProcessor::start_with_clock(workers_count, store, batch, deliverer, Arc::new(SystemClock))
+    }
+
+    /// Start a processor with `workers_count` workers that schedules the retries using the given Clock
+    pub fn start_with_clock(
+        workers_count: usize,
+        store: Arc<dyn MessageStore>,
+        batch: BatchConfiguration,
+        deliverer: Arc<dyn Deliverer>,
+        clock: Arc<dyn Clock>,
+    ) -> MessageProcessor {
+        let stats = ProcessorStats::default();
+        let shared = Arc::new(ProcessorShared {
+            queue: Mutex::new(Schedule::default()),
+            queue_changed: Condvar::new(),
+            attempts_finished: Condvar::new(),
+            next_sequence: AtomicU64::new(0),
+            running: AtomicBool::new(true),
+            writer: BatchedStoreWriter::with_metrics(store.clone(), batch, stats.pipeline.clone()),
+            store,
+            deliverer,
+            clock: clock.clone(),
+            stats,
+            retry_on: RwLock::new(RetryOn::default()),
+            retry_budgets: RetryBudgets::new(),
+            lag: RwLock::new(LagMonitor::default()),
+            concurrency: AdaptiveConcurrency::new(),
+            pull: PullQueue::new(),
+            recovery: Mutex::new(None),
+            deaths: Mutex::new(None),
+            topics: RwLock::new(Arc::new(TopicRegistry::new())),
+        });
+
+        // wake up the workers when a manually moved clock makes a scheduled message due
+        let weak_shared: Weak<ProcessorShared> = Arc::downgrade(&shared);
+        clock.on_advance(Arc::new(move || {
+            if let Some(shared) = weak_shared.upgrade() {
+                let _queue = shared.queue.lock().unwrap();
+                shared.queue_changed.notify_all();
+            }
+        }));
+
+        let workers = (0..workers_count.max(1))
+            .map(|index| {
+                let shared = shared.clone();
+                thread::Builder::new()
+                    .name(format!("angler-worker-{}", index))
+                    .spawn(move || {
+                        let worker = index.to_string();
+                        while let Some(work) = shared.next_work() {
+                            let (message_id, recipient_id) = (work.message().id.clone(), work.message().recipient_id.clone());
+                            // the work already left the schedule, so it is kept to be put back when it fails
+                            let retry = work.clone();
+                            let result = match work {
+                                Work::Attempt(message) => shared.process(&worker, message),
+                                Work::AckTimeout(message) => shared.time_out_ack(&worker, message),
+                            };
+                            if let Err(err) = result {
+                                log!(Level::Error, "Failed to process message {}, processing it again in {}s: {}", message_id, PROCESSING_RETRY_DELAY.whole_seconds(), err);
+                                shared.retry_later(retry);
+                            }
+                            shared.finish_processing(&recipient_id);
+                        }
+                    })
+                    .expect("failed to spawn a message processor worker")
+            })
+            .collect();
+
+        MessageProcessor { shared, workers: Mutex::new(workers), dedup_window: None, interceptors: InterceptorChain::new(), dedup_lock: Mutex::new(()), sequences: Mutex::new(HashMap::new()), id_generator: Arc::new(UuidV7Generator::new()) }
+    }
+
+    /// Start a processor using the `msgproc.` and `db.writes.` configurations. It fails, before
+    /// starting the workers, when a schema of `msgproc.interceptors.schema.` can not be loaded
+    pub fn from_configuration(
+        conf: &Configuration,
+        store: Arc<dyn MessageStore>,
+        deliverer: Arc<dyn Deliverer>,
+        clock: Arc<dyn Clock>,
+    ) -> Result<MessageProcessor, SchemaLoadError> {
+        let interceptors = InterceptorChain::from_configuration(conf)?;
+        let workers_count = conf.messages_processor.workers_count
+            .unwrap_or_else(|| thread::available_parallelism().map(|n| n.get()).unwrap_or(1));
+        let mut processor = MessageProcessor::start_with_clock(workers_count, store, BatchConfiguration::from_configuration(&conf.database), deliverer, clock);
+        processor.dedup_window = conf.messages_processor.dedup_window;
+        processor.interceptors = interceptors;
+        let lagging = &conf.messages_processor;
+        let thresholds = LagThresholds {
+            backlog_age: lagging.lagging_backlog_age.and_then(|age| StdDuration::try_from(age).ok()),
+            latency: lagging.lagging_latency.and_then(|latency| StdDuration::try_from(latency).ok()),
+            demote: lagging.lagging_demote.unwrap_or(false),
+        };
+        processor = processor.with_lag_thresholds(thresholds);
+        if conf.messages_processor.id_generator == Some(IdGeneratorKind::Snowflake) {
+            // without msgproc.ids.nodeId the node ID comes from the random ID of this node
+            let node_id = conf.messages_processor.snowflake_node_id
+                .unwrap_or_else(|| (local_node_id().bytes().fold(0u16, |hash, byte| hash.wrapping_mul(31).wrapping_add(u16::from(byte)))) & MAX_SNOWFLAKE_NODE_ID);
+            processor.id_generator = Arc::new(SnowflakeGenerator::new(node_id));
+        }
+        Ok(match &conf.retry_policy.retry_on {
+            Some(retry_on) => processor.with_retry_on(retry_on.clone()),
+            None => processor,
+        })
+    }
+
+    /// Return the concurrency of the destination, None when it has no max concurrency or had no
+    /// attempt yet
+    pub fn concurrency(&self, recipient_id: &str) -> Option<ConcurrencyLimit> {
+        self.shared.concurrency.get(recipient_id)
+    }
+
+    /// Return the concurrency of each destination with a max concurrency that had an attempt
+    pub fn concurrencies(&self) -> BTreeMap<String, ConcurrencyLimit> {
+        self.shared.concurrency.list()
+    }
+
+    /// Mark the destinations beyond the thresholds as lagging, demoting them when `demote` is on
+    pub fn with_lag_thresholds(self, thresholds: LagThresholds) -> MessageProcessor {
+        *self.shared.lag.write().unwrap() = LagMonitor::new(thresholds);
+        self
+    }
+
+    /// Return how far behind the destination is, None when it had no attempt yet
+    pub fn lag(&self, recipient_id: &str) -> Option<ConsumerLag> {
+        self.shared.lag.read().unwrap().get(recipient_id)
+    }
+
+    /// Return how far behind each destination that had an attempt is, by destination
+    pub fn lags(&self) -> BTreeMap<String, ConsumerLag> {
+        self.shared.lag.read().unwrap().list()
+    }
+
+    /// Only retry the failures that match, unless the Deliverer overrides it for the message
+    pub fn with_retry_on(self, retry_on: RetryOn) -> MessageProcessor {
+        *self.shared.retry_on.write().unwrap() = retry_on;
+        self
+    }
+
+    /// Drop the messages published with a producer message ID already published in the same topic
+    /// inside the window
+    pub fn with_dedup_window(mut self, window: Duration) -> MessageProcessor {
+        self.dedup_window = Some(window);
+        self
+    }
+
+    /// Create the IDs of the published messages with the generator instead of UUIDs version 7
+    pub fn with_id_generator(mut self, id_generator: Arc<dyn MessageIdGenerator>) -> MessageProcessor {
+        self.id_generator = id_generator;
+        self
+    }
+
+    /// Return a new message ID, sorted after the IDs this processor created before
+    pub fn next_message_id(&self) -> String {
+        self.id_generator.generate(self.shared.clock.now())
+    }
+
+    /// Publish a notification of each message that dies or expires from now on, to the destination
+    /// of the producer of its namespace. The notifications are published by a thread of their own,
+    /// so the workers do not wait for the store, and it stops with the processor
+    pub fn notify_deaths(self: &Arc<Self>, notifications: DeathNotifications) {
+        let (deaths, dead_messages) = mpsc::channel::<DeadMessage>();
+        *self.shared.deaths.lock().unwrap() = Some(deaths);
+        let processor = Arc::downgrade(self);
+        thread::Builder::new()
+            .name(String::from("angler-death-notifier"))
+            .spawn(move || {
+                for dead in dead_messages {
+                    let Some(processor) = processor.upgrade() else {
+                        return;
+                    };
+                    let Some(notification) = notifications.notification(processor.next_message_id(), &dead) else {
+                        continue;
+                    };
+                    match processor.publish(notification) {
+                        Ok(PublishOutcome::Rejected(rejection)) => log!(Level::Warn, "The death notification of message {} was rejected: {}", dead.message.id, rejection),
+                        Ok(_) => log!(Level::Debug, "The death of message {} was notified to its producer", dead.message.id),
+                        Err(err) => log!(Level::Warn, "Failed to notify the death of message {}: {}", dead.message.id, err),
+                    }
+                }
+            })
+            .expect("failed to spawn the death notifier");
+    }
+
+    /// Follow the settings of the topics of the registry: the messages of the ordered topics are
+    /// sent in order and the payloads are checked by the max payload size and the schema of their
+    /// topic, after the other interceptors
+    pub fn with_topics(self, topics: Arc<TopicRegistry>) -> MessageProcessor {
+        *self.shared.topics.write().unwrap() = topics.clone();
+        self.with_interceptor(topics)
+    }
+
+    /// Add an interceptor at the end of the chain applied to the published messages
+    pub fn with_interceptor(mut self, interceptor: Arc<dyn Interceptor>) -> MessageProcessor {
+        self.interceptors.push(interceptor);
+        self
+    }
+
+    /// Store the message and queue it to be sent at its `next_attempt_at`
+    pub fn publish(&self, mut message: Message) -> Result<PublishOutcome, StoreError> {
+        if !self.shared.running.load(Ordering::SeqCst) {
+            return Err(StoreError::WriterClosed);
+        }
+        if let Err(rejection) = self.interceptors.apply(&mut message) {
+            return Ok(PublishOutcome::Rejected(rejection));
+        }
+
+        let _dedup_guard = match (self.dedup_window, &message.producer_message_id) {
+            (Some(window), Some(producer_message_id)) => {
+                let guard = self.dedup_lock.lock().unwrap();
+                let window_start = self.shared.clock.now() - window;
+                let original = self.shared.store
+                    .find_by_producer_message_id(message.namespace(), message.topic(), producer_message_id)?
+                    .filter(|original| original.created_at > window_start);
+                if let Some(original) = original {
+                    return Ok(PublishOutcome::Duplicate(Box::new(original)));
+                }
+                Some(guard)
+            }
+            _ => None,
+        };
+
+        // the sequence is only taken after the message is stored, so a failed write leaves no gap
+        let mut sequences = self.sequences.lock().unwrap();
+        let topic = (message.namespace().to_string(), message.topic().to_string());
+        let last_sequence = match sequences.get(&topic) {
+            Some(last_sequence) => *last_sequence,
+            None => self.last_stored_sequence(&message)?,
+        };
+        message.sequence = Some(last_sequence + 1);
+
+        // the message is persisted before the publish is acknowledged
+        self.shared.store.write(StoreWrite::InsertMessage(Box::new(message.clone())))?;
+        sequences.insert(topic, last_sequence + 1);
+        self.shared.stats.record_topic(&message, |topic| {
+            topic.messages_in += 1;
+            topic.bytes_in += message.payload.len() as u64;
+        });
+        self.shared.stats.published.fetch_add(1, Ordering::SeqCst);
+        let due_at = message.next_attempt_at.unwrap_or_else(|| self.shared.clock.now());
+        // scheduled before the sequences are released, so a concurrent publish whose sequence is
+        // higher cannot reach the queue of an ordered topic first and be sent before this one
+        self.shared.schedule(message, due_at);
+        drop(sequences);
+        Ok(PublishOutcome::Accepted)
+    }
+
+    /// Return the highest sequence of the stored messages of the topic, so the sequence keeps
+    /// increasing when the processor is started over an existing store
+    fn last_stored_sequence(&self, message: &Message) -> Result<u64, StoreError> {
+        let query = MessageQuery {
+            namespace: Some(message.namespace().to_string()),
+            topic: Some(message.topic().to_string()),
+            ..MessageQuery::default()
+        };
+        Ok(self.shared.store.find_messages(&query)?.iter().filter_map(|stored| stored.sequence).max().unwrap_or(0))
+    }
+
+    /// Schedule the unfinished messages of the store, after a restart or a crash. The in-flight
+    /// messages were interrupted before their attempt finished, so they are reset to pending and
+    /// sent again now, while the pending messages keep their next attempt time
+    pub fn recover(&self) -> Result<RecoveryReport, StoreError> {
+        let now = self.shared.clock.now();
+        let mut report = RecoveryReport::default();
+        for mut message in self.shared.store.find_messages(&MessageQuery::default())? {
+            *report.statuses.entry(message.status).or_default() += 1;
+            let due_at = match message.status {
+                MessageStatus::InFlight => {
+                    let interrupted = MessageState::waiting(message.attempts);
+                    self.shared.transition(&mut message, interrupted, Some(now))?;
+                    message.next_attempt_at = Some(now);
+                    report.reset_in_flight += 1;
+                    now
+                }
+                MessageStatus::Pending => {
+                    report.rescheduled += 1;
+                    message.next_attempt_at.unwrap_or(now)
+                }
+                MessageStatus::Delivered | MessageStatus::Dead => continue,
+            };
+            self.shared.stats.recovered.fetch_add(1, Ordering::SeqCst);
+            self.shared.schedule(message, due_at);
+        }
+        self.shared.writer.flush()?;
+        *self.shared.recovery.lock().unwrap() = Some(report.clone());
+        Ok(report)
+    }
+
+    /// Return the report of the last `recover`
+    pub fn recovery(&self) -> Option<RecoveryReport> {
+        self.shared.recovery.lock().unwrap().clone()
+    }
+
+    /// Stop sending the messages of the destination, now owned by another processor, and return its
+    /// unfinished messages to be adopted by the new owner. The attempts in progress are waited for
+    /// and their retries are handed off too, so the two processors never send the same message.
+    /// Messages scheduled for the destination after it was released, like the ones published with
+    /// a stale owner, are kept and returned by the next `release`
+    pub fn release(&self, recipient_id: &str) -> Result<Handoff, StoreError> {
+        let mut queue = self.shared.queue.lock().unwrap();
+        let mut messages = Vec::new();
+        if !queue.released.contains_key(recipient_id) {
+            queue.released.insert(recipient_id.to_string(), Vec::new());
+            let (released, waiting): (Vec<_>, Vec<_>) = std::mem::take(&mut queue.waiting).into_vec().into_iter()
+                .partition(|Reverse(scheduled)| scheduled.message.recipient_id == recipient_id);
+            queue.waiting = waiting.into();
+            messages.extend(released.into_iter().map(|Reverse(scheduled)| scheduled.message));
+            messages.extend(queue.due.take(recipient_id));
+            messages.extend(queue.parked.remove(recipient_id).map(|(_, parked)| parked).unwrap_or_default());
+            // the pause is kept by this processor, the new owner sends them
+            messages.extend(queue.paused.get_mut(recipient_id).map(std::mem::take).unwrap_or_default());
+            // the new owner sends them again, as their confirmations can only reach this processor
+            let awaiting: Vec<String> = queue.awaiting_ack.iter().filter(|(_, (_, message))| message.recipient_id == recipient_id).map(|(id, _)| id.clone()).collect();
+            messages.extend(awaiting.iter().filter_map(|id| queue.awaiting_ack.remove(id)).map(|(_, message)| message));
+            // the new owner keeps their order
+            queue.ordered.retain(|(ordered_recipient_id, _, _), _| ordered_recipient_id != recipient_id);
+        }
+        while queue.in_flight.contains_key(recipient_id) {
+            queue = self.shared.attempts_finished.wait(queue).unwrap();
+        }
+        messages.append(queue.released.get_mut(recipient_id).expect("the destination was released"));
+        drop(queue);
+
+        // the new owner reads the outcomes of the last attempts from the store
+        self.shared.writer.flush()?;
+        let now = self.shared.clock.now();
+        messages.sort_by_key(|message| message.next_attempt_at.unwrap_or(now));
+        self.shared.stats.handed_off.fetch_add(messages.len() as u64, Ordering::SeqCst);
+        log!(Level::Info, "Released the destination {}, handing off {} messages", recipient_id, messages.len());
+        Ok(Handoff { recipient_id: recipient_id.to_string(), messages })
+    }
+
+    /// Schedule the messages handed off by the previous owner of the destination, owned by this
+    /// processor from now on. Return how many messages were scheduled
+    pub fn adopt(&self, handoff: Handoff) -> usize {
+        // the messages kept when this processor released the destination before
+        let kept = self.shared.queue.lock().unwrap().released.remove(&handoff.recipient_id).unwrap_or_default();
+        let now = self.shared.clock.now();
+        let adopted = handoff.messages.len();
+        let scheduled = adopted + kept.len();
+        for message in handoff.messages.into_iter().chain(kept) {
+            let due_at = message.next_attempt_at.unwrap_or(now);
+            self.shared.schedule(message, due_at);
+        }
+        self.shared.stats.adopted.fetch_add(adopted as u64, Ordering::SeqCst);
+        log!(Level::Info, "Adopted the destination {} with {} messages", handoff.recipient_id, scheduled);
+        scheduled
+    }
+
+    /// Make the messages parked for the destination due again, once it was restored or registered
+    /// after being deleted. Return how many messages were parked
+    pub fn unpark(&self, recipient_id: &str) -> usize {
+        let mut queue = self.shared.queue.lock().unwrap();
+        let Some((_, messages)) = queue.parked.remove(recipient_id) else {
+            return 0;
+        };
+        let unparked = messages.len();
+        for message in messages {
+            queue.due.push(recipient_id, message);
+        }
+        self.shared.queue_changed.notify_all();
+        log!(Level::Info, "Unparked {} messages of the destination {}", unparked, recipient_id);
+        unparked
+    }
+
+    /// Stop sending the messages of the destination until it is resumed. Its attempts in progress
+    /// finish and it keeps its messages that become due meanwhile, still pending. Return false when
+    /// the destination was already paused
+    pub fn pause(&self, recipient_id: &str) -> bool {
+        let mut queue = self.shared.queue.lock().unwrap();
+        if queue.paused.contains_key(recipient_id) {
+            return false;
+        }
+        let due = queue.due.take(recipient_id);
+        queue.paused.insert(recipient_id.to_string(), due);
+        log!(Level::Info, "Paused the destination {}", recipient_id);
+        true
+    }
+
+    /// Send the messages of a paused destination again, the ones that became due while it was
+    /// paused first. Return how many messages became due, or None when it was not paused
+    pub fn resume(&self, recipient_id: &str) -> Option<usize> {
+        let mut queue = self.shared.queue.lock().unwrap();
+        let messages = queue.paused.remove(recipient_id)?;
+        let resumed = messages.len();
+        for message in messages {
+            queue.due.push(recipient_id, message);
+        }
+        self.shared.queue_changed.notify_all();
+        log!(Level::Info, "Resumed the destination {} with {} due messages", recipient_id, resumed);
+        Some(resumed)
+    }
+
+    /// Return the paused destinations with how many of their messages became due since they were paused
+    pub fn paused(&self) -> BTreeMap<String, usize> {
+        self.shared.queue.lock().unwrap().paused.iter().map(|(recipient_id, messages)| (recipient_id.clone(), messages.len())).collect()
+    }
+
+    /// Cancel the pending messages with the IDs, that die without another attempt and get the
+    /// annotation. The messages whose attempt is in progress, waiting for its confirmation or
+    /// leased by a pull can not be cancelled, neither the ones of other processors. Return the
+    /// cancelled messages
+    pub fn cancel(&self, message_ids: &HashSet<String>, annotation: &Annotation) -> Result<Vec<Message>, StoreError> {
+        let mut cancelled = take_scheduled(&mut self.shared.queue.lock().unwrap(), |message| message_ids.contains(&message.id));
+
+        for message in &mut cancelled {
+            MessageState::of(message).transition(MessageState::Cancelled)?;
+            self.shared.writer.submit(StoreWrite::AnnotateMessage { message_id: message.id.clone(), annotation: annotation.clone() })?;
+            self.shared.transition(message, MessageState::Cancelled, None)?;
+            self.shared.finish_in_order(message);
+            self.shared.stats.record_finished(message, MessageState::Cancelled);
+        }
+        Ok(cancelled)
+    }
+
+    /// Force the stored message into the state, outside of the lifecycle, for the messages stuck in
+    /// a state the processor does not take them out of, like in flight after their node crashed.
+    /// The message leaves the queues of this processor, is scheduled again when the state waits
+    /// for an attempt and gets an annotation with its previous state, the reason and the author,
+    /// so the change can be audited. The messages whose attempt is being sent are not forced
+    pub fn force_transition(&self, message_id: &str, to: MessageState, reason: &str, author: Option<String>) -> Result<Message, ForceTransitionError> {
+        if !to.is_forceable() {
+            return Err(ForceTransitionError::NotForceable(to));
+        }
+        let mut message = self.shared.store.get_message(message_id)?.ok_or_else(|| ForceTransitionError::MessageNotFound(message_id.to_string()))?;
+        {
+            let mut queue = self.shared.queue.lock().unwrap();
+            if queue.confirmed_early.contains_key(message_id) {
+                return Err(ForceTransitionError::AttemptRunning(message_id.to_string()));
+            }
+            take_scheduled(&mut queue, |queued| queued.id == message_id);
+            queue.awaiting_ack.remove(message_id);
+        }
+        self.shared.pull.remove(message_id);
+
+        let now = self.shared.clock.now();
+        let from = MessageState::of(&message);
+        let annotation = Annotation { note: format!("Forced from {} to {}: {}", from, to, reason), author, created_at: now };
+        let next_attempt_at = matches!(to, MessageState::Scheduled | MessageState::Retrying).then_some(now);
+        self.shared.writer.submit(StoreWrite::AnnotateMessage { message_id: message.id.clone(), annotation: annotation.clone() })?;
+        self.shared.writer.submit(StoreWrite::UpdateStatus { message_id: message.id.clone(), status: to.status(), next_attempt_at })?;
+        log!(Level::Warn, "Message {} was forced from {} to {} by {}: {}", message.id, from, to, annotation.author.as_deref().unwrap_or("an operator"), reason);
+
+        message.status = to.status();
+        message.next_attempt_at = next_attempt_at;
+        message.annotations.push(annotation);
+        // a message that leaves a final state is started again, and one that reaches it is finished
+        if from.is_final() {
+            self.shared.stats.recovered.fetch_add(1, Ordering::SeqCst);
+        }
+        if let Some(due_at) = next_attempt_at {
+            self.shared.schedule(message.clone(), due_at);
+        }
+        if to.is_final() {
+            self.shared.finish_in_order(&message);
+            self.shared.stats.record_finished(&message, to);
+        }
+        if matches!(to, MessageState::Dead | MessageState::Expired) {
+            self.shared.notify_death(&message, to, Some(reason.to_string()), now);
+        }
+        Ok(message)
+    }
+
+    /// Lease up to `max` due messages of a pull destination topic, waiting up to `wait` for at
+    /// least one. The leases whose visibility timeout expired are failed first, so their messages
+    /// are retried according to their retry policy
+    pub fn pull(&self, topic: &str, recipient_id: Option<&str>, max: usize, wait: StdDuration, visibility: StdDuration) -> Result<Vec<PulledMessage>, StoreError> {
+        self.expire_leases()?;
+        let mut pulled = self.shared.pull.lease(topic, recipient_id, max, wait, visibility);
+        for pulled in &mut pulled {
+            self.shared.transition(&mut pulled.message, MessageState::InFlight, None)?;
+        }
+        Ok(pulled)
+    }
+
+    /// Finish the attempt of a pulled message as delivered. Return false when the receipt is
+    /// unknown or its visibility timeout expired
+    pub fn ack(&self, topic: &str, receipt: &str) -> Result<bool, StoreError> {
+        self.expire_leases()?;
+        match self.shared.pull.release(topic, receipt) {
+            Some(message) => self.shared.finish_attempt(message, AttemptOutcome::Delivered).map(|_| true),
+            None => Ok(false),
+        }
+    }
+
+    /// Finish the attempt of a pulled message as failed, so it is retried according to its retry
+    /// policy. Return false when the receipt is unknown or its visibility timeout expired
+    pub fn nack(&self, topic: &str, receipt: &str) -> Result<bool, StoreError> {
+        self.expire_leases()?;
+        let Some(message) = self.shared.pull.release(topic, receipt) else {
+            return Ok(false);
+        };
+        let error = DeliveryError::new(DeliveryErrorClass::Nacked, "the consumer nacked the message");
+        self.shared.finish_attempt(message, AttemptOutcome::Failed(error)).map(|_| true)
+    }
+
+    /// Finish the attempt of a message accepted with `202` as confirmed by its receiver: delivered,
+    /// or failed with the reason given by the receiver and retried according to its retry policy.
+    /// Return false when the message is not waiting for the confirmation of the attempt of the token
+    pub fn confirm_ack(&self, token: &AckToken, confirmation: Result<(), String>) -> Result<bool, StoreError> {
+        let mut queue = self.shared.queue.lock().unwrap();
+        // the receiver may confirm the message before its `202` arrives
+        if let Some((attempt, confirmed_early)) = queue.confirmed_early.get_mut(&token.message_id) {
+            if *attempt != token.attempt || confirmed_early.is_some() {
+                return Ok(false);
+            }
+            *confirmed_early = Some(confirmation);
+            return Ok(true);
+        }
+        let message = match queue.awaiting_ack.get(&token.message_id) {
+            Some((_, message)) if message.attempts + 1 == token.attempt => queue.awaiting_ack.remove(&token.message_id).map(|(_, message)| message),
+            _ => None,
+        };
+        drop(queue);
+        match message {
+            Some(message) => self.shared.finish_attempt(message, confirmation_outcome(confirmation)).map(|_| true),
+            None => Ok(false),
+        }
+    }
+
+    fn expire_leases(&self) -> Result<(), StoreError> {
+        for message in self.shared.pull.take_expired() {
+            let error = DeliveryError::new(DeliveryErrorClass::ResponseTimeout, "the message was not acked before its visibility timeout");
+            self.shared.finish_attempt(message, AttemptOutcome::Failed(error))?;
+        }
+        Ok(())
+    }
+
+    /// Return the Clock used to schedule the retries
+    pub fn clock(&self) -> &Arc<dyn Clock> {
+        &self.shared.clock
+    }
+
+    /// Return how many items wait in each stage of the pipeline: the due messages waiting for a
+    /// worker to claim them, the attempts being delivered and the writes not persisted yet
+    pub fn queue_depths(&self) -> BTreeMap<PipelineStage, u64> {
+        let queue = self.shared.queue.lock().unwrap();
+        BTreeMap::from([
+            (PipelineStage::Claim, queue.due.len() as u64),
+            (PipelineStage::Deliver, queue.in_flight.values().sum::<usize>() as u64),
+            (PipelineStage::Persist, self.shared.stats.pipeline.pending_writes()),
+        ])
+    }
+
+    /// Return how many workers send the messages, 0 after the processor was shut down
+    pub fn workers_count(&self) -> usize {
+        self.workers.lock().unwrap().len()
+    }
+
+    /// Return the counters of this processor
+    pub fn stats(&self) -> &ProcessorStats {
+        &self.shared.stats
+    }
+
+    /// Write all the pending writes into the store
+    pub fn flush(&self) -> Result<(), StoreError> {
+        self.shared.writer.flush()
+    }
+
+    /// Stop all workers, waiting for the attempts in progress, and flush the pending writes.
+    /// Messages still scheduled stay pending in the store
+    pub fn shutdown(&self) -> Result<(), StoreError> {
+        self.shared.running.store(false, Ordering::SeqCst);
+        self.shared.queue_changed.notify_all();
+        let workers: Vec<JoinHandle<()>> = self.workers.lock().unwrap().drain(..).collect();
+        for worker in workers {
+            let _ = worker.join();
+        }
+        self.shared.writer.flush()
+    }
+}
+
+impl Drop for MessageProcessor {
+    fn drop(&mut self) {
+        if let Err(err) = self.shutdown() {
+            log!(Level::Error, "Failed to flush the message processor writes on shutdown: {}", err);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{sync::mpsc, time::{Duration as StdDuration, Instant}};
+
+    use time::Duration;
+
+    use crate::{
+        db::memory::MemoryStore,
+        msgproc::{message::DeliveryError, pipeline::STORE_WRITER, retry::{RetryBudget, RetryPolicy}, topic::TopicSettings},
+        utils::{clock::VirtualClock, time::DurationSequence},
+    };
+
+    use super::*;
+
+    /// A Deliverer that fails the first `failures` attempts of every message
+    struct FlakyDeliverer {
+        failures: u16,
+        attempts: Mutex<HashMap<String, u16>>,
+    }
+
+    impl Deliverer for FlakyDeliverer {
+        fn deliver(&self, message: &Message) -> AttemptOutcome {
+            let mut attempts = self.attempts.lock().unwrap();
+            let attempt = attempts.entry(message.id.clone()).or_default();
+            *attempt += 1;
+            if *attempt <= self.failures {
+                AttemptOutcome::Failed(DeliveryError::from_response(503, "HTTP 503"))
+            } else {
+                AttemptOutcome::Delivered
+            }
+        }
+    }
+
+    fn start(failures: u16, store: Arc<MemoryStore>) -> MessageProcessor {
+        let deliverer = Arc::new(FlakyDeliverer { failures, attempts: Mutex::new(HashMap::new()) });
+        let batch = BatchConfiguration { max_batch_size: 100, flush_interval: StdDuration::from_millis(5) };
+        MessageProcessor::start(4, store, batch, deliverer)
+    }
+
+    fn message(id: &str, max_attempts: u16) -> Message {
+        let mut message = Message::new(id.to_string(), "recipient".to_string(), "service".to_string(), "event".to_string(), vec![]);
+        message.retry_policy = RetryPolicy {
+            interval: Some(DurationSequence::from_vec(vec![Duration::milliseconds(10)]).unwrap()),
+            max_attempts,
+        };
+        message
+    }
+
+    fn wait_until_finished(processor: &MessageProcessor) {
+        let started_at = Instant::now();
+        while processor.stats().outstanding() > 0 {
+            assert!(started_at.elapsed() < StdDuration::from_secs(5), "messages were not processed in time");
+            thread::sleep(StdDuration::from_millis(5));
+        }
+        processor.flush().unwrap();
+    }
+
+    #[test]
+    fn test_if_failed_messages_are_retried_until_delivered() {
+        let store = Arc::new(MemoryStore::new());
+        let processor = start(2, store.clone());
+        processor.publish(message("a", 5)).unwrap();
+        wait_until_finished(&processor);
+
+        let stored = store.get_message("a").unwrap().unwrap();
+        assert_eq!(stored.status, MessageStatus::Delivered);
+        assert_eq!(stored.attempts, 3);
+        assert_eq!(store.get_attempts("a").unwrap().len(), 3);
+    }
+
+    #[test]
+    fn test_if_unfinished_messages_of_the_store_are_recovered() {
+        let store = Arc::new(MemoryStore::new());
+        for (id, status) in [("a", MessageStatus::Pending), ("b", MessageStatus::InFlight), ("c", MessageStatus::Delivered), ("d", MessageStatus::Dead)] {
+            let mut stored = message(id, 0);
+            stored.status = status;
+            store.write(StoreWrite::InsertMessage(Box::new(stored))).unwrap();
+        }
+
+        let processor = start(0, store.clone());
+        let report = processor.recover().unwrap();
+        assert_eq!(report.reset_in_flight, 1);
+        assert_eq!(report.rescheduled, 1);
+        assert_eq!(report.statuses.values().sum::<usize>(), 4);
+        assert_eq!(report.to_string(), "Recovered 1 pending messages and reset 1 in-flight messages to pending (stored pending: 1, inFlight: 1, delivered: 1, dead: 1)");
+        assert_eq!(processor.recovery(), Some(report));
+
+        wait_until_finished(&processor);
+        assert_eq!(processor.stats().delivered.load(Ordering::SeqCst), 2);
+        assert_eq!(store.get_message("b").unwrap().unwrap().status, MessageStatus::Delivered);
+    }
+
+    #[test]
+    fn test_if_messages_get_increasing_sequences_per_topic() {
+        let store = Arc::new(MemoryStore::new());
+        let processor = start(0, store.clone());
+        let mut other_topic = message("c", 0);
+        other_topic.event_id = String::from("other");
+        for message in [message("a", 0), message("b", 0), other_topic] {
+            processor.publish(message).unwrap();
+        }
+        wait_until_finished(&processor);
+        processor.shutdown().unwrap();
+
+        let sequence = |id: &str| store.get_message(id).unwrap().unwrap().sequence;
+        assert_eq!((sequence("a"), sequence("b"), sequence("c")), (Some(1), Some(2), Some(1)));
+
+        // a processor started over the same store continues the sequence of the topic
+        let restarted = start(0, store.clone());
+        restarted.publish(message("d", 0)).unwrap();
+        assert_eq!(sequence("d"), Some(3));
+    }
+
+    /// A Deliverer that fails the first attempt of the message `a` and records the delivered ones
+    #[derive(Default)]
+    struct FirstFailsDeliverer {
+        failed: AtomicBool,
+        delivered: Mutex<Vec<String>>,
+    }
+
+    impl Deliverer for FirstFailsDeliverer {
+        fn deliver(&self, message: &Message) -> AttemptOutcome {
+            if message.id == "a" && !self.failed.swap(true, Ordering::SeqCst) {
+                return AttemptOutcome::Failed(DeliveryError::from_response(503, "HTTP 503"));
+            }
+            self.delivered.lock().unwrap().push(message.id.clone());
+            AttemptOutcome::Delivered
+        }
+    }
+
+    #[test]
+    fn test_if_the_messages_of_ordered_topics_wait_for_the_retries_of_the_previous_ones() {
+        let topics = Arc::new(TopicRegistry::new());
+        topics.create("event", TopicSettings { ordering: OrderingMode::Ordered, ..TopicSettings::default() }, OffsetDateTime::now_utc()).unwrap();
+        let deliverer = Arc::new(FirstFailsDeliverer::default());
+        let processor = MessageProcessor::start(4, Arc::new(MemoryStore::new()), BatchConfiguration::default(), deliverer.clone()).with_topics(topics.clone());
+        let mut other_topic = message("c", 5);
+        other_topic.event_id = String::from("other");
+        for message in [message("a", 5), message("b", 5), other_topic] {
+            processor.publish(message).unwrap();
+        }
+        wait_until_finished(&processor);
+        let delivered = deliverer.delivered.lock().unwrap().clone();
+        assert_eq!(delivered.iter().filter(|id| *id != "c").collect::<Vec<_>>(), ["a", "b"]);
+
+        // the messages of a topic that is not ordered anymore are sent as they become due
+        topics.update("event", TopicSettings::default(), OffsetDateTime::now_utc()).unwrap();
+        deliverer.failed.store(false, Ordering::SeqCst);
+        deliverer.delivered.lock().unwrap().clear();
+        for message in [message("a", 5), message("d", 5)] {
+            processor.publish(message).unwrap();
+        }
+        wait_until_finished(&processor);
+        assert_eq!(*deliverer.delivered.lock().unwrap(), ["d", "a"]);
+    }
+
+    /// A Deliverer that records the sequences of the delivered messages, in order
+    #[derive(Default)]
+    struct SequenceDeliverer {
+        sequences: Mutex<Vec<u64>>,
+    }
+
+    impl Deliverer for SequenceDeliverer {
+        fn deliver(&self, message: &Message) -> AttemptOutcome {
+            self.sequences.lock().unwrap().push(message.sequence.unwrap());
+            AttemptOutcome::Delivered
+        }
+    }
+
+    #[test]
+    fn test_if_the_messages_of_ordered_topics_published_concurrently_are_sent_in_order() {
+        let topics = Arc::new(TopicRegistry::new());
+        topics.create("event", TopicSettings { ordering: OrderingMode::Ordered, ..TopicSettings::default() }, OffsetDateTime::now_utc()).unwrap();
+        let deliverer = Arc::new(SequenceDeliverer::default());
+        let processor = MessageProcessor::start(4, Arc::new(MemoryStore::new()), BatchConfiguration::default(), deliverer.clone()).with_topics(topics);
+        thread::scope(|scope| {
+            for publisher in 0..2 {
+                let processor = &processor;
+                scope.spawn(move || {
+                    for index in 0..100 {
+                        processor.publish(message(&format!("{}-{}", publisher, index), 5)).unwrap();
+                    }
+                });
+            }
+        });
+        wait_until_finished(&processor);
+        assert_eq!(*deliverer.sequences.lock().unwrap(), (1..=200).collect::<Vec<u64>>());
+    }
+
+    #[test]
+    fn test_if_message_dies_after_exhausting_retries() {
+        let store = Arc::new(MemoryStore::new());
+        let processor = start(10, store.clone());
+        processor.publish(message("a", 2)).unwrap();
+        wait_until_finished(&processor);
+
+        let stored = store.get_message("a").unwrap().unwrap();
+        assert_eq!(stored.status, MessageStatus::Dead);
+        assert_eq!(stored.attempts, 3);
+        assert_eq!(processor.stats().dead.load(Ordering::SeqCst), 1);
+        assert_eq!(processor.stats().failures(), BTreeMap::from([(DeliveryErrorClass::Http5xx, 3)]));
+    }
+
+    #[test]
+    fn test_if_the_stages_of_the_attempts_are_timed_by_worker() {
+        let store = Arc::new(MemoryStore::new());
+        let processor = start(1, store.clone());
+        for id in ["a", "b", "c"] {
+            processor.publish(message(id, 3)).unwrap();
+        }
+        wait_until_finished(&processor);
+        processor.flush().unwrap();
+
+        let histograms = processor.stats().pipeline.histograms();
+        let count = |stage: PipelineStage| histograms.iter().filter(|((worker, timed), _)| *timed == stage && worker != STORE_WRITER).map(|(_, histogram)| histogram.count).sum::<u64>();
+        assert_eq!((count(PipelineStage::Claim), count(PipelineStage::Deliver), count(PipelineStage::Persist)), (6, 6, 6));
+        // the deliverer does not tell the transformation apart
+        assert_eq!(count(PipelineStage::Transform), 0);
+        assert!(histograms.keys().all(|(worker, _)| worker == STORE_WRITER || worker.parse::<usize>().is_ok_and(|index| index < 4)));
+        assert!(histograms.get(&(String::from(STORE_WRITER), PipelineStage::Persist)).is_some_and(|batches| batches.count > 0));
+        let depths = processor.queue_depths();
+        assert_eq!((depths[&PipelineStage::Claim], depths[&PipelineStage::Persist]), (0, 0));
+    }
+
+    #[test]
+    fn test_if_destinations_with_an_old_backlog_are_lagging_until_they_catch_up() {
+        let store = Arc::new(MemoryStore::new());
+        let thresholds = LagThresholds { backlog_age: Some(StdDuration::from_secs(60)), latency: None, demote: true };
+        let processor = start(0, store).with_lag_thresholds(thresholds);
+        assert_eq!(processor.lag("recipient"), None);
+
+        // due an hour ago, like the backlog recovered after an outage
+        let mut old = message("old", 0);
+        old.next_attempt_at = Some(old.created_at - Duration::hours(1));
+        processor.publish(old).unwrap();
+        wait_until_finished(&processor);
+        let lag = processor.lag("recipient").unwrap();
+        assert!(lag.lagging && lag.backlog_age >= StdDuration::from_secs(3600));
+        assert!(lag.lagging_since.is_some());
+
+        processor.publish(message("new", 0)).unwrap();
+        wait_until_finished(&processor);
+        let lag = processor.lag("recipient").unwrap();
+        assert!(!lag.lagging && lag.backlog_age < StdDuration::from_secs(60));
+        assert_eq!(processor.lags().len(), 1);
+    }
+
+    #[test]
+    fn test_if_counters_are_kept_by_namespace_and_topic() {
+        let store = Arc::new(MemoryStore::new());
+        let processor = start(1, store.clone());
+        let mut retried = message("a", 5);
+        retried.payload = b"{\"a\":1}".to_vec();
+        let mut other_topic = message("b", 0);
+        other_topic.event_id = String::from("other");
+        other_topic.payload = b"{}".to_vec();
+        processor.publish(retried).unwrap();
+        processor.publish(other_topic).unwrap();
+        wait_until_finished(&processor);
+
+        let topics = processor.stats().topics();
+        let counters = |topic: &str| topics[&(String::from("service"), String::from(topic))];
+        assert_eq!(counters("event"), TopicCounters { messages_in: 1, bytes_in: 7, delivered: 1, dead: 0 });
+        assert_eq!(counters("other"), TopicCounters { messages_in: 1, bytes_in: 2, delivered: 0, dead: 1 });
+    }
+
+    #[test]
+    fn test_if_forced_messages_are_counted_as_finished_and_started_again() {
+        let store = Arc::new(MemoryStore::new());
+        let processor = start(0, store.clone());
+        let mut stuck = message("a", 0);
+        stuck.next_attempt_at = Some(OffsetDateTime::now_utc() + Duration::hours(1));
+        processor.publish(stuck).unwrap();
+        processor.flush().unwrap();
+        // in flight on a node that crashed
+        store.write(StoreWrite::UpdateStatus { message_id: String::from("a"), status: MessageStatus::InFlight, next_attempt_at: None }).unwrap();
+        assert_eq!(processor.stats().outstanding(), 1);
+
+        processor.force_transition("a", MessageState::Dead, "the node crashed", None).unwrap();
+        processor.flush().unwrap();
+        assert_eq!(processor.stats().outstanding(), 0);
+        assert_eq!(processor.stats().dead.load(Ordering::SeqCst), 1);
+        assert_eq!(processor.stats().topics()[&(String::from("service"), String::from("event"))].dead, 1);
+
+        // sent again, out of its final state
+        processor.force_transition("a", MessageState::Scheduled, "the receiver was fixed", None).unwrap();
+        assert_eq!(processor.stats().recovered.load(Ordering::SeqCst), 1);
+        wait_until_finished(&processor);
+        assert_eq!(processor.stats().delivered.load(Ordering::SeqCst), 1);
+        assert_eq!(store.get_message("a").unwrap().unwrap().status, MessageStatus::Delivered);
+    }
+
+    #[test]
+    fn test_if_messages_that_fail_to_be_processed_are_scheduled_again() {
+        let store = Arc::new(MemoryStore::new());
+        let processor = start(0, store.clone());
+        // a delivered message can not be sent, so its transition is refused
+        let mut delivered = message("a", 0);
+        delivered.status = MessageStatus::Delivered;
+        processor.adopt(Handoff { recipient_id: String::from("recipient"), messages: vec![delivered] });
+        thread::sleep(StdDuration::from_millis(100));
+
+        let handoff = processor.release("recipient").unwrap();
+        assert_eq!(handoff.messages.len(), 1);
+        assert!(handoff.messages[0].next_attempt_at.is_some_and(|due_at| due_at > OffsetDateTime::now_utc() + Duration::seconds(4)));
+    }
+
+    #[test]
+    fn test_if_scheduled_messages_stay_pending_on_shutdown() {
+        let store = Arc::new(MemoryStore::new());
+        let processor = start(0, store.clone());
+        let mut scheduled = message("a", 0);
+        scheduled.next_attempt_at = Some(OffsetDateTime::now_utc() + Duration::hours(1));
+        processor.publish(scheduled).unwrap();
+        processor.shutdown().unwrap();
+
+        assert_eq!(store.get_message("a").unwrap().unwrap().status, MessageStatus::Pending);
+        assert!(processor.publish(message("b", 0)).is_err());
+    }
+
+    /// A Deliverer that records the recipients of the attempts, in order
+    #[derive(Default)]
+    struct RecordingDeliverer {
+        recipients: Mutex<Vec<String>>,
+    }
+
+    impl Deliverer for RecordingDeliverer {
+        fn deliver(&self, message: &Message) -> AttemptOutcome {
+            self.recipients.lock().unwrap().push(message.recipient_id.clone());
+            AttemptOutcome::Delivered
+        }
+    }
+
+    #[test]
+    fn test_if_due_messages_are_sent_in_turns_by_destination() {
+        let store = Arc::new(MemoryStore::new());
+        let start_time = OffsetDateTime::from_unix_timestamp(1_704_067_200).unwrap();
+        let clock = Arc::new(VirtualClock::new(start_time));
+        let deliverer = Arc::new(RecordingDeliverer::default());
+        let processor = MessageProcessor::start_with_clock(1, store, BatchConfiguration::default(), deliverer.clone(), clock.clone());
+
+        // every message becomes due at the same time, the small destinations after the backlog
+        for (index, recipient_id) in std::iter::repeat_n("backlog", 100).chain(["small", "other"]).enumerate() {
+            let mut message = Message::new_at(index.to_string(), recipient_id.to_string(), "service".to_string(), "event".to_string(), vec![], start_time);
+            message.next_attempt_at = Some(start_time + Duration::minutes(1));
+            processor.publish(message).unwrap();
+        }
+        clock.advance(Duration::minutes(1));
+        wait_until_finished(&processor);
+
+        let recipients = deliverer.recipients.lock().unwrap();
+        assert_eq!(recipients.len(), 102);
+        assert_eq!(recipients[..3], ["backlog", "small", "other"]);
+    }
+
+    #[test]
+    fn test_if_virtual_clock_fast_forwards_days_of_retries() {
+        let store = Arc::new(MemoryStore::new());
+        let start_time = OffsetDateTime::from_unix_timestamp(1_704_067_200).unwrap();
+        let clock = Arc::new(VirtualClock::new(start_time));
+        let deliverer = Arc::new(FlakyDeliverer { failures: 3, attempts: Mutex::new(HashMap::new()) });
+        let batch = BatchConfiguration { max_batch_size: 100, flush_interval: StdDuration::from_millis(5) };
+        let processor = MessageProcessor::start_with_clock(2, store.clone(), batch, deliverer, clock.clone());
+
+        let mut scheduled = Message::new_at("a".to_string(), "recipient".to_string(), "service".to_string(), "event".to_string(), vec![], start_time);
+        scheduled.retry_policy = RetryPolicy {
+            interval: Some(DurationSequence::from_vec(vec![Duration::hours(1), Duration::days(1)]).unwrap()),
+            max_attempts: 5,
+        };
+        processor.publish(scheduled).unwrap();
+
+        let wait_for_attempts = |attempts: u64| {
+            let started_at = Instant::now();
+            while processor.stats().attempts.load(Ordering::SeqCst) < attempts {
+                assert!(started_at.elapsed() < StdDuration::from_secs(5), "attempts were not made in time");
+                thread::sleep(StdDuration::from_millis(1));
+            }
+            processor.flush().unwrap();
+        };
+
+        wait_for_attempts(1);
+        assert_eq!(store.get_message("a").unwrap().unwrap().next_attempt_at, Some(start_time + Duration::hours(1)));
+
+        // the retry is not sent before the clock reaches it
+        clock.advance(Duration::minutes(59));
+        thread::sleep(StdDuration::from_millis(20));
+        assert_eq!(processor.stats().attempts.load(Ordering::SeqCst), 1);
+
+        clock.advance(Duration::minutes(1));
+        wait_for_attempts(2);
+        clock.advance(Duration::days(1));
+        wait_for_attempts(3);
+        clock.advance(Duration::days(1));
+        wait_for_attempts(4);
+
+        let attempts = store.get_attempts("a").unwrap();
+        assert_eq!(attempts[3].finished_at, start_time + Duration::hours(49));
+        assert_eq!(attempts[3].outcome, AttemptOutcome::Delivered);
+        assert_eq!(store.get_message("a").unwrap().unwrap().status, MessageStatus::Delivered);
+    }
+
+    #[test]
+    fn test_if_retries_are_not_affected_by_wall_clock_jumps() {
+        let store = Arc::new(MemoryStore::new());
+        let start_time = OffsetDateTime::from_unix_timestamp(1_704_067_200).unwrap();
+        let clock = Arc::new(VirtualClock::new(start_time));
+        let deliverer = Arc::new(FlakyDeliverer { failures: 2, attempts: Mutex::new(HashMap::new()) });
+        let batch = BatchConfiguration { max_batch_size: 100, flush_interval: StdDuration::from_millis(5) };
+        let processor = MessageProcessor::start_with_clock(1, store.clone(), batch, deliverer, clock.clone());
+
+        let mut scheduled = Message::new_at("a".to_string(), "recipient".to_string(), "service".to_string(), "event".to_string(), vec![], start_time);
+        scheduled.retry_policy = RetryPolicy {
+            interval: Some(DurationSequence::from_vec(vec![Duration::hours(1)]).unwrap()),
+            max_attempts: 5,
+        };
+        processor.publish(scheduled).unwrap();
+
+        let attempts = || processor.stats().attempts.load(Ordering::SeqCst);
+        let wait_for_attempts = |expected: u64| {
+            let started_at = Instant::now();
+            while attempts() < expected {
+                assert!(started_at.elapsed() < StdDuration::from_secs(5), "attempts were not made in time");
+                thread::sleep(StdDuration::from_millis(1));
+            }
+        };
+        wait_for_attempts(1);
+
+        // a forward jump of the wall clock does not make the retry happen early
+        clock.set(start_time + Duration::days(1));
+        thread::sleep(StdDuration::from_millis(20));
+        assert_eq!(attempts(), 1);
+        clock.advance(Duration::hours(1));
+        wait_for_attempts(2);
+
+        // a backward jump of the wall clock does not delay the retry
+        clock.set(start_time - Duration::days(1));
+        clock.advance(Duration::hours(1));
+        wait_for_attempts(3);
+        processor.flush().unwrap();
+        assert_eq!(store.get_message("a").unwrap().unwrap().status, MessageStatus::Delivered);
+    }
+
+    #[test]
+    fn test_if_duplicated_producer_message_ids_are_dropped_inside_the_window() {
+        let store = Arc::new(MemoryStore::new());
+        let start_time = OffsetDateTime::from_unix_timestamp(1_704_067_200).unwrap();
+        let clock = Arc::new(VirtualClock::new(start_time));
+        let deliverer = Arc::new(FlakyDeliverer { failures: 0, attempts: Mutex::new(HashMap::new()) });
+        let batch = BatchConfiguration { max_batch_size: 100, flush_interval: StdDuration::from_millis(5) };
+        let processor = MessageProcessor::start_with_clock(1, store.clone(), batch, deliverer, clock.clone())
+            .with_dedup_window(Duration::hours(1));
+
+        let produced = |id: &str, event_id: &str| {
+            let mut message = Message::new_at(id.to_string(), "recipient".to_string(), "service".to_string(), event_id.to_string(), vec![], clock.now());
+            message.producer_message_id = Some(String::from("order-1"));
+            message
+        };
+
+        assert_eq!(processor.publish(produced("a", "created")).unwrap(), PublishOutcome::Accepted);
+        match processor.publish(produced("b", "created")).unwrap() {
+            PublishOutcome::Duplicate(original) => assert_eq!(original.id, "a"),
+            outcome => panic!("the duplicated message was not dropped: {:?}", outcome),
+        }
+        // the same producer message ID on another topic is not a duplicate
+        assert_eq!(processor.publish(produced("c", "updated")).unwrap(), PublishOutcome::Accepted);
+
+        clock.advance(Duration::hours(1));
+        assert_eq!(processor.publish(produced("d", "created")).unwrap(), PublishOutcome::Accepted);
+        assert!(store.get_message("b").unwrap().is_none());
+        assert_eq!(processor.stats().published.load(Ordering::SeqCst), 3);
+    }
+
+    /// A Deliverer that blocks the first attempt of message "a" until resumed and then fails it
+    struct GatedDeliverer {
+        entered: Mutex<mpsc::Sender<()>>,
+        resume: Mutex<mpsc::Receiver<()>>,
+        attempted: Mutex<Vec<String>>,
+    }
+
+    impl Deliverer for GatedDeliverer {
+        fn deliver(&self, message: &Message) -> AttemptOutcome {
+            let first = !self.attempted.lock().unwrap().contains(&message.id);
+            self.attempted.lock().unwrap().push(message.id.clone());
+            if message.id != "a" || !first {
+                return AttemptOutcome::Delivered;
+            }
+            self.entered.lock().unwrap().send(()).unwrap();
+            self.resume.lock().unwrap().recv().unwrap();
+            AttemptOutcome::Failed(DeliveryError::from_response(503, "HTTP 503"))
+        }
+    }
+
+    #[test]
+    fn test_if_released_destinations_hand_off_their_in_flight_and_scheduled_messages() {
+        let store = Arc::new(MemoryStore::new());
+        let start_time = OffsetDateTime::from_unix_timestamp(1_704_067_200).unwrap();
+        let clock = Arc::new(VirtualClock::new(start_time));
+        let (entered, entered_receiver) = mpsc::channel();
+        let (resume, resume_receiver) = mpsc::channel();
+        let gated = Arc::new(GatedDeliverer { entered: Mutex::new(entered), resume: Mutex::new(resume_receiver), attempted: Mutex::new(Vec::new()) });
+        let batch = BatchConfiguration { max_batch_size: 100, flush_interval: StdDuration::from_millis(5) };
+        let previous = MessageProcessor::start_with_clock(2, store.clone(), batch, gated.clone(), clock.clone());
+        let recording = Arc::new(RecordingDeliverer::default());
+        let next = MessageProcessor::start_with_clock(2, store.clone(), batch, recording.clone(), clock.clone());
+
+        let scheduled = |id: &str, recipient_id: &str, due_in: Duration| {
+            let mut message = Message::new_at(id.to_string(), recipient_id.to_string(), "service".to_string(), "event".to_string(), vec![], start_time);
+            message.next_attempt_at = Some(start_time + due_in);
+            message.retry_policy = RetryPolicy { interval: Some(DurationSequence::from_vec(vec![Duration::minutes(1)]).unwrap()), max_attempts: 3 };
+            message
+        };
+        previous.publish(scheduled("a", "moved", Duration::ZERO)).unwrap();
+        previous.publish(scheduled("b", "moved", Duration::hours(1))).unwrap();
+        previous.publish(scheduled("c", "stays", Duration::ZERO)).unwrap();
+        entered_receiver.recv_timeout(StdDuration::from_secs(5)).unwrap();
+
+        let handoff = thread::scope(|scope| {
+            let release = scope.spawn(|| previous.release("moved"));
+            // the release waits for the attempt in progress, whose retry is handed off
+            thread::sleep(StdDuration::from_millis(20));
+            assert!(!release.is_finished());
+            resume.send(()).unwrap();
+            release.join().unwrap().unwrap()
+        });
+        let handed_off: Vec<(&str, Option<OffsetDateTime>)> = handoff.messages.iter().map(|message| (message.id.as_str(), message.next_attempt_at)).collect();
+        assert_eq!(handed_off, vec![("a", Some(start_time + Duration::minutes(1))), ("b", Some(start_time + Duration::hours(1)))]);
+        assert_eq!(store.get_message("a").unwrap().unwrap().status, MessageStatus::Pending);
+
+        assert_eq!(next.adopt(handoff), 2);
+        clock.advance(Duration::hours(1));
+        wait_until_finished(&next);
+        wait_until_finished(&previous);
+
+        // each attempt was made by one of the processors
+        assert_eq!(*recording.recipients.lock().unwrap(), vec!["moved", "moved"]);
+        assert_eq!(*gated.attempted.lock().unwrap(), vec!["a", "c"]);
+        assert_eq!(store.get_attempts("a").unwrap().len(), 2);
+        assert_eq!(store.get_message("b").unwrap().unwrap().status, MessageStatus::Delivered);
+        assert_eq!((previous.stats().handed_off.load(Ordering::SeqCst), next.stats().adopted.load(Ordering::SeqCst)), (2, 2));
+    }
+
+    #[test]
+    fn test_if_messages_published_after_a_release_are_handed_off_by_the_next_one() {
+        let store = Arc::new(MemoryStore::new());
+        let previous_deliverer = Arc::new(RecordingDeliverer::default());
+        let previous = MessageProcessor::start(1, store.clone(), BatchConfiguration::default(), previous_deliverer.clone());
+        let next_deliverer = Arc::new(RecordingDeliverer::default());
+        let next = MessageProcessor::start(1, store.clone(), BatchConfiguration::default(), next_deliverer.clone());
+
+        assert!(previous.release("recipient").unwrap().messages.is_empty());
+        // published with a stale owner of the destination
+        previous.publish(message("late", 0)).unwrap();
+        thread::sleep(StdDuration::from_millis(20));
+        assert!(previous_deliverer.recipients.lock().unwrap().is_empty());
+
+        let handoff = previous.release("recipient").unwrap();
+        assert_eq!(handoff.messages.iter().map(|message| message.id.as_str()).collect::<Vec<_>>(), vec!["late"]);
+        assert_eq!(next.adopt(handoff), 1);
+        wait_until_finished(&next);
+        assert_eq!(previous.stats().outstanding(), 0);
+        assert_eq!(next_deliverer.recipients.lock().unwrap().len(), 1);
+
+        // the destination moves back to its previous owner
+        next.publish(message("back", 0)).unwrap();
+        wait_until_finished(&next);
+        assert_eq!(previous.adopt(next.release("recipient").unwrap()), 0);
+        previous.publish(message("after", 0)).unwrap();
+        wait_until_finished(&previous);
+        assert_eq!(previous_deliverer.recipients.lock().unwrap().len(), 1);
+        assert_eq!(store.get_message("back").unwrap().unwrap().status, MessageStatus::Delivered);
+    }
+
+    /// A Deliverer whose `deleted` recipient is parked until the time it holds
+    #[derive(Default)]
+    struct ParkingDeliverer {
+        parked_until: Mutex<Option<OffsetDateTime>>,
+        recipients: Mutex<Vec<String>>,
+    }
+
+    impl Deliverer for ParkingDeliverer {
+        fn deliver(&self, message: &Message) -> AttemptOutcome {
+            self.recipients.lock().unwrap().push(message.recipient_id.clone());
+            AttemptOutcome::Delivered
+        }
+
+        fn parked_until(&self, message: &Message) -> Option<OffsetDateTime> {
+            self.parked_until.lock().unwrap().filter(|_| message.recipient_id == "deleted")
+        }
+    }
+
+    /// A Deliverer whose destinations fail every attempt and allow one retry per minute
+    struct FailingBudgetedDeliverer;
+
+    impl Deliverer for FailingBudgetedDeliverer {
+        fn deliver(&self, _message: &Message) -> AttemptOutcome {
+            AttemptOutcome::Failed(DeliveryError::from_response(503, "HTTP 503"))
+        }
+
+        fn retry_budget(&self, _message: &Message) -> Option<RetryBudget> {
+            Some(RetryBudget::new(0.0).with_min_per_minute(1))
+        }
+    }
+
+    #[test]
+    fn test_if_retries_above_the_retry_budget_are_postponed() {
+        let store = Arc::new(MemoryStore::new());
+        let start_time = OffsetDateTime::from_unix_timestamp(1_704_067_200).unwrap();
+        let clock = Arc::new(VirtualClock::new(start_time));
+        let batch = BatchConfiguration { max_batch_size: 100, flush_interval: StdDuration::from_millis(5) };
+        let processor = MessageProcessor::start_with_clock(2, store.clone(), batch, Arc::new(FailingBudgetedDeliverer), clock.clone());
+        for id in ["a", "b", "c"] {
+            let mut message = Message::new_at(id.to_string(), "recipient".to_string(), "service".to_string(), "event".to_string(), vec![], start_time);
+            message.retry_policy = RetryPolicy { interval: Some(DurationSequence::from_vec(vec![Duration::seconds(1)]).unwrap()), max_attempts: 1 };
+            processor.publish(message).unwrap();
+        }
+
+        let attempts = || processor.stats().attempts.load(Ordering::SeqCst);
+        let wait_for_attempts = |expected: u64| {
+            let started_at = Instant::now();
+            while attempts() < expected {
+                assert!(started_at.elapsed() < StdDuration::from_secs(5), "attempts were not made in time");
+                thread::sleep(StdDuration::from_millis(1));
+            }
+        };
+        wait_for_attempts(3);
+        // one of the three retries fits the budget
+        clock.advance(Duration::seconds(1));
+        wait_for_attempts(4);
+        thread::sleep(StdDuration::from_millis(20));
+        assert_eq!(attempts(), 4);
+        assert_eq!(processor.stats().retry_budget_exhausted().get("recipient"), Some(&2));
+        processor.flush().unwrap();
+        let postponed = store.find_messages(&MessageQuery { status: Some(MessageStatus::Pending), ..MessageQuery::default() }).unwrap();
+        assert_eq!(postponed.len(), 2);
+        assert!(postponed.iter().all(|message| message.next_attempt_at == Some(start_time + Duration::seconds(60))), "{:?}", postponed);
+
+        // the retry of the first minute left the window
+        clock.advance(Duration::seconds(60));
+        wait_for_attempts(5);
+        thread::sleep(StdDuration::from_millis(20));
+        assert_eq!(attempts(), 5);
+        assert_eq!(processor.stats().retry_budget_exhausted().get("recipient"), Some(&3));
+    }
+
+    /// A Deliverer whose destination takes some time to answer and allows 3 attempts at once,
+    /// recording the most attempts it received at once
+    struct ConcurrentDeliverer {
+        overloaded: AtomicBool,
+        in_flight: AtomicU64,
+        most_in_flight: AtomicU64,
+    }
+
+    impl Deliverer for ConcurrentDeliverer {
+        fn deliver(&self, _message: &Message) -> AttemptOutcome {
+            let in_flight = self.in_flight.fetch_add(1, Ordering::SeqCst) + 1;
+            self.most_in_flight.fetch_max(in_flight, Ordering::SeqCst);
+            thread::sleep(StdDuration::from_millis(5));
+            self.in_flight.fetch_sub(1, Ordering::SeqCst);
+            match self.overloaded.load(Ordering::SeqCst) {
+                true => AttemptOutcome::Failed(DeliveryError::from_response(503, "HTTP 503")),
+                false => AttemptOutcome::Delivered,
+            }
+        }
+
+        fn max_concurrency(&self, _message: &Message) -> Option<usize> {
+            Some(3)
+        }
+    }
+
+    #[test]
+    fn test_if_the_attempts_sent_at_once_are_limited_and_back_off_on_overload() {
+        let deliverer = Arc::new(ConcurrentDeliverer { overloaded: AtomicBool::new(false), in_flight: AtomicU64::new(0), most_in_flight: AtomicU64::new(0) });
+        let batch = BatchConfiguration { max_batch_size: 100, flush_interval: StdDuration::from_millis(5) };
+        let processor = MessageProcessor::start(8, Arc::new(MemoryStore::new()), batch, deliverer.clone());
+        for index in 0..30 {
+            processor.publish(message(&format!("m-{}", index), 1)).unwrap();
+        }
+        wait_until_finished(&processor);
+        assert!(deliverer.most_in_flight.load(Ordering::SeqCst) <= 3);
+        assert_eq!(processor.concurrency("recipient").unwrap().max, 3);
+
+        // the failures of a overloaded receiver lower the attempts sent at once
+        deliverer.overloaded.store(true, Ordering::SeqCst);
+        for index in 0..30 {
+            processor.publish(message(&format!("o-{}", index), 1)).unwrap();
+        }
+        wait_until_finished(&processor);
+        assert_eq!(processor.concurrency("recipient").unwrap().limit(), 1);
+        assert_eq!(processor.concurrencies().len(), 1);
+    }
+
+    #[test]
+    fn test_if_messages_of_deleted_destinations_are_parked_until_restored_or_purged() {
+        let store = Arc::new(MemoryStore::new());
+        let start_time = OffsetDateTime::from_unix_timestamp(1_704_067_200).unwrap();
+        let clock = Arc::new(VirtualClock::new(start_time));
+        let deliverer = Arc::new(ParkingDeliverer::default());
+        *deliverer.parked_until.lock().unwrap() = Some(start_time + Duration::hours(1));
+        let batch = BatchConfiguration { max_batch_size: 100, flush_interval: StdDuration::from_millis(5) };
+        let processor = MessageProcessor::start_with_clock(2, store.clone(), batch, deliverer.clone(), clock.clone());
+
+        let publish = |id: &str, recipient_id: &str| {
+            let message = Message::new_at(id.to_string(), recipient_id.to_string(), "service".to_string(), "event".to_string(), vec![], start_time);
+            processor.publish(message).unwrap();
+        };
+        publish("a", "deleted");
+        publish("b", "deleted");
+        publish("c", "other");
+        thread::sleep(StdDuration::from_millis(20));
+        processor.flush().unwrap();
+        assert_eq!(*deliverer.recipients.lock().unwrap(), vec!["other"]);
+        assert_eq!(processor.stats().outstanding(), 2);
+        // the parked messages were not attempted
+        assert_eq!(store.get_message("a").unwrap().unwrap().status, MessageStatus::Pending);
+        assert!(store.get_attempts("a").unwrap().is_empty());
+
+        // the destination was restored
+        *deliverer.parked_until.lock().unwrap() = None;
+        assert_eq!(processor.unpark("deleted"), 2);
+        wait_until_finished(&processor);
+        assert_eq!(deliverer.recipients.lock().unwrap().len(), 3);
+
+        // the destination was deleted again and purged at the end of the grace period
+        *deliverer.parked_until.lock().unwrap() = Some(start_time + Duration::hours(1));
+        publish("d", "deleted");
+        thread::sleep(StdDuration::from_millis(20));
+        assert_eq!(deliverer.recipients.lock().unwrap().len(), 3);
+        clock.advance(Duration::hours(1));
+        wait_until_finished(&processor);
+        assert_eq!(deliverer.recipients.lock().unwrap().len(), 4);
+        assert_eq!(processor.unpark("deleted"), 0);
+    }
+}
@@ -0,0 +1,178 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    sync::{Condvar, Mutex},
+    time::{Duration, Instant},
+};
+
+use crate::utils::random::uuid_v4;
+
+use super::message::{Message, MessageStatus};
+
+/// The maximum amount of messages returned by a single pull
+pub const MAX_PULL_MESSAGES: usize = 100;
+
+/// The maximum time a pull waits for messages
+pub const MAX_PULL_WAIT: Duration = Duration::from_secs(30);
+
+/// How long a pulled message stays invisible when the consumer does not set it
+pub const DEFAULT_VISIBILITY_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// A message leased to the consumer of a pull destination. It is invisible to the other pulls
+/// until it is acked, nacked or its visibility timeout expires
+#[derive(Debug, Clone, PartialEq)]
+pub struct PulledMessage {
+    /// The handle used to ack or nack the message
+    pub receipt: String,
+    pub message: Message,
+}
+
+struct Lease {
+    message: Message,
+    expires_at: Instant,
+}
+
+#[derive(Default)]
+struct PullState {
+    ready: VecDeque<Message>,
+    leases: HashMap<String, Lease>,
+}
+
+/// The messages of the pull destinations that are due, waiting to be pulled, and the ones leased
+/// to consumers
+#[derive(Default)]
+pub struct PullQueue {
+    state: Mutex<PullState>,
+    message_ready: Condvar,
+}
+
+impl PullQueue {
+    pub fn new() -> PullQueue {
+        PullQueue::default()
+    }
+
+    /// Make the message available to the pulls of its topic
+    pub fn push(&self, message: Message) {
+        self.state.lock().unwrap().ready.push_back(message);
+        self.message_ready.notify_all();
+    }
+
+    /// Lease up to `max` messages of the topic, oldest first, waiting up to `wait` until at least
+    /// one is available. Only the messages of the recipient are leased when it is given
+    pub fn lease(&self, topic: &str, recipient_id: Option<&str>, max: usize, wait: Duration, visibility: Duration) -> Vec<PulledMessage> {
+        let matches = |message: &Message| message.topic() == topic && recipient_id.is_none_or(|id| message.recipient_id == id);
+        let deadline = Instant::now() + wait;
+        let mut state = self.state.lock().unwrap();
+        loop {
+            if state.ready.iter().any(matches) {
+                break;
+            }
+            let Some(remaining) = deadline.checked_duration_since(Instant::now()).filter(|remaining| !remaining.is_zero()) else {
+                return Vec::new();
+            };
+            state = self.message_ready.wait_timeout(state, remaining).unwrap().0;
+        }
+
+        let mut pulled = Vec::new();
+        let mut index = 0;
+        while pulled.len() < max && index < state.ready.len() {
+            if !matches(&state.ready[index]) {
+                index += 1;
+                continue;
+            }
+            let message = state.ready.remove(index).expect("the index is inside the queue");
+            let receipt = uuid_v4();
+            // the leased message is in flight, so its ack finishes the attempt
+            let in_flight = Message { status: MessageStatus::InFlight, ..message.clone() };
+            state.leases.insert(receipt.clone(), Lease { message: in_flight, expires_at: Instant::now() + visibility });
+            pulled.push(PulledMessage { receipt, message });
+        }
+        pulled
+    }
+
+    /// Remove the lease of a message of the topic, returning the message. None when the receipt is
+    /// unknown, belongs to another topic or its visibility timeout expired
+    pub fn release(&self, topic: &str, receipt: &str) -> Option<Message> {
+        let mut state = self.state.lock().unwrap();
+        match state.leases.get(receipt) {
+            Some(lease) if lease.message.topic() == topic && lease.expires_at > Instant::now() => {
+                state.leases.remove(receipt).map(|lease| lease.message)
+            }
+            _ => None,
+        }
+    }
+
+    /// Remove the message, ready or leased, returning it
+    pub fn remove(&self, message_id: &str) -> Option<Message> {
+        let mut state = self.state.lock().unwrap();
+        if let Some(index) = state.ready.iter().position(|message| message.id == message_id) {
+            return state.ready.remove(index);
+        }
+        let receipt = state.leases.iter().find(|(_, lease)| lease.message.id == message_id).map(|(receipt, _)| receipt.clone())?;
+        state.leases.remove(&receipt).map(|lease| lease.message)
+    }
+
+    /// Remove the leases whose visibility timeout expired, returning their messages
+    pub fn take_expired(&self) -> Vec<Message> {
+        let now = Instant::now();
+        let mut state = self.state.lock().unwrap();
+        let expired: Vec<String> = state.leases.iter()
+            .filter(|(_, lease)| lease.expires_at <= now)
+            .map(|(receipt, _)| receipt.clone())
+            .collect();
+        expired.into_iter().filter_map(|receipt| state.leases.remove(&receipt)).map(|lease| lease.message).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{sync::Arc, thread};
+
+    use super::*;
+
+    fn message(id: &str, recipient_id: &str, topic: &str) -> Message {
+        Message::new(id.to_string(), recipient_id.to_string(), "service".to_string(), topic.to_string(), vec![])
+    }
+
+    #[test]
+    fn test_if_ready_messages_are_leased_once_and_released_by_their_receipt() {
+        let queue = PullQueue::new();
+        queue.push(message("a", "r1", "order.created"));
+        queue.push(message("b", "r2", "order.created"));
+        queue.push(message("c", "r1", "order.paid"));
+
+        let pulled = queue.lease("order.created", Some("r1"), 10, Duration::ZERO, Duration::from_secs(30));
+        assert_eq!(pulled.iter().map(|pulled| pulled.message.id.as_str()).collect::<Vec<_>>(), vec!["a"]);
+        assert!(queue.lease("order.created", Some("r1"), 10, Duration::ZERO, Duration::from_secs(30)).is_empty());
+
+        assert_eq!(queue.release("order.paid", &pulled[0].receipt), None);
+        assert_eq!(queue.release("order.created", &pulled[0].receipt).map(|message| message.id), Some(String::from("a")));
+        assert_eq!(queue.release("order.created", &pulled[0].receipt), None);
+        assert_eq!(queue.lease("order.created", None, 1, Duration::ZERO, Duration::from_secs(30)).len(), 1);
+    }
+
+    #[test]
+    fn test_if_expired_leases_can_not_be_released() {
+        let queue = PullQueue::new();
+        queue.push(message("a", "r1", "order.created"));
+        let pulled = queue.lease("order.created", None, 10, Duration::ZERO, Duration::from_millis(10));
+
+        thread::sleep(Duration::from_millis(20));
+        assert_eq!(queue.release("order.created", &pulled[0].receipt), None);
+        assert_eq!(queue.take_expired().iter().map(|message| message.id.as_str()).collect::<Vec<_>>(), vec!["a"]);
+        assert!(queue.take_expired().is_empty());
+    }
+
+    #[test]
+    fn test_if_lease_waits_for_a_message_to_be_pushed() {
+        let queue = Arc::new(PullQueue::new());
+        let pusher = queue.clone();
+        let handle = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(20));
+            pusher.push(message("a", "r1", "order.created"));
+        });
+
+        let pulled = queue.lease("order.created", None, 10, Duration::from_secs(5), Duration::from_secs(30));
+        assert_eq!(pulled.len(), 1);
+        handle.join().unwrap();
+    }
+}
@@ -0,0 +1,228 @@
+use std::{
+    collections::HashSet,
+    sync::Arc,
+    thread,
+    time::{Duration, Instant},
+};
+
+use crate::{
+    db::{MessageQuery, MessageStore, StoreError},
+    log,
+    syscom::jobs::{Job, JobContext, JobRegistry},
+    utils::log::Level,
+};
+
+use super::{
+    message::{AttemptOutcome, DeliveryErrorClass, Message, MessageStatus},
+    processor::{MessageProcessor, PublishOutcome},
+};
+
+/// How many messages per second are replayed when the request does not set a rate
+pub const DEFAULT_REPLAY_RATE: f64 = 100.0;
+
+/// Select which dead messages are replayed
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ReplayFilter {
+    /// The store filters. The status is always `dead`
+    pub query: MessageQuery,
+    /// Only match messages whose last attempt failed with this class
+    pub error_class: Option<DeliveryErrorClass>,
+}
+
+/// Return the dead messages that match the filter ordered by their creation time
+pub fn find_dead_messages(store: &dyn MessageStore, filter: &ReplayFilter) -> Result<Vec<Message>, StoreError> {
+    let query = MessageQuery {
+        status: Some(MessageStatus::Dead),
+        // the limit is applied after the error class filter
+        limit: if filter.error_class.is_some() { None } else { filter.query.limit },
+        ..filter.query.clone()
+    };
+    let messages = store.find_messages(&query)?;
+    let Some(error_class) = &filter.error_class else {
+        return Ok(messages);
+    };
+
+    let mut matched = Vec::new();
+    for message in messages {
+        let attempts = store.get_attempts(&message.id)?;
+        let last_class = attempts.iter().max_by_key(|attempt| attempt.attempt).and_then(|attempt| match &attempt.outcome {
+            AttemptOutcome::Failed(error) => Some(error.class),
+            AttemptOutcome::Delivered | AttemptOutcome::Filtered | AttemptOutcome::Accepted { .. } => None,
+        });
+        if last_class == Some(*error_class) {
+            matched.push(message);
+            if Some(matched.len()) == filter.query.limit {
+                break;
+            }
+        }
+    }
+    Ok(matched)
+}
+
+/// Select which delivered messages of a topic are copied to a new destination
+#[derive(Debug, Clone, PartialEq)]
+pub struct Backfill {
+    pub namespace: Option<String>,
+    pub topic: String,
+    /// Only copy the messages created at or after this time
+    pub since: time::OffsetDateTime,
+    /// The maximum amount of messages copied
+    pub limit: Option<usize>,
+}
+
+/// Return the delivered messages of the topic that should be copied to the recipient, ordered by
+/// their creation time and addressed to the recipient. The messages of the recipient itself are
+/// not copied and the messages published with the same producer message ID are only copied
+/// once. The messages purged by the retention are not found
+pub fn find_backfill_messages(store: &dyn MessageStore, recipient_id: &str, backfill: &Backfill) -> Result<Vec<Message>, StoreError> {
+    let query = MessageQuery {
+        status: Some(MessageStatus::Delivered),
+        namespace: backfill.namespace.clone(),
+        topic: Some(backfill.topic.clone()),
+        created_after: Some(backfill.since),
+        ..MessageQuery::default()
+    };
+    let mut producer_message_ids = HashSet::new();
+    let messages = store.find_messages(&query)?.into_iter()
+        .filter(|message| message.recipient_id != recipient_id)
+        .filter(|message| match &message.producer_message_id {
+            Some(producer_message_id) => producer_message_ids.insert((message.namespace().to_string(), producer_message_id.clone())),
+            None => true,
+        })
+        .take(backfill.limit.unwrap_or(usize::MAX))
+        .map(|mut message| {
+            message.recipient_id = recipient_id.to_string();
+            message
+        })
+        .collect();
+    Ok(messages)
+}
+
+/// Return a fresh pending copy of the dead message, with the new ID and no attempts, due now
+pub fn fresh_copy(dead: &Message, id: String, now: time::OffsetDateTime) -> Message {
+    let mut message = Message::new_at(id, dead.recipient_id.clone(), dead.service_id.clone(), dead.event_id.clone(), dead.payload.clone(), now);
+    message.retry_policy = dead.retry_policy.clone();
+    message.requested_retry_policy = dead.requested_retry_policy.clone();
+    message.attributes = dead.attributes.clone();
+    message.replayed_from = Some(dead.id.clone());
+    message
+}
+
+/// Publish fresh copies of the dead messages, or of the messages of a backfill, at most `rate`
+/// messages per second, counting the replayed messages as changed by the job and the ones
+/// rejected by the interceptors as only processed. It stops early when the job is cancelled and
+/// fails if the processor stops accepting messages
+pub fn replay_messages(processor: &MessageProcessor, dead_messages: &[Message], rate: f64, job: &JobContext) -> Result<(), String> {
+    let interval = Duration::from_secs_f64(1.0 / rate);
+    let started_at = Instant::now();
+    job.set_total(dead_messages.len());
+    for (index, dead) in dead_messages.iter().enumerate() {
+        if let Some(wait) = (started_at + interval.mul_f64(index as f64)).checked_duration_since(Instant::now()) {
+            thread::sleep(wait);
+        }
+        if job.is_cancelled() {
+            return Ok(());
+        }
+        match processor.publish(fresh_copy(dead, processor.next_message_id(), processor.clock().now())) {
+            Ok(PublishOutcome::Rejected(rejection)) => {
+                log!(Level::Warn, "The replay of message {} was rejected: {}", dead.id, rejection);
+                job.advance(1, 0);
+            }
+            Ok(_) => job.advance(1, 1),
+            Err(err) => return Err(format!("stopped replaying messages after {} of {}: {}", index, dead_messages.len(), err)),
+        }
+    }
+    Ok(())
+}
+
+/// Replay the messages on a background thread as a job of the kind, `replay` or `backfill`
+pub fn start_replay(jobs: &Arc<JobRegistry>, kind: &'static str, actor: Option<String>, processor: Arc<MessageProcessor>, dead_messages: Vec<Message>, rate: f64) -> Job {
+    jobs.spawn(kind, actor, move |job| replay_messages(&processor, &dead_messages, rate, job))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        db::{batch::BatchConfiguration, memory::MemoryStore, StoreWrite},
+        msgproc::{delivery::Deliverer, message::{AttemptRecord, DeliveryError}},
+    };
+
+    use super::*;
+
+    struct AlwaysDelivers;
+
+    impl Deliverer for AlwaysDelivers {
+        fn deliver(&self, _: &Message) -> AttemptOutcome {
+            AttemptOutcome::Delivered
+        }
+    }
+
+    fn dead_message(store: &MemoryStore, id: &str, recipient_id: &str, status: u16) {
+        let message = Message::new(id.to_string(), recipient_id.to_string(), "service".to_string(), "event".to_string(), b"{}".to_vec());
+        let attempt = AttemptRecord {
+            message_id: id.to_string(),
+            attempt: 1,
+            finished_at: message.created_at,
+            outcome: AttemptOutcome::Failed(DeliveryError::from_response(status, format!("HTTP {}", status))),
+        };
+        store.write_batch(&[
+            StoreWrite::InsertMessage(Box::new(message)),
+            StoreWrite::RecordAttempt(attempt),
+            StoreWrite::UpdateStatus { message_id: id.to_string(), status: MessageStatus::Dead, next_attempt_at: None },
+        ]).unwrap();
+    }
+
+    #[test]
+    fn test_if_delivered_messages_of_the_topic_are_backfilled_once() {
+        let store = MemoryStore::new();
+        let delivered = |id: &str, recipient_id: &str, topic: &str, producer_message_id: Option<&str>| {
+            let mut message = Message::new(id.to_string(), recipient_id.to_string(), "service".to_string(), topic.to_string(), b"{}".to_vec());
+            message.producer_message_id = producer_message_id.map(String::from);
+            message.status = MessageStatus::Delivered;
+            store.write(StoreWrite::InsertMessage(Box::new(message))).unwrap();
+        };
+        delivered("a", "r1", "order.created", Some("p1"));
+        delivered("b", "r2", "order.created", Some("p1"));
+        delivered("c", "r2", "order.created", None);
+        delivered("d", "new", "order.created", None);
+        delivered("e", "r1", "order.paid", None);
+        dead_message(&store, "f", "r1", 500);
+
+        let backfill = Backfill { namespace: None, topic: String::from("order.created"), since: time::OffsetDateTime::UNIX_EPOCH, limit: None };
+        let messages = find_backfill_messages(&store, "new", &backfill).unwrap();
+        assert_eq!(messages.iter().map(|m| m.id.as_str()).collect::<Vec<_>>(), vec!["a", "c"]);
+        assert!(messages.iter().all(|message| message.recipient_id == "new"));
+
+        let recent = Backfill { since: time::OffsetDateTime::now_utc() + time::Duration::hours(1), ..backfill };
+        assert!(find_backfill_messages(&store, "new", &recent).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_if_filtered_dead_messages_are_replayed_as_fresh_messages() {
+        let store = Arc::new(MemoryStore::new());
+        dead_message(&store, "a", "r1", 503);
+        dead_message(&store, "b", "r1", 400);
+        dead_message(&store, "c", "r2", 500);
+
+        let filter = ReplayFilter {
+            query: MessageQuery { recipient_id: Some(String::from("r1")), ..MessageQuery::default() },
+            error_class: Some(DeliveryErrorClass::Http5xx),
+        };
+        let dead_messages = find_dead_messages(store.as_ref(), &filter).unwrap();
+        assert_eq!(dead_messages.iter().map(|m| m.id.as_str()).collect::<Vec<_>>(), vec!["a"]);
+
+        let processor = Arc::new(MessageProcessor::start(1, store.clone(), BatchConfiguration::default(), Arc::new(AlwaysDelivers)));
+        let jobs = Arc::new(JobRegistry::new(processor.clock().clone()));
+        let job = jobs.track("replay", None);
+        replay_messages(&processor, &dead_messages, 1000.0, &job).unwrap();
+        assert_eq!(jobs.get(job.id()).map(|job| (job.total, job.changed)), Some((1, 1)));
+        processor.flush().unwrap();
+
+        let replayed = store.find_messages(&MessageQuery::default()).unwrap().into_iter()
+            .find(|message| message.replayed_from.as_deref() == Some("a"))
+            .unwrap();
+        assert_ne!(replayed.id, "a");
+        assert_eq!(replayed.payload, b"{}");
+        assert_eq!(store.get_message("a").unwrap().unwrap().status, MessageStatus::Dead);
+    }
+}
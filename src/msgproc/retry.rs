@@ -0,0 +1,380 @@
+use std::{
+    collections::{BTreeSet, HashMap, VecDeque},
+    fmt::Display,
+    sync::Mutex,
+    time::Duration as StdDuration,
+};
+
+use time::{Duration, OffsetDateTime};
+
+use crate::{ctx::config::RetryPolicyConfiguration, utils::time::DurationSequence};
+
+use super::message::{DeliveryError, DeliveryErrorClass};
+
+/// Define when a message that failed to be sent should be sent again
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct RetryPolicy {
+    /// The interval between each retry. If it is not defined the message is never sent again
+    pub interval: Option<DurationSequence>,
+    /// The maximum amount of times that the message will be sent again after the first attempt
+    pub max_attempts: u16,
+}
+
+impl RetryPolicy {
+    /// Create a RetryPolicy using the `retryPolicy.defaults.` configurations
+    pub fn from_configuration(conf: &RetryPolicyConfiguration) -> RetryPolicy {
+        RetryPolicy {
+            interval: conf.default_interval.clone(),
+            max_attempts: conf.default_max_attempts.unwrap_or(0),
+        }
+    }
+
+    /// Create the default RetryPolicy of the messages of the namespace. The defaults of a namespace
+    /// with its own configurations are clamped by its limits, so they follow the global limits too
+    pub fn for_namespace(conf: &RetryPolicyConfiguration, service_id: &str) -> RetryPolicy {
+        match conf.for_namespace(service_id) {
+            Some(namespace) => RetryPolicy::from_configuration(&namespace).clamp(&namespace),
+            None => RetryPolicy::from_configuration(conf),
+        }
+    }
+
+    /// Return a copy of the policy restricted by the `retryPolicy.limit.` configurations. Intervals
+    /// are moved inside the min and max intervals and the max attempts is lowered to its limit. When
+    /// the min interval is greater than the max interval the max interval wins
+    pub fn clamp(&self, conf: &RetryPolicyConfiguration) -> RetryPolicy {
+        let clamp_interval = |interval: Duration| {
+            let interval = conf.min_interval_limit.map_or(interval, |limit| interval.max(limit));
+            conf.max_interval_limit.map_or(interval, |limit| interval.min(limit))
+        };
+        let interval = self.interval.as_ref().map(|interval| {
+            let clamped = interval.sequence().iter().copied().map(clamp_interval).collect();
+            let clamped = DurationSequence::from_vec(clamped).expect("a sequence is never empty");
+            match interval.tail() {
+                Some(tail) => clamped.with_tail(tail.map(clamp_interval)),
+                None => clamped,
+            }
+        });
+        let max_attempts = conf.max_attempts_limit.map_or(self.max_attempts, |limit| self.max_attempts.min(limit));
+        RetryPolicy { interval, max_attempts }
+    }
+
+    /// Return each part of the policy beyond the `retryPolicy.limit.` configurations, like
+    /// `element 4 (2d) exceeds maxInterval 30m`. The elements of the interval after the max attempts
+    /// would never be used, so they are beyond the limits too
+    pub fn violations(&self, conf: &RetryPolicyConfiguration) -> Vec<String> {
+        let interval = self.interval.as_ref().map(|interval| interval.validate_against(conf.max_interval_limit, conf.max_attempts_limit.map(usize::from)));
+        let mut violations: Vec<String> = match interval {
+            Some(Err(violations)) => violations.iter().map(ToString::to_string).collect(),
+            _ => Vec::new(),
+        };
+        if let Some(limit) = conf.max_attempts_limit.filter(|limit| self.max_attempts > *limit) {
+            violations.push(format!("maxAttempts {} exceeds maxAttempts {}", self.max_attempts, limit));
+        }
+        violations
+    }
+
+    /// Return how long the message should wait to be sent again after `failed_attempts` attempts
+    /// failed, or None if the message should not be sent anymore. When the sequence is shorter than
+    /// the amount of retries its tail is followed or, without one, its last interval is repeated
+    pub fn next_retry_delay(&self, failed_attempts: u16) -> Option<Duration> {
+        let interval = self.interval.as_ref()?;
+        if failed_attempts == 0 || failed_attempts > self.max_attempts {
+            return None;
+        }
+
+        Some(interval.interval_at(usize::from(failed_attempts - 1)))
+    }
+
+    /// Return when each attempt of a message first attempted at `first_attempt_at` would happen if
+    /// all of them failed. Attempts are assumed to take no time
+    pub fn attempt_times(&self, first_attempt_at: OffsetDateTime) -> Vec<OffsetDateTime> {
+        let mut times = vec![first_attempt_at];
+        let mut failed_attempts = 1;
+        while let Some(delay) = self.next_retry_delay(failed_attempts) {
+            times.push(*times.last().unwrap() + delay);
+            failed_attempts += 1;
+        }
+        times
+    }
+}
+
+/// Which failed attempts are retried, set by `retryPolicy.retryOn` and by the `retryOn` of the
+/// destinations. A message whose attempt failed with an error it does not match dies right away,
+/// as retrying a `400 Bad Request` would never succeed. Everything is retried by default
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RetryOn {
+    classes: BTreeSet<DeliveryErrorClass>,
+    /// Statuses retried even when their class is not, like a `404` some receivers return transiently
+    statuses: BTreeSet<u16>,
+}
+
+impl Default for RetryOn {
+    fn default() -> Self {
+        RetryOn { classes: DeliveryErrorClass::ALL.into_iter().collect(), statuses: BTreeSet::new() }
+    }
+}
+
+impl RetryOn {
+    /// Parse a comma separated list of error classes, like `http5xx` or `dns`, HTTP statuses, like
+    /// `404`, and the groups `4xx` (`http4xx` and `payloadTooLarge`), `5xx`, `timeout`
+    /// (`connectTimeout` and `responseTimeout`), `connect` (`connection` and `connectTimeout`) and `all`
+    pub fn parse(value: &str) -> Result<RetryOn, String> {
+        let mut retry_on = RetryOn { classes: BTreeSet::new(), statuses: BTreeSet::new() };
+        for name in value.split(',').map(str::trim).filter(|name| !name.is_empty()) {
+            let classes: &[DeliveryErrorClass] = match name {
+                "all" => &DeliveryErrorClass::ALL,
+                "4xx" => &[DeliveryErrorClass::Http4xx, DeliveryErrorClass::PayloadTooLarge],
+                "5xx" => &[DeliveryErrorClass::Http5xx],
+                "timeout" => &[DeliveryErrorClass::ConnectTimeout, DeliveryErrorClass::ResponseTimeout],
+                "connect" => &[DeliveryErrorClass::Connection, DeliveryErrorClass::ConnectTimeout],
+                _ => match (DeliveryErrorClass::from_name(name), name.parse::<u16>()) {
+                    (Some(class), _) => &[class],
+                    (None, Ok(status)) if (100..=599).contains(&status) => {
+                        retry_on.statuses.insert(status);
+                        &[]
+                    }
+                    _ => return Err(format!("{} is not an error class nor a HTTP status", name)),
+                },
+            };
+            retry_on.classes.extend(classes);
+        }
+        if retry_on.classes.is_empty() && retry_on.statuses.is_empty() {
+            return Err(String::from("at least one error class or HTTP status should be retried"));
+        }
+        Ok(retry_on)
+    }
+
+    /// Return if an attempt that failed with the error should be retried
+    pub fn matches(&self, error: &DeliveryError) -> bool {
+        self.classes.contains(&error.class) || error.status.is_some_and(|status| self.statuses.contains(&status))
+    }
+}
+
+impl Display for RetryOn {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let classes = self.classes.iter().map(|class| class.as_str().to_string());
+        let names: Vec<String> = classes.chain(self.statuses.iter().map(u16::to_string)).collect();
+        write!(f, "{}", names.join(","))
+    }
+}
+
+/// The retries a destination receives without a RetryBudget limiting them, when it has one
+pub const DEFAULT_RETRY_BUDGET_MIN_PER_MINUTE: u32 = 10;
+
+/// How long the attempts count against the RetryBudget of their destination
+pub const RETRY_BUDGET_WINDOW: StdDuration = StdDuration::from_secs(60);
+
+/// How many retries a destination receives per minute, as a fraction of the first attempts it
+/// received in the last minute. A receiver that fails every message would otherwise receive each
+/// of them again and again; the retries above the budget are postponed, without counting as an
+/// attempt, until the retries of the last minute free it
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RetryBudget {
+    /// The retries allowed for each first attempt of the last minute, like 0.2
+    pub ratio: f64,
+    /// The retries allowed per minute whatever the first attempts, so the destinations that
+    /// receive few messages still retry them
+    pub min_per_minute: u32,
+}
+
+impl RetryBudget {
+    pub fn new(ratio: f64) -> RetryBudget {
+        RetryBudget { ratio, min_per_minute: DEFAULT_RETRY_BUDGET_MIN_PER_MINUTE }
+    }
+
+    pub fn with_min_per_minute(mut self, min_per_minute: u32) -> RetryBudget {
+        self.min_per_minute = min_per_minute;
+        self
+    }
+
+    /// Return how many retries are allowed in a minute with that many first attempts
+    pub fn allowed_retries(&self, first_attempts: u64) -> u64 {
+        ((first_attempts as f64 * self.ratio) as u64).max(u64::from(self.min_per_minute))
+    }
+}
+
+/// The attempts made in one second of the RETRY_BUDGET_WINDOW
+#[derive(Debug)]
+struct BudgetSecond {
+    second: u64,
+    first_attempts: u64,
+    retries: u64,
+}
+
+/// The first attempts and retries of the last minute of each destination with a RetryBudget. The
+/// times are the monotonic times of the processor Clock
+#[derive(Debug, Default)]
+pub struct RetryBudgets {
+    destinations: Mutex<HashMap<String, VecDeque<BudgetSecond>>>,
+}
+
+impl RetryBudgets {
+    pub fn new() -> RetryBudgets {
+        RetryBudgets::default()
+    }
+
+    /// Count the first attempt of a message of the destination
+    pub fn record_first_attempt(&self, recipient_id: &str, now: StdDuration) {
+        let mut destinations = self.destinations.lock().unwrap();
+        current_second(destinations.entry(recipient_id.to_string()).or_default(), now).first_attempts += 1;
+    }
+
+    /// Count a retry of the destination when its budget allows it, or return how long to wait for
+    /// the oldest attempts of the window to leave it otherwise
+    pub fn try_retry(&self, recipient_id: &str, budget: &RetryBudget, now: StdDuration) -> Result<(), StdDuration> {
+        let mut destinations = self.destinations.lock().unwrap();
+        let seconds = destinations.entry(recipient_id.to_string()).or_default();
+        current_second(seconds, now);
+        let first_attempts = seconds.iter().map(|second| second.first_attempts).sum();
+        let retries: u64 = seconds.iter().map(|second| second.retries).sum();
+        if retries >= budget.allowed_retries(first_attempts) {
+            let oldest = seconds.front().expect("the current second is in the window").second;
+            let frees_at = StdDuration::from_secs(oldest) + RETRY_BUDGET_WINDOW;
+            return Err(frees_at.saturating_sub(now).max(StdDuration::from_secs(1)));
+        }
+        current_second(seconds, now).retries += 1;
+        Ok(())
+    }
+}
+
+/// Drop the seconds that left the window and return the current one
+fn current_second(seconds: &mut VecDeque<BudgetSecond>, now: StdDuration) -> &mut BudgetSecond {
+    let second = now.as_secs();
+    while seconds.front().is_some_and(|oldest| oldest.second + RETRY_BUDGET_WINDOW.as_secs() <= second) {
+        seconds.pop_front();
+    }
+    if seconds.back().is_none_or(|last| last.second != second) {
+        seconds.push_back(BudgetSecond { second, first_attempts: 0, retries: 0 });
+    }
+    seconds.back_mut().expect("the current second was pushed")
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{ctx::config::Configuration, utils::time::DurationSequenceDeserializer};
+
+    use super::*;
+
+    #[test]
+    fn test_if_next_retry_delay_follows_the_sequence_and_repeats_the_last_interval() {
+        let policy = RetryPolicy { interval: Some("[1m, 5m]".to_duration_sequence().unwrap()), max_attempts: 3 };
+        assert_eq!(policy.next_retry_delay(1), Some(Duration::minutes(1)));
+        assert_eq!(policy.next_retry_delay(2), Some(Duration::minutes(5)));
+        assert_eq!(policy.next_retry_delay(3), Some(Duration::minutes(5)));
+        assert_eq!(policy.next_retry_delay(4), None);
+
+        let composite = RetryPolicy { interval: Some("[1m, 5m, 30m] then every 6h".to_duration_sequence().unwrap()), max_attempts: 40 };
+        assert_eq!(composite.next_retry_delay(3), Some(Duration::minutes(30)));
+        assert_eq!(composite.next_retry_delay(40), Some(Duration::hours(6)));
+        assert_eq!(composite.next_retry_delay(41), None);
+    }
+
+    #[test]
+    fn test_if_policy_is_clamped_by_the_limits() {
+        let mut conf = Configuration::new().retry_policy;
+        conf.max_interval_limit = Some(Duration::hours(1));
+        conf.min_interval_limit = Some(Duration::minutes(1));
+        conf.max_attempts_limit = Some(5);
+
+        let requested = RetryPolicy { interval: Some("[10s, 5m, 1d]".to_duration_sequence().unwrap()), max_attempts: 50 };
+        let effective = requested.clamp(&conf);
+        assert_eq!(effective.interval, Some("[1m, 5m, 1h]".to_duration_sequence().unwrap()));
+        assert_eq!(effective.max_attempts, 5);
+        assert_eq!(requested.violations(&conf), vec!["element 3 (1d) exceeds maxInterval 1h", "maxAttempts 50 exceeds maxAttempts 5"]);
+
+        let within_limits = RetryPolicy { interval: Some("[1m]".to_duration_sequence().unwrap()), max_attempts: 2 };
+        assert_eq!(within_limits.clamp(&conf), within_limits);
+        assert!(within_limits.violations(&conf).is_empty());
+        assert_eq!(requested.clamp(&Configuration::new().retry_policy), requested);
+
+        let with_tail = RetryPolicy { interval: Some("[10s] then every 1d".to_duration_sequence().unwrap()), max_attempts: 3 };
+        assert_eq!(with_tail.clamp(&conf).interval, Some("[1m] then every 1h".to_duration_sequence().unwrap()));
+    }
+
+    #[test]
+    fn test_if_namespaces_tighten_the_global_limits() {
+        let mut conf = Configuration::new().retry_policy;
+        conf.default_interval = Some("[1m, 5m]".to_duration_sequence().unwrap());
+        conf.default_max_attempts = Some(7);
+        conf.max_attempts_limit = Some(20);
+        conf.min_interval_limit = Some(Duration::minutes(1));
+        let mut billing = Configuration::new().retry_policy;
+        billing.default_interval = Some("[10s, 1h]".to_duration_sequence().unwrap());
+        billing.max_interval_limit = Some(Duration::minutes(30));
+        billing.max_attempts_limit = Some(30);
+        let mut shop = Configuration::new().retry_policy;
+        shop.max_attempts_limit = Some(3);
+        conf.namespaces = Some(HashMap::from([(String::from("billing"), billing), (String::from("shop"), shop)]));
+
+        // the defaults of billing are moved inside the limits of both, and its own max attempts limit does not loosen the global one
+        assert_eq!(RetryPolicy::for_namespace(&conf, "billing"), RetryPolicy { interval: Some("[1m, 30m]".to_duration_sequence().unwrap()), max_attempts: 7 });
+        let requested = RetryPolicy { interval: Some("[2h]".to_duration_sequence().unwrap()), max_attempts: 25 };
+        assert_eq!(requested.clamp(&conf.for_namespace("billing").unwrap()), RetryPolicy { interval: Some("[30m]".to_duration_sequence().unwrap()), max_attempts: 20 });
+        // shop keeps the global defaults, lowered to its limit
+        assert_eq!(RetryPolicy::for_namespace(&conf, "shop").max_attempts, 3);
+        assert_eq!(RetryPolicy::for_namespace(&conf, "other"), RetryPolicy::from_configuration(&conf));
+    }
+
+    #[test]
+    fn test_if_policy_without_interval_never_retries() {
+        let policy = RetryPolicy { interval: None, max_attempts: 10 };
+        assert_eq!(policy.next_retry_delay(1), None);
+        assert_eq!(policy.attempt_times(OffsetDateTime::UNIX_EPOCH), vec![OffsetDateTime::UNIX_EPOCH]);
+    }
+
+    #[test]
+    fn test_if_attempt_times_accumulate_the_retry_delays() {
+        let policy = RetryPolicy { interval: Some("[1m, 5m]".to_duration_sequence().unwrap()), max_attempts: 3 };
+        let start = OffsetDateTime::UNIX_EPOCH;
+        assert_eq!(
+            policy.attempt_times(start),
+            vec![start, start + Duration::minutes(1), start + Duration::minutes(6), start + Duration::minutes(11)]
+        );
+    }
+
+    #[test]
+    fn test_if_retry_on_matches_the_classes_groups_and_statuses() {
+        let retry_on = RetryOn::parse("5xx, timeout, connect, 404").unwrap();
+        let error = |class, status: Option<u16>| DeliveryError { class, message: String::new(), status };
+        assert!(retry_on.matches(&error(DeliveryErrorClass::Http5xx, Some(503))));
+        assert!(retry_on.matches(&error(DeliveryErrorClass::ResponseTimeout, None)));
+        assert!(retry_on.matches(&error(DeliveryErrorClass::Connection, None)));
+        assert!(retry_on.matches(&error(DeliveryErrorClass::Http4xx, Some(404))));
+        assert!(!retry_on.matches(&error(DeliveryErrorClass::Http4xx, Some(400))));
+        assert!(!retry_on.matches(&error(DeliveryErrorClass::Dns, None)));
+        assert_eq!(retry_on.to_string(), "connectTimeout,connection,responseTimeout,http5xx,404");
+        assert_eq!(RetryOn::parse(&retry_on.to_string()), Ok(retry_on));
+
+        assert!(RetryOn::default().matches(&error(DeliveryErrorClass::Http4xx, Some(400))));
+        assert_eq!(RetryOn::parse("all"), Ok(RetryOn::default()));
+        assert!(RetryOn::parse("5xx, teapot").is_err());
+        assert!(RetryOn::parse("700").is_err());
+        assert!(RetryOn::parse(" , ").is_err());
+    }
+
+    #[test]
+    fn test_if_retry_budgets_limit_the_retries_of_the_last_minute() {
+        let budgets = RetryBudgets::new();
+        let budget = RetryBudget::new(0.5).with_min_per_minute(1);
+        let at = StdDuration::from_secs;
+        assert_eq!(budget.allowed_retries(0), 1);
+        assert_eq!(budget.allowed_retries(10), 5);
+
+        assert_eq!(budgets.try_retry("r", &budget, at(100)), Ok(()));
+        assert_eq!(budgets.try_retry("r", &budget, at(110)), Err(at(50)));
+        for _ in 0..4 {
+            budgets.record_first_attempt("r", at(110));
+        }
+        assert_eq!(budgets.try_retry("r", &budget, at(110)), Ok(()));
+        assert_eq!(budgets.try_retry("r", &budget, at(111)), Err(at(49)));
+        // the other destinations have their own budget
+        assert_eq!(budgets.try_retry("other", &budget, at(111)), Ok(()));
+
+        // the retry of the second 100 left the window
+        assert_eq!(budgets.try_retry("r", &budget, at(160)), Ok(()));
+        assert_eq!(budgets.try_retry("r", &budget, at(169)), Err(at(1)));
+        // and the first attempts of the second 110 left with it
+        assert!(budgets.try_retry("r", &budget, at(170)).is_err());
+        assert_eq!(budgets.try_retry("r", &budget, at(220)), Ok(()));
+    }
+}
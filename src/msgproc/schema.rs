@@ -0,0 +1,114 @@
+use std::{collections::HashMap, fs, io};
+
+use thiserror::Error;
+
+use crate::{
+    ctx::config::MessagesProcessorConfigurations,
+    utils::{json::JsonValue, json_schema::{JsonSchema, JsonSchemaError}},
+};
+
+use super::{interceptor::{Interceptor, Rejection}, message::Message};
+
+#[derive(Debug, Error)]
+pub enum SchemaLoadError {
+    #[error("Failed to read the schema file {0}: {1}")]
+    Io(String, io::Error),
+    #[error("The schema file {0} is invalid: {1}")]
+    Invalid(String, JsonSchemaError),
+}
+
+/// Reject the messages whose payload does not conform to the JSON Schema of their topic, so
+/// malformed events are not sent to the recipients. Topics without a schema accept any payload
+#[derive(Debug, Clone)]
+pub struct TopicSchemas {
+    schemas: HashMap<String, JsonSchema>,
+}
+
+impl TopicSchemas {
+    /// Validate the payloads of each topic with its schema
+    pub fn new(schemas: HashMap<String, JsonSchema>) -> TopicSchemas {
+        TopicSchemas { schemas }
+    }
+
+    /// Load the schema files set by `msgproc.interceptors.schema.<eventId>`. Return None when no
+    /// topic has a schema
+    pub fn from_configuration(conf: &MessagesProcessorConfigurations) -> Result<Option<TopicSchemas>, SchemaLoadError> {
+        let Some(paths) = &conf.topic_schemas else {
+            return Ok(None);
+        };
+
+        let mut schemas = HashMap::new();
+        for (topic, path) in paths {
+            schemas.insert(topic.clone(), load_schema_file(path)?);
+        }
+        Ok(Some(TopicSchemas::new(schemas)))
+    }
+}
+
+/// Read and parse the JSON Schema file
+pub fn load_schema_file(path: &str) -> Result<JsonSchema, SchemaLoadError> {
+    let schema = fs::read_to_string(path).map_err(|err| SchemaLoadError::Io(path.to_string(), err))?;
+    JsonSchema::parse(&schema).map_err(|err| SchemaLoadError::Invalid(path.to_string(), err))
+}
+
+/// Reject the message when its payload is not JSON or does not conform to the schema of its topic
+pub fn validate_payload(schema: &JsonSchema, message: &Message) -> Result<(), Rejection> {
+    let payload = JsonValue::parse_bytes(&message.payload)
+        .map_err(|err| Rejection::new(format!("the payload of {} should be JSON: {}", message.topic(), err)))?;
+
+    let violations = schema.validate(&payload);
+    if violations.is_empty() {
+        return Ok(());
+    }
+    Err(Rejection::with_violations(
+        format!("the payload does not conform to the schema of {}", message.topic()),
+        violations.iter().map(ToString::to_string).collect(),
+    ))
+}
+
+impl Interceptor for TopicSchemas {
+    fn intercept(&self, message: &mut Message) -> Result<(), Rejection> {
+        match self.schemas.get(message.topic()) {
+            Some(schema) => validate_payload(schema, message),
+            None => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn message(event_id: &str, payload: &str) -> Message {
+        Message::new("a".to_string(), "r".to_string(), "s".to_string(), event_id.to_string(), payload.as_bytes().to_vec())
+    }
+
+    #[test]
+    fn test_if_payloads_are_validated_by_the_schema_of_their_topic() {
+        let schema = JsonSchema::parse(r#"{"type": "object", "required": ["id"]}"#).unwrap();
+        let schemas = TopicSchemas::new(HashMap::from([(String::from("order.created"), schema)]));
+
+        assert!(schemas.intercept(&mut message("order.created", r#"{"id": 1}"#)).is_ok());
+        assert!(schemas.intercept(&mut message("order.deleted", "not json")).is_ok());
+
+        let rejection = schemas.intercept(&mut message("order.created", "{}")).unwrap_err();
+        assert_eq!(rejection.violations, vec!["/: the property id is required"]);
+        assert!(schemas.intercept(&mut message("order.created", "not json")).is_err());
+    }
+
+    #[test]
+    fn test_if_schema_files_are_loaded_from_the_configuration() {
+        let path = std::env::temp_dir().join(format!("angler-schema-{}.json", std::process::id()));
+        fs::write(&path, r#"{"type": "object"}"#).unwrap();
+        let mut conf = crate::ctx::config::Configuration::new().messages_processor;
+        conf.topic_schemas = Some(HashMap::from([(String::from("order.created"), path.to_string_lossy().to_string())]));
+
+        let schemas = TopicSchemas::from_configuration(&conf).unwrap().unwrap();
+        assert!(schemas.intercept(&mut message("order.created", "[]")).is_err());
+
+        fs::write(&path, r#"{"type": 1}"#).unwrap();
+        assert!(matches!(TopicSchemas::from_configuration(&conf), Err(SchemaLoadError::Invalid(..))));
+        fs::remove_file(&path).unwrap();
+        assert!(matches!(TopicSchemas::from_configuration(&conf), Err(SchemaLoadError::Io(..))));
+    }
+}
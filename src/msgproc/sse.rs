@@ -0,0 +1,176 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    io::{self, Write},
+    sync::{mpsc::{self, Receiver, RecvTimeoutError, Sender}, Mutex},
+    time::Duration,
+};
+
+/// How many recent events of each destination are kept to resume the streams that reconnect
+pub const SSE_REPLAY_EVENTS: usize = 1000;
+
+/// How often a comment is written into a idle stream, so the consumers that disconnected are noticed
+pub const SSE_KEEPALIVE_INTERVAL: Duration = Duration::from_secs(15);
+
+/// A event sent to the server-sent events streams of a destination
+#[derive(Debug, Clone, PartialEq)]
+pub struct SseEvent {
+    /// Increases with each event of the destination, in the order the events are sent to its
+    /// streams, which is the delivery order and not the publish order when several workers
+    /// deliver. Consumers resume from it with `Last-Event-ID`
+    pub id: u64,
+    /// The topic of the message
+    pub event: String,
+    pub data: String,
+}
+
+impl SseEvent {
+    /// Encode the event in the `text/event-stream` format. Each line of the data is sent in its
+    /// own `data:` field, so the consumer receives the data with its line breaks
+    pub fn encode(&self) -> String {
+        let mut encoded = format!("id: {}\nevent: {}\n", self.id, self.event);
+        for line in self.data.split('\n') {
+            encoded.push_str("data: ");
+            encoded.push_str(line.strip_suffix('\r').unwrap_or(line));
+            encoded.push('\n');
+        }
+        encoded.push('\n');
+        encoded
+    }
+}
+
+/// The stream of a consumer: the events it missed, followed by the new ones
+pub struct SseSubscription {
+    pub missed: Vec<SseEvent>,
+    pub events: Receiver<SseEvent>,
+}
+
+impl SseSubscription {
+    /// Write the missed events and then each new event into the writer, until it fails because
+    /// the consumer disconnected
+    pub fn write_to(self, writer: &mut dyn Write, keepalive: Duration) -> io::Result<()> {
+        for event in &self.missed {
+            writer.write_all(event.encode().as_bytes())?;
+        }
+        writer.flush()?;
+        loop {
+            match self.events.recv_timeout(keepalive) {
+                Ok(event) => writer.write_all(event.encode().as_bytes())?,
+                Err(RecvTimeoutError::Timeout) => writer.write_all(b": keepalive\n\n")?,
+                Err(RecvTimeoutError::Disconnected) => return Ok(()),
+            }
+            writer.flush()?;
+        }
+    }
+}
+
+#[derive(Default)]
+struct SseChannel {
+    last_id: u64,
+    recent: VecDeque<SseEvent>,
+    consumers: Vec<Sender<SseEvent>>,
+}
+
+/// The consumers connected to the server-sent events streams of the `sse` destinations
+#[derive(Default)]
+pub struct SseHub {
+    channels: Mutex<HashMap<String, SseChannel>>,
+}
+
+impl SseHub {
+    pub fn new() -> SseHub {
+        SseHub::default()
+    }
+
+    /// Send the event to every consumer connected to the destination of the recipient, returning
+    /// the event or None when no consumer is connected. A consumer that disconnected is only
+    /// noticed when its stream is written, so the recent events are kept to be resumed
+    pub fn publish(&self, recipient_id: &str, event: &str, data: String) -> Option<SseEvent> {
+        let mut channels = self.channels.lock().unwrap();
+        let channel = channels.entry(recipient_id.to_string()).or_default();
+        let sse_event = SseEvent { id: channel.last_id + 1, event: event.to_string(), data };
+        channel.consumers.retain(|consumer| consumer.send(sse_event.clone()).is_ok());
+        if channel.consumers.is_empty() {
+            return None;
+        }
+
+        channel.last_id = sse_event.id;
+        if channel.recent.len() == SSE_REPLAY_EVENTS {
+            channel.recent.pop_front();
+        }
+        channel.recent.push_back(sse_event.clone());
+        Some(sse_event)
+    }
+
+    /// Connect a consumer to the destination of the recipient. The recent events after
+    /// `last_event_id` are returned as missed
+    pub fn subscribe(&self, recipient_id: &str, last_event_id: Option<u64>) -> SseSubscription {
+        let mut channels = self.channels.lock().unwrap();
+        let channel = channels.entry(recipient_id.to_string()).or_default();
+        let missed = match last_event_id {
+            Some(last_event_id) => channel.recent.iter().filter(|event| event.id > last_event_id).cloned().collect(),
+            None => Vec::new(),
+        };
+        let (sender, events) = mpsc::channel();
+        channel.consumers.push(sender);
+        SseSubscription { missed, events }
+    }
+
+    /// Return how many consumers are connected to the destination of the recipient
+    pub fn consumers(&self, recipient_id: &str) -> usize {
+        self.channels.lock().unwrap().get(recipient_id).map_or(0, |channel| channel.consumers.len())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_if_events_are_encoded_with_a_data_field_per_line() {
+        let event = SseEvent { id: 7, event: String::from("order.created"), data: String::from("{\"a\":1,\r\n\"b\":2}") };
+        assert_eq!(event.encode(), "id: 7\nevent: order.created\ndata: {\"a\":1,\ndata: \"b\":2}\n\n");
+    }
+
+    #[test]
+    fn test_if_events_are_only_published_to_connected_consumers_and_resumed_after_the_last_id() {
+        let hub = SseHub::new();
+        assert_eq!(hub.publish("r1", "order.created", String::from("lost")), None);
+
+        let first = hub.subscribe("r1", None);
+        assert_eq!(hub.publish("r1", "order.created", String::from("a")).map(|event| event.id), Some(1));
+        assert_eq!(hub.publish("r1", "order.created", String::from("b")).map(|event| event.id), Some(2));
+        assert_eq!(first.events.try_recv().unwrap().data, "a");
+        assert_eq!(hub.consumers("r2"), 0);
+
+        drop(first);
+        let resumed = hub.subscribe("r1", Some(1));
+        assert_eq!(resumed.missed.iter().map(|event| event.data.as_str()).collect::<Vec<_>>(), vec!["b"]);
+        assert_eq!(hub.publish("r1", "order.created", String::from("c")).map(|event| event.id), Some(3));
+        assert_eq!(hub.consumers("r1"), 1);
+    }
+
+    #[test]
+    fn test_if_events_published_concurrently_are_streamed_and_resumed_in_the_order_of_their_ids() {
+        let hub = std::sync::Arc::new(SseHub::new());
+        let live = hub.subscribe("r1", None);
+        let publishers: Vec<_> = (0..4).map(|publisher| {
+            let hub = hub.clone();
+            std::thread::spawn(move || {
+                for index in 0..50 {
+                    hub.publish("r1", "order.created", format!("{}-{}", publisher, index));
+                }
+            })
+        }).collect();
+        // a consumer that connects while the events are published continues from the last one it saw
+        let first = live.events.recv().unwrap();
+        let resumed = hub.subscribe("r1", Some(first.id));
+        for publisher in publishers {
+            publisher.join().unwrap();
+        }
+
+        let streamed: Vec<u64> = std::iter::once(first.id).chain(live.events.try_iter().map(|event| event.id)).collect();
+        assert_eq!(streamed, (1..=200).collect::<Vec<u64>>());
+        let resumed: Vec<u64> = resumed.missed.iter().map(|event| event.id).chain(resumed.events.try_iter().map(|event| event.id)).collect();
+        assert_eq!(resumed, ((first.id + 1)..=200).collect::<Vec<u64>>());
+    }
+}
@@ -0,0 +1,360 @@
+use std::{collections::BTreeMap, sync::RwLock};
+
+use thiserror::Error;
+use time::{Duration, OffsetDateTime};
+
+use crate::{
+    ctx::config::TopicsConfiguration,
+    log,
+    utils::{json_schema::JsonSchema, log::Level, time::DurationSequence},
+};
+
+use super::{
+    interceptor::{Interceptor, MaxPayloadSize, Rejection},
+    message::Message,
+    retry::RetryPolicy,
+    schema::{load_schema_file, validate_payload, SchemaLoadError},
+};
+
+/// How the messages of a topic are sent to each destination
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum OrderingMode {
+    /// The messages are sent as they become due, a message waiting for its retry does not hold
+    /// the others
+    #[default]
+    Unordered,
+    /// The messages of each namespace are sent to each destination one at a time, in the order of
+    /// their sequence. A message waiting for its retry holds the next ones until it is finished
+    Ordered,
+}
+
+impl OrderingMode {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            OrderingMode::Unordered => "unordered",
+            OrderingMode::Ordered => "ordered",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<OrderingMode> {
+        match name {
+            "unordered" => Some(OrderingMode::Unordered),
+            "ordered" => Some(OrderingMode::Ordered),
+            _ => None,
+        }
+    }
+}
+
+/// The retry policy of the messages of a topic published without their own. The fields that are
+/// not set come from the defaults of the namespace
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct RetryProfile {
+    pub interval: Option<DurationSequence>,
+    pub max_attempts: Option<u16>,
+}
+
+impl RetryProfile {
+    /// Return the policy of the profile, filling the fields that are not set with the defaults
+    pub fn over(&self, defaults: &RetryPolicy) -> RetryPolicy {
+        RetryPolicy {
+            interval: self.interval.clone().or_else(|| defaults.interval.clone()),
+            max_attempts: self.max_attempts.unwrap_or(defaults.max_attempts),
+        }
+    }
+}
+
+/// The settings of a topic. The ones that are not set follow the configurations
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct TopicSettings {
+    pub ordering: OrderingMode,
+    /// The most bytes of a payload, checked after `msgproc.interceptors.maxPayloadSize`
+    pub max_payload_size: Option<usize>,
+    /// The path of the JSON Schema file the payloads should conform to
+    pub schema_ref: Option<String>,
+    /// How long the delivered messages are kept, instead of `db.deliveredMessages.retention`
+    pub delivered_messages_retention: Option<Duration>,
+    /// How long the dead messages are kept, instead of `db.deadMessages.retention`
+    pub dead_messages_retention: Option<Duration>,
+    pub retry_profile: Option<RetryProfile>,
+}
+
+impl TopicSettings {
+    /// Return the settings of the `topics.defaults.` configurations
+    pub fn from_configuration(conf: &TopicsConfiguration) -> TopicSettings {
+        let retry_profile = RetryProfile { interval: conf.default_retry_interval.clone(), max_attempts: conf.default_retry_max_attempts };
+        TopicSettings {
+            ordering: conf.default_ordering.unwrap_or_default(),
+            max_payload_size: conf.default_max_payload_size,
+            schema_ref: conf.default_schema_ref.clone(),
+            delivered_messages_retention: conf.default_delivered_messages_retention,
+            dead_messages_retention: conf.default_dead_messages_retention,
+            retry_profile: Some(retry_profile).filter(|profile| *profile != RetryProfile::default()),
+        }
+    }
+
+    /// Return if the topic keeps its finished messages for its own time
+    pub fn overrides_retention(&self) -> bool {
+        self.delivered_messages_retention.is_some() || self.dead_messages_retention.is_some()
+    }
+}
+
+/// A topic created through the API or on its first publish, by its name: the `eventId` of its
+/// messages
+#[derive(Debug, Clone)]
+pub struct Topic {
+    pub name: String,
+    pub settings: TopicSettings,
+    /// The schema read from the `schema_ref` when the topic was created or last updated
+    pub schema: Option<JsonSchema>,
+    pub created_at: OffsetDateTime,
+    pub updated_at: OffsetDateTime,
+}
+
+#[derive(Debug, Error)]
+pub enum TopicError {
+    #[error("the topic {0} already exists")]
+    AlreadyExists(String),
+    #[error("the topic {0} was not found")]
+    NotFound(String),
+    #[error(transparent)]
+    Schema(#[from] SchemaLoadError),
+}
+
+/// The settings of the topics created on their first publish, with the schema read from their
+/// `schema_ref` once
+#[derive(Debug, Clone, Default)]
+struct TopicTemplate {
+    settings: TopicSettings,
+    schema: Option<JsonSchema>,
+}
+
+/// The topics with their settings. By default the topics that were not created through the API
+/// are created on their first publish, with the settings of the template, so publishing stays
+/// frictionless. Without auto-creation their messages are rejected, so each topic has to be
+/// provisioned first. As an Interceptor it also rejects the messages above the max payload size of
+/// their topic or whose payload does not conform to its schema
+#[derive(Debug)]
+pub struct TopicRegistry {
+    topics: RwLock<BTreeMap<String, Topic>>,
+    /// None when the topics are not created on their first publish
+    template: Option<TopicTemplate>,
+}
+
+impl TopicRegistry {
+    /// Create a registry that creates the topics on their first publish with the default settings
+    pub fn new() -> TopicRegistry {
+        TopicRegistry { topics: RwLock::default(), template: Some(TopicTemplate::default()) }
+    }
+
+    /// Create the registry of the `topics.` configurations: `topics.autoCreate` and the template of
+    /// `topics.defaults.`
+    pub fn from_configuration(conf: &TopicsConfiguration) -> Result<TopicRegistry, TopicError> {
+        let registry = TopicRegistry::new().with_template(TopicSettings::from_configuration(conf))?;
+        Ok(match conf.auto_create.unwrap_or(true) {
+            true => registry,
+            false => registry.without_auto_create(),
+        })
+    }
+
+    /// Create the topics on their first publish with the settings, loading their schema once
+    pub fn with_template(mut self, settings: TopicSettings) -> Result<TopicRegistry, TopicError> {
+        let schema = settings.schema_ref.as_deref().map(load_schema_file).transpose()?;
+        self.template = Some(TopicTemplate { settings, schema });
+        Ok(self)
+    }
+
+    /// Reject the messages of the topics that were not created through the API
+    pub fn without_auto_create(mut self) -> TopicRegistry {
+        self.template = None;
+        self
+    }
+
+    /// Return if the topics are created on their first publish
+    pub fn auto_creates(&self) -> bool {
+        self.template.is_some()
+    }
+
+    /// Create the topic, loading the schema of its settings
+    pub fn create(&self, name: &str, settings: TopicSettings, now: OffsetDateTime) -> Result<Topic, TopicError> {
+        let schema = settings.schema_ref.as_deref().map(load_schema_file).transpose()?;
+        let mut topics = self.topics.write().unwrap();
+        if topics.contains_key(name) {
+            return Err(TopicError::AlreadyExists(name.to_string()));
+        }
+        let topic = Topic { name: name.to_string(), settings, schema, created_at: now, updated_at: now };
+        topics.insert(name.to_string(), topic.clone());
+        Ok(topic)
+    }
+
+    /// Replace the settings of the topic, loading their schema again
+    pub fn update(&self, name: &str, settings: TopicSettings, now: OffsetDateTime) -> Result<Topic, TopicError> {
+        let schema = settings.schema_ref.as_deref().map(load_schema_file).transpose()?;
+        let mut topics = self.topics.write().unwrap();
+        let topic = topics.get_mut(name).ok_or_else(|| TopicError::NotFound(name.to_string()))?;
+        topic.settings = settings;
+        topic.schema = schema;
+        topic.updated_at = now;
+        Ok(topic.clone())
+    }
+
+    /// Delete the topic, whose messages follow the configurations from now on
+    pub fn remove(&self, name: &str) -> Option<Topic> {
+        self.topics.write().unwrap().remove(name)
+    }
+
+    pub fn get(&self, name: &str) -> Option<Topic> {
+        self.topics.read().unwrap().get(name).cloned()
+    }
+
+    /// Return the topics ordered by name
+    pub fn list(&self) -> Vec<Topic> {
+        self.topics.read().unwrap().values().cloned().collect()
+    }
+
+    /// Return the settings of the topic, the ones of the template when it was not created yet
+    fn settings_of<T>(&self, name: &str, setting: impl Fn(&TopicSettings) -> T) -> Option<T> {
+        match self.topics.read().unwrap().get(name) {
+            Some(topic) => Some(setting(&topic.settings)),
+            None => self.template.as_ref().map(|template| setting(&template.settings)),
+        }
+    }
+
+    /// Return the ordering mode of the topic, the one of the template when it was not created yet
+    pub fn ordering(&self, name: &str) -> OrderingMode {
+        self.settings_of(name, |settings| settings.ordering).unwrap_or_default()
+    }
+
+    /// Return the retry profile of the topic, the one of the template when it was not created yet.
+    /// None when it has none
+    pub fn retry_profile(&self, name: &str) -> Option<RetryProfile> {
+        self.settings_of(name, |settings| settings.retry_profile.clone()).flatten()
+    }
+}
+
+impl Default for TopicRegistry {
+    fn default() -> Self {
+        TopicRegistry::new()
+    }
+}
+
+/// Reject the message above the max payload size of the topic or whose payload does not conform
+/// to its schema
+fn check_payload(topic: &Topic, message: &mut Message) -> Result<(), Rejection> {
+    if let Some(max_size) = topic.settings.max_payload_size {
+        MaxPayloadSize(max_size).intercept(message)?;
+    }
+    match &topic.schema {
+        Some(schema) => validate_payload(schema, message),
+        None => Ok(()),
+    }
+}
+
+impl Interceptor for TopicRegistry {
+    fn intercept(&self, message: &mut Message) -> Result<(), Rejection> {
+        if let Some(topic) = self.topics.read().unwrap().get(message.topic()) {
+            return check_payload(topic, message);
+        }
+        let Some(template) = &self.template else {
+            return Err(Rejection::new(format!("the topic {} does not exist, it should be created through /topics first", message.topic())));
+        };
+        let mut topics = self.topics.write().unwrap();
+        let topic = topics.entry(message.topic().to_string()).or_insert_with(|| {
+            log!(Level::Info, "The topic {} was created on its first publish", message.topic());
+            Topic {
+                name: message.topic().to_string(),
+                settings: template.settings.clone(),
+                schema: template.schema.clone(),
+                created_at: message.created_at,
+                updated_at: message.created_at,
+            }
+        });
+        check_payload(topic, message)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::HashMap, fs};
+
+    use crate::{ctx::config::Configuration, utils::time::DurationSequenceDeserializer};
+
+    use super::*;
+
+    fn message(event_id: &str, payload: &str) -> Message {
+        Message::new("a".to_string(), "r".to_string(), "s".to_string(), event_id.to_string(), payload.as_bytes().to_vec())
+    }
+
+    #[test]
+    fn test_if_topics_are_created_updated_and_removed() {
+        let registry = TopicRegistry::new();
+        let now = OffsetDateTime::from_unix_timestamp(1_704_067_200).unwrap();
+        let settings = TopicSettings { ordering: OrderingMode::Ordered, max_payload_size: Some(8), ..TopicSettings::default() };
+        registry.create("order.created", settings.clone(), now).unwrap();
+        assert!(matches!(registry.create("order.created", settings.clone(), now), Err(TopicError::AlreadyExists(_))));
+        assert!(matches!(registry.update("order.deleted", settings, now), Err(TopicError::NotFound(_))));
+        assert_eq!(registry.ordering("order.created"), OrderingMode::Ordered);
+        assert_eq!(registry.ordering("order.deleted"), OrderingMode::Unordered);
+
+        assert!(registry.intercept(&mut message("order.created", "12345678")).is_ok());
+        assert!(registry.intercept(&mut message("order.created", "123456789")).is_err());
+        assert!(registry.intercept(&mut message("order.deleted", "123456789")).is_ok());
+
+        let profile = RetryProfile { interval: None, max_attempts: Some(2) };
+        let later = now + Duration::minutes(1);
+        let updated = registry.update("order.created", TopicSettings { retry_profile: Some(profile.clone()), ..TopicSettings::default() }, later).unwrap();
+        assert_eq!((updated.created_at, updated.updated_at), (now, later));
+        assert!(registry.intercept(&mut message("order.created", "123456789")).is_ok());
+        let defaults = RetryPolicy { interval: Some("[1m, 5m]".to_duration_sequence().unwrap()), max_attempts: 10 };
+        assert_eq!(registry.retry_profile("order.created").unwrap().over(&defaults), RetryPolicy { max_attempts: 2, ..defaults });
+
+        assert!(registry.remove("order.created").is_some());
+        assert!(registry.get("order.created").is_none() && registry.retry_profile("order.created").is_none());
+    }
+
+    #[test]
+    fn test_if_topics_are_created_on_their_first_publish_only_when_auto_creation_is_on() {
+        let profile = RetryProfile { interval: None, max_attempts: Some(3) };
+        let template = TopicSettings { ordering: OrderingMode::Ordered, max_payload_size: Some(4), retry_profile: Some(profile.clone()), ..TopicSettings::default() };
+        let registry = TopicRegistry::new().with_template(template.clone()).unwrap();
+        assert!(registry.auto_creates());
+        // the settings of the template apply before the first publish creates the topic
+        assert_eq!((registry.ordering("order.created"), registry.retry_profile("order.created")), (OrderingMode::Ordered, Some(profile)));
+
+        let mut first = message("order.created", "123");
+        assert!(registry.intercept(&mut first).is_ok());
+        let topic = registry.get("order.created").unwrap();
+        assert_eq!((topic.settings, topic.created_at), (template, first.created_at));
+        assert!(registry.intercept(&mut message("order.created", "12345")).is_err());
+        assert!(registry.intercept(&mut message("order.deleted", "12345")).is_err());
+        assert_eq!(registry.list().iter().map(|topic| topic.name.as_str()).collect::<Vec<_>>(), vec!["order.created", "order.deleted"]);
+
+        let registry = TopicRegistry::new().without_auto_create();
+        let rejection = registry.intercept(&mut message("order.created", "123")).unwrap_err();
+        assert_eq!(rejection.reason, "the topic order.created does not exist, it should be created through /topics first");
+        assert!(registry.list().is_empty() && registry.retry_profile("order.created").is_none());
+        registry.create("order.created", TopicSettings::default(), OffsetDateTime::UNIX_EPOCH).unwrap();
+        assert!(registry.intercept(&mut message("order.created", "123")).is_ok());
+
+        let conf = Configuration::from_map(&HashMap::from([(String::from("topics.autoCreate"), String::from("false"))])).unwrap();
+        assert!(!TopicRegistry::from_configuration(&conf.topics).unwrap().auto_creates());
+        assert!(TopicRegistry::from_configuration(&Configuration::new().topics).unwrap().auto_creates());
+    }
+
+    #[test]
+    fn test_if_payloads_are_validated_by_the_schema_of_the_topic() {
+        let path = std::env::temp_dir().join(format!("angler-topic-schema-{}.json", std::process::id()));
+        fs::write(&path, r#"{"type": "object", "required": ["id"]}"#).unwrap();
+        let settings = TopicSettings { schema_ref: Some(path.to_string_lossy().to_string()), ..TopicSettings::default() };
+        let registry = TopicRegistry::new();
+        registry.create("order.created", settings.clone(), OffsetDateTime::UNIX_EPOCH).unwrap();
+
+        assert!(registry.intercept(&mut message("order.created", r#"{"id": 1}"#)).is_ok());
+        let rejection = registry.intercept(&mut message("order.created", "{}")).unwrap_err();
+        assert_eq!(rejection.violations, vec!["/: the property id is required"]);
+
+        // a schema that can not be read leaves the topic as it was
+        fs::remove_file(&path).unwrap();
+        assert!(matches!(registry.update("order.created", settings, OffsetDateTime::UNIX_EPOCH), Err(TopicError::Schema(SchemaLoadError::Io(..)))));
+        assert!(registry.intercept(&mut message("order.created", "{}")).is_err());
+    }
+}
@@ -0,0 +1,171 @@
+use thiserror::Error;
+use time::{OffsetDateTime, Time, Weekday};
+
+use crate::utils::time::{TimeZone, TimeZoneError};
+
+#[derive(Debug, Error, PartialEq)]
+pub enum DeliveryWindowError {
+    #[error("{0} is not a weekday. Use mon, tue, wed, thu, fri, sat or sun, or a range like mon-fri")]
+    InvalidWeekday(String),
+    #[error("{0} is not a time of the day like 08:00")]
+    InvalidTime(String),
+    #[error(transparent)]
+    InvalidTimezone(#[from] TimeZoneError),
+    #[error("the delivery window should have at least one weekday")]
+    NoWeekdays,
+    #[error("the delivery window should not start and end at the same time")]
+    Empty,
+}
+
+const WEEKDAYS: [(&str, Weekday); 7] = [
+    ("mon", Weekday::Monday),
+    ("tue", Weekday::Tuesday),
+    ("wed", Weekday::Wednesday),
+    ("thu", Weekday::Thursday),
+    ("fri", Weekday::Friday),
+    ("sat", Weekday::Saturday),
+    ("sun", Weekday::Sunday),
+];
+
+/// When the messages of a destination can be sent, like weekdays from 08:00 to 20:00 in
+/// America/Sao_Paulo. The messages that become due outside of it wait as pending until it opens.
+/// A window that ends before it starts, like 22:00 to 06:00, crosses midnight and belongs to the
+/// day it starts. The times follow the wall clock of the timezone across daylight saving time
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DeliveryWindow {
+    /// Indexed by the days from monday
+    days: [bool; 7],
+    start: Time,
+    end: Time,
+    timezone: TimeZone,
+}
+
+impl DeliveryWindow {
+    pub fn new(days: &[Weekday], start: Time, end: Time, timezone: TimeZone) -> Result<DeliveryWindow, DeliveryWindowError> {
+        if days.is_empty() {
+            return Err(DeliveryWindowError::NoWeekdays);
+        }
+        if start == end {
+            return Err(DeliveryWindowError::Empty);
+        }
+        let mut selected = [false; 7];
+        for day in days {
+            selected[usize::from(day.number_days_from_monday())] = true;
+        }
+        Ok(DeliveryWindow { days: selected, start, end, timezone })
+    }
+
+    /// Create a window from the names of its days, like `mon-fri` or `sat`, and the times and the
+    /// timezone in the syntax of the APIs
+    pub fn parse<'a>(days: impl IntoIterator<Item = &'a str>, start: &str, end: &str, timezone: &str) -> Result<DeliveryWindow, DeliveryWindowError> {
+        let mut weekdays = Vec::new();
+        for days in days {
+            let (first, last) = days.split_once('-').unwrap_or((days, days));
+            let (first, last) = (parse_weekday(first)?.number_days_from_monday(), parse_weekday(last)?.number_days_from_monday());
+            // ranges like sat-mon wrap around the week
+            let length = (last + 7 - first) % 7;
+            weekdays.extend((0..=length).map(|offset| WEEKDAYS[usize::from((first + offset) % 7)].1));
+        }
+        DeliveryWindow::new(&weekdays, parse_time_of_day(start)?, parse_time_of_day(end)?, TimeZone::parse(timezone)?)
+    }
+
+    /// Return the names of the days of the window, starting on monday
+    pub fn days(&self) -> Vec<&'static str> {
+        WEEKDAYS.iter().filter(|(_, day)| self.has_day(*day)).map(|(name, _)| *name).collect()
+    }
+
+    pub fn start(&self) -> Time {
+        self.start
+    }
+
+    pub fn end(&self) -> Time {
+        self.end
+    }
+
+    pub fn timezone(&self) -> &TimeZone {
+        &self.timezone
+    }
+
+    fn has_day(&self, day: Weekday) -> bool {
+        self.days[usize::from(day.number_days_from_monday())]
+    }
+
+    /// Return if the messages can be sent at the given time
+    pub fn contains(&self, at: OffsetDateTime) -> bool {
+        let local = self.timezone.to_local(at);
+        let time = local.time();
+        if self.start < self.end {
+            self.has_day(local.weekday()) && self.start <= time && time < self.end
+        } else {
+            (self.has_day(local.weekday()) && time >= self.start) || (self.has_day(local.weekday().previous()) && time < self.end)
+        }
+    }
+
+    /// Return when the messages can be sent from the given time on: the time itself when the window
+    /// is open, or when it opens next
+    pub fn next_opening(&self, at: OffsetDateTime) -> OffsetDateTime {
+        if self.contains(at) {
+            return at;
+        }
+        self.timezone.next_occurrence(at, self.start, |day| self.has_day(day)).expect("a window with a weekday opens every week")
+    }
+}
+
+fn parse_weekday(name: &str) -> Result<Weekday, DeliveryWindowError> {
+    let name = name.trim().to_ascii_lowercase();
+    WEEKDAYS.iter().find(|(day, _)| *day == name).map(|(_, day)| *day).ok_or(DeliveryWindowError::InvalidWeekday(name))
+}
+
+fn parse_time_of_day(value: &str) -> Result<Time, DeliveryWindowError> {
+    let invalid = || DeliveryWindowError::InvalidTime(value.to_string());
+    let (hour, minute) = value.trim().split_once(':').ok_or_else(invalid)?;
+    match (hour.parse(), minute.parse()) {
+        // 24:00 is the end of the day
+        (Ok(24), Ok(0)) => Ok(Time::MIDNIGHT),
+        (Ok(hour), Ok(minute)) if minute < 60 => Time::from_hms(hour, minute, 0).map_err(|_| invalid()),
+        _ => Err(invalid()),
+    }
+}
+
+/// Format a time of a window in the syntax of the APIs
+pub fn format_time_of_day(time: Time) -> String {
+    format!("{:02}:{:02}", time.hour(), time.minute())
+}
+
+#[cfg(test)]
+mod tests {
+    use time::format_description::well_known::Rfc3339;
+
+    use super::*;
+
+    fn at(rfc3339: &str) -> OffsetDateTime {
+        OffsetDateTime::parse(rfc3339, &Rfc3339).unwrap()
+    }
+
+    #[test]
+    fn test_if_windows_open_on_their_days_and_hours() {
+        let window = DeliveryWindow::parse(["mon-fri"], "08:00", "20:00", "-03:00").unwrap();
+        assert_eq!(window.days(), vec!["mon", "tue", "wed", "thu", "fri"]);
+        // friday 2024-01-05 at 10:00 -03:00
+        assert!(window.contains(at("2024-01-05T13:00:00Z")));
+        assert!(!window.contains(at("2024-01-05T23:00:00Z")));
+        // the friday evening waits for monday
+        assert_eq!(window.next_opening(at("2024-01-05T23:00:00Z")), at("2024-01-08T08:00:00-03:00"));
+        assert_eq!(window.next_opening(at("2024-01-08T10:00:00Z")), at("2024-01-08T08:00:00-03:00"));
+        assert_eq!(window.next_opening(at("2024-01-05T13:00:00Z")), at("2024-01-05T13:00:00Z"));
+
+        let overnight = DeliveryWindow::parse(["sat-sun"], "22:00", "06:00", "UTC").unwrap();
+        assert_eq!(overnight.days(), vec!["sat", "sun"]);
+        // the window of sunday ends on monday morning
+        assert!(overnight.contains(at("2024-01-08T05:59:00Z")));
+        assert!(!overnight.contains(at("2024-01-06T05:00:00Z")));
+        assert_eq!(overnight.next_opening(at("2024-01-08T06:00:00Z")), at("2024-01-13T22:00:00Z"));
+
+        assert_eq!(DeliveryWindow::parse(["fri"], "08:00", "24:00", "+05:30").unwrap().end(), Time::MIDNIGHT);
+        assert_eq!(DeliveryWindow::parse(["fri"], "08:00", "20:00", "-03:30").unwrap().timezone().name(), "-03:30");
+        assert_eq!(DeliveryWindow::parse(["funday"], "08:00", "20:00", "UTC"), Err(DeliveryWindowError::InvalidWeekday(String::from("funday"))));
+        assert_eq!(DeliveryWindow::parse(["mon"], "8h", "20:00", "UTC"), Err(DeliveryWindowError::InvalidTime(String::from("8h"))));
+        assert_eq!(DeliveryWindow::parse(["mon"], "08:00", "20:00", "Mars/Olympus"), Err(DeliveryWindowError::InvalidTimezone(TimeZoneError::Unknown(String::from("Mars/Olympus")))));
+        assert_eq!(DeliveryWindow::parse([], "08:00", "20:00", "UTC"), Err(DeliveryWindowError::NoWeekdays));
+    }
+}
@@ -0,0 +1,154 @@
+use std::{collections::BTreeMap, sync::atomic::Ordering};
+
+use time::OffsetDateTime;
+
+use crate::{
+    db::{MessageQuery, MessageStore, StoreError},
+    msgproc::{
+        message::{AttemptOutcome, MessageStatus},
+        processor::ProcessorStats,
+    },
+    utils::{json::JsonValue, time::format_rfc3339},
+};
+
+/// The page served on `/admin/dashboard`. It loads `/admin/overview` every few seconds
+pub const DASHBOARD_HTML: &str = include_str!("dashboard.html");
+
+/// How many failed attempts are listed in the overview
+const RECENT_FAILURES_LIMIT: usize = 20;
+
+/// Serialize the processor counters
+pub fn stats_to_json(stats: &ProcessorStats) -> JsonValue {
+    JsonValue::object()
+        .with("published", stats.published.load(Ordering::Relaxed))
+        .with("recovered", stats.recovered.load(Ordering::Relaxed))
+        .with("handedOff", stats.handed_off.load(Ordering::Relaxed))
+        .with("adopted", stats.adopted.load(Ordering::Relaxed))
+        .with("attempts", stats.attempts.load(Ordering::Relaxed))
+        .with("delivered", stats.delivered.load(Ordering::Relaxed))
+        .with("dead", stats.dead.load(Ordering::Relaxed))
+        .with("filtered", stats.filtered.load(Ordering::Relaxed))
+        .with("cancelled", stats.cancelled.load(Ordering::Relaxed))
+        .with("outstanding", stats.outstanding())
+        .with("failures", stats.failures().into_iter().fold(JsonValue::object(), |json, (class, count)| json.with(class.as_str(), count)))
+}
+
+/// Serialize the counters of each topic, ordered by namespace and topic
+pub fn topic_stats_to_json(stats: &ProcessorStats) -> JsonValue {
+    let topics: Vec<JsonValue> = stats.topics().into_iter()
+        .map(|((namespace, topic), counters)| JsonValue::object()
+            .with("serviceId", namespace)
+            .with("eventId", topic)
+            .with("messagesIn", counters.messages_in)
+            .with("bytesIn", counters.bytes_in)
+            .with("delivered", counters.delivered)
+            .with("dead", counters.dead))
+        .collect();
+    JsonValue::from(topics)
+}
+
+#[derive(Default)]
+struct DestinationBacklog {
+    pending: usize,
+    in_flight: usize,
+    dead: usize,
+}
+
+/// Build the data shown by the dashboard: the nodes, the backlog and the dead messages of each
+/// destination and the most recent failed attempts. The cluster membership is not tracked yet, so
+/// the only node listed is the one serving the dashboard
+pub fn overview(stats: &ProcessorStats, store: &dyn MessageStore, started_at: OffsetDateTime) -> Result<JsonValue, StoreError> {
+    let mut backlogs: BTreeMap<String, DestinationBacklog> = BTreeMap::new();
+    let mut failures = Vec::new();
+
+    for status in [MessageStatus::Pending, MessageStatus::InFlight, MessageStatus::Dead] {
+        for message in store.find_messages(&MessageQuery { status: Some(status), ..MessageQuery::default() })? {
+            let backlog = backlogs.entry(message.recipient_id.clone()).or_default();
+            match status {
+                MessageStatus::Pending => backlog.pending += 1,
+                MessageStatus::InFlight => backlog.in_flight += 1,
+                _ => backlog.dead += 1,
+            }
+            // failures can only be found in messages that were attempted and did not finish well
+            if message.attempts == 0 {
+                continue;
+            }
+            for attempt in store.get_attempts(&message.id)? {
+                if let AttemptOutcome::Failed(reason) = attempt.outcome {
+                    failures.push((attempt.finished_at, message.id.clone(), message.recipient_id.clone(), attempt.attempt, reason));
+                }
+            }
+        }
+    }
+
+    failures.sort_by(|a, b| b.0.cmp(&a.0).then_with(|| a.1.cmp(&b.1)));
+    let recent_failures: Vec<JsonValue> = failures.into_iter()
+        .take(RECENT_FAILURES_LIMIT)
+        .map(|(finished_at, message_id, recipient_id, attempt, reason)| JsonValue::object()
+            .with("finishedAt", format_rfc3339(finished_at))
+            .with("messageId", message_id)
+            .with("recipientId", recipient_id)
+            .with("attempt", attempt)
+            .with("errorClass", reason.class.as_str())
+            .with("error", reason.message))
+        .collect();
+    let destinations: Vec<JsonValue> = backlogs.into_iter()
+        .map(|(recipient_id, backlog)| JsonValue::object()
+            .with("recipientId", recipient_id)
+            .with("pending", backlog.pending)
+            .with("inFlight", backlog.in_flight)
+            .with("dead", backlog.dead))
+        .collect();
+    let node = JsonValue::object()
+        .with("id", "local")
+        .with("status", "up")
+        .with("startedAt", format_rfc3339(started_at));
+
+    Ok(JsonValue::object()
+        .with("nodes", vec![node])
+        .with("stats", stats_to_json(stats))
+        .with("destinations", destinations)
+        .with("recentFailures", recent_failures))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        db::{memory::MemoryStore, StoreWrite},
+        msgproc::message::{AttemptRecord, DeliveryError, Message},
+    };
+
+    use super::*;
+
+    fn failed_message(store: &MemoryStore, id: &str, recipient_id: &str, status: MessageStatus, finished_at: i64) {
+        let message = Message::new(id.to_string(), recipient_id.to_string(), "s".to_string(), "e".to_string(), vec![]);
+        let finished_at = OffsetDateTime::from_unix_timestamp(finished_at).unwrap();
+        store.write_batch(&[
+            StoreWrite::InsertMessage(Box::new(message)),
+            StoreWrite::RecordAttempt(AttemptRecord { message_id: id.to_string(), attempt: 1, finished_at, outcome: AttemptOutcome::Failed(DeliveryError::from_response(500, "HTTP 500")) }),
+            StoreWrite::UpdateStatus { message_id: id.to_string(), status, next_attempt_at: None },
+        ]).unwrap();
+    }
+
+    #[test]
+    fn test_if_overview_groups_the_backlog_by_destination_and_lists_recent_failures() {
+        let store = MemoryStore::new();
+        failed_message(&store, "a", "r1", MessageStatus::Pending, 1_704_067_200);
+        failed_message(&store, "b", "r1", MessageStatus::Dead, 1_704_067_300);
+        failed_message(&store, "c", "r2", MessageStatus::Pending, 1_704_067_100);
+        store.write(StoreWrite::InsertMessage(Box::new(Message::new("d".to_string(), "r2".to_string(), "s".to_string(), "e".to_string(), vec![])))).unwrap();
+
+        let overview = overview(&ProcessorStats::default(), &store, OffsetDateTime::UNIX_EPOCH).unwrap();
+        let destinations = overview.get("destinations").unwrap().as_array().unwrap();
+        assert_eq!(destinations[0].get("recipientId").unwrap().as_str(), Some("r1"));
+        assert_eq!(destinations[0].get("pending").and_then(JsonValue::as_u64), Some(1));
+        assert_eq!(destinations[0].get("dead").and_then(JsonValue::as_u64), Some(1));
+        assert_eq!(destinations[1].get("pending").and_then(JsonValue::as_u64), Some(2));
+
+        let failures: Vec<&str> = overview.get("recentFailures").unwrap().as_array().unwrap().iter()
+            .map(|failure| failure.get("messageId").unwrap().as_str().unwrap())
+            .collect();
+        assert_eq!(failures, vec!["b", "a", "c"]);
+        assert_eq!(overview.get("nodes").unwrap().as_array().unwrap().len(), 1);
+    }
+}
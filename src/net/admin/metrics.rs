@@ -0,0 +1,115 @@
+use std::{fmt::Write, sync::atomic::Ordering};
+
+use crate::msgproc::processor::{MessageProcessor, TopicCounters};
+
+/// A metric of each topic: its name, its help and how it is read from the counters
+type TopicMetric = (&'static str, &'static str, fn(&TopicCounters) -> u64);
+
+/// The content type of the Prometheus text exposition format
+pub const METRICS_CONTENT_TYPE: &str = "text/plain; version=0.0.4; charset=utf-8";
+
+/// Render the processor counters in the Prometheus text exposition format, so they can be scraped
+/// from `/admin/metrics`. The counters of each topic are labelled with its namespace and topic, and
+/// the latencies of the stages of the attempts with the worker that made them
+pub fn render_metrics(processor: &MessageProcessor) -> String {
+    let stats = processor.stats();
+    let mut metrics = String::new();
+    let globals = [
+        ("angler_messages_published_total", "Messages published", stats.published.load(Ordering::Relaxed)),
+        ("angler_delivery_attempts_total", "Delivery attempts", stats.attempts.load(Ordering::Relaxed)),
+        ("angler_messages_delivered_total", "Messages delivered", stats.delivered.load(Ordering::Relaxed)),
+        ("angler_messages_dead_total", "Messages that exhausted their retry policy", stats.dead.load(Ordering::Relaxed)),
+        ("angler_messages_filtered_total", "Messages skipped by the attribute filter of their destination", stats.filtered.load(Ordering::Relaxed)),
+        ("angler_messages_cancelled_total", "Pending messages cancelled by an operator", stats.cancelled.load(Ordering::Relaxed)),
+    ];
+    for (name, help, value) in globals {
+        write_header(&mut metrics, name, help);
+        let _ = writeln!(metrics, "{} {}", name, value);
+    }
+    write_header(&mut metrics, "angler_messages_outstanding", "Messages published or recovered that did not finish yet");
+    let _ = writeln!(metrics, "angler_messages_outstanding {}", stats.outstanding());
+
+    write_header(&mut metrics, "angler_delivery_failures_total", "Failed delivery attempts by error class");
+    for (class, count) in stats.failures() {
+        let _ = writeln!(metrics, "angler_delivery_failures_total{{class=\"{}\"}} {}", class.as_str(), count);
+    }
+
+    write_header(&mut metrics, "angler_retry_budget_exhausted_total", "Retries postponed by the retry budget of their destination");
+    for (recipient_id, count) in stats.retry_budget_exhausted() {
+        let _ = writeln!(metrics, "angler_retry_budget_exhausted_total{{recipient_id=\"{}\"}} {}", escape_label(&recipient_id), count);
+    }
+
+    let topics = stats.topics();
+    let per_topic: [TopicMetric; 4] = [
+        ("angler_topic_messages_in_total", "Messages published by namespace and topic", |counters| counters.messages_in),
+        ("angler_topic_bytes_in_total", "Payload bytes published by namespace and topic", |counters| counters.bytes_in),
+        ("angler_topic_deliveries_total", "Messages delivered by namespace and topic", |counters| counters.delivered),
+        ("angler_topic_dead_total", "Dead messages by namespace and topic", |counters| counters.dead),
+    ];
+    for (name, help, value) in per_topic {
+        write_header(&mut metrics, name, help);
+        for ((namespace, topic), counters) in &topics {
+            let _ = writeln!(metrics, "{}{{service_id=\"{}\",event_id=\"{}\"}} {}", name, escape_label(namespace), escape_label(topic), value(counters));
+        }
+    }
+
+    let name = "angler_pipeline_stage_seconds";
+    write_typed_header(&mut metrics, name, "Time spent in each stage of the attempts by worker", "histogram");
+    for ((worker, stage), histogram) in stats.pipeline.histograms() {
+        let labels = format!("worker=\"{}\",stage=\"{}\"", escape_label(&worker), stage.as_str());
+        for (bound, count) in histogram.cumulative() {
+            let _ = writeln!(metrics, "{}_bucket{{{},le=\"{}\"}} {}", name, labels, bound, count);
+        }
+        let _ = writeln!(metrics, "{}_bucket{{{},le=\"+Inf\"}} {}", name, labels, histogram.count);
+        let _ = writeln!(metrics, "{}_sum{{{}}} {}", name, labels, histogram.sum.as_secs_f64());
+        let _ = writeln!(metrics, "{}_count{{{}}} {}", name, labels, histogram.count);
+    }
+    let lags = processor.lags();
+    write_header(&mut metrics, "angler_destination_lagging", "If the destination is beyond the msgproc.lagging thresholds");
+    for (recipient_id, lag) in &lags {
+        let _ = writeln!(metrics, "angler_destination_lagging{{recipient_id=\"{}\"}} {}", escape_label(recipient_id), u8::from(lag.lagging));
+    }
+    write_header(&mut metrics, "angler_destination_backlog_age_seconds", "How long the last attempt of the destination waited since it was due");
+    for (recipient_id, lag) in &lags {
+        let _ = writeln!(metrics, "angler_destination_backlog_age_seconds{{recipient_id=\"{}\"}} {}", escape_label(recipient_id), lag.backlog_age.as_secs_f64());
+    }
+    write_header(&mut metrics, "angler_destination_latency_seconds", "The average time the responses of the destination take");
+    for (recipient_id, lag) in &lags {
+        let _ = writeln!(metrics, "angler_destination_latency_seconds{{recipient_id=\"{}\"}} {}", escape_label(recipient_id), lag.latency.as_secs_f64());
+    }
+    write_header(&mut metrics, "angler_destination_concurrency_limit", "How many attempts of the destination with maxConcurrency are sent at once");
+    for (recipient_id, concurrency) in processor.concurrencies() {
+        let _ = writeln!(metrics, "angler_destination_concurrency_limit{{recipient_id=\"{}\"}} {}", escape_label(&recipient_id), concurrency.limit());
+    }
+
+    write_header(&mut metrics, "angler_pipeline_queue_depth", "Items waiting in each stage of the attempts");
+    for (stage, depth) in processor.queue_depths() {
+        let _ = writeln!(metrics, "angler_pipeline_queue_depth{{stage=\"{}\"}} {}", stage.as_str(), depth);
+    }
+    metrics
+}
+
+fn write_header(metrics: &mut String, name: &str, help: &str) {
+    let metric_type = if name.ends_with("_total") { "counter" } else { "gauge" };
+    write_typed_header(metrics, name, help, metric_type);
+}
+
+fn write_typed_header(metrics: &mut String, name: &str, help: &str, metric_type: &str) {
+    let _ = writeln!(metrics, "# HELP {} {}\n# TYPE {} {}", name, help, name, metric_type);
+}
+
+/// Escape a label value, as its backslashes, quotes and line breaks would end it early
+fn escape_label(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_if_label_values_are_escaped() {
+        assert_eq!(escape_label("orders"), "orders");
+        assert_eq!(escape_label("a\"b\\c\nd"), "a\\\"b\\\\c\\nd");
+    }
+}
@@ -0,0 +1,607 @@
+pub mod dashboard;
+pub mod metrics;
+pub mod search;
+
+use std::{io, net::ToSocketAddrs, sync::Arc};
+
+use time::OffsetDateTime;
+
+#[cfg(feature = "chaos")]
+use crate::chaos::FaultInjector;
+use crate::{
+    db::{MessageQuery, MessageStore, StoreError, StoreWrite},
+    msgproc::{
+        bulk::{start_bulk_job, BulkOperation},
+        capture::{CapturedBody, CapturedExchange, DebugCaptures},
+        lifecycle::{ForceTransitionError, MessageState},
+        message::Annotation,
+        processor::{MessageProcessor, RecoveryReport},
+    },
+    net::{
+        client::restful::{actor, annotation_to_json, error_response, job_response, json_response, message_to_json, MessageSearch},
+        http::{ConnectionLimits, HttpHandler, HttpHeaders, HttpRequest, HttpResponse, HttpServer},
+        pool::ConnectionPool,
+    },
+    syscom::{jobs::JobRegistry, usage::usage_ndjson},
+    utils::{base64, json::JsonValue, log::{self, LogFilter}, time::{format_rfc3339, parse_rfc3339}},
+};
+
+use self::{
+    dashboard::{overview, stats_to_json, topic_stats_to_json, DASHBOARD_HTML},
+    metrics::{render_metrics, METRICS_CONTENT_TYPE},
+};
+
+/// The maximum length, in characters, of the note and the author of an annotation
+const MAX_ANNOTATION_LENGTH: usize = 1024;
+
+/// The most destinations paused or resumed by a bulk job
+const MAX_BULK_DESTINATIONS: usize = 100_000;
+
+/// The API used by operators to inspect and control a running node. It is only opened when
+/// `net.admin.port` is set
+pub struct AdminApi {
+    processor: Arc<MessageProcessor>,
+    store: Arc<dyn MessageStore>,
+    captures: Arc<DebugCaptures>,
+    pool: Arc<ConnectionPool>,
+    jobs: Arc<JobRegistry>,
+    auth_token: Option<String>,
+    started_at: OffsetDateTime,
+    #[cfg(feature = "chaos")]
+    faults: Arc<FaultInjector>,
+}
+
+impl AdminApi {
+    pub fn new(processor: Arc<MessageProcessor>, store: Arc<dyn MessageStore>) -> AdminApi {
+        let started_at = processor.clock().now();
+        AdminApi {
+            jobs: Arc::new(JobRegistry::new(processor.clock().clone())),
+            processor,
+            store,
+            captures: Arc::new(DebugCaptures::new()),
+            pool: Arc::new(ConnectionPool::default()),
+            auth_token: None,
+            started_at,
+            #[cfg(feature = "chaos")]
+            faults: Arc::new(FaultInjector::new()),
+        }
+    }
+
+    /// Require the token on every request. See `net.admin.authToken`
+    pub fn with_auth_token(mut self, token: String) -> AdminApi {
+        self.auth_token = Some(token);
+        self
+    }
+
+    /// Control the given DebugCaptures through `/admin/debug-captures`
+    pub fn with_captures(mut self, captures: Arc<DebugCaptures>) -> AdminApi {
+        self.captures = captures;
+        self
+    }
+
+    /// Report the warm connections of the given ConnectionPool in `/admin/connections`
+    pub fn with_connection_pool(mut self, pool: Arc<ConnectionPool>) -> AdminApi {
+        self.pool = pool;
+        self
+    }
+
+    /// Control the given FaultInjector through `/admin/chaos`
+    #[cfg(feature = "chaos")]
+    pub fn with_faults(mut self, faults: Arc<FaultInjector>) -> AdminApi {
+        self.faults = faults;
+        self
+    }
+
+    /// Run the bulk jobs in the given JobRegistry and follow all of its jobs in `/admin/jobs`
+    pub fn with_jobs(mut self, jobs: Arc<JobRegistry>) -> AdminApi {
+        self.jobs = jobs;
+        self
+    }
+
+    /// Start a HttpServer on the address serving this API
+    pub fn listen<A: ToSocketAddrs>(api: Arc<AdminApi>, address: A) -> io::Result<HttpServer> {
+        AdminApi::listen_with_limits(api, address, ConnectionLimits::default())
+    }
+
+    /// Start a HttpServer like `listen`, refusing the connections beyond the limits
+    pub fn listen_with_limits<A: ToSocketAddrs>(api: Arc<AdminApi>, address: A, limits: ConnectionLimits) -> io::Result<HttpServer> {
+        let handler: Arc<HttpHandler> = Arc::new(move |request: &HttpRequest| api.handle(request));
+        HttpServer::bind_with_limits(address, handler, limits)
+    }
+
+    /// Route the request to its handler
+    pub fn handle(&self, request: &HttpRequest) -> HttpResponse {
+        if !self.is_authorized(request) {
+            let mut response = error_response(401, "a valid admin token is required");
+            response.headers.set("WWW-Authenticate", "Basic realm=\"Angler admin\"");
+            return response;
+        }
+
+        let segments: Vec<&str> = request.path().trim_matches('/').split('/').collect();
+        match (request.method.as_str(), segments.as_slice()) {
+            ("GET", ["admin", "stats"]) => json_response(200, &stats_to_json(self.processor.stats())),
+            ("GET", ["admin", "topics", "stats"]) => json_response(200, &topic_stats_to_json(self.processor.stats())),
+            ("GET", ["admin", "metrics"]) => HttpResponse::with_body(200, METRICS_CONTENT_TYPE, render_metrics(&self.processor)),
+            ("GET", ["admin", "overview"]) => match overview(self.processor.stats(), self.store.as_ref(), self.started_at) {
+                Ok(overview) => json_response(200, &overview),
+                Err(err) => error_response(500, &err.to_string()),
+            },
+            ("GET", ["admin", "recovery"]) => match self.processor.recovery() {
+                Some(recovery) => json_response(200, &recovery_to_json(&recovery)),
+                None => error_response(404, "the store was not recovered"),
+            },
+            ("GET", ["admin", "connections"]) => json_response(200, &JsonValue::object()
+                .with("destinations", self.pool.warm_counts().into_iter()
+                    .map(|(recipient_id, (open, target))| JsonValue::object().with("recipientId", recipient_id).with("open", open).with("target", target))
+                    .collect::<Vec<_>>())),
+            ("GET", ["admin", "usage"]) => self.export_usage(request),
+            ("GET", ["admin", "log-level"]) => json_response(200, &JsonValue::object().with("directives", log::filter().to_string())),
+            ("PUT", ["admin", "log-level"]) => self.put_log_level(request),
+            ("GET", ["admin", "dashboard"]) => HttpResponse::with_body(200, "text/html; charset=utf-8", DASHBOARD_HTML),
+            ("POST", ["admin", "bulk", "messages:cancel"]) => self.start_bulk_job(request, |body| parse_cancel_filter(body).map(BulkOperation::CancelMessages)),
+            ("POST", ["admin", "bulk", "destinations:pause"]) => self.start_bulk_job(request, |body| parse_recipient_ids(body).map(BulkOperation::PauseDestinations)),
+            ("POST", ["admin", "bulk", "destinations:resume"]) => self.start_bulk_job(request, |body| parse_recipient_ids(body).map(BulkOperation::ResumeDestinations)),
+            (method, ["admin", "jobs"]) => job_response(&self.jobs, method, None),
+            (method, ["admin", "jobs", id]) => job_response(&self.jobs, method, Some(id)),
+            ("GET", ["admin", "paused-destinations"]) => json_response(200, &self.processor.paused().into_iter()
+                .fold(JsonValue::object(), |json, (recipient_id, due)| json.with(&recipient_id, JsonValue::object().with("dueMessages", due)))),
+            ("GET", ["admin", "messages"]) => match MessageSearch::parse(request) {
+                Ok(search) => search.respond(self.store.find_messages(&search.query)),
+                Err(err) => error_response(400, &err),
+            },
+            ("GET", ["admin", "messages", id, "annotations"]) => self.get_annotations(id),
+            ("POST", ["admin", "messages", id, "annotations"]) => self.annotate(id, request),
+            ("POST", ["admin", "messages", id, "transition"]) => self.force_transition(id, request),
+            ("GET", ["admin", "debug-captures", id]) => json_response(200, &JsonValue::object()
+                .with("enabled", self.captures.is_enabled(id))
+                .with("exchanges", self.captures.exchanges(id).iter().map(exchange_to_json).collect::<Vec<_>>())),
+            ("PUT", ["admin", "debug-captures", id]) => {
+                self.captures.enable(id);
+                json_response(200, &JsonValue::object().with("enabled", true))
+            }
+            ("DELETE", ["admin", "debug-captures", id]) => match self.captures.disable(id) {
+                true => HttpResponse::new(204),
+                false => error_response(404, "the debug capture is not enabled for this destination"),
+            },
+            #[cfg(feature = "chaos")]
+            ("GET", ["admin", "chaos"]) => json_response(200, &self.faults.settings().to_json()),
+            #[cfg(feature = "chaos")]
+            ("PUT", ["admin", "chaos"]) => self.put_chaos(request),
+            #[cfg(feature = "chaos")]
+            ("DELETE", ["admin", "chaos"]) => {
+                self.faults.reset();
+                HttpResponse::new(204)
+            }
+            (_, ["admin", "stats" | "metrics" | "overview" | "recovery" | "log-level" | "dashboard" | "connections" | "usage" | "messages"] | ["admin", "topics", "stats"] | ["admin", "debug-captures", _] | ["admin", "messages", _, "annotations" | "transition"]) => {
+                error_response(405, "method not allowed")
+            }
+            #[cfg(feature = "chaos")]
+            (_, ["admin", "chaos"]) => error_response(405, "method not allowed"),
+            _ => error_response(404, "resource not found"),
+        }
+    }
+
+    /// Accept the token as `Bearer <token>` or as the password of a `Basic` authorization, so
+    /// browsers can open the dashboard
+    fn is_authorized(&self, request: &HttpRequest) -> bool {
+        let Some(token) = &self.auth_token else {
+            return true;
+        };
+        let Some(authorization) = request.headers.get("Authorization") else {
+            return false;
+        };
+        let sent = match authorization.split_once(' ') {
+            Some((scheme, value)) if scheme.eq_ignore_ascii_case("bearer") => value.trim().as_bytes().to_vec(),
+            Some((scheme, value)) if scheme.eq_ignore_ascii_case("basic") => {
+                let Some(credentials) = base64::decode(value.trim()) else {
+                    return false;
+                };
+                match credentials.iter().position(|b| *b == b':') {
+                    Some(separator) => credentials[separator + 1..].to_vec(),
+                    None => return false,
+                }
+            }
+            _ => return false,
+        };
+        constant_time_eq(&sent, token.as_bytes())
+    }
+
+    /// Replace the log filter of the process, like `{"directives": "info,angler::msgproc=debug"}`
+    fn put_log_level(&self, request: &HttpRequest) -> HttpResponse {
+        let body = match JsonValue::parse_bytes(&request.body) {
+            Ok(body) => body,
+            Err(err) => return error_response(400, &format!("body is not valid JSON: {}", err)),
+        };
+        let Some(directives) = body.get("directives").and_then(JsonValue::as_str) else {
+            return error_response(400, "directives should be a string. Example: info,angler::msgproc=debug");
+        };
+        match LogFilter::parse(directives) {
+            Ok(filter) => {
+                let json = JsonValue::object().with("directives", filter.to_string());
+                log::set_filter(filter);
+                json_response(200, &json)
+            }
+            Err(err) => error_response(400, &err.to_string()),
+        }
+    }
+
+    /// Export the usage records of `GET /admin/usage` as NDJSON
+    fn export_usage(&self, request: &HttpRequest) -> HttpResponse {
+        let (mut from, mut to, mut namespace) = (None, None, None);
+        for (key, value) in request.query_params() {
+            match key.as_str() {
+                "from" => match parse_rfc3339(&value) {
+                    Ok(value) => from = Some(value),
+                    Err(_) => return error_response(400, "from should be a RFC 3339 timestamp"),
+                },
+                "to" => match parse_rfc3339(&value) {
+                    Ok(value) => to = Some(value),
+                    Err(_) => return error_response(400, "to should be a RFC 3339 timestamp"),
+                },
+                "serviceId" => namespace = Some(value),
+                _ => return error_response(400, &format!("{} is not a valid usage parameter", key)),
+            }
+        }
+        let (Some(from), Some(to)) = (from, to) else {
+            return error_response(400, "from and to are required");
+        };
+        match self.store.find_usage(from, to) {
+            Ok(mut records) => {
+                records.retain(|record| namespace.as_ref().is_none_or(|namespace| &record.namespace == namespace));
+                HttpResponse::with_body(200, "application/x-ndjson", usage_ndjson(&records))
+            }
+            Err(err) => error_response(500, &err.to_string()),
+        }
+    }
+
+    /// Start the bulk job of the request body, answering with the job whose progress is read from
+    /// `GET /admin/jobs/{id}`
+    fn start_bulk_job(&self, request: &HttpRequest, parse: impl FnOnce(&JsonValue) -> Result<BulkOperation, String>) -> HttpResponse {
+        let body = match JsonValue::parse_bytes(&request.body) {
+            Ok(body) => body,
+            Err(err) => return error_response(400, &format!("body is not valid JSON: {}", err)),
+        };
+        let operation = match parse(&body) {
+            Ok(operation) => operation,
+            Err(err) => return error_response(400, &err),
+        };
+        let job = start_bulk_job(&self.jobs, self.processor.clone(), self.store.clone(), operation, actor(request));
+        let mut response = json_response(202, &job.to_json());
+        response.headers.set("Location", &format!("/admin/jobs/{}", job.id));
+        response
+    }
+
+    fn get_annotations(&self, message_id: &str) -> HttpResponse {
+        match self.store.get_message(message_id) {
+            Ok(Some(message)) => json_response(200, &JsonValue::Array(message.annotations.iter().map(annotation_to_json).collect())),
+            Ok(None) => error_response(404, "message not found"),
+            Err(err) => error_response(500, &err.to_string()),
+        }
+    }
+
+    /// Attach a note to a message, like `{"note": "customer notified", "author": "alice"}`
+    fn annotate(&self, message_id: &str, request: &HttpRequest) -> HttpResponse {
+        let body = match JsonValue::parse_bytes(&request.body) {
+            Ok(body) => body,
+            Err(err) => return error_response(400, &format!("body is not valid JSON: {}", err)),
+        };
+        let is_valid = |text: &str| !text.trim().is_empty() && text.chars().count() <= MAX_ANNOTATION_LENGTH;
+        let Some(note) = body.get("note").and_then(JsonValue::as_str).filter(|note| is_valid(note)) else {
+            return error_response(400, &format!("note should be a non-empty string with up to {} characters", MAX_ANNOTATION_LENGTH));
+        };
+        let author = match body.get("author") {
+            None | Some(JsonValue::Null) => None,
+            Some(author) => match author.as_str().filter(|author| is_valid(author)) {
+                Some(author) => Some(author.trim().to_string()),
+                None => return error_response(400, &format!("author should be a non-empty string with up to {} characters", MAX_ANNOTATION_LENGTH)),
+            },
+        };
+        let annotation = Annotation { note: note.trim().to_string(), author, created_at: self.processor.clock().now() };
+        let json = annotation_to_json(&annotation);
+        match self.store.write(StoreWrite::AnnotateMessage { message_id: message_id.to_string(), annotation }) {
+            Ok(()) => json_response(201, &json),
+            Err(StoreError::MessageNotFound(_)) => error_response(404, "message not found"),
+            Err(err) => error_response(500, &err.to_string()),
+        }
+    }
+
+    /// Force a stuck message into a state, like `{"state": "dead", "reason": "stuck after the crash of b1"}`,
+    /// recording the reason and the `X-Angler-Actor` in an annotation
+    fn force_transition(&self, message_id: &str, request: &HttpRequest) -> HttpResponse {
+        let body = match JsonValue::parse_bytes(&request.body) {
+            Ok(body) => body,
+            Err(err) => return error_response(400, &format!("body is not valid JSON: {}", err)),
+        };
+        let Some(state) = body.get("state").and_then(JsonValue::as_str).and_then(MessageState::from_name) else {
+            return error_response(400, "state should be scheduled, retrying, delivered, dead, cancelled or expired");
+        };
+        let is_valid = |text: &str| !text.trim().is_empty() && text.chars().count() <= MAX_ANNOTATION_LENGTH;
+        let Some(reason) = body.get("reason").and_then(JsonValue::as_str).filter(|reason| is_valid(reason)) else {
+            return error_response(400, &format!("reason should be a non-empty string with up to {} characters", MAX_ANNOTATION_LENGTH));
+        };
+        match self.processor.force_transition(message_id, state, reason.trim(), actor(request)) {
+            Ok(message) => json_response(200, &message_to_json(&message)),
+            Err(err @ ForceTransitionError::NotForceable(_)) => error_response(400, &err.to_string()),
+            Err(ForceTransitionError::MessageNotFound(_)) => error_response(404, "message not found"),
+            Err(err @ ForceTransitionError::AttemptRunning(_)) => error_response(409, &err.to_string()),
+            Err(err) => error_response(500, &err.to_string()),
+        }
+    }
+
+    #[cfg(feature = "chaos")]
+    fn put_chaos(&self, request: &HttpRequest) -> HttpResponse {
+        let body = match JsonValue::parse_bytes(&request.body) {
+            Ok(body) => body,
+            Err(err) => return error_response(400, &format!("body is not valid JSON: {}", err)),
+        };
+        match self.faults.settings().merge_json(&body) {
+            Ok(settings) => {
+                self.faults.update(settings);
+                json_response(200, &self.faults.settings().to_json())
+            }
+            Err(err) => error_response(400, &err),
+        }
+    }
+}
+
+/// Read which pending messages are cancelled, like `{"recipientId": "r1", "eventId": "order.created"}`.
+/// At least one filter is required, so a request can not cancel every message by mistake
+fn parse_cancel_filter(body: &JsonValue) -> Result<MessageQuery, String> {
+    let optional_string = |field: &str| -> Result<Option<String>, String> {
+        match body.get(field) {
+            None | Some(JsonValue::Null) => Ok(None),
+            Some(value) => value.as_str().map(|value| Some(value.to_string())).ok_or_else(|| format!("{} should be a string", field)),
+        }
+    };
+    let optional_time = |field: &str| -> Result<Option<OffsetDateTime>, String> {
+        optional_string(field)?
+            .map(|value| parse_rfc3339(&value).map_err(|_| format!("{} should be a RFC 3339 timestamp", field)))
+            .transpose()
+    };
+    let attributes = match body.get("attributes") {
+        None | Some(JsonValue::Null) => Vec::new(),
+        Some(attributes) => attributes.as_object().ok_or("attributes should be an object")?.iter()
+            .map(|(key, value)| value.as_str().map(|value| (key.clone(), value.to_string())).ok_or_else(|| format!("attributes.{} should be a string", key)))
+            .collect::<Result<_, _>>()?,
+    };
+    let query = MessageQuery {
+        recipient_id: optional_string("recipientId")?,
+        namespace: optional_string("serviceId")?,
+        topic: optional_string("eventId")?,
+        created_after: optional_time("createdAfter")?,
+        created_before: optional_time("createdBefore")?,
+        attributes,
+        ..MessageQuery::default()
+    };
+    if query == MessageQuery::default() {
+        return Err(String::from("at least one of recipientId, serviceId, eventId, createdAfter, createdBefore or attributes is required"));
+    }
+    Ok(query)
+}
+
+/// Read the destinations of a bulk job, like `{"recipientIds": ["r1", "r2"]}`
+fn parse_recipient_ids(body: &JsonValue) -> Result<Vec<String>, String> {
+    let invalid = || format!("recipientIds should be an array with 1 to {} strings", MAX_BULK_DESTINATIONS);
+    let recipient_ids = body.get("recipientIds").and_then(JsonValue::as_array).filter(|ids| (1..=MAX_BULK_DESTINATIONS).contains(&ids.len())).ok_or_else(invalid)?;
+    recipient_ids.iter().map(|id| id.as_str().map(String::from).ok_or_else(invalid)).collect()
+}
+
+fn headers_to_json(headers: &HttpHeaders) -> JsonValue {
+    JsonValue::Array(headers.iter().map(|(name, value)| JsonValue::Array(vec![name.into(), value.into()])).collect())
+}
+
+/// Bodies are shown as text, replacing the invalid UTF-8 sequences
+fn with_body(json: JsonValue, body: &CapturedBody) -> JsonValue {
+    json.with("body", String::from_utf8_lossy(&body.bytes).to_string())
+        .with("bodyTruncated", body.truncated)
+}
+
+fn exchange_to_json(exchange: &CapturedExchange) -> JsonValue {
+    let request = JsonValue::object()
+        .with("method", exchange.method.as_str())
+        .with("url", exchange.url.as_str())
+        .with("headers", headers_to_json(&exchange.request_headers));
+    let response = exchange.response.as_ref().map(|response| {
+        let json = JsonValue::object()
+            .with("status", response.status)
+            .with("headers", headers_to_json(&response.headers));
+        with_body(json, &response.body)
+    });
+    JsonValue::object()
+        .with("messageId", exchange.message_id.as_str())
+        .with("attempt", exchange.attempt)
+        .with("capturedAt", format_rfc3339(exchange.captured_at))
+        .with("request", with_body(request, &exchange.request_body))
+        .with("response", response)
+        .with("error", exchange.error.as_deref())
+}
+
+fn recovery_to_json(recovery: &RecoveryReport) -> JsonValue {
+    JsonValue::object()
+        .with("resetInFlight", recovery.reset_in_flight)
+        .with("rescheduled", recovery.rescheduled)
+        .with("statuses", recovery.statuses.iter().fold(JsonValue::object(), |json, (status, count)| json.with(status.as_str(), *count)))
+}
+
+/// Compare the bytes without stopping at the first difference, so the time taken does not tell
+/// how much of a token is right
+pub(crate) fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0u8, |diff, (x, y)| diff | (x ^ y)) == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        db::{memory::MemoryStore, batch::BatchConfiguration},
+        msgproc::{delivery::Deliverer, message::{AttemptOutcome, Message, MessageStatus}},
+        net::client::restful::ACTOR_HEADER,
+        syscom::usage::UsageRecord,
+    };
+
+    use super::*;
+
+    struct AlwaysDelivers;
+
+    impl Deliverer for AlwaysDelivers {
+        fn deliver(&self, _: &Message) -> AttemptOutcome {
+            AttemptOutcome::Delivered
+        }
+    }
+
+    fn request(path: &str, authorization: Option<&str>) -> HttpRequest {
+        let mut request = HttpRequest::new("GET", path);
+        if let Some(authorization) = authorization {
+            request.headers.set("Authorization", authorization);
+        }
+        request
+    }
+
+    #[test]
+    fn test_if_admin_token_is_required_as_bearer_or_basic_password() {
+        let store = Arc::new(MemoryStore::new());
+        let processor = Arc::new(MessageProcessor::start(1, store.clone(), BatchConfiguration::default(), Arc::new(AlwaysDelivers)));
+        let api = AdminApi::new(processor, store).with_auth_token(String::from("s3cr3t"));
+
+        let denied = api.handle(&request("/admin/dashboard", None));
+        assert_eq!(denied.status, 401);
+        assert!(denied.headers.get("WWW-Authenticate").unwrap().starts_with("Basic"));
+        assert_eq!(api.handle(&request("/admin/stats", Some("Bearer wrong"))).status, 401);
+
+        assert_eq!(api.handle(&request("/admin/stats", Some("Bearer s3cr3t"))).status, 200);
+        // "admin:s3cr3t"
+        let dashboard = api.handle(&request("/admin/dashboard", Some("Basic YWRtaW46czNjcjN0")));
+        assert_eq!(dashboard.status, 200);
+        assert!(dashboard.headers.get("Content-Type").unwrap().starts_with("text/html"));
+        assert_eq!(api.handle(&request("/admin/overview", Some("Basic YWRtaW46czNjcjN0"))).status, 200);
+    }
+
+    #[test]
+    fn test_if_operators_annotate_messages() {
+        let store = Arc::new(MemoryStore::new());
+        let processor = Arc::new(MessageProcessor::start(1, store.clone(), BatchConfiguration::default(), Arc::new(AlwaysDelivers)));
+        processor.publish(Message::new(String::from("a"), String::from("r"), String::from("s"), String::from("e"), vec![])).unwrap();
+        let api = AdminApi::new(processor, store.clone());
+        let annotate = |id: &str, body: &str| {
+            let mut request = HttpRequest::new("POST", &format!("/admin/messages/{}/annotations", id));
+            request.body = body.as_bytes().to_vec();
+            api.handle(&request).status
+        };
+
+        assert_eq!(annotate("a", r#"{"note": "customer notified", "author": "alice"}"#), 201);
+        assert_eq!(annotate("a", r#"{"note": "receiver bug #4521"}"#), 201);
+        assert_eq!(annotate("a", r#"{"note": " "}"#), 400);
+        assert_eq!(annotate("missing", r#"{"note": "lost"}"#), 404);
+
+        let annotations = store.get_message("a").unwrap().unwrap().annotations;
+        assert_eq!(annotations.iter().map(|annotation| annotation.note.as_str()).collect::<Vec<_>>(), vec!["customer notified", "receiver bug #4521"]);
+        assert_eq!(annotations[0].author.as_deref(), Some("alice"));
+        let listed = api.handle(&request("/admin/messages/a/annotations", None));
+        assert_eq!(JsonValue::parse_bytes(&listed.body).unwrap().as_array().map(Vec::len), Some(2));
+        assert_eq!(api.handle(&request("/admin/messages/missing/annotations", None)).status, 404);
+    }
+
+    #[test]
+    fn test_if_stuck_messages_are_forced_into_a_state_with_an_audit_annotation() {
+        let store = Arc::new(MemoryStore::new());
+        let mut stuck = Message::new(String::from("a"), String::from("r"), String::from("s"), String::from("e"), vec![]);
+        stuck.status = MessageStatus::InFlight;
+        store.write(StoreWrite::InsertMessage(Box::new(stuck))).unwrap();
+        let processor = Arc::new(MessageProcessor::start(1, store.clone(), BatchConfiguration::default(), Arc::new(AlwaysDelivers)));
+        let api = AdminApi::new(processor.clone(), store.clone());
+        let force = |id: &str, body: &str| {
+            let mut request = HttpRequest::new("POST", &format!("/admin/messages/{}/transition", id));
+            request.headers.set(ACTOR_HEADER, "alice");
+            request.body = body.as_bytes().to_vec();
+            api.handle(&request)
+        };
+
+        let forced = force("a", r#"{"state": "dead", "reason": "stuck after the crash of b1"}"#);
+        assert_eq!(forced.status, 200);
+        assert_eq!(JsonValue::parse_bytes(&forced.body).unwrap().get("status").and_then(JsonValue::as_str), Some("dead"));
+        processor.flush().unwrap();
+        let stored = store.get_message("a").unwrap().unwrap();
+        assert_eq!(stored.status, MessageStatus::Dead);
+        assert_eq!(stored.annotations[0].note, "Forced from inFlight to dead: stuck after the crash of b1");
+        assert_eq!(stored.annotations[0].author.as_deref(), Some("alice"));
+
+        assert_eq!(force("a", r#"{"state": "inFlight", "reason": "retry it"}"#).status, 400);
+        assert_eq!(force("a", r#"{"state": "dead"}"#).status, 400);
+        assert_eq!(force("a", r#"{"state": "gone", "reason": "typo"}"#).status, 400);
+        assert_eq!(force("missing", r#"{"state": "dead", "reason": "lost"}"#).status, 404);
+
+        // a waiting state schedules the message again
+        assert_eq!(force("a", r#"{"state": "retrying", "reason": "the receiver was fixed"}"#).status, 200);
+        for _ in 0..500 {
+            processor.flush().unwrap();
+            if store.get_message("a").unwrap().unwrap().status == MessageStatus::Delivered {
+                break;
+            }
+            std::thread::sleep(std::time::Duration::from_millis(5));
+        }
+        assert_eq!(store.get_message("a").unwrap().unwrap().status, MessageStatus::Delivered);
+    }
+
+    #[test]
+    fn test_if_bulk_jobs_are_started_and_followed() {
+        let store = Arc::new(MemoryStore::new());
+        let processor = Arc::new(MessageProcessor::start(1, store.clone(), BatchConfiguration::default(), Arc::new(AlwaysDelivers)));
+        let api = AdminApi::new(processor, store);
+        let start = |path: &str, body: &str| {
+            let mut request = HttpRequest::new("POST", path);
+            request.headers.set(ACTOR_HEADER, "alice");
+            request.body = body.as_bytes().to_vec();
+            api.handle(&request)
+        };
+
+        let started = start("/admin/bulk/destinations:pause", r#"{"recipientIds": ["r1", "r2"]}"#);
+        assert_eq!(started.status, 202);
+        let location = started.headers.get("Location").unwrap().to_string();
+        let mut job = JsonValue::Null;
+        for _ in 0..500 {
+            job = JsonValue::parse_bytes(&api.handle(&request(&location, None)).body).unwrap();
+            if job.get("state").and_then(JsonValue::as_str) != Some("running") {
+                break;
+            }
+            std::thread::sleep(std::time::Duration::from_millis(5));
+        }
+        assert_eq!(job.get("state").and_then(JsonValue::as_str), Some("succeeded"));
+        assert_eq!((job.get("changed").and_then(JsonValue::as_u64), job.get("actor").and_then(JsonValue::as_str)), (Some(2), Some("alice")));
+        let paused = JsonValue::parse_bytes(&api.handle(&request("/admin/paused-destinations", None)).body).unwrap();
+        assert_eq!(paused.pointer("r1.dueMessages").and_then(JsonValue::as_u64), Some(0));
+
+        assert_eq!(start("/admin/bulk/messages:cancel", "{}").status, 400);
+        assert_eq!(start("/admin/bulk/messages:cancel", r#"{"attributes": {"region": 1}}"#).status, 400);
+        assert_eq!(start("/admin/bulk/messages:cancel", r#"{"eventId": "order.created"}"#).status, 202);
+        assert_eq!(start("/admin/bulk/destinations:resume", r#"{"recipientIds": []}"#).status, 400);
+        assert_eq!(api.handle(&request("/admin/jobs", None)).status, 200);
+        let cancelled = api.handle(&HttpRequest::new("POST", &format!("{}:cancel", location)));
+        assert_eq!(JsonValue::parse_bytes(&cancelled.body).unwrap().get("state").and_then(JsonValue::as_str), Some("succeeded"));
+        assert_eq!(api.handle(&request("/admin/jobs/missing", None)).status, 404);
+    }
+
+    #[test]
+    fn test_if_usage_is_exported_as_ndjson() {
+        let store = Arc::new(MemoryStore::new());
+        let processor = Arc::new(MessageProcessor::start(1, store.clone(), BatchConfiguration::default(), Arc::new(AlwaysDelivers)));
+        let period_start = parse_rfc3339("2024-01-01T00:00:00Z").unwrap();
+        let record = |namespace: &str| UsageRecord {
+            namespace: namespace.to_string(),
+            period_start,
+            period_end: period_start + time::Duration::hours(1),
+            publishes: 3,
+            deliveries: 2,
+            storage_byte_hours: 100,
+        };
+        store.record_usage(&[record("orders"), record("billing")]).unwrap();
+        let api = AdminApi::new(processor, store);
+
+        let exported = api.handle(&request("/admin/usage?from=2024-01-01T00:00:00Z&to=2024-01-02T00:00:00Z", None));
+        assert_eq!(exported.headers.get("Content-Type"), Some("application/x-ndjson"));
+        let lines: Vec<JsonValue> = String::from_utf8(exported.body).unwrap().lines().map(|line| JsonValue::parse(line).unwrap()).collect();
+        assert_eq!(lines.iter().map(|line| line.get("serviceId").and_then(JsonValue::as_str).unwrap()).collect::<Vec<_>>(), vec!["billing", "orders"]);
+        let filtered = api.handle(&request("/admin/usage?from=2024-01-01T00:00:00Z&to=2024-01-02T00:00:00Z&serviceId=orders", None));
+        assert_eq!(String::from_utf8(filtered.body).unwrap().lines().count(), 1);
+        assert!(api.handle(&request("/admin/usage?from=2024-01-01T01:00:00Z&to=2024-01-02T00:00:00Z", None)).body.is_empty());
+        assert_eq!(api.handle(&request("/admin/usage?from=yesterday", None)).status, 400);
+    }
+}
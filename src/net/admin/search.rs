@@ -0,0 +1,226 @@
+use std::time::Duration;
+
+use clap::{Arg, ArgMatches, Command};
+use time::OffsetDateTime;
+
+use crate::{
+    ctx::config::NetworkingConfiguration,
+    msgproc::message::MessageStatus,
+    net::{
+        client::restful::NEXT_CURSOR_HEADER,
+        http::{percent_encode, send_request, HttpRequest, HttpUrl},
+    },
+    utils::{json::JsonValue, time::{format_rfc3339, DurationDeserializer}},
+};
+
+/// How long each request to the admin API can take
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// How many messages each page read by `messages search` has, the most `GET /admin/messages` returns
+const SEARCH_PAGE_SIZE: usize = 1000;
+
+/// The `messages` subcommand, that inspects the messages of a running instance
+pub fn messages_command() -> Command {
+    Command::new("messages")
+        .about("Inspect the messages of a running Angler instance through its admin API")
+        .subcommand_required(true)
+        .subcommand(Command::new("search")
+            .about("Print the messages that match the filters, reading every page of the results")
+            .arg(Arg::new("status").long("status").help("Only the messages with the status: pending, inFlight, delivered or dead"))
+            .arg(Arg::new("destination").long("destination").help("Only the messages of the destination, by its recipientId"))
+            .arg(Arg::new("service").long("service").help("Only the messages of the serviceId"))
+            .arg(Arg::new("event").long("event").help("Only the messages of the eventId"))
+            .arg(Arg::new("since").long("since").help("Only the messages created in this period before now. Uses the Angler time syntax, like 2h"))
+            .arg(Arg::new("limit").long("limit").help("The most messages printed. Defaults to all of them"))
+            .arg(Arg::new("output").long("output").default_value("table").help("How the messages are printed: table, json or ndjson"))
+            .arg(Arg::new("url").long("url")
+                .help("The URL of the admin API of the Angler instance. Defaults to the net.admin listener of the configuration, on the local host"))
+            .arg(Arg::new("token").long("token").help("The admin token. Defaults to net.admin.authToken")))
+}
+
+/// How `messages search` prints the messages
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SearchOutput {
+    /// A column for each of the main fields of the messages
+    Table,
+    /// A JSON list with the messages
+    Json,
+    /// A line with the JSON of each message
+    Ndjson,
+}
+
+impl SearchOutput {
+    pub fn from_name(name: &str) -> Option<SearchOutput> {
+        match name {
+            "table" => Some(SearchOutput::Table),
+            "json" => Some(SearchOutput::Json),
+            "ndjson" => Some(SearchOutput::Ndjson),
+            _ => None,
+        }
+    }
+}
+
+/// The filters of `messages search`
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct MessageSearchFilter {
+    pub status: Option<MessageStatus>,
+    pub recipient_id: Option<String>,
+    pub namespace: Option<String>,
+    pub topic: Option<String>,
+    pub created_after: Option<OffsetDateTime>,
+}
+
+impl MessageSearchFilter {
+    /// Return the path of `GET /admin/messages` that reads the page at the cursor
+    pub fn page_path(&self, page_size: usize, cursor: Option<&str>) -> String {
+        let mut params = vec![(String::from("limit"), page_size.to_string())];
+        let filters = [
+            ("status", self.status.map(|status| status.as_str().to_string())),
+            ("recipientId", self.recipient_id.clone()),
+            ("serviceId", self.namespace.clone()),
+            ("eventId", self.topic.clone()),
+            ("createdAfter", self.created_after.map(format_rfc3339)),
+            ("cursor", cursor.map(str::to_string)),
+        ];
+        params.extend(filters.into_iter().filter_map(|(key, value)| Some((key.to_string(), value?))));
+        let query: Vec<String> = params.iter().map(|(key, value)| format!("{}={}", key, percent_encode(value))).collect();
+        format!("/admin/messages?{}", query.join("&"))
+    }
+}
+
+/// Read the filters of `messages search`, counting `--since` back from now
+fn parse_filter(args: &ArgMatches, now: OffsetDateTime) -> Result<MessageSearchFilter, String> {
+    let arg = |name: &str| args.get_one::<String>(name).cloned();
+    let status = match arg("status") {
+        Some(status) => Some(MessageStatus::from_name(&status).ok_or_else(|| format!("--status {} should be pending, inFlight, delivered or dead", status))?),
+        None => None,
+    };
+    let created_after = match arg("since") {
+        Some(since) => {
+            let period = since.as_str().to_duration().ok().filter(|period| period.is_positive())
+                .ok_or_else(|| format!("--since {} should be a duration, like 2h", since))?;
+            Some(now - period)
+        }
+        None => None,
+    };
+    Ok(MessageSearchFilter { status, recipient_id: arg("destination"), namespace: arg("service"), topic: arg("event"), created_after })
+}
+
+/// Return the URL of the admin API: `--url` or the admin listener of the configuration
+fn admin_url(args: &ArgMatches, networking: &NetworkingConfiguration) -> Result<HttpUrl, String> {
+    let url = match (args.get_one::<String>("url"), &networking.admin) {
+        (Some(url), _) => url.clone(),
+        (None, Some(listener)) if listener.ip.is_unspecified() => format!("http://127.0.0.1:{}", listener.port),
+        (None, Some(listener)) => format!("http://{}", listener.socket_addr()),
+        (None, None) => return Err(String::from("--url is required when the admin API is not configured by net.admin.port")),
+    };
+    HttpUrl::parse(&url).map_err(|err| format!("--url {}: {}", url, err))
+}
+
+/// Read the messages of the admin API at the URL that match the filter, following the cursors of
+/// its pages until there are no more or `limit` messages were read
+pub fn search_messages(url: &HttpUrl, token: Option<&str>, filter: &MessageSearchFilter, limit: Option<usize>) -> Result<Vec<JsonValue>, String> {
+    let base = url.target.split('?').next().unwrap_or_default().trim_end_matches('/').to_string();
+    let (mut messages, mut cursor) = (Vec::new(), None);
+    loop {
+        let page_size = limit.map_or(SEARCH_PAGE_SIZE, |limit| (limit - messages.len()).clamp(1, SEARCH_PAGE_SIZE));
+        let path = filter.page_path(page_size, cursor.as_deref());
+        let page_url = url.join(&format!("{}{}", base, path)).map_err(|err| err.to_string())?;
+        let mut request = HttpRequest::new("GET", &page_url.target);
+        if let Some(token) = token {
+            request.headers.set("Authorization", &format!("Bearer {}", token));
+        }
+        let response = send_request(&page_url, request, REQUEST_TIMEOUT).map_err(|err| format!("GET /admin/messages failed: {}", err))?;
+        let body = JsonValue::parse_bytes(&response.body);
+        if !response.is_success() {
+            let error = body.ok().and_then(|body| body.get("error").and_then(JsonValue::as_str).map(str::to_string))
+                .unwrap_or_else(|| String::from_utf8_lossy(&response.body).into_owned());
+            return Err(format!("GET /admin/messages answered {}: {}", response.status, error));
+        }
+        let page = body.ok().and_then(|body| body.as_array().cloned()).ok_or("GET /admin/messages should answer a list")?;
+        messages.extend(page);
+        cursor = response.headers.get(NEXT_CURSOR_HEADER).map(str::to_string);
+        if cursor.is_none() || limit.is_some_and(|limit| messages.len() >= limit) {
+            return Ok(messages);
+        }
+    }
+}
+
+/// The columns of the table printed by `messages search`
+const TABLE_COLUMNS: [(&str, &str); 7] = [
+    ("ID", "id"),
+    ("STATUS", "status"),
+    ("DESTINATION", "recipientId"),
+    ("SERVICE", "serviceId"),
+    ("EVENT", "eventId"),
+    ("ATTEMPTS", "attempts"),
+    ("CREATED AT", "createdAt"),
+];
+
+/// Print the messages in the format
+pub fn format_messages(messages: &[JsonValue], output: SearchOutput) -> String {
+    match output {
+        SearchOutput::Json => JsonValue::Array(messages.to_vec()).to_string(),
+        SearchOutput::Ndjson => messages.iter().map(JsonValue::to_string).collect::<Vec<_>>().join("\n"),
+        SearchOutput::Table => {
+            let cell = |message: &JsonValue, field: &str| match message.get(field) {
+                Some(JsonValue::String(value)) => value.clone(),
+                None | Some(JsonValue::Null) => String::from("-"),
+                Some(value) => value.to_string(),
+            };
+            let rows: Vec<Vec<String>> = std::iter::once(TABLE_COLUMNS.iter().map(|(header, _)| header.to_string()).collect())
+                .chain(messages.iter().map(|message| TABLE_COLUMNS.iter().map(|(_, field)| cell(message, field)).collect()))
+                .collect();
+            let widths: Vec<usize> = (0..TABLE_COLUMNS.len()).map(|column| rows.iter().map(|row| row[column].chars().count()).max().unwrap_or(0)).collect();
+            rows.iter()
+                .map(|row| row.iter().zip(&widths).map(|(value, width)| format!("{:<width$}", value, width = width)).collect::<Vec<_>>().join("  ").trim_end().to_string())
+                .collect::<Vec<_>>()
+                .join("\n")
+        }
+    }
+}
+
+/// Run `messages search`, returning the messages to print. The admin API and its token default to
+/// the ones of the configuration
+pub fn run_messages_search(args: &ArgMatches, networking: &NetworkingConfiguration) -> Result<String, String> {
+    let output = args.get_one::<String>("output").unwrap();
+    let output = SearchOutput::from_name(output).ok_or_else(|| format!("--output {} should be table, json or ndjson", output))?;
+    let limit = match args.get_one::<String>("limit") {
+        Some(limit) => Some(limit.parse::<usize>().ok().filter(|limit| *limit >= 1).ok_or_else(|| format!("--limit {} should be a integer >= 1", limit))?),
+        None => None,
+    };
+    let filter = parse_filter(args, OffsetDateTime::now_utc())?;
+    let token = args.get_one::<String>("token").or(networking.admin_auth_token.as_ref());
+    let messages = search_messages(&admin_url(args, networking)?, token.map(String::as_str), &filter, limit)?;
+    Ok(format_messages(&messages, output))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::utils::time::parse_rfc3339;
+
+    use super::*;
+
+    #[test]
+    fn test_if_the_search_reads_the_filters_and_prints_the_messages() {
+        let now = parse_rfc3339("2024-05-30T12:00:00Z").unwrap();
+        let args = messages_command().get_matches_from(["messages", "search", "--status", "dead", "--destination", "api.foo.com", "--since", "2h"]);
+        let filter = parse_filter(args.subcommand_matches("search").unwrap(), now).unwrap();
+        assert_eq!(filter.page_path(1000, None), "/admin/messages?limit=1000&status=dead&recipientId=api.foo.com&createdAfter=2024-05-30T10%3A00%3A00.000Z");
+        assert!(MessageSearchFilter::default().page_path(5, Some("bS4x")).ends_with("?limit=5&cursor=bS4x"));
+        let invalid = |arg: &str, value: &str| parse_filter(messages_command().get_matches_from(["messages", "search", arg, value]).subcommand_matches("search").unwrap(), now).unwrap_err();
+        assert!(invalid("--status", "lost").contains("--status lost"));
+        assert!(invalid("--since", "yesterday").contains("--since yesterday"));
+
+        let messages = [
+            JsonValue::parse(r#"{"id": "m-1", "status": "dead", "recipientId": "api.foo.com", "serviceId": "billing", "eventId": "invoice.paid", "attempts": 12, "createdAt": "2024-05-30T10:30:00Z"}"#).unwrap(),
+            JsonValue::parse(r#"{"id": "m-20", "status": "dead", "recipientId": "api.foo.com", "serviceId": null, "eventId": "e", "attempts": 3, "createdAt": "2024-05-30T11:00:00Z"}"#).unwrap(),
+        ];
+        assert_eq!(format_messages(&messages, SearchOutput::Table), "\
+            ID    STATUS  DESTINATION  SERVICE  EVENT         ATTEMPTS  CREATED AT\n\
+            m-1   dead    api.foo.com  billing  invoice.paid  12        2024-05-30T10:30:00Z\n\
+            m-20  dead    api.foo.com  -        e             3         2024-05-30T11:00:00Z");
+        assert_eq!(format_messages(&messages, SearchOutput::Ndjson).lines().count(), 2);
+        assert!(format_messages(&[], SearchOutput::Json) == "[]");
+    }
+}
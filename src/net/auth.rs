@@ -0,0 +1,222 @@
+use std::sync::Arc;
+
+use thiserror::Error;
+
+use crate::{
+    ctx::config::NetworkingConfiguration,
+    utils::{base64, clock::Clock, json::JsonValue, sha256::hmac_sha256},
+};
+
+use super::{admin::constant_time_eq, http::HttpRequest};
+
+/// The header with the API key of a client, as an alternative to a `Bearer` authorization
+pub const API_KEY_HEADER: &str = "X-Api-Key";
+
+/// Who sent a client request, as told by the AuthProvider
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Identity {
+    pub subject: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Error)]
+pub enum AuthError {
+    #[error("the request has no credentials")]
+    MissingCredentials,
+    #[error("the credentials are not valid: {0}")]
+    InvalidCredentials(String),
+}
+
+/// Authenticate the requests of the client API. The built-in providers are selected by
+/// `net.client.auth.provider`, and embedders can give their own to the AnglerBuilder, like one that
+/// checks the tokens of their SSO
+pub trait AuthProvider: Send + Sync {
+    fn authenticate(&self, request: &HttpRequest) -> Result<Identity, AuthError>;
+
+    /// Return the `WWW-Authenticate` challenge of the requests that failed to authenticate
+    fn challenge(&self) -> String {
+        String::from("Bearer realm=\"Angler\"")
+    }
+}
+
+/// The built-in AuthProviders, set by `net.client.auth.provider=apiKeys|jwt`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AuthProviderKind {
+    ApiKeys,
+    Jwt,
+}
+
+impl AuthProviderKind {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            AuthProviderKind::ApiKeys => "apiKeys",
+            AuthProviderKind::Jwt => "jwt",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<AuthProviderKind> {
+        match name {
+            "apiKeys" => Some(AuthProviderKind::ApiKeys),
+            "jwt" => Some(AuthProviderKind::Jwt),
+            _ => None,
+        }
+    }
+}
+
+/// A `net.client.auth.provider` that misses the configuration it reads
+#[derive(Debug, Clone, PartialEq, Eq, Error)]
+#[error("{key} should be set when net.client.auth.provider is {}", provider.as_str())]
+pub struct MissingAuthConfiguration {
+    pub key: &'static str,
+    pub provider: AuthProviderKind,
+}
+
+/// Return the AuthProvider selected by `net.client.auth.provider`, None when the client API is not
+/// authenticated. It fails when the provider misses its keys or its secret
+pub fn auth_provider_from_configuration(networking: &NetworkingConfiguration, clock: Arc<dyn Clock>) -> Result<Option<Arc<dyn AuthProvider>>, MissingAuthConfiguration> {
+    let Some(provider) = networking.client_auth_provider else {
+        return Ok(None);
+    };
+    let missing = |key| MissingAuthConfiguration { key, provider };
+    Ok(Some(match provider {
+        AuthProviderKind::ApiKeys => {
+            let keys = networking.client_api_keys.clone().ok_or_else(|| missing("net.client.auth.apiKeys"))?;
+            Arc::new(ApiKeys::new(keys))
+        }
+        AuthProviderKind::Jwt => {
+            let secret = networking.client_jwt_secret.as_ref().ok_or_else(|| missing("net.client.auth.jwtSecret"))?;
+            Arc::new(JwtAuth::new(secret.as_bytes(), clock))
+        }
+    }))
+}
+
+/// Return the `Bearer` token of the request
+fn bearer_token(request: &HttpRequest) -> Option<&str> {
+    match request.headers.get("Authorization")?.split_once(' ') {
+        Some((scheme, token)) if scheme.eq_ignore_ascii_case("bearer") => Some(token.trim()),
+        _ => None,
+    }
+}
+
+/// Authenticate the clients by static API keys, sent as a `Bearer` token or in the API_KEY_HEADER
+pub struct ApiKeys {
+    /// The subject of each key
+    keys: Vec<(String, String)>,
+}
+
+impl ApiKeys {
+    /// Create the provider with the `(subject, key)` pairs
+    pub fn new(keys: Vec<(String, String)>) -> ApiKeys {
+        ApiKeys { keys }
+    }
+}
+
+impl AuthProvider for ApiKeys {
+    fn authenticate(&self, request: &HttpRequest) -> Result<Identity, AuthError> {
+        let sent = bearer_token(request).or_else(|| request.headers.get(API_KEY_HEADER).map(str::trim)).ok_or(AuthError::MissingCredentials)?;
+        // every key is compared, so the time taken does not tell which one almost matched
+        let matched = self.keys.iter().fold(None, |matched, (subject, key)| match constant_time_eq(key.as_bytes(), sent.as_bytes()) {
+            true => Some(subject),
+            false => matched,
+        });
+        matched.map(|subject| Identity { subject: subject.clone() }).ok_or_else(|| AuthError::InvalidCredentials(String::from("unknown API key")))
+    }
+}
+
+/// Authenticate the clients by JWTs signed with HS256 by the secret, like the ones of an SSO
+/// that shares it. The token must have a `sub` and not be expired by its `exp` or before its `nbf`
+pub struct JwtAuth {
+    secret: Vec<u8>,
+    clock: Arc<dyn Clock>,
+}
+
+impl JwtAuth {
+    pub fn new(secret: &[u8], clock: Arc<dyn Clock>) -> JwtAuth {
+        JwtAuth { secret: secret.to_vec(), clock }
+    }
+
+    fn verify(&self, token: &str) -> Result<Identity, String> {
+        let invalid = |reason: &str| reason.to_string();
+        let mut parts = token.split('.');
+        let (Some(header), Some(claims), Some(signature), None) = (parts.next(), parts.next(), parts.next(), parts.next()) else {
+            return Err(invalid("the token should have a header, claims and a signature"));
+        };
+        let decode = |part: &str| base64::decode_url(part).and_then(|json| JsonValue::parse_bytes(&json).ok());
+        let header = decode(header).ok_or_else(|| invalid("the header is not valid"))?;
+        if header.get("alg").and_then(JsonValue::as_str) != Some("HS256") {
+            return Err(invalid("only HS256 tokens are accepted"));
+        }
+        let expected = hmac_sha256(&self.secret, &token.as_bytes()[..token.len() - signature.len() - 1]);
+        let sent = base64::decode_url(signature).ok_or_else(|| invalid("the signature is not valid"))?;
+        if !constant_time_eq(&expected, &sent) {
+            return Err(invalid("the signature does not match"));
+        }
+
+        let claims = decode(claims).ok_or_else(|| invalid("the claims are not valid"))?;
+        let now = self.clock.now().unix_timestamp();
+        let time = |claim: &str| claims.get(claim).and_then(JsonValue::as_f64).map(|time| time as i64);
+        if time("exp").is_some_and(|exp| exp <= now) {
+            return Err(invalid("the token expired"));
+        }
+        if time("nbf").is_some_and(|nbf| nbf > now) {
+            return Err(invalid("the token is not valid yet"));
+        }
+        let subject = claims.get("sub").and_then(JsonValue::as_str).filter(|subject| !subject.is_empty()).ok_or_else(|| invalid("the token has no sub"))?;
+        Ok(Identity { subject: subject.to_string() })
+    }
+}
+
+impl AuthProvider for JwtAuth {
+    fn authenticate(&self, request: &HttpRequest) -> Result<Identity, AuthError> {
+        let token = bearer_token(request).ok_or(AuthError::MissingCredentials)?;
+        self.verify(token).map_err(AuthError::InvalidCredentials)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use time::OffsetDateTime;
+
+    use crate::utils::clock::VirtualClock;
+
+    use super::*;
+
+    fn request(header: (&str, &str)) -> HttpRequest {
+        let mut request = HttpRequest::new("GET", "/messages");
+        request.headers.set(header.0, header.1);
+        request
+    }
+
+    fn jwt(secret: &[u8], claims: &str) -> String {
+        let signed = format!("{}.{}", base64::encode_url(br#"{"alg":"HS256","typ":"JWT"}"#), base64::encode_url(claims.as_bytes()));
+        format!("{}.{}", signed, base64::encode_url(&hmac_sha256(secret, signed.as_bytes())))
+    }
+
+    #[test]
+    fn test_if_clients_are_authenticated_by_their_api_keys() {
+        let provider = ApiKeys::new(vec![(String::from("billing"), String::from("k-1")), (String::from("shop"), String::from("k-2"))]);
+        assert_eq!(provider.authenticate(&request(("Authorization", "Bearer k-2"))), Ok(Identity { subject: String::from("shop") }));
+        assert_eq!(provider.authenticate(&request((API_KEY_HEADER, "k-1"))).unwrap().subject, "billing");
+        assert!(matches!(provider.authenticate(&request((API_KEY_HEADER, "k-3"))), Err(AuthError::InvalidCredentials(_))));
+        assert_eq!(provider.authenticate(&HttpRequest::new("GET", "/messages")), Err(AuthError::MissingCredentials));
+    }
+
+    #[test]
+    fn test_if_clients_are_authenticated_by_signed_jwts() {
+        let now = OffsetDateTime::from_unix_timestamp(1_760_000_000).unwrap();
+        let provider = JwtAuth::new(b"sso-secret", Arc::new(VirtualClock::new(now)));
+        let bearer = |token: String| request(("Authorization", &format!("Bearer {}", token)));
+
+        let valid = jwt(b"sso-secret", r#"{"sub":"alice","exp":1760000060,"nbf":1759999990}"#);
+        assert_eq!(provider.authenticate(&bearer(valid)).unwrap().subject, "alice");
+        let reason = |token: String| match provider.authenticate(&bearer(token)) {
+            Err(AuthError::InvalidCredentials(reason)) => reason,
+            other => panic!("the token should be invalid, but was {:?}", other),
+        };
+        assert_eq!(reason(jwt(b"other-secret", r#"{"sub":"alice"}"#)), "the signature does not match");
+        assert_eq!(reason(jwt(b"sso-secret", r#"{"sub":"alice","exp":1759999999}"#)), "the token expired");
+        assert_eq!(reason(jwt(b"sso-secret", r#"{"sub":"alice","nbf":1760000100}"#)), "the token is not valid yet");
+        assert_eq!(reason(jwt(b"sso-secret", r#"{"exp":1760000060}"#)), "the token has no sub");
+        assert_eq!(reason(String::from("not-a-jwt")), "the token should have a header, claims and a signature");
+        assert_eq!(provider.authenticate(&request((API_KEY_HEADER, "k-1"))), Err(AuthError::MissingCredentials));
+    }
+}
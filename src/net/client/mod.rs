@@ -0,0 +1,3 @@
+pub mod report;
+pub mod restful;
+pub mod routing;
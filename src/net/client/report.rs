@@ -0,0 +1,204 @@
+use time::OffsetDateTime;
+
+use crate::{
+    db::{MessageQuery, MessageStore, StoreError},
+    msgproc::message::{AttemptOutcome, AttemptRecord, Message},
+    utils::{json::JsonValue, time::format_rfc3339},
+};
+
+/// The columns of a CSV delivery report, also used as the fields of the NDJSON lines
+const COLUMNS: [&str; 10] = ["finishedAt", "messageId", "recipientId", "serviceId", "eventId", "producerMessageId", "attempt", "outcome", "errorClass", "error"];
+
+/// How a delivery report is serialized
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ReportFormat {
+    /// RFC 4180 CSV with a header line
+    Csv,
+    /// One JSON object per line
+    Ndjson,
+}
+
+impl ReportFormat {
+    /// Return the format with the given name, as used in the `format` parameter
+    pub fn from_name(name: &str) -> Option<ReportFormat> {
+        match name {
+            "csv" => Some(ReportFormat::Csv),
+            "ndjson" => Some(ReportFormat::Ndjson),
+            _ => None,
+        }
+    }
+
+    pub fn content_type(&self) -> &'static str {
+        match self {
+            ReportFormat::Csv => "text/csv; charset=utf-8",
+            ReportFormat::Ndjson => "application/x-ndjson",
+        }
+    }
+
+    pub fn extension(&self) -> &'static str {
+        match self {
+            ReportFormat::Csv => "csv",
+            ReportFormat::Ndjson => "ndjson",
+        }
+    }
+}
+
+/// The attempts included in a delivery report
+#[derive(Debug, Clone, PartialEq)]
+pub struct DeliveryReportQuery {
+    /// Only include attempts finished at or after this time
+    pub from: OffsetDateTime,
+    /// Only include attempts finished before this time
+    pub to: OffsetDateTime,
+    pub recipient_id: Option<String>,
+    pub format: ReportFormat,
+}
+
+/// Build a report with every attempt finished in the query range, one line per attempt ordered by
+/// the time it finished. It is the proof that a message was (or could not be) delivered, so it
+/// includes the failed attempts and their errors
+pub fn delivery_report(store: &dyn MessageStore, query: &DeliveryReportQuery) -> Result<String, StoreError> {
+    // a message can only be attempted after it was created
+    let messages = store.find_messages(&MessageQuery {
+        recipient_id: query.recipient_id.clone(),
+        created_before: Some(query.to),
+        ..MessageQuery::default()
+    })?;
+
+    let mut rows = Vec::new();
+    for message in &messages {
+        if message.attempts == 0 {
+            continue;
+        }
+        for attempt in store.get_attempts(&message.id)? {
+            if attempt.finished_at >= query.from && attempt.finished_at < query.to {
+                rows.push((message, attempt));
+            }
+        }
+    }
+    rows.sort_by(|(a, a_attempt), (b, b_attempt)| {
+        a_attempt.finished_at.cmp(&b_attempt.finished_at)
+            .then_with(|| a.id.cmp(&b.id))
+            .then_with(|| a_attempt.attempt.cmp(&b_attempt.attempt))
+    });
+
+    let mut report = String::new();
+    if query.format == ReportFormat::Csv {
+        report.push_str(&COLUMNS.join(","));
+        report.push_str("\r\n");
+    }
+    for (message, attempt) in rows {
+        let values = row(message, &attempt);
+        match query.format {
+            ReportFormat::Csv => {
+                let fields: Vec<String> = values.iter().map(|value| csv_field(value.as_deref().unwrap_or_default())).collect();
+                report.push_str(&fields.join(","));
+                report.push_str("\r\n");
+            }
+            ReportFormat::Ndjson => {
+                let json = COLUMNS.iter().zip(values).fold(JsonValue::object(), |json, (column, value)| match (*column, value) {
+                    ("attempt", Some(value)) => json.with(column, value.parse::<u64>().unwrap_or_default()),
+                    (_, value) => json.with(column, value),
+                });
+                report.push_str(&json.to_string());
+                report.push('\n');
+            }
+        }
+    }
+    Ok(report)
+}
+
+/// Return the values of the COLUMNS for the attempt
+fn row(message: &Message, attempt: &AttemptRecord) -> [Option<String>; 10] {
+    let (outcome, error) = match &attempt.outcome {
+        AttemptOutcome::Delivered => ("delivered", None),
+        AttemptOutcome::Failed(error) => ("failed", Some(error)),
+        AttemptOutcome::Filtered => ("filtered", None),
+        AttemptOutcome::Accepted { .. } => ("accepted", None),
+    };
+    [
+        Some(format_rfc3339(attempt.finished_at)),
+        Some(message.id.clone()),
+        Some(message.recipient_id.clone()),
+        Some(message.service_id.clone()),
+        Some(message.event_id.clone()),
+        message.producer_message_id.clone(),
+        Some(attempt.attempt.to_string()),
+        Some(outcome.to_string()),
+        error.map(|error| error.class.as_str().to_string()),
+        error.map(|error| error.message.clone()),
+    ]
+}
+
+/// Quote the field when it has a comma, a quote or a line break
+fn csv_field(value: &str) -> String {
+    if value.contains([',', '"', '\r', '\n']) {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value.to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{db::{memory::MemoryStore, StoreWrite}, msgproc::message::{DeliveryError, DeliveryErrorClass}};
+
+    use super::*;
+
+    fn at(seconds: i64) -> OffsetDateTime {
+        OffsetDateTime::from_unix_timestamp(1_704_067_200 + seconds).unwrap()
+    }
+
+    fn attempted(store: &MemoryStore, id: &str, recipient_id: &str, attempts: &[(i64, AttemptOutcome)]) {
+        let message = Message::new_at(id.to_string(), recipient_id.to_string(), "shop".to_string(), "order.created".to_string(), vec![], at(0));
+        let mut writes = vec![StoreWrite::InsertMessage(Box::new(message))];
+        for (index, (finished_at, outcome)) in attempts.iter().enumerate() {
+            writes.push(StoreWrite::RecordAttempt(AttemptRecord {
+                message_id: id.to_string(),
+                attempt: index as u16 + 1,
+                finished_at: at(*finished_at),
+                outcome: outcome.clone(),
+            }));
+        }
+        store.write_batch(&writes).unwrap();
+    }
+
+    fn query(format: ReportFormat) -> DeliveryReportQuery {
+        DeliveryReportQuery { from: at(10), to: at(100), recipient_id: None, format }
+    }
+
+    #[test]
+    fn test_if_report_lists_the_attempts_finished_in_the_range() {
+        let store = MemoryStore::new();
+        attempted(&store, "a", "r1", &[(5, AttemptOutcome::Failed(DeliveryError::from_response(500, "HTTP 500, retrying"))), (50, AttemptOutcome::Delivered)]);
+        attempted(&store, "b", "r2", &[(20, AttemptOutcome::Failed(DeliveryError::new(DeliveryErrorClass::ResponseTimeout, "timeout"))), (100, AttemptOutcome::Delivered)]);
+
+        let report = delivery_report(&store, &query(ReportFormat::Csv)).unwrap();
+        let lines: Vec<&str> = report.lines().collect();
+        assert_eq!(lines, vec![
+            "finishedAt,messageId,recipientId,serviceId,eventId,producerMessageId,attempt,outcome,errorClass,error",
+            "2024-01-01T00:00:20.000Z,b,r2,shop,order.created,,1,failed,responseTimeout,timeout",
+            "2024-01-01T00:00:50.000Z,a,r1,shop,order.created,,2,delivered,,",
+        ]);
+
+        let only_r1 = DeliveryReportQuery { recipient_id: Some(String::from("r1")), ..query(ReportFormat::Csv) };
+        assert_eq!(delivery_report(&store, &only_r1).unwrap().lines().count(), 2);
+    }
+
+    #[test]
+    fn test_if_report_is_serialized_as_ndjson() {
+        let store = MemoryStore::new();
+        attempted(&store, "a", "r1", &[(10, AttemptOutcome::Failed(DeliveryError::from_response(500, "HTTP 500, \"oops\"")))]);
+
+        let report = delivery_report(&store, &query(ReportFormat::Ndjson)).unwrap();
+        let line = JsonValue::parse(report.trim_end()).unwrap();
+        assert_eq!(line.get("messageId").unwrap().as_str(), Some("a"));
+        assert_eq!(line.get("attempt").and_then(JsonValue::as_u64), Some(1));
+        assert_eq!(line.get("errorClass").unwrap().as_str(), Some("http5xx"));
+        assert_eq!(line.get("error").unwrap().as_str(), Some("HTTP 500, \"oops\""));
+        assert_eq!(line.get("producerMessageId"), Some(&JsonValue::Null));
+
+        let csv = delivery_report(&store, &query(ReportFormat::Csv)).unwrap();
+        assert!(csv.ends_with(",failed,http5xx,\"HTTP 500, \"\"oops\"\"\"\r\n"));
+    }
+}
@@ -0,0 +1,2170 @@
+use std::{borrow::Cow, collections::BTreeMap, io, net::ToSocketAddrs, sync::{Arc, Mutex}, time::Duration};
+
+use crate::{
+    cluster::readiness::ReadinessProbe,
+    ctx::{appenv::ApplicationRoles, config::RetryPolicyConfiguration},
+    db::{MessageQuery, MessageStore, StoreError},
+    log,
+    msgproc::{
+        ack::{AckError, AckToken, ACK_TOKEN_HEADER, ACK_URL_HEADER},
+        canary::{Branch, CanaryRoute},
+        concurrency::MAX_CONCURRENCY,
+        delivery::{delivery_request, ATTEMPT_HEADERS, ATTRIBUTE_HEADER_PREFIX, SEQUENCE_HEADER, SHADOW_HEADER},
+        destination::{
+            BodyCompression, ChangeOrigin, DeliveryMethod, DeliveryMode, Destination, DestinationChange, DestinationRegistry, ExpectedVersion, RedirectPolicy,
+            RollbackError, VersionConflict, DEFAULT_COMPRESSION_MIN_BYTES, DEFAULT_MAX_REDIRECTS, MAX_REDIRECTS_LIMIT,
+        },
+        message::{Annotation, AttemptOutcome, AttemptRecord, DeliveryErrorClass, Message, MessageStatus},
+        processor::{MessageProcessor, PublishOutcome},
+        pull::{PulledMessage, DEFAULT_VISIBILITY_TIMEOUT, MAX_PULL_MESSAGES, MAX_PULL_WAIT},
+        replay::{find_backfill_messages, find_dead_messages, start_replay, Backfill, ReplayFilter, DEFAULT_REPLAY_RATE},
+        retry::{RetryBudget, RetryOn, RetryPolicy, DEFAULT_RETRY_BUDGET_MIN_PER_MINUTE},
+        sse::{SseHub, SSE_KEEPALIVE_INTERVAL},
+        topic::{OrderingMode, RetryProfile, Topic, TopicError, TopicRegistry, TopicSettings},
+        window::{format_time_of_day, DeliveryWindow},
+    },
+    net::{
+        auth::AuthProvider,
+        client::report::{delivery_report, DeliveryReportQuery, ReportFormat},
+        http::{parse_multipart, ConnectionLimits, HttpHandler, HttpRequest, HttpResponse, HttpServer, HttpUrl, MAX_BODY_SIZE},
+        pool::MAX_WARM_CONNECTIONS,
+    },
+    syscom::jobs::{Job, JobRegistry},
+    utils::{
+        base64,
+        gzip::{self, GzipError},
+        json::JsonValue,
+        log::{self, Level},
+        random::uuid_v4,
+        time::{format_duration, format_rfc3339, parse_rfc3339, DurationDeserializer, DurationSequence, DurationSequenceDeserializer},
+    },
+};
+
+/// The default value of `net.client.restful.port`
+pub const DEFAULT_RESTFUL_PORT: u16 = 2460;
+
+/// Set to `true` on the responses of the status queries served by the read replica
+pub const REPLICA_HEADER: &str = "X-Angler-Replica";
+
+/// When the read replica was last repaired from the storage node, in RFC 3339
+pub const REPLICA_SYNCED_AT_HEADER: &str = "X-Angler-Replica-Synced-At";
+
+/// How many seconds ago the read replica was last repaired, or `unknown` when it never was. The
+/// replica may be missing the changes made since then
+pub const REPLICA_STALENESS_HEADER: &str = "X-Angler-Replica-Staleness";
+
+/// The header with the ID of a client API call, echoed in its response. It is created when the
+/// call does not have one
+pub const REQUEST_ID_HEADER: &str = "X-Request-ID";
+
+/// The header with who changes a destination, like the login of the operator, kept in the history
+/// of the destination
+pub const ACTOR_HEADER: &str = "X-Angler-Actor";
+
+/// The header with the cursor of the next page of a list, sent when there are more items after the
+/// page. The next page is read by repeating the request with `?cursor=<cursor>`
+pub const NEXT_CURSOR_HEADER: &str = "X-Angler-Next-Cursor";
+
+/// The most messages of a `application/x-ndjson` publish
+const MAX_BATCH_MESSAGES: usize = 1000;
+
+/// The longest request ID honored, others are replaced by a new one
+const MAX_REQUEST_ID_LENGTH: usize = 128;
+
+/// Return if the request ID sent by a client can be honored: visible ASCII without spaces, so it
+/// can be copied into logs and headers as it is
+fn is_valid_request_id(request_id: &str) -> bool {
+    !request_id.is_empty() && request_id.len() <= MAX_REQUEST_ID_LENGTH && request_id.bytes().all(|byte| byte.is_ascii_graphic())
+}
+
+/// Return a JSON response with the given status
+pub fn json_response(status: u16, body: &JsonValue) -> HttpResponse {
+    HttpResponse::with_body(status, "application/json", body.to_string())
+}
+
+/// Return a JSON response with the given status and a `{"error": message}` body
+pub fn error_response(status: u16, message: &str) -> HttpResponse {
+    json_response(status, &JsonValue::object().with("error", message))
+}
+
+/// Serialize a retry policy into the JSON representation used by the client API
+pub fn retry_policy_to_json(policy: &RetryPolicy) -> JsonValue {
+    JsonValue::object()
+        .with("interval", policy.interval.as_ref().map(ToString::to_string))
+        .with("maxAttempts", policy.max_attempts)
+}
+
+fn string_map_to_json(map: &BTreeMap<String, String>) -> JsonValue {
+    JsonValue::Object(map.iter().map(|(key, value)| (key.clone(), JsonValue::from(value.as_str()))).collect())
+}
+
+/// Return if the key of a attribute only has letters, digits, `-`, `_` and `.`
+fn is_attribute_key(key: &str) -> bool {
+    !key.is_empty() && key.chars().all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.'))
+}
+
+/// Read an object of attributes, like `{"region": "eu"}`. Keys are sent as header names, so they
+/// only accept letters, digits, `-`, `_` and `.`, and values can not have control characters
+fn parse_attributes(value: &JsonValue, field: &str) -> Result<BTreeMap<String, String>, String> {
+    let object = value.as_object().ok_or_else(|| format!("{} should be an object", field))?;
+    object.iter()
+        .map(|(key, value)| {
+            if !is_attribute_key(key) {
+                return Err(format!("{}.{} should only have letters, digits, '-', '_' and '.'", field, key));
+            }
+            match value.as_str() {
+                Some(value) if !value.chars().any(char::is_control) => Ok((key.clone(), value.to_string())),
+                _ => Err(format!("{}.{} should be a string without control characters", field, key)),
+            }
+        })
+        .collect()
+}
+
+/// The headers that can not be set by `headers` as they are managed by the deliverer
+const RESERVED_HEADERS: [&str; 5] = ["Host", "Content-Length", "Content-Type", "Connection", "Transfer-Encoding"];
+
+/// Read the static headers of a destination, like `{"Authorization": "Basic ..."}`
+fn parse_destination_headers(value: &JsonValue) -> Result<BTreeMap<String, String>, String> {
+    let object = value.as_object().ok_or("headers should be an object")?;
+    object.iter()
+        .map(|(name, value)| {
+            let is_token = !name.is_empty() && name.chars().all(|c| c.is_ascii_alphanumeric() || "!#$%&'*+-.^_`|~".contains(c));
+            if !is_token {
+                return Err(format!("headers.{} is not a valid header name", name));
+            }
+            let is_managed = RESERVED_HEADERS.iter().any(|reserved| reserved.eq_ignore_ascii_case(name))
+                || [SEQUENCE_HEADER, SHADOW_HEADER].iter().any(|header| header.eq_ignore_ascii_case(name))
+                || ATTEMPT_HEADERS.iter().any(|header| header.eq_ignore_ascii_case(name))
+                || [ACK_TOKEN_HEADER, ACK_URL_HEADER].iter().any(|header| header.eq_ignore_ascii_case(name))
+                || name.to_ascii_lowercase().starts_with(&ATTRIBUTE_HEADER_PREFIX.to_ascii_lowercase());
+            if is_managed {
+                return Err(format!("headers.{} is set by Angler and can not be changed", name));
+            }
+            match value.as_str() {
+                Some(value) if !value.chars().any(char::is_control) => Ok((name.clone(), value.to_string())),
+                _ => Err(format!("headers.{} should be a string without control characters", name)),
+            }
+        })
+        .collect()
+}
+
+/// Read the `redirectPolicy` object of a destination, like `{"mode": "sameHost", "maxRedirects": 3}`
+fn parse_redirect_policy(value: &JsonValue) -> Result<RedirectPolicy, String> {
+    let mode = value.get("mode").and_then(JsonValue::as_str).ok_or("redirectPolicy.mode should be a string")?;
+    let max_redirects = match value.get("maxRedirects") {
+        None | Some(JsonValue::Null) => DEFAULT_MAX_REDIRECTS,
+        Some(max) => max.as_u64()
+            .filter(|max| (1..=u64::from(MAX_REDIRECTS_LIMIT)).contains(max))
+            .ok_or_else(|| format!("redirectPolicy.maxRedirects should be a integer between 1 and {}", MAX_REDIRECTS_LIMIT))? as u8,
+    };
+    RedirectPolicy::from_name(mode, max_redirects).ok_or_else(|| String::from("redirectPolicy.mode should be none, sameHost or limited"))
+}
+
+fn redirect_policy_to_json(policy: &RedirectPolicy) -> JsonValue {
+    let json = JsonValue::object().with("mode", policy.as_str());
+    match policy {
+        RedirectPolicy::None => json,
+        _ => json.with("maxRedirects", policy.max_redirects()),
+    }
+}
+
+/// Read the body of `PUT /destinations/{recipientId}`. The url is optional for pull and sse destinations
+pub(crate) fn parse_destination(id: &str, body: &JsonValue) -> Result<Destination, String> {
+    let mode = match body.get("mode").filter(|mode| !mode.is_null()) {
+        Some(mode) => mode.as_str().and_then(DeliveryMode::from_name).ok_or("mode should be push, pull or sse")?,
+        None => DeliveryMode::Push,
+    };
+    let url = match body.get("url").filter(|url| !url.is_null()) {
+        Some(url) => {
+            let url = url.as_str().ok_or("url should be a string")?;
+            HttpUrl::parse(url).map_err(|err| err.to_string())?;
+            url
+        }
+        None if mode != DeliveryMode::Push => "",
+        None => return Err(String::from("url should be a string")),
+    };
+    let mut destination = Destination::new(id, url).with_mode(mode);
+
+    if let Some(filter) = body.get("attributeFilter").filter(|filter| !filter.is_null()) {
+        destination = destination.with_attribute_filter(parse_attributes(filter, "attributeFilter")?);
+    }
+    if let Some(method) = body.get("method").filter(|method| !method.is_null()) {
+        let method = method.as_str().and_then(DeliveryMethod::from_name).ok_or("method should be POST, PUT or PATCH")?;
+        destination = destination.with_method(method);
+    }
+    if let Some(content_type) = body.get("contentType").filter(|content_type| !content_type.is_null()) {
+        let content_type = content_type.as_str()
+            .filter(|content_type| !content_type.is_empty() && !content_type.chars().any(char::is_control))
+            .ok_or("contentType should be a non empty string without control characters")?;
+        destination = destination.with_content_type(content_type);
+    }
+    if let Some(headers) = body.get("headers").filter(|headers| !headers.is_null()) {
+        destination = destination.with_headers(parse_destination_headers(headers)?);
+    }
+    if let Some(redirect_policy) = body.get("redirectPolicy").filter(|redirect_policy| !redirect_policy.is_null()) {
+        destination = destination.with_redirect_policy(parse_redirect_policy(redirect_policy)?);
+    }
+    if let Some(hedge_after) = body.get("hedgeAfterMs").filter(|hedge_after| !hedge_after.is_null()) {
+        let hedge_after = hedge_after.as_u64().filter(|ms| *ms > 0).ok_or("hedgeAfterMs should be a integer > 0")?;
+        destination = destination.with_hedging(Duration::from_millis(hedge_after));
+    }
+    if let Some(address) = body.get("pinnedAddress").filter(|address| !address.is_null()) {
+        let address = address.as_str().and_then(|address| address.parse().ok()).ok_or("pinnedAddress should be a IPv4 or IPv6 address")?;
+        destination = destination.with_pinned_address(address);
+    }
+    if let Some(retry_on) = body.get("retryOn").filter(|retry_on| !retry_on.is_null()) {
+        let retry_on = retry_on.as_str().ok_or("retryOn should be a list of error classes and HTTP statuses. Example: 5xx,timeout,404")?;
+        destination = destination.with_retry_on(RetryOn::parse(retry_on).map_err(|err| format!("retryOn is invalid: {}", err))?);
+    }
+    if let Some(budget) = body.get("retryBudget").filter(|budget| !budget.is_null()) {
+        destination = destination.with_retry_budget(parse_retry_budget(budget)?);
+    }
+    if let Some(window) = body.get("deliveryWindow").filter(|window| !window.is_null()) {
+        destination = destination.with_delivery_window(parse_delivery_window(window)?);
+    }
+    if let Some(timeout) = body.get("asyncAckTimeout").filter(|timeout| !timeout.is_null()) {
+        let timeout = timeout.as_str()
+            .and_then(|timeout| timeout.to_duration().ok())
+            .filter(|timeout| timeout.is_positive())
+            .and_then(|timeout| Duration::try_from(timeout).ok())
+            .ok_or("asyncAckTimeout should be a duration > 0. Example: 5m")?;
+        destination = destination.with_async_ack(timeout);
+    }
+    if let Some(count) = body.get("warmConnections").filter(|count| !count.is_null()) {
+        let count = count.as_u64().filter(|count| (1..=u64::from(MAX_WARM_CONNECTIONS)).contains(count))
+            .ok_or_else(|| format!("warmConnections should be a integer between 1 and {}", MAX_WARM_CONNECTIONS))?;
+        destination = destination.with_warm_connections(count as u16);
+    }
+    if let Some(max) = body.get("maxConcurrency").filter(|max| !max.is_null()) {
+        let max = max.as_u64().filter(|max| (1..=u64::from(MAX_CONCURRENCY)).contains(max))
+            .ok_or_else(|| format!("maxConcurrency should be a integer between 1 and {}", MAX_CONCURRENCY))?;
+        destination = destination.with_max_concurrency(max as u16);
+    }
+    if let Some(compression) = body.get("compression").filter(|compression| !compression.is_null()) {
+        if mode != DeliveryMode::Push {
+            return Err(String::from("compression is only used by push destinations"));
+        }
+        destination = destination.with_compression(parse_compression(compression)?);
+    }
+    if let Some(canary) = body.get("canary").filter(|canary| !canary.is_null()) {
+        if mode != DeliveryMode::Push {
+            return Err(String::from("canary is only used by push destinations"));
+        }
+        destination = destination.with_canary(parse_canary(canary)?);
+    }
+    if let Some(shadow_url) = body.get("shadowUrl").filter(|shadow_url| !shadow_url.is_null()) {
+        let shadow_url = shadow_url.as_str().ok_or("shadowUrl should be a string")?;
+        HttpUrl::parse(shadow_url).map_err(|err| format!("shadowUrl is invalid: {}", err))?;
+        if mode != DeliveryMode::Push {
+            return Err(String::from("shadowUrl is only used by push destinations"));
+        }
+        destination = destination.with_shadow(shadow_url);
+    }
+    Ok(destination)
+}
+
+/// Read the `canary` object of a destination, like `{"url": "http://orders-v2.local/", "percent": 5}`
+fn parse_canary(value: &JsonValue) -> Result<CanaryRoute, String> {
+    let url = value.get("url").and_then(JsonValue::as_str).ok_or("canary.url should be a string")?;
+    HttpUrl::parse(url).map_err(|err| format!("canary.url is invalid: {}", err))?;
+    let percent = value.get("percent").and_then(JsonValue::as_u64).filter(|percent| (1..=99).contains(percent))
+        .ok_or("canary.percent should be a integer between 1 and 99, the percentage of the messages sent to canary.url")?;
+    let canary = CanaryRoute::new(url, percent as u8);
+    match value.get("keyAttribute") {
+        None | Some(JsonValue::Null) => Ok(canary),
+        Some(attribute) => {
+            let attribute = attribute.as_str().filter(|attribute| is_attribute_key(attribute))
+                .ok_or("canary.keyAttribute should be the key of a attribute, with letters, digits, -, _ and .")?;
+            Ok(canary.with_key_attribute(attribute))
+        }
+    }
+}
+
+/// Read the `compression` object of a destination, like `{"encoding": "gzip", "minBytes": 1024}`
+fn parse_compression(value: &JsonValue) -> Result<BodyCompression, String> {
+    value.get("encoding").and_then(JsonValue::as_str).filter(|encoding| *encoding == "gzip")
+        .ok_or("compression.encoding should be gzip")?;
+    match value.get("minBytes") {
+        None | Some(JsonValue::Null) => Ok(BodyCompression::new(DEFAULT_COMPRESSION_MIN_BYTES)),
+        Some(min_bytes) => {
+            let min_bytes = min_bytes.as_u64().and_then(|min_bytes| usize::try_from(min_bytes).ok())
+                .ok_or("compression.minBytes should be a integer >= 0, the smallest payload compressed")?;
+            Ok(BodyCompression::new(min_bytes))
+        }
+    }
+}
+
+/// Read the `retryBudget` object of a destination, like `{"ratio": 0.2, "minPerMinute": 10}`
+fn parse_retry_budget(value: &JsonValue) -> Result<RetryBudget, String> {
+    let ratio = value.get("ratio").and_then(JsonValue::as_f64).filter(|ratio| ratio.is_finite() && *ratio >= 0.0)
+        .ok_or("retryBudget.ratio should be a number >= 0, the retries allowed per first attempt. Example: 0.2")?;
+    let min_per_minute = match value.get("minPerMinute") {
+        None | Some(JsonValue::Null) => DEFAULT_RETRY_BUDGET_MIN_PER_MINUTE,
+        Some(min) => min.as_u64().and_then(|min| u32::try_from(min).ok()).ok_or("retryBudget.minPerMinute should be a integer >= 0")?,
+    };
+    Ok(RetryBudget::new(ratio).with_min_per_minute(min_per_minute))
+}
+
+/// Read the `deliveryWindow` object of a destination, like
+/// `{"days": ["mon-fri"], "start": "08:00", "end": "20:00", "timezone": "America/Sao_Paulo"}`
+fn parse_delivery_window(value: &JsonValue) -> Result<DeliveryWindow, String> {
+    let days = value.get("days").and_then(JsonValue::as_array)
+        .and_then(|days| days.iter().map(JsonValue::as_str).collect::<Option<Vec<_>>>())
+        .ok_or("deliveryWindow.days should be a list of weekdays. Example: [\"mon-fri\", \"sat\"]")?;
+    let time = |field: &str| value.get(field).and_then(JsonValue::as_str).ok_or_else(|| format!("deliveryWindow.{} should be a time like 08:00", field));
+    let timezone = match value.get("timezone") {
+        None | Some(JsonValue::Null) => "UTC",
+        Some(timezone) => timezone.as_str().ok_or("deliveryWindow.timezone should be a string")?,
+    };
+    DeliveryWindow::parse(days, time("start")?, time("end")?, timezone).map_err(|err| format!("deliveryWindow is invalid: {}", err))
+}
+
+fn delivery_window_to_json(window: &DeliveryWindow) -> JsonValue {
+    JsonValue::object()
+        .with("days", window.days())
+        .with("start", format_time_of_day(window.start()))
+        .with("end", format_time_of_day(window.end()))
+        .with("timezone", window.timezone().name())
+}
+
+/// Serialize a destination into the JSON representation used by the client API
+pub(crate) fn destination_to_json(destination: &Destination) -> JsonValue {
+    JsonValue::object()
+        .with("id", destination.id.as_str())
+        .with("url", Some(destination.url.as_str()).filter(|url| !url.is_empty()))
+        .with("mode", destination.mode.as_str())
+        .with("attributeFilter", string_map_to_json(&destination.attribute_filter))
+        .with("method", destination.method.as_str())
+        .with("contentType", destination.content_type.as_str())
+        .with("headers", string_map_to_json(&destination.headers))
+        .with("redirectPolicy", redirect_policy_to_json(&destination.redirect_policy))
+        .with("hedgeAfterMs", destination.hedge_after.map(|hedge_after| hedge_after.as_millis() as u64))
+        .with("pinnedAddress", destination.pinned_address.map(|address| address.to_string()))
+        .with("retryOn", destination.retry_on.as_ref().map(RetryOn::to_string))
+        .with("retryBudget", destination.retry_budget.map(|budget| JsonValue::object().with("ratio", budget.ratio).with("minPerMinute", budget.min_per_minute)))
+        .with("deliveryWindow", destination.delivery_window.as_ref().map(delivery_window_to_json))
+        .with("warmConnections", destination.warm_connections)
+        .with("maxConcurrency", destination.max_concurrency)
+        .with("compression", destination.compression.map(|compression| JsonValue::object()
+            .with("encoding", compression.encoding())
+            .with("minBytes", compression.min_bytes)))
+        .with("shadowUrl", destination.shadow_url.as_deref())
+        .with("canary", destination.canary.as_ref().map(|canary| JsonValue::object()
+            .with("url", canary.url.as_str())
+            .with("percent", canary.percent)
+            .with("keyAttribute", canary.key_attribute.as_deref())))
+        .with("asyncAckTimeout", destination.async_ack_timeout.and_then(|timeout| time::Duration::try_from(timeout).ok()).map(format_duration))
+        .with("version", destination.version)
+}
+
+/// Return the ETag of a destination version
+fn destination_etag(version: u64) -> String {
+    format!("\"{}\"", version)
+}
+
+/// Read the version a change expects the destination to have from the `If-Match` and
+/// `If-None-Match` headers. Changes without them are applied whatever the version is
+fn parse_expected_version(request: &HttpRequest) -> Result<ExpectedVersion, String> {
+    match (request.headers.get("If-Match").map(str::trim), request.headers.get("If-None-Match").map(str::trim)) {
+        (Some(_), Some(_)) => Err(String::from("If-Match and If-None-Match can not be used together")),
+        (Some("*"), None) => Ok(ExpectedVersion::Present),
+        (Some(etag), None) => etag.trim_start_matches("W/").trim_matches('"').parse().map(ExpectedVersion::Exactly)
+            .map_err(|_| String::from("If-Match should be * or the ETag of the destination")),
+        (None, Some("*")) => Ok(ExpectedVersion::Absent),
+        (None, Some(_)) => Err(String::from("If-None-Match should be *")),
+        (None, None) => Ok(ExpectedVersion::Any),
+    }
+}
+
+/// Return who sent the request, from its `X-Angler-Actor`
+pub fn actor(request: &HttpRequest) -> Option<String> {
+    request.headers.get(ACTOR_HEADER).map(str::trim).filter(|actor| !actor.is_empty()).map(str::to_string)
+}
+
+/// Return who and when a destination is changed by the request
+fn change_origin(request: &HttpRequest, now: time::OffsetDateTime) -> ChangeOrigin {
+    ChangeOrigin::at(now).with_author(actor(request))
+}
+
+/// Answer the job queries of `GET /jobs` and their cancellation, shared with the admin API
+pub fn job_response(jobs: &JobRegistry, method: &str, id: Option<&str>) -> HttpResponse {
+    let found = |job: Option<Job>| match job {
+        Some(job) => json_response(200, &job.to_json()),
+        None => error_response(404, "job not found"),
+    };
+    match (method, id) {
+        ("GET", None) => json_response(200, &JsonValue::Array(jobs.list().iter().map(Job::to_json).collect())),
+        ("GET", Some(id)) => found(jobs.get(id)),
+        ("POST", Some(id)) => match id.strip_suffix(":cancel") {
+            Some(id) => found(jobs.cancel(id)),
+            None => error_response(405, "method not allowed"),
+        },
+        _ => error_response(405, "method not allowed"),
+    }
+}
+
+/// Serialize the changes of a destination, each with the fields that changed from the previous one
+fn history_to_json(history: &[DestinationChange]) -> JsonValue {
+    let mut previous = JsonValue::Null;
+    let changes = history.iter()
+        .map(|change| {
+            let current = change.destination.as_ref().map(destination_to_json).unwrap_or(JsonValue::Null);
+            let diff = destination_diff(&previous, &current);
+            let json = JsonValue::object()
+                .with("kind", change.kind.as_str())
+                .with("changedAt", format_rfc3339(change.origin.at))
+                .with("changedBy", change.origin.by.clone())
+                .with("version", change.destination.as_ref().map(|destination| destination.version))
+                .with("destination", current.clone())
+                .with("diff", diff);
+            previous = current;
+            json
+        })
+        .collect::<Vec<_>>();
+    JsonValue::object().with("changes", changes)
+}
+
+/// Return the fields of the destination that differ between two versions, with their values
+/// `from` and `to`. A removed destination has no fields. The version is not compared
+pub(crate) fn destination_diff(from: &JsonValue, to: &JsonValue) -> JsonValue {
+    let empty = BTreeMap::new();
+    let (from, to) = (from.as_object().unwrap_or(&empty), to.as_object().unwrap_or(&empty));
+    let mut fields: Vec<&String> = from.keys().chain(to.keys()).filter(|field| field.as_str() != "version").collect();
+    fields.sort();
+    fields.dedup();
+    fields.into_iter()
+        .filter_map(|field| {
+            let (before, after) = (from.get(field).unwrap_or(&JsonValue::Null), to.get(field).unwrap_or(&JsonValue::Null));
+            (before != after).then(|| (field, JsonValue::object().with("from", before.clone()).with("to", after.clone())))
+        })
+        .fold(JsonValue::object(), |diff, (field, change)| diff.with(field, change))
+}
+
+/// Return the response of a destination with its ETag
+fn destination_response(status: u16, json: &JsonValue, version: u64) -> HttpResponse {
+    let mut response = json_response(status, json);
+    response.headers.set("ETag", &destination_etag(version));
+    response
+}
+
+/// Return the 412 of a change refused because the destination did not have the expected version
+fn version_conflict_response(conflict: &VersionConflict) -> HttpResponse {
+    let json = JsonValue::object().with("error", conflict.to_string()).with("version", conflict.current);
+    match conflict.current {
+        Some(current) => destination_response(412, &json, current),
+        None => json_response(412, &json),
+    }
+}
+
+/// Serialize a leased message with the receipt used to ack or nack it
+fn pulled_message_to_json(pulled: &PulledMessage) -> JsonValue {
+    JsonValue::object()
+        .with("receipt", pulled.receipt.as_str())
+        .with("message", message_to_json(&pulled.message))
+}
+
+/// Serialize a message into the JSON representation used by the client API
+pub fn message_to_json(message: &Message) -> JsonValue {
+    JsonValue::object()
+        .with("id", message.id.as_str())
+        .with("recipientId", message.recipient_id.as_str())
+        .with("serviceId", message.service_id.as_str())
+        .with("eventId", message.event_id.as_str())
+        .with("producerMessageId", message.producer_message_id.as_deref())
+        .with("replayedFrom", message.replayed_from.as_deref())
+        .with("requestId", message.request_id.as_deref())
+        .with("sequence", message.sequence)
+        .with("indexedFields", string_map_to_json(&message.indexed_fields))
+        .with("attributes", string_map_to_json(&message.attributes))
+        .with("retryPolicy", retry_policy_to_json(&message.retry_policy))
+        .with("requestedRetryPolicy", message.requested_retry_policy.as_ref().map(retry_policy_to_json))
+        .with("status", message.status.as_str())
+        .with("attempts", message.attempts)
+        .with("createdAt", format_rfc3339(message.created_at))
+        .with("nextAttemptAt", message.next_attempt_at.map(format_rfc3339))
+        .with("annotations", message.annotations.iter().map(annotation_to_json).collect::<Vec<_>>())
+}
+
+/// Serialize an annotation of a message into the JSON representation used by the APIs
+pub fn annotation_to_json(annotation: &Annotation) -> JsonValue {
+    JsonValue::object()
+        .with("note", annotation.note.as_str())
+        .with("author", annotation.author.as_deref())
+        .with("createdAt", format_rfc3339(annotation.created_at))
+}
+
+/// Serialize an attempt record into the JSON representation used by the client API
+pub fn attempt_to_json(attempt: &AttemptRecord) -> JsonValue {
+    let json = JsonValue::object()
+        .with("attempt", attempt.attempt)
+        .with("finishedAt", format_rfc3339(attempt.finished_at));
+    match &attempt.outcome {
+        AttemptOutcome::Delivered => json.with("outcome", "delivered"),
+        AttemptOutcome::Failed(error) => json.with("outcome", "failed").with("errorClass", error.class.as_str()).with("error", error.message.as_str()),
+        AttemptOutcome::Filtered => json.with("outcome", "filtered"),
+        AttemptOutcome::Accepted { confirm_before } => json.with("outcome", "accepted").with("confirmBefore", format_rfc3339(*confirm_before)),
+    }
+}
+
+/// The message sent by a client in the `sendMessage` object
+#[derive(Debug)]
+struct SendMessageRequest {
+    recipient_id: String,
+    service_id: String,
+    event_id: String,
+    producer_message_id: Option<String>,
+    retry_policy: Option<RetryPolicyRequest>,
+    attributes: BTreeMap<String, String>,
+    payload: Vec<u8>,
+}
+
+/// The `sendMessage.retryPolicy` object. Fields that are not sent use the `retryPolicy.defaults.`
+#[derive(Debug, Default, PartialEq)]
+struct RetryPolicyRequest {
+    interval: Option<DurationSequence>,
+    max_attempts: Option<u16>,
+}
+
+impl RetryPolicyRequest {
+    /// Return the requested policy, filling the fields that were not sent with the default policy
+    fn with_defaults(self, defaults: &RetryPolicy) -> RetryPolicy {
+        RetryPolicy {
+            interval: self.interval.or_else(|| defaults.interval.clone()),
+            max_attempts: self.max_attempts.unwrap_or(defaults.max_attempts),
+        }
+    }
+}
+
+/// Read a retry policy object, like `sendMessage.retryPolicy`, named `field` in the errors
+fn parse_retry_policy(retry_policy: &JsonValue, field: &str) -> Result<RetryPolicyRequest, String> {
+    if retry_policy.as_object().is_none() {
+        return Err(format!("{} should be an object", field));
+    }
+    let interval = match retry_policy.get("interval") {
+        None | Some(JsonValue::Null) => None,
+        Some(interval) => Some(
+            interval.as_str()
+                .and_then(|interval| interval.to_duration_sequence().ok())
+                .ok_or_else(|| format!("{}.interval should be a duration sequence. Example: [1m, 5m, 1h]", field))?
+        ),
+    };
+    let max_attempts = match retry_policy.get("maxAttempts") {
+        None | Some(JsonValue::Null) => None,
+        Some(max_attempts) => Some(
+            max_attempts.as_u64()
+                .and_then(|max_attempts| u16::try_from(max_attempts).ok())
+                .ok_or_else(|| format!("{}.maxAttempts should be a integer between 0 and 65535", field))?
+        ),
+    };
+    Ok(RetryPolicyRequest { interval, max_attempts })
+}
+
+/// Read the `sendMessage` object of the publish metadata
+fn parse_send_message(metadata: &JsonValue, payload: Vec<u8>) -> Result<SendMessageRequest, String> {
+    let send_message = metadata.get("sendMessage").ok_or("sendMessage is required")?;
+    let required_string = |field: &str| -> Result<String, String> {
+        send_message.get(field)
+            .and_then(JsonValue::as_str)
+            .filter(|value| !value.is_empty())
+            .map(String::from)
+            .ok_or_else(|| format!("sendMessage.{} should be a non empty string", field))
+    };
+
+    if let Some(message_type) = send_message.get("type") {
+        if message_type.as_str() != Some("http") {
+            return Err(String::from("sendMessage.type only supports the value 'http'"));
+        }
+    }
+
+    let producer_message_id = match send_message.get("producerMessageId") {
+        None | Some(JsonValue::Null) => None,
+        Some(_) => Some(required_string("producerMessageId")?),
+    };
+
+    let retry_policy = match send_message.get("retryPolicy") {
+        None | Some(JsonValue::Null) => None,
+        Some(retry_policy) => Some(parse_retry_policy(retry_policy, "sendMessage.retryPolicy")?),
+    };
+
+    let attributes = match send_message.get("attributes") {
+        None | Some(JsonValue::Null) => BTreeMap::new(),
+        Some(attributes) => parse_attributes(attributes, "sendMessage.attributes")?,
+    };
+
+    Ok(SendMessageRequest {
+        recipient_id: required_string("recipientId")?,
+        service_id: required_string("serviceId")?,
+        event_id: required_string("eventId")?,
+        producer_message_id,
+        retry_policy,
+        attributes,
+        payload,
+    })
+}
+
+/// Read the publish request body. It supports both the `multipart/form-data` format, with a JSON
+/// `metadata` part and a raw `data` part, and an `application/json` body with the `sendMessage`
+/// object plus the payload as JSON in the `data` field
+fn parse_publish_body(request: &HttpRequest) -> Result<SendMessageRequest, String> {
+    let content_type = request.headers.get("Content-Type").unwrap_or("application/json");
+
+    if content_type.to_ascii_lowercase().starts_with("multipart/form-data") {
+        let parts = parse_multipart(content_type, &request.body).map_err(|err| err.to_string())?;
+        let metadata = parts.iter().find(|part| part.name.as_deref() == Some("metadata")).ok_or("the metadata part is required")?;
+        let metadata = JsonValue::parse_bytes(&metadata.body).map_err(|err| format!("metadata is not valid JSON: {}", err))?;
+        let payload = parts.into_iter().find(|part| part.name.as_deref() == Some("data")).map(|part| part.body).unwrap_or_default();
+        return parse_send_message(&metadata, payload);
+    }
+
+    parse_json_publish(&request.body)
+}
+
+/// Read a `application/json` publish, or a line of a `application/x-ndjson` one
+fn parse_json_publish(body: &[u8]) -> Result<SendMessageRequest, String> {
+    let body = JsonValue::parse_bytes(body).map_err(|err| format!("body is not valid JSON: {}", err))?;
+    let payload = body.get("data").map(|data| data.to_string().into_bytes()).unwrap_or_default();
+    parse_send_message(&body, payload)
+}
+
+/// Return if the request is a batch publish, a `application/x-ndjson` body with a message by line
+fn is_batch_publish(request: &HttpRequest) -> bool {
+    request.headers.get("Content-Type").is_some_and(|content_type| content_type.to_ascii_lowercase().starts_with("application/x-ndjson"))
+}
+
+/// Decompress the body of the request by its `Content-Encoding`, `gzip` or `deflate`, up to the
+/// size of the bodies that are not compressed. Return None when the body is not compressed, and
+/// the error response when the encoding is not supported or the body is not valid
+fn decode_body(request: &HttpRequest) -> Result<Option<Vec<u8>>, HttpResponse> {
+    let encoding = match request.headers.get("Content-Encoding").map(str::trim) {
+        None | Some("") => return Ok(None),
+        Some(encoding) if encoding.eq_ignore_ascii_case("identity") => return Ok(None),
+        Some(encoding) => encoding.to_ascii_lowercase(),
+    };
+    let decoded = match encoding.as_str() {
+        "gzip" | "x-gzip" => gzip::decompress(&request.body, MAX_BODY_SIZE),
+        "deflate" => gzip::decompress_deflate(&request.body, MAX_BODY_SIZE),
+        _ => {
+            let mut response = error_response(415, &format!("Content-Encoding {} is not supported, the bodies should be gzip or deflate", encoding));
+            response.headers.set("Accept-Encoding", "gzip, deflate");
+            return Err(response);
+        }
+    };
+    match decoded {
+        Ok(body) => Ok(Some(body)),
+        Err(GzipError::TooLarge(max_len)) => Err(error_response(413, &format!("the decompressed body has more than {} bytes", max_len))),
+        Err(err) => Err(error_response(400, &format!("the {} body is invalid: {}", encoding, err))),
+    }
+}
+
+/// Read the query parameters of `GET /retry-policies/preview`, the same fields of `sendMessage.retryPolicy`,
+/// the `serviceId` whose defaults and limits are used and the `eventId` whose retry policy is used
+fn parse_preview_query(request: &HttpRequest) -> Result<(RetryPolicyRequest, Option<String>, Option<String>), String> {
+    let (mut retry_policy, mut service_id, mut event_id) = (RetryPolicyRequest::default(), None, None);
+    for (key, value) in request.query_params() {
+        match key.as_str() {
+            "interval" => retry_policy.interval = Some(
+                value.as_str().to_duration_sequence().map_err(|_| String::from("interval should be a duration sequence. Example: [1m, 5m, 1h]"))?
+            ),
+            "maxAttempts" => retry_policy.max_attempts = Some(
+                value.parse().map_err(|_| String::from("maxAttempts should be a integer between 0 and 65535"))?
+            ),
+            "serviceId" => service_id = Some(value),
+            "eventId" => event_id = Some(value),
+            _ => return Err(format!("{} is not a valid preview parameter", key)),
+        }
+    }
+    Ok((retry_policy, service_id, event_id))
+}
+
+/// Serialize a topic into the JSON representation used by `/topics`
+fn topic_to_json(topic: &Topic) -> JsonValue {
+    let settings = &topic.settings;
+    let retention = JsonValue::object()
+        .with("deliveredMessages", settings.delivered_messages_retention.map(format_duration))
+        .with("deadMessages", settings.dead_messages_retention.map(format_duration));
+    let retry_policy = settings.retry_profile.as_ref().map(|profile| JsonValue::object()
+        .with("interval", profile.interval.as_ref().map(ToString::to_string))
+        .with("maxAttempts", profile.max_attempts));
+    JsonValue::object()
+        .with("name", topic.name.as_str())
+        .with("ordering", settings.ordering.as_str())
+        .with("maxPayloadSize", settings.max_payload_size)
+        .with("schemaRef", settings.schema_ref.as_deref())
+        .with("retention", retention)
+        .with("retryPolicy", retry_policy)
+        .with("createdAt", format_rfc3339(topic.created_at))
+        .with("updatedAt", format_rfc3339(topic.updated_at))
+}
+
+/// Read the settings of a topic from the body of `POST /topics` or `PUT /topics/{name}`. The
+/// settings that are not sent follow the configurations
+fn parse_topic_settings(body: &JsonValue) -> Result<TopicSettings, String> {
+    let field = |name: &str| body.get(name).filter(|value| !value.is_null());
+    let mut settings = TopicSettings::default();
+    if let Some(ordering) = field("ordering") {
+        settings.ordering = ordering.as_str().and_then(OrderingMode::from_name).ok_or("ordering should be unordered or ordered")?;
+    }
+    if let Some(max_size) = field("maxPayloadSize") {
+        settings.max_payload_size = Some(max_size.as_u64().filter(|max_size| *max_size > 0).ok_or("maxPayloadSize should be a integer > 0")? as usize);
+    }
+    if let Some(schema_ref) = field("schemaRef") {
+        settings.schema_ref = Some(schema_ref.as_str().filter(|path| !path.is_empty()).ok_or("schemaRef should be the path of a JSON Schema file")?.to_string());
+    }
+    if let Some(retention) = field("retention") {
+        if retention.as_object().is_none() {
+            return Err(String::from("retention should be an object"));
+        }
+        let duration = |name: &str| match retention.get(name).filter(|value| !value.is_null()) {
+            None => Ok(None),
+            Some(value) => value.as_str()
+                .and_then(|value| value.to_duration().ok())
+                .filter(|duration| duration.is_positive())
+                .map(Some)
+                .ok_or_else(|| format!("retention.{} should be a duration > 0. Example: 7d", name)),
+        };
+        settings.delivered_messages_retention = duration("deliveredMessages")?;
+        settings.dead_messages_retention = duration("deadMessages")?;
+    }
+    if let Some(retry_policy) = field("retryPolicy") {
+        let RetryPolicyRequest { interval, max_attempts } = parse_retry_policy(retry_policy, "retryPolicy")?;
+        settings.retry_profile = Some(RetryProfile { interval, max_attempts });
+    }
+    Ok(settings)
+}
+
+/// Return the response of a failed change of a topic
+fn topic_error_response(err: &TopicError) -> HttpResponse {
+    let status = match err {
+        TopicError::AlreadyExists(_) => 409,
+        TopicError::NotFound(_) => 404,
+        TopicError::Schema(_) => 400,
+    };
+    error_response(status, &err.to_string())
+}
+
+/// How many messages `GET /messages` returns when the request does not set a limit
+const DEFAULT_SEARCH_LIMIT: usize = 100;
+
+/// The maximum limit accepted by `GET /messages`
+const MAX_SEARCH_LIMIT: usize = 1000;
+
+/// A search of a page of messages, as done by `GET /messages` and `GET /admin/messages`
+pub(crate) struct MessageSearch {
+    /// The query of the page, asking for one more message to tell if there is a next page
+    pub query: MessageQuery,
+    limit: usize,
+    selection: FieldSelection,
+}
+
+impl MessageSearch {
+    pub fn parse(request: &HttpRequest) -> Result<MessageSearch, String> {
+        let mut query = parse_search_query(request)?;
+        let selection = FieldSelection::parse(request, Some(&COMPACT_MESSAGE_FIELDS))?;
+        let limit = query.limit.unwrap_or(DEFAULT_SEARCH_LIMIT);
+        query.limit = Some(limit + 1);
+        Ok(MessageSearch { query, limit, selection })
+    }
+
+    /// Return the page of the messages found by the query, with the cursor of the next page
+    pub fn respond(&self, result: Result<Vec<Message>, StoreError>) -> HttpResponse {
+        match result {
+            Ok(mut messages) => {
+                let next = (messages.len() > self.limit).then(|| {
+                    messages.truncate(self.limit);
+                    let last = &messages[self.limit - 1];
+                    PageCursor::Message(last.created_at, last.id.clone())
+                });
+                page_response(&self.selection.apply_all(messages.iter().map(message_to_json)), next)
+            }
+            Err(err) => error_response(500, &err.to_string()),
+        }
+    }
+}
+
+/// Read the query parameters of `GET /messages`. Indexed payload fields are searched with
+/// `payload.<field>=<value>` parameters and attributes with `attr.<key>=<value>`
+fn parse_search_query(request: &HttpRequest) -> Result<MessageQuery, String> {
+    let mut query = MessageQuery { limit: Some(DEFAULT_SEARCH_LIMIT), ..MessageQuery::default() };
+    for (key, value) in request.query_params() {
+        match key.as_str() {
+            "recipientId" => query.recipient_id = Some(value),
+            "serviceId" => query.namespace = Some(value),
+            "eventId" => query.topic = Some(value),
+            "status" => query.status = Some(MessageStatus::from_name(&value).ok_or_else(|| format!("{} is not a valid status", value))?),
+            "createdAfter" => query.created_after = Some(parse_rfc3339(&value).map_err(|_| String::from("createdAfter should be a RFC 3339 timestamp"))?),
+            "createdBefore" => query.created_before = Some(parse_rfc3339(&value).map_err(|_| String::from("createdBefore should be a RFC 3339 timestamp"))?),
+            "limit" => query.limit = Some(parse_page_limit(&value, MAX_SEARCH_LIMIT)?),
+            "cursor" => match PageCursor::decode(&value)? {
+                PageCursor::Message(created_at, id) => query.after = Some((created_at, id)),
+                PageCursor::Attempt(_) => return Err(String::from("cursor is not a cursor of messages")),
+            },
+            // read by FieldSelection
+            "fields" | "view" => {}
+            _ => {
+                if let Some(field) = key.strip_prefix("payload.").filter(|field| !field.is_empty()) {
+                    query.indexed_fields.push((field.to_string(), value));
+                } else if let Some(attribute) = key.strip_prefix("attr.").filter(|attribute| !attribute.is_empty()) {
+                    query.attributes.push((attribute.to_string(), value));
+                } else {
+                    return Err(format!("{} is not a valid search parameter", key));
+                }
+            }
+        }
+    }
+    Ok(query)
+}
+
+fn parse_page_limit(value: &str, max: usize) -> Result<usize, String> {
+    let limit: usize = value.parse().map_err(|_| String::from("limit should be a integer >= 1"))?;
+    Ok(limit.clamp(1, max))
+}
+
+/// How many attempts `GET /messages/{id}/attempts` returns when the request does not set a limit
+const DEFAULT_ATTEMPTS_LIMIT: usize = 100;
+
+/// The maximum limit accepted by `GET /messages/{id}/attempts`
+const MAX_ATTEMPTS_LIMIT: usize = 1000;
+
+/// Read the `limit` and `cursor` of `GET /messages/{id}/attempts`, returning how many attempts
+/// are skipped and how many are returned
+fn parse_attempts_page(request: &HttpRequest) -> Result<(usize, usize), String> {
+    let (mut skipped, mut limit) = (0, DEFAULT_ATTEMPTS_LIMIT);
+    for (key, value) in request.query_params() {
+        match key.as_str() {
+            "limit" => limit = parse_page_limit(&value, MAX_ATTEMPTS_LIMIT)?,
+            "cursor" => match PageCursor::decode(&value)? {
+                PageCursor::Attempt(count) => skipped = count,
+                PageCursor::Message(..) => return Err(String::from("cursor is not a cursor of attempts")),
+            },
+            _ => {}
+        }
+    }
+    Ok((skipped, limit))
+}
+
+/// Where the next page of a list starts, sent to the clients as an opaque cursor. The pages of
+/// messages start after the last message of the previous page, in the order they are listed, and
+/// the attempts are only appended, so the pages are not shifted by the items inserted meanwhile
+#[derive(Debug, PartialEq)]
+enum PageCursor {
+    /// After the message created at this time with this ID
+    Message(time::OffsetDateTime, String),
+    /// After the first attempts
+    Attempt(usize),
+}
+
+impl PageCursor {
+    fn encode(&self) -> String {
+        let position = match self {
+            PageCursor::Message(created_at, id) => format!("m.{}.{}", created_at.unix_timestamp_nanos(), id),
+            PageCursor::Attempt(count) => format!("a.{}", count),
+        };
+        base64::encode_url(position.as_bytes())
+    }
+
+    fn decode(cursor: &str) -> Result<PageCursor, String> {
+        let invalid = || String::from("cursor is not a valid cursor");
+        let position = base64::decode_url(cursor).and_then(|bytes| String::from_utf8(bytes).ok()).ok_or_else(invalid)?;
+        match position.split_once('.') {
+            Some(("m", message)) => {
+                let (created_at, id) = message.split_once('.').ok_or_else(invalid)?;
+                let created_at = created_at.parse::<i128>().ok()
+                    .and_then(|nanos| time::OffsetDateTime::from_unix_timestamp_nanos(nanos).ok())
+                    .ok_or_else(invalid)?;
+                Ok(PageCursor::Message(created_at, id.to_string()))
+            }
+            Some(("a", count)) => count.parse().map(PageCursor::Attempt).map_err(|_| invalid()),
+            _ => Err(invalid()),
+        }
+    }
+}
+
+/// Return the JSON response of a page, with the cursor of the next page when there is one
+fn page_response(json: &JsonValue, next: Option<PageCursor>) -> HttpResponse {
+    let mut response = json_response(200, json);
+    if let Some(next) = next {
+        response.headers.set(NEXT_CURSOR_HEADER, &next.encode());
+    }
+    response
+}
+
+/// The fields of the messages in the responses with `?view=compact`, the ones the dashboards that
+/// poll the status of many messages need
+const COMPACT_MESSAGE_FIELDS: [&str; 8] = ["id", "recipientId", "serviceId", "eventId", "status", "attempts", "createdAt", "nextAttemptAt"];
+
+/// The fields kept in each object of a response, chosen by `?fields=id,status` or by `?view=compact`.
+/// Nested fields are separated by dots, like `retryPolicy.maxAttempts`, and the fields the objects
+/// do not have are left out. All the fields are kept when it is None
+#[derive(Debug, Default, PartialEq)]
+struct FieldSelection(Option<Vec<String>>);
+
+impl FieldSelection {
+    /// Read the selection of the request. `compact` has the fields of `?view=compact`, None when
+    /// the endpoint has no compact view
+    fn parse(request: &HttpRequest, compact: Option<&[&str]>) -> Result<FieldSelection, String> {
+        let (mut fields, mut view) = (None, None);
+        for (key, value) in request.query_params() {
+            match key.as_str() {
+                "fields" => fields = Some(value),
+                "view" => view = Some(value),
+                _ => {}
+            }
+        }
+        match (fields, view.as_deref(), compact) {
+            (Some(_), Some(_), _) => Err(String::from("fields and view should not be used together")),
+            (Some(fields), None, _) => {
+                let fields: Vec<String> = fields.split(',').map(str::trim).filter(|field| !field.is_empty()).map(String::from).collect();
+                if fields.is_empty() {
+                    return Err(String::from("fields should have at least one field"));
+                }
+                Ok(FieldSelection(Some(fields)))
+            }
+            (None, Some("full"), _) | (None, None, _) => Ok(FieldSelection(None)),
+            (None, Some("compact"), Some(compact)) => Ok(FieldSelection(Some(compact.iter().map(|field| field.to_string()).collect()))),
+            (None, Some(_), Some(_)) => Err(String::from("view should be full or compact")),
+            (None, Some(_), None) => Err(String::from("view should be full")),
+        }
+    }
+
+    fn apply(&self, json: JsonValue) -> JsonValue {
+        match &self.0 {
+            Some(fields) => select_fields(&json, &fields.iter().map(String::as_str).collect::<Vec<_>>()),
+            None => json,
+        }
+    }
+
+    fn apply_all(&self, json: impl IntoIterator<Item = JsonValue>) -> JsonValue {
+        JsonValue::Array(json.into_iter().map(|json| self.apply(json)).collect())
+    }
+}
+
+/// Keep only the fields of the object, selecting the nested fields of its objects
+fn select_fields(json: &JsonValue, fields: &[&str]) -> JsonValue {
+    let Some(object) = json.as_object() else {
+        return json.clone();
+    };
+    let mut selected: BTreeMap<String, JsonValue> = BTreeMap::new();
+    for field in fields {
+        let (key, nested) = match field.split_once('.') {
+            Some((key, nested)) => (key, Some(nested)),
+            None => (*field, None),
+        };
+        let Some(value) = object.get(key) else { continue };
+        let value = match nested {
+            Some(nested) if value.as_object().is_some() => select_fields(value, &[nested]),
+            Some(_) => continue,
+            None => value.clone(),
+        };
+        match (selected.get_mut(key), value) {
+            (Some(JsonValue::Object(previous)), JsonValue::Object(value)) => previous.extend(value),
+            (_, value) => { selected.insert(key.to_string(), value); }
+        }
+    }
+    JsonValue::Object(selected)
+}
+
+/// Read the query parameters of `GET /reports/deliveries`. `from` and `to` are required and the
+/// format defaults to CSV
+fn parse_report_query(request: &HttpRequest) -> Result<DeliveryReportQuery, String> {
+    let (mut from, mut to, mut recipient_id, mut format) = (None, None, None, ReportFormat::Csv);
+    for (key, value) in request.query_params() {
+        match key.as_str() {
+            "from" => from = Some(parse_rfc3339(&value).map_err(|_| String::from("from should be a RFC 3339 timestamp"))?),
+            "to" => to = Some(parse_rfc3339(&value).map_err(|_| String::from("to should be a RFC 3339 timestamp"))?),
+            "recipientId" => recipient_id = Some(value),
+            "format" => format = ReportFormat::from_name(&value).ok_or_else(|| String::from("format should be csv or ndjson"))?,
+            _ => return Err(format!("{} is not a valid report parameter", key)),
+        }
+    }
+
+    let (Some(from), Some(to)) = (from, to) else {
+        return Err(String::from("from and to are required"));
+    };
+    if from >= to {
+        return Err(String::from("from should be before to"));
+    }
+    Ok(DeliveryReportQuery { from, to, recipient_id, format })
+}
+
+/// The parameters of `GET /topics/{eventId}/pull`
+#[derive(Debug, PartialEq)]
+struct PullQuery {
+    recipient_id: Option<String>,
+    max: usize,
+    wait: Duration,
+    visibility: Duration,
+}
+
+/// How many messages `GET /topics/{eventId}/pull` returns when the request does not set a max
+const DEFAULT_PULL_MESSAGES: usize = 10;
+
+/// Read the query parameters of `GET /topics/{eventId}/pull`. The wait and the visibility timeout
+/// are durations like `30s`, and the pull does not wait by default
+fn parse_pull_query(request: &HttpRequest) -> Result<PullQuery, String> {
+    let duration = |value: &str, field: &str| -> Result<Duration, String> {
+        value.to_duration().ok()
+            .and_then(|duration| Duration::try_from(duration).ok())
+            .ok_or_else(|| format!("{} should be a duration. Example: 30s", field))
+    };
+    let mut query = PullQuery { recipient_id: None, max: DEFAULT_PULL_MESSAGES, wait: Duration::ZERO, visibility: DEFAULT_VISIBILITY_TIMEOUT };
+    for (key, value) in request.query_params() {
+        match key.as_str() {
+            "recipientId" => query.recipient_id = Some(value),
+            "max" => query.max = value.parse().ok()
+                .filter(|max| (1..=MAX_PULL_MESSAGES).contains(max))
+                .ok_or_else(|| format!("max should be a integer between 1 and {}", MAX_PULL_MESSAGES))?,
+            "wait" => query.wait = duration(&value, "wait")?,
+            "visibility" => query.visibility = Some(duration(&value, "visibility")?)
+                .filter(|visibility| !visibility.is_zero())
+                .ok_or("visibility should be a duration > 0")?,
+            _ => return Err(format!("{} is not a valid pull parameter", key)),
+        }
+    }
+    if query.wait > MAX_PULL_WAIT {
+        return Err(format!("wait should be at most {}s", MAX_PULL_WAIT.as_secs()));
+    }
+    Ok(query)
+}
+
+/// Read the receipts of the bodies of `POST /topics/{eventId}/ack` and `POST /topics/{eventId}/nack`
+fn parse_receipts(body: &JsonValue) -> Result<Vec<String>, String> {
+    let receipts = body.get("receipts").and_then(JsonValue::as_array).ok_or("receipts should be a array of strings")?;
+    receipts.iter()
+        .map(|receipt| receipt.as_str().map(String::from).ok_or_else(|| String::from("receipts should be a array of strings")))
+        .collect()
+}
+
+/// Read the optional body of `POST /acks/{token}`, like `{"outcome": "failed", "error": "..."}`,
+/// into the confirmation of the receiver. Without outcome the message was delivered
+fn parse_ack_confirmation(body: &JsonValue) -> Result<Result<(), String>, String> {
+    let outcome = match body.get("outcome") {
+        None | Some(JsonValue::Null) => "delivered",
+        Some(outcome) => outcome.as_str().ok_or("outcome should be delivered or failed")?,
+    };
+    match outcome {
+        "delivered" => Ok(Ok(())),
+        "failed" => match body.get("error") {
+            None | Some(JsonValue::Null) => Ok(Err(String::from("the receiver confirmed the message as failed"))),
+            Some(error) => Ok(Err(error.as_str().ok_or("error should be a string")?.to_string())),
+        },
+        _ => Err(String::from("outcome should be delivered or failed")),
+    }
+}
+
+/// Read the body of `POST /destinations/{recipientId}/transform:test` into a sample message of
+/// the recipient. The payload is the JSON of the `data` field, like in `POST /messages`
+fn parse_transform_test(recipient_id: &str, body: &JsonValue) -> Result<Message, String> {
+    let optional_string = |field: &str, default: &str| -> Result<String, String> {
+        match body.get(field) {
+            None | Some(JsonValue::Null) => Ok(default.to_string()),
+            Some(value) => value.as_str().filter(|value| !value.is_empty()).map(String::from).ok_or_else(|| format!("{} should be a non empty string", field)),
+        }
+    };
+    let payload = body.get("data").map(|data| data.to_string().into_bytes()).unwrap_or_default();
+    let mut message = Message::new(uuid_v4(), recipient_id.to_string(), optional_string("serviceId", "sample")?, optional_string("eventId", "sample")?, payload);
+    if let Some(attributes) = body.get("attributes").filter(|attributes| !attributes.is_null()) {
+        message.attributes = parse_attributes(attributes, "attributes")?;
+    }
+    Ok(message)
+}
+
+/// Read the optional `backfill` of the body of `PUT /destinations/{recipientId}` returning the
+/// backfill and its rate. The window is a duration counted back from now
+fn parse_backfill(body: &JsonValue, now: time::OffsetDateTime) -> Result<Option<(Backfill, f64)>, String> {
+    let Some(backfill) = body.get("backfill").filter(|backfill| !backfill.is_null()) else {
+        return Ok(None);
+    };
+    let topic = backfill.get("eventId").and_then(JsonValue::as_str).ok_or("backfill.eventId should be a string")?;
+    let namespace = match backfill.get("serviceId") {
+        None | Some(JsonValue::Null) => None,
+        Some(service_id) => Some(service_id.as_str().ok_or("backfill.serviceId should be a string")?.to_string()),
+    };
+    let window = backfill.get("window").and_then(JsonValue::as_str)
+        .and_then(|window| window.to_duration().ok())
+        .filter(|window| window.is_positive())
+        .ok_or("backfill.window should be a duration. Example: 24h")?;
+    let limit = match backfill.get("limit") {
+        None | Some(JsonValue::Null) => None,
+        Some(limit) => Some(limit.as_u64().ok_or("backfill.limit should be a integer >= 0")? as usize),
+    };
+    let rate = match backfill.get("ratePerSecond") {
+        None | Some(JsonValue::Null) => DEFAULT_REPLAY_RATE,
+        Some(rate) => rate.as_f64().filter(|rate| rate.is_finite() && *rate > 0.0).ok_or("backfill.ratePerSecond should be a number > 0")?,
+    };
+    Ok(Some((Backfill { namespace, topic: topic.to_string(), since: now - window, limit }, rate)))
+}
+
+/// Read the body of `POST /dead-messages:replay` returning the filter and the replay rate
+fn parse_replay_body(body: &JsonValue) -> Result<(ReplayFilter, f64), String> {
+    let optional_string = |field: &str| -> Result<Option<String>, String> {
+        match body.get(field) {
+            None | Some(JsonValue::Null) => Ok(None),
+            Some(value) => value.as_str().map(|value| Some(value.to_string())).ok_or_else(|| format!("{} should be a string", field)),
+        }
+    };
+    let optional_time = |field: &str| -> Result<Option<time::OffsetDateTime>, String> {
+        optional_string(field)?
+            .map(|value| parse_rfc3339(&value).map_err(|_| format!("{} should be a RFC 3339 timestamp", field)))
+            .transpose()
+    };
+
+    let limit = match body.get("limit") {
+        None | Some(JsonValue::Null) => None,
+        Some(limit) => Some(limit.as_u64().ok_or("limit should be a integer >= 0")? as usize),
+    };
+    let rate = match body.get("ratePerSecond") {
+        None | Some(JsonValue::Null) => DEFAULT_REPLAY_RATE,
+        Some(rate) => rate.as_f64().filter(|rate| rate.is_finite() && *rate > 0.0).ok_or("ratePerSecond should be a number > 0")?,
+    };
+
+    let filter = ReplayFilter {
+        query: MessageQuery {
+            recipient_id: optional_string("recipientId")?,
+            namespace: optional_string("serviceId")?,
+            topic: optional_string("eventId")?,
+            created_after: optional_time("createdAfter")?,
+            created_before: optional_time("createdBefore")?,
+            limit,
+            ..MessageQuery::default()
+        },
+        error_class: match optional_string("errorClass")? {
+            Some(name) => Some(DeliveryErrorClass::from_name(&name).ok_or("errorClass is not a known error class")?),
+            None => None,
+        },
+    };
+    Ok((filter, rate))
+}
+
+/// The RESTful API used by clients to publish messages, inspect them and register destinations
+pub struct RestfulApi {
+    processor: Arc<MessageProcessor>,
+    store: Arc<dyn MessageStore>,
+    destinations: Arc<DestinationRegistry>,
+    retry_configuration: RetryPolicyConfiguration,
+    default_retry_policy: RetryPolicy,
+    sse: Arc<SseHub>,
+    read_replica: Option<Arc<dyn MessageStore>>,
+    jobs: Arc<JobRegistry>,
+    auth: Option<Arc<dyn AuthProvider>>,
+    readiness: Arc<ReadinessProbe>,
+    topics: Arc<TopicRegistry>,
+}
+
+impl RestfulApi {
+    /// Create the API. Messages use the `retryPolicy.defaults.` unless the producer sends its own
+    /// policy, that is clamped by the `retryPolicy.limit.`
+    pub fn new(
+        processor: Arc<MessageProcessor>,
+        store: Arc<dyn MessageStore>,
+        destinations: Arc<DestinationRegistry>,
+        retry_configuration: RetryPolicyConfiguration,
+    ) -> RestfulApi {
+        let default_retry_policy = RetryPolicy::from_configuration(&retry_configuration);
+        let jobs = Arc::new(JobRegistry::new(processor.clock().clone()));
+        let readiness = Arc::new(ReadinessProbe::new(&[ApplicationRoles::MessageProcessor], store.clone()).with_processor(processor.clone()));
+        let topics = Arc::new(TopicRegistry::new());
+        RestfulApi { processor, store, destinations, retry_configuration, default_retry_policy, sse: Arc::new(SseHub::new()), read_replica: None, jobs, auth: None, readiness, topics }
+    }
+
+    /// Connect the consumers of the `sse` destinations to the SseHub used by the HttpDeliverer
+    pub fn with_sse_hub(mut self, sse: Arc<SseHub>) -> RestfulApi {
+        self.sse = sse;
+        self
+    }
+
+    /// Serve the status queries, `GET /messages`, `/messages/{id}` and `/messages/{id}/attempts`,
+    /// from a replica of the store. See `cluster.storage.readUrl`
+    pub fn with_read_replica(mut self, replica: Arc<dyn MessageStore>) -> RestfulApi {
+        self.read_replica = Some(replica);
+        self
+    }
+
+    /// Run the replays and backfills as jobs of the given JobRegistry, followed by `GET /jobs`
+    pub fn with_jobs(mut self, jobs: Arc<JobRegistry>) -> RestfulApi {
+        self.jobs = jobs;
+        self
+    }
+
+    /// Require the requests to be authenticated by the provider. The confirmations of `POST
+    /// /acks/{token}` are authenticated by their signed token instead, as they come from receivers
+    pub fn with_auth_provider(mut self, auth: Arc<dyn AuthProvider>) -> RestfulApi {
+        self.auth = Some(auth);
+        self
+    }
+
+    /// Answer `GET /readyz` with the checks of the probe, like the ones of all the roles of the
+    /// node. It defaults to the checks of the message-processor role
+    pub fn with_readiness(mut self, readiness: Arc<ReadinessProbe>) -> RestfulApi {
+        self.readiness = readiness;
+        self
+    }
+
+    /// Manage the topics of the registry through `/topics`, whose retry policies are the defaults
+    /// of the messages published to them. The processor should follow the same registry
+    pub fn with_topics(mut self, topics: Arc<TopicRegistry>) -> RestfulApi {
+        self.topics = topics;
+        self
+    }
+
+    /// Start a HttpServer on the address serving this API
+    pub fn listen<A: ToSocketAddrs>(api: Arc<RestfulApi>, address: A) -> io::Result<HttpServer> {
+        RestfulApi::listen_with_limits(api, address, ConnectionLimits::default())
+    }
+
+    /// Start a HttpServer like `listen`, refusing the connections beyond the limits
+    pub fn listen_with_limits<A: ToSocketAddrs>(api: Arc<RestfulApi>, address: A, limits: ConnectionLimits) -> io::Result<HttpServer> {
+        let handler: Arc<HttpHandler> = Arc::new(move |request: &HttpRequest| api.handle(request));
+        HttpServer::bind_with_limits(address, handler, limits)
+    }
+
+    /// Route the request to its handler. The `X-Request-ID` of the request, or a new one when it
+    /// has none, is echoed in the response and attached to the logs and the published message
+    pub fn handle(&self, request: &HttpRequest) -> HttpResponse {
+        let request_id = request.headers.get(REQUEST_ID_HEADER).map(str::trim).filter(|request_id| is_valid_request_id(request_id))
+            .map_or_else(uuid_v4, str::to_string);
+        let _scope = log::scope_request_id(&request_id);
+        let mut response = match self.authenticate(request) {
+            Ok(()) => self.route(request, &request_id),
+            Err(response) => response,
+        };
+        response.headers.set(REQUEST_ID_HEADER, &request_id);
+        response
+    }
+
+    /// Authenticate the request with the AuthProvider, returning the `401` response when it fails
+    fn authenticate(&self, request: &HttpRequest) -> Result<(), HttpResponse> {
+        let Some(auth) = &self.auth else {
+            return Ok(());
+        };
+        if request.method == "POST" && request.path().trim_matches('/').starts_with("acks/") {
+            return Ok(());
+        }
+        // probed by the load balancers, that have no credentials
+        if request.method == "GET" && request.path().trim_matches('/') == "readyz" {
+            return Ok(());
+        }
+        match auth.authenticate(request) {
+            Ok(identity) => {
+                log!(Level::Debug, "The request was authenticated as {}", identity.subject);
+                Ok(())
+            }
+            Err(err) => {
+                let mut response = error_response(401, &err.to_string());
+                response.headers.set("WWW-Authenticate", &auth.challenge());
+                Err(response)
+            }
+        }
+    }
+
+    fn route(&self, request: &HttpRequest, request_id: &str) -> HttpResponse {
+        let segments: Vec<&str> = request.path().trim_matches('/').split('/').collect();
+        match (request.method.as_str(), segments.as_slice()) {
+            ("POST", ["messages"]) => self.publish(request, request_id),
+            ("GET", ["messages"]) => self.search_messages(request),
+            ("GET", ["messages", id]) => self.get_message(id, request),
+            ("GET", ["messages", id, "attempts"]) => self.get_attempts(id, request),
+            ("POST", ["dead-messages:replay"]) => self.replay_dead_messages(request),
+            (method, ["jobs"]) => job_response(&self.jobs, method, None),
+            (method, ["jobs", id]) => job_response(&self.jobs, method, Some(id)),
+            ("GET", ["retry-policies", "preview"]) => self.preview_retry_policy(request),
+            ("GET", ["reports", "deliveries"]) => self.report_deliveries(request),
+            ("GET", ["destinations"]) => self.list_destinations(request),
+            ("GET", ["destinations", id]) => self.get_destination(id),
+            ("PUT", ["destinations", id]) => self.put_destination(id, request),
+            ("DELETE", ["destinations", id]) => self.delete_destination(id, request),
+            ("POST", ["destinations", id, "restore"]) => self.restore_destination(id, request),
+            ("GET", ["destinations", id, "history"]) => self.destination_history(id),
+            ("GET", ["destinations", id, "lag"]) => self.destination_lag(id),
+            ("GET", ["destinations", id, "canary"]) => self.destination_canary(id),
+            ("POST", ["destinations", id, "rollback"]) => self.rollback_destination(id, request),
+            ("GET", ["deleted-destinations"]) => self.list_deleted_destinations(request),
+            ("GET", ["destinations", id, "events"]) => self.stream_events(id, request),
+            ("POST", ["destinations", id, "transform:test"]) => self.test_transform(id, request),
+            ("GET", ["topics"]) => json_response(200, &JsonValue::Array(self.topics.list().iter().map(topic_to_json).collect())),
+            ("POST", ["topics"]) => self.create_topic(request),
+            ("GET", ["topics", name]) => match self.topics.get(name) {
+                Some(topic) => json_response(200, &topic_to_json(&topic)),
+                None => error_response(404, "topic not found"),
+            },
+            ("PUT", ["topics", name]) => self.update_topic(name, request),
+            ("DELETE", ["topics", name]) => match self.topics.remove(name) {
+                Some(_) => HttpResponse::new(204),
+                None => error_response(404, "topic not found"),
+            },
+            ("GET", ["topics", topic, "pull"]) => self.pull(topic, request),
+            ("POST", ["topics", topic, "ack"]) => self.settle(topic, request, "acked", MessageProcessor::ack),
+            ("POST", ["topics", topic, "nack"]) => self.settle(topic, request, "nacked", MessageProcessor::nack),
+            ("POST", ["acks", token]) => self.confirm_ack(token, request),
+            ("GET", ["readyz"]) => {
+                let readiness = self.readiness.check();
+                json_response(readiness.status(), &readiness.to_json())
+            }
+            (_, ["messages"] | ["messages", _] | ["messages", _, "attempts"] | ["dead-messages:replay"] | ["retry-policies", "preview"] | ["reports", "deliveries"] | ["destinations"] | ["destinations", _] | ["destinations", _, "events" | "transform:test" | "restore" | "history" | "rollback" | "lag" | "canary"] | ["deleted-destinations"])
+            | (_, ["topics"] | ["topics", _] | ["topics", _, "pull" | "ack" | "nack"] | ["acks", _] | ["readyz"]) => {
+                error_response(405, "method not allowed")
+            }
+            _ => error_response(404, "resource not found"),
+        }
+    }
+
+    /// Return the default retry policy of the messages of the namespace and topic and the limits
+    /// they are clamped by, the global ones when the namespace has no `retryPolicy.namespace.`
+    /// configurations. The retry policy of a topic fills the defaults of the namespace
+    fn retry_policies(&self, service_id: &str, event_id: &str) -> (RetryPolicy, Cow<'_, RetryPolicyConfiguration>) {
+        let (defaults, limits) = match self.retry_configuration.for_namespace(service_id) {
+            Some(limits) => (RetryPolicy::from_configuration(&limits).clamp(&limits), Cow::Owned(limits)),
+            None => (self.default_retry_policy.clone(), Cow::Borrowed(&self.retry_configuration)),
+        };
+        match self.topics.retry_profile(event_id) {
+            Some(profile) => (profile.over(&defaults).clamp(&limits), limits),
+            None => (defaults, limits),
+        }
+    }
+
+    /// Create the topic named by the `name` of the body, with the settings of the body
+    fn create_topic(&self, request: &HttpRequest) -> HttpResponse {
+        let body = match JsonValue::parse_bytes(&request.body) {
+            Ok(body) => body,
+            Err(err) => return error_response(400, &format!("body is not valid JSON: {}", err)),
+        };
+        let Some(name) = body.get("name").and_then(JsonValue::as_str).filter(|name| !name.is_empty() && !name.contains('/')) else {
+            return error_response(400, "name should be a non empty string without /");
+        };
+        let settings = match parse_topic_settings(&body) {
+            Ok(settings) => settings,
+            Err(err) => return error_response(400, &err),
+        };
+        match self.topics.create(name, settings, self.processor.clock().now()) {
+            Ok(topic) => json_response(201, &topic_to_json(&topic)),
+            Err(err) => topic_error_response(&err),
+        }
+    }
+
+    /// Replace the settings of the topic with the ones of the body
+    fn update_topic(&self, name: &str, request: &HttpRequest) -> HttpResponse {
+        let body = match JsonValue::parse_bytes(&request.body) {
+            Ok(body) => body,
+            Err(err) => return error_response(400, &format!("body is not valid JSON: {}", err)),
+        };
+        let settings = match parse_topic_settings(&body) {
+            Ok(settings) => settings,
+            Err(err) => return error_response(400, &err),
+        };
+        match self.topics.update(name, settings, self.processor.clock().now()) {
+            Ok(topic) => json_response(200, &topic_to_json(&topic)),
+            Err(err) => topic_error_response(&err),
+        }
+    }
+
+    fn publish(&self, request: &HttpRequest, request_id: &str) -> HttpResponse {
+        let decoded;
+        let request = match decode_body(request) {
+            Ok(None) => request,
+            Ok(Some(body)) => {
+                decoded = HttpRequest { body, ..request.clone() };
+                &decoded
+            }
+            Err(response) => return response,
+        };
+        if is_batch_publish(request) {
+            return self.publish_batch(request, request_id);
+        }
+        let send_message = match parse_publish_body(request) {
+            Ok(send_message) => send_message,
+            Err(err) => return error_response(400, &err),
+        };
+        let (status, body) = self.publish_message(send_message, request_id);
+        json_response(status, &body)
+    }
+
+    /// Publish each line of a `application/x-ndjson` body, with the same fields of a
+    /// `application/json` publish. Answer a line with the result of each message, in order, so a
+    /// invalid or rejected message does not fail the others
+    fn publish_batch(&self, request: &HttpRequest, request_id: &str) -> HttpResponse {
+        let lines: Vec<(usize, &[u8])> = request.body.split(|byte| *byte == b'\n')
+            .enumerate()
+            .map(|(index, line)| (index + 1, line.trim_ascii()))
+            .filter(|(_, line)| !line.is_empty())
+            .collect();
+        if lines.is_empty() {
+            return error_response(400, "the batch should have at least one message");
+        }
+        if lines.len() > MAX_BATCH_MESSAGES {
+            return error_response(413, &format!("the batch has {} messages, more than the {} allowed", lines.len(), MAX_BATCH_MESSAGES));
+        }
+
+        let results: Vec<String> = lines.into_iter().map(|(line, body)| {
+            let (status, body) = match parse_json_publish(body) {
+                Ok(send_message) => self.publish_message(send_message, request_id),
+                Err(err) => (400, JsonValue::object().with("error", err)),
+            };
+            let result = JsonValue::object().with("line", line).with("status", status);
+            let result = match status {
+                200 | 202 => result.with("message", body),
+                _ => result.with("error", body.get("error").cloned()).with("violations", body.get("violations").cloned()),
+            };
+            result.to_string()
+        }).collect();
+        HttpResponse::with_body(200, "application/x-ndjson", results.join("\n"))
+    }
+
+    /// Publish the message, returning the status and the body of its response
+    fn publish_message(&self, send_message: SendMessageRequest, request_id: &str) -> (u16, JsonValue) {
+        let mut message = Message::new_at(
+            self.processor.next_message_id(),
+            send_message.recipient_id,
+            send_message.service_id,
+            send_message.event_id,
+            send_message.payload,
+            self.processor.clock().now(),
+        );
+        message.producer_message_id = send_message.producer_message_id;
+        message.attributes = send_message.attributes;
+        message.request_id = Some(request_id.to_string());
+        let (default_retry_policy, limits) = self.retry_policies(&message.service_id, &message.event_id);
+        let mut violations = Vec::new();
+        match send_message.retry_policy {
+            Some(requested) => {
+                let requested = requested.with_defaults(&default_retry_policy);
+                violations = requested.violations(&limits);
+                message.retry_policy = requested.clamp(&limits);
+                message.requested_retry_policy = Some(requested);
+            }
+            None => message.retry_policy = default_retry_policy,
+        }
+
+        // the producer is told which parts of its retry policy were clamped
+        let json = message_to_json(&message).with("retryPolicyViolations", violations);
+        match self.processor.publish(message) {
+            Ok(PublishOutcome::Accepted) => (202, json),
+            // the original message is returned so producers can retry publishes safely
+            Ok(PublishOutcome::Duplicate(original)) => (200, message_to_json(&original)),
+            Ok(PublishOutcome::Rejected(rejection)) => (422, JsonValue::object()
+                .with("error", rejection.reason)
+                .with("violations", rejection.violations)),
+            Err(err) => (503, JsonValue::object().with("error", err.to_string())),
+        }
+    }
+
+    fn search_messages(&self, request: &HttpRequest) -> HttpResponse {
+        match MessageSearch::parse(request) {
+            Ok(search) => self.read(|store| store.find_messages(&search.query), |result| search.respond(result)),
+            Err(err) => error_response(400, &err),
+        }
+    }
+
+    fn get_message(&self, id: &str, request: &HttpRequest) -> HttpResponse {
+        let selection = match FieldSelection::parse(request, Some(&COMPACT_MESSAGE_FIELDS)) {
+            Ok(selection) => selection,
+            Err(err) => return error_response(400, &err),
+        };
+        self.read(|store| store.get_message(id), |result| match result {
+            Ok(Some(message)) => json_response(200, &selection.apply(message_to_json(&message))),
+            Ok(None) => error_response(404, "message not found"),
+            Err(err) => error_response(500, &err.to_string()),
+        })
+    }
+
+    fn get_attempts(&self, id: &str, request: &HttpRequest) -> HttpResponse {
+        let (selection, (skipped, limit)) = match FieldSelection::parse(request, None).and_then(|selection| Ok((selection, parse_attempts_page(request)?))) {
+            Ok(parsed) => parsed,
+            Err(err) => return error_response(400, &err),
+        };
+        let read = |store: &dyn MessageStore| store.get_message(id)?.map(|_| store.get_attempts(id)).transpose();
+        self.read(read, |result| match result {
+            Ok(None) => error_response(404, "message not found"),
+            Ok(Some(attempts)) => {
+                let page = attempts.iter().skip(skipped).take(limit);
+                let next = (attempts.len() > skipped.saturating_add(limit)).then(|| PageCursor::Attempt(skipped + limit));
+                page_response(&selection.apply_all(page.map(attempt_to_json)), next)
+            }
+            Err(err) => error_response(500, &err.to_string()),
+        })
+    }
+
+    /// Run a status query on the read replica and respond with how stale it may be. The query
+    /// runs on the store when there is no replica or when the replica fails
+    fn read<T>(
+        &self,
+        query: impl Fn(&dyn MessageStore) -> Result<T, StoreError>,
+        respond: impl FnOnce(Result<T, StoreError>) -> HttpResponse,
+    ) -> HttpResponse {
+        let Some(replica) = &self.read_replica else {
+            return respond(query(self.store.as_ref()));
+        };
+        let result = match query(replica.as_ref()) {
+            Ok(result) => result,
+            Err(err) => {
+                log!(Level::Warn, "Failed to query the read replica, querying the store: {}", err);
+                return respond(query(self.store.as_ref()));
+            }
+        };
+
+        let mut response = respond(Ok(result));
+        response.headers.set(REPLICA_HEADER, "true");
+        match replica.synced_at() {
+            Ok(Some(synced_at)) => {
+                let staleness = (self.processor.clock().now() - synced_at).whole_seconds().max(0);
+                response.headers.set(REPLICA_SYNCED_AT_HEADER, &format_rfc3339(synced_at));
+                response.headers.set(REPLICA_STALENESS_HEADER, &staleness.to_string());
+            }
+            Ok(None) | Err(_) => response.headers.set(REPLICA_STALENESS_HEADER, "unknown"),
+        }
+        response
+    }
+
+    fn replay_dead_messages(&self, request: &HttpRequest) -> HttpResponse {
+        let body = match JsonValue::parse_bytes(&request.body) {
+            Ok(body) => body,
+            Err(err) => return error_response(400, &format!("body is not valid JSON: {}", err)),
+        };
+        let (filter, rate) = match parse_replay_body(&body) {
+            Ok(parsed) => parsed,
+            Err(err) => return error_response(400, &err),
+        };
+        let dead_messages = match find_dead_messages(self.store.as_ref(), &filter) {
+            Ok(dead_messages) => dead_messages,
+            Err(err) => return error_response(500, &err.to_string()),
+        };
+
+        let matched = dead_messages.len();
+        let job = start_replay(&self.jobs, "replay", actor(request), self.processor.clone(), dead_messages, rate);
+        json_response(202, &JsonValue::object().with("matched", matched).with("ratePerSecond", rate).with("jobId", job.id))
+    }
+
+    fn preview_retry_policy(&self, request: &HttpRequest) -> HttpResponse {
+        let (requested, service_id, event_id) = match parse_preview_query(request) {
+            Ok(query) => query,
+            Err(err) => return error_response(400, &err),
+        };
+        let (default_retry_policy, limits) = self.retry_policies(service_id.as_deref().unwrap_or_default(), event_id.as_deref().unwrap_or_default());
+        let requested = requested.with_defaults(&default_retry_policy);
+        let effective = requested.clamp(&limits);
+        let attempts: Vec<JsonValue> = effective.attempt_times(self.processor.clock().now()).into_iter()
+            .enumerate()
+            .map(|(index, at)| JsonValue::object().with("attempt", index + 1).with("at", format_rfc3339(at)))
+            .collect();
+
+        json_response(200, &JsonValue::object()
+            .with("requestedRetryPolicy", retry_policy_to_json(&requested))
+            .with("retryPolicy", retry_policy_to_json(&effective))
+            .with("violations", requested.violations(&limits))
+            .with("attempts", attempts))
+    }
+
+    fn report_deliveries(&self, request: &HttpRequest) -> HttpResponse {
+        let query = match parse_report_query(request) {
+            Ok(query) => query,
+            Err(err) => return error_response(400, &err),
+        };
+        match delivery_report(self.store.as_ref(), &query) {
+            Ok(report) => {
+                let mut response = HttpResponse::with_body(200, query.format.content_type(), report);
+                let filename = format!("deliveries.{}", query.format.extension());
+                response.headers.set("Content-Disposition", &format!("attachment; filename=\"{}\"", filename));
+                response
+            }
+            Err(err) => error_response(500, &err.to_string()),
+        }
+    }
+
+    fn list_destinations(&self, request: &HttpRequest) -> HttpResponse {
+        let selection = match FieldSelection::parse(request, None) {
+            Ok(selection) => selection,
+            Err(err) => return error_response(400, &err),
+        };
+        let mut destinations = self.destinations.list();
+        destinations.sort_by(|a, b| a.id.cmp(&b.id));
+        json_response(200, &selection.apply_all(destinations.iter().map(destination_to_json)))
+    }
+
+    fn get_destination(&self, id: &str) -> HttpResponse {
+        match self.destinations.get(id) {
+            Some(destination) => destination_response(200, &destination_to_json(&destination), destination.version),
+            None => error_response(404, "destination not found"),
+        }
+    }
+
+    fn put_destination(&self, id: &str, request: &HttpRequest) -> HttpResponse {
+        let expected = match parse_expected_version(request) {
+            Ok(expected) => expected,
+            Err(err) => return error_response(400, &err),
+        };
+        let body = match JsonValue::parse_bytes(&request.body) {
+            Ok(body) => body,
+            Err(err) => return error_response(400, &format!("body is not valid JSON: {}", err)),
+        };
+        let destination = match parse_destination(id, &body) {
+            Ok(destination) => destination,
+            Err(err) => return error_response(400, &err),
+        };
+        let backfill = match parse_backfill(&body, self.processor.clock().now()) {
+            Ok(backfill) => backfill,
+            Err(err) => return error_response(400, &err),
+        };
+        let destination = match self.destinations.register_if(destination, expected, &change_origin(request, self.processor.clock().now())) {
+            Ok(destination) => destination,
+            Err(conflict) => return version_conflict_response(&conflict),
+        };
+        self.processor.unpark(id);
+        let mut json = destination_to_json(&destination);
+
+        if let Some((backfill, rate)) = backfill {
+            let messages = match find_backfill_messages(self.store.as_ref(), id, &backfill) {
+                Ok(messages) => messages,
+                Err(err) => return error_response(500, &err.to_string()),
+            };
+            let matched = messages.len();
+            let job = start_replay(&self.jobs, "backfill", actor(request), self.processor.clone(), messages, rate);
+            json = json.with("backfill", JsonValue::object().with("matched", matched).with("ratePerSecond", rate).with("jobId", job.id));
+        }
+        destination_response(200, &json, destination.version)
+    }
+
+    fn delete_destination(&self, id: &str, request: &HttpRequest) -> HttpResponse {
+        let expected = match parse_expected_version(request) {
+            Ok(expected) => expected,
+            Err(err) => return error_response(400, &err),
+        };
+        match self.destinations.remove_if(id, expected, &change_origin(request, self.processor.clock().now())) {
+            Ok(Some(_)) => HttpResponse::new(204),
+            Ok(None) => error_response(404, "destination not found"),
+            Err(conflict) => version_conflict_response(&conflict),
+        }
+    }
+
+    /// Register again a deleted destination during its grace period, sending its parked messages
+    fn restore_destination(&self, id: &str, request: &HttpRequest) -> HttpResponse {
+        let Some(destination) = self.destinations.restore(id, &change_origin(request, self.processor.clock().now())) else {
+            return error_response(404, "no deleted destination to restore");
+        };
+        self.processor.unpark(id);
+        destination_response(200, &destination_to_json(&destination), destination.version)
+    }
+
+    fn destination_history(&self, id: &str) -> HttpResponse {
+        let history = self.destinations.history(id);
+        if history.is_empty() {
+            return error_response(404, "destination not found");
+        }
+        json_response(200, &history_to_json(&history))
+    }
+
+    /// Answer how far behind the destination is, as of its last attempt
+    /// Return the outcomes of the attempts of each branch of the destination with a canary, since
+    /// its canary was set
+    fn destination_canary(&self, id: &str) -> HttpResponse {
+        let Some(destination) = self.destinations.get(id) else {
+            return error_response(404, "destination not found");
+        };
+        let Some(canary) = &destination.canary else {
+            return error_response(404, "destination has no canary");
+        };
+        let stats = self.destinations.canary_stats();
+        json_response(200, &JsonValue::object()
+            .with("recipientId", id)
+            .with("percent", canary.percent)
+            .with("branches", vec![
+                stats.get(id, Branch::Primary).to_json(Branch::Primary, &destination.url),
+                stats.get(id, Branch::Canary).to_json(Branch::Canary, &canary.url),
+            ]))
+    }
+
+    fn destination_lag(&self, id: &str) -> HttpResponse {
+        if self.destinations.get(id).is_none() {
+            return error_response(404, "destination not found");
+        }
+        let json = match self.processor.lag(id) {
+            Some(lag) => lag.to_json(id),
+            None => JsonValue::object()
+                .with("recipientId", id)
+                .with("lagging", false)
+                .with("backlogAgeMs", JsonValue::Null)
+                .with("latencyMs", JsonValue::Null)
+                .with("laggingSince", JsonValue::Null)
+                .with("updatedAt", JsonValue::Null),
+        };
+        json_response(200, &json)
+    }
+
+    /// Register again the destination as it was at a version of its history
+    fn rollback_destination(&self, id: &str, request: &HttpRequest) -> HttpResponse {
+        let expected = match parse_expected_version(request) {
+            Ok(expected) => expected,
+            Err(err) => return error_response(400, &err),
+        };
+        let body = match JsonValue::parse_bytes(&request.body) {
+            Ok(body) => body,
+            Err(err) => return error_response(400, &format!("body is not valid JSON: {}", err)),
+        };
+        let Some(version) = body.get("version").and_then(JsonValue::as_u64) else {
+            return error_response(400, "version should be a integer");
+        };
+        match self.destinations.rollback(id, version, expected, &change_origin(request, self.processor.clock().now())) {
+            Ok(destination) => {
+                self.processor.unpark(id);
+                destination_response(200, &destination_to_json(&destination), destination.version)
+            }
+            Err(RollbackError::UnknownVersion(_)) => error_response(404, &format!("the destination has no version {} in its history", version)),
+            Err(RollbackError::Conflict(conflict)) => version_conflict_response(&conflict),
+        }
+    }
+
+    fn list_deleted_destinations(&self, request: &HttpRequest) -> HttpResponse {
+        let selection = match FieldSelection::parse(request, None) {
+            Ok(selection) => selection,
+            Err(err) => return error_response(400, &err),
+        };
+        let mut deleted = self.destinations.deleted(self.processor.clock().now());
+        deleted.sort_by(|(a, _), (b, _)| a.id.cmp(&b.id));
+        let json = deleted.iter()
+            .map(|(destination, purge_at)| destination_to_json(destination).with("purgeAt", format_rfc3339(*purge_at)));
+        json_response(200, &selection.apply_all(json))
+    }
+
+    /// Answer what the destination would do with a sample message, without sending it
+    fn test_transform(&self, id: &str, request: &HttpRequest) -> HttpResponse {
+        let Some(destination) = self.destinations.get(id) else {
+            return error_response(404, "destination not found");
+        };
+        let body = match JsonValue::parse_bytes(&request.body) {
+            Ok(body) => body,
+            Err(err) => return error_response(400, &format!("body is not valid JSON: {}", err)),
+        };
+        let message = match parse_transform_test(id, &body) {
+            Ok(message) => message,
+            Err(err) => return error_response(400, &err),
+        };
+
+        let accepted = destination.accepts(&message);
+        let mut json = JsonValue::object()
+            .with("accepted", accepted)
+            .with("mode", destination.mode.as_str())
+            .with("attributeFilter", string_map_to_json(&destination.attribute_filter));
+        if !accepted {
+            return json_response(200, &json);
+        }
+        json = match destination.mode {
+            DeliveryMode::Push => {
+                let target = HttpUrl::parse(&destination.url).map(|url| url.target).unwrap_or_default();
+                let request = delivery_request(&destination, &message, &target, self.processor.clock().now());
+                let headers: BTreeMap<String, String> = request.headers.iter().map(|(name, value)| (name.to_string(), value.to_string())).collect();
+                json.with("request", JsonValue::object()
+                    .with("method", request.method.as_str())
+                    .with("url", destination.url.as_str())
+                    .with("headers", string_map_to_json(&headers))
+                    .with("body", String::from_utf8_lossy(&request.body).as_ref()))
+            }
+            DeliveryMode::Sse => json.with("event", JsonValue::object()
+                .with("event", message.topic())
+                .with("data", String::from_utf8_lossy(&message.payload).as_ref())),
+            DeliveryMode::Pull => json.with("message", message_to_json(&message)),
+        };
+        json_response(200, &json)
+    }
+
+    fn stream_events(&self, id: &str, request: &HttpRequest) -> HttpResponse {
+        match self.destinations.get(id) {
+            Some(destination) if destination.mode == DeliveryMode::Sse => {}
+            Some(_) => return error_response(409, "the destination mode is not sse"),
+            None => return error_response(404, "destination not found"),
+        }
+        let last_event_id = match request.headers.get("Last-Event-ID").map(|id| id.trim().parse::<u64>()) {
+            Some(Ok(last_event_id)) => Some(last_event_id),
+            Some(Err(_)) => return error_response(400, "Last-Event-ID should be a integer >= 0"),
+            None => None,
+        };
+
+        // subscribe before answering, so the consumer is connected once it receives the response head
+        let subscription = Mutex::new(Some(self.sse.subscribe(id, last_event_id)));
+        let mut response = HttpResponse::streamed(200, "text/event-stream", move |writer| {
+            match subscription.lock().unwrap().take() {
+                Some(subscription) => subscription.write_to(writer, SSE_KEEPALIVE_INTERVAL),
+                None => Ok(()),
+            }
+        });
+        response.headers.set("Cache-Control", "no-cache");
+        response
+    }
+
+    fn pull(&self, topic: &str, request: &HttpRequest) -> HttpResponse {
+        let query = match parse_pull_query(request) {
+            Ok(query) => query,
+            Err(err) => return error_response(400, &err),
+        };
+        match self.processor.pull(topic, query.recipient_id.as_deref(), query.max, query.wait, query.visibility) {
+            Ok(pulled) => {
+                let messages: Vec<JsonValue> = pulled.iter().map(pulled_message_to_json).collect();
+                json_response(200, &JsonValue::object().with("messages", messages))
+            }
+            Err(err) => error_response(500, &err.to_string()),
+        }
+    }
+
+    /// Ack or nack the receipts of the body, answering how many of them were still leased
+    fn settle(
+        &self,
+        topic: &str,
+        request: &HttpRequest,
+        field: &str,
+        settle: fn(&MessageProcessor, &str, &str) -> Result<bool, StoreError>,
+    ) -> HttpResponse {
+        let body = match JsonValue::parse_bytes(&request.body) {
+            Ok(body) => body,
+            Err(err) => return error_response(400, &format!("body is not valid JSON: {}", err)),
+        };
+        let receipts = match parse_receipts(&body) {
+            Ok(receipts) => receipts,
+            Err(err) => return error_response(400, &err),
+        };
+        let mut settled = 0;
+        for receipt in &receipts {
+            match settle(&self.processor, topic, receipt) {
+                Ok(true) => settled += 1,
+                Ok(false) => {}
+                Err(err) => return error_response(500, &err.to_string()),
+            }
+        }
+        json_response(200, &JsonValue::object().with(field, settled))
+    }
+
+    fn confirm_ack(&self, token: &str, request: &HttpRequest) -> HttpResponse {
+        let token = match AckToken::verify(token, time::OffsetDateTime::now_utc()) {
+            Ok(token) => token,
+            Err(err @ AckError::Expired(_)) => return error_response(410, &err.to_string()),
+            Err(err) => return error_response(403, &err.to_string()),
+        };
+        let body = match request.body.is_empty() {
+            true => JsonValue::object(),
+            false => match JsonValue::parse_bytes(&request.body) {
+                Ok(body) => body,
+                Err(err) => return error_response(400, &format!("body is not valid JSON: {}", err)),
+            },
+        };
+        let confirmation = match parse_ack_confirmation(&body) {
+            Ok(confirmation) => confirmation,
+            Err(err) => return error_response(400, &err),
+        };
+        let outcome = if confirmation.is_ok() { "delivered" } else { "failed" };
+        match self.processor.confirm_ack(&token, confirmation) {
+            Ok(true) => json_response(200, &JsonValue::object()
+                .with("messageId", token.message_id.as_str())
+                .with("attempt", token.attempt)
+                .with("outcome", outcome)),
+            Ok(false) => error_response(404, "the message is not waiting for the confirmation of this attempt"),
+            Err(err) => error_response(500, &err.to_string()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn json_request(body: &str) -> HttpRequest {
+        let mut request = HttpRequest::new("POST", "/messages");
+        request.headers.set("Content-Type", "application/json");
+        request.body = body.as_bytes().to_vec();
+        request
+    }
+
+    #[test]
+    fn test_if_json_publish_body_is_parsed() {
+        let request = json_request(r#"{"sendMessage": {"recipientId": "r", "serviceId": "s", "eventId": "e", "type": "http"}, "data": {"order": 1}}"#);
+        let send_message = parse_publish_body(&request).unwrap();
+        assert_eq!(send_message.recipient_id, "r");
+        assert_eq!(send_message.service_id, "s");
+        assert_eq!(send_message.event_id, "e");
+        assert_eq!(send_message.producer_message_id, None);
+        assert_eq!(send_message.payload, br#"{"order":1}"#);
+    }
+
+    #[test]
+    fn test_if_multipart_publish_body_is_parsed() {
+        let mut request = HttpRequest::new("POST", "/messages");
+        request.headers.set("Content-Type", "multipart/form-data; boundary=---b");
+        request.body = concat!(
+            "-----b\r\nContent-Disposition: form-data; name=\"metadata\"\r\nContent-Type: application/json\r\n\r\n",
+            r#"{"sendMessage": {"recipientId": "r", "serviceId": "s", "eventId": "e", "producerMessageId": "order-1"}}"#,
+            "\r\n-----b\r\nContent-Disposition: form-data; name=\"data\"\r\nContent-Type: image/jpeg\r\n\r\n",
+            "binary",
+            "\r\n-----b--"
+        ).as_bytes().to_vec();
+
+        let send_message = parse_publish_body(&request).unwrap();
+        assert_eq!(send_message.recipient_id, "r");
+        assert_eq!(send_message.producer_message_id.as_deref(), Some("order-1"));
+        assert_eq!(send_message.payload, b"binary");
+    }
+
+    #[test]
+    fn test_if_inline_retry_policy_is_parsed() {
+        let request = json_request(r#"{"sendMessage": {"recipientId": "r", "serviceId": "s", "eventId": "e", "retryPolicy": {"interval": "[1m, 5m]", "maxAttempts": 10}}}"#);
+        let retry_policy = parse_publish_body(&request).unwrap().retry_policy.unwrap();
+        assert_eq!(retry_policy.interval, Some("[1m, 5m]".to_duration_sequence().unwrap()));
+        assert_eq!(retry_policy.max_attempts, Some(10));
+
+        let defaults = RetryPolicy { interval: Some("1h".to_duration_sequence().unwrap()), max_attempts: 3 };
+        let request = json_request(r#"{"sendMessage": {"recipientId": "r", "serviceId": "s", "eventId": "e", "retryPolicy": {"maxAttempts": 0}}}"#);
+        let retry_policy = parse_publish_body(&request).unwrap().retry_policy.unwrap().with_defaults(&defaults);
+        assert_eq!(retry_policy, RetryPolicy { interval: defaults.interval.clone(), max_attempts: 0 });
+
+        for retry_policy in [r#""1m""#, r#"{"interval": "[1m 5m]"}"#, r#"{"maxAttempts": 70000}"#, r#"{"maxAttempts": -1}"#] {
+            let body = format!(r#"{{"sendMessage": {{"recipientId": "r", "serviceId": "s", "eventId": "e", "retryPolicy": {}}}}}"#, retry_policy);
+            assert!(parse_publish_body(&json_request(&body)).unwrap_err().contains("retryPolicy"), "{}", retry_policy);
+        }
+    }
+
+    #[test]
+    fn test_if_message_attributes_are_parsed() {
+        let request = json_request(r#"{"sendMessage": {"recipientId": "r", "serviceId": "s", "eventId": "e", "attributes": {"region": "eu", "tenant-id": "42"}}}"#);
+        let attributes = parse_publish_body(&request).unwrap().attributes;
+        assert_eq!(attributes.get("region").map(String::as_str), Some("eu"));
+        assert_eq!(attributes.get("tenant-id").map(String::as_str), Some("42"));
+
+        for attributes in [r#"["eu"]"#, r#"{"region": 1}"#, r#"{"my region": "eu"}"#, r#"{"region": "eu\r\nX-Evil: 1"}"#] {
+            let body = format!(r#"{{"sendMessage": {{"recipientId": "r", "serviceId": "s", "eventId": "e", "attributes": {}}}}}"#, attributes);
+            assert!(parse_publish_body(&json_request(&body)).unwrap_err().contains("attributes"), "{}", attributes);
+        }
+    }
+
+    #[test]
+    fn test_if_preview_query_is_parsed() {
+        let (retry_policy, service_id, event_id) = parse_preview_query(&HttpRequest::new("GET", "/retry-policies/preview?interval=%5B1m,5m,1h%5D&maxAttempts=10&serviceId=billing&eventId=invoice.paid")).unwrap();
+        assert_eq!(retry_policy.interval, Some("[1m, 5m, 1h]".to_duration_sequence().unwrap()));
+        assert_eq!(retry_policy.max_attempts, Some(10));
+        assert_eq!((service_id.as_deref(), event_id.as_deref()), (Some("billing"), Some("invoice.paid")));
+
+        assert_eq!(parse_preview_query(&HttpRequest::new("GET", "/retry-policies/preview")).unwrap(), (RetryPolicyRequest::default(), None, None));
+        assert!(parse_preview_query(&HttpRequest::new("GET", "/retry-policies/preview?maxAttempts=-1")).is_err());
+        assert!(parse_preview_query(&HttpRequest::new("GET", "/retry-policies/preview?interval=soon")).is_err());
+    }
+
+    #[test]
+    fn test_if_topic_settings_are_parsed() {
+        let body = JsonValue::parse(r#"{"ordering": "ordered", "schemaRef": "/etc/angler/invoice.json", "retention": {"deliveredMessages": "7d", "deadMessages": null}, "retryPolicy": {"maxAttempts": 3}}"#).unwrap();
+        let settings = parse_topic_settings(&body).unwrap();
+        assert_eq!(settings.ordering, OrderingMode::Ordered);
+        assert_eq!(settings.schema_ref.as_deref(), Some("/etc/angler/invoice.json"));
+        assert_eq!((settings.delivered_messages_retention, settings.dead_messages_retention), (Some(time::Duration::days(7)), None));
+        assert_eq!(settings.retry_profile, Some(RetryProfile { interval: None, max_attempts: Some(3) }));
+        assert_eq!(parse_topic_settings(&JsonValue::parse("{}").unwrap()).unwrap(), TopicSettings::default());
+
+        for (body, field) in [(r#"{"maxPayloadSize": 0}"#, "maxPayloadSize"), (r#"{"retention": {"deadMessages": "-1d"}}"#, "retention.deadMessages"), (r#"{"retryPolicy": {"maxAttempts": -1}}"#, "retryPolicy.maxAttempts")] {
+            assert!(parse_topic_settings(&JsonValue::parse(body).unwrap()).unwrap_err().starts_with(field), "{}", body);
+        }
+    }
+
+    #[test]
+    fn test_if_search_query_is_parsed() {
+        let request = HttpRequest::new("GET", "/messages?eventId=order.created&status=dead&payload.order.id=12345&attr.region=eu&limit=5000");
+        let query = parse_search_query(&request).unwrap();
+        assert_eq!(query.attributes, vec![(String::from("region"), String::from("eu"))]);
+        assert_eq!(query.topic.as_deref(), Some("order.created"));
+        assert_eq!(query.status, Some(MessageStatus::Dead));
+        assert_eq!(query.indexed_fields, vec![(String::from("order.id"), String::from("12345"))]);
+        assert_eq!(query.limit, Some(MAX_SEARCH_LIMIT));
+        let since = parse_search_query(&HttpRequest::new("GET", "/messages?createdAfter=2024-05-30T10:00:00Z")).unwrap();
+        assert_eq!(since.created_after, Some(parse_rfc3339("2024-05-30T10:00:00Z").unwrap()));
+        assert!(parse_search_query(&HttpRequest::new("GET", "/messages?createdBefore=yesterday")).is_err());
+
+        assert_eq!(parse_search_query(&HttpRequest::new("GET", "/messages")).unwrap().limit, Some(DEFAULT_SEARCH_LIMIT));
+        assert!(parse_search_query(&HttpRequest::new("GET", "/messages?order=1")).is_err());
+        assert!(parse_search_query(&HttpRequest::new("GET", "/messages?status=lost")).is_err());
+    }
+
+    #[test]
+    fn test_if_pages_start_at_their_cursor() {
+        let created_at = parse_rfc3339("2024-01-01T00:00:00.000000001Z").unwrap();
+        let cursor = PageCursor::Message(created_at, String::from("order.1+2"));
+        let query = parse_search_query(&HttpRequest::new("GET", &format!("/messages?cursor={}&limit=2", cursor.encode()))).unwrap();
+        assert_eq!(query.after, Some((created_at, String::from("order.1+2"))));
+        assert_eq!(PageCursor::decode(&PageCursor::Attempt(300).encode()), Ok(PageCursor::Attempt(300)));
+
+        let attempts = format!("/messages/a/attempts?limit=5000&cursor={}", PageCursor::Attempt(10).encode());
+        assert_eq!(parse_attempts_page(&HttpRequest::new("GET", &attempts)), Ok((10, MAX_ATTEMPTS_LIMIT)));
+        assert_eq!(parse_attempts_page(&HttpRequest::new("GET", "/messages/a/attempts")), Ok((0, DEFAULT_ATTEMPTS_LIMIT)));
+        assert!(parse_attempts_page(&HttpRequest::new("GET", &format!("/messages/a/attempts?cursor={}", cursor.encode()))).is_err());
+        assert!(parse_search_query(&HttpRequest::new("GET", "/messages?cursor=bm9wZQ")).is_err());
+        assert!(parse_search_query(&HttpRequest::new("GET", "/messages?cursor=%%%")).is_err());
+    }
+
+    #[test]
+    fn test_if_responses_are_shaped_by_the_selected_fields() {
+        let message = JsonValue::object().with("id", "a").with("status", "pending").with("retryPolicy", JsonValue::object().with("maxAttempts", 3u64).with("interval", "1m"));
+        let select = |target: &str| FieldSelection::parse(&HttpRequest::new("GET", target), Some(&COMPACT_MESSAGE_FIELDS)).map(|selection| selection.apply(message.clone()));
+        assert_eq!(select("/messages/a?fields=status,retryPolicy.maxAttempts,unknown"),
+            Ok(JsonValue::object().with("status", "pending").with("retryPolicy", JsonValue::object().with("maxAttempts", 3u64))));
+        assert_eq!(select("/messages/a?fields=retryPolicy.interval,retryPolicy,id.nested"),
+            Ok(JsonValue::object().with("retryPolicy", message.get("retryPolicy").unwrap().clone())));
+        assert_eq!(select("/messages/a?view=compact"), Ok(JsonValue::object().with("id", "a").with("status", "pending")));
+        assert_eq!(select("/messages/a?view=full"), Ok(message.clone()));
+        assert!(select("/messages/a?view=tiny").is_err());
+        assert!(select("/messages/a?fields=,").is_err());
+        assert!(select("/messages/a?fields=id&view=compact").is_err());
+        assert!(FieldSelection::parse(&HttpRequest::new("GET", "/destinations?view=compact"), None).is_err());
+        assert!(parse_search_query(&HttpRequest::new("GET", "/messages?status=dead&view=compact")).is_ok());
+    }
+
+    #[test]
+    fn test_if_destination_body_is_parsed() {
+        let body = JsonValue::parse(r#"{
+            "url": "http://localhost/legacy",
+            "method": "PUT",
+            "contentType": "application/x-www-form-urlencoded",
+            "headers": {"Authorization": "Basic dXNlcjpwYXNz", "X-Tenant": "acme"}
+        }"#).unwrap();
+        let destination = parse_destination("r", &body).unwrap();
+        assert_eq!(destination.method, DeliveryMethod::Put);
+        assert_eq!(destination.content_type, "application/x-www-form-urlencoded");
+        assert_eq!(destination.headers.get("X-Tenant").map(String::as_str), Some("acme"));
+
+        let same_host = parse_destination("r", &JsonValue::parse(r#"{"url": "http://localhost/", "redirectPolicy": {"mode": "sameHost"}}"#).unwrap()).unwrap();
+        assert_eq!(same_host.redirect_policy, RedirectPolicy::SameHost(DEFAULT_MAX_REDIRECTS));
+        let hedged = parse_destination("r", &JsonValue::parse(r#"{"url": "http://localhost/", "hedgeAfterMs": 250}"#).unwrap()).unwrap();
+        assert_eq!(hedged.hedge_after, Some(Duration::from_millis(250)));
+        let pinned = parse_destination("r", &JsonValue::parse(r#"{"url": "http://receiver.local/", "pinnedAddress": "::1"}"#).unwrap()).unwrap();
+        assert_eq!(pinned.pinned_address, Some("::1".parse().unwrap()));
+        let retry_on = parse_destination("r", &JsonValue::parse(r#"{"url": "http://localhost/", "retryOn": "5xx,404"}"#).unwrap()).unwrap();
+        assert_eq!(retry_on.retry_on, Some(RetryOn::parse("http5xx,404").unwrap()));
+        let budgeted = parse_destination("r", &JsonValue::parse(r#"{"url": "http://localhost/", "retryBudget": {"ratio": 0.2}}"#).unwrap()).unwrap();
+        assert_eq!(budgeted.retry_budget, Some(RetryBudget::new(0.2)));
+        let async_ack = parse_destination("r", &JsonValue::parse(r#"{"url": "http://localhost/", "asyncAckTimeout": "5m"}"#).unwrap()).unwrap();
+        assert_eq!(async_ack.async_ack_timeout, Some(Duration::from_secs(300)));
+        assert_eq!(destination_to_json(&async_ack).get("asyncAckTimeout").and_then(JsonValue::as_str), Some("5m"));
+        let warm = parse_destination("r", &JsonValue::parse(r#"{"url": "http://localhost/", "warmConnections": 4}"#).unwrap()).unwrap();
+        assert_eq!(warm.warm_connections, Some(4));
+        let limited = parse_destination("r", &JsonValue::parse(r#"{"url": "http://localhost/", "maxConcurrency": 8}"#).unwrap()).unwrap();
+        assert_eq!(limited.max_concurrency, Some(8));
+        assert_eq!(parse_destination("r", &destination_to_json(&limited)).unwrap(), limited);
+        let compressed = parse_destination("r", &JsonValue::parse(r#"{"url": "http://localhost/", "compression": {"encoding": "gzip"}}"#).unwrap()).unwrap();
+        assert_eq!(compressed.compression, Some(BodyCompression::new(DEFAULT_COMPRESSION_MIN_BYTES)));
+        assert_eq!(parse_destination("r", &destination_to_json(&compressed.clone().with_compression(BodyCompression::new(0)))).unwrap().compression, Some(BodyCompression::new(0)));
+        let shadowed = parse_destination("r", &JsonValue::parse(r#"{"url": "http://localhost/", "shadowUrl": "http://staging.local/hooks"}"#).unwrap()).unwrap();
+        assert_eq!(shadowed.shadow_url.as_deref(), Some("http://staging.local/hooks"));
+        assert_eq!(destination_to_json(&shadowed).get("shadowUrl").and_then(JsonValue::as_str), Some("http://staging.local/hooks"));
+        let canary = parse_destination("r", &JsonValue::parse(r#"{"url": "http://localhost/", "canary": {"url": "http://orders-v2.local/", "percent": 5, "keyAttribute": "customerId"}}"#).unwrap()).unwrap();
+        assert_eq!(canary.canary, Some(CanaryRoute::new("http://orders-v2.local/", 5).with_key_attribute("customerId")));
+        assert_eq!(parse_destination("r", &destination_to_json(&canary)).unwrap(), canary);
+
+        let pull = parse_destination("r", &JsonValue::parse(r#"{"mode": "pull"}"#).unwrap()).unwrap();
+        assert_eq!(pull, Destination::pull("r"));
+        assert_eq!(destination_to_json(&pull).get("url"), Some(&JsonValue::Null));
+        let defaults = parse_destination("r", &JsonValue::parse(r#"{"url": "http://localhost/"}"#).unwrap()).unwrap();
+        assert_eq!(defaults, Destination::new("r", "http://localhost/"));
+
+        for invalid in [
+            r#"{"url": "http://localhost/", "method": "DELETE"}"#,
+            r#"{"url": "http://localhost/", "contentType": ""}"#,
+            r#"{"url": "http://localhost/", "headers": {"Content-Length": "1"}}"#,
+            r#"{"url": "http://localhost/", "headers": {"x-angler-attr-region": "eu"}}"#,
+            r#"{"url": "http://localhost/", "headers": {"X-Angler-Attempt": "1"}}"#,
+            r#"{"url": "http://localhost/", "headers": {"Bad Name": "1"}}"#,
+            r#"{"url": "http://localhost/", "headers": {"X-Value": "a\nb"}}"#,
+            r#"{"url": "http://localhost/", "redirectPolicy": {"mode": "always"}}"#,
+            r#"{"url": "http://localhost/", "hedgeAfterMs": 0}"#,
+            r#"{"url": "http://localhost/", "pinnedAddress": "receiver.local"}"#,
+            r#"{"url": "http://localhost/", "retryOn": "teapot"}"#,
+            r#"{"url": "http://localhost/", "retryBudget": {"ratio": -1}}"#,
+            r#"{"url": "http://localhost/", "retryBudget": {"ratio": 0.2, "minPerMinute": 1.5}}"#,
+            r#"{"url": "http://localhost/", "asyncAckTimeout": "0s"}"#,
+            r#"{"url": "http://localhost/", "warmConnections": 33}"#,
+            r#"{"url": "http://localhost/", "maxConcurrency": 0}"#,
+            r#"{"url": "http://localhost/", "compression": {"encoding": "br"}}"#,
+            r#"{"url": "http://localhost/", "compression": {"encoding": "gzip", "minBytes": -1}}"#,
+            r#"{"mode": "pull", "compression": {"encoding": "gzip"}}"#,
+            r#"{"url": "http://localhost/", "headers": {"X-Angler-Ack-Token": "t"}}"#,
+            r#"{"mode": "push"}"#,
+            r#"{"url": "http://localhost/", "shadowUrl": "https://staging.local/"}"#,
+            r#"{"mode": "pull", "shadowUrl": "http://staging.local/"}"#,
+            r#"{"url": "http://localhost/", "canary": {"url": "http://orders-v2.local/", "percent": 100}}"#,
+            r#"{"url": "http://localhost/", "canary": {"percent": 5}}"#,
+            r#"{"url": "http://localhost/", "canary": {"url": "http://orders-v2.local/", "percent": 5, "keyAttribute": "customer id"}}"#,
+            r#"{"url": "http://localhost/", "mode": "poll"}"#,
+            r#"{"url": "http://localhost/", "redirectPolicy": {"mode": "limited", "maxRedirects": 11}}"#,
+        ] {
+            assert!(parse_destination("r", &JsonValue::parse(invalid).unwrap()).is_err(), "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn test_if_report_query_is_parsed() {
+        let request = HttpRequest::new("GET", "/reports/deliveries?from=2024-01-01T00:00:00Z&to=2024-02-01T00:00:00Z&format=ndjson&recipientId=r1");
+        let query = parse_report_query(&request).unwrap();
+        assert_eq!(query.format, ReportFormat::Ndjson);
+        assert_eq!(query.recipient_id.as_deref(), Some("r1"));
+        assert_eq!(query.to, parse_rfc3339("2024-02-01T00:00:00Z").unwrap());
+
+        let csv = HttpRequest::new("GET", "/reports/deliveries?from=2024-01-01T00:00:00Z&to=2024-02-01T00:00:00Z");
+        assert_eq!(parse_report_query(&csv).unwrap().format, ReportFormat::Csv);
+        assert!(parse_report_query(&HttpRequest::new("GET", "/reports/deliveries?from=2024-01-01T00:00:00Z")).is_err());
+        assert!(parse_report_query(&HttpRequest::new("GET", "/reports/deliveries?from=2024-02-01T00:00:00Z&to=2024-01-01T00:00:00Z")).is_err());
+        assert!(parse_report_query(&HttpRequest::new("GET", "/reports/deliveries?from=2024-01-01T00:00:00Z&to=2024-02-01T00:00:00Z&format=xml")).is_err());
+    }
+
+    #[test]
+    fn test_if_pull_query_is_parsed() {
+        let query = parse_pull_query(&HttpRequest::new("GET", "/topics/order.created/pull?max=100&wait=30s&visibility=2m&recipientId=r1")).unwrap();
+        assert_eq!(query, PullQuery { recipient_id: Some(String::from("r1")), max: 100, wait: Duration::from_secs(30), visibility: Duration::from_secs(120) });
+        let defaults = parse_pull_query(&HttpRequest::new("GET", "/topics/order.created/pull")).unwrap();
+        assert_eq!(defaults, PullQuery { recipient_id: None, max: DEFAULT_PULL_MESSAGES, wait: Duration::ZERO, visibility: DEFAULT_VISIBILITY_TIMEOUT });
+
+        for invalid in ["max=0", "max=101", "wait=31s", "wait=soon", "visibility=0s", "order=1"] {
+            assert!(parse_pull_query(&HttpRequest::new("GET", &format!("/topics/t/pull?{}", invalid))).is_err(), "{}", invalid);
+        }
+        assert_eq!(parse_receipts(&JsonValue::parse(r#"{"receipts": ["a", "b"]}"#).unwrap()).unwrap(), vec!["a", "b"]);
+        assert!(parse_receipts(&JsonValue::parse(r#"{"receipts": [1]}"#).unwrap()).is_err());
+    }
+
+    #[test]
+    fn test_if_transform_test_body_is_parsed() {
+        let body = JsonValue::parse(r#"{"eventId": "order.created", "data": {"order": 1}, "attributes": {"region": "eu"}}"#).unwrap();
+        let message = parse_transform_test("r", &body).unwrap();
+        assert_eq!((message.recipient_id.as_str(), message.service_id.as_str(), message.event_id.as_str()), ("r", "sample", "order.created"));
+        assert_eq!(message.payload, b"{\"order\":1}");
+        assert_eq!(message.attributes.get("region").map(String::as_str), Some("eu"));
+        assert!(parse_transform_test("r", &JsonValue::parse(r#"{"attributes": {"bad key": "1"}}"#).unwrap()).is_err());
+        assert!(parse_transform_test("r", &JsonValue::parse(r#"{"eventId": ""}"#).unwrap()).is_err());
+    }
+
+    #[test]
+    fn test_if_backfill_is_parsed() {
+        let now = parse_rfc3339("2024-01-02T00:00:00Z").unwrap();
+        let body = JsonValue::parse(r#"{"url": "http://localhost/", "backfill": {"eventId": "order.created", "window": "24h", "limit": 50}}"#).unwrap();
+        let (backfill, rate) = parse_backfill(&body, now).unwrap().unwrap();
+        assert_eq!(backfill, Backfill { namespace: None, topic: String::from("order.created"), since: parse_rfc3339("2024-01-01T00:00:00Z").unwrap(), limit: Some(50) });
+        assert_eq!(rate, DEFAULT_REPLAY_RATE);
+        assert_eq!(parse_backfill(&JsonValue::parse(r#"{"url": "http://localhost/"}"#).unwrap(), now).unwrap(), None);
+
+        for invalid in [
+            r#"{"backfill": {"window": "24h"}}"#,
+            r#"{"backfill": {"eventId": "order.created"}}"#,
+            r#"{"backfill": {"eventId": "order.created", "window": "0s"}}"#,
+            r#"{"backfill": {"eventId": "order.created", "window": "24h", "ratePerSecond": 0}}"#,
+        ] {
+            assert!(parse_backfill(&JsonValue::parse(invalid).unwrap(), now).is_err(), "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn test_if_replay_body_is_parsed() {
+        let body = JsonValue::parse(r#"{"recipientId": "r", "eventId": "e", "createdAfter": "2024-05-30T10:00:00Z", "errorClass": "http5xx", "ratePerSecond": 50}"#).unwrap();
+        let (filter, rate) = parse_replay_body(&body).unwrap();
+        assert_eq!(filter.query.recipient_id.as_deref(), Some("r"));
+        assert_eq!(filter.query.topic.as_deref(), Some("e"));
+        assert_eq!(filter.query.created_after, Some(parse_rfc3339("2024-05-30T10:00:00Z").unwrap()));
+        assert_eq!(filter.error_class, Some(DeliveryErrorClass::Http5xx));
+        assert_eq!(rate, 50.0);
+
+        assert_eq!(parse_replay_body(&JsonValue::object()).unwrap().1, DEFAULT_REPLAY_RATE);
+        assert!(parse_replay_body(&JsonValue::parse(r#"{"ratePerSecond": 0}"#).unwrap()).is_err());
+        assert!(parse_replay_body(&JsonValue::parse(r#"{"createdBefore": "yesterday"}"#).unwrap()).is_err());
+    }
+
+    #[test]
+    fn test_if_invalid_publish_body_is_rejected() {
+        assert!(parse_publish_body(&json_request("not json")).is_err());
+        assert!(parse_publish_body(&json_request(r#"{"sendMessage": {"recipientId": "r"}}"#)).unwrap_err().contains("serviceId"));
+        assert!(parse_publish_body(&json_request(r#"{"sendMessage": {"recipientId": "r", "serviceId": "s", "eventId": "e", "type": "sms"}}"#)).is_err());
+    }
+}
@@ -0,0 +1,376 @@
+use std::{collections::BTreeSet, fmt::Display, fs, io::Read, time::Duration};
+
+use clap::{Arg, ArgAction, ArgMatches, Command};
+
+use crate::{
+    net::http::{send_request, HttpRequest, HttpResponse, HttpUrl},
+    utils::{json::JsonValue, yaml::{parse_yaml, to_yaml}},
+};
+
+use super::restful::{destination_diff, destination_to_json, parse_destination, ACTOR_HEADER, DEFAULT_RESTFUL_PORT};
+
+/// The version of the routing documents written by `export routing`
+pub const ROUTING_DOCUMENT_VERSION: u64 = 1;
+
+/// How long each request to the client API can take
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(30);
+
+fn url_arg() -> Arg {
+    Arg::new("url").long("url")
+        .help("The URL of the client RESTful API of the Angler instance. Defaults to the local one, at http://127.0.0.1:2460")
+}
+
+fn url(args: &ArgMatches) -> Result<HttpUrl, String> {
+    let url = args.get_one::<String>("url").cloned().unwrap_or_else(|| format!("http://127.0.0.1:{}", DEFAULT_RESTFUL_PORT));
+    HttpUrl::parse(&url).map_err(|err| format!("--url {}: {}", url, err))
+}
+
+/// The `export` subcommand, that prints the configuration of a running instance
+pub fn export_command() -> Command {
+    Command::new("export")
+        .about("Print the configuration of a running Angler instance")
+        .subcommand_required(true)
+        .subcommand(Command::new("routing")
+            .about("Print the destinations as a YAML routing document, that can be kept in a repository and imported into another instance")
+            .arg(url_arg()))
+}
+
+/// The `import` subcommand, that applies a configuration to a running instance
+pub fn import_command() -> Command {
+    Command::new("import")
+        .about("Apply a configuration to a running Angler instance")
+        .subcommand_required(true)
+        .subcommand(Command::new("routing")
+            .about("Reconcile the destinations with a routing document written by `export routing`: create the missing ones and update the drifted ones")
+            .arg(Arg::new("file").required(true).help("The routing document, or - to read it from the standard input"))
+            .arg(url_arg())
+            .arg(Arg::new("prune").long("prune").action(ArgAction::SetTrue)
+                .help("Remove the destinations that are not in the document"))
+            .arg(Arg::new("dry-run").long("dry-run").action(ArgAction::SetTrue)
+                .help("Print the changes the import would make as a diff, without making them"))
+            .arg(Arg::new("actor").long("actor")
+                .help("Who is importing the document, recorded in the history of the changed destinations")))
+}
+
+/// What an import changed
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct RoutingImport {
+    pub created: Vec<String>,
+    pub updated: Vec<String>,
+    pub removed: Vec<String>,
+    pub unchanged: usize,
+}
+
+impl Display for RoutingImport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Created {}, updated {} and removed {} destinations, {} were unchanged", self.created.len(), self.updated.len(), self.removed.len(), self.unchanged)
+    }
+}
+
+/// Return the fields of a destination that are configured, without its version and the fields
+/// that are not set, as written in the routing documents
+fn routing_fields(destination: &JsonValue) -> JsonValue {
+    let fields = destination.as_object().into_iter().flatten()
+        .filter(|(key, value)| key.as_str() != "version" && !value.is_null() && value.as_object().is_none_or(|object| !object.is_empty()))
+        .map(|(key, value)| (key.clone(), value.clone()))
+        .collect();
+    JsonValue::Object(fields)
+}
+
+/// Create the routing document of the destinations, as returned by `GET /destinations`
+pub fn routing_document(destinations: &[JsonValue]) -> JsonValue {
+    let mut destinations: Vec<JsonValue> = destinations.iter().map(routing_fields).collect();
+    destinations.sort_by(|a, b| a.get("id").and_then(JsonValue::as_str).cmp(&b.get("id").and_then(JsonValue::as_str)));
+    JsonValue::object()
+        .with("version", ROUTING_DOCUMENT_VERSION)
+        .with("destinations", destinations)
+}
+
+/// Read the destinations of a routing document, validated like `PUT /destinations/{id}` does and
+/// in the form the client API returns them, so they can be compared with the registered ones
+pub fn parse_routing_document(document: &JsonValue) -> Result<Vec<JsonValue>, String> {
+    match document.get("version").map(JsonValue::as_u64) {
+        Some(Some(ROUTING_DOCUMENT_VERSION)) => {}
+        Some(_) => return Err(format!("version should be {}", ROUTING_DOCUMENT_VERSION)),
+        None => return Err(String::from("version is required")),
+    }
+    let entries = match document.get("destinations") {
+        None | Some(JsonValue::Null) => return Ok(Vec::new()),
+        Some(destinations) => destinations.as_array().ok_or("destinations should be a list")?,
+    };
+
+    let mut ids = BTreeSet::new();
+    let mut destinations = Vec::with_capacity(entries.len());
+    for (index, entry) in entries.iter().enumerate() {
+        let id = entry.get("id").and_then(JsonValue::as_str)
+            .filter(|id| !id.is_empty() && !id.contains(['/', '?', '#']) && !id.chars().any(|c| c.is_whitespace() || c.is_control()))
+            .ok_or_else(|| format!("destinations[{}].id should be a string without spaces, '/', '?' and '#'", index))?;
+        if !ids.insert(id) {
+            return Err(format!("destinations[{}]: the destination {} is repeated", index, id));
+        }
+        if entry.get("backfill").is_some() {
+            return Err(format!("destinations[{}]: backfill can not be imported, it would copy the messages again on every import", index));
+        }
+        let destination = parse_destination(id, entry).map_err(|err| format!("destinations[{}] ({}): {}", index, id, err))?;
+        destinations.push(routing_fields(&destination_to_json(&destination)));
+    }
+    Ok(destinations)
+}
+
+fn send(url: &HttpUrl, method: &str, path: &str, actor: Option<&str>, body: Option<&JsonValue>) -> Result<HttpResponse, String> {
+    let base = url.target.split('?').next().unwrap_or_default().trim_end_matches('/');
+    let url = url.join(&format!("{}{}", base, path)).map_err(|err| err.to_string())?;
+    let mut request = HttpRequest::new(method, &url.target);
+    if let Some(actor) = actor {
+        request.headers.set(ACTOR_HEADER, actor);
+    }
+    if let Some(body) = body {
+        request.headers.set("Content-Type", "application/json");
+        request.body = body.to_string().into_bytes();
+    }
+    let response = send_request(&url, request, REQUEST_TIMEOUT).map_err(|err| format!("{} {} failed: {}", method, path, err))?;
+    if !response.is_success() {
+        let error = JsonValue::parse_bytes(&response.body).ok()
+            .and_then(|body| body.get("error").and_then(JsonValue::as_str).map(str::to_string))
+            .unwrap_or_else(|| String::from_utf8_lossy(&response.body).into_owned());
+        return Err(format!("{} {} answered {}: {}", method, path, response.status, error));
+    }
+    Ok(response)
+}
+
+fn registered_destinations(url: &HttpUrl) -> Result<Vec<JsonValue>, String> {
+    let response = send(url, "GET", "/destinations", None, None)?;
+    let destinations = JsonValue::parse_bytes(&response.body).map_err(|err| format!("GET /destinations answered invalid JSON: {}", err))?;
+    destinations.as_array().cloned().ok_or_else(|| String::from("GET /destinations should answer a list"))
+}
+
+/// Return the routing document of the instance with the client API at the URL, as YAML
+pub fn export_routing(url: &HttpUrl) -> Result<String, String> {
+    let destinations = registered_destinations(url)?;
+    Ok(format!("# Angler routing document, apply it with `angler import routing <file>`\n{}", to_yaml(&routing_document(&destinations))))
+}
+
+/// What the import does to a destination
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RoutingAction {
+    Create,
+    Update,
+    /// Remove a destination that is not in the document, with `--prune`
+    Delete,
+}
+
+impl RoutingAction {
+    /// Return the mark of the action in the diff, like the ones of a unified diff
+    pub fn symbol(&self) -> char {
+        match self {
+            RoutingAction::Create => '+',
+            RoutingAction::Update => '~',
+            RoutingAction::Delete => '-',
+        }
+    }
+}
+
+/// A change the import makes to reconcile the registered destinations with the document
+#[derive(Debug, Clone, PartialEq)]
+pub struct RoutingChange {
+    pub action: RoutingAction,
+    pub id: String,
+    /// The destination as in the document. None when it is deleted
+    pub destination: Option<JsonValue>,
+    /// The fields that change, with their values `from` and `to`
+    pub diff: JsonValue,
+}
+
+/// The changes that make the registered destinations match a routing document
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct RoutingPlan {
+    pub changes: Vec<RoutingChange>,
+    /// How many destinations are already registered as in the document
+    pub unchanged: usize,
+    /// The registered destinations that are not in the document, kept because the import does
+    /// not prune
+    pub unmanaged: Vec<String>,
+}
+
+impl RoutingPlan {
+    pub fn is_empty(&self) -> bool {
+        self.changes.is_empty()
+    }
+
+    fn count(&self, action: RoutingAction) -> usize {
+        self.changes.iter().filter(|change| change.action == action).count()
+    }
+
+    /// Return the changes as a diff, a line for each destination followed by the fields it changes
+    pub fn diff(&self) -> String {
+        let mut diff = String::new();
+        for change in &self.changes {
+            diff.push_str(&format!("{} {}\n", change.action.symbol(), change.id));
+            let fields = change.diff.as_object().into_iter().flatten().filter(|(field, _)| field.as_str() != "id");
+            for (field, values) in fields {
+                let (from, to) = (values.get("from").unwrap_or(&JsonValue::Null), values.get("to").unwrap_or(&JsonValue::Null));
+                match change.action {
+                    RoutingAction::Create => diff.push_str(&format!("    {}: {}\n", field, to)),
+                    RoutingAction::Update => diff.push_str(&format!("    {}: {} -> {}\n", field, from, to)),
+                    RoutingAction::Delete => {}
+                }
+            }
+        }
+        for id in &self.unmanaged {
+            diff.push_str(&format!("  {} is not in the document and is kept, use --prune to remove it\n", id));
+        }
+        diff
+    }
+}
+
+impl Display for RoutingPlan {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.diff())?;
+        write!(f, "Would create {}, update {} and remove {} destinations, and keep {} unchanged",
+            self.count(RoutingAction::Create), self.count(RoutingAction::Update), self.count(RoutingAction::Delete), self.unchanged)
+    }
+}
+
+/// Return the changes that make the registered destinations, as returned by `GET /destinations`,
+/// match the destinations read by `parse_routing_document`. The drifted destinations are updated
+/// and, with `prune`, the ones that are not in the document are deleted
+pub fn plan_routing(destinations: &[JsonValue], registered: &[JsonValue], prune: bool) -> RoutingPlan {
+    let id_of = |destination: &JsonValue| destination.get("id").and_then(JsonValue::as_str).unwrap_or_default().to_string();
+    let mut plan = RoutingPlan::default();
+    for destination in destinations {
+        let id = id_of(destination);
+        let current = registered.iter().find(|registered| id_of(registered) == id).map(routing_fields);
+        let action = match &current {
+            Some(current) if current == destination => {
+                plan.unchanged += 1;
+                continue;
+            }
+            Some(_) => RoutingAction::Update,
+            None => RoutingAction::Create,
+        };
+        let diff = destination_diff(current.as_ref().unwrap_or(&JsonValue::Null), destination);
+        plan.changes.push(RoutingChange { action, id, destination: Some(destination.clone()), diff });
+    }
+
+    let mut others: Vec<&JsonValue> = registered.iter().filter(|registered| destinations.iter().all(|destination| id_of(destination) != id_of(registered))).collect();
+    others.sort_by_key(|registered| id_of(registered));
+    for registered in others {
+        if prune {
+            let diff = destination_diff(&routing_fields(registered), &JsonValue::Null);
+            plan.changes.push(RoutingChange { action: RoutingAction::Delete, id: id_of(registered), destination: None, diff });
+        } else {
+            plan.unmanaged.push(id_of(registered));
+        }
+    }
+    plan
+}
+
+/// Read the document, in YAML or JSON, and return the changes that make the destinations of the
+/// instance with the client API at the URL match it. The whole document is validated, so a plan
+/// is only returned for a document that can be applied
+pub fn reconcile_routing(url: &HttpUrl, document: &str, prune: bool) -> Result<RoutingPlan, String> {
+    let document = parse_yaml(document).map_err(|err| err.to_string())?;
+    let destinations = parse_routing_document(&document)?;
+    Ok(plan_routing(&destinations, &registered_destinations(url)?, prune))
+}
+
+/// Apply the changes of the plan, in its order, stopping at the first one that fails
+pub fn apply_routing(url: &HttpUrl, plan: &RoutingPlan, actor: Option<&str>) -> Result<RoutingImport, String> {
+    let mut import = RoutingImport { unchanged: plan.unchanged, ..RoutingImport::default() };
+    for change in &plan.changes {
+        let path = format!("/destinations/{}", change.id);
+        let result = match change.action {
+            RoutingAction::Create | RoutingAction::Update => send(url, "PUT", &path, actor, change.destination.as_ref()),
+            RoutingAction::Delete => send(url, "DELETE", &path, actor, None),
+        };
+        result.map_err(|err| format!("{} ({} changed before it)", err, import))?;
+        match change.action {
+            RoutingAction::Create => import.created.push(change.id.clone()),
+            RoutingAction::Update => import.updated.push(change.id.clone()),
+            RoutingAction::Delete => import.removed.push(change.id.clone()),
+        }
+    }
+    Ok(import)
+}
+
+/// Register the destinations of the document into the instance with the client API at the URL.
+/// The whole document is validated before any destination is changed, and the destinations that
+/// are already registered as in the document are not changed, so importing the same document
+/// again does nothing. With `prune` the other destinations are removed
+pub fn import_routing(url: &HttpUrl, document: &str, prune: bool, actor: Option<&str>) -> Result<RoutingImport, String> {
+    apply_routing(url, &reconcile_routing(url, document, prune)?, actor)
+}
+
+/// Run `export routing`, returning the document to print
+pub fn run_export(args: &ArgMatches) -> Result<String, String> {
+    export_routing(&url(args)?)
+}
+
+/// Run `import routing`, returning the diff of the changes and what was changed. With
+/// `--dry-run` nothing is changed
+pub fn run_import(args: &ArgMatches) -> Result<String, String> {
+    let url = url(args)?;
+    let file = args.get_one::<String>("file").unwrap();
+    let document = if file == "-" {
+        let mut document = String::new();
+        std::io::stdin().read_to_string(&mut document).map_err(|err| format!("failed to read the standard input: {}", err))?;
+        document
+    } else {
+        fs::read_to_string(file).map_err(|err| format!("failed to read {}: {}", file, err))?
+    };
+    let plan = reconcile_routing(&url, &document, args.get_flag("prune"))?;
+    if args.get_flag("dry-run") {
+        return Ok(plan.to_string());
+    }
+    let import = apply_routing(&url, &plan, args.get_one::<String>("actor").map(String::as_str))?;
+    Ok(format!("{}{}", plan.diff(), import))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_if_routing_documents_are_validated() {
+        let exported = routing_document(&[
+            JsonValue::parse(r#"{"id": "r2", "url": "http://b/hooks", "mode": "push", "headers": {}, "method": "POST", "version": 4, "retryOn": null}"#).unwrap(),
+            JsonValue::parse(r#"{"id": "r1", "mode": "pull", "version": 1}"#).unwrap(),
+        ]);
+        assert_eq!(exported.pointer("destinations").and_then(JsonValue::as_array).unwrap()[0].get("id").and_then(JsonValue::as_str), Some("r1"));
+        assert_eq!(exported.to_string(), r#"{"destinations":[{"id":"r1","mode":"pull"},{"id":"r2","method":"POST","mode":"push","url":"http://b/hooks"}],"version":1}"#);
+
+        // the defaults are filled, so a document that omits them matches the registered destinations
+        let destinations = parse_routing_document(&parse_yaml(&to_yaml(&exported)).unwrap()).unwrap();
+        assert_eq!(destinations[1].get("contentType").and_then(JsonValue::as_str), Some("application/json"));
+        assert_eq!(destinations[0].get("version"), None);
+
+        let invalid = |document: &str| parse_routing_document(&parse_yaml(document).unwrap()).unwrap_err();
+        assert_eq!(invalid("destinations: []"), "version is required");
+        assert!(invalid("version: 1\ndestinations:\n- id: a/b\n  url: http://a\n").contains("destinations[0].id"));
+        assert!(invalid("version: 1\ndestinations:\n- id: a\n  mode: pull\n- id: a\n  mode: pull\n").contains("repeated"));
+        assert!(invalid("version: 1\ndestinations:\n- id: a\n  url: ftp://a\n").starts_with("destinations[0] (a)"));
+        assert!(invalid("version: 1\ndestinations:\n- id: a\n  mode: pull\n  backfill: {eventId: e, window: 1h}\n").contains("backfill"));
+    }
+
+    #[test]
+    fn test_if_the_plan_reconciles_the_registered_destinations_with_the_document() {
+        let document = parse_yaml("version: 1\ndestinations:\n- id: a\n  mode: pull\n- id: b\n  url: http://b/v2\n- id: c\n  mode: sse\n").unwrap();
+        let destinations = parse_routing_document(&document).unwrap();
+        let registered = |id: &str, body: &str| destination_to_json(&parse_destination(id, &JsonValue::parse(body).unwrap()).unwrap()).with("version", 3u8);
+        let registered = [registered("b", r#"{"url": "http://b/v1"}"#), registered("c", r#"{"mode": "sse"}"#), registered("manual", r#"{"url": "http://m"}"#)];
+
+        let plan = plan_routing(&destinations, &registered, false);
+        assert_eq!(plan.changes.iter().map(|change| (change.action, change.id.as_str())).collect::<Vec<_>>(), vec![(RoutingAction::Create, "a"), (RoutingAction::Update, "b")]);
+        assert_eq!((plan.unchanged, plan.unmanaged.clone()), (1, vec![String::from("manual")]));
+        assert_eq!(plan.to_string(), "+ a\n    contentType: \"application/json\"\n    method: \"POST\"\n    mode: \"pull\"\n    redirectPolicy: {\"mode\":\"none\"}\n\
+            ~ b\n    url: \"http://b/v1\" -> \"http://b/v2\"\n\
+            \x20 manual is not in the document and is kept, use --prune to remove it\n\
+            Would create 1, update 1 and remove 0 destinations, and keep 1 unchanged");
+
+        let pruned = plan_routing(&destinations, &registered, true);
+        assert_eq!(pruned.changes.last().map(|change| (change.action, change.id.as_str(), change.destination.is_none())), Some((RoutingAction::Delete, "manual", true)));
+        assert!(pruned.unmanaged.is_empty());
+        assert!(plan_routing(&destinations[..1], &registered[..0], false).changes.len() == 1);
+        assert!(plan_routing(&[], &[], true).is_empty());
+    }
+}
@@ -0,0 +1,163 @@
+use std::{
+    collections::HashMap,
+    io,
+    net::{IpAddr, SocketAddr, ToSocketAddrs},
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use crate::ctx::config::MessagesProcessorConfigurations;
+
+/// The default value of `msgproc.dns.ttl`
+pub const DEFAULT_DNS_TTL: Duration = Duration::from_secs(30);
+
+/// The default value of `msgproc.dns.negativeTtl`
+pub const DEFAULT_DNS_NEGATIVE_TTL: Duration = Duration::from_secs(5);
+
+/// The function that resolves a host and a port into addresses
+pub type DnsLookup = dyn Fn(&str, u16) -> io::Result<Vec<SocketAddr>> + Send + Sync;
+
+#[derive(Debug, Clone)]
+enum CacheEntry {
+    Resolved { addresses: Vec<SocketAddr>, expires_at: Instant },
+    Failed { error: String, expires_at: Instant },
+}
+
+/// A cache in front of the system resolver used by the deliveries. Resolved addresses are kept for
+/// the TTL and failures for the negative TTL. When a host that was resolved before fails to
+/// resolve its previous addresses are still used, so a DNS flap does not fail the attempts
+pub struct DnsCache {
+    ttl: Duration,
+    negative_ttl: Duration,
+    lookup: Box<DnsLookup>,
+    entries: Mutex<HashMap<(String, u16), CacheEntry>>,
+}
+
+impl DnsCache {
+    /// Create a cache using the system resolver. A zero TTL disables the cache
+    pub fn new(ttl: Duration, negative_ttl: Duration) -> DnsCache {
+        let lookup = |host: &str, port: u16| (host, port).to_socket_addrs().map(Iterator::collect);
+        DnsCache { ttl, negative_ttl, lookup: Box::new(lookup), entries: Mutex::new(HashMap::new()) }
+    }
+
+    /// Create a cache using the `msgproc.dns.` configuration
+    pub fn from_configuration(conf: &MessagesProcessorConfigurations) -> DnsCache {
+        DnsCache::new(
+            conf.dns_ttl.map(|ttl| ttl.unsigned_abs()).unwrap_or(DEFAULT_DNS_TTL),
+            conf.dns_negative_ttl.map(|ttl| ttl.unsigned_abs()).unwrap_or(DEFAULT_DNS_NEGATIVE_TTL),
+        )
+    }
+
+    /// Resolve the hosts with the given function instead of the system resolver
+    pub fn with_lookup(mut self, lookup: Box<DnsLookup>) -> DnsCache {
+        self.lookup = lookup;
+        self
+    }
+
+    /// Return the addresses of the host. IP addresses are returned without a lookup
+    pub fn resolve(&self, host: &str, port: u16) -> io::Result<Vec<SocketAddr>> {
+        if let Ok(ip) = host.parse::<IpAddr>() {
+            return Ok(vec![SocketAddr::new(ip, port)]);
+        }
+        if self.ttl.is_zero() {
+            return self.lookup(host, port);
+        }
+
+        let key = (host.to_string(), port);
+        let now = Instant::now();
+        let stale = match self.entries.lock().unwrap().get(&key) {
+            Some(CacheEntry::Resolved { addresses, expires_at }) if *expires_at > now => return Ok(addresses.clone()),
+            Some(CacheEntry::Failed { error, expires_at }) if *expires_at > now => return Err(io::Error::other(error.clone())),
+            Some(CacheEntry::Resolved { addresses, .. }) => Some(addresses.clone()),
+            _ => None,
+        };
+
+        // the lock is not held during the lookup so a slow host does not block the others
+        let result = self.lookup(host, port);
+        let mut entries = self.entries.lock().unwrap();
+        match (result, stale) {
+            (Ok(addresses), _) => {
+                entries.insert(key, CacheEntry::Resolved { addresses: addresses.clone(), expires_at: now + self.ttl });
+                Ok(addresses)
+            }
+            (Err(_), Some(addresses)) => {
+                entries.insert(key, CacheEntry::Resolved { addresses: addresses.clone(), expires_at: now + self.negative_ttl });
+                Ok(addresses)
+            }
+            (Err(err), None) => {
+                entries.insert(key, CacheEntry::Failed { error: err.to_string(), expires_at: now + self.negative_ttl });
+                Err(err)
+            }
+        }
+    }
+
+    fn lookup(&self, host: &str, port: u16) -> io::Result<Vec<SocketAddr>> {
+        let addresses = (self.lookup)(host, port)?;
+        if addresses.is_empty() {
+            return Err(io::Error::other(format!("{} does not resolve to any address", host)));
+        }
+        Ok(addresses)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        sync::{atomic::{AtomicBool, AtomicUsize, Ordering}, Arc},
+        thread,
+    };
+
+    use super::*;
+
+    fn cache(ttl: Duration, lookups: Arc<AtomicUsize>, failing: Arc<AtomicBool>) -> DnsCache {
+        DnsCache::new(ttl, Duration::from_millis(50)).with_lookup(Box::new(move |_, port| {
+            lookups.fetch_add(1, Ordering::SeqCst);
+            match failing.load(Ordering::SeqCst) {
+                true => Err(io::Error::other("temporary failure in name resolution")),
+                false => Ok(vec![SocketAddr::new(IpAddr::from([10, 0, 0, 1]), port)]),
+            }
+        }))
+    }
+
+    #[test]
+    fn test_if_addresses_are_cached_and_kept_when_the_host_stops_resolving() {
+        let (lookups, failing) = (Arc::new(AtomicUsize::new(0)), Arc::new(AtomicBool::new(false)));
+        let cache = cache(Duration::from_millis(50), lookups.clone(), failing.clone());
+
+        let addresses = cache.resolve("receiver.local", 80).unwrap();
+        assert_eq!(cache.resolve("receiver.local", 80).unwrap(), addresses);
+        assert_eq!(lookups.load(Ordering::SeqCst), 1);
+
+        thread::sleep(Duration::from_millis(60));
+        failing.store(true, Ordering::SeqCst);
+        assert_eq!(cache.resolve("receiver.local", 80).unwrap(), addresses);
+        assert_eq!(lookups.load(Ordering::SeqCst), 2);
+
+        assert!(cache.resolve("10.1.2.3", 80).is_ok());
+        assert_eq!(lookups.load(Ordering::SeqCst), 2);
+    }
+
+    #[test]
+    fn test_if_failures_are_cached_for_the_negative_ttl() {
+        let (lookups, failing) = (Arc::new(AtomicUsize::new(0)), Arc::new(AtomicBool::new(true)));
+        let cache = cache(Duration::from_secs(30), lookups.clone(), failing.clone());
+
+        assert!(cache.resolve("receiver.local", 80).is_err());
+        assert!(cache.resolve("receiver.local", 80).is_err());
+        assert_eq!(lookups.load(Ordering::SeqCst), 1);
+
+        failing.store(false, Ordering::SeqCst);
+        thread::sleep(Duration::from_millis(60));
+        assert!(cache.resolve("receiver.local", 80).is_ok());
+        assert_eq!(lookups.load(Ordering::SeqCst), 2);
+    }
+
+    #[test]
+    fn test_if_zero_ttl_disables_the_cache() {
+        let lookups = Arc::new(AtomicUsize::new(0));
+        let cache = cache(Duration::ZERO, lookups.clone(), Arc::new(AtomicBool::new(false)));
+        cache.resolve("receiver.local", 80).unwrap();
+        cache.resolve("receiver.local", 80).unwrap();
+        assert_eq!(lookups.load(Ordering::SeqCst), 2);
+    }
+}
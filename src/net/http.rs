@@ -0,0 +1,977 @@
+use std::{
+    io::{self, BufRead, BufReader, Read, Write},
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, TcpListener, TcpStream, ToSocketAddrs},
+    collections::HashMap,
+    sync::{atomic::{AtomicBool, Ordering}, mpsc::{self, SyncSender}, Arc, Condvar, Mutex},
+    thread::{self, JoinHandle},
+    time::Duration,
+};
+
+use thiserror::Error;
+
+use crate::{log, utils::log::Level};
+
+/// The maximum size of the request line plus all headers of a HTTP message
+const MAX_HEAD_SIZE: usize = 64 * 1024;
+
+/// The maximum size of the body of a HTTP message
+pub const MAX_BODY_SIZE: usize = 16 * 1024 * 1024;
+
+/// How long a server connection can stay idle before it is closed
+const SERVER_IDLE_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// How many accepted connections wait for a slot when `maxConnections` is set without `acceptBacklog`
+pub const DEFAULT_ACCEPT_BACKLOG: usize = 128;
+
+/// How many refused connections wait to be answered. The ones beyond it are closed without a response
+const REFUSED_QUEUE_SIZE: usize = 64;
+
+/// How long the request of a refused connection is read before it is answered
+const REFUSED_READ_TIMEOUT: Duration = Duration::from_millis(200);
+
+#[derive(Debug, Error)]
+pub enum HttpError {
+    #[error("I/O error: {0}")]
+    Io(#[from] io::Error),
+    #[error("Failed to connect: {0}")]
+    Connect(io::Error),
+    #[error("Malformed HTTP message: {0}")]
+    Malformed(String),
+    #[error("HTTP message is larger than the allowed size")]
+    TooLarge,
+    #[error("Invalid URL: {0}")]
+    InvalidUrl(String),
+}
+
+/// A case-insensitive list of HTTP headers that keeps the insertion order
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct HttpHeaders {
+    entries: Vec<(String, String)>,
+}
+
+impl HttpHeaders {
+    pub fn new() -> HttpHeaders {
+        HttpHeaders::default()
+    }
+
+    /// Return the first value of the header with the given name
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.entries.iter().find(|(n, _)| n.eq_ignore_ascii_case(name)).map(|(_, v)| v.as_str())
+    }
+
+    /// Replace all the values of the header with the given name
+    pub fn set(&mut self, name: &str, value: &str) {
+        self.remove(name);
+        self.append(name, value);
+    }
+
+    /// Add a value for the header without removing the existing ones
+    pub fn append(&mut self, name: &str, value: &str) {
+        self.entries.push((name.to_string(), value.to_string()));
+    }
+
+    /// Remove all the values of the header with the given name
+    pub fn remove(&mut self, name: &str) {
+        self.entries.retain(|(n, _)| !n.eq_ignore_ascii_case(name));
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.entries.iter().map(|(n, v)| (n.as_str(), v.as_str()))
+    }
+}
+
+/// A HTTP request received by a HttpServer or sent with `send_request`
+#[derive(Debug, Clone, PartialEq)]
+pub struct HttpRequest {
+    pub method: String,
+    /// The request target containing the path and the query string
+    pub target: String,
+    pub headers: HttpHeaders,
+    pub body: Vec<u8>,
+}
+
+impl HttpRequest {
+    pub fn new(method: &str, target: &str) -> HttpRequest {
+        HttpRequest { method: method.to_string(), target: target.to_string(), headers: HttpHeaders::new(), body: Vec::new() }
+    }
+
+    /// Return the path of the request target without the query string
+    pub fn path(&self) -> &str {
+        self.target.split('?').next().unwrap_or_default()
+    }
+
+    /// Return the query string of the request target
+    pub fn query(&self) -> Option<&str> {
+        self.target.split_once('?').map(|(_, query)| query)
+    }
+
+    /// Return the decoded value of the first query parameter with the given name
+    pub fn query_param(&self, name: &str) -> Option<String> {
+        self.query_params().into_iter().find(|(key, _)| key == name).map(|(_, value)| value)
+    }
+
+    /// Return all the decoded query parameters in the order they appear
+    pub fn query_params(&self) -> Vec<(String, String)> {
+        self.query()
+            .map(|query| query.split('&')
+                .filter(|pair| !pair.is_empty())
+                .map(|pair| pair.split_once('=').unwrap_or((pair, "")))
+                .map(|(key, value)| (percent_decode(key), percent_decode(value)))
+                .collect())
+            .unwrap_or_default()
+    }
+}
+
+/// Writes the body of a streamed response until it ends or the client disconnects
+pub type StreamWriter = dyn Fn(&mut dyn Write) -> io::Result<()> + Send + Sync;
+
+/// The body of a response that is written while it is produced, like a server-sent events stream
+#[derive(Clone)]
+pub struct ResponseStream(Arc<StreamWriter>);
+
+impl std::fmt::Debug for ResponseStream {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("ResponseStream")
+    }
+}
+
+impl PartialEq for ResponseStream {
+    fn eq(&self, other: &ResponseStream) -> bool {
+        Arc::ptr_eq(&self.0, &other.0)
+    }
+}
+
+/// A HTTP response sent by a HttpServer or received from `send_request`
+#[derive(Debug, Clone, PartialEq)]
+pub struct HttpResponse {
+    pub status: u16,
+    pub headers: HttpHeaders,
+    pub body: Vec<u8>,
+    /// Written after the body by a HttpServer, which closes the connection when it ends
+    pub stream: Option<ResponseStream>,
+}
+
+impl HttpResponse {
+    /// Create a response with the given status and an empty body
+    pub fn new(status: u16) -> HttpResponse {
+        HttpResponse { status, headers: HttpHeaders::new(), body: Vec::new(), stream: None }
+    }
+
+    /// Create a response with the given status and content type whose body is written by the
+    /// writer without a Content-Length
+    pub fn streamed(status: u16, content_type: &str, writer: impl Fn(&mut dyn Write) -> io::Result<()> + Send + Sync + 'static) -> HttpResponse {
+        let mut response = HttpResponse::new(status);
+        response.headers.set("Content-Type", content_type);
+        response.stream = Some(ResponseStream(Arc::new(writer)));
+        response
+    }
+
+    /// Create a response with the given status, content type and body
+    pub fn with_body(status: u16, content_type: &str, body: impl Into<Vec<u8>>) -> HttpResponse {
+        let mut response = HttpResponse::new(status);
+        response.headers.set("Content-Type", content_type);
+        response.body = body.into();
+        response
+    }
+
+    /// Return if the status is in the 2xx range
+    pub fn is_success(&self) -> bool {
+        (200..300).contains(&self.status)
+    }
+}
+
+/// Return the standard reason phrase of a HTTP status
+pub fn reason_phrase(status: u16) -> &'static str {
+    match status {
+        200 => "OK",
+        201 => "Created",
+        202 => "Accepted",
+        204 => "No Content",
+        301 => "Moved Permanently",
+        302 => "Found",
+        304 => "Not Modified",
+        307 => "Temporary Redirect",
+        308 => "Permanent Redirect",
+        400 => "Bad Request",
+        401 => "Unauthorized",
+        403 => "Forbidden",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        409 => "Conflict",
+        412 => "Precondition Failed",
+        413 => "Payload Too Large",
+        415 => "Unsupported Media Type",
+        422 => "Unprocessable Entity",
+        429 => "Too Many Requests",
+        500 => "Internal Server Error",
+        502 => "Bad Gateway",
+        503 => "Service Unavailable",
+        504 => "Gateway Timeout",
+        _ => "Unknown",
+    }
+}
+
+/// Decode a percent-encoded (application/x-www-form-urlencoded) string
+pub fn percent_decode(value: &str) -> String {
+    let bytes = value.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut index = 0;
+    while index < bytes.len() {
+        match bytes[index] {
+            b'+' => decoded.push(b' '),
+            b'%' if index + 2 < bytes.len() => {
+                let hex = std::str::from_utf8(&bytes[index + 1..index + 3]).ok()
+                    .and_then(|hex| u8::from_str_radix(hex, 16).ok());
+                match hex {
+                    Some(byte) => {
+                        decoded.push(byte);
+                        index += 2;
+                    }
+                    None => decoded.push(b'%'),
+                }
+            }
+            byte => decoded.push(byte),
+        }
+        index += 1;
+    }
+    String::from_utf8_lossy(&decoded).into_owned()
+}
+
+/// Percent-encode a query parameter, keeping only the unreserved characters of RFC 3986
+pub fn percent_encode(value: &str) -> String {
+    value.bytes().map(|byte| match byte {
+        b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => char::from(byte).to_string(),
+        byte => format!("%{:02X}", byte),
+    }).collect()
+}
+
+/// A part of a `multipart/form-data` body
+#[derive(Debug, Clone, PartialEq)]
+pub struct MultipartPart {
+    /// The `name` parameter of the part Content-Disposition
+    pub name: Option<String>,
+    pub headers: HttpHeaders,
+    pub body: Vec<u8>,
+}
+
+/// Split a `multipart/form-data` body into its parts using the boundary of the Content-Type header
+pub fn parse_multipart(content_type: &str, body: &[u8]) -> Result<Vec<MultipartPart>, HttpError> {
+    let boundary = content_type.split(';')
+        .map(str::trim)
+        .find_map(|param| param.strip_prefix("boundary="))
+        .map(|boundary| boundary.trim_matches('"'))
+        .filter(|boundary| !boundary.is_empty())
+        .ok_or_else(|| HttpError::Malformed(String::from("multipart Content-Type without boundary")))?;
+    let delimiter = format!("--{}", boundary).into_bytes();
+
+    let mut parts = Vec::new();
+    let mut rest = match find_bytes(body, &delimiter) {
+        Some(index) => &body[index + delimiter.len()..],
+        None => return Err(HttpError::Malformed(String::from("multipart body without boundary"))),
+    };
+    loop {
+        // the closing delimiter is followed by "--"
+        if rest.starts_with(b"--") {
+            return Ok(parts);
+        }
+        let next = find_bytes(rest, &delimiter)
+            .ok_or_else(|| HttpError::Malformed(String::from("multipart body without closing boundary")))?;
+        let part = rest[..next].strip_prefix(b"\r\n").unwrap_or(&rest[..next]);
+        let part = part.strip_suffix(b"\r\n").unwrap_or(part);
+
+        let (head, part_body) = match find_bytes(part, b"\r\n\r\n") {
+            Some(index) => (&part[..index], &part[index + 4..]),
+            None => (part, &[][..]),
+        };
+        let mut headers = HttpHeaders::new();
+        for line in String::from_utf8_lossy(head).split("\r\n") {
+            if let Some((name, value)) = line.split_once(':') {
+                headers.append(name.trim(), value.trim());
+            }
+        }
+        let name = headers.get("Content-Disposition").and_then(|disposition| {
+            disposition.split(';')
+                .map(str::trim)
+                .find_map(|param| param.strip_prefix("name="))
+                .map(|name| name.trim_matches('"').to_string())
+        });
+        parts.push(MultipartPart { name, headers, body: part_body.to_vec() });
+        rest = &rest[next + delimiter.len()..];
+    }
+}
+
+fn find_bytes(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack.windows(needle.len()).position(|window| window == needle)
+}
+
+/// Read the request line (or status line) and the headers of a HTTP message. Return None if the
+/// connection was closed before any byte was read
+fn read_head<R: BufRead>(reader: &mut R) -> Result<Option<(String, HttpHeaders)>, HttpError> {
+    let mut first_line = String::new();
+    let mut head_size = 0;
+    loop {
+        first_line.clear();
+        let read = reader.by_ref().take(MAX_HEAD_SIZE as u64).read_line(&mut first_line)?;
+        if read == 0 {
+            return Ok(None);
+        }
+        head_size += read;
+        // tolerate empty lines before the request line (RFC 9112 section 2.2)
+        if !first_line.trim().is_empty() {
+            break;
+        }
+        if head_size >= MAX_HEAD_SIZE {
+            return Err(HttpError::TooLarge);
+        }
+    }
+
+    let mut headers = HttpHeaders::new();
+    loop {
+        let mut line = String::new();
+        let read = reader.by_ref().take((MAX_HEAD_SIZE - head_size.min(MAX_HEAD_SIZE)) as u64).read_line(&mut line)?;
+        head_size += read;
+        if head_size >= MAX_HEAD_SIZE {
+            return Err(HttpError::TooLarge);
+        }
+        if read == 0 {
+            return Err(HttpError::Malformed(String::from("connection closed before the end of the headers")));
+        }
+
+        let line = line.trim_end_matches(['\r', '\n']);
+        if line.is_empty() {
+            break;
+        }
+        let (name, value) = line.split_once(':')
+            .ok_or_else(|| HttpError::Malformed(format!("invalid header line: {}", line)))?;
+        headers.append(name.trim(), value.trim());
+    }
+
+    Ok(Some((first_line.trim_end_matches(['\r', '\n']).to_string(), headers)))
+}
+
+/// Read the body of a HTTP message according to its Content-Length or Transfer-Encoding headers.
+/// When `read_to_end` is set and none of those headers is present the body is everything until EOF
+fn read_body<R: BufRead>(reader: &mut R, headers: &HttpHeaders, read_to_end: bool) -> Result<Vec<u8>, HttpError> {
+    let chunked = headers.get("Transfer-Encoding")
+        .map(|v| v.to_ascii_lowercase().contains("chunked"))
+        .unwrap_or(false);
+
+    if chunked {
+        let mut body = Vec::new();
+        loop {
+            let mut size_line = String::new();
+            reader.read_line(&mut size_line)?;
+            let size_str = size_line.trim().split(';').next().unwrap_or_default();
+            let size = usize::from_str_radix(size_str, 16)
+                .map_err(|_| HttpError::Malformed(format!("invalid chunk size: {}", size_str)))?;
+            // the size is sent by the peer, so the sum is checked before it can overflow
+            body.len().checked_add(size).filter(|len| *len <= MAX_BODY_SIZE).ok_or(HttpError::TooLarge)?;
+            if size == 0 {
+                // skip trailers until the empty line
+                loop {
+                    let mut trailer = String::new();
+                    if reader.read_line(&mut trailer)? == 0 || trailer.trim().is_empty() {
+                        break;
+                    }
+                }
+                return Ok(body);
+            }
+            let start = body.len();
+            body.resize(start + size, 0);
+            reader.read_exact(&mut body[start..])?;
+            let mut crlf = [0u8; 2];
+            reader.read_exact(&mut crlf)?;
+        }
+    }
+
+    if let Some(length) = headers.get("Content-Length") {
+        let length: usize = length.parse()
+            .map_err(|_| HttpError::Malformed(format!("invalid Content-Length: {}", length)))?;
+        if length > MAX_BODY_SIZE {
+            return Err(HttpError::TooLarge);
+        }
+        let mut body = vec![0u8; length];
+        reader.read_exact(&mut body)?;
+        return Ok(body);
+    }
+
+    let mut body = Vec::new();
+    if read_to_end {
+        reader.take(MAX_BODY_SIZE as u64 + 1).read_to_end(&mut body)?;
+        if body.len() > MAX_BODY_SIZE {
+            return Err(HttpError::TooLarge);
+        }
+    }
+    Ok(body)
+}
+
+/// Read a HTTP request from the reader. Return None if the connection was closed
+pub fn read_request<R: BufRead>(reader: &mut R) -> Result<Option<HttpRequest>, HttpError> {
+    let Some((request_line, headers)) = read_head(reader)? else {
+        return Ok(None);
+    };
+
+    let mut parts = request_line.split_whitespace();
+    let (Some(method), Some(target), Some(version)) = (parts.next(), parts.next(), parts.next()) else {
+        return Err(HttpError::Malformed(format!("invalid request line: {}", request_line)));
+    };
+    if !version.starts_with("HTTP/1.") {
+        return Err(HttpError::Malformed(format!("unsupported HTTP version: {}", version)));
+    }
+
+    let body = read_body(reader, &headers, false)?;
+    Ok(Some(HttpRequest { method: method.to_string(), target: target.to_string(), headers, body }))
+}
+
+/// Read a HTTP response from the reader
+pub fn read_response<R: BufRead>(reader: &mut R) -> Result<HttpResponse, HttpError> {
+    let (status_line, headers) = read_head(reader)?
+        .ok_or_else(|| HttpError::Malformed(String::from("connection closed before the response")))?;
+
+    let mut parts = status_line.split_whitespace();
+    let status = match (parts.next(), parts.next()) {
+        (Some(version), Some(status)) if version.starts_with("HTTP/1.") => status.parse::<u16>()
+            .map_err(|_| HttpError::Malformed(format!("invalid status line: {}", status_line)))?,
+        _ => return Err(HttpError::Malformed(format!("invalid status line: {}", status_line))),
+    };
+
+    // responses to HEAD and 1xx, 204 and 304 responses never have a body
+    let body = if (100..200).contains(&status) || status == 204 || status == 304 {
+        Vec::new()
+    } else {
+        read_body(reader, &headers, true)?
+    };
+    Ok(HttpResponse { status, headers, body, stream: None })
+}
+
+/// Write the response into the writer, setting its Content-Length unless the response is
+/// streamed. Streamed responses are always written with `Connection: close`
+pub fn write_response<W: Write>(writer: &mut W, response: &HttpResponse, keep_alive: bool) -> Result<(), HttpError> {
+    let keep_alive = keep_alive && response.stream.is_none();
+    let mut head = format!("HTTP/1.1 {} {}\r\n", response.status, reason_phrase(response.status));
+    for (name, value) in response.headers.iter() {
+        if !name.eq_ignore_ascii_case("Content-Length") && !name.eq_ignore_ascii_case("Connection") {
+            head.push_str(&format!("{}: {}\r\n", name, value));
+        }
+    }
+    if response.stream.is_none() {
+        head.push_str(&format!("Content-Length: {}\r\n", response.body.len()));
+    }
+    head.push_str(if keep_alive { "Connection: keep-alive\r\n\r\n" } else { "Connection: close\r\n\r\n" });
+
+    // a single write, so the body is not held back by Nagle waiting for the ACK of the head
+    let mut bytes = head.into_bytes();
+    bytes.extend_from_slice(&response.body);
+    writer.write_all(&bytes)?;
+    writer.flush()?;
+    if let Some(ResponseStream(stream)) = &response.stream {
+        stream(writer)?;
+        writer.flush()?;
+    }
+    Ok(())
+}
+
+/// Write the request into the writer, always setting its Host and Content-Length
+pub fn write_request<W: Write>(writer: &mut W, host: &str, request: &HttpRequest) -> Result<(), HttpError> {
+    let mut head = format!("{} {} HTTP/1.1\r\n", request.method, request.target);
+    if request.headers.get("Host").is_none() {
+        head.push_str(&format!("Host: {}\r\n", host));
+    }
+    for (name, value) in request.headers.iter() {
+        if !name.eq_ignore_ascii_case("Content-Length") {
+            head.push_str(&format!("{}: {}\r\n", name, value));
+        }
+    }
+    head.push_str(&format!("Content-Length: {}\r\n\r\n", request.body.len()));
+
+    let mut bytes = head.into_bytes();
+    bytes.extend_from_slice(&request.body);
+    writer.write_all(&bytes)?;
+    writer.flush()?;
+    Ok(())
+}
+
+/// A parsed `http://` URL
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HttpUrl {
+    pub host: String,
+    pub port: u16,
+    /// The path plus the query string. Always starts with '/'
+    pub target: String,
+}
+
+impl HttpUrl {
+    /// Parse a URL like `http://host:port/path?query`. Only the `http` scheme is supported
+    pub fn parse(url: &str) -> Result<HttpUrl, HttpError> {
+        let rest = url.strip_prefix("http://").ok_or_else(|| HttpError::InvalidUrl(url.to_string()))?;
+        let (authority, target) = match rest.find(['/', '?']) {
+            Some(index) if rest.as_bytes()[index] == b'/' => (&rest[..index], rest[index..].to_string()),
+            Some(index) => (&rest[..index], format!("/{}", &rest[index..])),
+            None => (rest, String::from("/")),
+        };
+
+        // bracketed IPv6 literal: [::1]:8080
+        let (host, port) = if let Some(stripped) = authority.strip_prefix('[') {
+            let (host, after) = stripped.split_once(']').ok_or_else(|| HttpError::InvalidUrl(url.to_string()))?;
+            (host.to_string(), after.strip_prefix(':'))
+        } else {
+            match authority.rsplit_once(':') {
+                Some((host, port)) => (host.to_string(), Some(port)),
+                None => (authority.to_string(), None),
+            }
+        };
+        if host.is_empty() {
+            return Err(HttpError::InvalidUrl(url.to_string()));
+        }
+        let port = match port {
+            Some(port) => port.parse().map_err(|_| HttpError::InvalidUrl(url.to_string()))?,
+            None => 80,
+        };
+
+        Ok(HttpUrl { host, port, target })
+    }
+
+    /// Resolve a `Location` header against this URL. It can be an absolute `http://` URL, a
+    /// `//host/path` reference, an absolute path or a path relative to the current one
+    pub fn join(&self, location: &str) -> Result<HttpUrl, HttpError> {
+        if location.starts_with("http://") {
+            return HttpUrl::parse(location);
+        }
+        if let Some(rest) = location.strip_prefix("//") {
+            return HttpUrl::parse(&format!("http://{}", rest));
+        }
+        if location.contains("://") || location.is_empty() {
+            return Err(HttpError::InvalidUrl(location.to_string()));
+        }
+
+        let target = if location.starts_with('/') {
+            location.to_string()
+        } else {
+            let path = self.target.split(['?', '#']).next().unwrap_or("/");
+            let directory = &path[..path.rfind('/').map(|index| index + 1).unwrap_or(1)];
+            format!("{}{}", directory, location)
+        };
+        Ok(HttpUrl { host: self.host.clone(), port: self.port, target })
+    }
+
+    /// Return the value used in the Host header
+    pub fn authority(&self) -> String {
+        let host = if self.host.contains(':') { format!("[{}]", self.host) } else { self.host.clone() };
+        if self.port == 80 { host } else { format!("{}:{}", host, self.port) }
+    }
+}
+
+/// Send the request to the URL using a new connection and wait for the response. The timeout is
+/// applied to the connection and to every read and write
+pub fn send_request(url: &HttpUrl, request: HttpRequest, timeout: Duration) -> Result<HttpResponse, HttpError> {
+    let address = (url.host.as_str(), url.port).to_socket_addrs()?
+        .next()
+        .ok_or_else(|| HttpError::InvalidUrl(format!("{} does not resolve to any address", url.host)))?;
+    send_request_to(address, url, request, timeout)
+}
+
+/// Send the request to the URL like `send_request`, connecting to the given address instead of
+/// resolving the URL host. The Host header still has the URL host
+pub fn send_request_to(address: SocketAddr, url: &HttpUrl, mut request: HttpRequest, timeout: Duration) -> Result<HttpResponse, HttpError> {
+    let stream = TcpStream::connect_timeout(&address, timeout).map_err(HttpError::Connect)?;
+    stream.set_read_timeout(Some(timeout))?;
+    stream.set_write_timeout(Some(timeout))?;
+
+    request.target = url.target.clone();
+    request.headers.set("Connection", "close");
+    let mut writer = stream.try_clone()?;
+    write_request(&mut writer, &url.authority(), &request)?;
+    read_response(&mut BufReader::new(stream))
+}
+
+/// Return the address a listener configured only with a port binds to. It is `[::]`, that
+/// accepts IPv6 and IPv4 connections (dual stack), or `0.0.0.0` when the host does not support IPv6
+pub fn default_listener_address(port: u16) -> SocketAddr {
+    match TcpListener::bind("[::]:0") {
+        Ok(_) => SocketAddr::new(IpAddr::V6(Ipv6Addr::UNSPECIFIED), port),
+        Err(_) => SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), port),
+    }
+}
+
+/// The function that handles the requests received by a HttpServer
+pub type HttpHandler = dyn Fn(&HttpRequest) -> HttpResponse + Send + Sync;
+
+/// The limits of the connections of a HttpServer, so a flood of connections is refused instead of
+/// exhausting the file descriptors that the deliveries also need. Unset limits are not applied
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ConnectionLimits {
+    /// How many connections are served at once
+    pub max_connections: Option<usize>,
+    /// How many accepted connections wait for one of the `max_connections` to close. The ones
+    /// beyond it are refused with 503. Defaults to DEFAULT_ACCEPT_BACKLOG
+    pub accept_backlog: Option<usize>,
+    /// How many connections, served or waiting, each client IP address has at once. The ones
+    /// beyond it are refused with 429
+    pub max_connections_per_ip: Option<usize>,
+}
+
+impl ConnectionLimits {
+    pub fn is_unlimited(&self) -> bool {
+        self.max_connections.is_none() && self.max_connections_per_ip.is_none()
+    }
+}
+
+#[derive(Debug, Default)]
+struct ConnectionCounts {
+    served: usize,
+    waiting: usize,
+    by_ip: HashMap<IpAddr, usize>,
+}
+
+/// Count the connections of a HttpServer to apply its ConnectionLimits
+#[derive(Debug)]
+struct ConnectionGate {
+    limits: ConnectionLimits,
+    counts: Mutex<ConnectionCounts>,
+    closed: Condvar,
+}
+
+impl ConnectionGate {
+    /// Admit a connection of the IP address, or return the status it is refused with
+    fn admit(self: &Arc<Self>, ip: IpAddr) -> Result<ConnectionPermit, u16> {
+        let mut counts = self.counts.lock().unwrap();
+        if self.limits.max_connections_per_ip.is_some_and(|max| counts.by_ip.get(&ip).copied().unwrap_or(0) >= max) {
+            return Err(429);
+        }
+        let waiting = self.limits.max_connections.is_some_and(|max| counts.served >= max);
+        if waiting {
+            if counts.waiting >= self.limits.accept_backlog.unwrap_or(DEFAULT_ACCEPT_BACKLOG) {
+                return Err(503);
+            }
+            counts.waiting += 1;
+        } else {
+            counts.served += 1;
+        }
+        *counts.by_ip.entry(ip).or_default() += 1;
+        Ok(ConnectionPermit { gate: self.clone(), ip, waiting })
+    }
+}
+
+/// A connection admitted by a ConnectionGate, counted until it is dropped
+struct ConnectionPermit {
+    gate: Arc<ConnectionGate>,
+    ip: IpAddr,
+    waiting: bool,
+}
+
+impl ConnectionPermit {
+    /// Block until the connection can be served, when it is waiting in the backlog
+    fn wait(&mut self) {
+        if !self.waiting {
+            return;
+        }
+        let max = self.gate.limits.max_connections.unwrap_or(usize::MAX);
+        let mut counts = self.gate.counts.lock().unwrap();
+        while counts.served >= max {
+            counts = self.gate.closed.wait(counts).unwrap();
+        }
+        counts.waiting -= 1;
+        counts.served += 1;
+        self.waiting = false;
+    }
+}
+
+impl Drop for ConnectionPermit {
+    fn drop(&mut self) {
+        let mut counts = self.gate.counts.lock().unwrap();
+        if self.waiting {
+            counts.waiting -= 1;
+        } else {
+            counts.served -= 1;
+        }
+        if let Some(count) = counts.by_ip.get_mut(&self.ip) {
+            *count -= 1;
+            if *count == 0 {
+                counts.by_ip.remove(&self.ip);
+            }
+        }
+        self.gate.closed.notify_all();
+    }
+}
+
+/// Answer the refused connections with their status. The request is read first, so closing the
+/// connection does not reset it before the client reads the response
+fn answer_refused(mut stream: TcpStream, status: u16) {
+    let _ = stream.set_read_timeout(Some(REFUSED_READ_TIMEOUT));
+    let _ = stream.set_write_timeout(Some(REFUSED_READ_TIMEOUT));
+    let mut head = Vec::new();
+    let mut buffer = [0u8; 1024];
+    while head.len() < MAX_HEAD_SIZE && !head.windows(4).any(|window| window == b"\r\n\r\n") {
+        match stream.read(&mut buffer) {
+            Ok(0) | Err(_) => break,
+            Ok(read) => head.extend_from_slice(&buffer[..read]),
+        }
+    }
+    let mut response = HttpResponse::with_body(status, "text/plain", "the server has too many connections");
+    response.headers.set("Retry-After", "1");
+    let _ = write_response(&mut stream, &response, false);
+    let _ = stream.shutdown(std::net::Shutdown::Write);
+}
+
+/// A HTTP/1.1 server that handles each connection in its own thread
+pub struct HttpServer {
+    local_addr: SocketAddr,
+    running: Arc<AtomicBool>,
+    acceptor: Option<JoinHandle<()>>,
+}
+
+impl HttpServer {
+    /// Bind the server to the address and start accepting connections
+    pub fn bind<A: ToSocketAddrs>(address: A, handler: Arc<HttpHandler>) -> io::Result<HttpServer> {
+        HttpServer::bind_with_limits(address, handler, ConnectionLimits::default())
+    }
+
+    /// Bind the server like `bind`, refusing the connections beyond the limits
+    pub fn bind_with_limits<A: ToSocketAddrs>(address: A, handler: Arc<HttpHandler>, limits: ConnectionLimits) -> io::Result<HttpServer> {
+        let listener = TcpListener::bind(address)?;
+        let local_addr = listener.local_addr()?;
+        let running = Arc::new(AtomicBool::new(true));
+        let gate = (!limits.is_unlimited()).then(|| Arc::new(ConnectionGate { limits, counts: Mutex::default(), closed: Condvar::new() }));
+        let refused = match gate {
+            Some(_) => Some(HttpServer::start_refusing(local_addr)?),
+            None => None,
+        };
+
+        let acceptor_running = running.clone();
+        let acceptor = thread::Builder::new()
+            .name(format!("angler-http-{}", local_addr.port()))
+            .spawn(move || {
+                for stream in listener.incoming() {
+                    if !acceptor_running.load(Ordering::SeqCst) {
+                        break;
+                    }
+                    let Ok(stream) = stream else { continue };
+                    let permit = match (&gate, stream.peer_addr()) {
+                        (Some(gate), Ok(peer)) => match gate.admit(peer.ip()) {
+                            Ok(permit) => Some(permit),
+                            Err(status) => {
+                                log!(Level::Debug, "Refused a connection from {} to {} with {}", peer.ip(), local_addr, status);
+                                // closed without a response when too many refused connections wait
+                                let _ = refused.as_ref().map(|refused| refused.try_send((stream, status)));
+                                continue;
+                            }
+                        },
+                        _ => None,
+                    };
+                    let handler = handler.clone();
+                    let _ = thread::Builder::new()
+                        .name(String::from("angler-http-conn"))
+                        .spawn(move || {
+                            let mut permit = permit;
+                            if let Some(permit) = &mut permit {
+                                permit.wait();
+                            }
+                            serve_connection(stream, handler.as_ref());
+                        });
+                }
+            })?;
+
+        Ok(HttpServer { local_addr, running, acceptor: Some(acceptor) })
+    }
+
+    /// Start the thread that answers the refused connections. It stops when the acceptor does
+    fn start_refusing(local_addr: SocketAddr) -> io::Result<SyncSender<(TcpStream, u16)>> {
+        let (sender, receiver) = mpsc::sync_channel::<(TcpStream, u16)>(REFUSED_QUEUE_SIZE);
+        thread::Builder::new()
+            .name(format!("angler-http-{}-refused", local_addr.port()))
+            .spawn(move || {
+                for (stream, status) in receiver {
+                    answer_refused(stream, status);
+                }
+            })?;
+        Ok(sender)
+    }
+
+    /// Return the address the server is bound to
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+
+    /// Stop accepting new connections
+    pub fn shutdown(&mut self) {
+        if !self.running.swap(false, Ordering::SeqCst) {
+            return;
+        }
+        // wake up the acceptor that is blocked on accept()
+        let _ = TcpStream::connect_timeout(&self.local_addr, Duration::from_secs(1));
+        if let Some(acceptor) = self.acceptor.take() {
+            let _ = acceptor.join();
+        }
+    }
+}
+
+impl Drop for HttpServer {
+    fn drop(&mut self) {
+        self.shutdown();
+    }
+}
+
+fn serve_connection(stream: TcpStream, handler: &HttpHandler) {
+    let _ = stream.set_read_timeout(Some(SERVER_IDLE_TIMEOUT));
+    let Ok(mut writer) = stream.try_clone() else { return };
+    let mut reader = BufReader::new(stream);
+
+    loop {
+        let request = match read_request(&mut reader) {
+            Ok(Some(request)) => request,
+            Ok(None) => return,
+            Err(HttpError::Io(_)) => return,
+            Err(HttpError::TooLarge) => {
+                let _ = write_response(&mut writer, &HttpResponse::new(413), false);
+                return;
+            }
+            Err(err) => {
+                let _ = write_response(&mut writer, &HttpResponse::with_body(400, "text/plain", err.to_string()), false);
+                return;
+            }
+        };
+
+        let keep_alive = !request.headers.get("Connection").is_some_and(|v| v.eq_ignore_ascii_case("close"));
+        let response = handler(&request);
+        if write_response(&mut writer, &response, keep_alive).is_err() || !keep_alive || response.stream.is_some() {
+            return;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use super::*;
+
+    #[test]
+    fn test_if_request_with_content_length_is_parsed() {
+        let raw = "POST /hooks?a=1&b=hello%20world HTTP/1.1\r\nHost: example.com\r\nContent-Length: 5\r\n\r\nhello";
+        let request = read_request(&mut Cursor::new(raw)).unwrap().unwrap();
+        assert_eq!(request.method, "POST");
+        assert_eq!(request.path(), "/hooks");
+        assert_eq!(request.query_param("b").unwrap(), "hello world");
+        assert_eq!(request.headers.get("host").unwrap(), "example.com");
+        assert_eq!(request.body, b"hello");
+    }
+
+    #[test]
+    fn test_if_locations_are_resolved_against_the_url() {
+        let url = HttpUrl::parse("http://example.com:8080/hooks/orders?v=1").unwrap();
+        assert_eq!(url.join("http://other.com/x").unwrap(), HttpUrl::parse("http://other.com/x").unwrap());
+        assert_eq!(url.join("//other.com:81/x").unwrap(), HttpUrl::parse("http://other.com:81/x").unwrap());
+        assert_eq!(url.join("/v2/orders").unwrap(), HttpUrl::parse("http://example.com:8080/v2/orders").unwrap());
+        assert_eq!(url.join("payments?v=2").unwrap(), HttpUrl::parse("http://example.com:8080/hooks/payments?v=2").unwrap());
+        assert!(url.join("https://example.com/").is_err());
+    }
+
+    #[test]
+    fn test_if_chunked_response_is_parsed() {
+        let raw = "HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n5\r\nhello\r\n6\r\n world\r\n0\r\n\r\n";
+        let response = read_response(&mut Cursor::new(raw)).unwrap();
+        assert_eq!(response.status, 200);
+        assert_eq!(response.body, b"hello world");
+    }
+
+    #[test]
+    fn test_if_chunk_sizes_that_overflow_the_body_are_refused() {
+        let raw = "POST /messages HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n1\r\nx\r\nffffffffffffffff\r\n";
+        assert!(matches!(read_request(&mut Cursor::new(raw)), Err(HttpError::TooLarge)));
+    }
+
+    #[test]
+    fn test_if_malformed_request_line_is_rejected() {
+        assert!(matches!(read_request(&mut Cursor::new("GARBAGE\r\n\r\n")), Err(HttpError::Malformed(_))));
+    }
+
+    #[test]
+    fn test_if_multipart_body_is_parsed() {
+        let body = "--xyz\r\nContent-Disposition: form-data; name=\"metadata\"\r\nContent-Type: application/json\r\n\r\n{}\r\n--xyz\r\nContent-Disposition: form-data; name=\"data\"\r\n\r\nhello\r\n--xyz--";
+        let parts = parse_multipart("multipart/form-data; boundary=xyz", body.as_bytes()).unwrap();
+        assert_eq!(parts.len(), 2);
+        assert_eq!(parts[0].name.as_deref(), Some("metadata"));
+        assert_eq!(parts[0].headers.get("content-type"), Some("application/json"));
+        assert_eq!(parts[0].body, b"{}");
+        assert_eq!(parts[1].name.as_deref(), Some("data"));
+        assert_eq!(parts[1].body, b"hello");
+        assert!(parse_multipart("multipart/form-data", body.as_bytes()).is_err());
+    }
+
+    #[test]
+    fn test_if_url_is_parsed() {
+        let url = HttpUrl::parse("http://localhost:8080/hooks?x=1").unwrap();
+        assert_eq!(url, HttpUrl { host: String::from("localhost"), port: 8080, target: String::from("/hooks?x=1") });
+
+        let url = HttpUrl::parse("http://[::1]:9000").unwrap();
+        assert_eq!(url.host, "::1");
+        assert_eq!(url.authority(), "[::1]:9000");
+
+        assert_eq!(HttpUrl::parse("http://example.com").unwrap().port, 80);
+        assert!(HttpUrl::parse("https://example.com").is_err());
+    }
+
+    #[test]
+    fn test_if_server_answers_requests() {
+        let handler: Arc<HttpHandler> = Arc::new(|request: &HttpRequest| {
+            HttpResponse::with_body(201, "text/plain", format!("{} {}", request.method, request.path()))
+        });
+        let server = HttpServer::bind("127.0.0.1:0", handler).unwrap();
+        let url = HttpUrl::parse(&format!("http://{}/echo", server.local_addr())).unwrap();
+
+        let response = send_request(&url, HttpRequest::new("PUT", "/"), Duration::from_secs(5)).unwrap();
+        assert_eq!(response.status, 201);
+        assert_eq!(response.body, b"PUT /echo");
+    }
+
+    #[test]
+    fn test_if_default_listener_accepts_ipv4_and_ipv6_connections() {
+        let handler: Arc<HttpHandler> = Arc::new(|_: &HttpRequest| HttpResponse::new(204));
+        let address = default_listener_address(0);
+        let server = HttpServer::bind(address, handler).unwrap();
+        let port = server.local_addr().port();
+
+        let mut hosts = vec![String::from("127.0.0.1")];
+        if address.is_ipv6() {
+            hosts.push(String::from("[::1]"));
+        }
+        for host in hosts {
+            let url = HttpUrl::parse(&format!("http://{}:{}/", host, port)).unwrap();
+            assert_eq!(send_request(&url, HttpRequest::new("GET", "/"), Duration::from_secs(5)).unwrap().status, 204, "{}", host);
+        }
+    }
+
+    #[test]
+    fn test_if_connections_beyond_the_limits_are_refused() {
+        let handler: Arc<HttpHandler> = Arc::new(|_: &HttpRequest| HttpResponse::new(204));
+        let limits = ConnectionLimits { max_connections: Some(1), accept_backlog: Some(1), max_connections_per_ip: None };
+        let server = HttpServer::bind_with_limits("127.0.0.1:0", handler.clone(), limits).unwrap();
+        let url = HttpUrl::parse(&format!("http://{}/", server.local_addr())).unwrap();
+        let connect = || {
+            let stream = TcpStream::connect(server.local_addr()).unwrap();
+            stream.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+            let mut writer = stream.try_clone().unwrap();
+            write_request(&mut writer, &url.authority(), &HttpRequest::new("GET", "/")).unwrap();
+            BufReader::new(stream)
+        };
+
+        // the served connection is kept alive, so the next one waits in the backlog
+        let mut served = connect();
+        assert_eq!(read_response(&mut served).unwrap().status, 204);
+        let mut waiting = connect();
+        thread::sleep(Duration::from_millis(50));
+        let refused = send_request(&url, HttpRequest::new("GET", "/"), Duration::from_secs(5)).unwrap();
+        assert_eq!(refused.status, 503);
+        assert_eq!(refused.headers.get("Retry-After"), Some("1"));
+
+        drop(served);
+        assert_eq!(read_response(&mut waiting).unwrap().status, 204);
+
+        let limits = ConnectionLimits { max_connections_per_ip: Some(1), ..ConnectionLimits::default() };
+        let server = HttpServer::bind_with_limits("127.0.0.1:0", handler, limits).unwrap();
+        let url = HttpUrl::parse(&format!("http://{}/", server.local_addr())).unwrap();
+        let _open = TcpStream::connect(server.local_addr()).unwrap();
+        thread::sleep(Duration::from_millis(50));
+        assert_eq!(send_request(&url, HttpRequest::new("GET", "/"), Duration::from_secs(5)).unwrap().status, 429);
+    }
+}
@@ -0,0 +1,7 @@
+pub mod admin;
+pub mod auth;
+pub mod client;
+pub mod dns;
+pub mod http;
+pub mod pool;
+pub mod storage;
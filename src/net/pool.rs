@@ -0,0 +1,224 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    io::{self, BufReader},
+    net::{SocketAddr, TcpStream},
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use super::http::{read_response, write_request, HttpError, HttpRequest, HttpResponse, HttpUrl};
+
+/// How long an idle connection is kept before it is closed
+pub const DEFAULT_POOL_IDLE_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// The most connections kept warm for a destination
+pub const MAX_WARM_CONNECTIONS: u16 = 32;
+
+/// A keep-alive connection waiting in the pool for its next request
+struct IdleConnection {
+    address: SocketAddr,
+    stream: TcpStream,
+    idle_since: Instant,
+}
+
+impl IdleConnection {
+    /// Return if the connection can send a request: it was not closed by the peer, that did not
+    /// send anything unexpected, and it was not idle for too long
+    fn is_usable(&self, idle_timeout: Duration) -> bool {
+        if self.idle_since.elapsed() >= idle_timeout || self.stream.set_nonblocking(true).is_err() {
+            return false;
+        }
+        let alive = matches!(self.stream.peek(&mut [0u8; 1]), Err(err) if err.kind() == io::ErrorKind::WouldBlock);
+        alive && self.stream.set_nonblocking(false).is_ok()
+    }
+}
+
+#[derive(Default)]
+struct PooledConnections {
+    idle: Vec<IdleConnection>,
+    /// How many connections the warmup keeps, 0 when the destination is not warmed up
+    warm_target: usize,
+}
+
+/// The keep-alive connections of the destinations, by destination ID. The connections are opened
+/// in advance by `warm` for the destinations with `warmConnections`, so their attempts do not wait
+/// for the TCP handshake, and are reused by the following attempts
+pub struct ConnectionPool {
+    idle_timeout: Duration,
+    connections: Mutex<HashMap<String, PooledConnections>>,
+}
+
+impl Default for ConnectionPool {
+    fn default() -> Self {
+        ConnectionPool::new(DEFAULT_POOL_IDLE_TIMEOUT)
+    }
+}
+
+impl ConnectionPool {
+    pub fn new(idle_timeout: Duration) -> ConnectionPool {
+        ConnectionPool { idle_timeout, connections: Mutex::new(HashMap::new()) }
+    }
+
+    /// Take an usable idle connection of the destination to the address, closing the unusable ones
+    fn take(&self, key: &str, address: SocketAddr) -> Option<TcpStream> {
+        let mut connections = self.connections.lock().unwrap();
+        let pooled = connections.get_mut(key)?;
+        while let Some(connection) = pooled.idle.pop() {
+            if connection.address == address && connection.is_usable(self.idle_timeout) {
+                return Some(connection.stream);
+            }
+        }
+        None
+    }
+
+    fn put(&self, key: &str, address: SocketAddr, stream: TcpStream) {
+        let idle = IdleConnection { address, stream, idle_since: Instant::now() };
+        self.connections.lock().unwrap().entry(key.to_string()).or_default().idle.push(idle);
+    }
+
+    /// Send the request to the URL through a connection of the destination, reused from the pool
+    /// or opened to the address, and keep the connection for the next request when the response
+    /// allows it. A request whose reused connection was closed before the response is sent again
+    /// through a new connection
+    pub fn send(&self, key: &str, address: SocketAddr, url: &HttpUrl, mut request: HttpRequest, timeout: Duration) -> Result<HttpResponse, HttpError> {
+        request.target = url.target.clone();
+        request.headers.set("Connection", "keep-alive");
+        if let Some(stream) = self.take(key, address) {
+            match exchange(&stream, url, &request, timeout) {
+                Ok(response) => return Ok(self.release(key, address, stream, response)),
+                // the peer closed the idle connection, so the request was not handled
+                Err(HttpError::Io(_)) | Err(HttpError::Malformed(_)) => {}
+                Err(err) => return Err(err),
+            }
+        }
+        let stream = TcpStream::connect_timeout(&address, timeout).map_err(HttpError::Connect)?;
+        let response = exchange(&stream, url, &request, timeout)?;
+        Ok(self.release(key, address, stream, response))
+    }
+
+    /// Keep the connection when the response was read to its end and the peer keeps it open
+    fn release(&self, key: &str, address: SocketAddr, stream: TcpStream, response: HttpResponse) -> HttpResponse {
+        let closed = response.headers.get("Connection").is_some_and(|value| value.eq_ignore_ascii_case("close"));
+        let framed = response.headers.get("Content-Length").is_some() || response.headers.get("Transfer-Encoding").is_some()
+            || (100..200).contains(&response.status) || response.status == 204 || response.status == 304;
+        if !closed && framed {
+            self.put(key, address, stream);
+        }
+        response
+    }
+
+    /// Open connections of the destination to the address until it has `count` idle ones. Return
+    /// how many idle connections it has
+    pub fn warm(&self, key: &str, address: SocketAddr, count: usize, timeout: Duration) -> usize {
+        let mut idle = {
+            let mut connections = self.connections.lock().unwrap();
+            let pooled = connections.entry(key.to_string()).or_default();
+            pooled.warm_target = count;
+            let idle_timeout = self.idle_timeout;
+            pooled.idle.retain(|connection| connection.address == address && connection.is_usable(idle_timeout));
+            pooled.idle.len()
+        };
+        // connected without the lock, so the attempts are not blocked by the handshakes
+        while idle < count {
+            match TcpStream::connect_timeout(&address, timeout) {
+                Ok(stream) => {
+                    self.put(key, address, stream);
+                    idle += 1;
+                }
+                Err(_) => break,
+            }
+        }
+        idle
+    }
+
+    /// Send the request through every idle connection of the destination to the address, keeping
+    /// the ones that answered it and closing the others, like the half-open connections whose peer
+    /// is gone. Return how many connections answered and how many were closed
+    pub fn ping(&self, key: &str, address: SocketAddr, url: &HttpUrl, request: &HttpRequest, timeout: Duration) -> (usize, usize) {
+        let idle = {
+            let mut connections = self.connections.lock().unwrap();
+            let Some(pooled) = connections.get_mut(key) else {
+                return (0, 0);
+            };
+            std::mem::take(&mut pooled.idle)
+        };
+        let mut request = request.clone();
+        request.target = url.target.clone();
+        request.headers.set("Connection", "keep-alive");
+        let (mut answered, mut closed) = (0, 0);
+        // pinged without the lock, so the calls are not blocked by the connections that do not answer
+        for connection in idle {
+            if connection.address != address || !connection.is_usable(self.idle_timeout) {
+                closed += 1;
+                continue;
+            }
+            match exchange(&connection.stream, url, &request, timeout) {
+                Ok(response) => {
+                    self.release(key, address, connection.stream, response);
+                    answered += 1;
+                }
+                Err(_) => closed += 1,
+            }
+        }
+        (answered, closed)
+    }
+
+    /// Close the connections of the destinations that are not in the list and stop warming them up
+    pub fn retain(&self, keys: &[&str]) {
+        self.connections.lock().unwrap().retain(|key, _| keys.contains(&key.as_str()));
+    }
+
+    /// Return the idle connections and the warmup target of each destination, by destination ID
+    pub fn warm_counts(&self) -> BTreeMap<String, (usize, usize)> {
+        self.connections.lock().unwrap().iter()
+            .map(|(key, pooled)| (key.clone(), (pooled.idle.len(), pooled.warm_target)))
+            .collect()
+    }
+}
+
+/// Send the request through the connection and read its response
+fn exchange(stream: &TcpStream, url: &HttpUrl, request: &HttpRequest, timeout: Duration) -> Result<HttpResponse, HttpError> {
+    stream.set_read_timeout(Some(timeout))?;
+    stream.set_write_timeout(Some(timeout))?;
+    let mut writer = stream;
+    write_request(&mut writer, &url.authority(), request)?;
+    read_response(&mut BufReader::new(stream))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{atomic::{AtomicUsize, Ordering}, Arc};
+
+    use crate::net::http::{HttpHandler, HttpServer};
+
+    use super::*;
+
+    #[test]
+    fn test_if_warm_connections_are_reused_by_the_requests() {
+        let requests = Arc::new(AtomicUsize::new(0));
+        let counter = requests.clone();
+        let handler: Arc<HttpHandler> = Arc::new(move |_: &HttpRequest| {
+            counter.fetch_add(1, Ordering::SeqCst);
+            HttpResponse::with_body(200, "text/plain", "ok")
+        });
+        let server = HttpServer::bind("127.0.0.1:0", handler).unwrap();
+        let address = server.local_addr();
+        let url = HttpUrl::parse(&format!("http://{}/hooks", address)).unwrap();
+        let pool = ConnectionPool::default();
+
+        assert_eq!(pool.warm("r", address, 2, Duration::from_secs(1)), 2);
+        assert_eq!(pool.warm_counts().get("r"), Some(&(2, 2)));
+        for _ in 0..3 {
+            let response = pool.send("r", address, &url, HttpRequest::new("POST", "/hooks"), Duration::from_secs(1)).unwrap();
+            assert_eq!(response.status, 200);
+        }
+        assert_eq!(requests.load(Ordering::SeqCst), 3);
+        assert_eq!(pool.warm_counts().get("r"), Some(&(2, 2)));
+
+        pool.retain(&[]);
+        assert!(pool.warm_counts().is_empty());
+        let idle = ConnectionPool::new(Duration::ZERO);
+        idle.put("r", address, TcpStream::connect(address).unwrap());
+        assert!(idle.take("r", address).is_none());
+    }
+}
@@ -0,0 +1,1587 @@
+use std::{
+    collections::{BTreeMap, HashSet},
+    io,
+    net::{SocketAddr, ToSocketAddrs},
+    sync::{atomic::{AtomicBool, AtomicU32, Ordering}, mpsc::{self, RecvTimeoutError, Sender}, Arc, Mutex},
+    thread::{self, JoinHandle},
+    time::Duration,
+};
+
+use time::{Duration as TimeDuration, OffsetDateTime};
+
+#[cfg(feature = "chaos")]
+use crate::chaos::FaultInjector;
+use crate::{
+    cluster::{
+        antientropy::MessageDigest,
+        features::{local_node_id, parse_protocol_version, ClusterFeature, FeatureGate, NODE_HEADER, PROTOCOL_VERSION, PROTOCOL_VERSION_HEADER},
+        join::{is_valid_node_id, node_credential, verify_node_credential, JoinRegistry},
+        readiness::{Readiness, ReadinessCheck, ReadinessProbe},
+        skew::{clock_header, ClockSkewMonitor, CLOCK_HEADER},
+    },
+    ctx::{appenv::ApplicationRoles, config::ClusterConfiguration},
+    db::{MessageQuery, MessageStore, PurgeScope, StoreError, StoreWrite},
+    log,
+    msgproc::{
+        message::{Annotation, AttemptOutcome, AttemptRecord, DeliveryError, DeliveryErrorClass, Message, MessageStatus},
+        retry::RetryPolicy,
+    },
+    net::{
+        admin::constant_time_eq,
+        client::restful::{error_response, json_response},
+        http::{send_request, ConnectionLimits, HttpError, HttpHandler, HttpHeaders, HttpRequest, HttpResponse, HttpServer, HttpUrl},
+        pool::ConnectionPool,
+    },
+    syscom::usage::UsageRecord,
+    utils::{base64, json::JsonValue, log::Level, lz4, time::{DurationSequence, SequenceTail}},
+};
+
+/// The port the store is served on by a storage node without `cluster.storage.address` or
+/// `cluster.storage.port`
+pub const DEFAULT_STORAGE_PORT: u16 = 2462;
+
+/// How long a message-processor node waits for the storage node when `cluster.requestTimeout`
+/// is not set
+pub const DEFAULT_STORAGE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// The path prefix of the store calls. Each call is a `POST` of its JSON arguments to
+/// `/cluster/store/<method>`, answered with `{"result": ...}` or with the error of the store
+const STORE_PATH: &str = "/cluster/store/";
+
+/// The path of the members and the features negotiated with them
+const FEATURES_PATH: &str = "/cluster/features";
+
+/// The path of the keepalive pings, answered with `204`
+const PING_PATH: &str = "/cluster/ping";
+
+/// The path of the members with the skews of their clocks
+const NODES_PATH: &str = "/cluster/nodes";
+
+/// The path of the health of the storage node as the controller of the members that call it
+const CONTROLLER_PATH: &str = "/cluster/controller";
+
+/// The path of the readiness of the node, answered without the `cluster.authKey` as it is probed
+/// by the load balancers
+const READY_PATH: &str = "/readyz";
+
+/// How many pings in a row the storage node can miss before it is considered unreachable when
+/// `cluster.keepalive.threshold` is not set
+pub const DEFAULT_KEEPALIVE_THRESHOLD: u32 = 3;
+
+/// The key of the connections to the storage node in the pool of a RemoteStore
+const STORAGE_CONNECTIONS: &str = "storage";
+
+/// The path where a new node trades a join token for its node credential
+const JOIN_PATH: &str = "/cluster/join";
+
+/// The content coding of the compressed calls, a LZ4 block prefixed by its length
+const LZ4_ENCODING: &str = "lz4";
+
+/// Smaller bodies are sent without compression, as they would barely shrink
+const COMPRESSION_MIN_SIZE: usize = 1024;
+
+/// How the bodies of the cluster calls are compressed, set by `cluster.compression`. Nodes
+/// advertise the compression in the `Accept-Encoding` of their requests and responses, and a body
+/// is only compressed when the other node advertised it, so nodes without it keep working
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ClusterCompression {
+    None,
+    Lz4,
+}
+
+impl ClusterCompression {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ClusterCompression::None => "none",
+            ClusterCompression::Lz4 => "lz4",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<ClusterCompression> {
+        match name {
+            "none" => Some(ClusterCompression::None),
+            "lz4" => Some(ClusterCompression::Lz4),
+            _ => None,
+        }
+    }
+}
+
+/// Serve a MessageStore to the message-processor nodes of the cluster. It is opened by the
+/// nodes with the storage role, and requires the `cluster.authKey`, or a node credential signed
+/// by it, when it is set
+pub struct StoreServer {
+    store: Arc<dyn MessageStore>,
+    auth_key: Option<String>,
+    /// The last time the store was repaired by the anti-entropy of its primary, when it is a replica
+    synced_at: Mutex<Option<OffsetDateTime>>,
+    compression: ClusterCompression,
+    /// The versions of the nodes that call this one
+    features: FeatureGate,
+    /// The skews of the clocks of the nodes that call this one
+    skews: ClockSkewMonitor,
+    joins: JoinRegistry,
+    /// The IDs of the join tokens whose node credentials are refused
+    revoked_join_tokens: HashSet<String>,
+    readiness: Arc<ReadinessProbe>,
+    /// Refuses the calls of the nodes partitioned from this one
+    #[cfg(feature = "chaos")]
+    faults: Option<Arc<FaultInjector>>,
+}
+
+impl StoreServer {
+    pub fn new(store: Arc<dyn MessageStore>) -> StoreServer {
+        StoreServer {
+            readiness: Arc::new(ReadinessProbe::new(&[ApplicationRoles::Storage], store.clone())),
+            store,
+            auth_key: None,
+            synced_at: Mutex::new(None),
+            compression: ClusterCompression::None,
+            features: FeatureGate::default(),
+            skews: ClockSkewMonitor::default(),
+            joins: JoinRegistry::new(),
+            revoked_join_tokens: HashSet::new(),
+            #[cfg(feature = "chaos")]
+            faults: None,
+        }
+    }
+
+    /// Refuse the calls of the nodes in the `partitionedNodes` of the FaultInjector
+    #[cfg(feature = "chaos")]
+    pub fn with_faults(mut self, faults: Arc<FaultInjector>) -> StoreServer {
+        self.faults = Some(faults);
+        self
+    }
+
+    /// Answer `GET /readyz` with the checks of the probe. It defaults to the store check of the
+    /// storage role
+    pub fn with_readiness(mut self, readiness: Arc<ReadinessProbe>) -> StoreServer {
+        self.readiness = readiness;
+        self
+    }
+
+    /// Require the key as a `Bearer` token in every call
+    pub fn with_auth_key(mut self, auth_key: String) -> StoreServer {
+        self.auth_key = Some(auth_key);
+        self
+    }
+
+    /// Refuse the join tokens of the IDs and the credentials of the nodes that joined with them
+    pub fn with_revoked_join_tokens(mut self, token_ids: HashSet<String>) -> StoreServer {
+        self.revoked_join_tokens = token_ids;
+        self
+    }
+
+    /// Compress the responses of the nodes that accept the compression, once every node that calls
+    /// this one supports it. Compressed requests are always accepted
+    pub fn with_compression(mut self, compression: ClusterCompression) -> StoreServer {
+        self.compression = compression;
+        self
+    }
+
+    /// Warn about the nodes whose clock is more than the threshold from the one of this node, and
+    /// refuse their store calls when `fence` is on
+    pub fn with_clock_skew(mut self, threshold: time::Duration, fence: bool) -> StoreServer {
+        self.skews = ClockSkewMonitor::new(threshold, fence);
+        self
+    }
+
+    /// Start a HttpServer that serves the store on the address
+    pub fn listen<A: ToSocketAddrs>(server: Arc<StoreServer>, address: A) -> io::Result<HttpServer> {
+        StoreServer::listen_with_limits(server, address, ConnectionLimits::default())
+    }
+
+    /// Start a HttpServer like `listen`, refusing the connections beyond the limits
+    pub fn listen_with_limits<A: ToSocketAddrs>(server: Arc<StoreServer>, address: A, limits: ConnectionLimits) -> io::Result<HttpServer> {
+        let handler: Arc<HttpHandler> = Arc::new(move |request: &HttpRequest| server.handle(request));
+        HttpServer::bind_with_limits(address, handler, limits)
+    }
+
+    /// Return the versions of the nodes that call this one and the features enabled with them
+    pub fn features(&self) -> &FeatureGate {
+        &self.features
+    }
+
+    /// Return the skews of the clocks of the nodes that call this one
+    pub fn clock_skews(&self) -> &ClockSkewMonitor {
+        &self.skews
+    }
+
+    /// Handle a store call, a `GET /cluster/features`, a `GET /cluster/nodes`, a
+    /// `GET /cluster/controller`, a `GET /cluster/ping`, a `POST /cluster/join` or a `GET /readyz`
+    pub fn handle(&self, request: &HttpRequest) -> HttpResponse {
+        #[cfg(feature = "chaos")]
+        if let (Some(faults), Some(node_id)) = (&self.faults, request.headers.get(NODE_HEADER)) {
+            if faults.is_partitioned(node_id.trim()) {
+                return error_response(503, &format!("the node {} is partitioned from this one by the chaos faults", node_id.trim()));
+            }
+        }
+        let path = request.path();
+        let mut response = if path == JOIN_PATH {
+            self.join(request)
+        } else if path == READY_PATH {
+            match request.method.as_str() {
+                "GET" => {
+                    let readiness = self.readiness.check();
+                    json_response(readiness.status(), &readiness.to_json())
+                }
+                _ => error_response(405, "method not allowed"),
+            }
+        } else if ![FEATURES_PATH, NODES_PATH, CONTROLLER_PATH, PING_PATH].contains(&path) && !path.starts_with(STORE_PATH) {
+            return error_response(404, "not found");
+        } else if !self.is_authorized(request) {
+            error_response(401, "the cluster.authKey or a node credential is required")
+        } else {
+            match path.strip_prefix(STORE_PATH) {
+                Some(method) => self.handle_call(method, request),
+                None if request.method != "GET" => error_response(405, "method not allowed"),
+                None if path == PING_PATH => {
+                    self.observe_clock(request);
+                    HttpResponse::new(204)
+                }
+                None if path == NODES_PATH => json_response(200, &self.nodes_to_json()),
+                None if path == CONTROLLER_PATH => {
+                    let readiness = self.readiness.check();
+                    json_response(readiness.status(), &self.controller_to_json(&readiness))
+                }
+                None => json_response(200, &self.features.to_json()),
+            }
+        };
+        response.headers.set(PROTOCOL_VERSION_HEADER, &PROTOCOL_VERSION.to_string());
+        response.headers.set(NODE_HEADER, local_node_id());
+        response
+    }
+
+    /// Accept the `cluster.authKey` or the credential of a node that joined with a token
+    fn is_authorized(&self, request: &HttpRequest) -> bool {
+        let Some(auth_key) = &self.auth_key else {
+            return true;
+        };
+        let Some(sent) = bearer_token(request) else {
+            return false;
+        };
+        constant_time_eq(sent.as_bytes(), auth_key.as_bytes())
+            || verify_node_credential(auth_key, sent).is_some_and(|credential| !self.revoked_join_tokens.contains(&credential.token_id))
+    }
+
+    /// Trade a join token, sent as the `Bearer` token, for the credential of the node. The node is
+    /// the one of the node header, or the ID of the token
+    fn join(&self, request: &HttpRequest) -> HttpResponse {
+        if request.method != "POST" {
+            return error_response(405, "method not allowed");
+        }
+        let Some(auth_key) = &self.auth_key else {
+            return error_response(409, "the cluster has no cluster.authKey, nodes join it without a token");
+        };
+        let Some(sent) = bearer_token(request) else {
+            return error_response(401, "a join token is required");
+        };
+        // the node ID is checked before the token is redeemed, so a bad ID does not use it up
+        let node_id = match request.headers.get(NODE_HEADER).map(str::trim) {
+            Some(node_id) if is_valid_node_id(node_id) => Some(node_id.to_string()),
+            Some(node_id) => return error_response(400, &format!("{} is not a valid node ID", node_id)),
+            None => None,
+        };
+        let token = match self.joins.redeem(auth_key, sent, OffsetDateTime::now_utc()) {
+            Ok(token) if self.revoked_join_tokens.contains(&token.id) => return error_response(401, "the join token was revoked"),
+            Ok(token) => token,
+            Err(err) => return error_response(401, &err.to_string()),
+        };
+        let node_id = node_id.unwrap_or_else(|| token.id.clone());
+        log!(Level::Info, "The node {} joined the cluster with the join token {}", node_id, token.id);
+        json_response(200, &JsonValue::object()
+            .with("credential", node_credential(auth_key, &node_id, &token.id))
+            .with("nodeId", node_id)
+            .with("tokenId", token.id))
+    }
+
+    fn observe_clock(&self, request: &HttpRequest) {
+        self.skews.observe(request.headers.get(NODE_HEADER), request.headers.get(CLOCK_HEADER), OffsetDateTime::now_utc());
+    }
+
+    /// Serialize the members into the JSON of `GET /cluster/nodes`, with the skews of their clocks
+    fn nodes_to_json(&self) -> JsonValue {
+        let nodes = self.features.members().into_iter()
+            .map(|(node_id, version)| {
+                let skew = self.skews.skew(&node_id);
+                JsonValue::object()
+                    .with("nodeId", node_id.as_str())
+                    .with("protocolVersion", version)
+                    .with("clockSkewMs", skew.map(|skew| skew.whole_milliseconds() as f64))
+                    .with("fenced", self.skews.is_fenced(Some(&node_id)))
+            })
+            .collect::<Vec<_>>();
+        JsonValue::object()
+            .with("clockSkewThresholdMs", self.skews.threshold().whole_milliseconds() as f64)
+            .with("fencing", self.skews.fences())
+            .with("nodes", nodes)
+    }
+
+    /// Serialize the health of this node as the controller into the JSON of `GET /cluster/controller`.
+    /// There is no leader election yet: the storage node coordinates its members without a lease,
+    /// so only the state it keeps is reported
+    fn controller_to_json(&self, readiness: &Readiness) -> JsonValue {
+        let members = self.features.members();
+        let fenced = members.iter()
+            .filter(|(node_id, _)| self.skews.is_fenced(Some(node_id)))
+            .map(|(node_id, _)| JsonValue::from(node_id.as_str()))
+            .collect::<Vec<_>>();
+        let failed = readiness.checks.iter()
+            .filter(|check| check.failure.is_some())
+            .map(ReadinessCheck::to_json)
+            .collect::<Vec<_>>();
+        JsonValue::object()
+            .with("nodeId", local_node_id())
+            .with("leadership", "static")
+            .with("ready", readiness.is_ready())
+            .with("failedChecks", failed)
+            .with("members", members.len())
+            .with("fencedMembers", fenced)
+            .with("negotiatedVersion", self.features.negotiated_version())
+            .with("syncedAt", self.synced_at.lock().unwrap().map(time_to_json))
+    }
+
+    fn handle_call(&self, method: &str, request: &HttpRequest) -> HttpResponse {
+        // only the callers of the store are members, not the operators reading the features
+        let node_id = request.headers.get(NODE_HEADER);
+        self.features.observe(node_id, parse_protocol_version(request.headers.get(PROTOCOL_VERSION_HEADER)));
+        self.observe_clock(request);
+        if self.skews.is_fenced(node_id) {
+            return error_response(503, "the clock of this node is more than the cluster.clockSkew.threshold from the one of the storage node");
+        }
+        if request.method != "POST" {
+            return error_response(405, "method not allowed");
+        }
+        let body = match decode_body(&request.headers, &request.body) {
+            Ok(body) => body,
+            Err(err) => return error_response(415, &err),
+        };
+        let arguments = match JsonValue::parse_bytes(&body) {
+            Ok(arguments) => arguments,
+            Err(err) => return error_response(400, &format!("body is not valid JSON: {}", err)),
+        };
+        let mut response = match self.call(method, &arguments) {
+            Ok(Ok(result)) => json_response(200, &JsonValue::object().with("result", result)),
+            Ok(Err(err)) => json_response(500, &store_error_to_json(&err)),
+            Err(CallError::UnknownMethod) => error_response(404, &format!("{} is not a store method", method)),
+            Err(CallError::InvalidArguments(err)) => error_response(400, &err),
+        };
+        if self.compression == ClusterCompression::Lz4 && self.features.is_enabled(ClusterFeature::Lz4Compression) {
+            response.headers.set("Accept-Encoding", LZ4_ENCODING);
+            if accepts_lz4(&request.headers) {
+                encode_body(&mut response.headers, &mut response.body);
+            }
+        }
+        response
+    }
+
+    fn call(&self, method: &str, arguments: &JsonValue) -> Result<Result<JsonValue, StoreError>, CallError> {
+        let store = self.store.as_ref();
+        Ok(match method {
+            "writeBatch" => {
+                let writes = field(arguments, "writes")?.as_array().ok_or_else(|| invalid("writes should be an array"))?
+                    .iter()
+                    .map(write_from_json)
+                    .collect::<Result<Vec<StoreWrite>, String>>()
+                    .map_err(CallError::InvalidArguments)?;
+                store.write_batch(&writes).map(|_| JsonValue::Null)
+            }
+            "getMessage" => store.get_message(&string_field(arguments, "messageId")?)
+                .map(|message| message.as_ref().map(message_to_json).into()),
+            "getAttempts" => store.get_attempts(&string_field(arguments, "messageId")?)
+                .map(|attempts| JsonValue::Array(attempts.iter().map(attempt_to_json).collect())),
+            "findMessages" => {
+                let query = query_from_json(field(arguments, "query")?).map_err(CallError::InvalidArguments)?;
+                store.find_messages(&query).map(|messages| JsonValue::Array(messages.iter().map(message_to_json).collect()))
+            }
+            "findByProducerMessageId" => store.find_by_producer_message_id(
+                &string_field(arguments, "namespace")?,
+                &string_field(arguments, "topic")?,
+                &string_field(arguments, "producerMessageId")?,
+            ).map(|message| message.as_ref().map(message_to_json).into()),
+            "purgeFinished" => {
+                let status = status_from_json(field(arguments, "status")?).map_err(CallError::InvalidArguments)?;
+                let finished_before = time_from_json(field(arguments, "finishedBefore")?).map_err(CallError::InvalidArguments)?;
+                let scope = scope_from_json(arguments.get("scope").unwrap_or(&JsonValue::Null)).map_err(CallError::InvalidArguments)?;
+                store.purge_finished(status, finished_before, &scope).map(JsonValue::from)
+            }
+            "merkleLeaves" => store.merkle_leaves(leaves_field(arguments)?)
+                .map(|hashes| JsonValue::Array(hashes.into_iter().map(|hash| JsonValue::from(format!("{:016x}", hash))).collect())),
+            "messageDigests" => {
+                let selected = match field(arguments, "selected")? {
+                    JsonValue::Null => None,
+                    selected => Some(array(selected, |leaf| leaf.as_u64().map(|leaf| leaf as usize).ok_or_else(|| String::from("selected should have leaf indexes")))
+                        .map_err(CallError::InvalidArguments)?),
+                };
+                store.message_digests(leaves_field(arguments)?, selected.as_deref())
+                    .map(|digests| JsonValue::Array(digests.iter().map(digest_to_json).collect()))
+            }
+            "markSynced" => {
+                let at = time_from_json(field(arguments, "at")?).map_err(CallError::InvalidArguments)?;
+                let mut synced_at = self.synced_at.lock().unwrap();
+                // repairs of different primaries may finish out of order
+                *synced_at = Some(synced_at.map_or(at, |synced_at| synced_at.max(at)));
+                Ok(JsonValue::Null)
+            }
+            "syncedAt" => Ok(self.synced_at.lock().unwrap().map(time_to_json).into()),
+            "recordUsage" => {
+                let records = array(field(arguments, "records")?, usage_from_json).map_err(CallError::InvalidArguments)?;
+                store.record_usage(&records).map(|_| JsonValue::Null)
+            }
+            "findUsage" => {
+                let from = time_from_json(field(arguments, "from")?).map_err(CallError::InvalidArguments)?;
+                let to = time_from_json(field(arguments, "to")?).map_err(CallError::InvalidArguments)?;
+                store.find_usage(from, to).map(|records| JsonValue::Array(records.iter().map(usage_to_json).collect()))
+            }
+            _ => return Err(CallError::UnknownMethod),
+        })
+    }
+}
+
+enum CallError {
+    UnknownMethod,
+    InvalidArguments(String),
+}
+
+fn invalid(message: &str) -> CallError {
+    CallError::InvalidArguments(message.to_string())
+}
+
+fn field<'a>(arguments: &'a JsonValue, name: &str) -> Result<&'a JsonValue, CallError> {
+    arguments.get(name).ok_or_else(|| CallError::InvalidArguments(format!("{} is required", name)))
+}
+
+fn leaves_field(arguments: &JsonValue) -> Result<usize, CallError> {
+    field(arguments, "leaves")?.as_u64().filter(|leaves| *leaves >= 1).map(|leaves| leaves as usize).ok_or_else(|| invalid("leaves should be a integer >= 1"))
+}
+
+fn string_field(arguments: &JsonValue, name: &str) -> Result<String, CallError> {
+    field(arguments, name)?.as_str().map(str::to_string).ok_or_else(|| CallError::InvalidArguments(format!("{} should be a string", name)))
+}
+
+/// A MessageStore of a storage node, used by the message-processor nodes without the storage role.
+/// Every call is sent to the storage node, so the batches of the BatchedStoreWriter are also the
+/// batches sent over the network
+pub struct RemoteStore {
+    base_url: String,
+    auth_key: Option<String>,
+    timeout: Duration,
+    compression: ClusterCompression,
+    /// If the storage node advertised the compression in its last response
+    peer_accepts_compression: AtomicBool,
+    /// The protocol version of the storage node in its last response, 0 before the first one
+    peer_version: AtomicU32,
+    /// The connections kept open between the calls, when they are pinged by the keepalive
+    connections: Option<ConnectionPool>,
+    /// How many pings in a row the storage node can miss before the calls fail without waiting for it
+    liveness_threshold: u32,
+    missed_pings: AtomicU32,
+    /// Fails the calls to the storage node when it is partitioned from this one
+    #[cfg(feature = "chaos")]
+    faults: Option<Arc<FaultInjector>>,
+    /// The node ID of the storage node in its last response
+    #[cfg(feature = "chaos")]
+    peer_node_id: Mutex<Option<String>>,
+}
+
+impl RemoteStore {
+    /// Use the store served on the base URL of a storage node, like `http://storage-1:2462`
+    pub fn new(base_url: &str) -> RemoteStore {
+        RemoteStore {
+            base_url: base_url.trim_end_matches('/').to_string(),
+            auth_key: None,
+            timeout: DEFAULT_STORAGE_TIMEOUT,
+            compression: ClusterCompression::None,
+            peer_accepts_compression: AtomicBool::new(false),
+            peer_version: AtomicU32::new(0),
+            connections: None,
+            liveness_threshold: DEFAULT_KEEPALIVE_THRESHOLD,
+            missed_pings: AtomicU32::new(0),
+            #[cfg(feature = "chaos")]
+            faults: None,
+            #[cfg(feature = "chaos")]
+            peer_node_id: Mutex::new(None),
+        }
+    }
+
+    /// Use the storage node of `cluster.storage.url` with the `cluster.authKey` and the
+    /// `cluster.requestTimeout`. None when the URL is not set
+    pub fn from_configuration(cluster: &ClusterConfiguration) -> Option<RemoteStore> {
+        Some(RemoteStore::for_node(cluster.storage_url.as_deref()?, cluster))
+    }
+
+    /// Use the storage node on the base URL with the `cluster.authKey`, the `cluster.requestTimeout`
+    /// and the `cluster.compression`
+    pub fn for_node(base_url: &str, cluster: &ClusterConfiguration) -> RemoteStore {
+        let mut store = RemoteStore::new(base_url);
+        store.auth_key = cluster.auth_key.clone();
+        store.compression = cluster.compression.unwrap_or(ClusterCompression::None);
+        if let Some(timeout) = cluster.request_timeout.and_then(|timeout| Duration::try_from(timeout).ok()) {
+            store.timeout = timeout;
+        }
+        store
+    }
+
+    /// Send the key as a `Bearer` token in every call
+    pub fn with_auth_key(mut self, auth_key: String) -> RemoteStore {
+        self.auth_key = Some(auth_key);
+        self
+    }
+
+    /// Set how long each call waits for the storage node
+    pub fn with_timeout(mut self, timeout: Duration) -> RemoteStore {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Accept compressed responses, and compress the calls once the storage node accepts them
+    pub fn with_compression(mut self, compression: ClusterCompression) -> RemoteStore {
+        self.compression = compression;
+        self
+    }
+
+    /// Keep the connections to the storage node open between the calls, so they are pinged by
+    /// `start_keepalive`, and fail the calls without waiting for the storage node once it misses
+    /// `threshold` pings in a row
+    pub fn with_keepalive(mut self, threshold: u32) -> RemoteStore {
+        self.connections = Some(ConnectionPool::default());
+        self.liveness_threshold = threshold.max(1);
+        self
+    }
+
+    /// Fail the calls and the pings to the storage node once its node ID, learned from its
+    /// responses, is in the `partitionedNodes` of the FaultInjector
+    #[cfg(feature = "chaos")]
+    pub fn with_faults(mut self, faults: Arc<FaultInjector>) -> RemoteStore {
+        self.faults = Some(faults);
+        self
+    }
+
+    /// Return the error of a call to the storage node while it is partitioned from this one. The
+    /// node is checked before the request is sent and again with the node ID of its response, so
+    /// the first response of a partitioned node is lost like it would be in a real partition
+    #[cfg(feature = "chaos")]
+    fn partitioned(&self, response: Option<&HttpResponse>) -> Result<(), StoreError> {
+        let Some(faults) = &self.faults else {
+            return Ok(());
+        };
+        let mut peer_node_id = self.peer_node_id.lock().unwrap();
+        if let Some(node_id) = response.and_then(|response| response.headers.get(NODE_HEADER)) {
+            *peer_node_id = Some(node_id.trim().to_string());
+        }
+        match peer_node_id.as_deref() {
+            Some(node_id) if faults.is_partitioned(node_id) => {
+                Err(StoreError::Backend(format!("the storage node {} is partitioned from this node by the chaos faults", self.base_url)))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    #[cfg(not(feature = "chaos"))]
+    fn partitioned(&self, _: Option<&HttpResponse>) -> Result<(), StoreError> {
+        Ok(())
+    }
+
+    /// Return if the storage node answered one of its last pings, always true before the first one
+    pub fn is_reachable(&self) -> bool {
+        self.missed_pings.load(Ordering::Relaxed) < self.liveness_threshold
+    }
+
+    fn url(&self, path: &str) -> Result<HttpUrl, StoreError> {
+        HttpUrl::parse(&format!("{}{}", self.base_url, path))
+            .map_err(|err| StoreError::Backend(format!("the storage URL {} is invalid: {}", self.base_url, err)))
+    }
+
+    fn request(&self, method: &str, url: &HttpUrl) -> HttpRequest {
+        let mut request = HttpRequest::new(method, &url.target);
+        request.headers.set(PROTOCOL_VERSION_HEADER, &PROTOCOL_VERSION.to_string());
+        request.headers.set(NODE_HEADER, local_node_id());
+        request.headers.set(CLOCK_HEADER, &clock_header(OffsetDateTime::now_utc()));
+        if let Some(auth_key) = &self.auth_key {
+            request.headers.set("Authorization", &format!("Bearer {}", auth_key));
+        }
+        request
+    }
+
+    /// Send the request through a kept connection when the keepalive is on, else through a new one
+    fn send(&self, url: &HttpUrl, request: HttpRequest) -> Result<HttpResponse, HttpError> {
+        match &self.connections {
+            Some(connections) => connections.send(STORAGE_CONNECTIONS, resolve(url)?, url, request, self.timeout),
+            None => send_request(url, request, self.timeout),
+        }
+    }
+
+    /// Ping the kept connections to the storage node, closing the ones that do not answer, and
+    /// open a new one when none answered. Return if the storage node answered
+    pub fn ping(&self) -> bool {
+        let answered = match self.url(PING_PATH) {
+            Ok(_) if self.partitioned(None).is_err() => false,
+            Ok(url) => {
+                let request = self.request("GET", &url);
+                let pinged = match (&self.connections, resolve(&url)) {
+                    (Some(connections), Ok(address)) => connections.ping(STORAGE_CONNECTIONS, address, &url, &request, self.timeout).0 > 0,
+                    _ => false,
+                };
+                // any answer, even from a storage node without the pings, shows that it is reachable
+                pinged || self.send(&url, request).is_ok_and(|response| self.partitioned(Some(&response)).is_ok())
+            }
+            Err(_) => false,
+        };
+
+        if answered {
+            if !self.is_reachable() {
+                log!(Level::Info, "The storage node {} is reachable again", self.base_url);
+            }
+            self.missed_pings.store(0, Ordering::Relaxed);
+        } else if self.missed_pings.fetch_add(1, Ordering::Relaxed) + 1 == self.liveness_threshold {
+            log!(Level::Warn, "The storage node {} missed {} pings in a row and is considered unreachable", self.base_url, self.liveness_threshold);
+        }
+        answered
+    }
+
+    /// Ping the storage node on a background thread every `interval`
+    pub fn start_keepalive(store: Arc<RemoteStore>, interval: Duration) -> KeepaliveHandle {
+        let (sender, receiver) = mpsc::channel::<()>();
+        let thread = thread::Builder::new()
+            .name(String::from("angler-cluster-keepalive"))
+            .spawn(move || loop {
+                match receiver.recv_timeout(interval) {
+                    Ok(()) | Err(RecvTimeoutError::Disconnected) => return,
+                    Err(RecvTimeoutError::Timeout) => {
+                        store.ping();
+                    }
+                }
+            })
+            .expect("failed to spawn the cluster keepalive");
+
+        KeepaliveHandle { sender, thread: Some(thread) }
+    }
+
+    fn call(&self, method: &str, arguments: JsonValue) -> Result<JsonValue, StoreError> {
+        if !self.is_reachable() {
+            return Err(StoreError::Backend(format!("the storage node {} missed its last {} pings", self.base_url, self.liveness_threshold)));
+        }
+        self.partitioned(None)?;
+        let url = self.url(&format!("{}{}", STORE_PATH, method))?;
+        let mut request = self.request("POST", &url);
+        request.headers.set("Content-Type", "application/json");
+        request.body = arguments.to_string().into_bytes();
+        if self.compression == ClusterCompression::Lz4 {
+            request.headers.set("Accept-Encoding", LZ4_ENCODING);
+            if self.peer_accepts_compression.load(Ordering::Relaxed) {
+                encode_body(&mut request.headers, &mut request.body);
+            }
+        }
+
+        let response = self.send(&url, request)
+            .map_err(|err| StoreError::Backend(format!("failed to call the storage node {}: {}", self.base_url, err)))?;
+        self.partitioned(Some(&response))?;
+        self.peer_version.store(parse_protocol_version(response.headers.get(PROTOCOL_VERSION_HEADER)), Ordering::Relaxed);
+        if self.compression == ClusterCompression::Lz4 {
+            self.peer_accepts_compression.store(accepts_lz4(&response.headers), Ordering::Relaxed);
+        }
+        let body = decode_body(&response.headers, &response.body)
+            .map_err(|err| StoreError::Backend(format!("the storage node answered {} with a invalid body: {}", response.status, err)))?;
+        let body = JsonValue::parse_bytes(&body)
+            .map_err(|err| StoreError::Backend(format!("the storage node answered {} with a invalid body: {}", response.status, err)))?;
+        match response.status {
+            200 => Ok(body.get("result").cloned().unwrap_or(JsonValue::Null)),
+            _ => Err(store_error_from_json(response.status, &body)),
+        }
+    }
+
+    /// Return if the storage node may lack the feature. Before the first call it is assumed to have it
+    fn lacks(&self, feature: ClusterFeature) -> bool {
+        let version = self.peer_version.load(Ordering::Relaxed);
+        version != 0 && version < feature.required_version()
+    }
+
+    fn decode<T>(&self, method: &str, result: Result<T, String>) -> Result<T, StoreError> {
+        result.map_err(|err| StoreError::Backend(format!("the storage node answered {} with a invalid result: {}", method, err)))
+    }
+}
+
+impl MessageStore for RemoteStore {
+    fn write_batch(&self, writes: &[StoreWrite]) -> Result<(), StoreError> {
+        self.call("writeBatch", JsonValue::object().with("writes", JsonValue::Array(writes.iter().map(write_to_json).collect())))?;
+        Ok(())
+    }
+
+    fn get_message(&self, message_id: &str) -> Result<Option<Message>, StoreError> {
+        let result = self.call("getMessage", JsonValue::object().with("messageId", message_id))?;
+        self.decode("getMessage", optional(&result, message_from_json))
+    }
+
+    fn get_attempts(&self, message_id: &str) -> Result<Vec<AttemptRecord>, StoreError> {
+        let result = self.call("getAttempts", JsonValue::object().with("messageId", message_id))?;
+        self.decode("getAttempts", array(&result, attempt_from_json))
+    }
+
+    fn find_messages(&self, query: &MessageQuery) -> Result<Vec<Message>, StoreError> {
+        // a storage node without the feature would answer the first page again
+        if query.after.is_some() && self.lacks(ClusterFeature::CursorPagination) {
+            return Err(StoreError::Backend(format!("the storage node {} does not page the messages by cursor yet", self.base_url)));
+        }
+        let result = self.call("findMessages", JsonValue::object().with("query", query_to_json(query)))?;
+        self.decode("findMessages", array(&result, message_from_json))
+    }
+
+    fn find_by_producer_message_id(&self, namespace: &str, topic: &str, producer_message_id: &str) -> Result<Option<Message>, StoreError> {
+        let arguments = JsonValue::object()
+            .with("namespace", namespace)
+            .with("topic", topic)
+            .with("producerMessageId", producer_message_id);
+        let result = self.call("findByProducerMessageId", arguments)?;
+        self.decode("findByProducerMessageId", optional(&result, message_from_json))
+    }
+
+    fn purge_finished(&self, status: MessageStatus, finished_before: OffsetDateTime, scope: &PurgeScope) -> Result<usize, StoreError> {
+        let arguments = JsonValue::object()
+            .with("status", status.as_str())
+            .with("finishedBefore", time_to_json(finished_before))
+            .with("scope", scope_to_json(scope));
+        let result = self.call("purgeFinished", arguments)?;
+        self.decode("purgeFinished", result.as_u64().map(|purged| purged as usize).ok_or_else(|| String::from("expected a number")))
+    }
+
+    fn message_digests(&self, leaves: usize, selected: Option<&[usize]>) -> Result<Vec<MessageDigest>, StoreError> {
+        let selected = selected.map(|selected| JsonValue::from(selected.to_vec()));
+        let result = self.call("messageDigests", JsonValue::object().with("leaves", leaves).with("selected", selected))?;
+        self.decode("messageDigests", array(&result, digest_from_json))
+    }
+
+    fn merkle_leaves(&self, leaves: usize) -> Result<Vec<u64>, StoreError> {
+        let result = self.call("merkleLeaves", JsonValue::object().with("leaves", leaves))?;
+        self.decode("merkleLeaves", array(&result, hash_from_json))
+    }
+
+    fn mark_synced(&self, at: OffsetDateTime) -> Result<(), StoreError> {
+        // a replica that was not upgraded yet is still repaired, it only can not tell its staleness
+        if self.lacks(ClusterFeature::ReplicaSync) {
+            return Ok(());
+        }
+        self.call("markSynced", JsonValue::object().with("at", time_to_json(at)))?;
+        Ok(())
+    }
+
+    fn synced_at(&self) -> Result<Option<OffsetDateTime>, StoreError> {
+        if self.lacks(ClusterFeature::ReplicaSync) {
+            return Ok(None);
+        }
+        let result = self.call("syncedAt", JsonValue::object())?;
+        self.decode("syncedAt", optional(&result, time_from_json))
+    }
+
+    fn record_usage(&self, records: &[UsageRecord]) -> Result<(), StoreError> {
+        if self.lacks(ClusterFeature::UsageRecords) {
+            return Err(StoreError::Backend(format!("the storage node {} does not keep usage records until it is upgraded", self.base_url)));
+        }
+        self.call("recordUsage", JsonValue::object().with("records", JsonValue::Array(records.iter().map(usage_to_json).collect())))?;
+        Ok(())
+    }
+
+    fn find_usage(&self, from: OffsetDateTime, to: OffsetDateTime) -> Result<Vec<UsageRecord>, StoreError> {
+        if self.lacks(ClusterFeature::UsageRecords) {
+            return Ok(Vec::new());
+        }
+        let result = self.call("findUsage", JsonValue::object().with("from", time_to_json(from)).with("to", time_to_json(to)))?;
+        self.decode("findUsage", array(&result, usage_from_json))
+    }
+}
+
+/// Stop the keepalive pings when dropped
+pub struct KeepaliveHandle {
+    sender: Sender<()>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl KeepaliveHandle {
+    /// Stop the pings, waiting for the ping in progress
+    pub fn stop(&mut self) {
+        let _ = self.sender.send(());
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+impl Drop for KeepaliveHandle {
+    fn drop(&mut self) {
+        self.stop();
+    }
+}
+
+fn resolve(url: &HttpUrl) -> Result<SocketAddr, HttpError> {
+    (url.host.as_str(), url.port).to_socket_addrs()?
+        .next()
+        .ok_or_else(|| HttpError::InvalidUrl(format!("{} does not resolve to any address", url.host)))
+}
+
+/// Trade the join token for the credential of this node at the storage node on the base URL. The
+/// credential is sent instead of the `cluster.authKey` in the calls of the node
+pub fn join_cluster(base_url: &str, join_token: &str, timeout: Duration) -> Result<String, String> {
+    let url = HttpUrl::parse(&format!("{}{}", base_url.trim_end_matches('/'), JOIN_PATH)).map_err(|err| format!("the storage URL {} is invalid: {}", base_url, err))?;
+    let mut request = HttpRequest::new("POST", &url.target);
+    request.headers.set("Authorization", &format!("Bearer {}", join_token.trim()));
+    request.headers.set(PROTOCOL_VERSION_HEADER, &PROTOCOL_VERSION.to_string());
+    request.headers.set(NODE_HEADER, local_node_id());
+    let response = send_request(&url, request, timeout).map_err(|err| format!("failed to call the storage node {}: {}", base_url, err))?;
+    let body = JsonValue::parse_bytes(&response.body).unwrap_or(JsonValue::Null);
+    match (response.status, body.get("credential").and_then(JsonValue::as_str)) {
+        (200, Some(credential)) => Ok(credential.to_string()),
+        (status, _) => Err(format!("the storage node refused the join token with {}: {}", status, body.get("error").and_then(JsonValue::as_str).unwrap_or("no reason"))),
+    }
+}
+
+fn bearer_token(request: &HttpRequest) -> Option<&str> {
+    request.headers.get("Authorization").and_then(|value| value.strip_prefix("Bearer ")).map(str::trim)
+}
+
+/// Return if the `Accept-Encoding` of the headers has the LZ4 compression
+fn accepts_lz4(headers: &HttpHeaders) -> bool {
+    headers.get("Accept-Encoding").is_some_and(|encodings| encodings.split(',')
+        .any(|encoding| encoding.split(';').next().unwrap_or_default().trim().eq_ignore_ascii_case(LZ4_ENCODING)))
+}
+
+/// Compress the body when it is large enough and shrinks, setting its `Content-Encoding`
+fn encode_body(headers: &mut HttpHeaders, body: &mut Vec<u8>) {
+    if body.len() < COMPRESSION_MIN_SIZE {
+        return;
+    }
+    let compressed = lz4::compress(body);
+    if compressed.len() < body.len() {
+        *body = compressed;
+        headers.set("Content-Encoding", LZ4_ENCODING);
+    }
+}
+
+/// Return the body without the compression of its `Content-Encoding`
+fn decode_body(headers: &HttpHeaders, body: &[u8]) -> Result<Vec<u8>, String> {
+    match headers.get("Content-Encoding").map(str::trim) {
+        None | Some("identity") => Ok(body.to_vec()),
+        Some(encoding) if encoding.eq_ignore_ascii_case(LZ4_ENCODING) => lz4::decompress(body).map_err(|err| err.to_string()),
+        Some(encoding) => Err(format!("the content encoding {} is not supported", encoding)),
+    }
+}
+
+fn store_error_to_json(err: &StoreError) -> JsonValue {
+    let json = JsonValue::object().with("error", err.to_string());
+    match err {
+        StoreError::MessageNotFound(message_id) => json.with("kind", "messageNotFound").with("messageId", message_id.as_str()),
+        StoreError::WriterClosed => json.with("kind", "writerClosed"),
+        StoreError::Backend(reason) => json.with("kind", "backend").with("reason", reason.as_str()),
+        StoreError::IllegalTransition(_) => json.with("kind", "illegalTransition"),
+    }
+}
+
+/// Rebuild the error of the store of the storage node, so the message-processor nodes handle it
+/// like the errors of a local store
+fn store_error_from_json(status: u16, json: &JsonValue) -> StoreError {
+    let text = |key: &str| json.get(key).and_then(JsonValue::as_str).map(str::to_string);
+    match (json.get("kind").and_then(JsonValue::as_str), text("messageId"), text("reason")) {
+        (Some("messageNotFound"), Some(message_id), _) => StoreError::MessageNotFound(message_id),
+        (Some("writerClosed"), _, _) => StoreError::WriterClosed,
+        (Some("backend"), _, Some(reason)) => StoreError::Backend(reason),
+        _ => StoreError::Backend(format!("the storage node answered {}: {}", status, text("error").unwrap_or_default())),
+    }
+}
+
+// The messages are sent with all their fields. Times and durations are sent as strings of
+// nanoseconds, as JSON numbers can not hold them without losing precision
+
+fn time_to_json(time: OffsetDateTime) -> JsonValue {
+    JsonValue::from(time.unix_timestamp_nanos().to_string())
+}
+
+fn time_from_json(json: &JsonValue) -> Result<OffsetDateTime, String> {
+    json.as_str()
+        .and_then(|nanos| nanos.parse::<i128>().ok())
+        .and_then(|nanos| OffsetDateTime::from_unix_timestamp_nanos(nanos).ok())
+        .ok_or_else(|| format!("{} is not a time in nanoseconds", json))
+}
+
+fn status_from_json(json: &JsonValue) -> Result<MessageStatus, String> {
+    json.as_str().and_then(MessageStatus::from_name).ok_or_else(|| format!("{} is not a message status", json))
+}
+
+fn optional<T>(json: &JsonValue, decode: fn(&JsonValue) -> Result<T, String>) -> Result<Option<T>, String> {
+    if json.is_null() { Ok(None) } else { decode(json).map(Some) }
+}
+
+fn array<T>(json: &JsonValue, decode: fn(&JsonValue) -> Result<T, String>) -> Result<Vec<T>, String> {
+    json.as_array().ok_or_else(|| format!("{} is not an array", json))?.iter().map(decode).collect()
+}
+
+fn get<'a>(json: &'a JsonValue, key: &str) -> Result<&'a JsonValue, String> {
+    json.get(key).ok_or_else(|| format!("{} is missing", key))
+}
+
+fn get_str(json: &JsonValue, key: &str) -> Result<String, String> {
+    get(json, key)?.as_str().map(str::to_string).ok_or_else(|| format!("{} should be a string", key))
+}
+
+fn get_optional_str(json: &JsonValue, key: &str) -> Result<Option<String>, String> {
+    optional(json.get(key).unwrap_or(&JsonValue::Null), |value| value.as_str().map(str::to_string).ok_or_else(|| String::from("expected a string")))
+}
+
+fn get_u64(json: &JsonValue, key: &str) -> Result<u64, String> {
+    get(json, key)?.as_u64().ok_or_else(|| format!("{} should be a integer", key))
+}
+
+fn get_u16(json: &JsonValue, key: &str) -> Result<u16, String> {
+    u16::try_from(get_u64(json, key)?).map_err(|_| format!("{} should be a integer up to {}", key, u16::MAX))
+}
+
+fn string_map_to_json(map: &BTreeMap<String, String>) -> JsonValue {
+    JsonValue::Object(map.iter().map(|(key, value)| (key.clone(), JsonValue::from(value.as_str()))).collect())
+}
+
+fn string_map_from_json(json: &JsonValue, key: &str) -> Result<BTreeMap<String, String>, String> {
+    get(json, key)?.as_object().ok_or_else(|| format!("{} should be an object", key))?
+        .iter()
+        .map(|(name, value)| value.as_str().map(|value| (name.clone(), value.to_string())).ok_or_else(|| format!("{}.{} should be a string", key, name)))
+        .collect()
+}
+
+fn pairs_to_json(pairs: &[(String, String)]) -> JsonValue {
+    JsonValue::Array(pairs.iter().map(|(key, value)| JsonValue::Array(vec![key.as_str().into(), value.as_str().into()])).collect())
+}
+
+fn pairs_from_json(json: &JsonValue, key: &str) -> Result<Vec<(String, String)>, String> {
+    array(get(json, key)?, |pair| match pair.as_array().map(Vec::as_slice) {
+        Some([key, value]) => key.as_str().zip(value.as_str()).map(|(key, value)| (key.to_string(), value.to_string())).ok_or_else(|| String::from("pairs should have strings")),
+        _ => Err(String::from("pairs should be arrays of a key and a value")),
+    })
+}
+
+fn retry_policy_to_json(policy: &RetryPolicy) -> JsonValue {
+    let interval = policy.interval.as_ref().map(|interval| JsonValue::Array(
+        interval.sequence().iter().copied().map(nanos_to_json).collect()
+    ));
+    let tail = policy.interval.as_ref().and_then(DurationSequence::tail).map(|tail| match tail {
+        SequenceTail::Every(interval) => JsonValue::object().with("every", nanos_to_json(*interval)),
+        SequenceTail::Doubling { up_to } => JsonValue::object().with("doublingUpTo", nanos_to_json(*up_to)),
+    });
+    JsonValue::object().with("interval", interval).with("intervalTail", tail).with("maxAttempts", policy.max_attempts)
+}
+
+fn nanos_to_json(duration: TimeDuration) -> JsonValue {
+    JsonValue::from(duration.whole_nanoseconds().to_string())
+}
+
+fn nanos_from_json(duration: &JsonValue) -> Result<TimeDuration, String> {
+    duration.as_str()
+        .and_then(|nanos| nanos.parse::<i128>().ok())
+        .and_then(|nanos| i64::try_from(nanos).ok())
+        .map(TimeDuration::nanoseconds)
+        .ok_or_else(|| format!("{} is not a duration in nanoseconds", duration))
+}
+
+fn retry_policy_from_json(json: &JsonValue) -> Result<RetryPolicy, String> {
+    let interval = optional(get(json, "interval")?, |interval| {
+        let durations = array(interval, nanos_from_json)?;
+        DurationSequence::from_vec(durations).map_err(|err| err.to_string())
+    })?;
+    // missing on the messages written before the retry policies had tails
+    let tail = match json.get("intervalTail") {
+        None | Some(JsonValue::Null) => None,
+        Some(tail) => match (tail.get("every"), tail.get("doublingUpTo")) {
+            (Some(interval), _) => Some(SequenceTail::Every(nanos_from_json(interval)?)),
+            (_, Some(up_to)) => Some(SequenceTail::Doubling { up_to: nanos_from_json(up_to)? }),
+            _ => return Err(String::from("intervalTail should have every or doublingUpTo")),
+        },
+    };
+    let interval = match (interval, tail) {
+        (Some(interval), Some(tail)) => Some(interval.with_tail(tail)),
+        (interval, _) => interval,
+    };
+    Ok(RetryPolicy { interval, max_attempts: get_u16(json, "maxAttempts")? })
+}
+
+// the hashes use all the 64 bits, so they are sent as hexadecimal strings
+fn hash_from_json(json: &JsonValue) -> Result<u64, String> {
+    json.as_str().and_then(|hash| u64::from_str_radix(hash, 16).ok()).ok_or_else(|| format!("{} is not a hash", json))
+}
+
+fn digest_to_json(digest: &MessageDigest) -> JsonValue {
+    JsonValue::object().with("messageId", digest.message_id.as_str()).with("digest", format!("{:016x}", digest.digest))
+}
+
+fn digest_from_json(json: &JsonValue) -> Result<MessageDigest, String> {
+    Ok(MessageDigest { message_id: get_str(json, "messageId")?, digest: hash_from_json(get(json, "digest")?)? })
+}
+
+pub(crate) fn message_to_json(message: &Message) -> JsonValue {
+    JsonValue::object()
+        .with("id", message.id.as_str())
+        .with("recipientId", message.recipient_id.as_str())
+        .with("serviceId", message.service_id.as_str())
+        .with("eventId", message.event_id.as_str())
+        .with("payload", base64::encode(&message.payload))
+        .with("producerMessageId", message.producer_message_id.as_deref())
+        .with("replayedFrom", message.replayed_from.as_deref())
+        .with("requestId", message.request_id.as_deref())
+        .with("sequence", message.sequence)
+        .with("indexedFields", string_map_to_json(&message.indexed_fields))
+        .with("attributes", string_map_to_json(&message.attributes))
+        .with("retryPolicy", retry_policy_to_json(&message.retry_policy))
+        .with("requestedRetryPolicy", message.requested_retry_policy.as_ref().map(retry_policy_to_json))
+        .with("status", message.status.as_str())
+        .with("attempts", message.attempts)
+        .with("createdAt", time_to_json(message.created_at))
+        .with("nextAttemptAt", message.next_attempt_at.map(time_to_json))
+        .with("annotations", message.annotations.iter().map(annotation_to_json).collect::<Vec<_>>())
+}
+
+pub(crate) fn message_from_json(json: &JsonValue) -> Result<Message, String> {
+    Ok(Message {
+        id: get_str(json, "id")?,
+        recipient_id: get_str(json, "recipientId")?,
+        service_id: get_str(json, "serviceId")?,
+        event_id: get_str(json, "eventId")?,
+        payload: base64::decode(&get_str(json, "payload")?).ok_or_else(|| String::from("payload should be base64"))?,
+        producer_message_id: get_optional_str(json, "producerMessageId")?,
+        replayed_from: get_optional_str(json, "replayedFrom")?,
+        request_id: get_optional_str(json, "requestId")?,
+        sequence: optional(json.get("sequence").unwrap_or(&JsonValue::Null), |sequence| sequence.as_u64().ok_or_else(|| String::from("sequence should be a integer")))?,
+        indexed_fields: string_map_from_json(json, "indexedFields")?,
+        attributes: string_map_from_json(json, "attributes")?,
+        retry_policy: retry_policy_from_json(get(json, "retryPolicy")?)?,
+        requested_retry_policy: optional(json.get("requestedRetryPolicy").unwrap_or(&JsonValue::Null), retry_policy_from_json)?,
+        status: status_from_json(get(json, "status")?)?,
+        attempts: get_u16(json, "attempts")?,
+        created_at: time_from_json(get(json, "createdAt")?)?,
+        next_attempt_at: optional(json.get("nextAttemptAt").unwrap_or(&JsonValue::Null), time_from_json)?,
+        // the nodes that do not annotate messages do not send them
+        annotations: optional(json.get("annotations").unwrap_or(&JsonValue::Null), |annotations| array(annotations, annotation_from_json))?.unwrap_or_default(),
+    })
+}
+
+fn annotation_to_json(annotation: &Annotation) -> JsonValue {
+    JsonValue::object()
+        .with("note", annotation.note.as_str())
+        .with("author", annotation.author.as_deref())
+        .with("createdAt", time_to_json(annotation.created_at))
+}
+
+fn annotation_from_json(json: &JsonValue) -> Result<Annotation, String> {
+    Ok(Annotation { note: get_str(json, "note")?, author: get_optional_str(json, "author")?, created_at: time_from_json(get(json, "createdAt")?)? })
+}
+
+fn attempt_to_json(attempt: &AttemptRecord) -> JsonValue {
+    let json = JsonValue::object()
+        .with("messageId", attempt.message_id.as_str())
+        .with("attempt", attempt.attempt)
+        .with("finishedAt", time_to_json(attempt.finished_at));
+    match &attempt.outcome {
+        AttemptOutcome::Delivered => json.with("outcome", "delivered"),
+        AttemptOutcome::Filtered => json.with("outcome", "filtered"),
+        AttemptOutcome::Failed(error) => json.with("outcome", "failed")
+            .with("errorClass", error.class.as_str())
+            .with("error", error.message.as_str())
+            .with("status", error.status),
+        AttemptOutcome::Accepted { confirm_before } => json.with("outcome", "accepted").with("confirmBefore", time_to_json(*confirm_before)),
+    }
+}
+
+fn attempt_from_json(json: &JsonValue) -> Result<AttemptRecord, String> {
+    let outcome = match get_str(json, "outcome")?.as_str() {
+        "delivered" => AttemptOutcome::Delivered,
+        "filtered" => AttemptOutcome::Filtered,
+        "failed" => {
+            let class = DeliveryErrorClass::from_name(&get_str(json, "errorClass")?).ok_or_else(|| String::from("errorClass is not a error class"))?;
+            let status = optional(json.get("status").unwrap_or(&JsonValue::Null), |status| {
+                status.as_u64().and_then(|status| u16::try_from(status).ok()).ok_or_else(|| String::from("status should be a HTTP status"))
+            })?;
+            AttemptOutcome::Failed(DeliveryError { class, message: get_str(json, "error")?, status })
+        }
+        "accepted" => AttemptOutcome::Accepted { confirm_before: time_from_json(get(json, "confirmBefore")?)? },
+        outcome => return Err(format!("{} is not a attempt outcome", outcome)),
+    };
+    Ok(AttemptRecord {
+        message_id: get_str(json, "messageId")?,
+        attempt: get_u16(json, "attempt")?,
+        finished_at: time_from_json(get(json, "finishedAt")?)?,
+        outcome,
+    })
+}
+
+fn usage_to_json(record: &UsageRecord) -> JsonValue {
+    JsonValue::object()
+        .with("namespace", record.namespace.as_str())
+        .with("periodStart", time_to_json(record.period_start))
+        .with("periodEnd", time_to_json(record.period_end))
+        .with("publishes", record.publishes)
+        .with("deliveries", record.deliveries)
+        .with("storageByteHours", record.storage_byte_hours)
+}
+
+fn usage_from_json(json: &JsonValue) -> Result<UsageRecord, String> {
+    Ok(UsageRecord {
+        namespace: get_str(json, "namespace")?,
+        period_start: time_from_json(get(json, "periodStart")?)?,
+        period_end: time_from_json(get(json, "periodEnd")?)?,
+        publishes: get_u64(json, "publishes")?,
+        deliveries: get_u64(json, "deliveries")?,
+        storage_byte_hours: get_u64(json, "storageByteHours")?,
+    })
+}
+
+fn write_to_json(write: &StoreWrite) -> JsonValue {
+    match write {
+        StoreWrite::InsertMessage(message) => JsonValue::object().with("type", "insertMessage").with("message", message_to_json(message)),
+        StoreWrite::UpdateStatus { message_id, status, next_attempt_at } => JsonValue::object()
+            .with("type", "updateStatus")
+            .with("messageId", message_id.as_str())
+            .with("status", status.as_str())
+            .with("nextAttemptAt", next_attempt_at.map(time_to_json)),
+        StoreWrite::RecordAttempt(attempt) => JsonValue::object().with("type", "recordAttempt").with("attempt", attempt_to_json(attempt)),
+        StoreWrite::AnnotateMessage { message_id, annotation } => JsonValue::object()
+            .with("type", "annotateMessage")
+            .with("messageId", message_id.as_str())
+            .with("annotation", annotation_to_json(annotation)),
+    }
+}
+
+fn write_from_json(json: &JsonValue) -> Result<StoreWrite, String> {
+    match get_str(json, "type")?.as_str() {
+        "insertMessage" => Ok(StoreWrite::InsertMessage(Box::new(message_from_json(get(json, "message")?)?))),
+        "updateStatus" => Ok(StoreWrite::UpdateStatus {
+            message_id: get_str(json, "messageId")?,
+            status: status_from_json(get(json, "status")?)?,
+            next_attempt_at: optional(json.get("nextAttemptAt").unwrap_or(&JsonValue::Null), time_from_json)?,
+        }),
+        "recordAttempt" => Ok(StoreWrite::RecordAttempt(attempt_from_json(get(json, "attempt")?)?)),
+        "annotateMessage" => Ok(StoreWrite::AnnotateMessage { message_id: get_str(json, "messageId")?, annotation: annotation_from_json(get(json, "annotation")?)? }),
+        write => Err(format!("{} is not a store write", write)),
+    }
+}
+
+fn query_to_json(query: &MessageQuery) -> JsonValue {
+    JsonValue::object()
+        .with("status", query.status.map(|status| status.as_str()))
+        .with("recipientId", query.recipient_id.as_deref())
+        .with("namespace", query.namespace.as_deref())
+        .with("topic", query.topic.as_deref())
+        .with("createdAfter", query.created_after.map(time_to_json))
+        .with("createdBefore", query.created_before.map(time_to_json))
+        .with("indexedFields", pairs_to_json(&query.indexed_fields))
+        .with("attributes", pairs_to_json(&query.attributes))
+        .with("after", query.after.as_ref().map(|(created_at, id)| JsonValue::object().with("createdAt", time_to_json(*created_at)).with("id", id.as_str())))
+        .with("limit", query.limit)
+}
+
+fn query_from_json(json: &JsonValue) -> Result<MessageQuery, String> {
+    let null = JsonValue::Null;
+    Ok(MessageQuery {
+        status: optional(json.get("status").unwrap_or(&null), status_from_json)?,
+        recipient_id: get_optional_str(json, "recipientId")?,
+        namespace: get_optional_str(json, "namespace")?,
+        topic: get_optional_str(json, "topic")?,
+        created_after: optional(json.get("createdAfter").unwrap_or(&null), time_from_json)?,
+        created_before: optional(json.get("createdBefore").unwrap_or(&null), time_from_json)?,
+        indexed_fields: pairs_from_json(json, "indexedFields")?,
+        attributes: pairs_from_json(json, "attributes")?,
+        after: optional(json.get("after").unwrap_or(&null), |after| Ok((time_from_json(get(after, "createdAt")?)?, get_str(after, "id")?)))?,
+        limit: optional(json.get("limit").unwrap_or(&null), |limit| limit.as_u64().map(|limit| limit as usize).ok_or_else(|| String::from("limit should be a integer")))?,
+    })
+}
+
+/// The scope of all topics is sent as null
+fn scope_to_json(scope: &PurgeScope) -> JsonValue {
+    match scope {
+        PurgeScope::AllTopics => JsonValue::Null,
+        PurgeScope::Topic(topic) => JsonValue::object().with("topic", topic.as_str()),
+        PurgeScope::ExceptTopics(topics) => JsonValue::object().with("exceptTopics", topics.iter().map(|topic| JsonValue::from(topic.as_str())).collect::<Vec<_>>()),
+    }
+}
+
+fn scope_from_json(json: &JsonValue) -> Result<PurgeScope, String> {
+    if json.is_null() {
+        return Ok(PurgeScope::AllTopics);
+    }
+    if let Some(topic) = get_optional_str(json, "topic")? {
+        return Ok(PurgeScope::Topic(topic));
+    }
+    let topics = array(get(json, "exceptTopics")?, |topic| topic.as_str().map(str::to_string).ok_or_else(|| String::from("exceptTopics should have topics")))?;
+    Ok(PurgeScope::ExceptTopics(topics))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use crate::{cluster::{join::JoinToken, readiness::ReadySignal}, db::memory::MemoryStore, net::http::{read_request, read_response, write_request, write_response, MAX_BODY_SIZE}, utils::random::FastRng};
+
+    use super::*;
+
+    #[test]
+    fn test_if_messages_writes_and_queries_are_sent_without_losing_fields() {
+        let mut message = Message::new(String::from("a"), String::from("r1"), String::from("orders"), String::from("order.created"), vec![0, 159, 146, 150]);
+        message.producer_message_id = Some(String::from("p1"));
+        message.sequence = Some(7);
+        message.attributes.insert(String::from("region"), String::from("eu"));
+        message.retry_policy = RetryPolicy { interval: Some(DurationSequence::from_vec(vec![TimeDuration::nanoseconds(1_500_000_001), TimeDuration::days(400)]).unwrap().with_tail(SequenceTail::Doubling { up_to: TimeDuration::weeks(60) })), max_attempts: 3 };
+        message.next_attempt_at = None;
+        message.annotations.push(Annotation { note: String::from("customer notified"), author: Some(String::from("ops")), created_at: message.created_at });
+        assert_eq!(message_from_json(&message_to_json(&message)), Ok(message.clone()));
+
+        let attempt = AttemptRecord {
+            message_id: String::from("a"),
+            attempt: 2,
+            finished_at: message.created_at,
+            outcome: AttemptOutcome::Failed(DeliveryError::from_response(503, "HTTP 503")),
+        };
+        for write in [StoreWrite::InsertMessage(Box::new(message.clone())), StoreWrite::RecordAttempt(attempt), StoreWrite::UpdateStatus { message_id: String::from("a"), status: MessageStatus::Dead, next_attempt_at: None },
+            StoreWrite::AnnotateMessage { message_id: String::from("a"), annotation: message.annotations[0].clone() }] {
+            assert_eq!(write_from_json(&JsonValue::parse(&write_to_json(&write).to_string()).unwrap()), Ok(write));
+        }
+
+        let query = MessageQuery {
+            status: Some(MessageStatus::Pending),
+            topic: Some(String::from("order.created")),
+            created_after: Some(message.created_at),
+            after: Some((message.created_at, String::from("a"))),
+            attributes: vec![(String::from("region"), String::from("eu"))],
+            limit: Some(10),
+            ..MessageQuery::default()
+        };
+        assert_eq!(query_from_json(&query_to_json(&query)), Ok(query));
+        for scope in [PurgeScope::AllTopics, PurgeScope::Topic(String::from("audit")), PurgeScope::ExceptTopics(vec![String::from("audit")])] {
+            assert_eq!(scope_from_json(&scope_to_json(&scope)), Ok(scope));
+        }
+
+        let usage = UsageRecord {
+            namespace: String::from("orders"),
+            period_start: message.created_at,
+            period_end: message.created_at + TimeDuration::hours(1),
+            publishes: 12,
+            deliveries: 10,
+            storage_byte_hours: 4096,
+        };
+        assert_eq!(usage_from_json(&usage_to_json(&usage)), Ok(usage));
+    }
+
+    #[test]
+    fn test_if_store_errors_are_rebuilt_by_the_remote_store() {
+        let server = StoreServer::new(Arc::new(MemoryStore::new())).with_auth_key(String::from("k3y"));
+        let mut request = HttpRequest::new("POST", "/cluster/store/writeBatch");
+        request.body = br#"{"writes": [{"type": "updateStatus", "messageId": "missing", "status": "dead", "nextAttemptAt": null}]}"#.to_vec();
+        assert_eq!(server.handle(&request).status, 401);
+
+        request.headers.set("Authorization", "Bearer k3y");
+        let response = server.handle(&request);
+        assert_eq!(response.status, 500);
+        let err = store_error_from_json(response.status, &JsonValue::parse_bytes(&response.body).unwrap());
+        assert!(matches!(err, StoreError::MessageNotFound(message_id) if message_id == "missing"));
+
+        request.target = String::from("/cluster/store/dropEverything");
+        assert_eq!(server.handle(&request).status, 404);
+    }
+
+    #[test]
+    fn test_if_calls_are_compressed_only_after_the_storage_node_accepts_it() {
+        let store = Arc::new(MemoryStore::new());
+        let payload = b"{\"order\": 12345, \"customer\": \"someone@example.com\"}".repeat(100);
+        store.write(StoreWrite::InsertMessage(Box::new(Message::new(String::from("a"), String::from("r1"), String::from("orders"), String::from("order.created"), payload.clone())))).unwrap();
+
+        let plain = StoreServer::listen(Arc::new(StoreServer::new(store.clone())), "127.0.0.1:0").unwrap();
+        let remote = RemoteStore::new(&format!("http://{}", plain.local_addr())).with_compression(ClusterCompression::Lz4);
+        assert_eq!(remote.get_message("a").unwrap().unwrap().payload, payload);
+        assert!(!remote.peer_accepts_compression.load(Ordering::Relaxed));
+
+        let server = StoreServer::new(store.clone()).with_compression(ClusterCompression::Lz4);
+        let mut request = HttpRequest::new("POST", "/cluster/store/getMessage");
+        request.body = br#"{"messageId": "a"}"#.to_vec();
+        request.headers.set(PROTOCOL_VERSION_HEADER, &PROTOCOL_VERSION.to_string());
+        let uncompressed = server.handle(&request);
+        assert_eq!(uncompressed.headers.get("Content-Encoding"), None);
+        request.headers.set("Accept-Encoding", "gzip, lz4");
+        let compressed = server.handle(&request);
+        assert_eq!(compressed.headers.get("Content-Encoding"), Some("lz4"));
+        assert!(compressed.body.len() < uncompressed.body.len() / 4);
+
+        let compressing = StoreServer::listen(Arc::new(server), "127.0.0.1:0").unwrap();
+        let remote = RemoteStore::new(&format!("http://{}", compressing.local_addr())).with_compression(ClusterCompression::Lz4);
+        assert_eq!(remote.get_message("a").unwrap().unwrap().payload, payload);
+        assert!(remote.peer_accepts_compression.load(Ordering::Relaxed));
+        let mut message = store.get_message("a").unwrap().unwrap();
+        message.id = String::from("b");
+        remote.write(StoreWrite::InsertMessage(Box::new(message))).unwrap();
+        assert_eq!(store.get_message("b").unwrap().unwrap().payload, payload);
+
+        request.headers.set("Content-Encoding", "br");
+        assert_eq!(StoreServer::new(store.clone()).handle(&request).status, 415);
+
+        // a caller that was not upgraded yet holds the compression back
+        let server = StoreServer::new(store).with_compression(ClusterCompression::Lz4);
+        let mut request = HttpRequest::new("POST", "/cluster/store/getMessage");
+        request.body = br#"{"messageId": "a"}"#.to_vec();
+        request.headers.set("Accept-Encoding", "lz4");
+        request.headers.set(NODE_HEADER, "b1");
+        request.headers.set(PROTOCOL_VERSION_HEADER, "3");
+        let response = server.handle(&request);
+        assert_eq!(response.headers.get("Content-Encoding"), None);
+        assert_eq!(response.headers.get(PROTOCOL_VERSION_HEADER), Some(PROTOCOL_VERSION.to_string().as_str()));
+
+        let features = server.handle(&HttpRequest::new("GET", "/cluster/features"));
+        let features = JsonValue::parse_bytes(&features.body).unwrap();
+        assert_eq!(features.get("negotiatedVersion").and_then(JsonValue::as_u64), Some(3));
+        let lz4 = features.get("features").and_then(JsonValue::as_array).unwrap().iter()
+            .find(|feature| feature.get("name").and_then(JsonValue::as_str) == Some("lz4Compression")).unwrap();
+        assert_eq!(lz4.get("enabled"), Some(&JsonValue::Bool(false)));
+    }
+
+    #[test]
+    fn test_if_kept_connections_are_pinged_until_the_storage_node_stops_answering() {
+        let store = Arc::new(MemoryStore::new());
+        store.write(StoreWrite::InsertMessage(Box::new(Message::new(String::from("a"), String::from("r1"), String::from("orders"), String::from("order.created"), vec![1])))).unwrap();
+        let server = StoreServer::listen(Arc::new(StoreServer::new(store)), "127.0.0.1:0").unwrap();
+        let remote = RemoteStore::new(&format!("http://{}", server.local_addr())).with_keepalive(2);
+        assert!(remote.get_message("a").unwrap().is_some());
+        assert!(remote.ping());
+        // the call and the ping used the same connection
+        assert_eq!(remote.connections.as_ref().unwrap().warm_counts().get(STORAGE_CONNECTIONS), Some(&(1, 0)));
+
+        // the handshakes are completed by the listener, that never answers
+        let silent = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let remote = RemoteStore::new(&format!("http://{}", silent.local_addr().unwrap())).with_keepalive(2).with_timeout(Duration::from_millis(100));
+        assert!(!remote.ping());
+        assert!(remote.is_reachable());
+        assert!(!remote.ping());
+        assert!(!remote.is_reachable());
+        let err = remote.get_message("a").unwrap_err();
+        assert!(err.to_string().contains("missed its last 2 pings"), "{}", err);
+    }
+    #[cfg(feature = "chaos")]
+    #[test]
+    fn test_if_the_calls_of_partitioned_nodes_fail_on_both_sides() {
+        use crate::chaos::{FaultInjector, FaultSettings};
+
+        let partition = |nodes: &[&str]| FaultSettings { partitioned_nodes: nodes.iter().map(|node| node.to_string()).collect(), ..FaultSettings::default() };
+        let server_faults = Arc::new(FaultInjector::new());
+        server_faults.update(partition(&["b1"]));
+        let server = StoreServer::new(Arc::new(MemoryStore::new())).with_faults(server_faults.clone());
+        let call = |node_id: &str| {
+            let mut request = HttpRequest::new("POST", "/cluster/store/getMessage");
+            request.body = br#"{"messageId": "a"}"#.to_vec();
+            request.headers.set(NODE_HEADER, node_id);
+            server.handle(&request)
+        };
+        assert_eq!(call("b1").status, 503);
+        assert_eq!(call("b2").status, 200);
+        server_faults.reset();
+        assert_eq!(call("b1").status, 200);
+
+        // the storage node answers with its node ID, the one of this process
+        let listening = StoreServer::listen(Arc::new(StoreServer::new(Arc::new(MemoryStore::new()))), "127.0.0.1:0").unwrap();
+        let faults = Arc::new(FaultInjector::new());
+        let remote = RemoteStore::new(&format!("http://{}", listening.local_addr())).with_faults(faults.clone());
+        assert!(remote.get_message("a").is_ok());
+        faults.update(partition(&[local_node_id()]));
+        let err = remote.get_message("a").unwrap_err();
+        assert!(err.to_string().contains("partitioned"), "{}", err);
+        assert!(!remote.ping());
+        faults.reset();
+        assert!(remote.ping());
+        assert!(remote.get_message("a").is_ok());
+    }
+
+    #[test]
+    fn test_if_nodes_with_skewed_clocks_are_listed_and_fenced() {
+        let server = StoreServer::new(Arc::new(MemoryStore::new())).with_clock_skew(TimeDuration::seconds(2), true);
+        let call = |node_id: &str, skew: TimeDuration| {
+            let mut request = HttpRequest::new("POST", "/cluster/store/getMessage");
+            request.body = br#"{"messageId": "a"}"#.to_vec();
+            request.headers.set(NODE_HEADER, node_id);
+            request.headers.set(PROTOCOL_VERSION_HEADER, &PROTOCOL_VERSION.to_string());
+            request.headers.set(CLOCK_HEADER, &clock_header(OffsetDateTime::now_utc() + skew));
+            server.handle(&request)
+        };
+        assert_eq!(call("b1", TimeDuration::ZERO).status, 200);
+        assert_eq!(call("b2", TimeDuration::minutes(-1)).status, 503);
+
+        let nodes = JsonValue::parse_bytes(&server.handle(&HttpRequest::new("GET", "/cluster/nodes")).body).unwrap();
+        assert_eq!(nodes.get("clockSkewThresholdMs").and_then(JsonValue::as_u64), Some(2000));
+        let nodes = nodes.get("nodes").and_then(JsonValue::as_array).unwrap();
+        assert_eq!(nodes.len(), 2);
+        let b2 = nodes.iter().find(|node| node.get("nodeId").and_then(JsonValue::as_str) == Some("b2")).unwrap();
+        assert_eq!(b2.get("fenced"), Some(&JsonValue::Bool(true)));
+        assert!(b2.get("clockSkewMs").and_then(JsonValue::as_f64).is_some_and(|skew| skew < -59_000.0));
+
+        // the pings of a fenced node are still answered, so it recovers once its clock is fixed
+        let mut ping = HttpRequest::new("GET", "/cluster/ping");
+        ping.headers.set(NODE_HEADER, "b2");
+        ping.headers.set(CLOCK_HEADER, &clock_header(OffsetDateTime::now_utc()));
+        assert_eq!(server.handle(&ping).status, 204);
+        assert_eq!(call("b2", TimeDuration::ZERO).status, 200);
+    }
+
+    #[test]
+    fn test_if_the_controller_health_reports_the_readiness_and_the_members() {
+        let server = StoreServer::new(Arc::new(MemoryStore::new())).with_clock_skew(TimeDuration::seconds(2), true);
+        for (node_id, skew) in [("b1", TimeDuration::ZERO), ("b2", TimeDuration::minutes(1))] {
+            let mut request = HttpRequest::new("POST", "/cluster/store/getMessage");
+            request.body = br#"{"messageId": "a"}"#.to_vec();
+            request.headers.set(NODE_HEADER, node_id);
+            request.headers.set(PROTOCOL_VERSION_HEADER, &PROTOCOL_VERSION.to_string());
+            request.headers.set(CLOCK_HEADER, &clock_header(OffsetDateTime::now_utc() + skew));
+            server.handle(&request);
+        }
+
+        let response = server.handle(&HttpRequest::new("GET", "/cluster/controller"));
+        assert_eq!(response.status, 200);
+        let controller = JsonValue::parse_bytes(&response.body).unwrap();
+        assert_eq!(controller.get("nodeId").and_then(JsonValue::as_str), Some(local_node_id()));
+        assert_eq!(controller.get("leadership").and_then(JsonValue::as_str), Some("static"));
+        assert_eq!(controller.get("ready"), Some(&JsonValue::Bool(true)));
+        assert_eq!(controller.get("members").and_then(JsonValue::as_u64), Some(2));
+        assert_eq!(controller.get("fencedMembers"), Some(&JsonValue::from(vec!["b2"])));
+        assert_eq!(controller.get("syncedAt"), Some(&JsonValue::Null));
+
+        // a controller that is not ready is answered with 503, so monitoring alerts on it
+        let readiness = ReadinessProbe::new(&[ApplicationRoles::Storage], Arc::new(MemoryStore::new())).with_replica("http://replica", ReadySignal::new());
+        let server = StoreServer::new(Arc::new(MemoryStore::new())).with_readiness(Arc::new(readiness)).with_auth_key(String::from("key"));
+        assert_eq!(server.handle(&HttpRequest::new("GET", "/cluster/controller")).status, 401);
+        let mut request = HttpRequest::new("GET", "/cluster/controller");
+        request.headers.set("Authorization", "Bearer key");
+        let response = server.handle(&request);
+        assert_eq!(response.status, 503);
+        let failed = JsonValue::parse_bytes(&response.body).unwrap().get("failedChecks").and_then(JsonValue::as_array).cloned().unwrap();
+        assert_eq!(failed.len(), 1);
+        assert_eq!(failed[0].get("name").and_then(JsonValue::as_str), Some("replicas"));
+    }
+
+    #[test]
+    fn test_if_join_tokens_are_traded_for_revocable_credentials_of_their_node() {
+        let token = JoinToken::create("k3y", TimeDuration::hours(1), OffsetDateTime::now_utc());
+        let token_id = JoinToken::verify("k3y", &token, OffsetDateTime::now_utc()).unwrap().id;
+        let server = StoreServer::new(Arc::new(MemoryStore::new())).with_auth_key(String::from("k3y"))
+            .with_revoked_join_tokens(HashSet::from([String::from("revoked")]));
+        let join = |node_id: &str| {
+            let mut request = HttpRequest::new("POST", JOIN_PATH);
+            request.headers.set("Authorization", &format!("Bearer {}", token));
+            request.headers.set(NODE_HEADER, node_id);
+            server.handle(&request)
+        };
+        let ping = |credential: &str| {
+            let mut request = HttpRequest::new("GET", PING_PATH);
+            request.headers.set("Authorization", &format!("Bearer {}", credential));
+            server.handle(&request).status
+        };
+
+        // a bad node ID does not use up the token
+        assert_eq!(join("b1.b2").status, 400);
+        let response = join("b1");
+        assert_eq!(response.status, 200);
+        let body = JsonValue::parse_bytes(&response.body).unwrap();
+        assert_eq!(body.get("tokenId").and_then(JsonValue::as_str), Some(token_id.as_str()));
+        let credential = body.get("credential").and_then(JsonValue::as_str).unwrap().to_string();
+        assert_eq!(credential, node_credential("k3y", "b1", &token_id));
+        assert_eq!(join("b1").status, 401);
+
+        assert_eq!(ping(&credential), 204);
+        assert_eq!(ping(&node_credential("k3y", "b1", "revoked")), 401);
+        assert_eq!(ping(&node_credential("other", "b1", &token_id)), 401);
+    }
+
+    #[test]
+    fn test_if_mutated_and_random_calls_are_answered_without_panicking() {
+        let server = StoreServer::new(Arc::new(MemoryStore::new()));
+        let mut rng = FastRng::with_seed(1254);
+        let mut message = Message::new(String::from("a"), String::from("r1"), String::from("orders"), String::from("order.created"), b"{}".to_vec());
+        message.retry_policy = RetryPolicy { interval: Some(DurationSequence::from_vec(vec![TimeDuration::minutes(1)]).unwrap()), max_attempts: 3 };
+        let writes = JsonValue::object().with("writes", vec![write_to_json(&StoreWrite::InsertMessage(Box::new(message)))]).to_string();
+        let query = JsonValue::object().with("query", query_to_json(&MessageQuery { limit: Some(10), ..MessageQuery::default() })).to_string();
+        const JSON_BYTES: &[u8] = b"0\"{}[],:-9ae";
+        let methods = ["writeBatch", "getMessage", "getAttempts", "findMessages", "findByProducerMessageId", "purgeFinished", "merkleLeaves", "messageDigests", "markSynced", "syncedAt", "recordUsage", "findUsage"];
+
+        for case in 0..2000 {
+            let mut body = [&writes, &query][case % 2].clone().into_bytes();
+            // flip, drop and repeat the bytes of a valid call, so the decoders get the fields of
+            // every shape and not only invalid JSON
+            for _ in 0..1 + rng.next_u64() % 4 {
+                let position = rng.next_u64() as usize % body.len();
+                match rng.next_u64() % 3 {
+                    0 => body[position] = JSON_BYTES[rng.next_u64() as usize % JSON_BYTES.len()],
+                    1 => { body.remove(position); }
+                    _ => body.insert(position, body[position]),
+                }
+            }
+            let mut request = HttpRequest::new("POST", &format!("{}{}", STORE_PATH, methods[rng.next_u64() as usize % methods.len()]));
+            if rng.next_u64().is_multiple_of(4) {
+                body = match rng.next_u64() % 2 {
+                    0 => lz4::compress(&body),
+                    _ => (0..rng.next_u64() % 64).map(|_| rng.next_u64() as u8).collect(),
+                };
+                request.headers.set("Content-Encoding", LZ4_ENCODING);
+            }
+            request.headers.set(PROTOCOL_VERSION_HEADER, &format!("{}", rng.next_u64() % 1000));
+            request.body = body;
+            let response = server.handle(&request);
+            assert!([200, 400, 415, 500].contains(&response.status), "{} for {:?}", response.status, String::from_utf8_lossy(&request.body));
+        }
+    }
+    #[test]
+    fn test_if_mutated_and_random_bytes_of_the_cluster_calls_are_framed_without_panicking() {
+        let server = StoreServer::new(Arc::new(MemoryStore::new()));
+        let mut rng = FastRng::with_seed(1254);
+        let body = br#"{"query": {"limit": 10}}"#.to_vec();
+        let mut request = HttpRequest::new("POST", "/cluster/store/findMessages");
+        request.headers.set(PROTOCOL_VERSION_HEADER, &PROTOCOL_VERSION.to_string());
+        request.body = body.clone();
+        let mut chunked = request.clone();
+        chunked.headers.set("Transfer-Encoding", "chunked");
+        chunked.body = format!("{:x}\r\n{}\r\n0\r\n\r\n", body.len(), String::from_utf8_lossy(&body)).into_bytes();
+        let (mut calls, mut answers) = (Vec::new(), Vec::new());
+        for request in [&request, &chunked] {
+            let mut raw = Vec::new();
+            write_request(&mut raw, "storage", request).unwrap();
+            calls.push(raw);
+            let mut raw = Vec::new();
+            write_response(&mut raw, &server.handle(request), true).unwrap();
+            answers.push(raw);
+        }
+        let mut chunked_answer = b"HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n".to_vec();
+        chunked_answer.extend_from_slice(format!("{:x}\r\n{}\r\n0\r\n\r\n", body.len(), String::from_utf8_lossy(&body)).as_bytes());
+        answers.push(chunked_answer);
+        // the bytes of the framing, so the sizes and the lines are mutated and not only the JSON
+        const FRAMING_BYTES: &[u8] = b"0123456789abcdefF\r\n;: -";
+
+        for case in 0..3000 {
+            let mut raw = match case % 2 {
+                0 => calls[rng.next_u64() as usize % calls.len()].clone(),
+                _ => answers[rng.next_u64() as usize % answers.len()].clone(),
+            };
+            for _ in 0..1 + rng.next_u64() % 4 {
+                let position = rng.next_u64() as usize % raw.len();
+                match rng.next_u64() % 6 {
+                    0 => raw[position] = FRAMING_BYTES[rng.next_u64() as usize % FRAMING_BYTES.len()],
+                    1 => raw[position] = rng.next_u64() as u8,
+                    2 => { raw.remove(position); }
+                    3 => raw.insert(position, FRAMING_BYTES[rng.next_u64() as usize % FRAMING_BYTES.len()]),
+                    // a number of the message, like a chunk size, replaced by one at the limits
+                    4 => {
+                        let numbers: Vec<usize> = (0..raw.len()).filter(|i| raw[*i].is_ascii_hexdigit() && (*i == 0 || !raw[*i - 1].is_ascii_alphanumeric())).collect();
+                        if let Some(start) = numbers.get(rng.next_u64() as usize % numbers.len().max(1)).copied() {
+                            let end = (start..raw.len()).find(|i| !raw[*i].is_ascii_hexdigit()).unwrap_or(raw.len());
+                            let limits = [u64::MAX, u64::MAX - 1, MAX_BODY_SIZE as u64, MAX_BODY_SIZE as u64 + 1, rng.next_u64()];
+                            let limit = limits[rng.next_u64() as usize % limits.len()];
+                            let number = if rng.next_u64().is_multiple_of(2) { format!("{:x}", limit) } else { limit.to_string() };
+                            raw.splice(start..end, number.into_bytes());
+                        }
+                    }
+                    _ => raw.truncate(position),
+                }
+                if raw.is_empty() {
+                    break;
+                }
+            }
+            if case % 2 == 1 {
+                let _ = read_response(&mut Cursor::new(&raw));
+            } else if let Ok(Some(request)) = read_request(&mut Cursor::new(&raw)) {
+                // the calls that are still framed reach the decoders of the store calls
+                let _ = server.handle(&request);
+            }
+        }
+    }
+}
@@ -0,0 +1,268 @@
+use std::{
+    sync::{atomic::{AtomicBool, Ordering}, Arc, Mutex},
+    thread,
+};
+
+use time::OffsetDateTime;
+
+use crate::{
+    log,
+    utils::{clock::Clock, json::JsonValue, log::Level, random::uuid_v4, time::format_rfc3339},
+};
+
+/// How many finished jobs of each kind are kept to be queried, the oldest are forgotten first, so
+/// the frequent jobs like the retention sweeps do not push the others out
+pub const MAX_FINISHED_JOBS_PER_KIND: usize = 20;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum JobState {
+    Running,
+    Succeeded,
+    Failed,
+    /// Stopped early because it was cancelled
+    Cancelled,
+}
+
+impl JobState {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            JobState::Running => "running",
+            JobState::Succeeded => "succeeded",
+            JobState::Failed => "failed",
+            JobState::Cancelled => "cancelled",
+        }
+    }
+}
+
+/// The progress of a background job
+#[derive(Debug, Clone, PartialEq)]
+pub struct Job {
+    pub id: String,
+    /// What the job does, like `retentionSweep` or `replay`
+    pub kind: &'static str,
+    pub state: JobState,
+    /// Who started the job, like the login of the operator. None for the jobs started by Angler
+    pub actor: Option<String>,
+    /// How many items the job acts on, known once they were found
+    pub total: usize,
+    /// How many of them were handled
+    pub processed: usize,
+    /// How many of the handled ones were changed. The others were already in the final state or
+    /// could not be changed
+    pub changed: usize,
+    pub started_at: OffsetDateTime,
+    pub finished_at: Option<OffsetDateTime>,
+    /// Why the job failed
+    pub error: Option<String>,
+}
+
+impl Job {
+    pub fn to_json(&self) -> JsonValue {
+        JsonValue::object()
+            .with("id", self.id.as_str())
+            .with("kind", self.kind)
+            .with("state", self.state.as_str())
+            .with("actor", self.actor.as_deref())
+            .with("total", self.total)
+            .with("processed", self.processed)
+            .with("changed", self.changed)
+            .with("startedAt", format_rfc3339(self.started_at))
+            .with("finishedAt", self.finished_at.map(format_rfc3339))
+            .with("error", self.error.as_deref())
+    }
+}
+
+struct JobEntry {
+    job: Job,
+    cancelled: Arc<AtomicBool>,
+}
+
+/// The background jobs of a node, the running ones and the last finished ones of each kind, so
+/// the long operations like the replays are observable and can be cancelled. The jobs check if
+/// they were cancelled between their steps
+pub struct JobRegistry {
+    clock: Arc<dyn Clock>,
+    jobs: Mutex<Vec<JobEntry>>,
+}
+
+impl JobRegistry {
+    pub fn new(clock: Arc<dyn Clock>) -> JobRegistry {
+        JobRegistry { clock, jobs: Mutex::new(Vec::new()) }
+    }
+
+    /// Register a job that runs on the thread of the caller, that reports its progress and its
+    /// end through the returned context
+    pub fn track(self: &Arc<Self>, kind: &'static str, actor: Option<String>) -> JobContext {
+        let job = Job {
+            id: uuid_v4(),
+            kind,
+            state: JobState::Running,
+            actor,
+            total: 0,
+            processed: 0,
+            changed: 0,
+            started_at: self.clock.now(),
+            finished_at: None,
+            error: None,
+        };
+        let cancelled = Arc::new(AtomicBool::new(false));
+        let context = JobContext { id: job.id.clone(), registry: self.clone(), cancelled: cancelled.clone() };
+        self.jobs.lock().unwrap().push(JobEntry { job, cancelled });
+        context
+    }
+
+    /// Run the work on a background thread as a job, returning the job just started
+    pub fn spawn(self: &Arc<Self>, kind: &'static str, actor: Option<String>, work: impl FnOnce(&JobContext) -> Result<(), String> + Send + 'static) -> Job {
+        let context = self.track(kind, actor);
+        let job = self.get(&context.id).expect("the job was just registered");
+        thread::Builder::new()
+            .name(String::from("angler-job"))
+            .spawn(move || {
+                let result = work(&context);
+                context.finish(result);
+            })
+            .expect("failed to spawn the job thread");
+        job
+    }
+
+    pub fn get(&self, id: &str) -> Option<Job> {
+        self.jobs.lock().unwrap().iter().find(|entry| entry.job.id == id).map(|entry| entry.job.clone())
+    }
+
+    /// Return the running jobs and the last finished ones, in the order they started
+    pub fn list(&self) -> Vec<Job> {
+        self.jobs.lock().unwrap().iter().map(|entry| entry.job.clone()).collect()
+    }
+
+    /// Ask the job to stop at its next step. Return the job, or None when it does not exist
+    pub fn cancel(&self, id: &str) -> Option<Job> {
+        let jobs = self.jobs.lock().unwrap();
+        let entry = jobs.iter().find(|entry| entry.job.id == id)?;
+        if entry.job.state == JobState::Running {
+            entry.cancelled.store(true, Ordering::SeqCst);
+            log!(Level::Info, "Cancelling the job {} ({})", id, entry.job.kind);
+        }
+        Some(entry.job.clone())
+    }
+
+    fn update(&self, id: &str, update: impl FnOnce(&mut Job)) {
+        if let Some(entry) = self.jobs.lock().unwrap().iter_mut().find(|entry| entry.job.id == id) {
+            update(&mut entry.job);
+        }
+    }
+}
+
+/// What a running job reports its progress through
+pub struct JobContext {
+    id: String,
+    registry: Arc<JobRegistry>,
+    cancelled: Arc<AtomicBool>,
+}
+
+impl JobContext {
+    pub fn id(&self) -> &str {
+        &self.id
+    }
+
+    /// Return if the job was asked to stop
+    pub fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::SeqCst)
+    }
+
+    pub fn set_total(&self, total: usize) {
+        self.registry.update(&self.id, |job| job.total = total);
+    }
+
+    /// Count the items handled since the last call and how many of them were changed
+    pub fn advance(&self, processed: usize, changed: usize) {
+        self.registry.update(&self.id, |job| {
+            job.processed += processed;
+            job.changed += changed;
+        });
+    }
+
+    /// End the job, as cancelled when it was asked to stop
+    pub fn finish(&self, result: Result<(), String>) {
+        let now = self.registry.clock.now();
+        let mut jobs = self.registry.jobs.lock().unwrap();
+        let Some(entry) = jobs.iter_mut().find(|entry| entry.job.id == self.id) else {
+            return;
+        };
+        let job = &mut entry.job;
+        job.finished_at = Some(now);
+        match result {
+            Ok(()) if self.is_cancelled() => {
+                job.state = JobState::Cancelled;
+                log!(Level::Info, "The job {} ({}) was cancelled after changing {} of {}", job.id, job.kind, job.changed, job.total);
+            }
+            Ok(()) => {
+                job.state = JobState::Succeeded;
+                log!(Level::Debug, "The job {} ({}) changed {} of {}", job.id, job.kind, job.changed, job.total);
+            }
+            Err(err) => {
+                job.state = JobState::Failed;
+                log!(Level::Error, "The job {} ({}) failed after changing {} of {}: {}", job.id, job.kind, job.changed, job.total, err);
+                job.error = Some(err);
+            }
+        }
+
+        let kind = job.kind;
+        let finished = jobs.iter().filter(|entry| entry.job.kind == kind && entry.job.state != JobState::Running).count();
+        let mut forgotten = finished.saturating_sub(MAX_FINISHED_JOBS_PER_KIND);
+        jobs.retain(|entry| {
+            let forget = forgotten > 0 && entry.job.kind == kind && entry.job.state != JobState::Running;
+            forgotten -= usize::from(forget);
+            !forget
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{sync::mpsc, time::Duration};
+
+    use crate::utils::clock::SystemClock;
+
+    use super::*;
+
+    #[test]
+    fn test_if_jobs_report_their_progress_until_they_finish_or_are_cancelled() {
+        let registry = Arc::new(JobRegistry::new(Arc::new(SystemClock)));
+        let (step, steps) = mpsc::channel::<()>();
+        let started = registry.spawn("replay", Some(String::from("alice")), move |job| {
+            job.set_total(3);
+            for _ in steps.iter() {
+                if job.is_cancelled() {
+                    return Ok(());
+                }
+                job.advance(1, 1);
+            }
+            Ok(())
+        });
+        assert_eq!((started.kind, started.state), ("replay", JobState::Running));
+
+        step.send(()).unwrap();
+        while registry.get(&started.id).unwrap().processed < 1 {
+            thread::sleep(Duration::from_millis(1));
+        }
+        assert_eq!(registry.cancel(&started.id).map(|job| job.state), Some(JobState::Running));
+        step.send(()).unwrap();
+        while registry.get(&started.id).unwrap().state == JobState::Running {
+            thread::sleep(Duration::from_millis(1));
+        }
+        let cancelled = registry.get(&started.id).unwrap();
+        assert_eq!((cancelled.state, cancelled.total, cancelled.changed), (JobState::Cancelled, 3, 1));
+        assert!(cancelled.finished_at.is_some());
+        assert_eq!(registry.cancel("missing"), None);
+
+        let failed = registry.track("retentionSweep", None);
+        failed.finish(Err(String::from("the store is down")));
+        for _ in 0..MAX_FINISHED_JOBS_PER_KIND {
+            registry.track("retentionSweep", None).finish(Ok(()));
+        }
+        // the oldest sweep was forgotten, but not the job of the other kind
+        assert_eq!(registry.get(failed.id()), None);
+        assert_eq!(registry.list().len(), MAX_FINISHED_JOBS_PER_KIND + 1);
+        assert_eq!(registry.list()[0].to_json().get("state").and_then(JsonValue::as_str), Some("cancelled"));
+    }
+}
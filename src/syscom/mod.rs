@@ -0,0 +1,3 @@
+pub mod jobs;
+pub mod retention;
+pub mod usage;
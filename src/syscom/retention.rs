@@ -0,0 +1,229 @@
+use std::{
+    sync::{mpsc::{self, RecvTimeoutError, Sender}, Arc},
+    thread::{self, JoinHandle},
+    time::Duration as StdDuration,
+};
+
+use time::Duration;
+
+use crate::{
+    ctx::config::DatabaseConfigurations,
+    db::{MessageStore, PurgeScope, StoreError},
+    log,
+    msgproc::{message::MessageStatus, topic::TopicRegistry},
+    utils::{clock::Clock, log::Level},
+};
+
+use super::jobs::JobRegistry;
+
+/// How often the RetentionSweeper looks for expired messages
+pub const DEFAULT_SWEEP_INTERVAL: StdDuration = StdDuration::from_secs(60);
+
+/// How long finished messages are kept in the store. None keeps them forever
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct RetentionPolicy {
+    pub delivered: Option<Duration>,
+    pub dead: Option<Duration>,
+}
+
+impl RetentionPolicy {
+    /// Read the policy from `db.deliveredMessages.retention` and `db.deadMessages.retention`
+    pub fn from_configuration(conf: &DatabaseConfigurations) -> RetentionPolicy {
+        RetentionPolicy { delivered: conf.delivered_messages_retention, dead: conf.dead_messages_retention }
+    }
+}
+
+/// How many messages were removed by a sweep
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct SweepReport {
+    pub delivered: usize,
+    pub dead: usize,
+}
+
+/// Remove the delivered and dead messages from the store once their retention expires. The
+/// topics with their own retention keep their messages for their own time
+pub struct RetentionSweeper {
+    store: Arc<dyn MessageStore>,
+    clock: Arc<dyn Clock>,
+    policy: RetentionPolicy,
+    jobs: Option<Arc<JobRegistry>>,
+    topics: Option<Arc<TopicRegistry>>,
+}
+
+enum SweeperSignal {
+    Sweep,
+    Stop,
+}
+
+impl RetentionSweeper {
+    pub fn new(store: Arc<dyn MessageStore>, clock: Arc<dyn Clock>, policy: RetentionPolicy) -> RetentionSweeper {
+        RetentionSweeper { store, clock, policy, jobs: None, topics: None }
+    }
+
+    /// Report each background sweep as a `retentionSweep` job of the JobRegistry
+    pub fn with_jobs(mut self, jobs: Arc<JobRegistry>) -> RetentionSweeper {
+        self.jobs = Some(jobs);
+        self
+    }
+
+    /// Keep the messages of the topics of the registry with their own retention for their time
+    pub fn with_topics(mut self, topics: Arc<TopicRegistry>) -> RetentionSweeper {
+        self.topics = Some(topics);
+        self
+    }
+
+    /// Remove the messages whose retention expired at the current time of the clock
+    pub fn sweep(&self) -> Result<SweepReport, StoreError> {
+        let now = self.clock.now();
+        let purge = |status: MessageStatus, retention: Option<Duration>, scope: &PurgeScope| match retention {
+            Some(retention) => self.store.purge_finished(status, now - retention, scope),
+            None => Ok(0),
+        };
+        let mut report = SweepReport::default();
+        let mut overriding = Vec::new();
+        let topics = self.topics.as_ref().map(|topics| topics.list()).unwrap_or_default();
+        for topic in topics.into_iter().filter(|topic| topic.settings.overrides_retention()) {
+            let scope = PurgeScope::Topic(topic.name.clone());
+            report.delivered += purge(MessageStatus::Delivered, topic.settings.delivered_messages_retention.or(self.policy.delivered), &scope)?;
+            report.dead += purge(MessageStatus::Dead, topic.settings.dead_messages_retention.or(self.policy.dead), &scope)?;
+            overriding.push(topic.name);
+        }
+        let scope = if overriding.is_empty() { PurgeScope::AllTopics } else { PurgeScope::ExceptTopics(overriding) };
+        report.delivered += purge(MessageStatus::Delivered, self.policy.delivered, &scope)?;
+        report.dead += purge(MessageStatus::Dead, self.policy.dead, &scope)?;
+        Ok(report)
+    }
+
+    /// Sweep on a background thread every `interval` and every time the clock is moved by hand
+    pub fn start(self, interval: StdDuration) -> SweeperHandle {
+        let (sender, receiver) = mpsc::channel();
+        let listener_sender = sender.clone();
+        self.clock.on_advance(Arc::new(move || { let _ = listener_sender.send(SweeperSignal::Sweep); }));
+
+        let thread = thread::Builder::new()
+            .name(String::from("angler-retention-sweeper"))
+            .spawn(move || loop {
+                match receiver.recv_timeout(interval) {
+                    Ok(SweeperSignal::Stop) | Err(RecvTimeoutError::Disconnected) => return,
+                    Ok(SweeperSignal::Sweep) | Err(RecvTimeoutError::Timeout) => {
+                        let job = self.jobs.as_ref().map(|jobs| jobs.track("retentionSweep", None));
+                        let result = self.sweep();
+                        if let Err(err) = &result {
+                            log!(Level::Error, "Failed to remove the expired messages: {}", err);
+                        }
+                        if let Some(job) = job {
+                            if let Ok(report) = &result {
+                                let removed = report.delivered + report.dead;
+                                job.set_total(removed);
+                                job.advance(removed, removed);
+                            }
+                            job.finish(result.map(|_| ()).map_err(|err| err.to_string()));
+                        }
+                    }
+                }
+            })
+            .expect("failed to spawn the retention sweeper");
+
+        SweeperHandle { sender, thread: Some(thread) }
+    }
+}
+
+/// Stop the background sweeps when dropped
+pub struct SweeperHandle {
+    sender: Sender<SweeperSignal>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl SweeperHandle {
+    /// Stop the background sweeps, waiting for the sweep in progress
+    pub fn stop(&mut self) {
+        let _ = self.sender.send(SweeperSignal::Stop);
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+impl Drop for SweeperHandle {
+    fn drop(&mut self) {
+        self.stop();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use time::OffsetDateTime;
+
+    use crate::{
+        db::{memory::MemoryStore, StoreWrite},
+        msgproc::{message::{AttemptOutcome, AttemptRecord, DeliveryError, Message}, topic::TopicSettings},
+        utils::clock::VirtualClock,
+    };
+
+    use super::*;
+
+    fn finished_message(store: &MemoryStore, id: &str, status: MessageStatus, finished_at: OffsetDateTime) {
+        finished_topic_message(store, id, "event", status, finished_at);
+    }
+
+    fn finished_topic_message(store: &MemoryStore, id: &str, topic: &str, status: MessageStatus, finished_at: OffsetDateTime) {
+        let outcome = match status {
+            MessageStatus::Delivered => AttemptOutcome::Delivered,
+            _ => AttemptOutcome::Failed(DeliveryError::from_response(500, "HTTP 500")),
+        };
+        let message = Message::new(id.to_string(), "recipient".to_string(), "service".to_string(), topic.to_string(), vec![]);
+        store.write_batch(&[
+            StoreWrite::InsertMessage(Box::new(message)),
+            StoreWrite::RecordAttempt(AttemptRecord { message_id: id.to_string(), attempt: 1, finished_at, outcome }),
+            StoreWrite::UpdateStatus { message_id: id.to_string(), status, next_attempt_at: None },
+        ]).unwrap();
+    }
+
+    #[test]
+    fn test_if_expired_messages_are_removed_as_the_clock_moves() {
+        let start_time = OffsetDateTime::from_unix_timestamp(1_704_067_200).unwrap();
+        let clock = Arc::new(VirtualClock::new(start_time));
+        let store = Arc::new(MemoryStore::new());
+        finished_message(&store, "delivered", MessageStatus::Delivered, start_time);
+        finished_message(&store, "dead", MessageStatus::Dead, start_time);
+        store.write(StoreWrite::InsertMessage(Box::new(Message::new("pending".to_string(), "r".to_string(), "s".to_string(), "e".to_string(), vec![])))).unwrap();
+
+        let policy = RetentionPolicy { delivered: Some(Duration::days(1)), dead: Some(Duration::days(30)) };
+        let sweeper = RetentionSweeper::new(store.clone(), clock.clone(), policy);
+
+        assert_eq!(sweeper.sweep().unwrap(), SweepReport::default());
+        clock.advance(Duration::days(2));
+        assert_eq!(sweeper.sweep().unwrap(), SweepReport { delivered: 1, dead: 0 });
+        clock.advance(Duration::days(29));
+        assert_eq!(sweeper.sweep().unwrap(), SweepReport { delivered: 0, dead: 1 });
+
+        assert!(store.get_message("delivered").unwrap().is_none());
+        assert!(store.get_attempts("dead").unwrap().is_empty());
+        assert!(store.get_message("pending").unwrap().is_some());
+    }
+
+    #[test]
+    fn test_if_topics_with_their_own_retention_keep_their_messages_for_their_time() {
+        let start_time = OffsetDateTime::from_unix_timestamp(1_704_067_200).unwrap();
+        let clock = Arc::new(VirtualClock::new(start_time));
+        let store = Arc::new(MemoryStore::new());
+        finished_topic_message(&store, "audit-delivered", "audit", MessageStatus::Delivered, start_time);
+        finished_topic_message(&store, "audit-dead", "audit", MessageStatus::Dead, start_time);
+        finished_message(&store, "delivered", MessageStatus::Delivered, start_time);
+
+        let topics = Arc::new(TopicRegistry::new());
+        let settings = TopicSettings { delivered_messages_retention: Some(Duration::days(7)), ..TopicSettings::default() };
+        topics.create("audit", settings, start_time).unwrap();
+        let policy = RetentionPolicy { delivered: Some(Duration::days(1)), dead: Some(Duration::days(3)) };
+        let sweeper = RetentionSweeper::new(store.clone(), clock.clone(), policy).with_topics(topics);
+
+        clock.advance(Duration::days(2));
+        assert_eq!(sweeper.sweep().unwrap(), SweepReport { delivered: 1, dead: 0 });
+        assert!(store.get_message("audit-delivered").unwrap().is_some());
+        // the dead messages of the topic follow db.deadMessages.retention
+        clock.advance(Duration::days(2));
+        assert_eq!(sweeper.sweep().unwrap(), SweepReport { delivered: 0, dead: 1 });
+        clock.advance(Duration::days(4));
+        assert_eq!(sweeper.sweep().unwrap(), SweepReport { delivered: 1, dead: 0 });
+    }
+}
@@ -0,0 +1,221 @@
+use std::{
+    collections::BTreeMap,
+    sync::{mpsc::{self, RecvTimeoutError, Sender}, Arc, Mutex},
+    thread::{self, JoinHandle},
+    time::Duration as StdDuration,
+};
+
+use time::OffsetDateTime;
+
+use crate::{
+    db::{MessageQuery, MessageStore, StoreError},
+    log,
+    msgproc::processor::MessageProcessor,
+    utils::{clock::Clock, json::JsonValue, log::Level, time::format_rfc3339},
+};
+
+/// The usage of a namespace (`serviceId`) in a period, recorded in the store for billing
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UsageRecord {
+    pub namespace: String,
+    pub period_start: OffsetDateTime,
+    pub period_end: OffsetDateTime,
+    /// How many messages were published in the period
+    pub publishes: u64,
+    /// How many messages were delivered in the period
+    pub deliveries: u64,
+    /// The bytes of the stored payloads at the end of the period times the hours of the period
+    pub storage_byte_hours: u64,
+}
+
+impl UsageRecord {
+    /// Serialize the record into a line of the NDJSON export
+    pub fn to_json(&self) -> JsonValue {
+        JsonValue::object()
+            .with("serviceId", self.namespace.as_str())
+            .with("periodStart", format_rfc3339(self.period_start))
+            .with("periodEnd", format_rfc3339(self.period_end))
+            .with("publishes", self.publishes)
+            .with("deliveries", self.deliveries)
+            .with("storageByteHours", self.storage_byte_hours)
+    }
+}
+
+/// Serialize the records as NDJSON, one record per line
+pub fn usage_ndjson(records: &[UsageRecord]) -> String {
+    records.iter().map(|record| format!("{}\n", record.to_json())).collect()
+}
+
+/// The publishes and deliveries counted by the processor, by namespace
+type NamespaceCounters = BTreeMap<String, (u64, u64)>;
+
+/// Record the usage of each namespace since the previous record: the publishes and deliveries
+/// counted by the processor and the payloads kept in the store. Namespaces without publishes,
+/// deliveries or stored payloads in the period are not recorded
+pub struct UsageMeter {
+    processor: Arc<MessageProcessor>,
+    store: Arc<dyn MessageStore>,
+    clock: Arc<dyn Clock>,
+    /// When the last period ended and what was counted until then
+    last: Mutex<(OffsetDateTime, NamespaceCounters)>,
+}
+
+impl UsageMeter {
+    /// Start the first period at the current time of the clock
+    pub fn new(processor: Arc<MessageProcessor>, store: Arc<dyn MessageStore>, clock: Arc<dyn Clock>) -> UsageMeter {
+        let counters = namespace_counters(&processor);
+        let last = Mutex::new((clock.now(), counters));
+        UsageMeter { processor, store, clock, last }
+    }
+
+    /// End the current period, writing the usage of each namespace into the store
+    pub fn record(&self) -> Result<Vec<UsageRecord>, StoreError> {
+        let mut last = self.last.lock().unwrap();
+        let (period_start, previous) = &*last;
+        let period_end = self.clock.now();
+        let counters = namespace_counters(&self.processor);
+
+        let mut stored_bytes: BTreeMap<String, u64> = BTreeMap::new();
+        for message in self.store.find_messages(&MessageQuery::default())? {
+            *stored_bytes.entry(message.namespace().to_string()).or_default() += message.payload.len() as u64;
+        }
+        let period_millis = (period_end - *period_start).whole_milliseconds().max(0) as u128;
+
+        let mut namespaces: Vec<&String> = counters.keys().chain(stored_bytes.keys()).collect();
+        namespaces.sort();
+        namespaces.dedup();
+        let records: Vec<UsageRecord> = namespaces.into_iter()
+            .map(|namespace| {
+                let (publishes, deliveries) = counters.get(namespace).copied().unwrap_or_default();
+                let (previous_publishes, previous_deliveries) = previous.get(namespace).copied().unwrap_or_default();
+                let bytes = stored_bytes.get(namespace).copied().unwrap_or_default();
+                UsageRecord {
+                    namespace: namespace.clone(),
+                    period_start: *period_start,
+                    period_end,
+                    publishes: publishes.saturating_sub(previous_publishes),
+                    deliveries: deliveries.saturating_sub(previous_deliveries),
+                    storage_byte_hours: (u128::from(bytes) * period_millis / 3_600_000) as u64,
+                }
+            })
+            .filter(|record| record.publishes > 0 || record.deliveries > 0 || record.storage_byte_hours > 0)
+            .collect();
+
+        self.store.record_usage(&records)?;
+        *last = (period_end, counters);
+        Ok(records)
+    }
+
+    /// Record the usage on a background thread every `interval`
+    pub fn start(self, interval: StdDuration) -> UsageMeterHandle {
+        let (sender, receiver) = mpsc::channel::<()>();
+        let thread = thread::Builder::new()
+            .name(String::from("angler-usage-meter"))
+            .spawn(move || loop {
+                match receiver.recv_timeout(interval) {
+                    Ok(()) | Err(RecvTimeoutError::Disconnected) => return,
+                    Err(RecvTimeoutError::Timeout) => {
+                        if let Err(err) = self.record() {
+                            log!(Level::Error, "Failed to record the usage of the namespaces: {}", err);
+                        }
+                    }
+                }
+            })
+            .expect("failed to spawn the usage meter");
+
+        UsageMeterHandle { sender, thread: Some(thread) }
+    }
+}
+
+fn namespace_counters(processor: &MessageProcessor) -> NamespaceCounters {
+    let mut counters = NamespaceCounters::new();
+    for ((namespace, _), topic) in processor.stats().topics() {
+        let (publishes, deliveries) = counters.entry(namespace).or_default();
+        *publishes += topic.messages_in;
+        *deliveries += topic.delivered;
+    }
+    counters
+}
+
+/// Stop the usage records when dropped
+pub struct UsageMeterHandle {
+    sender: Sender<()>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl UsageMeterHandle {
+    /// Stop the usage records, waiting for the record in progress
+    pub fn stop(&mut self) {
+        let _ = self.sender.send(());
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+impl Drop for UsageMeterHandle {
+    fn drop(&mut self) {
+        self.stop();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use time::Duration;
+
+    use crate::{
+        db::{batch::BatchConfiguration, memory::MemoryStore},
+        msgproc::{delivery::Deliverer, message::{AttemptOutcome, Message, MessageStatus}},
+        utils::clock::VirtualClock,
+    };
+
+    use super::*;
+
+    struct AlwaysDelivers;
+
+    impl Deliverer for AlwaysDelivers {
+        fn deliver(&self, _: &Message) -> AttemptOutcome {
+            AttemptOutcome::Delivered
+        }
+    }
+
+    #[test]
+    fn test_if_usage_is_recorded_by_namespace_for_each_period() {
+        let start_time = OffsetDateTime::from_unix_timestamp(1_704_067_200).unwrap();
+        let clock = Arc::new(VirtualClock::new(start_time));
+        let store = Arc::new(MemoryStore::new());
+        let processor = Arc::new(MessageProcessor::start_with_clock(1, store.clone(), BatchConfiguration::default(), Arc::new(AlwaysDelivers), clock.clone()));
+        let meter = UsageMeter::new(processor.clone(), store.clone(), clock.clone());
+
+        let publish = |id: &str, namespace: &str, payload: &[u8]| {
+            let message = Message::new_at(id.to_string(), String::from("r"), namespace.to_string(), String::from("e"), payload.to_vec(), clock.now());
+            processor.publish(message).unwrap();
+        };
+        publish("a", "orders", &[0; 1000]);
+        publish("b", "orders", &[0; 500]);
+        publish("c", "billing", &[0; 10]);
+        for id in ["a", "b", "c"] {
+            while store.get_message(id).unwrap().is_none_or(|message| message.status != MessageStatus::Delivered) {
+                processor.flush().unwrap();
+            }
+        }
+
+        clock.advance(Duration::hours(2));
+        let records = meter.record().unwrap();
+        assert_eq!(records.iter().map(|record| (record.namespace.as_str(), record.publishes, record.deliveries, record.storage_byte_hours)).collect::<Vec<_>>(),
+            vec![("billing", 1, 1, 20), ("orders", 2, 2, 3000)]);
+        assert_eq!(records[0].period_start, start_time);
+        assert_eq!(records[0].period_end, start_time + Duration::hours(2));
+
+        // only the storage is charged in the next period
+        clock.advance(Duration::minutes(30));
+        let records = meter.record().unwrap();
+        assert_eq!(records.iter().map(|record| (record.publishes, record.deliveries, record.storage_byte_hours)).collect::<Vec<_>>(), vec![(0, 0, 5), (0, 0, 750)]);
+
+        let stored = store.find_usage(start_time, start_time + Duration::hours(2)).unwrap();
+        assert_eq!(stored.len(), 2);
+        assert_eq!(usage_ndjson(&stored[..1]),
+            "{\"deliveries\":1,\"periodEnd\":\"2024-01-01T02:00:00.000Z\",\"periodStart\":\"2024-01-01T00:00:00.000Z\",\"publishes\":1,\"serviceId\":\"billing\",\"storageByteHours\":20}\n");
+        processor.shutdown().unwrap();
+    }
+}
@@ -0,0 +1,198 @@
+use std::{
+    collections::HashMap,
+    io,
+    net::SocketAddr,
+    sync::{Arc, Condvar, Mutex},
+    thread,
+    time::{Duration, Instant},
+};
+
+use crate::net::http::{HttpHandler, HttpRequest, HttpResponse, HttpServer};
+
+/// How a route of the MockDestinationServer answers the requests it receives
+#[derive(Debug, Clone)]
+struct MockRoute {
+    /// The statuses returned for each request, in order. The last status repeats forever
+    statuses: Vec<u16>,
+    /// How many requests this route has answered
+    answered: usize,
+    /// How long the route waits before answering
+    latency: Duration,
+    /// The `Location` header sent in the responses
+    location: Option<String>,
+    /// Other headers sent in the responses
+    headers: Vec<(String, String)>,
+}
+
+impl Default for MockRoute {
+    fn default() -> Self {
+        MockRoute { statuses: vec![200], answered: 0, latency: Duration::ZERO, location: None, headers: Vec::new() }
+    }
+}
+
+/// A request captured by the MockDestinationServer
+#[derive(Debug, Clone)]
+pub struct CapturedRequest {
+    pub request: HttpRequest,
+    pub received_at: Instant,
+}
+
+#[derive(Default)]
+struct MockState {
+    routes: Mutex<HashMap<String, MockRoute>>,
+    requests: Mutex<Vec<CapturedRequest>>,
+    request_received: Condvar,
+}
+
+impl MockState {
+    fn handle(&self, request: &HttpRequest) -> HttpResponse {
+        let (status, latency, location, headers) = {
+            let mut routes = self.routes.lock().unwrap();
+            let route = routes.entry(request.path().to_string()).or_default();
+            let status = route.statuses[route.answered.min(route.statuses.len() - 1)];
+            route.answered += 1;
+            (status, route.latency, route.location.clone(), route.headers.clone())
+        };
+
+        if !latency.is_zero() {
+            thread::sleep(latency);
+        }
+
+        // the request is captured after the latency so a test waiting for it also waits the response
+        self.requests.lock().unwrap().push(CapturedRequest { request: request.clone(), received_at: Instant::now() });
+        self.request_received.notify_all();
+        let mut response = HttpResponse::new(status);
+        if let Some(location) = location {
+            response.headers.set("Location", &location);
+        }
+        for (name, value) in headers {
+            response.headers.set(&name, &value);
+        }
+        response
+    }
+}
+
+/// A programmable HTTP destination. Each route (request path) answers with a configurable sequence
+/// of statuses after a configurable latency, and every request received is captured so tests can
+/// assert what was sent. Routes that were not configured answer `200`.
+///
+/// ```no_run
+/// use angler::testutil::mock_destination::MockDestinationServer;
+///
+/// let server = MockDestinationServer::start().unwrap();
+/// server.respond_with("/hooks", &[503, 503, 200]);
+/// let url = server.url("/hooks"); // register this URL as the destination
+/// ```
+pub struct MockDestinationServer {
+    server: HttpServer,
+    state: Arc<MockState>,
+}
+
+impl MockDestinationServer {
+    /// Start a server listening on an ephemeral port of the loopback interface
+    pub fn start() -> io::Result<MockDestinationServer> {
+        let state = Arc::new(MockState::default());
+        let handler_state = state.clone();
+        let handler: Arc<HttpHandler> = Arc::new(move |request: &HttpRequest| handler_state.handle(request));
+        let server = HttpServer::bind("127.0.0.1:0", handler)?;
+        Ok(MockDestinationServer { server, state })
+    }
+
+    /// Return the address the server is listening to
+    pub fn local_addr(&self) -> SocketAddr {
+        self.server.local_addr()
+    }
+
+    /// Return the URL of the given path on this server
+    pub fn url(&self, path: &str) -> String {
+        format!("http://{}{}", self.local_addr(), path)
+    }
+
+    /// Make the route answer with the statuses in order, repeating the last one forever. It also
+    /// restarts the sequence of the route
+    pub fn respond_with(&self, path: &str, statuses: &[u16]) {
+        assert!(!statuses.is_empty(), "a route should answer with at least one status");
+        let mut routes = self.state.routes.lock().unwrap();
+        let route = routes.entry(path.to_string()).or_default();
+        route.statuses = statuses.to_vec();
+        route.answered = 0;
+    }
+
+    /// Make the route answer every request with a redirect to the location
+    pub fn redirect(&self, path: &str, status: u16, location: &str) {
+        self.respond_with(path, &[status]);
+        self.state.routes.lock().unwrap().entry(path.to_string()).or_default().location = Some(location.to_string());
+    }
+
+    /// Make the route send the header in every response, like the `Accept-Encoding` of a receiver
+    pub fn set_header(&self, path: &str, name: &str, value: &str) {
+        self.state.routes.lock().unwrap().entry(path.to_string()).or_default().headers.push((name.to_string(), value.to_string()));
+    }
+
+    /// Make the route wait the given latency before answering each request
+    pub fn set_latency(&self, path: &str, latency: Duration) {
+        self.state.routes.lock().unwrap().entry(path.to_string()).or_default().latency = latency;
+    }
+
+    /// Return all requests received by the server
+    pub fn requests(&self) -> Vec<CapturedRequest> {
+        self.state.requests.lock().unwrap().clone()
+    }
+
+    /// Return all requests received by the given route
+    pub fn requests_to(&self, path: &str) -> Vec<CapturedRequest> {
+        self.requests().into_iter().filter(|captured| captured.request.path() == path).collect()
+    }
+
+    /// Wait until the route received at least `count` requests. Return false on timeout
+    pub fn wait_for_requests(&self, path: &str, count: usize, timeout: Duration) -> bool {
+        let deadline = Instant::now() + timeout;
+        let mut requests = self.state.requests.lock().unwrap();
+        loop {
+            if requests.iter().filter(|captured| captured.request.path() == path).count() >= count {
+                return true;
+            }
+            let Some(remaining) = deadline.checked_duration_since(Instant::now()) else {
+                return false;
+            };
+            requests = self.state.request_received.wait_timeout(requests, remaining).unwrap().0;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::net::http::{send_request, HttpUrl};
+
+    use super::*;
+
+    fn post(server: &MockDestinationServer, path: &str) -> u16 {
+        let url = HttpUrl::parse(&server.url(path)).unwrap();
+        let mut request = HttpRequest::new("POST", path);
+        request.body = b"payload".to_vec();
+        send_request(&url, request, Duration::from_secs(5)).unwrap().status
+    }
+
+    #[test]
+    fn test_if_route_follows_status_sequence_and_repeats_the_last_status() {
+        let server = MockDestinationServer::start().unwrap();
+        server.respond_with("/a", &[503, 200]);
+
+        assert_eq!(post(&server, "/a"), 503);
+        assert_eq!(post(&server, "/a"), 200);
+        assert_eq!(post(&server, "/a"), 200);
+        assert_eq!(post(&server, "/unconfigured"), 200);
+        assert_eq!(server.requests_to("/a").len(), 3);
+        assert_eq!(server.requests_to("/a")[0].request.body, b"payload");
+    }
+
+    #[test]
+    fn test_if_route_latency_is_applied() {
+        let server = MockDestinationServer::start().unwrap();
+        server.set_latency("/slow", Duration::from_millis(100));
+
+        let started_at = Instant::now();
+        post(&server, "/slow");
+        assert!(started_at.elapsed() >= Duration::from_millis(100));
+    }
+}
@@ -0,0 +1,4 @@
+//! Helpers to test Angler and the retry configurations of applications that use it. Only
+//! available with the `test-util` feature.
+
+pub mod mock_destination;
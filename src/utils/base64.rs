@@ -0,0 +1,96 @@
+const ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+/// Encode the bytes in standard base64 with padding
+pub fn encode(input: &[u8]) -> String {
+    let mut output = String::with_capacity(input.len().div_ceil(3) * 4);
+    for chunk in input.chunks(3) {
+        let mut bytes = [0u8; 3];
+        bytes[..chunk.len()].copy_from_slice(chunk);
+        let bits = u32::from_be_bytes([0, bytes[0], bytes[1], bytes[2]]);
+        for index in 0..4 {
+            if index <= chunk.len() {
+                output.push(char::from(ALPHABET[(bits >> (18 - 6 * index) & 0x3f) as usize]));
+            } else {
+                output.push('=');
+            }
+        }
+    }
+    output
+}
+
+/// Decode standard base64 with padding, returning None when it is invalid
+pub fn decode(input: &str) -> Option<Vec<u8>> {
+    let input = input.as_bytes();
+    if !input.len().is_multiple_of(4) {
+        return None;
+    }
+    let value = |c: u8| -> Option<u32> {
+        match c {
+            b'A'..=b'Z' => Some(u32::from(c - b'A')),
+            b'a'..=b'z' => Some(u32::from(c - b'a') + 26),
+            b'0'..=b'9' => Some(u32::from(c - b'0') + 52),
+            b'+' => Some(62),
+            b'/' => Some(63),
+            _ => None,
+        }
+    };
+
+    let mut output = Vec::with_capacity(input.len() / 4 * 3);
+    for (index, chunk) in input.chunks(4).enumerate() {
+        let padding = chunk.iter().rev().take_while(|c| **c == b'=').count();
+        if padding > 2 || (padding > 0 && index != input.len() / 4 - 1) {
+            return None;
+        }
+        let mut bits = 0u32;
+        for c in &chunk[..4 - padding] {
+            bits = (bits << 6) | value(*c)?;
+        }
+        bits <<= 6 * padding as u32;
+        output.extend_from_slice(&bits.to_be_bytes()[1..4 - padding]);
+    }
+    Some(output)
+}
+
+/// Encode the bytes in the URL and filename safe base64, without padding
+pub fn encode_url(input: &[u8]) -> String {
+    encode(input).trim_end_matches('=').replace('+', "-").replace('/', "_")
+}
+
+/// Decode the URL and filename safe base64, with or without padding
+pub fn decode_url(input: &str) -> Option<Vec<u8>> {
+    if input.contains(['+', '/']) {
+        return None;
+    }
+    let mut standard = input.trim_end_matches('=').replace('-', "+").replace('_', "/");
+    while !standard.len().is_multiple_of(4) {
+        standard.push('=');
+    }
+    decode(&standard)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_if_base64_is_decoded() {
+        assert_eq!(decode("YWRtaW46czNjcjN0").unwrap(), b"admin:s3cr3t");
+        assert_eq!(decode("YQ==").unwrap(), b"a");
+        assert_eq!(decode("YWI=").unwrap(), b"ab");
+        assert_eq!(decode(""), Some(vec![]));
+        assert_eq!(decode("YQ=="[..3].as_ref()), None);
+        assert_eq!(decode("Y!=="), None);
+    }
+
+    #[test]
+    fn test_if_encoded_bytes_are_decoded_back() {
+        assert_eq!(encode(b"admin:s3cr3t"), "YWRtaW46czNjcjN0");
+        assert_eq!(encode(b"a"), "YQ==");
+        assert_eq!(encode(b"ab"), "YWI=");
+        let bytes: Vec<u8> = (0..=255).collect();
+        assert_eq!(decode(&encode(&bytes)).unwrap(), bytes);
+        assert_eq!(decode_url(&encode_url(&bytes)).unwrap(), bytes);
+        assert_eq!(encode_url(&[0xfb, 0xff]), "-_8");
+        assert_eq!(decode_url("+_8"), None);
+    }
+}
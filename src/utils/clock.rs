@@ -0,0 +1,162 @@
+use std::{
+    sync::{Arc, Mutex, OnceLock},
+    time::{Duration as StdDuration, Instant},
+};
+
+use time::{Duration, OffsetDateTime};
+
+/// Called by a VirtualClock every time its time moves
+pub type ClockListener = dyn Fn() + Send + Sync;
+
+/// The source of time used by Angler. All the code should read the time from a Clock instead of
+/// calling `OffsetDateTime::now_utc` so simulations and tests can control it.
+///
+/// A Clock has two readings: the wall clock time (`now`), that is stored and shown to users, and a
+/// monotonic time (`monotonic`) that never goes backwards. Waits, like the retry schedule, should
+/// be anchored to the monotonic time so they are not affected by wall clock jumps (NTP corrections
+/// or a wrong system time being fixed)
+pub trait Clock: Send + Sync {
+    /// Return the current wall clock time, in UTC
+    fn now(&self) -> OffsetDateTime;
+
+    /// Return the time elapsed since an arbitrary point fixed when the clock was created. It only
+    /// moves forward
+    fn monotonic(&self) -> StdDuration;
+
+    /// Return how long a thread should block, in real time, to wait for `duration` of the monotonic
+    /// time of this clock. None means the thread should block until one of the `on_advance`
+    /// listeners is called
+    fn real_wait(&self, duration: StdDuration) -> Option<StdDuration> {
+        Some(duration)
+    }
+
+    /// Register a listener called every time the time of this clock is moved by hand. Clocks that
+    /// follow the real time never call it
+    fn on_advance(&self, _listener: Arc<ClockListener>) {}
+}
+
+/// Return how long, in monotonic time, until the wall clock time `at` is reached. Times in the
+/// past return zero
+pub fn monotonic_deadline(clock: &dyn Clock, at: OffsetDateTime) -> StdDuration {
+    let wait = at - clock.now();
+    clock.monotonic() + if wait.is_positive() { wait.unsigned_abs() } else { StdDuration::ZERO }
+}
+
+/// The Clock that follows the system clocks
+#[derive(Debug, Default, Clone, Copy)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> OffsetDateTime {
+        OffsetDateTime::now_utc()
+    }
+
+    fn monotonic(&self) -> StdDuration {
+        static ANCHOR: OnceLock<Instant> = OnceLock::new();
+        ANCHOR.get_or_init(Instant::now).elapsed()
+    }
+}
+
+struct VirtualTime {
+    now: OffsetDateTime,
+    monotonic: StdDuration,
+}
+
+/// A Clock that only moves when told to. Used by simulations to fast-forward days of retry
+/// schedules in milliseconds and to reproduce timing bugs deterministically
+pub struct VirtualClock {
+    time: Mutex<VirtualTime>,
+    listeners: Mutex<Vec<Arc<ClockListener>>>,
+}
+
+impl VirtualClock {
+    /// Create a clock stopped at the given time
+    pub fn new(start: OffsetDateTime) -> VirtualClock {
+        VirtualClock {
+            time: Mutex::new(VirtualTime { now: start, monotonic: StdDuration::ZERO }),
+            listeners: Mutex::new(vec![]),
+        }
+    }
+
+    /// Let `duration` pass, moving both the wall clock and the monotonic time forward
+    pub fn advance(&self, duration: Duration) {
+        {
+            let mut time = self.time.lock().unwrap();
+            time.now += duration;
+            time.monotonic += duration.unsigned_abs();
+        }
+        self.notify();
+    }
+
+    /// Move the wall clock to the given time without moving the monotonic time. It simulates a wall
+    /// clock jump, like a NTP correction
+    pub fn set(&self, now: OffsetDateTime) {
+        self.time.lock().unwrap().now = now;
+        self.notify();
+    }
+
+    fn notify(&self) {
+        let listeners: Vec<Arc<ClockListener>> = self.listeners.lock().unwrap().clone();
+        for listener in listeners {
+            listener();
+        }
+    }
+}
+
+impl Clock for VirtualClock {
+    fn now(&self) -> OffsetDateTime {
+        self.time.lock().unwrap().now
+    }
+
+    fn monotonic(&self) -> StdDuration {
+        self.time.lock().unwrap().monotonic
+    }
+
+    fn real_wait(&self, _duration: StdDuration) -> Option<StdDuration> {
+        None
+    }
+
+    fn on_advance(&self, listener: Arc<ClockListener>) {
+        self.listeners.lock().unwrap().push(listener);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use super::*;
+
+    fn datetime(unix_timestamp: i64) -> OffsetDateTime {
+        OffsetDateTime::from_unix_timestamp(unix_timestamp).unwrap()
+    }
+
+    #[test]
+    fn test_if_virtual_clock_only_moves_when_told_to() {
+        let clock = VirtualClock::new(datetime(1_704_067_200));
+        let advances = Arc::new(AtomicUsize::new(0));
+        let counter = advances.clone();
+        clock.on_advance(Arc::new(move || { counter.fetch_add(1, Ordering::SeqCst); }));
+
+        assert_eq!(clock.now(), datetime(1_704_067_200));
+        clock.advance(Duration::days(3));
+        assert_eq!(clock.now(), datetime(1_704_326_400));
+        clock.set(datetime(1_704_153_600));
+        assert_eq!(clock.now(), datetime(1_704_153_600));
+        assert_eq!(clock.monotonic(), Duration::days(3).unsigned_abs());
+        assert_eq!(advances.load(Ordering::SeqCst), 2);
+        assert_eq!(clock.real_wait(StdDuration::from_secs(3600)), None);
+    }
+
+    #[test]
+    fn test_if_monotonic_deadline_ignores_past_times() {
+        let clock = VirtualClock::new(datetime(1_704_067_200));
+        clock.advance(Duration::hours(1));
+        assert_eq!(monotonic_deadline(&clock, datetime(1_704_067_200)), StdDuration::from_secs(3600));
+        assert_eq!(monotonic_deadline(&clock, datetime(1_704_067_200) + Duration::hours(2)), StdDuration::from_secs(7200));
+
+        let system_clock = SystemClock;
+        let before = system_clock.monotonic();
+        assert!(system_clock.monotonic() >= before);
+    }
+}
@@ -0,0 +1,470 @@
+use thiserror::Error;
+
+/// The first bytes of a gzip member, its magic number and the DEFLATE method
+const GZIP_MAGIC: [u8; 3] = [0x1f, 0x8b, 8];
+/// The flags of the optional fields of the gzip header
+const FLAG_HCRC: u8 = 2;
+const FLAG_EXTRA: u8 = 4;
+const FLAG_NAME: u8 = 8;
+const FLAG_COMMENT: u8 = 16;
+
+const MIN_MATCH: usize = 3;
+const MAX_MATCH: usize = 258;
+const WINDOW_SIZE: usize = 32 * 1024;
+const HASH_BITS: u32 = 15;
+const END_OF_BLOCK: u16 = 256;
+
+/// The first length of each length code from 257, and its extra bits
+const LENGTH_BASES: [u16; 29] = [3, 4, 5, 6, 7, 8, 9, 10, 11, 13, 15, 17, 19, 23, 27, 31, 35, 43, 51, 59, 67, 83, 99, 115, 131, 163, 195, 227, 258];
+const LENGTH_EXTRA_BITS: [u8; 29] = [0, 0, 0, 0, 0, 0, 0, 0, 1, 1, 1, 1, 2, 2, 2, 2, 3, 3, 3, 3, 4, 4, 4, 4, 5, 5, 5, 5, 0];
+/// The first distance of each distance code, and its extra bits
+const DISTANCE_BASES: [u16; 30] = [1, 2, 3, 4, 5, 7, 9, 13, 17, 25, 33, 49, 65, 97, 129, 193, 257, 385, 513, 769, 1025, 1537, 2049, 3073, 4097, 6145, 8193, 12289, 16385, 24577];
+const DISTANCE_EXTRA_BITS: [u8; 30] = [0, 0, 0, 0, 1, 1, 2, 2, 3, 3, 4, 4, 5, 5, 6, 6, 7, 7, 8, 8, 9, 9, 10, 10, 11, 11, 12, 12, 13, 13];
+/// The order of the code lengths of the code length alphabet in a dynamic block
+const CODE_LENGTH_ORDER: [usize; 19] = [16, 17, 18, 0, 8, 7, 9, 6, 10, 5, 11, 4, 12, 3, 13, 2, 14, 1, 15];
+
+const CRC_TABLE: [u32; 256] = crc_table();
+
+#[derive(Debug, Error, PartialEq)]
+pub enum GzipError {
+    #[error("the data is not gzip compressed")]
+    InvalidHeader,
+    #[error("the compressed data ends before its end")]
+    Truncated,
+    #[error("the compressed data is invalid: {0}")]
+    InvalidData(&'static str),
+    #[error("the decompressed data does not match its checksum")]
+    ChecksumMismatch,
+    #[error("the decompressed data has more than {0} bytes")]
+    TooLarge(usize),
+}
+
+const fn crc_table() -> [u32; 256] {
+    let mut table = [0u32; 256];
+    let mut index = 0;
+    while index < 256 {
+        let mut crc = index as u32;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 1 == 1 { 0xedb8_8320 ^ (crc >> 1) } else { crc >> 1 };
+            bit += 1;
+        }
+        table[index] = crc;
+        index += 1;
+    }
+    table
+}
+
+/// Return the Adler-32 of the bytes, the checksum of the zlib trailer
+pub fn adler32(input: &[u8]) -> u32 {
+    let (a, b) = input.chunks(5552).fold((1u32, 0u32), |(mut a, mut b), chunk| {
+        for byte in chunk {
+            a += u32::from(*byte);
+            b += a;
+        }
+        (a % 65521, b % 65521)
+    });
+    (b << 16) | a
+}
+
+/// Return the CRC-32 of the bytes, the checksum of the gzip trailer
+pub fn crc32(input: &[u8]) -> u32 {
+    !input.iter().fold(!0u32, |crc, byte| CRC_TABLE[((crc ^ u32::from(*byte)) & 0xff) as usize] ^ (crc >> 8))
+}
+
+/// Write the bits of the DEFLATE stream from the least significant one
+struct BitWriter {
+    output: Vec<u8>,
+    bits: u64,
+    count: u32,
+}
+
+impl BitWriter {
+    fn write(&mut self, value: u32, count: u32) {
+        self.bits |= u64::from(value) << self.count;
+        self.count += count;
+        while self.count >= 8 {
+            self.output.push(self.bits as u8);
+            self.bits >>= 8;
+            self.count -= 8;
+        }
+    }
+
+    /// Write a Huffman code, whose bits go from the most significant one
+    fn write_code(&mut self, code: u16, length: u32) {
+        self.write(u32::from(code.reverse_bits() >> (16 - length)), length);
+    }
+
+    fn finish(mut self) -> Vec<u8> {
+        if self.count > 0 {
+            self.output.push(self.bits as u8);
+        }
+        self.output
+    }
+}
+
+/// Write a literal or length symbol with the fixed Huffman codes
+fn write_literal(writer: &mut BitWriter, symbol: u16) {
+    match symbol {
+        0..=143 => writer.write_code(0x30 + symbol, 8),
+        144..=255 => writer.write_code(0x190 + symbol - 144, 9),
+        256..=279 => writer.write_code(symbol - 256, 7),
+        _ => writer.write_code(0xc0 + symbol - 280, 8),
+    }
+}
+
+fn write_match(writer: &mut BitWriter, length: usize, distance: usize) {
+    let code = LENGTH_BASES.iter().rposition(|base| usize::from(*base) <= length).expect("the match has at least the shortest length");
+    write_literal(writer, 257 + code as u16);
+    writer.write((length - usize::from(LENGTH_BASES[code])) as u32, u32::from(LENGTH_EXTRA_BITS[code]));
+    let code = DISTANCE_BASES.iter().rposition(|base| usize::from(*base) <= distance).expect("the distance is at least 1");
+    writer.write_code(code as u16, 5);
+    writer.write((distance - usize::from(DISTANCE_BASES[code])) as u32, u32::from(DISTANCE_EXTRA_BITS[code]));
+}
+
+/// Compress the bytes into a gzip member. The DEFLATE stream is a single block with the fixed
+/// Huffman codes, and its matches are found greedily, so it favors speed over ratio like `lz4`
+pub fn compress(input: &[u8]) -> Vec<u8> {
+    let mut writer = BitWriter { output: Vec::with_capacity(input.len() / 2 + 32), bits: 0, count: 0 };
+    writer.output.extend_from_slice(&GZIP_MAGIC);
+    // no flags, no modification time, no extra flags and a unknown operating system
+    writer.output.extend_from_slice(&[0, 0, 0, 0, 0, 0, 0xff]);
+    // the final block, with the fixed Huffman codes
+    writer.write(1, 1);
+    writer.write(1, 2);
+
+    let mut table = vec![usize::MAX; 1 << HASH_BITS];
+    let mut position = 0;
+    while position < input.len() {
+        if position + MIN_MATCH > input.len() {
+            write_literal(&mut writer, u16::from(input[position]));
+            position += 1;
+            continue;
+        }
+        let sequence = u32::from(input[position]) | u32::from(input[position + 1]) << 8 | u32::from(input[position + 2]) << 16;
+        let slot = (sequence.wrapping_mul(2654435761) >> (32 - HASH_BITS)) as usize;
+        let candidate = table[slot];
+        table[slot] = position;
+        let matched = candidate != usize::MAX && position - candidate <= WINDOW_SIZE && input[candidate..candidate + MIN_MATCH] == input[position..position + MIN_MATCH];
+        if !matched {
+            write_literal(&mut writer, u16::from(input[position]));
+            position += 1;
+            continue;
+        }
+        let limit = (input.len() - position).min(MAX_MATCH);
+        let mut length = MIN_MATCH;
+        while length < limit && input[candidate + length] == input[position + length] {
+            length += 1;
+        }
+        write_match(&mut writer, length, position - candidate);
+        position += length;
+    }
+    write_literal(&mut writer, END_OF_BLOCK);
+
+    let mut output = writer.finish();
+    output.extend_from_slice(&crc32(input).to_le_bytes());
+    output.extend_from_slice(&(input.len() as u32).to_le_bytes());
+    output
+}
+
+/// Read the bits of the DEFLATE stream from the least significant one
+struct BitReader<'a> {
+    input: &'a [u8],
+    position: usize,
+    bits: u32,
+    count: u32,
+}
+
+impl BitReader<'_> {
+    fn read(&mut self, count: u32) -> Result<u32, GzipError> {
+        while self.count < count {
+            let byte = *self.input.get(self.position).ok_or(GzipError::Truncated)?;
+            self.position += 1;
+            self.bits |= u32::from(byte) << self.count;
+            self.count += 8;
+        }
+        let value = self.bits & ((1u64 << count) - 1) as u32;
+        self.bits >>= count;
+        self.count -= count;
+        Ok(value)
+    }
+
+    /// Drop the bits left of the current byte
+    fn align(&mut self) {
+        self.bits = 0;
+        self.count = 0;
+    }
+
+    fn read_bytes(&mut self, count: usize) -> Result<&[u8], GzipError> {
+        let bytes = self.input.get(self.position..self.position + count).ok_or(GzipError::Truncated)?;
+        self.position += count;
+        Ok(bytes)
+    }
+}
+
+/// A canonical Huffman code, by how many symbols have each code length and the symbols in the
+/// order of their codes
+struct Huffman {
+    counts: [u16; 16],
+    symbols: Vec<u16>,
+}
+
+impl Huffman {
+    fn new(lengths: &[u8]) -> Result<Huffman, GzipError> {
+        let mut counts = [0u16; 16];
+        for length in lengths {
+            counts[usize::from(*length)] += 1;
+        }
+        counts[0] = 0;
+        let mut left: i32 = 1;
+        for count in &counts[1..] {
+            left = (left << 1) - i32::from(*count);
+            if left < 0 {
+                return Err(GzipError::InvalidData("a Huffman code has too many codes"));
+            }
+        }
+        let mut offsets = [0u16; 16];
+        for length in 1..15 {
+            offsets[length + 1] = offsets[length] + counts[length];
+        }
+        let mut symbols = vec![0; lengths.len()];
+        for (symbol, length) in lengths.iter().enumerate().filter(|(_, length)| **length != 0) {
+            symbols[usize::from(offsets[usize::from(*length)])] = symbol as u16;
+            offsets[usize::from(*length)] += 1;
+        }
+        Ok(Huffman { counts, symbols })
+    }
+
+    fn decode(&self, reader: &mut BitReader) -> Result<u16, GzipError> {
+        let (mut code, mut first, mut index) = (0i32, 0i32, 0i32);
+        for count in &self.counts[1..] {
+            code |= reader.read(1)? as i32;
+            let count = i32::from(*count);
+            if code - first < count {
+                return Ok(self.symbols[(index + code - first) as usize]);
+            }
+            index += count;
+            first = (first + count) << 1;
+            code <<= 1;
+        }
+        Err(GzipError::InvalidData("a Huffman code is not in the table"))
+    }
+}
+
+/// Return the fixed Huffman codes of the literals and lengths, and of the distances
+fn fixed_codes() -> (Huffman, Huffman) {
+    let mut lengths = [8u8; 288];
+    lengths[144..256].fill(9);
+    lengths[256..280].fill(7);
+    let literals = Huffman::new(&lengths).expect("the fixed literal codes are complete");
+    (literals, Huffman::new(&[5; 30]).expect("the fixed distance codes are complete"))
+}
+
+/// Read the Huffman codes of a dynamic block
+fn dynamic_codes(reader: &mut BitReader) -> Result<(Huffman, Huffman), GzipError> {
+    let literals = reader.read(5)? as usize + 257;
+    let distances = reader.read(5)? as usize + 1;
+    let code_lengths = reader.read(4)? as usize + 4;
+    if literals > 286 || distances > 30 {
+        return Err(GzipError::InvalidData("a dynamic block has too many codes"));
+    }
+    let mut lengths = [0u8; 19];
+    for index in CODE_LENGTH_ORDER.iter().take(code_lengths) {
+        lengths[*index] = reader.read(3)? as u8;
+    }
+    let code_lengths = Huffman::new(&lengths)?;
+
+    let mut lengths = Vec::with_capacity(literals + distances);
+    while lengths.len() < literals + distances {
+        let (length, repeat) = match code_lengths.decode(reader)? {
+            symbol @ 0..=15 => (symbol as u8, 1),
+            16 => (*lengths.last().ok_or(GzipError::InvalidData("a code length repeats no length"))?, 3 + reader.read(2)? as usize),
+            17 => (0, 3 + reader.read(3)? as usize),
+            _ => (0, 11 + reader.read(7)? as usize),
+        };
+        if lengths.len() + repeat > literals + distances {
+            return Err(GzipError::InvalidData("the code lengths of a dynamic block are too many"));
+        }
+        lengths.extend(std::iter::repeat_n(length, repeat));
+    }
+    if lengths[usize::from(END_OF_BLOCK)] == 0 {
+        return Err(GzipError::InvalidData("a dynamic block has no end of block code"));
+    }
+    Ok((Huffman::new(&lengths[..literals])?, Huffman::new(&lengths[literals..])?))
+}
+
+/// Decompress a DEFLATE stream into the output, which can not grow past `max_len` bytes
+fn inflate(reader: &mut BitReader, output: &mut Vec<u8>, max_len: usize) -> Result<(), GzipError> {
+    loop {
+        let last = reader.read(1)? == 1;
+        match reader.read(2)? {
+            0 => {
+                reader.align();
+                let header = reader.read_bytes(4)?;
+                let length = u16::from_le_bytes([header[0], header[1]]);
+                if length != !u16::from_le_bytes([header[2], header[3]]) {
+                    return Err(GzipError::InvalidData("a stored block has a invalid length"));
+                }
+                if output.len() + usize::from(length) > max_len {
+                    return Err(GzipError::TooLarge(max_len));
+                }
+                output.extend_from_slice(reader.read_bytes(usize::from(length))?);
+            }
+            kind @ (1 | 2) => {
+                let (literals, distances) = if kind == 1 { fixed_codes() } else { dynamic_codes(reader)? };
+                loop {
+                    let symbol = literals.decode(reader)?;
+                    if output.len() >= max_len && symbol != END_OF_BLOCK {
+                        return Err(GzipError::TooLarge(max_len));
+                    }
+                    match symbol {
+                        0..=255 => output.push(symbol as u8),
+                        END_OF_BLOCK => break,
+                        _ => {
+                            let code = usize::from(symbol - 257);
+                            let base = *LENGTH_BASES.get(code).ok_or(GzipError::InvalidData("a length code is invalid"))?;
+                            let length = usize::from(base) + reader.read(u32::from(LENGTH_EXTRA_BITS[code]))? as usize;
+                            let code = usize::from(distances.decode(reader)?);
+                            let base = *DISTANCE_BASES.get(code).ok_or(GzipError::InvalidData("a distance code is invalid"))?;
+                            let distance = usize::from(base) + reader.read(u32::from(DISTANCE_EXTRA_BITS[code]))? as usize;
+                            if distance > output.len() {
+                                return Err(GzipError::InvalidData("a match starts before the data"));
+                            }
+                            if output.len() + length > max_len {
+                                return Err(GzipError::TooLarge(max_len));
+                            }
+                            let start = output.len() - distance;
+                            for index in 0..length {
+                                output.push(output[start + index]);
+                            }
+                        }
+                    }
+                }
+            }
+            _ => return Err(GzipError::InvalidData("a block has a reserved type")),
+        }
+        if last {
+            return Ok(());
+        }
+    }
+}
+
+/// Decompress the gzip members, like the ones written by `compress` or by the `gzip` tool. The
+/// output can not grow past `max_len` bytes, so a small body can not expand into a huge one
+pub fn decompress(input: &[u8], max_len: usize) -> Result<Vec<u8>, GzipError> {
+    let mut output = Vec::new();
+    let mut position = 0;
+    loop {
+        let member = &input[position..];
+        if member.len() < 10 || member[..3] != GZIP_MAGIC {
+            return Err(GzipError::InvalidHeader);
+        }
+        let flags = member[3];
+        let mut reader = BitReader { input: member, position: 10, bits: 0, count: 0 };
+        if flags & FLAG_EXTRA != 0 {
+            let length = reader.read_bytes(2)?;
+            let length = usize::from(u16::from_le_bytes([length[0], length[1]]));
+            reader.read_bytes(length)?;
+        }
+        for flag in [FLAG_NAME, FLAG_COMMENT] {
+            if flags & flag != 0 {
+                let end = member[reader.position..].iter().position(|byte| *byte == 0).ok_or(GzipError::Truncated)?;
+                reader.position += end + 1;
+            }
+        }
+        if flags & FLAG_HCRC != 0 {
+            reader.read_bytes(2)?;
+        }
+
+        let start = output.len();
+        inflate(&mut reader, &mut output, max_len)?;
+        reader.align();
+        let trailer = reader.read_bytes(8)?;
+        let (checksum, size) = (u32::from_le_bytes(trailer[..4].try_into().unwrap()), u32::from_le_bytes(trailer[4..].try_into().unwrap()));
+        if crc32(&output[start..]) != checksum || (output.len() - start) as u32 != size {
+            return Err(GzipError::ChecksumMismatch);
+        }
+        position += reader.position;
+        if position == input.len() {
+            return Ok(output);
+        }
+    }
+}
+
+/// Return if the bytes start with a zlib header of a DEFLATE stream without preset dictionary
+fn is_zlib_header(input: &[u8]) -> bool {
+    match input {
+        [method, flags, ..] => method & 0x0f == 8 && method >> 4 <= 7 && flags & 0x20 == 0 && (u16::from(*method) << 8 | u16::from(*flags)) % 31 == 0,
+        _ => false,
+    }
+}
+
+/// Decompress a `Content-Encoding: deflate` body: a zlib stream, or a raw DEFLATE stream as sent
+/// by some clients. The output can not grow past `max_len` bytes
+pub fn decompress_deflate(input: &[u8], max_len: usize) -> Result<Vec<u8>, GzipError> {
+    let mut output = Vec::new();
+    if !is_zlib_header(input) {
+        inflate(&mut BitReader { input, position: 0, bits: 0, count: 0 }, &mut output, max_len)?;
+        return Ok(output);
+    }
+    let mut reader = BitReader { input, position: 2, bits: 0, count: 0 };
+    inflate(&mut reader, &mut output, max_len)?;
+    reader.align();
+    let trailer = reader.read_bytes(4)?;
+    if adler32(&output) != u32::from_be_bytes(trailer.try_into().unwrap()) {
+        return Err(GzipError::ChecksumMismatch);
+    }
+    Ok(output)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_if_compressed_bytes_are_decompressed_back() {
+        assert_eq!(crc32(b"123456789"), 0xcbf4_3926);
+        let payload = br#"{"orderId": 12345, "items": [{"sku": "a-1", "quantity": 2}, {"sku": "a-1", "quantity": 2}]}"#.repeat(200);
+        let compressed = compress(&payload);
+        assert!(compressed.len() < payload.len() / 10, "{} bytes compressed into {}", payload.len(), compressed.len());
+        assert_eq!(decompress(&compressed, payload.len()).unwrap(), payload);
+        assert_eq!(decompress(&compressed, payload.len() - 1), Err(GzipError::TooLarge(payload.len() - 1)));
+        for input in [&b""[..], b"a", b"ab", b"aaaaaaaaaaaaaaaaaaaaaaaa", &(0..=255).collect::<Vec<u8>>()] {
+            assert_eq!(decompress(&compress(input), 1024).unwrap(), input);
+        }
+
+        // written by Python's `gzip.compress`, whose DEFLATE stream has a dynamic block
+        let written = "1f8b08000000000002033d8bab0e803010043d5f41568368018343f20d0471d08a8614481faae9bf73679033b35bf00463c36a30b74a0fe3d4b58889528e2cf092336073e6981e6f03bb02275b9cbd927293b782cb4da8cc2e592fd7ad205e590af190cbcf47af51f7da7cde7b51de7a000000";
+        let written: Vec<u8> = (0..written.len()).step_by(2).map(|index| u8::from_str_radix(&written[index..index + 2], 16).unwrap()).collect();
+        let expected = br#"{"orderId": 12345, "status": "paid", "customer": {"id": "c-1", "name": "Ana"}, "items": [{"sku": "a-1"}, {"sku": "b-2"}]}"#;
+        assert_eq!(decompress(&written, 1024).unwrap(), [&expected[..], b"\n"].concat());
+        let mut stored = vec![0x1f, 0x8b, 0x08, FLAG_NAME, 0, 0, 0, 0, 0, 0xff, b'a', 0, 1, 2, 0, 0xfd, 0xff, b'h', b'i'];
+        stored.extend_from_slice(&crc32(b"hi").to_le_bytes());
+        stored.extend_from_slice(&2u32.to_le_bytes());
+        let members = [stored.clone(), compress(b"!")].concat();
+        assert_eq!(decompress(&members, 1024).unwrap(), b"hi!");
+
+        let mut corrupted = compressed.clone();
+        let last = corrupted.len() - 5;
+        corrupted[last] ^= 1;
+        assert_eq!(decompress(&corrupted, payload.len()), Err(GzipError::ChecksumMismatch));
+        assert_eq!(decompress(&compressed[..compressed.len() - 4], payload.len()), Err(GzipError::Truncated));
+        assert_eq!(decompress(b"{\"not\": \"gzip\"}", 1024), Err(GzipError::InvalidHeader));
+    }
+
+    #[test]
+    fn test_if_zlib_and_raw_deflate_streams_are_decompressed() {
+        assert_eq!(adler32(b"Wikipedia"), 0x11e6_0398);
+        // written by Python's `zlib.compress`, and the same stream without its zlib header and trailer
+        let written = "789cab56ca2f4a492df24c51b2523034323631d551502a2e492c292d060a28152466a628d50200c80f0acf";
+        let written: Vec<u8> = (0..written.len()).step_by(2).map(|index| u8::from_str_radix(&written[index..index + 2], 16).unwrap()).collect();
+        let expected = br#"{"orderId": 12345, "status": "paid"}"#;
+        assert_eq!(decompress_deflate(&written, 1024).unwrap(), expected);
+        assert_eq!(decompress_deflate(&written[2..written.len() - 4], 1024).unwrap(), expected);
+        assert_eq!(decompress_deflate(&written, 10), Err(GzipError::TooLarge(10)));
+        let mut corrupted = written.clone();
+        let last = corrupted.len() - 1;
+        corrupted[last] ^= 1;
+        assert_eq!(decompress_deflate(&corrupted, 1024), Err(GzipError::ChecksumMismatch));
+    }
+}
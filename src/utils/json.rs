@@ -0,0 +1,444 @@
+use std::{collections::BTreeMap, fmt::Display};
+
+use thiserror::Error;
+
+#[derive(Debug, Error, PartialEq)]
+pub enum JsonError {
+    #[error("Unexpected end of JSON input")]
+    UnexpectedEnd,
+    #[error("Unexpected character '{0}' at position {1}")]
+    UnexpectedCharacter(char, usize),
+    #[error("Invalid number at position {0}")]
+    InvalidNumber(usize),
+    #[error("Invalid escape sequence at position {0}")]
+    InvalidEscape(usize),
+    #[error("JSON nesting is deeper than the allowed depth")]
+    TooDeep,
+}
+
+/// The maximum depth of arrays and objects accepted by the parser
+const MAX_DEPTH: usize = 128;
+
+/// A JSON value. Objects keep their keys sorted so the serialization is deterministic
+#[derive(Debug, Clone, PartialEq)]
+pub enum JsonValue {
+    Null,
+    Bool(bool),
+    Number(f64),
+    String(String),
+    Array(Vec<JsonValue>),
+    Object(BTreeMap<String, JsonValue>),
+}
+
+impl JsonValue {
+    /// Parse a JSON document
+    pub fn parse(input: &str) -> Result<JsonValue, JsonError> {
+        let mut parser = JsonParser { input: input.as_bytes(), position: 0, depth: 0 };
+        let value = parser.parse_value()?;
+        parser.skip_whitespace();
+        match parser.peek() {
+            Some(c) => Err(JsonError::UnexpectedCharacter(c as char, parser.position)),
+            None => Ok(value),
+        }
+    }
+
+    /// Parse a JSON document from bytes, failing if they are not valid UTF-8
+    pub fn parse_bytes(input: &[u8]) -> Result<JsonValue, JsonError> {
+        let input = std::str::from_utf8(input).map_err(|err| JsonError::UnexpectedCharacter('\u{FFFD}', err.valid_up_to()))?;
+        JsonValue::parse(input)
+    }
+
+    /// Create an empty object
+    pub fn object() -> JsonValue {
+        JsonValue::Object(BTreeMap::new())
+    }
+
+    /// Set the key of this value if it is an object. Return self so calls can be chained
+    pub fn with(mut self, key: &str, value: impl Into<JsonValue>) -> JsonValue {
+        if let JsonValue::Object(map) = &mut self {
+            map.insert(key.to_string(), value.into());
+        }
+        self
+    }
+
+    /// Return the value of the key if this value is an object
+    pub fn get(&self, key: &str) -> Option<&JsonValue> {
+        match self {
+            JsonValue::Object(map) => map.get(key),
+            _ => None,
+        }
+    }
+
+    /// Follow a path of object keys separated by '.' like `message.headers`
+    pub fn pointer(&self, path: &str) -> Option<&JsonValue> {
+        path.split('.').try_fold(self, |value, key| value.get(key))
+    }
+
+    pub fn as_str(&self) -> Option<&str> {
+        match self {
+            JsonValue::String(s) => Some(s),
+            _ => None,
+        }
+    }
+
+    pub fn as_f64(&self) -> Option<f64> {
+        match self {
+            JsonValue::Number(n) => Some(*n),
+            _ => None,
+        }
+    }
+
+    /// Return the number if it is a non-negative integer
+    pub fn as_u64(&self) -> Option<u64> {
+        match self {
+            JsonValue::Number(n) if *n >= 0.0 && n.fract() == 0.0 && *n <= u64::MAX as f64 => Some(*n as u64),
+            _ => None,
+        }
+    }
+
+    pub fn as_bool(&self) -> Option<bool> {
+        match self {
+            JsonValue::Bool(b) => Some(*b),
+            _ => None,
+        }
+    }
+
+    pub fn as_array(&self) -> Option<&Vec<JsonValue>> {
+        match self {
+            JsonValue::Array(a) => Some(a),
+            _ => None,
+        }
+    }
+
+    pub fn as_object(&self) -> Option<&BTreeMap<String, JsonValue>> {
+        match self {
+            JsonValue::Object(o) => Some(o),
+            _ => None,
+        }
+    }
+
+    pub fn is_null(&self) -> bool {
+        matches!(self, JsonValue::Null)
+    }
+}
+
+impl From<bool> for JsonValue {
+    fn from(value: bool) -> Self {
+        JsonValue::Bool(value)
+    }
+}
+
+impl From<&str> for JsonValue {
+    fn from(value: &str) -> Self {
+        JsonValue::String(value.to_string())
+    }
+}
+
+impl From<String> for JsonValue {
+    fn from(value: String) -> Self {
+        JsonValue::String(value)
+    }
+}
+
+macro_rules! json_number_from {
+    ($($t:ty),*) => {
+        $(impl From<$t> for JsonValue {
+            fn from(value: $t) -> Self {
+                JsonValue::Number(value as f64)
+            }
+        })*
+    };
+}
+
+json_number_from!(u8, u16, u32, u64, usize, i32, i64, f64);
+
+impl<T: Into<JsonValue>> From<Option<T>> for JsonValue {
+    fn from(value: Option<T>) -> Self {
+        value.map_or(JsonValue::Null, Into::into)
+    }
+}
+
+impl<T: Into<JsonValue>> From<Vec<T>> for JsonValue {
+    fn from(value: Vec<T>) -> Self {
+        JsonValue::Array(value.into_iter().map(Into::into).collect())
+    }
+}
+
+/// Serialize the value as compact JSON
+impl Display for JsonValue {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            JsonValue::Null => write!(f, "null"),
+            JsonValue::Bool(b) => write!(f, "{}", b),
+            JsonValue::Number(n) if !n.is_finite() => write!(f, "null"),
+            JsonValue::Number(n) if n.fract() == 0.0 && n.abs() < 1e15 => write!(f, "{}", *n as i64),
+            JsonValue::Number(n) => write!(f, "{}", n),
+            JsonValue::String(s) => write_json_string(f, s),
+            JsonValue::Array(values) => {
+                write!(f, "[")?;
+                for (index, value) in values.iter().enumerate() {
+                    if index > 0 {
+                        write!(f, ",")?;
+                    }
+                    write!(f, "{}", value)?;
+                }
+                write!(f, "]")
+            }
+            JsonValue::Object(map) => {
+                write!(f, "{{")?;
+                for (index, (key, value)) in map.iter().enumerate() {
+                    if index > 0 {
+                        write!(f, ",")?;
+                    }
+                    write_json_string(f, key)?;
+                    write!(f, ":{}", value)?;
+                }
+                write!(f, "}}")
+            }
+        }
+    }
+}
+
+fn write_json_string(f: &mut std::fmt::Formatter<'_>, s: &str) -> std::fmt::Result {
+    write!(f, "\"")?;
+    for c in s.chars() {
+        match c {
+            '"' => write!(f, "\\\"")?,
+            '\\' => write!(f, "\\\\")?,
+            '\n' => write!(f, "\\n")?,
+            '\r' => write!(f, "\\r")?,
+            '\t' => write!(f, "\\t")?,
+            c if (c as u32) < 0x20 => write!(f, "\\u{:04x}", c as u32)?,
+            c => write!(f, "{}", c)?,
+        }
+    }
+    write!(f, "\"")
+}
+
+struct JsonParser<'a> {
+    input: &'a [u8],
+    position: usize,
+    depth: usize,
+}
+
+impl JsonParser<'_> {
+    fn peek(&self) -> Option<u8> {
+        self.input.get(self.position).copied()
+    }
+
+    fn skip_whitespace(&mut self) {
+        while matches!(self.peek(), Some(b' ' | b'\t' | b'\n' | b'\r')) {
+            self.position += 1;
+        }
+    }
+
+    fn expect(&mut self, expected: u8) -> Result<(), JsonError> {
+        match self.peek() {
+            Some(c) if c == expected => {
+                self.position += 1;
+                Ok(())
+            }
+            Some(c) => Err(JsonError::UnexpectedCharacter(c as char, self.position)),
+            None => Err(JsonError::UnexpectedEnd),
+        }
+    }
+
+    fn expect_literal(&mut self, literal: &str, value: JsonValue) -> Result<JsonValue, JsonError> {
+        for expected in literal.bytes() {
+            self.expect(expected)?;
+        }
+        Ok(value)
+    }
+
+    fn parse_value(&mut self) -> Result<JsonValue, JsonError> {
+        self.skip_whitespace();
+        match self.peek() {
+            None => Err(JsonError::UnexpectedEnd),
+            Some(b'n') => self.expect_literal("null", JsonValue::Null),
+            Some(b't') => self.expect_literal("true", JsonValue::Bool(true)),
+            Some(b'f') => self.expect_literal("false", JsonValue::Bool(false)),
+            Some(b'"') => Ok(JsonValue::String(self.parse_string()?)),
+            Some(b'[') => self.parse_array(),
+            Some(b'{') => self.parse_object(),
+            Some(b'-' | b'0'..=b'9') => self.parse_number(),
+            Some(c) => Err(JsonError::UnexpectedCharacter(c as char, self.position)),
+        }
+    }
+
+    fn enter(&mut self) -> Result<(), JsonError> {
+        self.depth += 1;
+        if self.depth > MAX_DEPTH {
+            return Err(JsonError::TooDeep);
+        }
+        Ok(())
+    }
+
+    fn parse_array(&mut self) -> Result<JsonValue, JsonError> {
+        self.enter()?;
+        self.expect(b'[')?;
+        let mut values = Vec::new();
+        self.skip_whitespace();
+        if self.peek() == Some(b']') {
+            self.position += 1;
+            self.depth -= 1;
+            return Ok(JsonValue::Array(values));
+        }
+        loop {
+            values.push(self.parse_value()?);
+            self.skip_whitespace();
+            match self.peek() {
+                Some(b',') => self.position += 1,
+                Some(b']') => {
+                    self.position += 1;
+                    self.depth -= 1;
+                    return Ok(JsonValue::Array(values));
+                }
+                Some(c) => return Err(JsonError::UnexpectedCharacter(c as char, self.position)),
+                None => return Err(JsonError::UnexpectedEnd),
+            }
+        }
+    }
+
+    fn parse_object(&mut self) -> Result<JsonValue, JsonError> {
+        self.enter()?;
+        self.expect(b'{')?;
+        let mut map = BTreeMap::new();
+        self.skip_whitespace();
+        if self.peek() == Some(b'}') {
+            self.position += 1;
+            self.depth -= 1;
+            return Ok(JsonValue::Object(map));
+        }
+        loop {
+            self.skip_whitespace();
+            let key = self.parse_string()?;
+            self.skip_whitespace();
+            self.expect(b':')?;
+            let value = self.parse_value()?;
+            map.insert(key, value);
+            self.skip_whitespace();
+            match self.peek() {
+                Some(b',') => self.position += 1,
+                Some(b'}') => {
+                    self.position += 1;
+                    self.depth -= 1;
+                    return Ok(JsonValue::Object(map));
+                }
+                Some(c) => return Err(JsonError::UnexpectedCharacter(c as char, self.position)),
+                None => return Err(JsonError::UnexpectedEnd),
+            }
+        }
+    }
+
+    fn parse_hex4(&mut self) -> Result<u32, JsonError> {
+        let start = self.position;
+        let hex = self.input.get(start..start + 4).ok_or(JsonError::UnexpectedEnd)?;
+        let hex = std::str::from_utf8(hex).map_err(|_| JsonError::InvalidEscape(start))?;
+        let code = u32::from_str_radix(hex, 16).map_err(|_| JsonError::InvalidEscape(start))?;
+        self.position += 4;
+        Ok(code)
+    }
+
+    fn parse_string(&mut self) -> Result<String, JsonError> {
+        self.expect(b'"')?;
+        let mut bytes = Vec::new();
+        loop {
+            let c = self.peek().ok_or(JsonError::UnexpectedEnd)?;
+            self.position += 1;
+            match c {
+                b'"' => break,
+                b'\\' => {
+                    let escape = self.peek().ok_or(JsonError::UnexpectedEnd)?;
+                    self.position += 1;
+                    let decoded = match escape {
+                        b'"' => '"',
+                        b'\\' => '\\',
+                        b'/' => '/',
+                        b'b' => '\u{0008}',
+                        b'f' => '\u{000C}',
+                        b'n' => '\n',
+                        b'r' => '\r',
+                        b't' => '\t',
+                        b'u' => {
+                            let escape_position = self.position;
+                            let mut code = self.parse_hex4()?;
+                            // surrogate pairs encode characters outside the BMP
+                            if (0xD800..0xDC00).contains(&code) {
+                                if self.input.get(self.position..self.position + 2) != Some(b"\\u") {
+                                    return Err(JsonError::InvalidEscape(escape_position));
+                                }
+                                self.position += 2;
+                                let low = self.parse_hex4()?;
+                                if !(0xDC00..0xE000).contains(&low) {
+                                    return Err(JsonError::InvalidEscape(escape_position));
+                                }
+                                code = 0x10000 + ((code - 0xD800) << 10) + (low - 0xDC00);
+                            }
+                            char::from_u32(code).ok_or(JsonError::InvalidEscape(escape_position))?
+                        }
+                        _ => return Err(JsonError::InvalidEscape(self.position - 1)),
+                    };
+                    let mut buffer = [0u8; 4];
+                    bytes.extend_from_slice(decoded.encode_utf8(&mut buffer).as_bytes());
+                }
+                c if c < 0x20 => return Err(JsonError::UnexpectedCharacter(c as char, self.position - 1)),
+                c => bytes.push(c),
+            }
+        }
+        // the input is a &str and escapes are encoded as UTF-8, so the bytes are always valid UTF-8
+        Ok(String::from_utf8(bytes).unwrap_or_default())
+    }
+
+    fn parse_number(&mut self) -> Result<JsonValue, JsonError> {
+        let start = self.position;
+        if self.peek() == Some(b'-') {
+            self.position += 1;
+        }
+        while let Some(b'0'..=b'9' | b'.' | b'e' | b'E' | b'+' | b'-') = self.peek() {
+            self.position += 1;
+        }
+        let literal = std::str::from_utf8(&self.input[start..self.position]).map_err(|_| JsonError::InvalidNumber(start))?;
+        literal.parse::<f64>()
+            .ok()
+            .filter(|n| n.is_finite())
+            .map(JsonValue::Number)
+            .ok_or(JsonError::InvalidNumber(start))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_if_json_document_is_parsed() {
+        let value = JsonValue::parse(r#"{"a": [1, 2.5, -3e2], "b": {"c": "d\n\u00e9\ud83d\ude00"}, "e": null, "f": true}"#).unwrap();
+        assert_eq!(value.get("a").unwrap().as_array().unwrap().len(), 3);
+        assert_eq!(value.get("a").unwrap().as_array().unwrap()[2].as_f64().unwrap(), -300.0);
+        assert_eq!(value.pointer("b.c").unwrap().as_str().unwrap(), "d\né😀");
+        assert!(value.get("e").unwrap().is_null());
+        assert_eq!(value.get("f").unwrap().as_bool(), Some(true));
+    }
+
+    #[test]
+    fn test_if_serialization_round_trips() {
+        let value = JsonValue::object()
+            .with("id", "a\"b")
+            .with("count", 3u16)
+            .with("ratio", 0.5)
+            .with("tags", vec!["x", "y"])
+            .with("next", Option::<String>::None);
+        let serialized = value.to_string();
+        assert_eq!(serialized, r#"{"count":3,"id":"a\"b","next":null,"ratio":0.5,"tags":["x","y"]}"#);
+        assert_eq!(JsonValue::parse(&serialized).unwrap(), value);
+    }
+
+    #[test]
+    fn test_if_invalid_json_is_rejected() {
+        assert!(JsonValue::parse("{\"a\": }").is_err());
+        assert!(JsonValue::parse("[1, 2").is_err());
+        assert!(JsonValue::parse("{} extra").is_err());
+        assert!(JsonValue::parse("\"\\x\"").is_err());
+        assert_eq!(JsonValue::parse(&"[".repeat(1000)), Err(JsonError::TooDeep));
+    }
+}
@@ -0,0 +1,297 @@
+use std::{collections::HashMap, fmt::Display};
+
+use regex::Regex;
+use thiserror::Error;
+
+use super::json::JsonValue;
+
+#[derive(Debug, Error, PartialEq)]
+pub enum JsonSchemaError {
+    #[error("The schema at {0} should be an object or a boolean")]
+    InvalidSchema(String),
+    #[error("The keyword {keyword} at {path} has an invalid value")]
+    InvalidKeyword { path: String, keyword: String },
+    #[error("The pattern {0} is not a valid regular expression")]
+    InvalidPattern(String),
+}
+
+/// A place where a JSON document does not conform to a schema
+#[derive(Debug, Clone, PartialEq)]
+pub struct SchemaViolation {
+    /// The JSON pointer of the value that does not conform, like `/order/id`. The document root is `/`
+    pub path: String,
+    pub message: String,
+}
+
+impl Display for SchemaViolation {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}: {}", self.path, self.message)
+    }
+}
+
+/// A JSON Schema used to validate JSON documents. It supports the keywords `type`, `enum`,
+/// `const`, `required`, `properties`, `additionalProperties`, `items`, `minItems`, `maxItems`,
+/// `minLength`, `maxLength`, `pattern`, `minimum`, `maximum`, `exclusiveMinimum` and
+/// `exclusiveMaximum`. Other keywords are ignored
+#[derive(Debug, Clone)]
+pub struct JsonSchema {
+    schema: JsonValue,
+    patterns: HashMap<String, Regex>,
+}
+
+const TYPES: [&str; 7] = ["null", "boolean", "object", "array", "number", "integer", "string"];
+
+impl JsonSchema {
+    /// Check that the schema uses the supported keywords correctly and compile its patterns
+    pub fn new(schema: JsonValue) -> Result<JsonSchema, JsonSchemaError> {
+        let mut patterns = HashMap::new();
+        check_schema(&schema, "", &mut patterns)?;
+        Ok(JsonSchema { schema, patterns })
+    }
+
+    /// Parse and check a schema
+    pub fn parse(schema: &str) -> Result<JsonSchema, JsonSchemaError> {
+        let schema = JsonValue::parse(schema).map_err(|_| JsonSchemaError::InvalidSchema(String::from("/")))?;
+        JsonSchema::new(schema)
+    }
+
+    /// Return all the places where the document does not conform to the schema. An empty list
+    /// means the document is valid
+    pub fn validate(&self, document: &JsonValue) -> Vec<SchemaViolation> {
+        let mut violations = Vec::new();
+        self.validate_value(&self.schema, document, "", &mut violations);
+        violations
+    }
+
+    fn validate_value(&self, schema: &JsonValue, value: &JsonValue, path: &str, violations: &mut Vec<SchemaViolation>) {
+        let mut violation = |message: String| violations.push(SchemaViolation { path: display_path(path), message });
+        let schema = match schema {
+            JsonValue::Bool(true) => return,
+            JsonValue::Bool(false) => return violation(String::from("no value is allowed")),
+            JsonValue::Object(schema) => schema,
+            _ => return,
+        };
+
+        if let Some(expected) = schema.get("type") {
+            let expected: Vec<&str> = match expected {
+                JsonValue::String(expected) => vec![expected.as_str()],
+                expected => expected.as_array().into_iter().flatten().filter_map(JsonValue::as_str).collect(),
+            };
+            if !expected.iter().any(|expected| has_type(value, expected)) {
+                return violation(format!("expected {} but found {}", expected.join(" or "), type_name(value)));
+            }
+        }
+        if let Some(allowed) = schema.get("enum").and_then(JsonValue::as_array) {
+            if !allowed.contains(value) {
+                violation(format!("{} is not one of {}", value, JsonValue::Array(allowed.clone())));
+            }
+        }
+        if let Some(constant) = schema.get("const") {
+            if constant != value {
+                violation(format!("expected {}", constant));
+            }
+        }
+
+        match value {
+            JsonValue::String(text) => {
+                let length = text.chars().count() as f64;
+                if let Some(min) = number(schema.get("minLength")).filter(|min| length < *min) {
+                    violation(format!("should have at least {} characters", min));
+                }
+                if let Some(max) = number(schema.get("maxLength")).filter(|max| length > *max) {
+                    violation(format!("should have at most {} characters", max));
+                }
+                if let Some(pattern) = schema.get("pattern").and_then(JsonValue::as_str) {
+                    if !self.patterns[pattern].is_match(text) {
+                        violation(format!("should match the pattern {}", pattern));
+                    }
+                }
+            }
+            JsonValue::Number(n) => {
+                if let Some(min) = number(schema.get("minimum")).filter(|min| n < min) {
+                    violation(format!("should be >= {}", min));
+                }
+                if let Some(max) = number(schema.get("maximum")).filter(|max| n > max) {
+                    violation(format!("should be <= {}", max));
+                }
+                if let Some(min) = number(schema.get("exclusiveMinimum")).filter(|min| n <= min) {
+                    violation(format!("should be > {}", min));
+                }
+                if let Some(max) = number(schema.get("exclusiveMaximum")).filter(|max| n >= max) {
+                    violation(format!("should be < {}", max));
+                }
+            }
+            JsonValue::Array(items) => {
+                let length = items.len() as f64;
+                if let Some(min) = number(schema.get("minItems")).filter(|min| length < *min) {
+                    violation(format!("should have at least {} items", min));
+                }
+                if let Some(max) = number(schema.get("maxItems")).filter(|max| length > *max) {
+                    violation(format!("should have at most {} items", max));
+                }
+                if let Some(item_schema) = schema.get("items") {
+                    for (index, item) in items.iter().enumerate() {
+                        self.validate_value(item_schema, item, &format!("{}/{}", path, index), violations);
+                    }
+                }
+            }
+            JsonValue::Object(object) => {
+                for required in schema.get("required").and_then(JsonValue::as_array).into_iter().flatten().filter_map(JsonValue::as_str) {
+                    if !object.contains_key(required) {
+                        violation(format!("the property {} is required", required));
+                    }
+                }
+                let properties = schema.get("properties").and_then(JsonValue::as_object);
+                for (key, property) in object {
+                    let property_path = format!("{}/{}", path, escape_pointer(key));
+                    match (properties.and_then(|properties| properties.get(key)), schema.get("additionalProperties")) {
+                        (Some(property_schema), _) | (None, Some(property_schema)) => {
+                            self.validate_value(property_schema, property, &property_path, violations)
+                        }
+                        (None, None) => {}
+                    }
+                }
+            }
+            JsonValue::Null | JsonValue::Bool(_) => {}
+        }
+    }
+}
+
+/// Check the keywords of the schema and its subschemas, compiling the patterns
+fn check_schema(schema: &JsonValue, path: &str, patterns: &mut HashMap<String, Regex>) -> Result<(), JsonSchemaError> {
+    let object = match schema {
+        JsonValue::Bool(_) => return Ok(()),
+        JsonValue::Object(object) => object,
+        _ => return Err(JsonSchemaError::InvalidSchema(display_path(path))),
+    };
+    let invalid = |keyword: &str| JsonSchemaError::InvalidKeyword { path: display_path(path), keyword: keyword.to_string() };
+
+    if let Some(types) = object.get("type") {
+        let valid = match types {
+            JsonValue::String(name) => TYPES.contains(&name.as_str()),
+            JsonValue::Array(names) => !names.is_empty() && names.iter().all(|name| name.as_str().is_some_and(|name| TYPES.contains(&name))),
+            _ => false,
+        };
+        if !valid {
+            return Err(invalid("type"));
+        }
+    }
+    if object.get("enum").is_some_and(|allowed| allowed.as_array().is_none()) {
+        return Err(invalid("enum"));
+    }
+    if object.get("required").is_some_and(|required| !required.as_array().is_some_and(|names| names.iter().all(|name| name.as_str().is_some()))) {
+        return Err(invalid("required"));
+    }
+    for keyword in ["minLength", "maxLength", "minItems", "maxItems"] {
+        if object.get(keyword).is_some_and(|limit| limit.as_u64().is_none()) {
+            return Err(invalid(keyword));
+        }
+    }
+    for keyword in ["minimum", "maximum", "exclusiveMinimum", "exclusiveMaximum"] {
+        if object.get(keyword).is_some_and(|limit| limit.as_f64().is_none()) {
+            return Err(invalid(keyword));
+        }
+    }
+    if let Some(pattern) = object.get("pattern") {
+        let pattern = pattern.as_str().ok_or_else(|| invalid("pattern"))?;
+        let regex = Regex::new(pattern).map_err(|_| JsonSchemaError::InvalidPattern(pattern.to_string()))?;
+        patterns.insert(pattern.to_string(), regex);
+    }
+
+    if let Some(properties) = object.get("properties") {
+        let properties = properties.as_object().ok_or_else(|| invalid("properties"))?;
+        for (key, property) in properties {
+            check_schema(property, &format!("{}/properties/{}", path, escape_pointer(key)), patterns)?;
+        }
+    }
+    if let Some(additional) = object.get("additionalProperties") {
+        check_schema(additional, &format!("{}/additionalProperties", path), patterns)?;
+    }
+    if let Some(items) = object.get("items") {
+        check_schema(items, &format!("{}/items", path), patterns)?;
+    }
+    Ok(())
+}
+
+fn has_type(value: &JsonValue, expected: &str) -> bool {
+    match (expected, value) {
+        ("integer", JsonValue::Number(n)) => n.fract() == 0.0,
+        (expected, value) => type_name(value) == expected || (expected == "number" && matches!(value, JsonValue::Number(_))),
+    }
+}
+
+fn type_name(value: &JsonValue) -> &'static str {
+    match value {
+        JsonValue::Null => "null",
+        JsonValue::Bool(_) => "boolean",
+        JsonValue::Number(n) if n.fract() == 0.0 => "integer",
+        JsonValue::Number(_) => "number",
+        JsonValue::String(_) => "string",
+        JsonValue::Array(_) => "array",
+        JsonValue::Object(_) => "object",
+    }
+}
+
+fn number(value: Option<&JsonValue>) -> Option<f64> {
+    value.and_then(JsonValue::as_f64)
+}
+
+fn escape_pointer(key: &str) -> String {
+    key.replace('~', "~0").replace('/', "~1")
+}
+
+fn display_path(path: &str) -> String {
+    if path.is_empty() { String::from("/") } else { path.to_string() }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn order_schema() -> JsonSchema {
+        JsonSchema::parse(r#"{
+            "type": "object",
+            "required": ["id", "status"],
+            "properties": {
+                "id": {"type": "integer", "minimum": 1},
+                "status": {"enum": ["created", "paid"]},
+                "email": {"type": "string", "pattern": "^[^@]+@[^@]+$"},
+                "items": {"type": "array", "minItems": 1, "items": {"type": "object", "required": ["sku"]}}
+            },
+            "additionalProperties": false
+        }"#).unwrap()
+    }
+
+    fn violations(schema: &JsonSchema, document: &str) -> Vec<String> {
+        schema.validate(&JsonValue::parse(document).unwrap()).iter().map(ToString::to_string).collect()
+    }
+
+    #[test]
+    fn test_if_conforming_documents_have_no_violations() {
+        let schema = order_schema();
+        assert!(violations(&schema, r#"{"id": 1, "status": "paid", "email": "a@b.c", "items": [{"sku": "x"}]}"#).is_empty());
+    }
+
+    #[test]
+    fn test_if_every_violation_is_reported_with_its_path() {
+        let schema = order_schema();
+        assert_eq!(
+            violations(&schema, r#"{"id": 0.5, "email": "nope", "items": [{}], "extra": 1}"#),
+            vec![
+                "/: the property status is required",
+                "/email: should match the pattern ^[^@]+@[^@]+$",
+                "/extra: no value is allowed",
+                "/id: expected integer but found number",
+                "/items/0: the property sku is required",
+            ]
+        );
+        assert_eq!(violations(&schema, "[]"), vec!["/: expected object but found array"]);
+    }
+
+    #[test]
+    fn test_if_invalid_schemas_are_rejected() {
+        assert!(matches!(JsonSchema::parse(r#"{"type": "text"}"#), Err(JsonSchemaError::InvalidKeyword { .. })));
+        assert!(matches!(JsonSchema::parse(r#"{"properties": {"a": 1}}"#), Err(JsonSchemaError::InvalidSchema(path)) if path == "/properties/a"));
+        assert!(matches!(JsonSchema::parse(r#"{"pattern": "("}"#), Err(JsonSchemaError::InvalidPattern(_))));
+    }
+}
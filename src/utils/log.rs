@@ -0,0 +1,218 @@
+use std::{cell::RefCell, fmt, sync::RwLock};
+
+use thiserror::Error;
+
+use super::time::format_rfc3339;
+
+/// The severity of a log record. Filters allow the records at or above a level
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Level {
+    Error,
+    Warn,
+    Info,
+    Debug,
+    Trace,
+}
+
+impl Level {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Level::Error => "error",
+            Level::Warn => "warn",
+            Level::Info => "info",
+            Level::Debug => "debug",
+            Level::Trace => "trace",
+        }
+    }
+
+    /// Parse the name of the level used in the filters
+    pub fn from_name(name: &str) -> Option<Level> {
+        match name {
+            "error" => Some(Level::Error),
+            "warn" => Some(Level::Warn),
+            "info" => Some(Level::Info),
+            "debug" => Some(Level::Debug),
+            "trace" => Some(Level::Trace),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Error, PartialEq)]
+pub enum LogFilterError {
+    #[error("{0} is not a valid log level. Use off, error, warn, info, debug or trace")]
+    InvalidLevel(String),
+    #[error("{0} is not a valid module path")]
+    InvalidModule(String),
+}
+
+/// Which log records are written, like `info,angler::msgproc=debug`. Each directive sets the level
+/// of a module and its submodules, and the directive without module sets the level of the others.
+/// The most specific module wins. `off` disables the records of the module
+#[derive(Debug, Clone, PartialEq)]
+pub struct LogFilter {
+    default: Option<Level>,
+    /// The modules and their levels, None when they are off
+    modules: Vec<(String, Option<Level>)>,
+}
+
+impl Default for LogFilter {
+    /// Write the records at or above `info`
+    fn default() -> Self {
+        LogFilter { default: Some(Level::Info), modules: Vec::new() }
+    }
+}
+
+impl LogFilter {
+    /// Parse a comma separated list of directives. A later directive of the same module replaces
+    /// the former one
+    pub fn parse(directives: &str) -> Result<LogFilter, LogFilterError> {
+        let parse_level = |level: &str| match level {
+            "off" => Ok(None),
+            _ => Level::from_name(level).map(Some).ok_or_else(|| LogFilterError::InvalidLevel(level.to_string())),
+        };
+
+        let mut filter = LogFilter::default();
+        for directive in directives.split(',').map(str::trim).filter(|directive| !directive.is_empty()) {
+            match directive.split_once('=') {
+                Some((module, level)) => {
+                    let module = module.trim();
+                    let valid = !module.is_empty() && module.split("::").all(|part| !part.is_empty() && part.chars().all(|c| c.is_ascii_alphanumeric() || c == '_'));
+                    if !valid {
+                        return Err(LogFilterError::InvalidModule(module.to_string()));
+                    }
+                    let level = parse_level(level.trim())?;
+                    filter.modules.retain(|(other, _)| other != module);
+                    filter.modules.push((module.to_string(), level));
+                }
+                None => filter.default = parse_level(directive)?,
+            }
+        }
+        Ok(filter)
+    }
+
+    /// Return if the records of the module at the level are written
+    pub fn enabled(&self, module: &str, level: Level) -> bool {
+        let matched = self.modules.iter()
+            .filter(|(prefix, _)| module == prefix || module.strip_prefix(prefix.as_str()).is_some_and(|rest| rest.starts_with("::")))
+            .max_by_key(|(prefix, _)| prefix.len());
+        let allowed = match matched {
+            Some((_, allowed)) => *allowed,
+            None => self.default,
+        };
+        allowed.is_some_and(|allowed| level <= allowed)
+    }
+}
+
+impl fmt::Display for LogFilter {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let level_name = |level: &Option<Level>| level.map_or("off", |level| level.as_str());
+        let directives: Vec<String> = std::iter::once(level_name(&self.default).to_string())
+            .chain(self.modules.iter().map(|(module, level)| format!("{}={}", module, level_name(level))))
+            .collect();
+        f.write_str(&directives.join(","))
+    }
+}
+
+static FILTER: RwLock<Option<LogFilter>> = RwLock::new(None);
+
+/// Return the filter applied to the records of the process
+pub fn filter() -> LogFilter {
+    FILTER.read().unwrap().clone().unwrap_or_default()
+}
+
+/// Replace the filter applied to the records of the process. It takes effect immediately
+pub fn set_filter(filter: LogFilter) {
+    *FILTER.write().unwrap() = Some(filter);
+}
+
+/// Return if the records of the module at the level are written by the current filter
+pub fn enabled(module: &str, level: Level) -> bool {
+    match &*FILTER.read().unwrap() {
+        Some(filter) => filter.enabled(module, level),
+        None => LogFilter::default().enabled(module, level),
+    }
+}
+
+thread_local! {
+    /// The ID of the request handled by the thread, see `scope_request_id`
+    static REQUEST_ID: RefCell<Option<String>> = const { RefCell::new(None) };
+}
+
+/// Attach the ID of a request to the records written by the thread until the scope is dropped
+pub fn scope_request_id(request_id: &str) -> RequestIdScope {
+    let previous = REQUEST_ID.with(|current| current.replace(Some(request_id.to_string())));
+    RequestIdScope { previous }
+}
+
+/// Return the ID of the request handled by the thread
+pub fn request_id() -> Option<String> {
+    REQUEST_ID.with(|current| current.borrow().clone())
+}
+
+/// Restore the request ID the thread had before `scope_request_id` when dropped
+pub struct RequestIdScope {
+    previous: Option<String>,
+}
+
+impl Drop for RequestIdScope {
+    fn drop(&mut self) {
+        REQUEST_ID.with(|current| *current.borrow_mut() = self.previous.take());
+    }
+}
+
+/// Write the record into the standard error, with the ID of the request handled by the thread.
+/// Use the `log!` macro, that checks the filter first
+pub fn write(module: &str, level: Level, args: fmt::Arguments<'_>) {
+    let now = format_rfc3339(time::OffsetDateTime::now_utc());
+    match request_id() {
+        Some(request_id) => eprintln!("{} {:<5} {} [{}]: {}", now, level.as_str().to_uppercase(), module, request_id, args),
+        None => eprintln!("{} {:<5} {}: {}", now, level.as_str().to_uppercase(), module, args),
+    }
+}
+
+/// Write a log record of the calling module when the filter allows its level.
+/// Example: `log!(Level::Debug, "sending message {}", message.id)`
+#[macro_export]
+macro_rules! log {
+    ($level:expr, $($arg:tt)+) => {{
+        let level = $level;
+        if $crate::utils::log::enabled(module_path!(), level) {
+            $crate::utils::log::write(module_path!(), level, format_args!($($arg)+));
+        }
+    }};
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_if_the_most_specific_directive_sets_the_level_of_a_module() {
+        let filter = LogFilter::parse("warn, angler::msgproc=debug, angler::msgproc::delivery=trace, angler::db=off").unwrap();
+        assert!(filter.enabled("angler::msgproc::processor", Level::Debug));
+        assert!(!filter.enabled("angler::msgproc::processor", Level::Trace));
+        assert!(filter.enabled("angler::msgproc::delivery", Level::Trace));
+        assert!(!filter.enabled("angler::msgprocessor", Level::Info));
+        assert!(filter.enabled("angler::net", Level::Warn));
+        assert!(!filter.enabled("angler::db::batch", Level::Error));
+        assert_eq!(filter.to_string(), "warn,angler::msgproc=debug,angler::msgproc::delivery=trace,angler::db=off");
+
+        assert_eq!(LogFilter::parse("").unwrap(), LogFilter::default());
+        assert_eq!(LogFilter::parse("angler::msgproc=loud"), Err(LogFilterError::InvalidLevel(String::from("loud"))));
+        assert_eq!(LogFilter::parse("angler::=debug"), Err(LogFilterError::InvalidModule(String::from("angler::"))));
+    }
+
+    #[test]
+    fn test_if_request_ids_are_scoped_to_the_thread() {
+        let outer = scope_request_id("outer");
+        {
+            let _inner = scope_request_id("inner");
+            assert_eq!(request_id().as_deref(), Some("inner"));
+            assert_eq!(std::thread::spawn(request_id).join().unwrap(), None);
+        }
+        assert_eq!(request_id().as_deref(), Some("outer"));
+        drop(outer);
+        assert_eq!(request_id(), None);
+    }
+}
@@ -0,0 +1,195 @@
+use thiserror::Error;
+
+/// The shortest match of the LZ4 block format
+const MIN_MATCH: usize = 4;
+/// The last bytes of a block are always literals
+const LAST_LITERALS: usize = 5;
+/// No match starts in the last bytes of a block
+const MATCH_LIMIT: usize = 12;
+const MAX_OFFSET: usize = u16::MAX as usize;
+const HASH_BITS: u32 = 12;
+
+#[derive(Debug, Error, PartialEq)]
+pub enum Lz4Error {
+    #[error("the compressed data ends before the block")]
+    Truncated,
+    #[error("the compressed data has a match at offset {0}, before its start")]
+    InvalidOffset(usize),
+    #[error("the compressed data has {actual} bytes instead of the {expected} bytes of its header")]
+    InvalidLength { expected: usize, actual: usize },
+}
+
+/// Compress the bytes into a LZ4 block prefixed by their length as a little-endian u32, the
+/// layout of `LZ4_compress_default` with the size prepended. The compressor is greedy and favors
+/// speed over ratio
+pub fn compress(input: &[u8]) -> Vec<u8> {
+    let mut output = Vec::with_capacity(input.len() / 2 + 16);
+    output.extend_from_slice(&(input.len() as u32).to_le_bytes());
+
+    let mut table = vec![usize::MAX; 1 << HASH_BITS];
+    let mut anchor = 0;
+    let mut position = 0;
+    let match_limit = input.len().saturating_sub(MATCH_LIMIT);
+    while position < match_limit {
+        let sequence = read_u32(input, position);
+        let slot = (sequence.wrapping_mul(2654435761) >> (32 - HASH_BITS)) as usize;
+        let candidate = table[slot];
+        table[slot] = position;
+        if candidate == usize::MAX || position - candidate > MAX_OFFSET || read_u32(input, candidate) != sequence {
+            position += 1;
+            continue;
+        }
+
+        let end_limit = input.len() - LAST_LITERALS;
+        let mut length = MIN_MATCH;
+        while position + length < end_limit && input[candidate + length] == input[position + length] {
+            length += 1;
+        }
+        write_sequence(&mut output, &input[anchor..position], Some((position - candidate, length)));
+        position += length;
+        anchor = position;
+    }
+    write_sequence(&mut output, &input[anchor..], None);
+    output
+}
+
+/// Decompress a block written by `compress`. The length in the header bounds the output, so a
+/// corrupted block can not make it grow past it
+pub fn decompress(input: &[u8]) -> Result<Vec<u8>, Lz4Error> {
+    let header: [u8; 4] = input.get(..4).ok_or(Lz4Error::Truncated)?.try_into().unwrap();
+    let expected = u32::from_le_bytes(header) as usize;
+    // every byte of a block expands into at most 255 bytes
+    let mut output = Vec::with_capacity(expected.min(input.len().saturating_mul(255)));
+    let mut position = 4;
+    let next = |position: &mut usize| -> Result<u8, Lz4Error> {
+        let byte = *input.get(*position).ok_or(Lz4Error::Truncated)?;
+        *position += 1;
+        Ok(byte)
+    };
+    let read_length = |position: &mut usize, mut length: usize| -> Result<usize, Lz4Error> {
+        if length == 15 {
+            loop {
+                let byte = next(position)?;
+                length += usize::from(byte);
+                if byte != 255 {
+                    break;
+                }
+            }
+        }
+        Ok(length)
+    };
+    let too_long = |actual: usize| Lz4Error::InvalidLength { expected, actual };
+
+    loop {
+        let token = next(&mut position)?;
+        let literals = read_length(&mut position, usize::from(token >> 4))?;
+        let literals = input.get(position..position + literals).ok_or(Lz4Error::Truncated)?;
+        if output.len() + literals.len() > expected {
+            return Err(too_long(output.len() + literals.len()));
+        }
+        output.extend_from_slice(literals);
+        position += literals.len();
+        if position == input.len() {
+            break;
+        }
+
+        let offset = usize::from(u16::from_le_bytes([next(&mut position)?, next(&mut position)?]));
+        if offset == 0 || offset > output.len() {
+            return Err(Lz4Error::InvalidOffset(offset));
+        }
+        let length = read_length(&mut position, usize::from(token & 0x0f))? + MIN_MATCH;
+        if output.len() + length > expected {
+            return Err(too_long(output.len() + length));
+        }
+        // the match may overlap the bytes it writes, so it is copied byte by byte
+        let start = output.len() - offset;
+        for index in 0..length {
+            output.push(output[start + index]);
+        }
+    }
+
+    if output.len() != expected {
+        return Err(too_long(output.len()));
+    }
+    Ok(output)
+}
+
+fn read_u32(input: &[u8], position: usize) -> u32 {
+    u32::from_le_bytes(input[position..position + 4].try_into().unwrap())
+}
+
+/// Write the literals and the match after them. The last sequence of a block has no match
+fn write_sequence(output: &mut Vec<u8>, literals: &[u8], matched: Option<(usize, usize)>) {
+    let match_length = matched.map_or(0, |(_, length)| length - MIN_MATCH);
+    output.push(((literals.len().min(15) as u8) << 4) | match_length.min(15) as u8);
+    if literals.len() >= 15 {
+        write_length(output, literals.len() - 15);
+    }
+    output.extend_from_slice(literals);
+    if let Some((offset, _)) = matched {
+        output.extend_from_slice(&(offset as u16).to_le_bytes());
+        if match_length >= 15 {
+            write_length(output, match_length - 15);
+        }
+    }
+}
+
+fn write_length(output: &mut Vec<u8>, mut length: usize) {
+    while length >= 255 {
+        output.push(255);
+        length -= 255;
+    }
+    output.push(length as u8);
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::utils::random::FastRng;
+
+    use super::*;
+
+    #[test]
+    fn test_if_compressed_bytes_are_restored() {
+        let json = r#"{"message":{"id":"0b6f","status":"delivered","payload":"e30="},"attempts":[]}"#.repeat(200);
+        let mut cases: Vec<Vec<u8>> = vec![Vec::new(), b"short".to_vec(), vec![7u8; 70_000], json.clone().into_bytes()];
+        cases.push((0..5000u32).map(|n| (n.wrapping_mul(2654435761) >> 13) as u8).collect());
+        for case in &cases {
+            assert_eq!(&decompress(&compress(case)).unwrap(), case);
+        }
+        assert!(compress(json.as_bytes()).len() < json.len() / 10);
+        // the literals "abc", a match of 12 bytes overlapping itself at offset 3 and the literal "!"
+        let known = [16, 0, 0, 0, 0x38, b'a', b'b', b'c', 3, 0, 0x10, b'!'];
+        assert_eq!(decompress(&known).unwrap(), b"abcabcabcabcabc!");
+    }
+
+    #[test]
+    fn test_if_corrupted_blocks_are_rejected() {
+        let compressed = compress(&b"angler ".repeat(100));
+        assert_eq!(decompress(&compressed[..compressed.len() - 3]), Err(Lz4Error::Truncated));
+        assert_eq!(decompress(&[10, 0, 0, 0, 0x10, b'a', 5, 0]), Err(Lz4Error::InvalidOffset(5)));
+
+        let mut too_long = compressed.clone();
+        too_long[..4].copy_from_slice(&10u32.to_le_bytes());
+        assert!(matches!(decompress(&too_long), Err(Lz4Error::InvalidLength { expected: 10, .. })));
+    }
+
+    #[test]
+    fn test_if_random_and_mutated_blocks_never_decompress_past_their_header() {
+        let mut rng = FastRng::with_seed(1254);
+        for _ in 0..500 {
+            // the bytes repeat a few values, so the blocks have matches
+            let input: Vec<u8> = (0..rng.next_u64() % 2000).map(|_| b"ang"[rng.next_u64() as usize % 3]).collect();
+            let mut compressed = compress(&input);
+            assert_eq!(decompress(&compressed).unwrap(), input);
+
+            let position = rng.next_u64() as usize % compressed.len();
+            compressed[position] = rng.next_u64() as u8;
+            let expected = u32::from_le_bytes(compressed[..4].try_into().unwrap()) as usize;
+            if let Ok(output) = decompress(&compressed) {
+                assert_eq!(output.len(), expected);
+            }
+            let random: Vec<u8> = (0..rng.next_u64() % 64).map(|_| rng.next_u64() as u8).collect();
+            let _ = decompress(&random);
+        }
+    }
+}
@@ -1 +1,12 @@
-pub mod time;
\ No newline at end of file
+pub mod base64;
+pub mod clock;
+pub mod gzip;
+pub mod json;
+pub mod json_schema;
+pub mod log;
+pub mod lz4;
+pub mod random;
+pub mod sha256;
+pub mod time;
+pub mod toml;
+pub mod yaml;
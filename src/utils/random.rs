@@ -0,0 +1,103 @@
+use std::{
+    collections::hash_map::RandomState,
+    hash::{BuildHasher, Hasher},
+    sync::atomic::{AtomicU64, Ordering},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+/// Return a random seed mixing the std RandomState keys, the current time and a process counter
+pub fn random_seed() -> u64 {
+    static COUNTER: AtomicU64 = AtomicU64::new(0);
+    let mut hasher = RandomState::new().build_hasher();
+    hasher.write_u64(COUNTER.fetch_add(1, Ordering::Relaxed));
+    hasher.write_u128(SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_nanos()).unwrap_or_default());
+    hasher.finish()
+}
+
+/// A small and fast pseudo random number generator (xorshift64*). It is NOT suitable
+/// for cryptographic use
+#[derive(Debug, Clone)]
+pub struct FastRng {
+    state: u64,
+}
+
+impl FastRng {
+    /// Create a generator seeded with a random seed
+    pub fn new() -> FastRng {
+        FastRng::with_seed(random_seed())
+    }
+
+    /// Create a generator with the given seed. The same seed always produces the same sequence
+    pub fn with_seed(seed: u64) -> FastRng {
+        // the state of xorshift can never be zero
+        FastRng { state: if seed == 0 { 0x9E37_79B9_7F4A_7C15 } else { seed } }
+    }
+
+    /// Return the next random u64
+    pub fn next_u64(&mut self) -> u64 {
+        self.state ^= self.state >> 12;
+        self.state ^= self.state << 25;
+        self.state ^= self.state >> 27;
+        self.state.wrapping_mul(0x2545_F491_4F6C_DD1D)
+    }
+
+    /// Return a random f64 in the range [0, 1)
+    pub fn next_f64(&mut self) -> f64 {
+        (self.next_u64() >> 11) as f64 / (1u64 << 53) as f64
+    }
+}
+
+impl Default for FastRng {
+    fn default() -> Self {
+        FastRng::new()
+    }
+}
+
+/// Generate a random UUID (version 4) in its hyphenated form
+pub fn uuid_v4() -> String {
+    let mut rng = FastRng::new();
+    let mut bytes = [0u8; 16];
+    bytes[..8].copy_from_slice(&rng.next_u64().to_be_bytes());
+    bytes[8..].copy_from_slice(&rng.next_u64().to_be_bytes());
+    bytes[6] = (bytes[6] & 0x0f) | 0x40; // version 4
+    bytes[8] = (bytes[8] & 0x3f) | 0x80; // RFC 4122 variant
+    format_uuid(&bytes)
+}
+
+/// Format 16 bytes as a hyphenated UUID string
+pub fn format_uuid(bytes: &[u8; 16]) -> String {
+    let hex: String = bytes.iter().map(|b| format!("{:02x}", b)).collect();
+    format!("{}-{}-{}-{}-{}", &hex[0..8], &hex[8..12], &hex[12..16], &hex[16..20], &hex[20..32])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_if_same_seed_generates_same_sequence() {
+        let mut a = FastRng::with_seed(42);
+        let mut b = FastRng::with_seed(42);
+        for _ in 0..100 {
+            assert_eq!(a.next_u64(), b.next_u64());
+        }
+    }
+
+    #[test]
+    fn test_if_next_f64_is_in_range() {
+        let mut rng = FastRng::new();
+        for _ in 0..1000 {
+            let v = rng.next_f64();
+            assert!((0.0..1.0).contains(&v));
+        }
+    }
+
+    #[test]
+    fn test_if_uuid_v4_has_expected_format() {
+        let uuid = uuid_v4();
+        assert_eq!(uuid.len(), 36);
+        assert_eq!(&uuid[14..15], "4");
+        assert!(["8", "9", "a", "b"].contains(&&uuid[19..20]));
+        assert_ne!(uuid, uuid_v4());
+    }
+}
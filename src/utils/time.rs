@@ -1,14 +1,13 @@
-use std::sync::OnceLock;
+use std::{path::PathBuf, sync::{Arc, OnceLock}};
 
 use regex::Regex;
 use thiserror::Error;
-use time::{error, Duration};
+use time::{format_description::well_known::Rfc3339, Date, Duration, Month, OffsetDateTime, PrimitiveDateTime, Time, UtcOffset, Weekday};
 
 fn duration_unit_syntax_regex() -> &'static Regex {
     static REGEX: OnceLock<Regex> = OnceLock::new();
     REGEX.get_or_init(|| {
-        let mut r = Regex::new(r"^(\d+)(s|m|h|d|w)$").unwrap();
-        r
+        Regex::new(r"^(\d+)(s|m|h|d|w)$").unwrap()
     })
 }
 
@@ -18,42 +17,126 @@ pub enum DurationSequenceError {
     EmptySequence,
 }
 
+/// The intervals after the last one of a DurationSequence, written after it with `then`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SequenceTail {
+    /// `then every 6h`, the interval repeated forever
+    Every(Duration),
+    /// `then doubling up to 6h`, the last interval of the sequence doubled each time up to the limit
+    Doubling { up_to: Duration },
+}
+
+impl SequenceTail {
+    /// Return the interval `index` positions after the last one of the sequence, where the first
+    /// interval of the tail is at 0
+    fn interval_at(&self, last: Duration, index: usize) -> Duration {
+        match *self {
+            SequenceTail::Every(interval) => interval,
+            SequenceTail::Doubling { up_to } => {
+                let mut interval = last;
+                for _ in 0..=index {
+                    if interval >= up_to {
+                        break;
+                    }
+                    interval = interval.saturating_mul(2);
+                }
+                interval.min(up_to)
+            }
+        }
+    }
+
+    /// Return the tail with its interval changed by the function
+    pub fn map(&self, f: impl Fn(Duration) -> Duration) -> SequenceTail {
+        match *self {
+            SequenceTail::Every(interval) => SequenceTail::Every(f(interval)),
+            SequenceTail::Doubling { up_to } => SequenceTail::Doubling { up_to: f(up_to) },
+        }
+    }
+}
+
+impl std::fmt::Display for SequenceTail {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SequenceTail::Every(interval) => write!(f, "every {}", format_duration(*interval)),
+            SequenceTail::Doubling { up_to } => write!(f, "doubling up to {}", format_duration(*up_to)),
+        }
+    }
+}
+
+/// A part of a DurationSequence beyond a limit, returned by `DurationSequence::validate_against`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SequenceViolation {
+    /// The element at the position, starting at 1, is longer than the max interval
+    IntervalTooLong { position: usize, interval: Duration, max_interval: Duration },
+    /// The interval of the tail is longer than the max interval
+    TailTooLong { tail: SequenceTail, max_interval: Duration },
+    /// The sequence has more elements than the max length
+    TooManyElements { len: usize, max_len: usize },
+}
+
+impl std::fmt::Display for SequenceViolation {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SequenceViolation::IntervalTooLong { position, interval, max_interval } => {
+                write!(f, "element {} ({}) exceeds maxInterval {}", position, format_duration(*interval), format_duration(*max_interval))
+            }
+            SequenceViolation::TailTooLong { tail, max_interval } => write!(f, "the tail ({}) exceeds maxInterval {}", tail, format_duration(*max_interval)),
+            SequenceViolation::TooManyElements { len, max_len } => write!(f, "the sequence has {} elements, more than the limit of {}", len, max_len),
+        }
+    }
+}
+
 /// A duration sequence is a Vec<Duration> where it stores a interval of time where a event should happen. For example:
 /// [5m, 5m, 1h, 12h, 36h, 1d, 1d, 3d] represents that a event should happen first in 5 minutes than 5 minutes than 1 hour and so on.
+/// A sequence may end with a tail, like `[1m, 5m, 30m] then every 6h`, that defines the intervals after its last one.
 #[derive(Debug)]
 pub struct DurationSequence {
     sequence: Vec<Duration>,
     total_duration: Duration,
+    tail: Option<SequenceTail>,
 }
 
 impl DurationSequence {
     /// Create a instance of DurationSequence from a Vec<Duration>
     pub fn from_vec(dur_seq: Vec<Duration>) -> Result<DurationSequence, DurationSequenceError> {
-        if dur_seq.len() == 0 {
+        if dur_seq.is_empty() {
             return Err(DurationSequenceError::EmptySequence);
         }
 
-        let total_duration = dur_seq.iter().sum();
-        return Ok(DurationSequence { sequence: dur_seq, total_duration })
+        // saturated, so the sequences of a untrusted input can not overflow it
+        let total_duration = dur_seq.iter().fold(Duration::ZERO, |total, d| total.saturating_add(*d));
+        Ok(DurationSequence { sequence: dur_seq, total_duration, tail: None })
+    }
+
+    /// Set the intervals after the last one of the sequence
+    pub fn with_tail(mut self, tail: SequenceTail) -> DurationSequence {
+        self.tail = Some(tail);
+        self
     }
 
     /// Return a element from the duration sequence wrapped on a Option
     pub fn get_from_sequence(&self, index: usize) -> Option<&Duration> {
-        return self.sequence.get(index);
+        self.sequence.get(index)
     }
 
     /// Return a element from the sequence if its not found return the first element. This function asserts
     /// that this instance has at least 1 element
     pub fn get_from_sequence_or_first(&self, index: usize) -> &Duration {
-        return self.get_from_sequence(index).unwrap_or_else(|| self.sequence.get(0).unwrap())
+        self.get_from_sequence(index).unwrap_or_else(|| self.sequence.first().unwrap())
+    }
+
+    /// Return a element from the sequence if its not found return the last element. This function asserts
+    /// that this instance has at least 1 element
+    pub fn get_from_sequence_or_last(&self, index: usize) -> &Duration {
+        self.get_from_sequence(index).unwrap_or_else(|| self.sequence.last().unwrap())
     }
 
     /// Add a duration into the duration sequence of this instance. Also return a mutable reference to this
     /// instance so it can call another function in cascade
     pub fn push(&mut self, d: Duration) -> &mut Self {
         self.sequence.push(d);
-        self.total_duration += d;
-        return self
+        self.total_duration = self.total_duration.saturating_add(d);
+        self
     }
 
     /// Return the reference to the duration sequence of this instance
@@ -61,24 +144,75 @@ impl DurationSequence {
         &self.sequence
     }
 
-    /// Return the reference to the total duration of this instance
+    /// Return the reference to the total duration of this instance, not counting its tail
     pub fn total_duration(&self) -> &Duration {
         &self.total_duration
     }
 
+    pub fn tail(&self) -> Option<&SequenceTail> {
+        self.tail.as_ref()
+    }
+
+    /// Return the interval at the index. After the sequence the interval comes from its tail or,
+    /// without one, is the last element of the sequence
+    pub fn interval_at(&self, index: usize) -> Duration {
+        let last = *self.sequence.last().unwrap();
+        match (self.get_from_sequence(index), &self.tail) {
+            (Some(interval), _) => *interval,
+            (None, Some(tail)) => tail.interval_at(last, index - self.sequence.len()),
+            (None, None) => last,
+        }
+    }
+
+    /// Return each element of the sequence, and its tail, longer than the max interval and if the
+    /// sequence has more elements than the max length, so the limits can be told apart instead of
+    /// silently clamped
+    pub fn validate_against(&self, max_interval: Option<Duration>, max_len: Option<usize>) -> Result<(), Vec<SequenceViolation>> {
+        let mut violations = Vec::new();
+        if let Some(max_interval) = max_interval {
+            let too_long = self.sequence.iter().enumerate().filter(|(_, interval)| **interval > max_interval);
+            violations.extend(too_long.map(|(index, interval)| SequenceViolation::IntervalTooLong { position: index + 1, interval: *interval, max_interval }));
+            let tail_interval = self.tail.map(|tail| match tail {
+                SequenceTail::Every(interval) => interval,
+                SequenceTail::Doubling { up_to } => up_to,
+            });
+            if let Some(tail) = self.tail.filter(|_| tail_interval > Some(max_interval)) {
+                violations.push(SequenceViolation::TailTooLong { tail, max_interval });
+            }
+        }
+        if let Some(max_len) = max_len.filter(|max_len| self.sequence.len() > *max_len) {
+            violations.push(SequenceViolation::TooManyElements { len: self.sequence.len(), max_len });
+        }
+        match violations.is_empty() {
+            true => Ok(()),
+            false => Err(violations),
+        }
+    }
 }
 
 /// DurationSequence implementation of Clone
 impl Clone for DurationSequence {
     fn clone(&self) -> Self {
-        Self { sequence: self.sequence.clone(), total_duration: self.total_duration.clone() }
+        Self { sequence: self.sequence.clone(), total_duration: self.total_duration, tail: self.tail }
+    }
+}
+
+/// Format the sequence in the Angler duration syntax, like `[5m, 1h, 1d]` or `[5m, 1h] then every 6h`
+impl std::fmt::Display for DurationSequence {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let durations: Vec<String> = self.sequence.iter().map(|d| format_duration(*d)).collect();
+        write!(f, "[{}]", durations.join(", "))?;
+        match &self.tail {
+            Some(tail) => write!(f, " then {}", tail),
+            None => Ok(()),
+        }
     }
 }
 
 /// DurationSequence implementation of PartialEq
 impl PartialEq for DurationSequence {
     fn eq(&self, other: &Self) -> bool {
-        self.sequence == other.sequence && self.total_duration == other.total_duration
+        self.sequence == other.sequence && self.total_duration == other.total_duration && self.tail == other.tail
     }
 }
 
@@ -107,16 +241,17 @@ impl DurationDeserializer for &str {
             let unit = caps.get(2).unwrap().as_str();
             let number: i64 = number_str.parse().map_err(|_| DurationSerdeErrors::InvalidSyntax)?;
 
-            // Match the unit and create the corresponding Duration
-            let duration = match unit {
-                "s" => Duration::seconds(number),
-                "m" => Duration::minutes(number),
-                "h" => Duration::hours(number),
-                "d" => Duration::days(number),
-                "w" => Duration::weeks(number),
+            // Match the unit and create the corresponding Duration, refusing the amounts that
+            // overflow it instead of panicking
+            let unit_seconds: i64 = match unit {
+                "s" => 1,
+                "m" => 60,
+                "h" => 3_600,
+                "d" => 86_400,
+                "w" => 604_800,
                 _ => return Err(DurationSerdeErrors::InvalidSyntax), // should not happen
             };
-            Ok(duration)
+            number.checked_mul(unit_seconds).map(Duration::seconds).ok_or(DurationSerdeErrors::InvalidSyntax)
         } else {
             Err(DurationSerdeErrors::InvalidSyntax)
         }
@@ -125,6 +260,17 @@ impl DurationDeserializer for &str {
 
 impl DurationSequenceDeserializer for &str {
     fn to_duration_sequence(&self) -> Result<DurationSequence, DurationSerdeErrors> {
+        // a tail like `then every 6h` or `then doubling up to 6h` follows the sequence
+        if let Some((sequence, tail)) = self.split_once(" then ") {
+            let tail = tail.trim();
+            let tail = match (tail.strip_prefix("every "), tail.strip_prefix("doubling up to ")) {
+                (Some(interval), _) => SequenceTail::Every(interval.trim().to_duration()?),
+                (_, Some(up_to)) => SequenceTail::Doubling { up_to: up_to.trim().to_duration()? },
+                _ => return Err(DurationSerdeErrors::InvalidSyntax),
+            };
+            return Ok(sequence.trim().to_duration_sequence()?.with_tail(tail));
+        }
+
         // if the value does is not contained by '['']' this compiler will assume that it is a single
         // value sequence (like 1d, 30m, etc,) so it will deserialize as self.to_duration and included it in a sequence
         if !self.starts_with('[')  || !self.ends_with(']') {
@@ -142,7 +288,7 @@ impl DurationSequenceDeserializer for &str {
         }
 
         // assert that at least one value is passed in duration sequence vec
-        if duration_seq.len() < 1 {
+        if duration_seq.is_empty() {
             return Err(DurationSerdeErrors::InvalidSyntax)
         }
 
@@ -150,11 +296,409 @@ impl DurationSequenceDeserializer for &str {
     }
 }
 
+/// Format a duration in the Angler duration syntax using the largest unit that represents it
+/// exactly, like `90m` or `2d`. Fractions of seconds are truncated
+pub fn format_duration(duration: Duration) -> String {
+    let seconds = duration.whole_seconds();
+    let (amount, unit) = [(604_800, "w"), (86_400, "d"), (3_600, "h"), (60, "m")].iter()
+        .find(|(unit_seconds, _)| seconds != 0 && seconds % unit_seconds == 0)
+        .map(|(unit_seconds, unit)| (seconds / unit_seconds, *unit))
+        .unwrap_or((seconds, "s"));
+    format!("{}{}", amount, unit)
+}
+
+/// Format a date time as a RFC 3339 UTC timestamp with milliseconds, like `2024-05-30T13:45:10.250Z`
+pub fn format_rfc3339(date_time: OffsetDateTime) -> String {
+    let utc = date_time.to_offset(UtcOffset::UTC);
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.{:03}Z",
+        utc.year(), u8::from(utc.month()), utc.day(),
+        utc.hour(), utc.minute(), utc.second(), utc.millisecond()
+    )
+}
+
+/// Parse a RFC 3339 timestamp, like `2024-05-30T10:45:10-03:00`
+pub fn parse_rfc3339(value: &str) -> Result<OffsetDateTime, time::error::Parse> {
+    OffsetDateTime::parse(value, &Rfc3339)
+}
+
+/// Where the IANA timezones are read from when `TZDIR` is not set
+pub const DEFAULT_ZONEINFO_DIR: &str = "/usr/share/zoneinfo";
+
+#[derive(Debug, Error, PartialEq)]
+pub enum TimeZoneError {
+    #[error("{0} is not a timezone. Use UTC, a offset like -03:00 or a IANA name like America/Sao_Paulo")]
+    Unknown(String),
+    #[error("the timezone file of {0} is invalid: {1}")]
+    InvalidFile(String, String),
+}
+
+/// A timezone whose offset may change along the year: UTC, a fixed offset or a IANA zone read
+/// from the zoneinfo database of the system. Every conversion between wall clock times and
+/// instants of Angler goes through it, so the daylight saving time rules live in one place
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TimeZone {
+    name: String,
+    rules: ZoneRules,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum ZoneRules {
+    Fixed(UtcOffset),
+    /// The transitions of a TZif file, with the rule of its footer for the times after them
+    Transitions(Arc<Transitions>),
+}
+
+#[derive(Debug, PartialEq, Eq)]
+struct Transitions {
+    /// The unix times when the offset changes, with the offset from then on
+    changes: Vec<(i64, UtcOffset)>,
+    /// The offset before the first change
+    initial: UtcOffset,
+    tail: Option<PosixRule>,
+}
+
+/// The rule of a POSIX TZ string like `CET-1CEST,M3.5.0,M10.5.0/3`
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct PosixRule {
+    standard: UtcOffset,
+    daylight: Option<DaylightRule>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct DaylightRule {
+    offset: UtcOffset,
+    /// When the daylight saving time starts and ends, with the seconds after midnight of the day
+    /// in the offset in effect before the change
+    start: (RuleDay, i64),
+    end: (RuleDay, i64),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum RuleDay {
+    /// `Jn`, the day of the year from 1 to 365 not counting february 29
+    Julian(u16),
+    /// `n`, the day of the year from 0 to 365 counting february 29
+    Ordinal(u16),
+    /// `Mm.w.d`, the day `d` (0 is sunday) of the week `w` (5 is the last one) of the month `m`
+    MonthWeek { month: Month, week: u8, weekday: u8 },
+}
+
+impl TimeZone {
+    pub const UTC: TimeZone = TimeZone { name: String::new(), rules: ZoneRules::Fixed(UtcOffset::UTC) };
+
+    /// Parse `UTC`, a offset like `-03:00` or a IANA name like `America/Sao_Paulo`, read from
+    /// `TZDIR` or /usr/share/zoneinfo
+    pub fn parse(name: &str) -> Result<TimeZone, TimeZoneError> {
+        let name = name.trim();
+        if name.eq_ignore_ascii_case("utc") || name == "Z" {
+            return Ok(TimeZone::UTC);
+        }
+        if name.starts_with(['+', '-']) {
+            return parse_fixed_offset(name).map(TimeZone::fixed).ok_or_else(|| TimeZoneError::Unknown(name.to_string()));
+        }
+        let is_valid_part = |part: &str| !part.is_empty() && !part.starts_with('.') && part.chars().all(|c| c.is_ascii_alphanumeric() || "_-+".contains(c));
+        if !name.split('/').all(is_valid_part) {
+            return Err(TimeZoneError::Unknown(name.to_string()));
+        }
+        let dir = std::env::var_os("TZDIR").map(PathBuf::from).unwrap_or_else(|| PathBuf::from(DEFAULT_ZONEINFO_DIR));
+        let bytes = std::fs::read(dir.join(name)).map_err(|_| TimeZoneError::Unknown(name.to_string()))?;
+        TimeZone::from_tzif(name, &bytes)
+    }
+
+    /// Create a timezone that is always at the offset
+    pub fn fixed(offset: UtcOffset) -> TimeZone {
+        if offset.is_utc() {
+            return TimeZone::UTC;
+        }
+        let sign = if offset.is_negative() { '-' } else { '+' };
+        let name = format!("{}{:02}:{:02}", sign, offset.whole_hours().abs(), offset.minutes_past_hour().abs());
+        TimeZone { name, rules: ZoneRules::Fixed(offset) }
+    }
+
+    /// Read a zone from the bytes of its TZif file (RFC 8536)
+    pub fn from_tzif(name: &str, bytes: &[u8]) -> Result<TimeZone, TimeZoneError> {
+        let transitions = parse_tzif(bytes).map_err(|err| TimeZoneError::InvalidFile(name.to_string(), err))?;
+        Ok(TimeZone { name: name.to_string(), rules: ZoneRules::Transitions(Arc::new(transitions)) })
+    }
+
+    /// Return the name used to parse the timezone, `UTC` for UTC
+    pub fn name(&self) -> &str {
+        if self.name.is_empty() { "UTC" } else { &self.name }
+    }
+
+    /// Return the offset of the timezone at the instant
+    pub fn offset_at(&self, at: OffsetDateTime) -> UtcOffset {
+        match &self.rules {
+            ZoneRules::Fixed(offset) => *offset,
+            ZoneRules::Transitions(transitions) => transitions.offset_at(at.unix_timestamp()),
+        }
+    }
+
+    /// Return the instant in the offset of the timezone at it
+    pub fn to_local(&self, at: OffsetDateTime) -> OffsetDateTime {
+        at.to_offset(self.offset_at(at))
+    }
+
+    /// Return the instant when the wall clock of the timezone shows the local time. A time skipped
+    /// when the clocks move forward is moved forward by the gap, like 02:30 becoming 03:30, and a
+    /// time repeated when they move back is the first of the two
+    pub fn from_local(&self, local: PrimitiveDateTime) -> OffsetDateTime {
+        let naive = local.assume_utc();
+        let before = self.offset_at(naive - Duration::days(1));
+        let after = self.offset_at(naive + Duration::days(1));
+        let mut candidates: Vec<OffsetDateTime> = [before, after].into_iter()
+            .map(|offset| local.assume_offset(offset))
+            .filter(|instant| self.offset_at(*instant) == instant.offset())
+            .collect();
+        candidates.sort();
+        candidates.first().copied().unwrap_or_else(|| local.assume_offset(before))
+    }
+
+    /// Add calendar days keeping the wall clock time, so a day across a daylight saving time
+    /// change has 23 or 25 hours
+    pub fn add_days(&self, at: OffsetDateTime, days: i64) -> OffsetDateTime {
+        let local = self.to_local(at);
+        self.from_local(PrimitiveDateTime::new(local.date() + Duration::days(days), local.time()))
+    }
+
+    /// Return the first instant after `after` when the wall clock shows `time` on a day accepted
+    /// by `on`, the next opening of a window that opens at `time`. None when `on` accepts no day
+    pub fn next_occurrence(&self, after: OffsetDateTime, time: Time, on: impl Fn(Weekday) -> bool) -> Option<OffsetDateTime> {
+        let today = self.to_local(after).date();
+        // a week and a day, as the occurrence of today may have passed
+        (0..=8)
+            .map(|days| today + Duration::days(days))
+            .filter(|date| on(date.weekday()))
+            .map(|date| self.from_local(PrimitiveDateTime::new(date, time)))
+            .find(|occurrence| *occurrence > after)
+    }
+}
+
+impl Transitions {
+    fn offset_at(&self, unix_time: i64) -> UtcOffset {
+        match self.changes.partition_point(|(at, _)| *at <= unix_time) {
+            0 if self.changes.is_empty() => self.tail.as_ref().map_or(self.initial, |tail| tail.offset_at(unix_time)),
+            0 => self.initial,
+            index if index == self.changes.len() => self.tail.as_ref().map_or(self.changes[index - 1].1, |tail| tail.offset_at(unix_time)),
+            index => self.changes[index - 1].1,
+        }
+    }
+}
+
+impl PosixRule {
+    fn offset_at(&self, unix_time: i64) -> UtcOffset {
+        let Some(daylight) = &self.daylight else {
+            return self.standard;
+        };
+        let year = OffsetDateTime::from_unix_timestamp(unix_time).map_or(1970, |at| at.to_offset(self.standard).year());
+        let change = |(day, seconds): (RuleDay, i64), offset: UtcOffset| {
+            day.date(year).midnight().assume_utc().unix_timestamp() + seconds - i64::from(offset.whole_seconds())
+        };
+        let starts_at = change(daylight.start, self.standard);
+        let ends_at = change(daylight.end, daylight.offset);
+        let is_daylight = if starts_at < ends_at {
+            starts_at <= unix_time && unix_time < ends_at
+        } else {
+            // the southern hemisphere, where the daylight saving time crosses the new year
+            unix_time < ends_at || unix_time >= starts_at
+        };
+        if is_daylight { daylight.offset } else { self.standard }
+    }
+
+    /// Parse a POSIX TZ string, the footer of the TZif files
+    fn parse(rule: &str) -> Option<PosixRule> {
+        let mut rest = rule;
+        skip_zone_name(&mut rest)?;
+        // the POSIX offsets are west of Greenwich, the opposite of the ISO ones
+        let standard = offset_from_seconds(-parse_posix_time(&mut rest)?)?;
+        if rest.is_empty() {
+            return Some(PosixRule { standard, daylight: None });
+        }
+        skip_zone_name(&mut rest)?;
+        let offset = match rest.starts_with(|c: char| c.is_ascii_digit() || c == '+' || c == '-') {
+            true => offset_from_seconds(-parse_posix_time(&mut rest)?)?,
+            false => offset_from_seconds(i64::from(standard.whole_seconds()) + 3600)?,
+        };
+        let mut changes = rest.strip_prefix(',')?.split(',');
+        let start = parse_rule_change(changes.next()?)?;
+        let end = parse_rule_change(changes.next()?)?;
+        if changes.next().is_some() {
+            return None;
+        }
+        Some(PosixRule { standard, daylight: Some(DaylightRule { offset, start, end }) })
+    }
+}
+
+impl RuleDay {
+    fn date(&self, year: i32) -> Date {
+        let first_day = Date::from_ordinal_date(year, 1).expect("every year has a first day");
+        match *self {
+            RuleDay::Julian(day) => {
+                let leap_day = u16::from(time::util::is_leap_year(year) && day >= 60);
+                first_day + Duration::days(i64::from(day + leap_day) - 1)
+            }
+            RuleDay::Ordinal(day) => first_day + Duration::days(i64::from(day.min(time::util::days_in_year(year) - 1))),
+            RuleDay::MonthWeek { month, week, weekday } => {
+                let first = Date::from_calendar_date(year, month, 1).expect("every month has a first day");
+                let first_weekday = (7 + weekday - first.weekday().number_days_from_sunday()) % 7;
+                let mut day = 1 + first_weekday + (week - 1) * 7;
+                while day > time::util::days_in_year_month(year, month) {
+                    day -= 7;
+                }
+                first.replace_day(day).expect("the day is inside the month")
+            }
+        }
+    }
+}
+
+/// Parse `+hh:mm` or `-hh:mm`
+fn parse_fixed_offset(value: &str) -> Option<UtcOffset> {
+    let (sign, offset) = match value.split_at_checked(1)? {
+        ("+", offset) => (1, offset),
+        ("-", offset) => (-1, offset),
+        _ => return None,
+    };
+    let (hours, minutes) = offset.split_once(':')?;
+    let (hours, minutes): (i8, i8) = (hours.parse().ok()?, minutes.parse().ok()?);
+    if !(0..60).contains(&minutes) || hours < 0 {
+        return None;
+    }
+    UtcOffset::from_hms(sign * hours, sign * minutes, 0).ok()
+}
+
+fn offset_from_seconds(seconds: i64) -> Option<UtcOffset> {
+    UtcOffset::from_whole_seconds(i32::try_from(seconds).ok()?).ok()
+}
+
+/// Skip a zone abbreviation, like `CET` or `<-03>`
+fn skip_zone_name(rest: &mut &str) -> Option<()> {
+    let length = match rest.strip_prefix('<') {
+        Some(quoted) => quoted.find('>')? + 2,
+        None => rest.find(|c: char| !c.is_ascii_alphabetic()).unwrap_or(rest.len()),
+    };
+    if length < 3 {
+        return None;
+    }
+    *rest = &rest[length..];
+    Some(())
+}
+
+/// Parse a `[+-]hh[:mm[:ss]]` into seconds
+fn parse_posix_time(rest: &mut &str) -> Option<i64> {
+    let length = rest.find(|c: char| !(c.is_ascii_digit() || ":+-".contains(c))).unwrap_or(rest.len());
+    let (time, remaining) = rest.split_at(length);
+    *rest = remaining;
+    let (sign, time) = match time.strip_prefix('-') {
+        Some(time) => (-1, time),
+        None => (1, time.strip_prefix('+').unwrap_or(time)),
+    };
+    let mut seconds = 0;
+    for (index, part) in time.split(':').enumerate() {
+        if index > 2 {
+            return None;
+        }
+        seconds += part.parse::<i64>().ok()? * [3600, 60, 1][index];
+    }
+    Some(sign * seconds)
+}
+
+/// Parse a change of a POSIX rule, like `M3.5.0` or `M10.5.0/3`
+fn parse_rule_change(change: &str) -> Option<(RuleDay, i64)> {
+    let (day, time) = change.split_once('/').unwrap_or((change, "2"));
+    let day = if let Some(julian) = day.strip_prefix('J') {
+        RuleDay::Julian(julian.parse().ok().filter(|day| (1..=365).contains(day))?)
+    } else if let Some(month_week) = day.strip_prefix('M') {
+        let [month, week, weekday] = month_week.split('.').map(str::parse::<u8>).collect::<Result<Vec<_>, _>>().ok()?[..] else {
+            return None;
+        };
+        if !(1..=5).contains(&week) || weekday > 6 {
+            return None;
+        }
+        RuleDay::MonthWeek { month: Month::try_from(month).ok()?, week, weekday }
+    } else {
+        RuleDay::Ordinal(day.parse().ok().filter(|day| *day <= 365)?)
+    };
+    let mut time = time;
+    let seconds = parse_posix_time(&mut time)?;
+    time.is_empty().then_some((day, seconds))
+}
+
+/// Read the transitions of a TZif file. The 64-bit data of the version 2 and later files is
+/// used when present, with the POSIX rule of their footer
+fn parse_tzif(bytes: &[u8]) -> Result<Transitions, String> {
+    let header = |bytes: &[u8]| -> Result<(u8, [usize; 6]), String> {
+        if bytes.get(..4) != Some(b"TZif") {
+            return Err(String::from("it does not start with TZif"));
+        }
+        let counts = bytes.get(20..44).ok_or("the header is truncated")?;
+        let mut parsed = [0; 6];
+        for (index, count) in counts.chunks(4).enumerate() {
+            parsed[index] = u32::from_be_bytes(count.try_into().unwrap()) as usize;
+        }
+        Ok((bytes[4], parsed))
+    };
+    // isutcnt, isstdcnt, leapcnt, timecnt, typecnt and charcnt
+    let block_length = |counts: [usize; 6], time_size: usize| {
+        counts[3] * time_size + counts[3] + counts[4] * 6 + counts[5] + counts[2] * (time_size + 4) + counts[1] + counts[0]
+    };
+
+    let (version, counts) = header(bytes)?;
+    let (data, counts, time_size) = if version >= b'2' {
+        let second = bytes.get(44 + block_length(counts, 4)..).ok_or("the version 1 data is truncated")?;
+        let (_, counts) = header(second)?;
+        (&second[44..], counts, 8)
+    } else {
+        (&bytes[44..], counts, 4)
+    };
+    let length = block_length(counts, time_size);
+    if data.len() < length {
+        return Err(String::from("the data block is truncated"));
+    }
+    let [_, _, _, time_count, type_count, _] = counts;
+    if type_count == 0 {
+        return Err(String::from("it has no local time types"));
+    }
+
+    let types_start = time_count * time_size + time_count;
+    let offsets = (0..type_count)
+        .map(|index| {
+            let seconds = i32::from_be_bytes(data[types_start + index * 6..types_start + index * 6 + 4].try_into().unwrap());
+            UtcOffset::from_whole_seconds(seconds).map_err(|_| format!("{} is not a UTC offset", seconds))
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+    let mut changes = Vec::with_capacity(time_count);
+    for index in 0..time_count {
+        let time = &data[index * time_size..(index + 1) * time_size];
+        let at = match time_size {
+            8 => i64::from_be_bytes(time.try_into().unwrap()),
+            _ => i64::from(i32::from_be_bytes(time.try_into().unwrap())),
+        };
+        let offset = *offsets.get(usize::from(data[time_count * time_size + index])).ok_or("a transition has a unknown local time type")?;
+        changes.push((at, offset));
+    }
+
+    let tail = match time_size {
+        8 => {
+            let footer = std::str::from_utf8(&data[length..]).map_err(|_| "the footer is not UTF-8")?;
+            let rule = footer.trim_matches('\n');
+            match rule.is_empty() {
+                true => None,
+                false => Some(PosixRule::parse(rule).ok_or_else(|| format!("{} is not a POSIX TZ rule", rule))?),
+            }
+        }
+        _ => None,
+    };
+    Ok(Transitions { changes, initial: offsets[0], tail })
+}
+
 #[cfg(test)]
 mod tests {
 
     use std::vec;
 
+    use crate::utils::random::FastRng;
+
     use super::*;
 
     /// Tests if DurationSequence::from_vec() has expected values
@@ -177,6 +721,54 @@ mod tests {
         DurationSequence::from_vec(vec![]).unwrap();
     }
 
+    #[test]
+    fn test_if_format_rfc3339_uses_utc_with_milliseconds() {
+        let date_time = OffsetDateTime::from_unix_timestamp_nanos(1_717_076_710_250_000_000).unwrap()
+            .to_offset(UtcOffset::from_hms(-3, 0, 0).unwrap());
+        assert_eq!(format_rfc3339(date_time), "2024-05-30T13:45:10.250Z");
+        assert_eq!(parse_rfc3339("2024-05-30T10:45:10.250-03:00").unwrap(), date_time);
+        assert!(parse_rfc3339("2024-05-30").is_err());
+    }
+
+    #[test]
+    fn test_if_durations_are_formatted_with_the_largest_exact_unit() {
+        assert_eq!(format_duration(Duration::minutes(90)), "90m");
+        assert_eq!(format_duration(Duration::days(14)), "2w");
+        assert_eq!(format_duration(Duration::ZERO), "0s");
+        assert_eq!("[5m, 1h, 36h]".to_duration_sequence().unwrap().to_string(), "[5m, 1h, 36h]");
+    }
+
+    #[test]
+    fn test_if_generated_durations_and_sequences_are_parsed_back_and_garbage_is_refused() {
+        let mut rng = FastRng::with_seed(1254);
+        let units = [1, 60, 3_600, 86_400, 604_800];
+        let duration = |rng: &mut FastRng| Duration::seconds((rng.next_u64() % 10_000) as i64 * units[rng.next_u64() as usize % units.len()]);
+        for _ in 0..500 {
+            let len = 1 + rng.next_u64() as usize % 6;
+            let mut sequence = DurationSequence::from_vec((0..len).map(|_| duration(&mut rng)).collect()).unwrap();
+            assert_eq!(format_duration(sequence.sequence()[0]).as_str().to_duration().unwrap(), sequence.sequence()[0]);
+            sequence = match rng.next_u64() % 3 {
+                0 => sequence,
+                1 => sequence.with_tail(SequenceTail::Every(duration(&mut rng))),
+                _ => sequence.with_tail(SequenceTail::Doubling { up_to: duration(&mut rng) }),
+            };
+            assert_eq!(sequence.to_string().as_str().to_duration_sequence().unwrap(), sequence);
+        }
+
+        // the bytes of the valid syntax, shuffled, and the amounts that overflow a duration
+        let alphabet: Vec<char> = "0123456789smhdwx[], then every doubling up to ".chars().collect();
+        for _ in 0..2000 {
+            let len = rng.next_u64() as usize % 24;
+            let garbage: String = (0..len).map(|_| alphabet[rng.next_u64() as usize % alphabet.len()]).collect();
+            let _ = garbage.as_str().to_duration();
+            let _ = garbage.as_str().to_duration_sequence();
+        }
+        assert!("9223372036854775807w".to_duration().is_err());
+        assert!("99999999999999999999s".to_duration().is_err());
+        assert!("[1m, 9223372036854775807d]".to_duration_sequence().is_err());
+        assert_eq!(*"[15250284452471w, 15250284452471w]".to_duration_sequence().unwrap().total_duration(), Duration::MAX);
+    }
+
     #[test]
     fn test_if_string_to_duration_works() {
         let duration = "5d".to_duration().unwrap();
@@ -196,6 +788,40 @@ mod tests {
         assert_eq!(duration_seq.total_duration.whole_hours(), (3 + 1 + 1 + 1) * 24 + (36 + 12 + 1));
     }
 
+    #[test]
+    fn test_if_duration_sequences_continue_with_their_tail() {
+        let every = "[1m, 5m, 30m] then every 6h".to_duration_sequence().unwrap();
+        assert_eq!(every.tail(), Some(&SequenceTail::Every(Duration::hours(6))));
+        assert_eq!((2..6).map(|index| every.interval_at(index)).collect::<Vec<_>>(), vec![Duration::minutes(30), Duration::hours(6), Duration::hours(6), Duration::hours(6)]);
+        assert_eq!(every.to_string(), "[1m, 5m, 30m] then every 6h");
+        assert_eq!(every.to_string().as_str().to_duration_sequence().unwrap(), every);
+
+        let doubling = "[1m, 5m] then doubling up to 30m".to_duration_sequence().unwrap();
+        let intervals: Vec<i64> = (0..6).map(|index| doubling.interval_at(index).whole_minutes()).collect();
+        assert_eq!(intervals, vec![1, 5, 10, 20, 30, 30]);
+        assert_eq!("1m then every 1h".to_duration_sequence().unwrap().interval_at(1), Duration::hours(1));
+        assert_eq!("[1m, 5m]".to_duration_sequence().unwrap().interval_at(9), Duration::minutes(5));
+        assert!("[1m] then sometimes".to_duration_sequence().is_err());
+        assert!("[1m] then every".to_duration_sequence().is_err());
+    }
+
+    #[test]
+    fn test_if_the_elements_beyond_the_limits_are_told_apart() {
+        let sequence = "[1m, 5m, 30m, 2d] then every 1h".to_duration_sequence().unwrap();
+        let violations = sequence.validate_against(Some(Duration::minutes(30)), Some(3)).unwrap_err();
+        let messages: Vec<String> = violations.iter().map(ToString::to_string).collect();
+        assert_eq!(messages, vec![
+            "element 4 (2d) exceeds maxInterval 30m",
+            "the tail (every 1h) exceeds maxInterval 30m",
+            "the sequence has 4 elements, more than the limit of 3",
+        ]);
+        assert_eq!(violations[0], SequenceViolation::IntervalTooLong { position: 4, interval: Duration::days(2), max_interval: Duration::minutes(30) });
+        assert!(sequence.validate_against(Some(Duration::days(2)), Some(4)).is_ok());
+        assert!(sequence.validate_against(None, None).is_ok());
+        let doubling = "[1m] then doubling up to 1d".to_duration_sequence().unwrap();
+        assert_eq!(doubling.validate_against(Some(Duration::hours(1)), None).unwrap_err().len(), 1);
+    }
+
     #[test]
     #[should_panic]
     fn test_if_string_to_duration_sequence_panics_at_invalid_missing_comma() {
@@ -209,4 +835,66 @@ mod tests {
         "5m, 5m, 1h, 12h, 36h, 1d, 1d, 1d, 3d".to_duration_sequence().unwrap(); // should panic (missing '[' ']')
     }
 
-}
\ No newline at end of file
+    /// Build a TZif version 2 file without transitions and with the POSIX rule as footer
+    fn tzif_with_rule(rule: &str, offset: i32) -> Vec<u8> {
+        let header = |version: u8| {
+            let mut header = b"TZif".to_vec();
+            header.push(version);
+            header.extend([0; 15]);
+            // isutcnt, isstdcnt, leapcnt, timecnt, typecnt and charcnt
+            for count in [0u32, 0, 0, 0, 1, 4] {
+                header.extend(count.to_be_bytes());
+            }
+            header
+        };
+        let mut bytes = Vec::new();
+        for _ in 0..2 {
+            bytes.extend(header(b'2'));
+            bytes.extend(offset.to_be_bytes());
+            bytes.extend([0, 0]);
+            bytes.extend(b"STD\0");
+        }
+        bytes.extend(format!("\n{}\n", rule).into_bytes());
+        bytes
+    }
+
+    #[test]
+    fn test_if_timezones_follow_their_daylight_saving_time_rules() {
+        let berlin = TimeZone::from_tzif("Europe/Berlin", &tzif_with_rule("CET-1CEST,M3.5.0,M10.5.0/3", 3600)).unwrap();
+        let at = |value: &str| parse_rfc3339(value).unwrap();
+        let local = |date: &str, time: &str| PrimitiveDateTime::new(
+            Date::parse(date, &time::format_description::well_known::Iso8601::DEFAULT).unwrap(),
+            Time::from_hms(time[..2].parse().unwrap(), time[3..].parse().unwrap(), 0).unwrap(),
+        );
+        assert_eq!(berlin.offset_at(at("2024-01-15T12:00:00Z")).whole_hours(), 1);
+        assert_eq!(berlin.offset_at(at("2024-07-15T12:00:00Z")).whole_hours(), 2);
+        // the clocks moved forward at 01:00 UTC of the last sunday of march
+        assert_eq!(berlin.offset_at(at("2024-03-31T00:59:59Z")).whole_hours(), 1);
+        assert_eq!(berlin.offset_at(at("2024-03-31T01:00:00Z")).whole_hours(), 2);
+
+        // 02:30 was skipped and 02:30 of october happened twice
+        assert_eq!(berlin.from_local(local("2024-03-31", "02:30")), at("2024-03-31T01:30:00Z"));
+        assert_eq!(berlin.from_local(local("2024-10-27", "02:30")), at("2024-10-27T00:30:00Z"));
+        assert_eq!(berlin.from_local(local("2024-10-27", "12:00")), at("2024-10-27T11:00:00Z"));
+        // the day of the change has 23 hours
+        assert_eq!(berlin.add_days(at("2024-03-30T11:00:00Z"), 1) - at("2024-03-30T11:00:00Z"), Duration::hours(23));
+
+        let opening = berlin.next_occurrence(at("2024-03-29T18:00:00Z"), Time::from_hms(8, 0, 0).unwrap(), |day| day == Weekday::Monday);
+        assert_eq!(opening, Some(at("2024-04-01T06:00:00Z")));
+        assert_eq!(berlin.next_occurrence(at("2024-03-29T18:00:00Z"), Time::MIDNIGHT, |_| false), None);
+
+        // the southern hemisphere, with the daylight saving time across the new year
+        let sydney = TimeZone::from_tzif("Australia/Sydney", &tzif_with_rule("AEST-10AEDT,M10.1.0,M4.1.0/3", 36000)).unwrap();
+        assert_eq!(sydney.offset_at(at("2024-01-15T00:00:00Z")).whole_hours(), 11);
+        assert_eq!(sydney.offset_at(at("2024-07-15T00:00:00Z")).whole_hours(), 10);
+        let sao_paulo = TimeZone::from_tzif("America/Sao_Paulo", &tzif_with_rule("<-03>3", -10800)).unwrap();
+        assert_eq!(sao_paulo.offset_at(at("2024-01-15T00:00:00Z")).whole_hours(), -3);
+        assert_eq!(sao_paulo.name(), "America/Sao_Paulo");
+
+        assert_eq!(TimeZone::parse("utc").unwrap(), TimeZone::UTC);
+        assert_eq!(TimeZone::parse("-03:30").unwrap().name(), "-03:30");
+        assert_eq!(TimeZone::parse("../etc/passwd"), Err(TimeZoneError::Unknown(String::from("../etc/passwd"))));
+        assert!(matches!(TimeZone::from_tzif("Broken", b"TZif2"), Err(TimeZoneError::InvalidFile(..))));
+        assert_eq!(PosixRule::parse("EST5EDT,M3.2.0,M11.1.0").unwrap().daylight.unwrap().offset.whole_hours(), -4);
+    }
+}
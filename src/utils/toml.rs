@@ -0,0 +1,340 @@
+use std::collections::{BTreeMap, HashSet};
+
+use thiserror::Error;
+
+use super::json::JsonValue;
+
+/// Why a TOML document could not be read, with the line (from 1) where it happened
+#[derive(Debug, Error, PartialEq)]
+#[error("Invalid TOML at line {line}: {reason}")]
+pub struct TomlError {
+    pub line: usize,
+    pub reason: String,
+}
+
+/// Parse a TOML document with tables, dotted and quoted keys, basic and literal strings, integers,
+/// floats, booleans, arrays and inline tables into an object. Arrays of tables, multi-line strings
+/// and dates are not supported
+pub fn parse_toml(input: &str) -> Result<JsonValue, TomlError> {
+    let mut parser = TomlParser { chars: input.chars().collect(), position: 0, line: 1 };
+    let mut root = BTreeMap::new();
+    let mut table: Vec<String> = Vec::new();
+    let mut headers: HashSet<Vec<String>> = HashSet::new();
+    loop {
+        parser.skip_blank(true);
+        match parser.peek() {
+            None => break,
+            Some('[') => {
+                parser.position += 1;
+                if parser.peek() == Some('[') {
+                    return Err(parser.error("arrays of tables are not supported"));
+                }
+                let path = parser.parse_key()?;
+                parser.expect(']')?;
+                if !headers.insert(path.clone()) {
+                    return Err(parser.error(&format!("the table {} is already defined", path.join("."))));
+                }
+                table_at(&mut root, &path).map_err(|reason| parser.error(&reason))?;
+                table = path;
+            }
+            Some(_) => {
+                let key = parser.parse_key()?;
+                parser.expect('=')?;
+                let value = parser.parse_value()?;
+                let (name, parents) = key.split_last().expect("a key has at least one part");
+                let path: Vec<String> = table.iter().chain(parents).cloned().collect();
+                let parent = table_at(&mut root, &path).map_err(|reason| parser.error(&reason))?;
+                if parent.insert(name.clone(), value).is_some() {
+                    return Err(parser.error(&format!("the key {} is already defined", key.join("."))));
+                }
+            }
+        }
+        parser.end_of_line()?;
+    }
+    Ok(JsonValue::Object(root))
+}
+
+/// Return the table at the path, creating the tables that do not exist yet
+fn table_at<'a>(root: &'a mut BTreeMap<String, JsonValue>, path: &[String]) -> Result<&'a mut BTreeMap<String, JsonValue>, String> {
+    let mut table = root;
+    for key in path {
+        let value = table.entry(key.clone()).or_insert_with(JsonValue::object);
+        table = match value {
+            JsonValue::Object(map) => map,
+            _ => return Err(format!("the key {} is already defined as a value", key)),
+        };
+    }
+    Ok(table)
+}
+
+fn is_bare_key_char(c: char) -> bool {
+    c.is_ascii_alphanumeric() || c == '_' || c == '-'
+}
+
+struct TomlParser {
+    chars: Vec<char>,
+    position: usize,
+    line: usize,
+}
+
+impl TomlParser {
+    fn error(&self, reason: &str) -> TomlError {
+        TomlError { line: self.line, reason: reason.to_string() }
+    }
+
+    fn peek(&self) -> Option<char> {
+        self.chars.get(self.position).copied()
+    }
+
+    fn next(&mut self) -> Option<char> {
+        let c = self.peek()?;
+        self.position += 1;
+        if c == '\n' {
+            self.line += 1;
+        }
+        Some(c)
+    }
+
+    /// Skip the spaces and the comments, and the line breaks too when `newlines`
+    fn skip_blank(&mut self, newlines: bool) {
+        while let Some(c) = self.peek() {
+            match c {
+                ' ' | '\t' | '\r' => self.position += 1,
+                '\n' if newlines => {
+                    self.next();
+                }
+                '#' => {
+                    while self.peek().is_some_and(|c| c != '\n') {
+                        self.position += 1;
+                    }
+                }
+                _ => break,
+            }
+        }
+    }
+
+    fn expect(&mut self, expected: char) -> Result<(), TomlError> {
+        self.skip_blank(false);
+        match self.peek() {
+            Some(c) if c == expected => {
+                self.next();
+                Ok(())
+            }
+            Some('\n') => Err(self.error(&format!("expected '{}' but the line ended", expected))),
+            Some(c) => Err(self.error(&format!("expected '{}' but found '{}'", expected, c))),
+            None => Err(self.error(&format!("expected '{}' but the document ended", expected))),
+        }
+    }
+
+    /// Require that nothing but a comment follows on the line
+    fn end_of_line(&mut self) -> Result<(), TomlError> {
+        self.skip_blank(false);
+        match self.next() {
+            None | Some('\n') => Ok(()),
+            Some(c) => Err(self.error(&format!("unexpected '{}' after the value", c))),
+        }
+    }
+
+    /// Parse a key, like `a`, `a.b` or `a."b.c"`, into its parts
+    fn parse_key(&mut self) -> Result<Vec<String>, TomlError> {
+        let mut parts = Vec::new();
+        loop {
+            self.skip_blank(false);
+            let part = match self.peek() {
+                Some(quote @ ('"' | '\'')) => {
+                    self.position += 1;
+                    self.parse_string(quote)?
+                }
+                Some(c) if is_bare_key_char(c) => {
+                    let start = self.position;
+                    while self.peek().is_some_and(is_bare_key_char) {
+                        self.position += 1;
+                    }
+                    self.chars[start..self.position].iter().collect()
+                }
+                _ => return Err(self.error("expected a key")),
+            };
+            parts.push(part);
+            self.skip_blank(false);
+            if self.peek() != Some('.') {
+                return Ok(parts);
+            }
+            self.position += 1;
+        }
+    }
+
+    fn parse_value(&mut self) -> Result<JsonValue, TomlError> {
+        self.skip_blank(false);
+        match self.peek() {
+            Some(quote @ ('"' | '\'')) => {
+                self.position += 1;
+                if self.chars[self.position..].starts_with(&[quote, quote]) {
+                    return Err(self.error("multi-line strings are not supported"));
+                }
+                self.parse_string(quote).map(JsonValue::String)
+            }
+            Some('[') => {
+                self.position += 1;
+                self.parse_array()
+            }
+            Some('{') => {
+                self.position += 1;
+                self.parse_inline_table()
+            }
+            None | Some('\n' | '#') => Err(self.error("expected a value")),
+            Some(_) => self.parse_literal(),
+        }
+    }
+
+    /// Parse the rest of a string opened by the quote. Only the double quoted strings have escapes
+    fn parse_string(&mut self, quote: char) -> Result<String, TomlError> {
+        let mut value = String::new();
+        loop {
+            if matches!(self.peek(), None | Some('\n')) {
+                return Err(self.error("the string is not closed"));
+            }
+            match self.next() {
+                Some(c) if c == quote => return Ok(value),
+                Some('\\') if quote == '"' => {
+                    let escaped = match self.next() {
+                        Some('"') => '"',
+                        Some('\\') => '\\',
+                        Some('n') => '\n',
+                        Some('t') => '\t',
+                        Some('r') => '\r',
+                        Some('b') => '\u{8}',
+                        Some('f') => '\u{c}',
+                        Some(size @ ('u' | 'U')) => {
+                            let digits = if size == 'u' { 4 } else { 8 };
+                            let hex: String = (0..digits).filter_map(|_| self.next()).collect();
+                            u32::from_str_radix(&hex, 16).ok().and_then(char::from_u32)
+                                .ok_or_else(|| self.error(&format!("\\{}{} is not a valid unicode escape", size, hex)))?
+                        }
+                        _ => return Err(self.error("invalid escape sequence")),
+                    };
+                    value.push(escaped);
+                }
+                Some(c) => value.push(c),
+                None => unreachable!("the end of the document was checked"),
+            }
+        }
+    }
+
+    fn parse_array(&mut self) -> Result<JsonValue, TomlError> {
+        let mut values = Vec::new();
+        loop {
+            self.skip_blank(true);
+            if self.peek() == Some(']') {
+                self.position += 1;
+                return Ok(JsonValue::Array(values));
+            }
+            values.push(self.parse_value()?);
+            self.skip_blank(true);
+            match self.next() {
+                Some(',') => {}
+                Some(']') => return Ok(JsonValue::Array(values)),
+                _ => return Err(self.error("expected ',' or ']' in the array")),
+            }
+        }
+    }
+
+    fn parse_inline_table(&mut self) -> Result<JsonValue, TomlError> {
+        let mut table = BTreeMap::new();
+        self.skip_blank(false);
+        if self.peek() == Some('}') {
+            self.position += 1;
+            return Ok(JsonValue::Object(table));
+        }
+        loop {
+            let key = self.parse_key()?;
+            self.expect('=')?;
+            let value = self.parse_value()?;
+            let (name, parents) = key.split_last().expect("a key has at least one part");
+            let parent = table_at(&mut table, parents).map_err(|reason| self.error(&reason))?;
+            if parent.insert(name.clone(), value).is_some() {
+                return Err(self.error(&format!("the key {} is already defined", key.join("."))));
+            }
+            self.skip_blank(false);
+            match self.next() {
+                Some(',') => {}
+                Some('}') => return Ok(JsonValue::Object(table)),
+                _ => return Err(self.error("expected ',' or '}' in the inline table")),
+            }
+        }
+    }
+
+    /// Parse a boolean, an integer or a float
+    fn parse_literal(&mut self) -> Result<JsonValue, TomlError> {
+        let start = self.position;
+        while self.peek().is_some_and(|c| !matches!(c, ',' | ']' | '}' | '#' | '\n' | ' ' | '\t' | '\r')) {
+            self.position += 1;
+        }
+        let literal: String = self.chars[start..self.position].iter().collect();
+        match literal.as_str() {
+            "true" => return Ok(JsonValue::Bool(true)),
+            "false" => return Ok(JsonValue::Bool(false)),
+            _ => {}
+        }
+        let digits = literal.replace('_', "");
+        let number = match digits.strip_prefix("0x") {
+            Some(hex) => i64::from_str_radix(hex, 16).ok().map(|number| number as f64),
+            None if digits.starts_with(|c: char| c.is_ascii_digit() || c == '+' || c == '-') => digits.parse::<f64>().ok().filter(|number| number.is_finite()),
+            None => None,
+        };
+        number.map(JsonValue::Number).ok_or_else(|| self.error(&format!("{} is not a supported value, strings should be quoted", literal)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_if_toml_document_is_parsed() {
+        let document = r#"
+# the configurations of the node
+title = "angler" # the name
+
+[cluster]
+authKey = 'abcd\1234'
+roles = ["messageProcessor", "storage"]
+storage.port = 2_462
+
+[net.client."auth"]
+provider = "apiKeys"
+escaped = "tab\there é"
+limits = { maxConnections = 64, tls.enabled = true }
+ratios = [
+    0.5,
+    -1e3, # a comment inside the array
+]
+"#;
+        let value = parse_toml(document).unwrap();
+        assert_eq!(value.get("title").and_then(JsonValue::as_str), Some("angler"));
+        assert_eq!(value.pointer("cluster.authKey").and_then(JsonValue::as_str), Some("abcd\\1234"));
+        assert_eq!(value.pointer("cluster.roles").unwrap(), &JsonValue::from(vec!["messageProcessor", "storage"]));
+        assert_eq!(value.pointer("cluster.storage.port").and_then(JsonValue::as_u64), Some(2462));
+        let auth = value.pointer("net.client").and_then(|client| client.get("auth")).unwrap();
+        assert_eq!(auth.get("escaped").and_then(JsonValue::as_str), Some("tab\there é"));
+        assert_eq!(auth.pointer("limits.maxConnections").and_then(JsonValue::as_u64), Some(64));
+        assert_eq!(auth.pointer("limits.tls.enabled").and_then(JsonValue::as_bool), Some(true));
+        assert_eq!(auth.get("ratios").unwrap(), &JsonValue::Array(vec![JsonValue::Number(0.5), JsonValue::Number(-1000.0)]));
+        assert_eq!(parse_toml("").unwrap(), JsonValue::object());
+    }
+
+    #[test]
+    fn test_if_invalid_toml_is_rejected() {
+        assert_eq!(parse_toml("a = 1\nb = \n").unwrap_err().line, 2);
+        assert_eq!(parse_toml("a = 1\na = 2\n").unwrap_err().reason, "the key a is already defined");
+        assert!(parse_toml("[a]\n[a]\n").is_err());
+        assert!(parse_toml("a = 1\n[a]\n").is_err());
+        assert!(parse_toml("[[a]]\n").is_err());
+        assert!(parse_toml("a = 1 2\n").is_err());
+        assert!(parse_toml("a = [1, 2\n").is_err());
+        assert_eq!(parse_toml("a = 1\na = \"b\n").unwrap_err().line, 2);
+        assert_eq!(parse_toml("[a\nb = 1").unwrap_err().line, 1);
+        assert!(parse_toml("a = b\n").is_err());
+        assert!(parse_toml("a = 1979-05-27\n").is_err());
+        assert!(parse_toml("a = \"\"\"b\"\"\"\n").is_err());
+    }
+}
@@ -0,0 +1,484 @@
+use std::collections::BTreeMap;
+
+use thiserror::Error;
+
+use super::json::JsonValue;
+
+/// Why a YAML document could not be read, with the line (from 1) where it happened
+#[derive(Debug, Error, PartialEq)]
+#[error("Invalid YAML at line {line}: {reason}")]
+pub struct YamlError {
+    pub line: usize,
+    pub reason: String,
+}
+
+/// The characters that can not start a plain (unquoted) scalar
+const INDICATORS: &str = "-?:,[]{}#&*!|>'\"%@`";
+
+/// Serialize the value as a block style YAML document, quoting the strings that would otherwise
+/// be read as another value
+pub fn to_yaml(value: &JsonValue) -> String {
+    let mut output = String::new();
+    match value {
+        JsonValue::Object(map) if !map.is_empty() => write_block(&mut output, value, 0),
+        JsonValue::Array(values) if !values.is_empty() => write_block(&mut output, value, 0),
+        scalar => {
+            output.push_str(&scalar_to_yaml(scalar));
+            output.push('\n');
+        }
+    }
+    output
+}
+
+fn write_block(output: &mut String, value: &JsonValue, indent: usize) {
+    let pad = " ".repeat(indent);
+    match value {
+        JsonValue::Object(map) => {
+            for (key, value) in map {
+                output.push_str(&format!("{}{}:", pad, string_to_yaml(key)));
+                write_nested(output, value, indent + 2);
+            }
+        }
+        JsonValue::Array(values) => {
+            for value in values {
+                match value {
+                    JsonValue::Object(map) if !map.is_empty() => {
+                        // the first entry of the object goes on the line of the dash
+                        let mut item = String::new();
+                        write_block(&mut item, value, indent + 2);
+                        output.push_str(&format!("{}- {}", pad, &item[indent + 2..]));
+                    }
+                    _ => {
+                        output.push_str(&format!("{}-", pad));
+                        write_nested(output, value, indent + 2);
+                    }
+                }
+            }
+        }
+        scalar => output.push_str(&format!("{}{}\n", pad, scalar_to_yaml(scalar))),
+    }
+}
+
+/// Write the value of a key or item, on the same line when it is a scalar
+fn write_nested(output: &mut String, value: &JsonValue, indent: usize) {
+    match value {
+        JsonValue::Object(map) if !map.is_empty() => {
+            output.push('\n');
+            write_block(output, value, indent);
+        }
+        JsonValue::Array(values) if !values.is_empty() => {
+            output.push('\n');
+            write_block(output, value, indent);
+        }
+        scalar => output.push_str(&format!(" {}\n", scalar_to_yaml(scalar))),
+    }
+}
+
+fn scalar_to_yaml(value: &JsonValue) -> String {
+    match value {
+        JsonValue::String(s) => string_to_yaml(s),
+        JsonValue::Object(_) => String::from("{}"),
+        JsonValue::Array(_) => String::from("[]"),
+        other => other.to_string(),
+    }
+}
+
+/// Write the string plain when it is read back as the same string, or double quoted, whose
+/// escapes are the ones of JSON
+fn string_to_yaml(s: &str) -> String {
+    let is_plain = !s.is_empty()
+        && s.trim() == s
+        && !s.starts_with(|c| INDICATORS.contains(c))
+        && !s.ends_with(':')
+        && !s.contains(": ")
+        && !s.contains(" #")
+        && !s.chars().any(char::is_control)
+        && parse_plain_scalar(s) == JsonValue::String(s.to_string());
+    if is_plain { s.to_string() } else { JsonValue::String(s.to_string()).to_string() }
+}
+
+/// A line with content, without its comment
+#[derive(Clone, Copy)]
+struct Line<'a> {
+    number: usize,
+    indent: usize,
+    content: &'a str,
+}
+
+/// Parse a YAML document with block mappings and sequences, flow collections, plain, single and
+/// double quoted scalars and comments. Anchors, tags, block scalars (`|` and `>`) and documents
+/// with several `---` are not supported
+pub fn parse_yaml(input: &str) -> Result<JsonValue, YamlError> {
+    let mut lines = Vec::new();
+    for (index, raw) in input.lines().enumerate() {
+        let number = index + 1;
+        let content = strip_comment(raw).trim_end();
+        let trimmed = content.trim_start();
+        if trimmed.is_empty() || (lines.is_empty() && trimmed == "---") {
+            continue;
+        }
+        let indentation = &content[..content.len() - trimmed.len()];
+        if indentation.contains('\t') {
+            return Err(YamlError { line: number, reason: String::from("tabs can not be used to indent") });
+        }
+        if trimmed == "---" || trimmed == "..." {
+            return Err(YamlError { line: number, reason: String::from("only one document is supported") });
+        }
+        lines.push(Line { number, indent: indentation.len(), content: trimmed });
+    }
+
+    let mut parser = YamlParser { lines, position: 0 };
+    let Some(first) = parser.lines.first() else {
+        return Ok(JsonValue::Null);
+    };
+    let value = parser.parse_block(first.indent)?;
+    match parser.lines.get(parser.position) {
+        Some(line) => Err(YamlError { line: line.number, reason: String::from("unexpected indentation") }),
+        None => Ok(value),
+    }
+}
+
+/// Remove the comment of the line, that starts at a '#' after a space and outside the quotes
+fn strip_comment(line: &str) -> &str {
+    let mut quote = None;
+    let mut escaped = false;
+    let mut previous = ' ';
+    for (index, c) in line.char_indices() {
+        match quote {
+            // the backslash only escapes inside double quotes
+            Some('"') if escaped => escaped = false,
+            Some('"') if c == '\\' => escaped = true,
+            Some(q) if c == q => quote = None,
+            Some(_) => {}
+            None if c == '#' && previous.is_whitespace() => return &line[..index],
+            None if (c == '"' || c == '\'') && (previous.is_whitespace() || "[{,:-".contains(previous)) => quote = Some(c),
+            None => {}
+        }
+        previous = c;
+    }
+    line
+}
+
+struct YamlParser<'a> {
+    lines: Vec<Line<'a>>,
+    position: usize,
+}
+
+impl<'a> YamlParser<'a> {
+    fn error(&self, reason: &str) -> YamlError {
+        let line = self.lines.get(self.position).or(self.lines.last()).map_or(1, |line| line.number);
+        YamlError { line, reason: reason.to_string() }
+    }
+
+    fn current(&self) -> Option<Line<'a>> {
+        self.lines.get(self.position).copied()
+    }
+
+    /// Parse the mapping or the sequence whose lines have the indentation
+    fn parse_block(&mut self, indent: usize) -> Result<JsonValue, YamlError> {
+        let line = self.current().ok_or_else(|| self.error("expected a value"))?;
+        if is_sequence_item(line.content) {
+            self.parse_sequence(indent)
+        } else if split_key(line.content).is_some() {
+            self.parse_mapping(indent)
+        } else {
+            let value = parse_scalar(line.content).map_err(|reason| self.error(&reason))?;
+            self.position += 1;
+            Ok(value)
+        }
+    }
+
+    fn parse_sequence(&mut self, indent: usize) -> Result<JsonValue, YamlError> {
+        let mut values = Vec::new();
+        while let Some(line) = self.current() {
+            if line.indent != indent || !is_sequence_item(line.content) {
+                break;
+            }
+            let rest = line.content[1..].trim_start();
+            if rest.is_empty() {
+                self.position += 1;
+                values.push(self.parse_nested(indent, false)?);
+            } else {
+                // the item continues at the column after the dash, like `- key: value`
+                let offset = line.content.len() - rest.len();
+                let (number, content) = (line.number, rest);
+                self.lines[self.position] = Line { number, indent: indent + offset, content };
+                values.push(self.parse_block(indent + offset)?);
+            }
+        }
+        Ok(JsonValue::Array(values))
+    }
+
+    fn parse_mapping(&mut self, indent: usize) -> Result<JsonValue, YamlError> {
+        let mut map = BTreeMap::new();
+        while let Some(line) = self.current() {
+            if line.indent != indent || is_sequence_item(line.content) {
+                break;
+            }
+            let Some((key, rest)) = split_key(line.content) else {
+                return Err(self.error("expected a key followed by ':'"));
+            };
+            let key = match parse_scalar(key).map_err(|reason| self.error(&reason))? {
+                JsonValue::String(key) => key,
+                JsonValue::Null => return Err(self.error("keys can not be null")),
+                other => other.to_string(),
+            };
+            if map.contains_key(&key) {
+                return Err(self.error(&format!("the key {} is repeated", key)));
+            }
+            let value = if rest.is_empty() {
+                self.position += 1;
+                self.parse_nested(indent, true)?
+            } else {
+                let value = parse_scalar(rest).map_err(|reason| self.error(&reason))?;
+                self.position += 1;
+                value
+            };
+            map.insert(key, value);
+        }
+        Ok(JsonValue::Object(map))
+    }
+
+    /// Parse the value of a key or item written in the next lines, that is null when they are
+    /// not indented further. A sequence can be the value of a key at the indentation of the key
+    fn parse_nested(&mut self, indent: usize, sequence_at_same_indent: bool) -> Result<JsonValue, YamlError> {
+        match self.current() {
+            Some(next) if next.indent > indent => self.parse_block(next.indent),
+            Some(next) if sequence_at_same_indent && next.indent == indent && is_sequence_item(next.content) => self.parse_sequence(indent),
+            _ => Ok(JsonValue::Null),
+        }
+    }
+}
+
+fn is_sequence_item(content: &str) -> bool {
+    content == "-" || content.starts_with("- ")
+}
+
+/// Split `key: value` at the first ':' followed by a space or the end, outside quotes and flow
+/// collections
+fn split_key(content: &str) -> Option<(&str, &str)> {
+    if content.starts_with('[') || content.starts_with('{') {
+        return None;
+    }
+    let bytes = content.as_bytes();
+    let mut index = 0;
+    if let Some(&quote @ (b'"' | b'\'')) = bytes.first() {
+        index = closing_quote(content, quote as char)? + 1;
+    }
+    while index < bytes.len() {
+        if bytes[index] == b':' && bytes.get(index + 1).is_none_or(|next| *next == b' ') {
+            return Some((content[..index].trim_end(), content[index + 1..].trim_start()));
+        }
+        index += 1;
+    }
+    None
+}
+
+/// Return the index of the quote that closes the one at the start of the string
+fn closing_quote(s: &str, quote: char) -> Option<usize> {
+    let mut chars = s.char_indices().skip(1);
+    while let Some((index, c)) = chars.next() {
+        match c {
+            '\\' if quote == '"' => {
+                chars.next();
+            }
+            // two single quotes are an escaped single quote
+            '\'' if quote == '\'' && s[index + 1..].starts_with('\'') => {
+                chars.next();
+            }
+            c if c == quote => return Some(index),
+            _ => {}
+        }
+    }
+    None
+}
+
+/// Parse a scalar or a flow collection written on a single line
+fn parse_scalar(text: &str) -> Result<JsonValue, String> {
+    let mut flow = FlowParser { text, position: 0 };
+    let value = flow.parse_value()?;
+    flow.skip_spaces();
+    if flow.position < text.len() {
+        return Err(format!("unexpected '{}' after the value", &text[flow.position..]));
+    }
+    Ok(value)
+}
+
+/// Read the plain scalars: null, booleans, numbers and the other strings
+fn parse_plain_scalar(text: &str) -> JsonValue {
+    match text {
+        "" | "~" | "null" | "Null" | "NULL" => JsonValue::Null,
+        "true" | "True" | "TRUE" => JsonValue::Bool(true),
+        "false" | "False" | "FALSE" => JsonValue::Bool(false),
+        _ => {
+            let is_number = text.starts_with(|c: char| c.is_ascii_digit() || c == '-' || c == '+' || c == '.')
+                && text.chars().any(|c| c.is_ascii_digit())
+                && text.chars().all(|c| c.is_ascii_digit() || "+-.eE".contains(c));
+            match text.parse::<f64>() {
+                Ok(number) if is_number => JsonValue::Number(number),
+                _ => JsonValue::String(text.to_string()),
+            }
+        }
+    }
+}
+
+struct FlowParser<'a> {
+    text: &'a str,
+    position: usize,
+}
+
+impl<'a> FlowParser<'a> {
+    fn rest(&self) -> &'a str {
+        &self.text[self.position..]
+    }
+
+    fn skip_spaces(&mut self) {
+        self.position = self.text.len() - self.rest().trim_start().len();
+    }
+
+    /// Parse a value, that inside a flow collection ends at a ',' or at its closing bracket
+    fn parse_value(&mut self) -> Result<JsonValue, String> {
+        self.parse_value_in(None)
+    }
+
+    fn parse_value_in(&mut self, collection: Option<char>) -> Result<JsonValue, String> {
+        self.skip_spaces();
+        let rest = self.rest();
+        match rest.chars().next() {
+            Some('[') => self.parse_collection('[', ']'),
+            Some('{') => self.parse_collection('{', '}'),
+            Some('"') => {
+                let end = closing_quote(rest, '"').ok_or("the double quoted string is not closed")?;
+                let value = JsonValue::parse(&rest[..=end]).map_err(|err| format!("invalid double quoted string: {}", err))?;
+                self.position += end + 1;
+                Ok(value)
+            }
+            Some('\'') => {
+                let end = closing_quote(rest, '\'').ok_or("the single quoted string is not closed")?;
+                let value = rest[1..end].replace("''", "'");
+                self.position += end + 1;
+                Ok(JsonValue::String(value))
+            }
+            Some(c) if c != '-' && c != '?' && c != ':' && INDICATORS.contains(c) => Err(format!("'{}' is not supported at the start of a value", c)),
+            _ => {
+                let end = match collection {
+                    // a ':' only ends the keys when it is followed by a space, so URLs are values
+                    Some(close) => rest.char_indices()
+                        .find(|&(index, c)| c == ',' || c == close || (c == ':' && rest[index + 1..].starts_with([' ', ',', close])))
+                        .map_or(rest.len(), |(index, _)| index),
+                    None => rest.len(),
+                };
+                let text = rest[..end].trim_end();
+                self.position += end;
+                Ok(parse_plain_scalar(text))
+            }
+        }
+    }
+
+    fn parse_collection(&mut self, open: char, close: char) -> Result<JsonValue, String> {
+        self.position += open.len_utf8();
+        let mut values = Vec::new();
+        let mut map = BTreeMap::new();
+        loop {
+            self.skip_spaces();
+            if self.rest().starts_with(close) {
+                self.position += 1;
+                break;
+            }
+            let value = self.parse_value_in(Some(close))?;
+            self.skip_spaces();
+            if open == '{' {
+                if !self.rest().starts_with(':') {
+                    return Err(String::from("expected ':' after the key of the flow mapping"));
+                }
+                self.position += 1;
+                let key = match value {
+                    JsonValue::String(key) => key,
+                    other => other.to_string(),
+                };
+                map.insert(key, self.parse_value_in(Some(close))?);
+                self.skip_spaces();
+            } else {
+                values.push(value);
+            }
+            match self.rest().chars().next() {
+                Some(',') => self.position += 1,
+                Some(c) if c == close => {}
+                Some(c) => return Err(format!("unexpected '{}' in the flow collection", c)),
+                None => return Err(format!("the flow collection is not closed with '{}'", close)),
+            }
+        }
+        Ok(if open == '{' { JsonValue::Object(map) } else { JsonValue::Array(values) })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_if_yaml_document_is_parsed() {
+        let document = r#"
+# the routing of the shop
+---
+version: 1
+destinations:
+- id: r1
+  url: http://localhost:8080/hooks # the local one
+  headers:
+    Authorization: "Basic dXNlcjpwYXNz"
+  retryOn: 5xx,timeout
+  tags: [a, 'b''c', "d#e"]
+  budget: {ratio: 0.2, minPerMinute: 10, url: http://b:80}
+-
+  id: r2
+  enabled: false
+  nothing:
+empty: []
+"#;
+        let value = parse_yaml(document).unwrap();
+        assert_eq!(value.get("version").and_then(JsonValue::as_u64), Some(1));
+        let destinations = value.get("destinations").and_then(JsonValue::as_array).unwrap();
+        assert_eq!(destinations.len(), 2);
+        assert_eq!(destinations[0].get("url").and_then(JsonValue::as_str), Some("http://localhost:8080/hooks"));
+        assert_eq!(destinations[0].pointer("headers.Authorization").and_then(JsonValue::as_str), Some("Basic dXNlcjpwYXNz"));
+        assert_eq!(destinations[0].get("retryOn").and_then(JsonValue::as_str), Some("5xx,timeout"));
+        assert_eq!(destinations[0].get("tags").unwrap(), &JsonValue::from(vec!["a", "b'c", "d#e"]));
+        assert_eq!(destinations[0].pointer("budget.ratio").and_then(JsonValue::as_f64), Some(0.2));
+        assert_eq!(destinations[0].pointer("budget.url").and_then(JsonValue::as_str), Some("http://b:80"));
+        assert_eq!(destinations[1].get("enabled").and_then(JsonValue::as_bool), Some(false));
+        assert!(destinations[1].get("nothing").unwrap().is_null());
+        assert_eq!(value.get("empty").and_then(JsonValue::as_array).map(Vec::len), Some(0));
+
+        let keys_with_sequences = parse_yaml("ids:\n- a\n- b\nnext: c\n").unwrap();
+        assert_eq!(keys_with_sequences.get("ids").unwrap(), &JsonValue::from(vec!["a", "b"]));
+        assert_eq!(parse_yaml("").unwrap(), JsonValue::Null);
+    }
+
+    #[test]
+    fn test_if_serialization_round_trips() {
+        let value = JsonValue::object()
+            .with("id", "order: created")
+            .with("count", 3u16)
+            .with("flags", vec![JsonValue::from("true"), JsonValue::from(""), JsonValue::from("12"), JsonValue::from(" a")])
+            .with("nested", vec![JsonValue::object().with("a", 1u8).with("b", vec!["x"]), JsonValue::from("- dash")])
+            .with("empty", JsonValue::object())
+            .with("next", Option::<String>::None)
+            .with("text", "line\nbreak # not a comment");
+        let yaml = to_yaml(&value);
+        assert!(yaml.starts_with("count: 3\n"), "{}", yaml);
+        assert!(yaml.contains("\n  - a: 1\n    b:\n      - x\n"), "{}", yaml);
+        assert_eq!(parse_yaml(&yaml).unwrap(), value, "{}", yaml);
+    }
+
+    #[test]
+    fn test_if_invalid_yaml_is_rejected() {
+        assert_eq!(parse_yaml("a: 1\n  b: 2\n").unwrap_err().line, 2);
+        assert!(parse_yaml("a: 1\na: 2\n").is_err());
+        assert!(parse_yaml("a:\n\t- b\n").is_err());
+        assert!(parse_yaml("a: [1, 2\n").is_err());
+        assert!(parse_yaml("a: \"b\n").is_err());
+        assert!(parse_yaml("a: &anchor b\n").is_err());
+        assert!(parse_yaml("a: 1\n---\nb: 2\n").is_err());
+    }
+}
@@ -0,0 +1,70 @@
+#![cfg(feature = "chaos")]
+
+use std::{thread, time::{Duration, Instant}};
+
+use angler::{
+    msgproc::{
+        message::{AttemptOutcome, DeliveryError, DeliveryErrorClass, Message, MessageStatus},
+        retry::RetryPolicy,
+    },
+    net::http::{send_request, HttpRequest, HttpUrl},
+    testutil::mock_destination::MockDestinationServer,
+    utils::{json::JsonValue, time::DurationSequence},
+    Angler,
+};
+
+fn admin_request(angler: &Angler, method: &str, body: &str) -> (u16, JsonValue) {
+    let url = HttpUrl::parse(&format!("http://{}/admin/chaos", angler.admin_addr().unwrap())).unwrap();
+    let mut request = HttpRequest::new(method, "/admin/chaos");
+    request.headers.set("Content-Type", "application/json");
+    request.body = body.as_bytes().to_vec();
+    let response = send_request(&url, request, Duration::from_secs(5)).unwrap();
+    let json = if response.body.is_empty() { JsonValue::Null } else { JsonValue::parse_bytes(&response.body).unwrap() };
+    (response.status, json)
+}
+
+fn message(recipient_id: &str) -> Message {
+    let mut message = Message::new("a".to_string(), recipient_id.to_string(), "service".to_string(), "event".to_string(), b"{}".to_vec());
+    message.retry_policy = RetryPolicy {
+        interval: Some(DurationSequence::from_vec(vec![time::Duration::milliseconds(20)]).unwrap()),
+        max_attempts: 100,
+    };
+    message
+}
+
+#[test]
+fn test_if_injected_delivery_failures_are_retried_after_the_faults_are_removed() {
+    let destination = MockDestinationServer::start().unwrap();
+    let angler = Angler::builder().workers(2).admin_address("127.0.0.1:0").build().unwrap();
+    angler.register_destination("recipient", &destination.url("/hooks"));
+
+    let (status, settings) = admin_request(&angler, "PUT", r#"{"deliveryFailureRate": 1}"#);
+    assert_eq!(status, 200);
+    assert_eq!(settings.get("deliveryFailureRate").and_then(JsonValue::as_f64), Some(1.0));
+
+    let id = angler.publish_message(message("recipient")).unwrap();
+    let started_at = Instant::now();
+    while angler.attempts(&id).unwrap().len() < 3 {
+        assert!(started_at.elapsed() < Duration::from_secs(5), "the message was not retried in time");
+        thread::sleep(Duration::from_millis(5));
+    }
+    assert!(destination.requests_to("/hooks").is_empty());
+    assert_eq!(angler.attempts(&id).unwrap()[0].outcome, AttemptOutcome::Failed(DeliveryError::new(DeliveryErrorClass::Other, "injected delivery failure")));
+
+    assert_eq!(admin_request(&angler, "DELETE", "").0, 204);
+    assert!(angler.wait_for_status(&id, MessageStatus::Delivered, Duration::from_secs(5)).unwrap().is_some());
+    assert_eq!(destination.requests_to("/hooks").len(), 1);
+}
+
+#[test]
+fn test_if_injected_store_write_errors_reject_publishing() {
+    let angler = Angler::builder().workers(1).admin_address("127.0.0.1:0").build().unwrap();
+
+    assert_eq!(admin_request(&angler, "PUT", r#"{"storeWriteFailureRate": 1, "partitionedNodes": ["b1"]}"#).0, 200);
+    assert!(angler.publish_message(message("recipient")).is_err());
+    assert!(angler.faults().is_partitioned("b1"));
+
+    assert_eq!(admin_request(&angler, "PUT", r#"{"storeWriteFailureRate": 3}"#).0, 400);
+    assert_eq!(admin_request(&angler, "PUT", r#"{"storeWriteFailureRate": 0}"#).0, 200);
+    assert!(angler.publish_message(message("recipient")).is_ok());
+}
@@ -0,0 +1,437 @@
+use std::{collections::BTreeMap, sync::Arc, thread, time::{Duration, Instant}};
+
+use angler::{
+    db::{batch::BatchConfiguration, memory::MemoryStore, MessageStore},
+    msgproc::{
+        ack::{AckToken, ACK_TOKEN_HEADER},
+        canary::{Branch, BranchStats, CanaryRoute},
+        delivery::{Deliverer, HttpDeliverer},
+        destination::{BodyCompression, DeliveryMethod, Destination, DestinationRegistry, RedirectPolicy},
+        message::{AttemptOutcome, DeliveryError, DeliveryErrorClass, Message, MessageStatus},
+        processor::MessageProcessor,
+        retry::{RetryOn, RetryPolicy},
+    },
+    net::pool::ConnectionPool,
+    testutil::mock_destination::MockDestinationServer,
+    utils::{gzip, time::{parse_rfc3339, DurationSequence}},
+};
+
+fn start_processor(store: Arc<MemoryStore>, destinations: Arc<DestinationRegistry>) -> MessageProcessor {
+    let deliverer = Arc::new(HttpDeliverer::new(destinations, Duration::from_millis(500)));
+    let batch = BatchConfiguration { max_batch_size: 100, flush_interval: Duration::from_millis(5) };
+    MessageProcessor::start(4, store, batch, deliverer)
+}
+
+fn message(id: &str, recipient_id: &str, max_attempts: u16) -> Message {
+    let mut message = Message::new(id.to_string(), recipient_id.to_string(), "service".to_string(), "event".to_string(), b"{\"ok\":true}".to_vec());
+    message.retry_policy = RetryPolicy {
+        interval: Some(DurationSequence::from_vec(vec![time::Duration::milliseconds(20)]).unwrap()),
+        max_attempts,
+    };
+    message
+}
+
+fn wait_until_finished(processor: &MessageProcessor) {
+    let started_at = Instant::now();
+    while processor.stats().outstanding() > 0 {
+        assert!(started_at.elapsed() < Duration::from_secs(10), "messages were not processed in time");
+        thread::sleep(Duration::from_millis(5));
+    }
+    processor.flush().unwrap();
+}
+
+#[test]
+fn test_if_message_is_retried_until_destination_accepts_it() {
+    let server = MockDestinationServer::start().unwrap();
+    server.respond_with("/hooks", &[503, 500, 200]);
+    let destinations = Arc::new(DestinationRegistry::new());
+    destinations.register(Destination::new("recipient", &server.url("/hooks")));
+    let store = Arc::new(MemoryStore::new());
+    let processor = start_processor(store.clone(), destinations);
+
+    processor.publish(message("a", "recipient", 5)).unwrap();
+    wait_until_finished(&processor);
+
+    let requests = server.requests_to("/hooks");
+    assert_eq!(requests.len(), 3);
+    assert_eq!(requests[0].request.method, "POST");
+    assert_eq!(requests[0].request.body, b"{\"ok\":true}");
+
+    let attempts = store.get_attempts("a").unwrap();
+    assert_eq!(attempts[0].outcome, AttemptOutcome::Failed(DeliveryError::from_response(503, "HTTP 503")));
+    assert_eq!(attempts[2].outcome, AttemptOutcome::Delivered);
+    assert_eq!(store.get_message("a").unwrap().unwrap().status, MessageStatus::Delivered);
+}
+
+#[test]
+fn test_if_message_dies_when_destination_keeps_failing() {
+    let server = MockDestinationServer::start().unwrap();
+    server.respond_with("/hooks", &[500]);
+    let destinations = Arc::new(DestinationRegistry::new());
+    destinations.register(Destination::new("recipient", &server.url("/hooks")));
+    let store = Arc::new(MemoryStore::new());
+    let processor = start_processor(store.clone(), destinations);
+
+    processor.publish(message("a", "recipient", 2)).unwrap();
+    wait_until_finished(&processor);
+
+    assert_eq!(server.requests_to("/hooks").len(), 3);
+    assert_eq!(store.get_message("a").unwrap().unwrap().status, MessageStatus::Dead);
+}
+
+#[test]
+fn test_if_slow_destination_times_out() {
+    let server = MockDestinationServer::start().unwrap();
+    server.set_latency("/slow", Duration::from_secs(2));
+    let destinations = Arc::new(DestinationRegistry::new());
+    destinations.register(Destination::new("recipient", &server.url("/slow")));
+    let store = Arc::new(MemoryStore::new());
+    let processor = start_processor(store.clone(), destinations);
+
+    processor.publish(message("a", "recipient", 0)).unwrap();
+    wait_until_finished(&processor);
+
+    assert_eq!(store.get_message("a").unwrap().unwrap().status, MessageStatus::Dead);
+    assert!(matches!(&store.get_attempts("a").unwrap()[0].outcome, AttemptOutcome::Failed(_)));
+}
+
+#[test]
+fn test_if_destination_method_content_type_and_headers_are_used() {
+    let server = MockDestinationServer::start().unwrap();
+    let destinations = Arc::new(DestinationRegistry::new());
+    destinations.register(
+        Destination::new("legacy", &server.url("/legacy"))
+            .with_method(DeliveryMethod::Put)
+            .with_content_type("application/x-www-form-urlencoded")
+            .with_headers(BTreeMap::from([(String::from("X-Api-Key"), String::from("k1"))])),
+    );
+    let store = Arc::new(MemoryStore::new());
+    let processor = start_processor(store, destinations);
+
+    let mut form = message("a", "legacy", 1);
+    form.payload = b"order=1&status=paid".to_vec();
+    processor.publish(form).unwrap();
+    wait_until_finished(&processor);
+
+    let request = &server.requests_to("/legacy")[0].request;
+    assert_eq!(request.method, "PUT");
+    assert_eq!(request.headers.get("Content-Type"), Some("application/x-www-form-urlencoded"));
+    assert_eq!(request.headers.get("X-Api-Key"), Some("k1"));
+    assert_eq!(request.headers.get("X-Angler-Sequence"), Some("1"));
+    assert_eq!(request.headers.get("User-Agent"), Some(format!("Angler/{}", env!("CARGO_PKG_VERSION")).as_str()));
+    assert_eq!(request.body, b"order=1&status=paid");
+}
+
+#[test]
+fn test_if_attempts_tell_the_receiver_about_their_retries() {
+    let server = MockDestinationServer::start().unwrap();
+    server.respond_with("/hooks", &[503, 503, 200]);
+    let destinations = Arc::new(DestinationRegistry::new());
+    destinations.register(Destination::new("recipient", &server.url("/hooks")));
+    let processor = start_processor(Arc::new(MemoryStore::new()), destinations);
+
+    processor.publish(message("a", "recipient", 2)).unwrap();
+    wait_until_finished(&processor);
+
+    let requests = server.requests_to("/hooks");
+    assert_eq!(requests.len(), 3);
+    for (index, captured) in requests.iter().enumerate() {
+        let headers = &captured.request.headers;
+        assert_eq!(headers.get("X-Angler-Message-Id"), Some("a"));
+        assert_eq!(headers.get("X-Angler-Attempt"), Some((index + 1).to_string().as_str()));
+        assert_eq!(headers.get("X-Angler-Max-Attempts"), Some("3"));
+    }
+    assert!(requests[0].request.headers.get("X-Angler-Next-Retry-At").is_some_and(|at| parse_rfc3339(at).is_ok()));
+    // a failure of the last attempt kills the message
+    assert_eq!(requests[2].request.headers.get("X-Angler-Next-Retry-At"), None);
+}
+
+#[test]
+fn test_if_redirects_are_only_followed_when_the_policy_allows_them() {
+    let server = MockDestinationServer::start().unwrap();
+    let other_host = MockDestinationServer::start().unwrap();
+    server.redirect("/moved", 308, "/hooks");
+    server.redirect("/away", 302, &other_host.url("/hooks"));
+    server.redirect("/loop", 307, "/loop");
+
+    let destinations = Arc::new(DestinationRegistry::new());
+    destinations.register(Destination::new("none", &server.url("/moved")));
+    destinations.register(Destination::new("same", &server.url("/moved")).with_redirect_policy(RedirectPolicy::SameHost(3)));
+    destinations.register(Destination::new("away", &server.url("/away")).with_redirect_policy(RedirectPolicy::SameHost(3)));
+    destinations.register(Destination::new("limited", &server.url("/loop")).with_redirect_policy(RedirectPolicy::Limited(2)));
+    let store = Arc::new(MemoryStore::new());
+    let processor = start_processor(store.clone(), destinations);
+
+    for (id, recipient_id) in [("a", "none"), ("b", "same"), ("c", "away"), ("d", "limited")] {
+        processor.publish(message(id, recipient_id, 1)).unwrap();
+    }
+    wait_until_finished(&processor);
+
+    let outcome = |id: &str| store.get_attempts(id).unwrap()[0].outcome.clone();
+    assert_eq!(outcome("a"), AttemptOutcome::Failed(DeliveryError::from_response(308, "HTTP 308 redirect to /hooks was not followed")));
+    assert_eq!(outcome("b"), AttemptOutcome::Delivered);
+    assert_eq!(server.requests_to("/hooks")[0].request.body, b"{\"ok\":true}");
+    assert!(matches!(outcome("c"), AttemptOutcome::Failed(reason) if reason.message.starts_with("HTTP 302 redirect")));
+    assert!(other_host.requests().is_empty());
+    assert!(matches!(outcome("d"), AttemptOutcome::Failed(reason) if reason.message.starts_with("HTTP 307 redirect")));
+    // each attempt follows the two redirects allowed
+    assert_eq!(server.requests_to("/loop").len(), 3 * store.get_attempts("d").unwrap().len());
+}
+
+#[test]
+fn test_if_slow_requests_are_hedged() {
+    let server = MockDestinationServer::start().unwrap();
+    server.set_latency("/slow", Duration::from_millis(300));
+    let destinations = Arc::new(DestinationRegistry::new());
+    destinations.register(Destination::new("hedged", &server.url("/slow")).with_hedging(Duration::from_millis(50)));
+    destinations.register(Destination::new("fast", &server.url("/fast")).with_hedging(Duration::from_millis(200)));
+    let store = Arc::new(MemoryStore::new());
+    let processor = start_processor(store.clone(), destinations);
+
+    processor.publish(message("a", "hedged", 1)).unwrap();
+    processor.publish(message("b", "fast", 1)).unwrap();
+    wait_until_finished(&processor);
+
+    assert_eq!(store.get_attempts("a").unwrap().len(), 1);
+    assert_eq!(store.get_message("a").unwrap().unwrap().status, MessageStatus::Delivered);
+    assert!(server.wait_for_requests("/slow", 2, Duration::from_secs(5)));
+    let requests = server.requests_to("/slow");
+    // the hedged request was sent after the first one was waiting for a while
+    assert!(requests[1].received_at.duration_since(requests[0].received_at) >= Duration::from_millis(40));
+    assert_eq!(server.requests_to("/fast").len(), 1);
+}
+
+#[test]
+fn test_if_pinned_destinations_connect_to_their_address() {
+    let server = MockDestinationServer::start().unwrap();
+    let destinations = Arc::new(DestinationRegistry::new());
+    let url = format!("http://receiver.invalid:{}/hooks", server.local_addr().port());
+    destinations.register(Destination::new("pinned", &url).with_pinned_address(server.local_addr().ip()));
+    destinations.register(Destination::new("unpinned", &url));
+    let store = Arc::new(MemoryStore::new());
+    let processor = start_processor(store.clone(), destinations);
+
+    processor.publish(message("a", "pinned", 1)).unwrap();
+    processor.publish(message("b", "unpinned", 0)).unwrap();
+    wait_until_finished(&processor);
+
+    assert_eq!(store.get_message("a").unwrap().unwrap().status, MessageStatus::Delivered);
+    let host = format!("receiver.invalid:{}", server.local_addr().port());
+    assert_eq!(server.requests_to("/hooks")[0].request.headers.get("Host"), Some(host.as_str()));
+    assert!(matches!(&store.get_attempts("b").unwrap()[0].outcome, AttemptOutcome::Failed(reason) if reason.class == DeliveryErrorClass::Dns && reason.message.starts_with("failed to resolve receiver.invalid")));
+}
+
+#[test]
+fn test_if_only_the_failures_matching_retry_on_are_retried() {
+    let server = MockDestinationServer::start().unwrap();
+    server.respond_with("/bad-request", &[400, 200]);
+    server.respond_with("/not-found", &[404, 404, 200]);
+    let destinations = Arc::new(DestinationRegistry::new());
+    destinations.register(Destination::new("strict", &server.url("/bad-request")));
+    destinations.register(Destination::new("eventual", &server.url("/not-found")).with_retry_on(RetryOn::parse("5xx,404").unwrap()));
+    let store = Arc::new(MemoryStore::new());
+    let processor = start_processor(store.clone(), destinations).with_retry_on(RetryOn::parse("5xx,timeout,connect").unwrap());
+
+    processor.publish(message("a", "strict", 5)).unwrap();
+    processor.publish(message("b", "eventual", 5)).unwrap();
+    wait_until_finished(&processor);
+
+    assert_eq!(server.requests_to("/bad-request").len(), 1);
+    assert_eq!(store.get_message("a").unwrap().unwrap().status, MessageStatus::Dead);
+    assert_eq!(server.requests_to("/not-found").len(), 3);
+    assert_eq!(store.get_message("b").unwrap().unwrap().status, MessageStatus::Delivered);
+}
+
+#[test]
+fn test_if_pulled_messages_are_redelivered_until_acked() {
+    let destinations = Arc::new(DestinationRegistry::new());
+    destinations.register(Destination::pull("puller"));
+    let store = Arc::new(MemoryStore::new());
+    let processor = start_processor(store.clone(), destinations);
+    let pull = |visibility: Duration| processor.pull("event", Some("puller"), 10, Duration::from_secs(5), visibility).unwrap();
+
+    processor.publish(message("a", "puller", 5)).unwrap();
+    let first = pull(Duration::from_secs(30));
+    assert_eq!(first.len(), 1);
+    assert!(processor.nack("event", &first[0].receipt).unwrap());
+    assert!(!processor.ack("event", &first[0].receipt).unwrap());
+
+    let second = pull(Duration::from_millis(50));
+    assert_eq!(second[0].message.id, "a");
+    thread::sleep(Duration::from_millis(100));
+    assert!(!processor.ack("event", &second[0].receipt).unwrap());
+
+    let third = pull(Duration::from_secs(30));
+    assert_eq!(third[0].message.id, "a");
+    assert!(!processor.ack("other.event", &third[0].receipt).unwrap());
+    assert!(processor.ack("event", &third[0].receipt).unwrap());
+    wait_until_finished(&processor);
+
+    let classes: Vec<Option<DeliveryErrorClass>> = store.get_attempts("a").unwrap().iter()
+        .map(|attempt| match &attempt.outcome {
+            AttemptOutcome::Failed(error) => Some(error.class),
+            _ => None,
+        })
+        .collect();
+    assert_eq!(classes, vec![Some(DeliveryErrorClass::Nacked), Some(DeliveryErrorClass::ResponseTimeout), None]);
+    assert_eq!(store.get_message("a").unwrap().unwrap().status, MessageStatus::Delivered);
+}
+
+#[test]
+fn test_if_accepted_messages_wait_for_their_confirmation() {
+    let server = MockDestinationServer::start().unwrap();
+    server.respond_with("/hooks", &[202]);
+    let destinations = Arc::new(DestinationRegistry::new());
+    destinations.register(Destination::new("recipient", &server.url("/hooks")).with_async_ack(Duration::from_millis(200)));
+    let store = Arc::new(MemoryStore::new());
+    let processor = start_processor(store.clone(), destinations);
+    let wait_for_request = |count: usize| {
+        let started_at = Instant::now();
+        while server.requests_to("/hooks").len() < count {
+            assert!(started_at.elapsed() < Duration::from_secs(10), "the message was not sent in time");
+            thread::sleep(Duration::from_millis(5));
+        }
+        server.requests_to("/hooks")[count - 1].request.headers.get(ACK_TOKEN_HEADER).unwrap().to_string()
+    };
+
+    processor.publish(message("a", "recipient", 5)).unwrap();
+    // the first attempt is not confirmed in time and is retried
+    let first = AckToken::verify(&wait_for_request(1), time::OffsetDateTime::now_utc()).unwrap();
+    let second = AckToken::verify(&wait_for_request(2), time::OffsetDateTime::now_utc()).unwrap();
+    assert_eq!((first.attempt, second.attempt), (1, 2));
+    assert!(!processor.confirm_ack(&first, Ok(())).unwrap());
+    assert!(processor.confirm_ack(&second, Ok(())).unwrap());
+    assert!(!processor.confirm_ack(&second, Ok(())).unwrap());
+    wait_until_finished(&processor);
+
+    let attempts = store.get_attempts("a").unwrap();
+    assert!(matches!(&attempts[0].outcome, AttemptOutcome::Failed(error) if error.class == DeliveryErrorClass::ResponseTimeout));
+    assert_eq!(attempts[1].outcome, AttemptOutcome::Delivered);
+    assert_eq!(store.get_message("a").unwrap().unwrap().status, MessageStatus::Delivered);
+
+    // receivers can confirm the failure of a message
+    processor.publish(message("b", "recipient", 0)).unwrap();
+    let token = AckToken::verify(&wait_for_request(3), time::OffsetDateTime::now_utc()).unwrap();
+    assert!(processor.confirm_ack(&token, Err(String::from("invalid order"))).unwrap());
+    wait_until_finished(&processor);
+    assert_eq!(store.get_attempts("b").unwrap()[0].outcome, AttemptOutcome::Failed(DeliveryError::new(DeliveryErrorClass::Nacked, "invalid order")));
+    assert_eq!(store.get_message("b").unwrap().unwrap().status, MessageStatus::Dead);
+}
+
+#[test]
+fn test_if_warm_connections_are_opened_in_advance_and_reused() {
+    let server = MockDestinationServer::start().unwrap();
+    server.respond_with("/hooks", &[200]);
+    let destinations = Arc::new(DestinationRegistry::new());
+    destinations.register(Destination::new("recipient", &server.url("/hooks")).with_warm_connections(2));
+    let pool = Arc::new(ConnectionPool::default());
+    let deliverer = HttpDeliverer::new(destinations.clone(), Duration::from_millis(500)).with_connection_pool(pool.clone());
+    let warmer = deliverer.connection_warmer();
+    warmer.warm_up();
+    assert_eq!(pool.warm_counts().get("recipient"), Some(&(2, 2)));
+
+    for id in ["a", "b", "c"] {
+        assert_eq!(deliverer.deliver(&message(id, "recipient", 0)), AttemptOutcome::Delivered);
+    }
+    assert_eq!(server.requests_to("/hooks").len(), 3);
+    assert_eq!(pool.warm_counts().get("recipient"), Some(&(2, 2)));
+
+    // the connections are closed once the destination no longer has warmConnections
+    destinations.register(Destination::new("recipient", &server.url("/hooks")));
+    warmer.warm_up();
+    assert!(pool.warm_counts().is_empty());
+}
+
+#[test]
+fn test_if_large_bodies_are_compressed_once_the_receiver_accepts_gzip() {
+    let server = MockDestinationServer::start().unwrap();
+    server.set_header("/hooks", "Accept-Encoding", "br, gzip;q=0.8");
+    server.set_header("/legacy", "Accept-Encoding", "identity, gzip;q=0");
+    let destinations = Arc::new(DestinationRegistry::new());
+    destinations.register(Destination::new("recipient", &server.url("/hooks")).with_compression(BodyCompression::new(100)));
+    destinations.register(Destination::new("legacy", &server.url("/legacy")).with_compression(BodyCompression::new(100)));
+    let deliverer = HttpDeliverer::new(destinations.clone(), Duration::from_millis(500));
+    let large = |id: &str, recipient_id: &str| {
+        let mut message = message(id, recipient_id, 0);
+        message.payload = format!("[{}]", vec![r#"{"sku":"A-1","quantity":1}"#; 50].join(",")).into_bytes();
+        message
+    };
+
+    // the first request tells if the receiver accepts gzip, so it is sent as published
+    for id in ["a", "b"] {
+        assert_eq!(deliverer.deliver(&large(id, "recipient")), AttemptOutcome::Delivered);
+        assert_eq!(deliverer.deliver(&large(id, "legacy")), AttemptOutcome::Delivered);
+    }
+    assert_eq!(deliverer.deliver(&message("c", "recipient", 0)), AttemptOutcome::Delivered);
+    let requests = server.requests_to("/hooks");
+    assert_eq!(requests[0].request.headers.get("Content-Encoding"), None);
+    let compressed = &requests[1].request;
+    assert_eq!(compressed.headers.get("Content-Encoding"), Some("gzip"));
+    assert!(compressed.body.len() < large("b", "recipient").payload.len() / 4);
+    assert_eq!(gzip::decompress(&compressed.body, 1 << 20).unwrap(), large("b", "recipient").payload);
+    // the small payloads and the receivers that refuse gzip are sent as published
+    assert_eq!((requests[2].request.headers.get("Content-Encoding"), requests[2].request.body.as_slice()), (None, b"{\"ok\":true}".as_slice()));
+    assert!(server.requests_to("/legacy").iter().all(|captured| captured.request.headers.get("Content-Encoding").is_none()));
+
+    // a receiver that answers 415 to a compressed body gets the next ones as published
+    server.respond_with("/hooks", &[415, 200]);
+    assert!(matches!(deliverer.deliver(&large("d", "recipient")), AttemptOutcome::Failed(_)));
+    assert_eq!(deliverer.deliver(&large("d", "recipient")), AttemptOutcome::Delivered);
+    let requests = server.requests_to("/hooks");
+    assert_eq!(requests[3].request.headers.get("Content-Encoding"), Some("gzip"));
+    assert_eq!(requests[4].request.headers.get("Content-Encoding"), None);
+}
+
+#[test]
+fn test_if_shadow_receives_a_copy_of_each_delivery_without_affecting_it() {
+    let server = MockDestinationServer::start().unwrap();
+    server.respond_with("/staging", &[500, 500]);
+    let destinations = Arc::new(DestinationRegistry::new());
+    destinations.register(
+        Destination::new("recipient", &server.url("/hooks"))
+            .with_async_ack(Duration::from_secs(5))
+            .with_shadow(&server.url("/staging")),
+    );
+    let store = Arc::new(MemoryStore::new());
+    let processor = start_processor(store.clone(), destinations);
+
+    processor.publish(message("a", "recipient", 2)).unwrap();
+    processor.publish(message("b", "recipient", 2)).unwrap();
+    wait_until_finished(&processor);
+    assert!(server.wait_for_requests("/staging", 2, Duration::from_secs(5)));
+
+    // the failures of the shadow are not retried
+    assert_eq!(store.get_message("a").unwrap().unwrap().status, MessageStatus::Delivered);
+    assert_eq!(server.requests_to("/hooks").len(), 2);
+    let shadowed = &server.requests_to("/staging")[0].request;
+    assert_eq!(shadowed.headers.get("X-Angler-Shadow"), Some("true"));
+    assert!(shadowed.headers.get("X-Angler-Message-Id").is_some());
+    assert_eq!(shadowed.headers.get(ACK_TOKEN_HEADER), None);
+    assert!(server.requests_to("/hooks")[0].request.headers.get(ACK_TOKEN_HEADER).is_some());
+}
+
+#[test]
+fn test_if_canary_receives_its_share_with_the_outcomes_of_each_branch() {
+    let server = MockDestinationServer::start().unwrap();
+    server.respond_with("/v2", &[500, 200]);
+    let destinations = Arc::new(DestinationRegistry::new());
+    destinations.register(Destination::new("recipient", &server.url("/v1")).with_canary(CanaryRoute::new(&server.url("/v2"), 30)));
+    let store = Arc::new(MemoryStore::new());
+    let processor = start_processor(store, destinations.clone());
+
+    for id in 0..50 {
+        processor.publish(message(&format!("m-{}", id), "recipient", 0)).unwrap();
+    }
+    wait_until_finished(&processor);
+
+    let (primary, canary) = (server.requests_to("/v1").len() as u64, server.requests_to("/v2").len() as u64);
+    assert_eq!(primary + canary, 50);
+    assert!(canary > 0 && primary > canary, "{} messages were sent to the canary", canary);
+    let stats = destinations.canary_stats();
+    assert_eq!(stats.get("recipient", Branch::Primary), BranchStats { delivered: primary, failed: 0 });
+    assert_eq!(stats.get("recipient", Branch::Canary), BranchStats { delivered: canary - 1, failed: 1 });
+
+    // the outcomes of the previous canary are forgotten
+    destinations.register(Destination::new("recipient", &server.url("/v1")).with_canary(CanaryRoute::new(&server.url("/v2"), 50)));
+    assert_eq!(stats.get("recipient", Branch::Canary), BranchStats::default());
+}
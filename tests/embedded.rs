@@ -0,0 +1,1025 @@
+use std::{collections::HashSet, io::{BufRead, BufReader, Write}, net::TcpStream, sync::Arc, thread, time::{Duration, Instant}};
+
+use angler::{
+    cluster::join::JoinToken,
+    ctx::{appenv::ApplicationRoles, config::Configuration},
+    db::{memory::MemoryStore, MessageStore, StoreWrite},
+    embedded::{PublishError, StorageNode},
+    msgproc::{
+        interceptor::{Interceptor, Rejection},
+        message::{Message, MessageStatus},
+        retry::RetryPolicy,
+    },
+    net::{
+        admin::search::{search_messages, MessageSearchFilter},
+        auth::{AuthError, AuthProvider, AuthProviderKind, Identity},
+        client::{restful::NEXT_CURSOR_HEADER, routing::{export_routing, import_routing, reconcile_routing}},
+        http::{send_request, HttpRequest, HttpUrl},
+    },
+    testutil::mock_destination::MockDestinationServer,
+    utils::{clock::VirtualClock, gzip, json::JsonValue, time::{DurationSequence, DurationSequenceDeserializer}},
+    Angler,
+};
+
+fn request(angler: &Angler, method: &str, path: &str, body: &str) -> (u16, JsonValue) {
+    request_to(&angler.client_url(), method, path, body)
+}
+
+fn admin_request(angler: &Angler, method: &str, path: &str) -> (u16, JsonValue) {
+    request_to(&format!("http://{}", angler.admin_addr().unwrap()), method, path, "")
+}
+
+fn request_to(base_url: &str, method: &str, path: &str, body: &str) -> (u16, JsonValue) {
+    let url = HttpUrl::parse(&format!("{}{}", base_url, path)).unwrap();
+    let mut request = HttpRequest::new(method, path);
+    request.headers.set("Content-Type", "application/json");
+    request.body = body.as_bytes().to_vec();
+    let response = send_request(&url, request, Duration::from_secs(5)).unwrap();
+    let json = if response.body.is_empty() { JsonValue::Null } else { JsonValue::parse_bytes(&response.body).unwrap() };
+    (response.status, json)
+}
+
+#[test]
+fn test_if_embedded_instance_delivers_messages_published_in_process() {
+    let destination = MockDestinationServer::start().unwrap();
+    let angler = Angler::builder().workers(2).build().unwrap();
+    angler.register_destination("recipient", &destination.url("/hooks"));
+
+    let id = angler.publish("recipient", "service", "event", b"{\"hello\":1}").unwrap();
+    let message = angler.wait_for_status(&id, MessageStatus::Delivered, Duration::from_secs(5)).unwrap();
+
+    assert!(message.is_some());
+    assert_eq!(destination.requests_to("/hooks")[0].request.body, b"{\"hello\":1}");
+    assert_eq!(angler.attempts(&id).unwrap().len(), 1);
+}
+
+#[test]
+fn test_if_embedded_instance_serves_the_restful_api() {
+    let destination = MockDestinationServer::start().unwrap();
+    let angler = Angler::builder().workers(2).build().unwrap();
+
+    let (status, _) = request(&angler, "PUT", "/destinations/recipient", &format!("{{\"url\": \"{}\"}}", destination.url("/hooks")));
+    assert_eq!(status, 200);
+
+    let (status, published) = request(
+        &angler,
+        "POST",
+        "/messages",
+        r#"{"sendMessage": {"recipientId": "recipient", "serviceId": "service", "eventId": "event"}, "data": {"order": 12345}}"#,
+    );
+    assert_eq!(status, 202);
+    let id = published.get("id").unwrap().as_str().unwrap().to_string();
+
+    assert!(destination.wait_for_requests("/hooks", 1, Duration::from_secs(5)));
+    assert!(angler.wait_for_status(&id, MessageStatus::Delivered, Duration::from_secs(5)).unwrap().is_some());
+
+    let (status, message) = request(&angler, "GET", &format!("/messages/{}", id), "");
+    assert_eq!(status, 200);
+    assert_eq!(message.get("status").unwrap().as_str(), Some("delivered"));
+    assert_eq!(destination.requests_to("/hooks")[0].request.body, br#"{"order":12345}"#);
+
+    let (status, attempts) = request(&angler, "GET", &format!("/messages/{}/attempts", id), "");
+    assert_eq!(status, 200);
+    assert_eq!(attempts.as_array().unwrap().len(), 1);
+
+    let (status, _) = request(&angler, "GET", "/messages/unknown", "");
+    assert_eq!(status, 404);
+}
+
+#[test]
+fn test_if_request_ids_are_echoed_and_recorded_in_the_published_message() {
+    let angler = Angler::builder().workers(1).build().unwrap();
+    let url = HttpUrl::parse(&format!("{}/messages", angler.client_url())).unwrap();
+    let publish = |request_id: Option<&str>| {
+        let mut request = HttpRequest::new("POST", "/messages");
+        request.headers.set("Content-Type", "application/json");
+        if let Some(request_id) = request_id {
+            request.headers.set("X-Request-ID", request_id);
+        }
+        request.body = br#"{"sendMessage": {"recipientId": "recipient", "serviceId": "service", "eventId": "event"}, "data": {}}"#.to_vec();
+        send_request(&url, request, Duration::from_secs(5)).unwrap()
+    };
+
+    let response = publish(Some("order-import-42"));
+    assert_eq!(response.status, 202);
+    assert_eq!(response.headers.get("X-Request-ID"), Some("order-import-42"));
+    let id = JsonValue::parse_bytes(&response.body).unwrap().get("id").unwrap().as_str().unwrap().to_string();
+    let (_, message) = request(&angler, "GET", &format!("/messages/{}", id), "");
+    assert_eq!(message.get("requestId").and_then(JsonValue::as_str), Some("order-import-42"));
+
+    // a request ID that can not be copied into the logs is replaced
+    let generated = publish(Some("two words"));
+    let request_id = generated.headers.get("X-Request-ID").unwrap();
+    assert_eq!(request_id.len(), 36);
+    assert_eq!(JsonValue::parse_bytes(&generated.body).unwrap().get("requestId").and_then(JsonValue::as_str), Some(request_id));
+    assert_eq!(publish(None).headers.get("X-Request-ID").map(str::len), Some(36));
+}
+
+#[test]
+fn test_if_compressed_and_ndjson_publishes_are_decoded() {
+    let destination = MockDestinationServer::start().unwrap();
+    let angler = Angler::builder().workers(2).build().unwrap();
+    angler.register_destination("recipient", &destination.url("/hooks"));
+    let url = HttpUrl::parse(&format!("{}/messages", angler.client_url())).unwrap();
+    let publish = |content_type: &str, encoding: &str, body: Vec<u8>| {
+        let mut request = HttpRequest::new("POST", "/messages");
+        request.headers.set("Content-Type", content_type);
+        request.headers.set("Content-Encoding", encoding);
+        request.body = body;
+        send_request(&url, request, Duration::from_secs(5)).unwrap()
+    };
+
+    let body = br#"{"sendMessage": {"recipientId": "recipient", "serviceId": "service", "eventId": "event"}, "data": {"order": 1}}"#;
+    let response = publish("application/json", "gzip", gzip::compress(body));
+    assert_eq!(response.status, 202);
+    assert!(destination.wait_for_requests("/hooks", 1, Duration::from_secs(5)));
+    assert_eq!(destination.requests_to("/hooks")[0].request.body, br#"{"order":1}"#);
+
+    // each line is published on its own, and the invalid ones do not fail the others
+    let batch = [
+        r#"{"sendMessage": {"recipientId": "recipient", "serviceId": "service", "eventId": "event"}, "data": {"order": 2}}"#,
+        r#"{"sendMessage": {"recipientId": "recipient", "eventId": "event"}, "data": {"order": 3}}"#,
+        "",
+        r#"{"sendMessage": {"recipientId": "recipient", "serviceId": "service", "eventId": "event"}, "data": {"order": 4}}"#,
+    ].join("\n");
+    let response = publish("application/x-ndjson", "gzip", gzip::compress(batch.as_bytes()));
+    assert_eq!((response.status, response.headers.get("Content-Type")), (200, Some("application/x-ndjson")));
+    let results: Vec<JsonValue> = String::from_utf8(response.body).unwrap().lines().map(|line| JsonValue::parse(line).unwrap()).collect();
+    let statuses: Vec<(u64, u64)> = results.iter().map(|result| (result.get("line").unwrap().as_u64().unwrap(), result.get("status").unwrap().as_u64().unwrap())).collect();
+    assert_eq!(statuses, vec![(1, 202), (2, 400), (4, 202)]);
+    assert!(results[1].get("error").and_then(JsonValue::as_str).unwrap().contains("serviceId"));
+    let id = results[2].get("message").and_then(|message| message.get("id")).and_then(JsonValue::as_str).unwrap();
+    assert!(angler.wait_for_status(id, MessageStatus::Delivered, Duration::from_secs(5)).unwrap().is_some());
+    assert_eq!(publish("application/x-ndjson", "identity", b"\n\n".to_vec()).status, 400);
+
+    let unsupported = publish("application/json", "br", body.to_vec());
+    assert_eq!((unsupported.status, unsupported.headers.get("Accept-Encoding")), (415, Some("gzip, deflate")));
+    assert_eq!(publish("application/json", "gzip", body.to_vec()).status, 400);
+}
+
+#[test]
+fn test_if_destination_changes_are_refused_when_their_etag_is_stale() {
+    let angler = Angler::builder().workers(1).build().unwrap();
+    let change = |method: &str, condition: (&str, &str), url: &str| {
+        let target = HttpUrl::parse(&format!("{}/destinations/recipient", angler.client_url())).unwrap();
+        let mut request = HttpRequest::new(method, "/destinations/recipient");
+        request.headers.set(condition.0, condition.1);
+        request.body = format!("{{\"url\": \"{}\"}}", url).into_bytes();
+        let response = send_request(&target, request, Duration::from_secs(5)).unwrap();
+        (response.status, response.headers.get("ETag").map(str::to_string))
+    };
+
+    assert_eq!(change("PUT", ("If-None-Match", "*"), "http://a/"), (200, Some(String::from("\"1\""))));
+    assert_eq!(change("PUT", ("If-None-Match", "*"), "http://a/"), (412, Some(String::from("\"1\""))));
+
+    // two operators read the same version and the second change is refused
+    assert_eq!(change("PUT", ("If-Match", "\"1\""), "http://b/"), (200, Some(String::from("\"2\""))));
+    assert_eq!(change("PUT", ("If-Match", "\"1\""), "http://c/"), (412, Some(String::from("\"2\""))));
+    let (status, destination) = request(&angler, "GET", "/destinations/recipient", "");
+    assert_eq!(status, 200);
+    assert_eq!(destination.get("url").and_then(JsonValue::as_str), Some("http://b/"));
+    assert_eq!(destination.get("version").and_then(JsonValue::as_u64), Some(2));
+
+    assert_eq!(change("DELETE", ("If-Match", "\"1\""), "").0, 412);
+    assert_eq!(change("PUT", ("If-Match", "latest"), "http://c/").0, 400);
+    assert_eq!(change("DELETE", ("If-Match", "\"2\""), "").0, 204);
+    assert_eq!(change("PUT", ("If-Match", "*"), "http://c/"), (412, None));
+}
+
+#[test]
+fn test_if_destination_changes_are_kept_in_its_history_and_rolled_back() {
+    let angler = Angler::builder().workers(1).build().unwrap();
+    let change = |method: &str, path: &str, actor: &str, body: &str| {
+        let target = HttpUrl::parse(&format!("{}{}", angler.client_url(), path)).unwrap();
+        let mut request = HttpRequest::new(method, path);
+        request.headers.set("X-Angler-Actor", actor);
+        request.body = body.as_bytes().to_vec();
+        send_request(&target, request, Duration::from_secs(5)).unwrap().status
+    };
+    assert_eq!(change("PUT", "/destinations/recipient", "alice", r#"{"url": "http://a/"}"#), 200);
+    assert_eq!(change("PUT", "/destinations/recipient", "bob", r#"{"url": "http://broken/", "headers": {"Authorization": "Basic x"}}"#), 200);
+    assert_eq!(change("DELETE", "/destinations/recipient", "bob", ""), 204);
+
+    let (status, history) = request(&angler, "GET", "/destinations/recipient/history", "");
+    assert_eq!(status, 200);
+    let changes = history.get("changes").and_then(JsonValue::as_array).unwrap();
+    let field = |index: usize, pointer: &str| changes[index].pointer(pointer).cloned().unwrap_or(JsonValue::Null);
+    assert_eq!(changes.iter().map(|change| change.get("kind").and_then(JsonValue::as_str).unwrap()).collect::<Vec<_>>(), vec!["registered", "registered", "removed"]);
+    assert_eq!(field(1, "changedBy"), JsonValue::from("bob"));
+    assert_eq!(field(1, "diff.url.from"), JsonValue::from("http://a/"));
+    assert_eq!(field(1, "diff.url.to"), JsonValue::from("http://broken/"));
+    assert_eq!(field(1, "diff.headers.to.Authorization"), JsonValue::from("Basic x"));
+    assert_eq!(changes[1].get("diff").and_then(JsonValue::as_object).map(|diff| diff.len()), Some(2));
+    assert_eq!(field(2, "destination"), JsonValue::Null);
+
+    assert_eq!(change("POST", "/destinations/recipient/rollback", "alice", r#"{"version": 1}"#), 200);
+    let (_, destination) = request(&angler, "GET", "/destinations/recipient", "");
+    assert_eq!(destination.get("url").and_then(JsonValue::as_str), Some("http://a/"));
+    assert_eq!(destination.get("version").and_then(JsonValue::as_u64), Some(3));
+    assert_eq!(change("POST", "/destinations/recipient/rollback", "alice", r#"{"version": 9}"#), 404);
+    assert_eq!(request(&angler, "GET", "/destinations/unknown/history", "").0, 404);
+}
+
+#[test]
+fn test_if_deleted_destinations_park_their_messages_until_restored() {
+    let destination = MockDestinationServer::start().unwrap();
+    let angler = Angler::builder().workers(1).build().unwrap();
+    let body = format!("{{\"url\": \"{}\"}}", destination.url("/hooks"));
+    assert_eq!(request(&angler, "PUT", "/destinations/recipient", &body).0, 200);
+    assert_eq!(request(&angler, "DELETE", "/destinations/recipient", "").0, 204);
+    assert_eq!(request(&angler, "GET", "/destinations/recipient", "").0, 404);
+
+    let id = angler.publish("recipient", "service", "event", b"{}").unwrap();
+    std::thread::sleep(Duration::from_millis(50));
+    assert!(destination.requests_to("/hooks").is_empty());
+    assert!(angler.attempts(&id).unwrap().is_empty());
+    assert!(angler.wait_for_status(&id, MessageStatus::Pending, Duration::from_secs(5)).unwrap().is_some());
+
+    let (status, deleted) = request(&angler, "GET", "/deleted-destinations", "");
+    assert_eq!(status, 200);
+    let deleted = deleted.as_array().unwrap();
+    assert_eq!(deleted.len(), 1);
+    assert_eq!(deleted[0].get("id").and_then(JsonValue::as_str), Some("recipient"));
+    assert!(deleted[0].get("purgeAt").and_then(JsonValue::as_str).is_some());
+
+    let (status, restored) = request(&angler, "POST", "/destinations/recipient/restore", "");
+    assert_eq!(status, 200);
+    assert_eq!(restored.get("version").and_then(JsonValue::as_u64), Some(2));
+    assert!(angler.wait_for_status(&id, MessageStatus::Delivered, Duration::from_secs(5)).unwrap().is_some());
+    assert_eq!(destination.requests_to("/hooks").len(), 1);
+
+    assert_eq!(request(&angler, "POST", "/destinations/recipient/restore", "").0, 404);
+    assert_eq!(request(&angler, "GET", "/deleted-destinations", "").1.as_array().map(Vec::len), Some(0));
+}
+
+#[test]
+fn test_if_messages_outside_the_delivery_window_wait_for_it_to_open() {
+    let destination = MockDestinationServer::start().unwrap();
+    // monday 2024-01-01 at 00:00 UTC
+    let start_time = time::OffsetDateTime::from_unix_timestamp(1_704_067_200).unwrap();
+    let clock = Arc::new(VirtualClock::new(start_time));
+    let angler = Angler::builder().clock(clock.clone()).workers(1).build().unwrap();
+    let body = format!(
+        r#"{{"url": "{}", "deliveryWindow": {{"days": ["mon-fri"], "start": "08:00", "end": "20:00", "timezone": "-03:00"}}}}"#,
+        destination.url("/hooks")
+    );
+    let (status, registered) = request(&angler, "PUT", "/destinations/recipient", &body);
+    assert_eq!(status, 200);
+    assert_eq!(registered.get("deliveryWindow").and_then(|window| window.get("timezone")).and_then(JsonValue::as_str), Some("-03:00"));
+    assert_eq!(request(&angler, "PUT", "/destinations/other", r#"{"url": "http://a/", "deliveryWindow": {"days": ["someday"], "start": "08:00", "end": "20:00"}}"#).0, 400);
+
+    let id = angler.publish("recipient", "service", "event", b"{}").unwrap();
+    let opens_at = start_time + time::Duration::hours(11);
+    let held = angler.wait_for_status(&id, MessageStatus::Pending, Duration::from_secs(5)).unwrap();
+    let started_at = std::time::Instant::now();
+    while angler.message(&id).unwrap().unwrap().next_attempt_at != Some(opens_at) {
+        assert!(started_at.elapsed() < Duration::from_secs(5), "the message was not held: {:?}", held);
+        std::thread::sleep(Duration::from_millis(5));
+    }
+    assert!(angler.attempts(&id).unwrap().is_empty());
+
+    clock.advance(time::Duration::hours(11));
+    assert!(angler.wait_for_status(&id, MessageStatus::Delivered, Duration::from_secs(5)).unwrap().is_some());
+    assert_eq!(destination.requests_to("/hooks").len(), 1);
+}
+
+#[test]
+fn test_if_virtual_clock_fast_forwards_retries_and_retention() {
+    let destination = MockDestinationServer::start().unwrap();
+    destination.respond_with("/hooks", &[500, 200]);
+    let mut configuration = Configuration::new();
+    configuration.retry_policy.default_interval = Some("1d".to_duration_sequence().unwrap());
+    configuration.retry_policy.default_max_attempts = Some(3);
+    configuration.database.delivered_messages_retention = Some(time::Duration::days(7));
+
+    let clock = Arc::new(VirtualClock::new(time::OffsetDateTime::from_unix_timestamp(1_704_067_200).unwrap()));
+    let angler = Angler::builder().configuration(configuration).clock(clock.clone()).workers(1).build().unwrap();
+    angler.register_destination("recipient", &destination.url("/hooks"));
+
+    let id = angler.publish("recipient", "service", "event", b"{}").unwrap();
+    assert!(destination.wait_for_requests("/hooks", 1, Duration::from_secs(5)));
+    assert!(angler.wait_for_status(&id, MessageStatus::Pending, Duration::from_secs(5)).unwrap().is_some());
+
+    clock.advance(time::Duration::days(1));
+    assert!(angler.wait_for_status(&id, MessageStatus::Delivered, Duration::from_secs(5)).unwrap().is_some());
+
+    clock.advance(time::Duration::days(8));
+    let started_at = std::time::Instant::now();
+    while angler.message(&id).unwrap().is_some() {
+        assert!(started_at.elapsed() < Duration::from_secs(5), "the delivered message was not removed in time");
+        std::thread::sleep(Duration::from_millis(5));
+    }
+}
+
+#[test]
+fn test_if_dead_messages_are_replayed_through_the_restful_api() {
+    let destination = MockDestinationServer::start().unwrap();
+    let angler = Angler::builder().workers(2).build().unwrap();
+
+    // without a destination the message dies on its first attempt
+    let id = angler.publish("recipient", "service", "event", b"{\"order\":1}").unwrap();
+    assert!(angler.wait_for_status(&id, MessageStatus::Dead, Duration::from_secs(5)).unwrap().is_some());
+
+    angler.register_destination("recipient", &destination.url("/hooks"));
+    let (status, replay) = request(&angler, "POST", "/dead-messages:replay", r#"{"recipientId": "recipient", "errorClass": "noDestination"}"#);
+    assert_eq!(status, 202);
+    assert_eq!(replay.get("matched").and_then(JsonValue::as_u64), Some(1));
+
+    assert!(destination.wait_for_requests("/hooks", 1, Duration::from_secs(5)));
+    assert_eq!(destination.requests_to("/hooks")[0].request.body, b"{\"order\":1}");
+    assert_eq!(angler.message(&id).unwrap().unwrap().status, MessageStatus::Dead);
+
+    let job_path = format!("/jobs/{}", replay.get("jobId").and_then(JsonValue::as_str).unwrap());
+    let deadline = Instant::now() + Duration::from_secs(5);
+    let job = loop {
+        let (status, job) = request(&angler, "GET", &job_path, "");
+        assert_eq!(status, 200);
+        if job.get("state").and_then(JsonValue::as_str) != Some("running") || Instant::now() > deadline {
+            break job;
+        }
+        thread::sleep(Duration::from_millis(10));
+    };
+    assert_eq!(job.get("kind").and_then(JsonValue::as_str), Some("replay"));
+    assert_eq!(job.get("state").and_then(JsonValue::as_str), Some("succeeded"));
+    assert_eq!(job.get("changed").and_then(JsonValue::as_u64), Some(1));
+}
+
+#[test]
+fn test_if_the_producers_are_notified_of_the_messages_that_die_or_expire() {
+    let destination = MockDestinationServer::start().unwrap();
+    let mut configuration = Configuration::new();
+    configuration.messages_processor.death_notifications_recipient_id = Some(String::from("producers"));
+    let angler = Angler::builder().configuration(configuration).workers(1).admin_address("127.0.0.1:0").build().unwrap();
+    angler.register_destination("producers", &destination.url("/dlq"));
+
+    // without a destination the message dies on its first attempt
+    let id = angler.publish("recipient", "billing", "invoice.paid", b"{}").unwrap();
+    assert!(destination.wait_for_requests("/dlq", 1, Duration::from_secs(5)));
+    let sent = &destination.requests_to("/dlq")[0].request;
+    assert_eq!(sent.headers.get("X-Angler-Attr-state"), Some("dead"));
+    let notification = JsonValue::parse_bytes(&sent.body).unwrap();
+    assert_eq!(notification.get("messageId").and_then(JsonValue::as_str), Some(id.as_str()));
+    assert_eq!(notification.get("eventId").and_then(JsonValue::as_str), Some("invoice.paid"));
+    assert_eq!(notification.get("attempts").and_then(JsonValue::as_u64), Some(1));
+    assert!(notification.get("reason").and_then(JsonValue::as_str).unwrap().starts_with("noDestination: "));
+
+    let (status, published) = request(
+        &angler,
+        "POST",
+        "/messages",
+        r#"{"sendMessage": {"recipientId": "recipient", "serviceId": "billing", "eventId": "invoice.paid", "retryPolicy": {"interval": "1h", "maxAttempts": 3}}}"#,
+    );
+    assert_eq!(status, 202);
+    let id = published.get("id").and_then(JsonValue::as_str).unwrap();
+    assert!(angler.wait_for_status(id, MessageStatus::Pending, Duration::from_secs(5)).unwrap().is_some());
+    let admin_url = format!("http://{}", angler.admin_addr().unwrap());
+    let (status, _) = request_to(&admin_url, "POST", &format!("/admin/messages/{}/transition", id), r#"{"state": "expired", "reason": "the invoice was voided"}"#);
+    assert_eq!(status, 200);
+    assert!(destination.wait_for_requests("/dlq", 2, Duration::from_secs(5)));
+    let sent = &destination.requests_to("/dlq")[1].request;
+    assert_eq!(sent.headers.get("X-Angler-Attr-state"), Some("expired"));
+    let notification = JsonValue::parse_bytes(&sent.body).unwrap();
+    assert_eq!(notification.get("messageId").and_then(JsonValue::as_str), Some(id));
+    assert_eq!(notification.get("reason").and_then(JsonValue::as_str), Some("the invoice was voided"));
+}
+
+#[test]
+fn test_if_topics_are_managed_with_their_settings() {
+    let angler = Angler::builder().workers(1).build().unwrap();
+    let (status, topic) = request(
+        &angler,
+        "POST",
+        "/topics",
+        r#"{"name": "invoice.paid", "ordering": "ordered", "maxPayloadSize": 8, "retention": {"deadMessages": "30d"}, "retryPolicy": {"interval": "[1m, 1h]", "maxAttempts": 2}}"#,
+    );
+    assert_eq!(status, 201);
+    assert_eq!(topic.get("ordering").and_then(JsonValue::as_str), Some("ordered"));
+    assert_eq!(topic.get("retention").unwrap().get("deadMessages").and_then(JsonValue::as_str), Some("30d"));
+    assert_eq!(request(&angler, "POST", "/topics", r#"{"name": "invoice.paid"}"#).0, 409);
+    assert_eq!(request(&angler, "POST", "/topics", r#"{"name": "invoice.paid", "ordering": "sorted"}"#).0, 400);
+
+    // the messages published without a retry policy get the one of their topic
+    let body = |data: &str| format!(r#"{{"sendMessage": {{"recipientId": "recipient", "serviceId": "billing", "eventId": "invoice.paid"}}, "data": {}}}"#, data);
+    let (status, published) = request(&angler, "POST", "/messages", &body(r#"{"a": 1}"#));
+    assert_eq!(status, 202);
+    assert_eq!(published.get("retryPolicy").unwrap().to_string(), r#"{"interval":"[1m, 1h]","maxAttempts":2}"#);
+    let (status, rejected) = request(&angler, "POST", "/messages", &body(r#"{"ab": 12}"#));
+    assert_eq!(status, 422);
+    assert_eq!(rejected.get("error").and_then(JsonValue::as_str), Some("the payload has 9 bytes, more than the limit of 8 bytes"));
+
+    let (status, updated) = request(&angler, "PUT", "/topics/invoice.paid", r#"{"ordering": "unordered"}"#);
+    assert_eq!(status, 200);
+    assert_eq!((updated.get("maxPayloadSize"), updated.get("retryPolicy")), (Some(&JsonValue::Null), Some(&JsonValue::Null)));
+    assert_eq!(request(&angler, "POST", "/messages", &body(r#"{"ab": 12}"#)).0, 202);
+    assert_eq!(request(&angler, "PUT", "/topics/invoice.voided", "{}").0, 404);
+
+    let (status, topics) = request(&angler, "GET", "/topics", "");
+    assert_eq!(status, 200);
+    assert_eq!(topics.as_array().unwrap().len(), 1);
+    assert_eq!(request(&angler, "DELETE", "/topics/invoice.paid", "").0, 204);
+    assert_eq!(request(&angler, "GET", "/topics/invoice.paid", "").0, 404);
+    assert!(angler.topics().list().is_empty());
+}
+
+#[test]
+fn test_if_messages_are_searched_by_indexed_payload_fields() {
+    let mut configuration = Configuration::new();
+    configuration.database.payload_index = Some(std::collections::HashMap::from([
+        (String::from("order.created"), vec![String::from("order.id")]),
+    ]));
+    let angler = Angler::builder().configuration(configuration).workers(1).build().unwrap();
+
+    let wanted = angler.publish("recipient", "shop", "order.created", br#"{"order": {"id": 12345}}"#).unwrap();
+    let other = angler.publish("recipient", "shop", "order.created", br#"{"order": {"id": 1}}"#).unwrap();
+    for id in [&wanted, &other] {
+        assert!(angler.wait_for_status(id, MessageStatus::Dead, Duration::from_secs(5)).unwrap().is_some());
+    }
+
+    let (status, found) = request(&angler, "GET", "/messages?eventId=order.created&payload.order.id=12345", "");
+    assert_eq!(status, 200);
+    let found = found.as_array().unwrap();
+    assert_eq!(found.len(), 1);
+    assert_eq!(found[0].get("id").unwrap().as_str(), Some(wanted.as_str()));
+    assert_eq!(found[0].get("indexedFields").unwrap().get("order.id").unwrap().as_str(), Some("12345"));
+
+    let (status, _) = request(&angler, "GET", "/messages?order=12345", "");
+    assert_eq!(status, 400);
+}
+
+#[test]
+fn test_if_messages_are_paged_by_cursor_while_others_are_published() {
+    let angler = Angler::builder().workers(1).build().unwrap();
+    let mut published: Vec<String> = (0..5).map(|_| angler.publish("recipient", "shop", "order.created", b"{}").unwrap()).collect();
+    let page = |cursor: Option<&str>| {
+        let path = match cursor {
+            Some(cursor) => format!("/messages?eventId=order.created&limit=2&fields=id&cursor={}", cursor),
+            None => String::from("/messages?eventId=order.created&limit=2&fields=id"),
+        };
+        let url = HttpUrl::parse(&format!("{}{}", angler.client_url(), path)).unwrap();
+        let response = send_request(&url, HttpRequest::new("GET", &path), Duration::from_secs(5)).unwrap();
+        assert_eq!(response.status, 200);
+        let ids: Vec<String> = JsonValue::parse_bytes(&response.body).unwrap().as_array().unwrap().iter()
+            .map(|message| message.get("id").and_then(JsonValue::as_str).unwrap().to_string())
+            .collect();
+        (ids, response.headers.get(NEXT_CURSOR_HEADER).map(String::from))
+    };
+
+    let (mut listed, mut cursor) = page(None);
+    // published after the first page, so it is listed at the end instead of shifting the pages
+    published.push(angler.publish("recipient", "shop", "order.created", b"{}").unwrap());
+    while let Some(next) = cursor {
+        let (ids, next) = page(Some(&next));
+        listed.extend(ids);
+        cursor = next;
+    }
+    assert_eq!(listed, published);
+
+    let (status, _) = request(&angler, "GET", "/messages?cursor=invalid", "");
+    assert_eq!(status, 400);
+}
+
+#[test]
+fn test_if_inline_retry_policy_is_clamped_by_the_limits() {
+    let mut configuration = Configuration::new();
+    configuration.retry_policy.max_interval_limit = Some(time::Duration::hours(1));
+    configuration.retry_policy.max_attempts_limit = Some(5);
+    let angler = Angler::builder().configuration(configuration).workers(1).build().unwrap();
+
+    let (status, published) = request(
+        &angler,
+        "POST",
+        "/messages",
+        r#"{"sendMessage": {"recipientId": "r", "serviceId": "s", "eventId": "e", "retryPolicy": {"interval": "[1m, 1d]", "maxAttempts": 50}}}"#,
+    );
+    assert_eq!(status, 202);
+    let requested = published.get("requestedRetryPolicy").unwrap();
+    assert_eq!(requested.get("interval").unwrap().as_str(), Some("[1m, 1d]"));
+    assert_eq!(requested.get("maxAttempts").and_then(JsonValue::as_u64), Some(50));
+    let violations = published.get("retryPolicyViolations").unwrap().to_string();
+    assert_eq!(violations, r#"["element 2 (1d) exceeds maxInterval 1h","maxAttempts 50 exceeds maxAttempts 5"]"#);
+
+    let id = published.get("id").unwrap().as_str().unwrap();
+    let message = angler.message(id).unwrap().unwrap();
+    assert_eq!(message.retry_policy.interval, Some("[1m, 1h]".to_duration_sequence().unwrap()));
+    assert_eq!(message.retry_policy.max_attempts, 5);
+}
+
+#[test]
+fn test_if_retry_schedule_preview_returns_the_clamped_attempt_times() {
+    let mut configuration = Configuration::new();
+    configuration.retry_policy.max_interval_limit = Some(time::Duration::hours(1));
+    configuration.retry_policy.max_attempts_limit = Some(3);
+    let mut billing = Configuration::new().retry_policy;
+    billing.max_attempts_limit = Some(1);
+    configuration.retry_policy.namespaces = Some(std::collections::HashMap::from([(String::from("billing"), billing)]));
+    let clock = Arc::new(VirtualClock::new(time::OffsetDateTime::from_unix_timestamp(1_704_067_200).unwrap()));
+    let angler = Angler::builder().configuration(configuration).clock(clock).workers(1).build().unwrap();
+
+    let (status, preview) = request(&angler, "GET", "/retry-policies/preview?interval=[1m,5m,1d]&maxAttempts=10", "");
+    assert_eq!(status, 200);
+    assert_eq!(preview.get("retryPolicy").unwrap().get("interval").unwrap().as_str(), Some("[1m, 5m, 1h]"));
+    assert_eq!(preview.get("violations").unwrap().to_string(), r#"["element 3 (1d) exceeds maxInterval 1h","maxAttempts 10 exceeds maxAttempts 3"]"#);
+    let attempts: Vec<&str> = preview.get("attempts").unwrap().as_array().unwrap().iter()
+        .map(|attempt| attempt.get("at").unwrap().as_str().unwrap())
+        .collect();
+    assert_eq!(attempts, vec!["2024-01-01T00:00:00.000Z", "2024-01-01T00:01:00.000Z", "2024-01-01T00:06:00.000Z", "2024-01-01T01:06:00.000Z"]);
+
+    // the messages of billing are clamped by its own limits too
+    let (_, preview) = request(&angler, "GET", "/retry-policies/preview?interval=[1m,5m,1d]&maxAttempts=10&serviceId=billing", "");
+    assert_eq!(preview.get("retryPolicy").unwrap().get("maxAttempts").and_then(JsonValue::as_u64), Some(1));
+    assert_eq!(preview.get("attempts").unwrap().as_array().unwrap().len(), 2);
+
+    let (status, _) = request(&angler, "GET", "/retry-policies/preview?maxAttempts=many", "");
+    assert_eq!(status, 400);
+}
+
+/// Tag the messages with the tenant taken from the service ID, like `acme.billing`
+struct TenantTagger;
+
+impl Interceptor for TenantTagger {
+    fn intercept(&self, message: &mut Message) -> Result<(), Rejection> {
+        let tenant = message.service_id.split('.').next().unwrap_or_default().to_string();
+        if tenant.is_empty() {
+            return Err(Rejection::new("the service ID has no tenant"));
+        }
+        message.indexed_fields.insert(String::from("tenant"), tenant);
+        Ok(())
+    }
+}
+
+#[test]
+fn test_if_published_messages_go_through_the_interceptors() {
+    let mut configuration = Configuration::new();
+    configuration.messages_processor.max_payload_size = Some(16);
+    let angler = Angler::builder().configuration(configuration).interceptor(Arc::new(TenantTagger)).workers(1).build().unwrap();
+
+    let id = angler.publish("recipient", "acme.billing", "event", b"{}").unwrap();
+    assert_eq!(angler.message(&id).unwrap().unwrap().indexed_fields.get("tenant").map(String::as_str), Some("acme"));
+    assert!(matches!(angler.publish("recipient", ".billing", "event", b"{}"), Err(PublishError::Rejected(_))));
+
+    let (status, rejected) = request(
+        &angler,
+        "POST",
+        "/messages",
+        r#"{"sendMessage": {"recipientId": "r", "serviceId": "acme.billing", "eventId": "e"}, "data": {"text": "longer than the limit"}}"#,
+    );
+    assert_eq!(status, 422);
+    assert!(rejected.get("error").unwrap().as_str().unwrap().contains("limit of 16 bytes"));
+    assert_eq!(angler.stats().published.load(std::sync::atomic::Ordering::SeqCst), 1);
+}
+
+#[test]
+fn test_if_payloads_that_do_not_conform_to_the_topic_schema_are_rejected() {
+    let path = std::env::temp_dir().join(format!("angler-embedded-schema-{}.json", std::process::id()));
+    std::fs::write(&path, r#"{"type": "object", "required": ["order"], "properties": {"order": {"type": "integer"}}}"#).unwrap();
+    let mut configuration = Configuration::new();
+    configuration.messages_processor.topic_schemas = Some(std::collections::HashMap::from([
+        (String::from("order.created"), path.to_string_lossy().to_string()),
+    ]));
+    let angler = Angler::builder().configuration(configuration).workers(1).build().unwrap();
+    std::fs::remove_file(&path).unwrap();
+
+    let publish = |data: &str| request(
+        &angler,
+        "POST",
+        "/messages",
+        &format!(r#"{{"sendMessage": {{"recipientId": "r", "serviceId": "s", "eventId": "order.created"}}, "data": {}}}"#, data),
+    );
+    assert_eq!(publish(r#"{"order": 1}"#).0, 202);
+
+    let (status, rejected) = publish(r#"{"order": "one"}"#);
+    assert_eq!(status, 422);
+    let violations: Vec<&str> = rejected.get("violations").unwrap().as_array().unwrap().iter().filter_map(JsonValue::as_str).collect();
+    assert_eq!(violations, vec!["/order: expected integer but found string"]);
+}
+
+#[test]
+fn test_if_attributes_are_forwarded_as_headers_and_filter_destinations() {
+    let destination = MockDestinationServer::start().unwrap();
+    let angler = Angler::builder().workers(1).build().unwrap();
+    let (status, _) = request(
+        &angler,
+        "PUT",
+        "/destinations/recipient",
+        &format!(r#"{{"url": "{}", "attributeFilter": {{"region": "eu"}}}}"#, destination.url("/hooks")),
+    );
+    assert_eq!(status, 200);
+
+    let publish = |region: &str| {
+        let (status, published) = request(
+            &angler,
+            "POST",
+            "/messages",
+            &format!(r#"{{"sendMessage": {{"recipientId": "recipient", "serviceId": "s", "eventId": "e", "attributes": {{"region": "{}"}}}}}}"#, region),
+        );
+        assert_eq!(status, 202);
+        published.get("id").unwrap().as_str().unwrap().to_string()
+    };
+    let eu = publish("eu");
+    let us = publish("us");
+
+    assert!(angler.wait_for_status(&us, MessageStatus::Delivered, Duration::from_secs(5)).unwrap().is_some());
+    assert!(angler.wait_for_status(&eu, MessageStatus::Delivered, Duration::from_secs(5)).unwrap().is_some());
+    let requests = destination.requests_to("/hooks");
+    assert_eq!(requests.len(), 1);
+    assert_eq!(requests[0].request.headers.get("X-Angler-Attr-region"), Some("eu"));
+
+    let (_, attempts) = request(&angler, "GET", &format!("/messages/{}/attempts", us), "");
+    assert_eq!(attempts.as_array().unwrap()[0].get("outcome").unwrap().as_str(), Some("filtered"));
+    let (_, found) = request(&angler, "GET", "/messages?attr.region=us", "");
+    assert_eq!(found.as_array().unwrap()[0].get("id").unwrap().as_str(), Some(us.as_str()));
+}
+
+#[test]
+fn test_if_debug_capture_records_the_exchanges_of_the_destination() {
+    let destination = MockDestinationServer::start().unwrap();
+    let angler = Angler::builder().workers(1).admin_address("127.0.0.1:0").build().unwrap();
+    angler.register_destination("recipient", &destination.url("/hooks"));
+
+    let id = angler.publish("recipient", "s", "e", b"{\"before\":1}").unwrap();
+    assert!(angler.wait_for_status(&id, MessageStatus::Delivered, Duration::from_secs(5)).unwrap().is_some());
+    assert_eq!(admin_request(&angler, "PUT", "/admin/debug-captures/recipient").0, 200);
+    let id = angler.publish("recipient", "s", "e", b"{\"after\":1}").unwrap();
+    assert!(angler.wait_for_status(&id, MessageStatus::Delivered, Duration::from_secs(5)).unwrap().is_some());
+
+    let (status, captures) = admin_request(&angler, "GET", "/admin/debug-captures/recipient");
+    assert_eq!(status, 200);
+    assert_eq!(captures.get("enabled"), Some(&JsonValue::Bool(true)));
+    let exchanges = captures.get("exchanges").unwrap().as_array().unwrap();
+    assert_eq!(exchanges.len(), 1);
+    assert_eq!(exchanges[0].get("messageId").unwrap().as_str(), Some(id.as_str()));
+    assert_eq!(exchanges[0].get("request").unwrap().get("body").unwrap().as_str(), Some("{\"after\":1}"));
+    assert_eq!(exchanges[0].get("response").unwrap().get("status").and_then(JsonValue::as_u64), Some(200));
+
+    assert_eq!(admin_request(&angler, "DELETE", "/admin/debug-captures/recipient").0, 204);
+    let (_, captures) = admin_request(&angler, "GET", "/admin/debug-captures/recipient");
+    assert!(captures.get("exchanges").unwrap().as_array().unwrap().is_empty());
+    assert_eq!(admin_request(&angler, "DELETE", "/admin/debug-captures/recipient").0, 404);
+}
+
+/// Open the event stream of the destination, returning the status and the stream after the head
+fn open_events(angler: &Angler, recipient_id: &str, last_event_id: Option<u64>) -> (u16, BufReader<TcpStream>) {
+    let stream = TcpStream::connect(angler.client_addr()).unwrap();
+    stream.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+    let mut head = format!("GET /destinations/{}/events HTTP/1.1\r\nHost: angler\r\n", recipient_id);
+    if let Some(last_event_id) = last_event_id {
+        head.push_str(&format!("Last-Event-ID: {}\r\n", last_event_id));
+    }
+    (&stream).write_all(format!("{}\r\n", head).as_bytes()).unwrap();
+
+    let mut reader = BufReader::new(stream);
+    let mut status_line = String::new();
+    reader.read_line(&mut status_line).unwrap();
+    let mut line = String::from("-");
+    while line.trim_end() != "" {
+        line.clear();
+        reader.read_line(&mut line).unwrap();
+    }
+    (status_line.split_whitespace().nth(1).unwrap().parse().unwrap(), reader)
+}
+
+fn read_event(reader: &mut BufReader<TcpStream>) -> Vec<String> {
+    let mut fields = Vec::new();
+    loop {
+        let mut line = String::new();
+        reader.read_line(&mut line).unwrap();
+        match line.trim_end() {
+            "" => return fields,
+            field => fields.push(field.to_string()),
+        }
+    }
+}
+
+#[test]
+fn test_if_sse_destinations_stream_messages_and_resume_after_the_last_event_id() {
+    let angler = Angler::builder().workers(2).build().unwrap();
+    assert_eq!(request(&angler, "PUT", "/destinations/feed", r#"{"mode": "sse"}"#).1.get("url"), Some(&JsonValue::Null));
+
+    let (status, mut events) = open_events(&angler, "feed", None);
+    assert_eq!(status, 200);
+    let first = angler.publish("feed", "service", "order.created", b"{\"order\":1}").unwrap();
+    let second = angler.publish("feed", "service", "order.paid", b"{\"order\":1}").unwrap();
+    // the workers may deliver the messages in any order, but the IDs follow the order of the stream
+    let (one, two) = (read_event(&mut events), read_event(&mut events));
+    assert_eq!((one[0].as_str(), two[0].as_str()), ("id: 1", "id: 2"));
+    let mut topics = vec![one[1].as_str(), two[1].as_str()];
+    topics.sort();
+    assert_eq!(topics, vec!["event: order.created", "event: order.paid"]);
+    assert_eq!(one[2], "data: {\"order\":1}");
+    assert!(angler.wait_for_status(&first, MessageStatus::Delivered, Duration::from_secs(5)).unwrap().is_some());
+    assert!(angler.wait_for_status(&second, MessageStatus::Delivered, Duration::from_secs(5)).unwrap().is_some());
+    drop(events);
+
+    let (_, mut resumed) = open_events(&angler, "feed", Some(1));
+    assert_eq!(read_event(&mut resumed), two);
+    assert_eq!(open_events(&angler, "unknown", None).0, 404);
+}
+
+#[test]
+fn test_if_transform_test_shows_the_request_without_sending_it() {
+    let destination = MockDestinationServer::start().unwrap();
+    let angler = Angler::builder().workers(2).build().unwrap();
+    let body = format!(r#"{{"url": "{}", "method": "PUT", "attributeFilter": {{"region": "eu"}}}}"#, destination.url("/hooks"));
+    assert_eq!(request(&angler, "PUT", "/destinations/recipient", &body).0, 200);
+
+    let (status, accepted) = request(&angler, "POST", "/destinations/recipient/transform:test", r#"{"data": {"order": 1}, "attributes": {"region": "eu"}}"#);
+    assert_eq!(status, 200);
+    assert_eq!(accepted.get("accepted"), Some(&JsonValue::from(true)));
+    let sent = accepted.get("request").unwrap();
+    assert_eq!(sent.get("method").and_then(JsonValue::as_str), Some("PUT"));
+    assert_eq!(sent.get("body").and_then(JsonValue::as_str), Some("{\"order\":1}"));
+    assert_eq!(sent.get("headers").and_then(|headers| headers.get("X-Angler-Attr-region")).and_then(JsonValue::as_str), Some("eu"));
+
+    let (_, filtered) = request(&angler, "POST", "/destinations/recipient/transform:test", r#"{"data": {"order": 1}, "attributes": {"region": "us"}}"#);
+    assert_eq!(filtered.get("accepted"), Some(&JsonValue::from(false)));
+    assert_eq!(filtered.get("request"), None);
+    assert!(destination.requests().is_empty());
+    assert_eq!(request(&angler, "POST", "/destinations/unknown/transform:test", "{}").0, 404);
+}
+
+#[test]
+fn test_if_in_flight_messages_of_the_store_are_recovered_on_startup() {
+    let destination = MockDestinationServer::start().unwrap();
+    let store = Arc::new(MemoryStore::new());
+    let mut interrupted = Message::new(String::from("a"), String::from("recipient"), String::from("service"), String::from("event"), b"{}".to_vec());
+    interrupted.status = MessageStatus::InFlight;
+    // the destination is registered after the instance starts, so the first attempts may fail
+    interrupted.retry_policy = RetryPolicy { interval: Some(DurationSequence::from_vec(vec![time::Duration::milliseconds(10)]).unwrap()), max_attempts: 100 };
+    store.write(StoreWrite::InsertMessage(Box::new(interrupted))).unwrap();
+
+    let angler = Angler::builder().store(store).admin_address("127.0.0.1:0").workers(2).build().unwrap();
+    angler.register_destination("recipient", &destination.url("/hooks"));
+    assert!(angler.wait_for_status("a", MessageStatus::Delivered, Duration::from_secs(5)).unwrap().is_some());
+
+    let (status, recovery) = admin_request(&angler, "GET", "/admin/recovery");
+    assert_eq!(status, 200);
+    assert_eq!(recovery.get("resetInFlight"), Some(&JsonValue::from(1)));
+    assert_eq!(recovery.get("statuses").and_then(|statuses| statuses.get("inFlight")), Some(&JsonValue::from(1)));
+}
+
+#[test]
+fn test_if_topic_counters_are_served_by_the_admin_api() {
+    let destination = MockDestinationServer::start().unwrap();
+    let angler = Angler::builder().admin_address("127.0.0.1:0").workers(2).build().unwrap();
+    angler.register_destination("recipient", &destination.url("/hooks"));
+    let id = angler.publish("recipient", "orders", "order.created", b"{\"id\":1}").unwrap();
+    assert!(angler.wait_for_status(&id, MessageStatus::Delivered, Duration::from_secs(5)).unwrap().is_some());
+
+    let (status, topics) = admin_request(&angler, "GET", "/admin/topics/stats");
+    assert_eq!(status, 200);
+    let expected = JsonValue::object()
+        .with("serviceId", "orders")
+        .with("eventId", "order.created")
+        .with("messagesIn", 1)
+        .with("bytesIn", 8)
+        .with("delivered", 1)
+        .with("dead", 0);
+    assert_eq!(topics, JsonValue::from(vec![expected]));
+
+    let url = HttpUrl::parse(&format!("http://{}/admin/metrics", angler.admin_addr().unwrap())).unwrap();
+    let metrics = send_request(&url, HttpRequest::new("GET", "/admin/metrics"), Duration::from_secs(5)).unwrap();
+    assert_eq!(metrics.status, 200);
+    let metrics = String::from_utf8(metrics.body).unwrap();
+    assert!(metrics.contains("\nangler_messages_published_total 1\n"));
+    assert!(metrics.contains("\nangler_topic_bytes_in_total{service_id=\"orders\",event_id=\"order.created\"} 8\n"));
+    assert!(metrics.contains("# TYPE angler_pipeline_stage_seconds histogram\n"));
+    assert!(metrics.contains(",stage=\"transform\",le=\"+Inf\"} 1\n"));
+    assert!(metrics.contains("\nangler_pipeline_queue_depth{stage=\"claim\"} 0\n"));
+    assert!(metrics.contains("\nangler_destination_lagging{recipient_id=\"recipient\"} 0\n"));
+
+    let (status, lag) = request(&angler, "GET", "/destinations/recipient/lag", "");
+    assert_eq!(status, 200);
+    assert_eq!(lag.get("lagging"), Some(&JsonValue::Bool(false)));
+    assert!(lag.get("latencyMs").and_then(JsonValue::as_u64).is_some());
+    assert_eq!(request(&angler, "GET", "/destinations/missing/lag", "").0, 404);
+}
+
+#[test]
+fn test_if_message_processor_node_keeps_its_messages_in_the_storage_node() {
+    let destination = MockDestinationServer::start().unwrap();
+    let mut configuration = Configuration::new();
+    configuration.cluster.auth_key = Some(String::from("cluster-k3y"));
+    let storage_store = Arc::new(MemoryStore::new());
+    let storage = StorageNode::start(&configuration, storage_store.clone(), "127.0.0.1:0").unwrap();
+
+    configuration.cluster.roles = Some(HashSet::from([ApplicationRoles::MessageProcessor]));
+    configuration.cluster.storage_url = Some(storage.url());
+    let angler = Angler::builder().configuration(configuration.clone()).workers(2).build().unwrap();
+    angler.register_destination("recipient", &destination.url("/hooks"));
+    let id = angler.publish("recipient", "service", "event", b"{\"hello\":1}").unwrap();
+    assert!(angler.wait_for_status(&id, MessageStatus::Delivered, Duration::from_secs(5)).unwrap().is_some());
+
+    let stored = storage_store.get_message(&id).unwrap().unwrap();
+    assert_eq!(stored.payload, b"{\"hello\":1}");
+    assert_eq!(storage_store.get_attempts(&id).unwrap().len(), 1);
+    assert_eq!(angler.attempts(&id).unwrap().len(), 1);
+
+    // the storage node refuses the nodes without the cluster key
+    configuration.cluster.auth_key = Some(String::from("wrong"));
+    let rejected = Angler::builder().configuration(configuration).build();
+    assert!(rejected.err().unwrap().to_string().contains("401"));
+}
+
+#[test]
+fn test_if_a_node_joins_the_cluster_once_with_a_join_token() {
+    let destination = MockDestinationServer::start().unwrap();
+    let mut configuration = Configuration::new();
+    configuration.cluster.auth_key = Some(String::from("cluster-k3y"));
+    let storage = StorageNode::start(&configuration, Arc::new(MemoryStore::new()), "127.0.0.1:0").unwrap();
+    let token = JoinToken::create("cluster-k3y", time::Duration::hours(1), time::OffsetDateTime::now_utc());
+
+    configuration.cluster.auth_key = None;
+    configuration.cluster.join_token = Some(token);
+    configuration.cluster.roles = Some(HashSet::from([ApplicationRoles::MessageProcessor]));
+    configuration.cluster.storage_url = Some(storage.url());
+    let angler = Angler::builder().configuration(configuration.clone()).build().unwrap();
+    angler.register_destination("recipient", &destination.url("/hooks"));
+    let id = angler.publish("recipient", "service", "event", b"{}").unwrap();
+    assert!(angler.wait_for_status(&id, MessageStatus::Delivered, Duration::from_secs(5)).unwrap().is_some());
+    assert!(storage.store().get_message(&id).unwrap().is_some());
+
+    let reused = Angler::builder().configuration(configuration).build();
+    assert!(reused.err().unwrap().to_string().contains("already used"));
+}
+
+#[test]
+fn test_if_status_queries_are_served_by_the_read_replica_with_its_staleness() {
+    let destination = MockDestinationServer::start().unwrap();
+    let mut configuration = Configuration::new();
+    let replica_store = Arc::new(MemoryStore::new());
+    let replica = StorageNode::start(&configuration, replica_store.clone(), "127.0.0.1:0").unwrap();
+    configuration.cluster.storage_replicas = Some(vec![replica.url()]);
+    configuration.cluster.anti_entropy_interval = Some(time::Duration::milliseconds(50));
+    let storage = StorageNode::start(&configuration, Arc::new(MemoryStore::new()), "127.0.0.1:0").unwrap();
+
+    configuration.cluster.roles = Some(HashSet::from([ApplicationRoles::MessageProcessor]));
+    configuration.cluster.storage_url = Some(storage.url());
+    configuration.cluster.storage_read_url = Some(replica.url());
+    let angler = Angler::builder().configuration(configuration).workers(2).build().unwrap();
+    angler.register_destination("recipient", &destination.url("/hooks"));
+    let id = angler.publish("recipient", "service", "event", b"{}").unwrap();
+    assert!(angler.wait_for_status(&id, MessageStatus::Delivered, Duration::from_secs(5)).unwrap().is_some());
+
+    // the replica has the delivered message once the storage node repairs it
+    let path = format!("/messages/{}", id);
+    let url = HttpUrl::parse(&format!("{}{}", angler.client_url(), path)).unwrap();
+    let deadline = std::time::Instant::now() + Duration::from_secs(5);
+    let response = loop {
+        let response = send_request(&url, HttpRequest::new("GET", &path), Duration::from_secs(5)).unwrap();
+        let delivered = response.status == 200 && JsonValue::parse_bytes(&response.body).unwrap().get("status").and_then(JsonValue::as_str) == Some("delivered");
+        if delivered || std::time::Instant::now() > deadline {
+            break response;
+        }
+        std::thread::sleep(Duration::from_millis(20));
+    };
+    assert_eq!(response.status, 200);
+    assert!(replica_store.get_message(&id).unwrap().is_some());
+    assert_eq!(response.headers.get("X-Angler-Replica"), Some("true"));
+    assert!(response.headers.get("X-Angler-Replica-Synced-At").is_some());
+    assert!(response.headers.get("X-Angler-Replica-Staleness").unwrap().parse::<u64>().is_ok());
+}
+
+#[test]
+fn test_if_log_level_is_changed_at_runtime_through_the_admin_api() {
+    let angler = Angler::builder().admin_address("127.0.0.1:0").build().unwrap();
+    let admin_url = format!("http://{}", angler.admin_addr().unwrap());
+
+    let (status, changed) = request_to(&admin_url, "PUT", "/admin/log-level", r#"{"directives": "warn,angler::msgproc=debug"}"#);
+    assert_eq!(status, 200);
+    assert_eq!(changed.get("directives").and_then(JsonValue::as_str), Some("warn,angler::msgproc=debug"));
+    assert!(angler::utils::log::enabled("angler::msgproc::processor", angler::utils::log::Level::Debug));
+    assert_eq!(admin_request(&angler, "GET", "/admin/log-level").1, changed);
+
+    assert_eq!(request_to(&admin_url, "PUT", "/admin/log-level", r#"{"directives": "angler=loud"}"#).0, 400);
+    request_to(&admin_url, "PUT", "/admin/log-level", r#"{"directives": "info"}"#);
+}
+
+#[test]
+fn test_if_routing_is_exported_and_imported_into_a_standby_instance() {
+    let primary = Angler::builder().workers(1).build().unwrap();
+    let standby = Angler::builder().workers(1).build().unwrap();
+    let destinations = [
+        ("orders", r#"{"url": "http://orders.internal/hooks", "headers": {"Authorization": "Basic dXNlcjpwYXNz"}, "retryOn": "5xx,timeout"}"#),
+        ("billing", r#"{"mode": "pull", "attributeFilter": {"region": "eu"}}"#),
+    ];
+    for (id, body) in destinations {
+        assert_eq!(request(&primary, "PUT", &format!("/destinations/{}", id), body).0, 200);
+    }
+    assert_eq!(request(&standby, "PUT", "/destinations/stale", r#"{"mode": "pull"}"#).0, 200);
+
+    let document = export_routing(&HttpUrl::parse(&primary.client_url()).unwrap()).unwrap();
+    assert!(document.contains("\n  - attributeFilter:\n      region: eu\n"), "{}", document);
+
+    let standby_url = HttpUrl::parse(&standby.client_url()).unwrap();
+    let import = import_routing(&standby_url, &document, true, Some("alice")).unwrap();
+    assert_eq!((import.created, import.removed, import.unchanged), (vec![String::from("billing"), String::from("orders")], vec![String::from("stale")], 0));
+    let (_, orders) = request(&standby, "GET", "/destinations/orders", "");
+    assert_eq!(orders.pointer("headers.Authorization").and_then(JsonValue::as_str), Some("Basic dXNlcjpwYXNz"));
+    assert_eq!(export_routing(&standby_url).unwrap(), document);
+
+    // importing again changes nothing, and an invalid document changes nothing either
+    let import = import_routing(&standby_url, &document, true, None).unwrap();
+    assert_eq!((import.created.len(), import.updated.len(), import.unchanged), (0, 0, 2));
+    let drifted = document.replace("retryOn: connectTimeout,responseTimeout,http5xx", "retryOn: 4xx");
+    let plan = reconcile_routing(&standby_url, &drifted, false).unwrap();
+    assert!(plan.diff().contains("~ orders\n    retryOn: \"connectTimeout,responseTimeout,http5xx\" -> \"payloadTooLarge,http4xx\"\n"), "{}", plan);
+    assert_eq!(export_routing(&standby_url).unwrap(), document);
+    let changed = drifted.replace("id: billing", "id: billing\n    url: ftp://x");
+    assert!(import_routing(&standby_url, &changed, false, None).unwrap_err().contains("billing"));
+    let (_, history) = request(&standby, "GET", "/destinations/orders/history", "");
+    let changes = history.get("changes").and_then(JsonValue::as_array).unwrap();
+    assert_eq!(changes.len(), 1);
+    assert_eq!(changes[0].get("changedBy").and_then(JsonValue::as_str), Some("alice"));
+}
+
+struct TenantHeader;
+
+impl AuthProvider for TenantHeader {
+    fn authenticate(&self, request: &HttpRequest) -> Result<Identity, AuthError> {
+        let tenant = request.headers.get("X-Tenant").ok_or(AuthError::MissingCredentials)?;
+        Ok(Identity { subject: tenant.to_string() })
+    }
+}
+
+#[test]
+fn test_if_client_requests_are_authenticated_by_the_provider() {
+    let mut configuration = Configuration::new();
+    configuration.networking.client_auth_provider = Some(AuthProviderKind::ApiKeys);
+    configuration.networking.client_api_keys = Some(vec![(String::from("shop"), String::from("k-1"))]);
+    let angler = Angler::builder().configuration(configuration).build().unwrap();
+    let get = |angler: &Angler, header: Option<(&str, &str)>| {
+        let url = HttpUrl::parse(&format!("{}/destinations", angler.client_url())).unwrap();
+        let mut request = HttpRequest::new("GET", "/destinations");
+        if let Some((name, value)) = header {
+            request.headers.set(name, value);
+        }
+        send_request(&url, request, Duration::from_secs(5)).unwrap()
+    };
+
+    let denied = get(&angler, None);
+    assert_eq!(denied.status, 401);
+    assert_eq!(denied.headers.get("WWW-Authenticate"), Some("Bearer realm=\"Angler\""));
+    assert_eq!(get(&angler, Some(("X-Api-Key", "wrong"))).status, 401);
+    assert_eq!(get(&angler, Some(("Authorization", "Bearer k-1"))).status, 200);
+    // the receivers confirm their messages with the signed token only
+    assert_eq!(request(&angler, "POST", "/acks/not-a-token", "").0, 403);
+    angler.shutdown().unwrap();
+
+    let custom = Angler::builder().auth_provider(Arc::new(TenantHeader)).build().unwrap();
+    assert_eq!(get(&custom, None).status, 401);
+    assert_eq!(get(&custom, Some(("X-Tenant", "acme"))).status, 200);
+    custom.shutdown().unwrap();
+}
+
+#[test]
+fn test_if_messages_search_reads_every_page_of_the_admin_api() {
+    let store = Arc::new(MemoryStore::new());
+    for index in 0..1203 {
+        let mut message = Message::new(format!("dead-{}", index), String::from("api.foo.com"), String::from("billing"), String::from("invoice.paid"), b"{}".to_vec());
+        message.status = MessageStatus::Dead;
+        store.write(StoreWrite::InsertMessage(Box::new(message))).unwrap();
+    }
+    let mut other = Message::new(String::from("other"), String::from("api.bar.com"), String::from("billing"), String::from("invoice.paid"), b"{}".to_vec());
+    other.status = MessageStatus::Dead;
+    store.write(StoreWrite::InsertMessage(Box::new(other))).unwrap();
+
+    let mut configuration = Configuration::new();
+    configuration.networking.admin_auth_token = Some(String::from("s3cr3t"));
+    let angler = Angler::builder().configuration(configuration).store(store).admin_address("127.0.0.1:0").workers(1).build().unwrap();
+    let url = HttpUrl::parse(&format!("http://{}", angler.admin_addr().unwrap())).unwrap();
+    let filter = MessageSearchFilter { status: Some(MessageStatus::Dead), recipient_id: Some(String::from("api.foo.com")), ..MessageSearchFilter::default() };
+
+    let messages = search_messages(&url, Some("s3cr3t"), &filter, None).unwrap();
+    let ids: HashSet<&str> = messages.iter().filter_map(|message| message.get("id").and_then(JsonValue::as_str)).collect();
+    assert_eq!((messages.len(), ids.len()), (1203, 1203));
+    assert!(!ids.contains("other"));
+    assert_eq!(search_messages(&url, Some("s3cr3t"), &filter, Some(10)).unwrap().len(), 10);
+    let since = MessageSearchFilter { created_after: Some(time::OffsetDateTime::now_utc() + time::Duration::hours(1)), ..filter.clone() };
+    assert!(search_messages(&url, Some("s3cr3t"), &since, None).unwrap().is_empty());
+    assert!(search_messages(&url, None, &filter, None).unwrap_err().contains("401"));
+}
+
+#[test]
+fn test_if_readyz_checks_the_roles_of_the_node() {
+    let mut configuration = Configuration::new();
+    configuration.networking.client_auth_provider = Some(AuthProviderKind::ApiKeys);
+    configuration.networking.client_api_keys = Some(vec![(String::from("shop"), String::from("k-1"))]);
+    let angler = Angler::builder().configuration(configuration).build().unwrap();
+    // the probe needs no credentials
+    let (status, readiness) = request(&angler, "GET", "/readyz", "");
+    assert_eq!((status, readiness.get("ready").and_then(JsonValue::as_bool)), (200, Some(true)));
+    let checks: Vec<&str> = readiness.get("checks").and_then(JsonValue::as_array).unwrap().iter().filter_map(|check| check.get("name").and_then(JsonValue::as_str)).collect();
+    assert_eq!(checks, vec!["store", "recovery", "connections"]);
+    angler.shutdown().unwrap();
+
+    // a storage node is not ready until its replicas were repaired
+    let mut configuration = Configuration::new();
+    configuration.cluster.auth_key = Some(String::from("cluster-k3y"));
+    configuration.cluster.storage_replicas = Some(vec![String::from("http://127.0.0.1:1")]);
+    let storage = StorageNode::start(&configuration, Arc::new(MemoryStore::new()), "127.0.0.1:0").unwrap();
+    let (status, readiness) = request_to(&storage.url(), "GET", "/readyz", "");
+    assert_eq!(status, 503);
+    let replicas = &readiness.get("checks").and_then(JsonValue::as_array).unwrap()[1];
+    assert_eq!(replicas.get("role").and_then(JsonValue::as_str), Some("storage"));
+    assert_eq!(replicas.get("reason").and_then(JsonValue::as_str), Some("the replicas http://127.0.0.1:1 were not repaired yet"));
+}